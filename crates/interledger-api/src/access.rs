@@ -0,0 +1,445 @@
+use super::{api_key_token, required_scope, ApiKeyStore, NodeAccount};
+use futures::{
+    future::{ok, Either},
+    Async, Future, Poll,
+};
+use http::{header::AUTHORIZATION, Method, Request, Response};
+use interledger_http::HttpStore;
+use tower_service::Service;
+use tower_web::{middleware::Middleware, util::http::HttpService};
+
+/// Whether the request would change anything on the node.
+///
+/// This includes ILP packets sent over HTTP, which change the balances.
+fn changes_state<B>(request: &Request<B>) -> bool {
+    match *request.method() {
+        Method::GET | Method::HEAD | Method::OPTIONS => false,
+        _ => true,
+    }
+}
+
+fn forbidden<B>() -> Response<Option<B>> {
+    Response::builder().status(403).body(None).unwrap()
+}
+
+/// Checks what the credentials of each API request are allowed to do before it is routed,
+/// so the endpoints do not need to check for themselves. Requests that are not allowed
+/// are rejected with 403 Forbidden:
+///
+/// - Requests that would change anything on the node, if the node is read-only or the
+///   request is authorized by a read-only account
+/// - Requests authorized by API keys that do not have the scope the endpoint requires
+///
+/// Other requests are passed on for the endpoints to authorize as usual.
+#[derive(Clone)]
+pub struct AccessMiddleware<T> {
+    store: T,
+    read_only: bool,
+}
+
+impl<T> AccessMiddleware<T> {
+    /// If `read_only` is set, the whole node is read-only, for example because it is a standby
+    /// instance. Otherwise, only the requests authorized by read-only accounts are limited.
+    pub fn new(store: T, read_only: bool) -> Self {
+        AccessMiddleware { store, read_only }
+    }
+}
+
+impl<S, T> Middleware<S> for AccessMiddleware<T>
+where
+    S: HttpService + Clone,
+    T: HttpStore + ApiKeyStore,
+    T::Account: NodeAccount,
+{
+    type Request = Request<S::RequestBody>;
+    type Response = Response<Option<S::ResponseBody>>;
+    type Error = S::Error;
+    type Service = AccessService<S, T>;
+
+    fn wrap(&self, inner: S) -> Self::Service {
+        AccessService {
+            inner,
+            store: self.store.clone(),
+            read_only: self.read_only,
+        }
+    }
+}
+
+/// The service created by the `AccessMiddleware`.
+#[derive(Clone)]
+pub struct AccessService<S, T> {
+    inner: S,
+    store: T,
+    read_only: bool,
+}
+
+impl<S, T> AccessService<S, T>
+where
+    T: HttpStore + ApiKeyStore,
+    T::Account: NodeAccount,
+{
+    // Resolves to whether the request is allowed. Failing to look up an API key
+    // denies the request, because the endpoint would not check its scopes
+    fn check<B>(
+        &self,
+        request: &Request<B>,
+        authorization: String,
+    ) -> Box<Future<Item = bool, Error = ()> + Send> {
+        let changes_state = changes_state(request);
+        let scope_check = if let Some(token) = api_key_token(&authorization) {
+            let required = required_scope(request.method(), request.uri());
+            Either::A(
+                self.store
+                    .get_api_key(token)
+                    .map(move |key| match (key, required) {
+                        (Some(key), Some(scope)) => {
+                            let allowed = key.scopes.contains(&scope);
+                            if !allowed {
+                                debug!("API key {} does not have the scope: {}", key.id, scope);
+                            }
+                            allowed
+                        }
+                        (Some(key), None) => {
+                            debug!("API key {} cannot use this endpoint", key.id);
+                            false
+                        }
+                        // Not an API key, so it is left for the endpoint to authorize
+                        (None, _) => true,
+                    })
+                    .or_else(|_| Ok(false)),
+            )
+        } else {
+            Either::B(ok(true))
+        };
+        let store = self.store.clone();
+        Box::new(scope_check.and_then(move |allowed| {
+            if allowed && changes_state {
+                // Requests with invalid credentials are left for the endpoint to reject
+                Either::A(
+                    store
+                        .get_account_from_http_auth(&authorization)
+                        .then(|result| match result {
+                            Ok(ref account) if account.is_read_only() => {
+                                debug!("Rejecting request from read-only account");
+                                Ok(false)
+                            }
+                            _ => Ok(true),
+                        }),
+                )
+            } else {
+                Either::B(ok(allowed))
+            }
+        }))
+    }
+}
+
+impl<S, T> Service for AccessService<S, T>
+where
+    S: HttpService + Clone,
+    T: HttpStore + ApiKeyStore,
+    T::Account: NodeAccount,
+{
+    type Request = Request<S::RequestBody>;
+    type Response = Response<Option<S::ResponseBody>>;
+    type Error = S::Error;
+    type Future = AccessFuture<S>;
+
+    fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+        self.inner.poll_http_ready()
+    }
+
+    fn call(&mut self, request: Self::Request) -> Self::Future {
+        if self.read_only && changes_state(&request) {
+            debug!(
+                "Rejecting {} {} because the node is read-only",
+                request.method(),
+                request.uri().path()
+            );
+            return AccessFuture::Forbidden;
+        }
+        let authorization = request
+            .headers()
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .map(String::from);
+        if let Some(authorization) = authorization {
+            AccessFuture::Checking {
+                check: self.check(&request, authorization),
+                inner: self.inner.clone(),
+                request: Some(request),
+            }
+        } else {
+            AccessFuture::Calling(self.inner.call_http(request))
+        }
+    }
+}
+
+/// The response of the `AccessService`.
+pub enum AccessFuture<S: HttpService> {
+    Forbidden,
+    Checking {
+        check: Box<Future<Item = bool, Error = ()> + Send>,
+        inner: S,
+        request: Option<Request<S::RequestBody>>,
+    },
+    Calling(S::Future),
+}
+
+impl<S> Future for AccessFuture<S>
+where
+    S: HttpService,
+{
+    type Item = Response<Option<S::ResponseBody>>;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        loop {
+            let next = match self {
+                AccessFuture::Forbidden => return Ok(Async::Ready(forbidden())),
+                AccessFuture::Calling(response) => {
+                    return match response.poll()? {
+                        Async::Ready(response) => Ok(Async::Ready(response.map(Some))),
+                        Async::NotReady => Ok(Async::NotReady),
+                    };
+                }
+                AccessFuture::Checking {
+                    check,
+                    inner,
+                    request,
+                } => match check.poll() {
+                    Ok(Async::NotReady) => return Ok(Async::NotReady),
+                    Ok(Async::Ready(true)) => {
+                        let request = request.take().expect("Polled after completion");
+                        AccessFuture::Calling(inner.call_http(request))
+                    }
+                    _ => AccessFuture::Forbidden,
+                },
+            };
+            *self = next;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ApiKey, ApiScope};
+    use futures::future::{err, FutureResult};
+    use interledger_http::HttpAccount;
+    use interledger_service::{Account as AccountTrait, StoreError};
+    use url::Url;
+
+    fn request(method: Method, path: &str) -> Request<()> {
+        Request::builder()
+            .method(method)
+            .uri(path)
+            .body(())
+            .unwrap()
+    }
+
+    #[derive(Debug, Clone)]
+    struct TestAccount {
+        read_only: bool,
+    }
+
+    impl AccountTrait for TestAccount {
+        type AccountId = u64;
+
+        fn id(&self) -> u64 {
+            0
+        }
+    }
+
+    impl HttpAccount for TestAccount {
+        fn get_http_url(&self) -> Option<&Url> {
+            None
+        }
+
+        fn get_http_auth_header(&self) -> Option<&str> {
+            None
+        }
+    }
+
+    impl NodeAccount for TestAccount {
+        fn is_admin(&self) -> bool {
+            true
+        }
+
+        fn owner(&self) -> Option<&str> {
+            None
+        }
+
+        fn is_pending(&self) -> bool {
+            false
+        }
+
+        fn is_read_only(&self) -> bool {
+            self.read_only
+        }
+    }
+
+    // "Bearer admin" is the admin account, "Bearer reader" a read-only account,
+    // and "Bearer ilpk_reader" an API key of the admin that can only read
+    #[derive(Clone)]
+    struct TestStore;
+
+    impl HttpStore for TestStore {
+        type Account = TestAccount;
+
+        fn get_account_from_http_auth(
+            &self,
+            auth_header: &str,
+        ) -> Box<Future<Item = TestAccount, Error = ()> + Send> {
+            match auth_header {
+                "Bearer admin" | "Bearer ilpk_reader" => {
+                    Box::new(ok(TestAccount { read_only: false }))
+                }
+                "Bearer reader" => Box::new(ok(TestAccount { read_only: true })),
+                _ => Box::new(err(())),
+            }
+        }
+    }
+
+    fn reader_key() -> ApiKey {
+        ApiKey {
+            id: 1,
+            name: "reader".to_string(),
+            account_id: "0".to_string(),
+            scopes: vec![ApiScope::AccountsRead],
+            created_at: 0,
+        }
+    }
+
+    impl ApiKeyStore for TestStore {
+        fn create_api_key(
+            &self,
+            _name: String,
+            _account_id: String,
+            _scopes: Vec<ApiScope>,
+        ) -> Box<Future<Item = (ApiKey, String), Error = StoreError> + Send> {
+            Box::new(err(StoreError::StoreUnavailable))
+        }
+
+        fn get_api_key(
+            &self,
+            token: &str,
+        ) -> Box<Future<Item = Option<ApiKey>, Error = ()> + Send> {
+            let key = if token == "ilpk_reader" {
+                Some(reader_key())
+            } else {
+                None
+            };
+            Box::new(ok(key))
+        }
+
+        fn get_api_keys(&self) -> Box<Future<Item = Vec<ApiKey>, Error = StoreError> + Send> {
+            Box::new(ok(vec![reader_key()]))
+        }
+
+        fn delete_api_key(
+            &self,
+            _id: u64,
+        ) -> Box<Future<Item = ApiKey, Error = StoreError> + Send> {
+            Box::new(err(StoreError::NotFound))
+        }
+    }
+
+    // Responds 200 OK to every request that reaches it
+    #[derive(Clone)]
+    struct Endpoints;
+
+    impl Service for Endpoints {
+        type Request = Request<String>;
+        type Response = Response<String>;
+        type Error = ();
+        type Future = FutureResult<Response<String>, ()>;
+
+        fn poll_ready(&mut self) -> Poll<(), ()> {
+            Ok(Async::Ready(()))
+        }
+
+        fn call(&mut self, _request: Request<String>) -> Self::Future {
+            ok(Response::new(String::new()))
+        }
+    }
+
+    fn status(read_only_node: bool, method: Method, path: &str, authorization: &str) -> u16 {
+        let mut service = AccessMiddleware::new(TestStore, read_only_node).wrap(Endpoints);
+        let request = Request::builder()
+            .method(method)
+            .uri(path)
+            .header(AUTHORIZATION, authorization)
+            .body(String::new())
+            .unwrap();
+        service.call(request).wait().unwrap().status().as_u16()
+    }
+
+    #[test]
+    fn api_keys_only_use_their_scopes() {
+        assert_eq!(
+            status(false, Method::GET, "/accounts", "Bearer ilpk_reader"),
+            200
+        );
+        assert_eq!(
+            status(false, Method::POST, "/accounts", "Bearer ilpk_reader"),
+            403
+        );
+        assert_eq!(
+            status(false, Method::POST, "/accounts", "Bearer admin"),
+            200
+        );
+    }
+
+    #[test]
+    fn api_keys_cannot_export_secrets() {
+        assert_eq!(
+            status(false, Method::GET, "/accounts/export", "Bearer ilpk_reader"),
+            200
+        );
+        assert_eq!(
+            status(
+                false,
+                Method::GET,
+                "/accounts/export?include_secrets=true",
+                "Bearer ilpk_reader"
+            ),
+            403
+        );
+        assert_eq!(
+            status(
+                false,
+                Method::GET,
+                "/accounts/export?include_secrets=true",
+                "Bearer admin"
+            ),
+            200
+        );
+        assert_eq!(
+            status(
+                false,
+                Method::GET,
+                "/accounts/0/payment_pointer/check?payment_pointer=$example.com",
+                "Bearer ilpk_reader"
+            ),
+            403
+        );
+    }
+
+    #[test]
+    fn only_reads_dont_change_state() {
+        assert!(!changes_state(&request(Method::GET, "/accounts")));
+        assert!(!changes_state(&request(Method::HEAD, "/accounts/1")));
+        assert!(changes_state(&request(Method::POST, "/ilp")));
+        assert!(changes_state(&request(Method::POST, "/accounts")));
+        assert!(changes_state(&request(Method::PUT, "/routes/static")));
+        assert!(changes_state(&request(Method::DELETE, "/accounts/1")));
+    }
+
+    #[test]
+    fn read_only_nodes_and_accounts_cannot_send_packets() {
+        assert_eq!(status(false, Method::POST, "/ilp", "Bearer admin"), 200);
+        assert_eq!(status(true, Method::POST, "/ilp", "Bearer admin"), 403);
+        assert_eq!(status(false, Method::POST, "/ilp", "Bearer reader"), 403);
+        assert_eq!(status(true, Method::GET, "/accounts", "Bearer admin"), 200);
+    }
+}
@@ -0,0 +1,232 @@
+use futures::Future;
+use http::{Method, Uri};
+use interledger_service::StoreError;
+use serde::{Deserialize, Serialize};
+use std::{fmt, str::FromStr};
+use url::form_urlencoded;
+
+/// The tokens of API keys start with this, so that requests authorized by them can be told
+/// apart from the ones authorized by accounts' credentials without looking them up.
+pub const API_KEY_PREFIX: &str = "ilpk_";
+
+/// A permission that can be granted to an API key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum ApiScope {
+    /// Use the endpoints that only view data
+    #[serde(rename = "accounts:read")]
+    AccountsRead,
+    /// Create, change and delete accounts, and approve or reject peering requests
+    #[serde(rename = "accounts:write")]
+    AccountsWrite,
+    /// Send payments, refunds and ILP packets, and manage scheduled payments and streaming sessions
+    #[serde(rename = "payments:send")]
+    PaymentsSend,
+    /// Create payment links for accounts to be paid
+    #[serde(rename = "payments:receive")]
+    PaymentsReceive,
+    /// Change the static routes and roll back the routing table
+    #[serde(rename = "routes:write")]
+    RoutesWrite,
+}
+
+impl ApiScope {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ApiScope::AccountsRead => "accounts:read",
+            ApiScope::AccountsWrite => "accounts:write",
+            ApiScope::PaymentsSend => "payments:send",
+            ApiScope::PaymentsReceive => "payments:receive",
+            ApiScope::RoutesWrite => "routes:write",
+        }
+    }
+}
+
+impl FromStr for ApiScope {
+    type Err = ();
+
+    fn from_str(string: &str) -> Result<Self, ()> {
+        match string {
+            "accounts:read" => Ok(ApiScope::AccountsRead),
+            "accounts:write" => Ok(ApiScope::AccountsWrite),
+            "payments:send" => Ok(ApiScope::PaymentsSend),
+            "payments:receive" => Ok(ApiScope::PaymentsReceive),
+            "routes:write" => Ok(ApiScope::RoutesWrite),
+            _ => Err(()),
+        }
+    }
+}
+
+impl fmt::Display for ApiScope {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// A credential for automation that acts on behalf of the admin account that created it,
+/// but can only use the endpoints its scopes allow.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ApiKey {
+    /// Assigned by the store
+    pub id: u64,
+    pub name: String,
+    /// The account the key acts on behalf of
+    pub account_id: String,
+    pub scopes: Vec<ApiScope>,
+    /// Seconds since the UNIX epoch
+    pub created_at: u64,
+}
+
+/// The token of the API key the Authorization header holds, if any.
+pub fn api_key_token(authorization: &str) -> Option<&str> {
+    if authorization.starts_with("Bearer ") {
+        let token = &authorization[7..];
+        if token.starts_with(API_KEY_PREFIX) {
+            return Some(token);
+        }
+    }
+    None
+}
+
+// Whether the request asks for the accounts' credentials, with which the key could act as them.
+// The query is decoded the way the endpoint decodes it, so encoded names are caught too
+fn requests_secrets(uri: &Uri) -> bool {
+    form_urlencoded::parse(uri.query().unwrap_or("").as_bytes())
+        .any(|(name, value)| name == "include_secrets" && value != "false")
+}
+
+/// The scope an API key needs to make the request, or `None` if API keys cannot use the
+/// endpoint at all, such as the ones that change the node's configuration or manage API keys,
+/// export the accounts' credentials or make the node connect to other hosts.
+pub fn required_scope(method: &Method, uri: &Uri) -> Option<ApiScope> {
+    let segments: Vec<&str> = uri.path().trim_matches('/').split('/').collect();
+    let is_read = *method == Method::GET || *method == Method::HEAD;
+    match (segments[0], segments.get(1), segments.get(2)) {
+        ("api_keys", _, _) | ("chaos", _, _) | ("metrics", _, _) => None,
+        ("accounts", Some(&"export"), _) if requests_secrets(uri) => None,
+        ("accounts", _, Some(&"payment_pointer")) if segments.get(3) == Some(&"check") => None,
+        ("ilp", _, _) | ("pay", _, _) => Some(ApiScope::PaymentsSend),
+        ("accounts", _, Some(&"scheduled_payments"))
+        | ("accounts", _, Some(&"streaming_sessions"))
+        | ("accounts", _, Some(&"payments"))
+            if !is_read =>
+        {
+            Some(ApiScope::PaymentsSend)
+        }
+        ("accounts", _, Some(&"payment_links")) if !is_read => Some(ApiScope::PaymentsReceive),
+        ("accounts", _, _) | ("peering_requests", _, _) if !is_read => {
+            Some(ApiScope::AccountsWrite)
+        }
+        ("routes", _, _) if !is_read => Some(ApiScope::RoutesWrite),
+        _ if is_read => Some(ApiScope::AccountsRead),
+        _ => None,
+    }
+}
+
+/// Stores the API keys. Stores must also resolve the tokens of API keys to the accounts
+/// they act on behalf of in `HttpStore::get_account_from_http_auth`.
+pub trait ApiKeyStore: Clone + Send + Sync + 'static {
+    /// Create an API key for the account, returning it along with its secret token.
+    /// The token starts with `API_KEY_PREFIX`.
+    fn create_api_key(
+        &self,
+        name: String,
+        account_id: String,
+        scopes: Vec<ApiScope>,
+    ) -> Box<Future<Item = (ApiKey, String), Error = StoreError> + Send>;
+
+    /// Look up the API key with the given token.
+    fn get_api_key(&self, token: &str) -> Box<Future<Item = Option<ApiKey>, Error = ()> + Send>;
+
+    /// Get every API key, oldest first. The tokens are not included.
+    fn get_api_keys(&self) -> Box<Future<Item = Vec<ApiKey>, Error = StoreError> + Send>;
+
+    /// Delete the API key so that its token can no longer be used, returning the deleted key.
+    fn delete_api_key(&self, id: u64) -> Box<Future<Item = ApiKey, Error = StoreError> + Send>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_api_key_tokens() {
+        assert_eq!(api_key_token("Bearer ilpk_abc"), Some("ilpk_abc"));
+        assert_eq!(api_key_token("Bearer abc"), None);
+        assert_eq!(api_key_token("ilpk_abc"), None);
+    }
+
+    #[test]
+    fn maps_endpoints_to_scopes() {
+        let scope = |method: Method, path: &str| required_scope(&method, &path.parse().unwrap());
+        assert_eq!(
+            scope(Method::GET, "/accounts/1/balance"),
+            Some(ApiScope::AccountsRead)
+        );
+        assert_eq!(scope(Method::GET, "/routes"), Some(ApiScope::AccountsRead));
+        assert_eq!(
+            scope(Method::PUT, "/accounts/1"),
+            Some(ApiScope::AccountsWrite)
+        );
+        assert_eq!(
+            scope(Method::POST, "/accounts/1/scheduled_payments"),
+            Some(ApiScope::PaymentsSend)
+        );
+        assert_eq!(scope(Method::POST, "/ilp"), Some(ApiScope::PaymentsSend));
+        assert_eq!(
+            scope(Method::POST, "/accounts/1/payments/abc/refunds"),
+            Some(ApiScope::PaymentsSend)
+        );
+        assert_eq!(
+            scope(Method::POST, "/accounts/1/payment_links"),
+            Some(ApiScope::PaymentsReceive)
+        );
+        assert_eq!(
+            scope(Method::PUT, "/routes/static/batch"),
+            Some(ApiScope::RoutesWrite)
+        );
+        assert_eq!(scope(Method::PUT, "/rates"), None);
+        assert_eq!(scope(Method::GET, "/api_keys"), None);
+    }
+
+    #[test]
+    fn denies_endpoints_that_expose_secrets_or_reach_other_hosts() {
+        let scope = |method: Method, path: &str| required_scope(&method, &path.parse().unwrap());
+        assert_eq!(
+            scope(Method::GET, "/accounts/export"),
+            Some(ApiScope::AccountsRead)
+        );
+        assert_eq!(
+            scope(Method::GET, "/accounts/export?include_secrets=false"),
+            Some(ApiScope::AccountsRead)
+        );
+        assert_eq!(
+            scope(Method::GET, "/accounts/export?include_secrets=true"),
+            None
+        );
+        assert_eq!(
+            scope(
+                Method::GET,
+                "/accounts/export?format=json&include_secrets=1"
+            ),
+            None
+        );
+        assert_eq!(
+            scope(Method::GET, "/accounts/export?include%5Fsecrets=true"),
+            None
+        );
+        assert_eq!(
+            scope(Method::GET, "/accounts/export?include_secrets=%74rue"),
+            None
+        );
+        assert_eq!(
+            scope(Method::GET, "/accounts/1/payment_pointer"),
+            Some(ApiScope::AccountsRead)
+        );
+        assert_eq!(
+            scope(Method::GET, "/accounts/1/payment_pointer/check"),
+            None
+        );
+        assert_eq!(scope(Method::GET, "/chaos"), None);
+        assert_eq!(scope(Method::GET, "/metrics"), None);
+    }
+}
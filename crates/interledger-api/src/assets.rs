@@ -0,0 +1,172 @@
+use serde::Serialize;
+
+/// Where an asset's symbol goes relative to the amount.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SymbolPosition {
+    /// Before the amount, as in "$1.00"
+    Prefix,
+    /// After the amount and a space, as in "1.00 XRP"
+    Suffix,
+}
+
+/// How amounts of an asset are shown to users.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct AssetMetadata {
+    pub code: String,
+    pub name: String,
+    pub symbol: String,
+    /// The number of decimal places amounts are usually shown with,
+    /// which may be fewer than the account's asset scale
+    pub display_decimals: u8,
+    pub symbol_position: SymbolPosition,
+}
+
+// Code, name, symbol, display decimals and symbol position of the assets the node knows about.
+// Other assets are shown with their code as the symbol and all of their decimal places
+static ASSETS: [(&str, &str, &str, u8, SymbolPosition); 9] = [
+    ("USD", "US Dollar", "$", 2, SymbolPosition::Prefix),
+    ("EUR", "Euro", "€", 2, SymbolPosition::Prefix),
+    ("GBP", "British Pound", "£", 2, SymbolPosition::Prefix),
+    ("JPY", "Japanese Yen", "¥", 0, SymbolPosition::Prefix),
+    ("CNY", "Chinese Yuan", "CN¥", 2, SymbolPosition::Prefix),
+    ("CAD", "Canadian Dollar", "CA$", 2, SymbolPosition::Prefix),
+    ("XRP", "XRP", "XRP", 6, SymbolPosition::Suffix),
+    ("BTC", "Bitcoin", "BTC", 8, SymbolPosition::Suffix),
+    ("ETH", "Ether", "ETH", 6, SymbolPosition::Suffix),
+];
+
+impl AssetMetadata {
+    /// Look up the asset in the registry of known assets.
+    pub fn for_asset(asset_code: &str, asset_scale: u8) -> Self {
+        let code = asset_code.to_uppercase();
+        match ASSETS.iter().find(|(known, ..)| *known == code) {
+            Some((_, name, symbol, display_decimals, symbol_position)) => AssetMetadata {
+                code,
+                name: name.to_string(),
+                symbol: symbol.to_string(),
+                display_decimals: *display_decimals,
+                symbol_position: *symbol_position,
+            },
+            None => AssetMetadata {
+                name: code.clone(),
+                symbol: code.clone(),
+                code,
+                display_decimals: asset_scale,
+                symbol_position: SymbolPosition::Suffix,
+            },
+        }
+    }
+}
+
+/// An amount converted from an account's base units for display.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DisplayAmount {
+    /// The exact amount in whole units of the asset, such as "12.345678"
+    pub value: String,
+    /// The amount rounded to the asset's display decimals, with its symbol, such as "$12.35"
+    pub formatted: String,
+    pub asset: AssetMetadata,
+}
+
+impl DisplayAmount {
+    /// Convert an amount in base units of an asset with the given scale.
+    pub fn new(amount: i128, asset_code: &str, asset_scale: u8) -> Self {
+        let asset = AssetMetadata::for_asset(asset_code, asset_scale);
+        let sign = if amount < 0 { "-" } else { "" };
+        let digits = amount.abs().to_string();
+        let value = format!(
+            "{}{}",
+            sign,
+            with_decimal_point(&digits, asset_scale as usize)
+        );
+        let rounded = rounded(
+            &digits,
+            asset_scale as usize,
+            asset.display_decimals as usize,
+        );
+        // Amounts that round to zero are shown without a sign
+        let sign = if rounded.bytes().all(|c| c == b'0' || c == b'.') {
+            ""
+        } else {
+            sign
+        };
+        let formatted = match asset.symbol_position {
+            SymbolPosition::Prefix => format!("{}{}{}", sign, asset.symbol, rounded),
+            SymbolPosition::Suffix => format!("{}{} {}", sign, rounded, asset.symbol),
+        };
+        DisplayAmount {
+            value,
+            formatted,
+            asset,
+        }
+    }
+}
+
+// Place the decimal point `scale` digits from the right of the base units
+fn with_decimal_point(digits: &str, scale: usize) -> String {
+    if scale == 0 {
+        return digits.to_string();
+    }
+    let padded = format!("{:0>width$}", digits, width = scale + 1);
+    let (whole, fraction) = padded.split_at(padded.len() - scale);
+    format!("{}.{}", whole, fraction)
+}
+
+// Round the base units to `decimals` decimal places (half away from zero), or pad them with zeros
+fn rounded(digits: &str, scale: usize, decimals: usize) -> String {
+    if decimals >= scale {
+        let padded = format!("{}{}", digits, "0".repeat(decimals - scale));
+        return with_decimal_point(&padded, decimals);
+    }
+    let dropped = scale - decimals;
+    let padded = format!("{:0>width$}", digits, width = dropped + 1);
+    let (kept, rest) = padded.split_at(padded.len() - dropped);
+    let mut kept = kept.as_bytes().to_vec();
+    if rest.as_bytes()[0] >= b'5' {
+        // Carry the one, adding a digit if every kept digit was a nine
+        let mut carried = true;
+        for digit in kept.iter_mut().rev() {
+            if *digit == b'9' {
+                *digit = b'0';
+            } else {
+                *digit += 1;
+                carried = false;
+                break;
+            }
+        }
+        if carried {
+            kept.insert(0, b'1');
+        }
+    }
+    with_decimal_point(&String::from_utf8(kept).unwrap(), decimals)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_known_assets() {
+        let amount = DisplayAmount::new(12_345_678, "usd", 6);
+        assert_eq!(amount.value, "12.345678");
+        assert_eq!(amount.formatted, "$12.35");
+        assert_eq!(amount.asset.name, "US Dollar");
+        assert_eq!(DisplayAmount::new(-5, "USD", 2).formatted, "-$0.05");
+        assert_eq!(
+            DisplayAmount::new(999_999_500, "XRP", 9).formatted,
+            "1.000000 XRP"
+        );
+        assert_eq!(DisplayAmount::new(-1, "JPY", 3).formatted, "¥0");
+        assert_eq!(DisplayAmount::new(1, "EUR", 0).formatted, "€1.00");
+    }
+
+    #[test]
+    fn shows_unknown_assets_with_their_scale() {
+        let amount = DisplayAmount::new(1050, "ABC", 3);
+        assert_eq!(amount.value, "1.050");
+        assert_eq!(amount.formatted, "1.050 ABC");
+        assert_eq!(amount.asset.symbol_position, SymbolPosition::Suffix);
+        assert_eq!(DisplayAmount::new(-7, "ABC", 0).value, "-7");
+    }
+}
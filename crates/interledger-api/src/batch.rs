@@ -0,0 +1,242 @@
+use super::{AccountDetails, AccountRecord, FieldError, NodeConstraints};
+use interledger_service::StoreError;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+use std::str::FromStr;
+
+/// Why a batch of writes was not applied. Batches are applied atomically,
+/// so if any entry fails none of the entries are written.
+#[derive(Debug, Clone, PartialEq)]
+pub enum BatchError {
+    /// The entries at these indexes could not be written
+    Entries(Vec<(usize, StoreError)>),
+    /// The whole batch failed, for example because the store could not be reached
+    Store(StoreError),
+}
+
+impl From<StoreError> for BatchError {
+    fn from(err: StoreError) -> Self {
+        BatchError::Store(err)
+    }
+}
+
+/// One entry of a batch of static routes.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct StaticRouteEntry {
+    pub prefix: String,
+    pub account_id: String,
+}
+
+/// The outcome of one entry of a batch request, reported in the same order as the entries.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct BatchEntryResult {
+    pub index: usize,
+    /// The HTTP status the entry would have gotten on its own. Valid entries that were not
+    /// applied because of problems with other entries get 424 (Failed Dependency)
+    pub status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result: Option<Value>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub errors: Vec<FieldError>,
+}
+
+impl BatchEntryResult {
+    pub(crate) fn applied(index: usize, result: Value) -> Self {
+        BatchEntryResult {
+            index,
+            status: 200,
+            result: Some(result),
+            errors: Vec::new(),
+        }
+    }
+
+    fn not_applied(index: usize) -> Self {
+        BatchEntryResult {
+            index,
+            status: 424,
+            result: None,
+            errors: Vec::new(),
+        }
+    }
+}
+
+pub(crate) fn store_error_status(err: StoreError) -> u16 {
+    match err {
+        StoreError::NotFound => 404,
+        StoreError::Conflict => 409,
+        StoreError::InvalidData => 400,
+        StoreError::InsufficientBalance => 422,
+        StoreError::StoreUnavailable => 503,
+    }
+}
+
+/// The results of a batch that was rejected, with the status of the whole request, which is
+/// the status of the first entry that failed.
+pub(crate) fn rejected_batch(
+    len: usize,
+    failed: Vec<(usize, u16, Vec<FieldError>)>,
+) -> (u16, Vec<BatchEntryResult>) {
+    let mut results: Vec<BatchEntryResult> = (0..len).map(BatchEntryResult::not_applied).collect();
+    for (index, status, errors) in failed {
+        if let Some(result) = results.get_mut(index) {
+            result.status = status;
+            result.errors = errors;
+        }
+    }
+    let status = results
+        .iter()
+        .find(|result| result.status != 424)
+        .map_or(400, |result| result.status);
+    (status, results)
+}
+
+/// Parse and validate every account of a batch, which is a JSON array of account records in the
+/// same format as the account import. If any entry is invalid, the problems with each are returned.
+pub fn parse_account_batch(
+    body: &str,
+    constraints: &NodeConstraints,
+) -> Result<Vec<AccountDetails>, Vec<(usize, Vec<FieldError>)>> {
+    let entries: Vec<Value> = parse_batch(body)?;
+    let mut accounts = Vec::with_capacity(entries.len());
+    let mut entry_errors = Vec::new();
+    for (index, entry) in entries.into_iter().enumerate() {
+        let result = serde_json::from_value::<AccountRecord>(entry)
+            .map_err(|err| {
+                vec![FieldError {
+                    field: "record",
+                    message: err.to_string(),
+                }]
+            })
+            .and_then(|record| {
+                let details = record.into_details();
+                details
+                    .validate()
+                    .and_then(|_| constraints.check_account(&details))
+                    .map(|_| details)
+            });
+        match result {
+            Ok(details) => accounts.push(details),
+            Err(errors) => entry_errors.push((index, errors)),
+        }
+    }
+    if entry_errors.is_empty() {
+        Ok(accounts)
+    } else {
+        Err(entry_errors)
+    }
+}
+
+fn parse_batch<T: DeserializeOwned>(body: &str) -> Result<Vec<T>, Vec<(usize, Vec<FieldError>)>> {
+    serde_json::from_str(body).map_err(|err| {
+        vec![(
+            0,
+            vec![FieldError {
+                field: "batch",
+                message: err.to_string(),
+            }],
+        )]
+    })
+}
+
+/// Parse and validate every entry of a batch of static routes, which is a JSON array of
+/// objects with a `prefix` and an `account_id`. If any entry is invalid, the problems with
+/// each are returned.
+pub fn parse_route_batch<I: FromStr>(
+    body: &str,
+    constraints: &NodeConstraints,
+) -> Result<Vec<(String, I)>, Vec<(usize, Vec<FieldError>)>> {
+    let entries: Vec<StaticRouteEntry> = parse_batch(body)?;
+    let mut routes = Vec::with_capacity(entries.len());
+    let mut entry_errors = Vec::new();
+    for (index, entry) in entries.into_iter().enumerate() {
+        let mut errors = Vec::new();
+        if let Err(message) = constraints.check_route_prefix(&entry.prefix) {
+            errors.push(FieldError {
+                field: "prefix",
+                message,
+            });
+        }
+        // Later entries for the same prefix would silently replace earlier ones
+        if routes
+            .iter()
+            .any(|(prefix, _): &(String, I)| prefix == &entry.prefix)
+        {
+            errors.push(FieldError {
+                field: "prefix",
+                message: "Prefix appears more than once in the batch".to_string(),
+            });
+        }
+        let account_id = I::from_str(&entry.account_id).ok();
+        if account_id.is_none() {
+            errors.push(FieldError {
+                field: "account_id",
+                message: "Invalid account ID".to_string(),
+            });
+        }
+        match account_id {
+            Some(account_id) if errors.is_empty() => routes.push((entry.prefix, account_id)),
+            _ => entry_errors.push((index, errors)),
+        }
+    }
+    if entry_errors.is_empty() {
+        Ok(routes)
+    } else {
+        Err(entry_errors)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_every_invalid_account() {
+        let body = json!([
+            {
+                "ilp_address": "example.alice",
+                "asset_code": "XYZ",
+                "asset_scale": 9,
+                "max_packet_amount": 100
+            },
+            {
+                "ilp_address": "example..bob",
+                "asset_code": "XYZ",
+                "asset_scale": 9,
+                "max_packet_amount": 100
+            },
+            { "ilp_address": "example.carl" }
+        ])
+        .to_string();
+        let errors = parse_account_batch(&body, &NodeConstraints::default()).unwrap_err();
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[0].0, 1);
+        assert_eq!(errors[0].1[0].field, "ilp_address");
+        assert_eq!(errors[1].0, 2);
+        assert_eq!(errors[1].1[0].field, "record");
+    }
+
+    #[test]
+    fn rejects_duplicate_route_prefixes() {
+        let body = json!([
+            { "prefix": "example.a", "account_id": "1" },
+            { "prefix": "example.b", "account_id": "x" },
+            { "prefix": "example.a", "account_id": "2" }
+        ])
+        .to_string();
+        let errors = parse_route_batch::<u64>(&body, &NodeConstraints::default()).unwrap_err();
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[0].0, 1);
+        assert_eq!(errors[0].1[0].field, "account_id");
+        assert_eq!(errors[1].0, 2);
+        assert_eq!(errors[1].1[0].field, "prefix");
+    }
+
+    #[test]
+    fn rejected_batch_uses_first_failure_status() {
+        let (status, results) = rejected_batch(3, vec![(1, 409, Vec::new())]);
+        assert_eq!(status, 409);
+        assert_eq!(results[0].status, 424);
+        assert_eq!(results[1].status, 409);
+        assert_eq!(results[2].status, 424);
+    }
+}
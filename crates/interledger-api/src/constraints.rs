@@ -0,0 +1,331 @@
+use super::validation::{validate_address, FieldError};
+use super::AccountDetails;
+use futures::{
+    future::{err, ok, result, Either},
+    Future,
+};
+use hyper::client::connect::dns::{GaiResolver, Name, Resolve};
+use interledger_config::{forbidden_networks, loopback_networks, IpNetwork};
+use interledger_spsp::payment_pointer_url;
+use std::net::IpAddr;
+use url::{Host, Url};
+
+/// Node-wide rules that every account and route must follow, so that the node
+/// is not configured in a way that would produce unroutable or non-convertible traffic.
+#[derive(Debug, Clone, Default)]
+pub struct NodeConstraints {
+    /// If set, only accounts denominated in one of these assets can be created
+    pub allowed_asset_codes: Option<Vec<String>>,
+    /// If set, accounts cannot have a larger asset scale than this
+    pub max_asset_scale: Option<u8>,
+    /// If set, the addresses of child accounts must start with this prefix
+    pub child_address_prefix: Option<Vec<u8>>,
+    /// If set, accounts' HTTP and BTP URLs must only resolve to addresses in these networks.
+    /// Otherwise, they can resolve to any address except loopback ones
+    pub allowed_endpoint_networks: Option<Vec<IpNetwork>>,
+    /// Accounts' HTTP and BTP URLs must not resolve to addresses in these networks
+    /// (in addition to the link-local ones, which are always denied)
+    pub denied_endpoint_networks: Vec<IpNetwork>,
+}
+
+impl NodeConstraints {
+    pub fn check_account(&self, details: &AccountDetails) -> Result<(), Vec<FieldError>> {
+        let mut errors = Vec::new();
+
+        if let Some(ref allowed) = self.allowed_asset_codes {
+            if !allowed
+                .iter()
+                .any(|code| code.eq_ignore_ascii_case(&details.asset_code))
+            {
+                errors.push(FieldError {
+                    field: "asset_code",
+                    message: format!(
+                        "This node only supports the assets: {}",
+                        allowed.join(", ")
+                    ),
+                });
+            }
+        }
+
+        if let Some(max_asset_scale) = self.max_asset_scale {
+            if details.asset_scale > max_asset_scale {
+                errors.push(FieldError {
+                    field: "asset_scale",
+                    message: format!(
+                        "This node only supports asset scales up to {}",
+                        max_asset_scale
+                    ),
+                });
+            }
+        }
+
+        if let Some(ref prefix) = self.child_address_prefix {
+            // Accounts are children unless otherwise specified
+            let is_child = details
+                .routing_relation
+                .as_ref()
+                .map(|relation| relation.eq_ignore_ascii_case("child"))
+                .unwrap_or(true);
+            let has_prefix = details.ilp_address.starts_with(prefix)
+                && details.ilp_address.get(prefix.len()) == Some(&b'.');
+            if is_child && !has_prefix {
+                errors.push(FieldError {
+                    field: "ilp_address",
+                    message: format!(
+                        "Child account addresses must start with {}.",
+                        String::from_utf8_lossy(prefix)
+                    ),
+                });
+            }
+        }
+
+        for &(field, url) in &[
+            ("http_endpoint", &details.http_endpoint),
+            ("btp_uri", &details.btp_uri),
+        ] {
+            if let Some(Err(message)) = url.as_ref().map(|url| self.check_endpoint(url)) {
+                errors.push(FieldError { field, message });
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Check that the node may connect to the given URL, so that users who can create accounts
+    /// cannot make it send requests to internal services. Only hosts that are IP addresses are
+    /// checked here, because a hostname can resolve to other addresses by the time the node
+    /// connects. The node checks what hostnames resolve to each time it connects (see `check_host`).
+    pub fn check_endpoint(&self, url: &str) -> Result<(), String> {
+        // Invalid URLs are reported by `AccountDetails::validate`
+        let url = match Url::parse(url) {
+            Ok(url) => url,
+            Err(_) => return Ok(()),
+        };
+        match url.host() {
+            Some(Host::Ipv4(address)) => self.check_addresses(&[IpAddr::V4(address)]),
+            Some(Host::Ipv6(address)) => self.check_addresses(&[IpAddr::V6(address)]),
+            // The url crate only parses IPv4 hosts of the schemes it knows, so
+            // those of btp+ws URLs are domains
+            Some(Host::Domain(domain)) => match domain.parse::<IpAddr>() {
+                Ok(address) => self.check_addresses(&[address]),
+                Err(_) => Ok(()),
+            },
+            None => Ok(()),
+        }
+    }
+
+    /// Resolve the host of the URL, without blocking, and check that the node may connect to
+    /// every address it resolves to. This should be done right before connecting.
+    pub fn check_host(&self, url: &Url) -> impl Future<Item = (), Error = String> + Send {
+        let addresses = match url.host() {
+            Some(Host::Ipv4(address)) => Either::A(ok(vec![IpAddr::V4(address)])),
+            Some(Host::Ipv6(address)) => Either::A(ok(vec![IpAddr::V6(address)])),
+            Some(Host::Domain(domain)) => {
+                let domain = domain.to_string();
+                let name = domain
+                    .parse::<Name>()
+                    .map_err(|_| format!("{} is not a valid host", domain));
+                Either::B(result(name).and_then(move |name| {
+                    GaiResolver::new(1)
+                        .resolve(name)
+                        .map(|addresses| addresses.collect::<Vec<IpAddr>>())
+                        .map_err(move |error| format!("Unable to resolve {}: {}", domain, error))
+                }))
+            }
+            None => return Either::A(err(format!("{} has no host", url))),
+        };
+        let constraints = self.clone();
+        let url = url.clone();
+        Either::B(addresses.and_then(move |addresses| {
+            if addresses.is_empty() {
+                return Err(format!("{} does not resolve to any address", url));
+            }
+            constraints.check_addresses(&addresses)
+        }))
+    }
+
+    /// Check that the node may query a payment pointer given by someone else, such as the return
+    /// pointer the sender of a payment gave. Only payment pointers (not other URLs) are accepted,
+    /// and their host must only resolve to addresses the node connects to. Resolves to the URL
+    /// of the SPSP endpoint.
+    ///
+    /// The receiver should then be queried without following redirects.
+    pub fn check_payment_pointer_host(
+        &self,
+        payment_pointer: &str,
+    ) -> impl Future<Item = Url, Error = String> {
+        match payment_pointer_url(payment_pointer) {
+            Ok(url) => Either::A(self.check_host(&url).map(move |_| url)),
+            Err(error) => Either::B(err(error.to_string())),
+        }
+    }
+
+    fn check_addresses(&self, addresses: &[IpAddr]) -> Result<(), String> {
+        for &address in addresses {
+            if forbidden_networks()
+                .iter()
+                .chain(self.denied_endpoint_networks.iter())
+                .any(|network| network.contains(address))
+            {
+                return Err(format!("Connections to {} are not allowed", address));
+            }
+            match self.allowed_endpoint_networks {
+                Some(ref allowed) => {
+                    if !allowed.iter().any(|network| network.contains(address)) {
+                        return Err(format!(
+                            "{} is not in one of the networks this node connects to",
+                            address
+                        ));
+                    }
+                }
+                // The node's own services are only reachable if they are explicitly allowed
+                None if loopback_networks()
+                    .iter()
+                    .any(|network| network.contains(address)) =>
+                {
+                    return Err(format!("Connections to {} are not allowed", address));
+                }
+                None => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Check that a route can be installed for the given prefix.
+    pub fn check_route_prefix(&self, prefix: &str) -> Result<(), String> {
+        validate_address(prefix.as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn details(ilp_address: &[u8], routing_relation: Option<&str>) -> AccountDetails {
+        AccountDetails {
+            ilp_address: ilp_address.to_vec(),
+            asset_code: "XRP".to_string(),
+            asset_scale: 9,
+            max_packet_amount: 1000,
+            max_packet_data: None,
+            priority: None,
+            max_value_per_second: None,
+            inactivity_timeout: None,
+            owner: None,
+            pending: false,
+            min_balance: 0,
+            http_endpoint: None,
+            http_incoming_authorization: None,
+            http_outgoing_authorization: None,
+            btp_uri: None,
+            btp_incoming_authorization: None,
+            is_admin: false,
+            read_only: false,
+            xrp_address: None,
+            settle_threshold: None,
+            settle_to: None,
+            send_routes: false,
+            receive_routes: false,
+            routing_relation: routing_relation.map(|r| r.to_string()),
+        }
+    }
+
+    #[test]
+    fn no_constraints_by_default() {
+        let constraints = NodeConstraints::default();
+        assert!(constraints
+            .check_account(&details(b"example.alice", None))
+            .is_ok());
+    }
+
+    #[test]
+    fn checks_asset_code_and_scale() {
+        let constraints = NodeConstraints {
+            allowed_asset_codes: Some(vec!["USD".to_string()]),
+            max_asset_scale: Some(6),
+            child_address_prefix: None,
+            allowed_endpoint_networks: None,
+            denied_endpoint_networks: Vec::new(),
+        };
+        let errors = constraints
+            .check_account(&details(b"example.alice", None))
+            .unwrap_err();
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[0].field, "asset_code");
+        assert_eq!(errors[1].field, "asset_scale");
+    }
+
+    #[test]
+    fn only_applies_prefix_to_children() {
+        let constraints = NodeConstraints {
+            allowed_asset_codes: None,
+            max_asset_scale: None,
+            child_address_prefix: Some(b"example.node".to_vec()),
+            allowed_endpoint_networks: None,
+            denied_endpoint_networks: Vec::new(),
+        };
+        assert!(constraints
+            .check_account(&details(b"example.node.alice", None))
+            .is_ok());
+        assert!(constraints
+            .check_account(&details(b"example.nodealice", Some("Child")))
+            .is_err());
+        assert!(constraints
+            .check_account(&details(b"example.other", Some("Peer")))
+            .is_ok());
+    }
+
+    #[test]
+    fn checks_endpoint_addresses() {
+        let constraints = NodeConstraints::default();
+        assert!(constraints
+            .check_endpoint("http://169.254.169.254/latest/meta-data")
+            .is_err());
+        assert!(constraints.check_endpoint("http://[fe80::1]/ilp").is_err());
+        assert!(constraints.check_endpoint("http://10.1.1.1/ilp").is_ok());
+        assert!(constraints.check_endpoint("http://127.0.0.1/ilp").is_err());
+        assert!(constraints.check_endpoint("btp+ws://[::1]:7768").is_err());
+        // Hostnames are checked when the node connects
+        assert!(constraints
+            .check_endpoint("http://internal.invalid/ilp")
+            .is_ok());
+        assert!(constraints
+            .check_host(&Url::parse("http://127.0.0.1/ilp").unwrap())
+            .wait()
+            .is_err());
+        assert!(constraints
+            .check_host(&Url::parse("http://10.1.1.1/ilp").unwrap())
+            .wait()
+            .is_ok());
+
+        let constraints = NodeConstraints {
+            allowed_endpoint_networks: Some(vec!["127.0.0.0/8".parse().unwrap()]),
+            ..NodeConstraints::default()
+        };
+        assert!(constraints.check_endpoint("http://127.0.0.1/ilp").is_ok());
+
+        let constraints = NodeConstraints {
+            allowed_endpoint_networks: Some(vec!["192.0.2.0/24".parse().unwrap()]),
+            denied_endpoint_networks: vec!["192.0.2.128/25".parse().unwrap()],
+            ..NodeConstraints::default()
+        };
+        assert!(constraints
+            .check_endpoint("btp+ws://192.0.2.1:7768")
+            .is_ok());
+        assert!(constraints
+            .check_endpoint("btp+ws://192.0.2.200:7768")
+            .is_err());
+        assert!(constraints
+            .check_endpoint("https://198.51.100.1/ilp")
+            .is_err());
+
+        let mut account = details(b"example.alice", None);
+        account.http_endpoint = Some("http://169.254.169.254".to_string());
+        let errors = constraints.check_account(&account).unwrap_err();
+        assert_eq!(errors[0].field, "http_endpoint");
+    }
+}
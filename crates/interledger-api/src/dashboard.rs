@@ -0,0 +1,153 @@
+use http::Response;
+
+// The dashboard's files are compiled into the binary so the node can serve it without
+// any other files. They are only served to the admin, and the page is given the admin
+// token to load everything from the other API endpoints with
+static INDEX_HTML: &str = include_str!("dashboard/index.html");
+static DASHBOARD_JS: &str = include_str!("dashboard/dashboard.js");
+static DASHBOARD_CSS: &str = include_str!("dashboard/dashboard.css");
+
+static ADMIN_TOKEN_PLACEHOLDER: &str = "{{admin_token}}";
+
+// The page only loads its own files and the API's JSON, and must not be framed by other sites
+static SECURITY_HEADERS: &[(&str, &str)] = &[
+    (
+        "Content-Security-Policy",
+        "default-src 'self'; base-uri 'none'; form-action 'none'; frame-ancestors 'none'",
+    ),
+    ("X-Frame-Options", "DENY"),
+    ("X-Content-Type-Options", "nosniff"),
+    ("Referrer-Policy", "no-referrer"),
+    ("Cache-Control", "no-store"),
+];
+
+/// A file the dashboard is made of.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DashboardAsset {
+    pub content_type: &'static str,
+    pub body: String,
+}
+
+/// Get one of the dashboard's files by its name, or the page itself for an empty name.
+pub fn dashboard_asset(name: &str, admin_token: &str) -> Option<DashboardAsset> {
+    let (content_type, body) = match name {
+        "" | "index.html" => (
+            "text/html; charset=utf-8",
+            INDEX_HTML.replace(ADMIN_TOKEN_PLACEHOLDER, &escape_html(admin_token)),
+        ),
+        "dashboard.js" => (
+            "application/javascript; charset=utf-8",
+            DASHBOARD_JS.to_string(),
+        ),
+        "dashboard.css" => ("text/css; charset=utf-8", DASHBOARD_CSS.to_string()),
+        _ => return None,
+    };
+    Some(DashboardAsset { content_type, body })
+}
+
+pub fn dashboard_response(asset: DashboardAsset) -> Response<String> {
+    let mut response = Response::builder();
+    response.header("Content-Type", asset.content_type);
+    for (name, value) in SECURITY_HEADERS {
+        response.header(*name, *value);
+    }
+    response.body(asset.body).unwrap()
+}
+
+/// The response that makes the browser ask for the admin token.
+pub fn sign_in_response() -> Response<()> {
+    let mut response = Response::builder();
+    response
+        .status(401)
+        .header("WWW-Authenticate", "Basic realm=\"Interledger node\"");
+    for (name, value) in SECURITY_HEADERS {
+        response.header(*name, *value);
+    }
+    response.body(()).unwrap()
+}
+
+/// Get the admin token from the credentials the browser signed in to the dashboard with.
+/// The token is the password of HTTP Basic auth, and the user name is ignored.
+pub fn basic_auth_token(authorization: &str) -> Option<String> {
+    let mut parts = authorization.splitn(2, ' ');
+    match (parts.next(), parts.next()) {
+        (Some(scheme), Some(credentials)) if scheme.eq_ignore_ascii_case("basic") => {
+            let credentials = base64::decode(credentials.trim()).ok()?;
+            let credentials = String::from_utf8(credentials).ok()?;
+            let token = credentials.splitn(2, ':').nth(1)?;
+            if token.is_empty() {
+                None
+            } else {
+                Some(token.to_string())
+            }
+        }
+        _ => None,
+    }
+}
+
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serves_page_and_its_files() {
+        let page = dashboard_asset("", "token").unwrap();
+        assert!(page.content_type.starts_with("text/html"));
+        assert!(page.body.contains("dashboard.js"));
+        assert!(page.body.contains("dashboard.css"));
+        assert_eq!(dashboard_asset("index.html", "token"), Some(page));
+        assert!(dashboard_asset("dashboard.js", "token").is_some());
+        assert!(dashboard_asset("../lib.rs", "token").is_none());
+    }
+
+    #[test]
+    fn gives_the_page_the_escaped_token() {
+        let page = dashboard_asset("", "a\"><script>").unwrap();
+        assert!(page.body.contains("content=\"a&quot;&gt;&lt;script&gt;\""));
+        assert!(!page.body.contains(ADMIN_TOKEN_PLACEHOLDER));
+    }
+
+    #[test]
+    fn sends_security_headers() {
+        let response = dashboard_response(dashboard_asset("", "token").unwrap());
+        assert_eq!(response.headers()["X-Frame-Options"], "DENY");
+        assert!(response.headers()["Content-Security-Policy"]
+            .to_str()
+            .unwrap()
+            .contains("frame-ancestors 'none'"));
+        assert_eq!(sign_in_response().status(), 401);
+    }
+
+    #[test]
+    fn gets_token_from_basic_auth() {
+        // admin:secret:token
+        assert_eq!(
+            basic_auth_token("Basic YWRtaW46c2VjcmV0OnRva2Vu"),
+            Some("secret:token".to_string())
+        );
+        // :token
+        assert_eq!(
+            basic_auth_token("basic OnRva2Vu"),
+            Some("token".to_string())
+        );
+        // admin:
+        assert_eq!(basic_auth_token("Basic YWRtaW46"), None);
+        assert_eq!(basic_auth_token("Bearer token"), None);
+        assert_eq!(basic_auth_token("Basic not base64"), None);
+    }
+}
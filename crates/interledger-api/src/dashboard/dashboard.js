@@ -0,0 +1,191 @@
+// Node dashboard. Everything is loaded from the node's API with the admin token
+// the page was served with
+(function () {
+  'use strict';
+
+  var REFRESH_INTERVAL = 10000;
+  var RECENT_PAYMENTS = 20;
+  var refreshTimer = null;
+
+  function $(id) {
+    return document.getElementById(id);
+  }
+
+  function token() {
+    return document.querySelector('meta[name="admin-token"]').content;
+  }
+
+  function api(path) {
+    return fetch(path, {
+      headers: { Authorization: 'Bearer ' + token() }
+    }).then(function (response) {
+      if (response.status === 401) {
+        var error = new Error('The admin token is no longer accepted. Reload the page to sign in again');
+        error.unauthorized = true;
+        throw error;
+      }
+      if (!response.ok) {
+        throw new Error(path + ' responded with ' + response.status);
+      }
+      return response.json();
+    });
+  }
+
+  // Optional sections (such as the connections, which need a BTP server) are shown empty
+  function optional(promise, fallback) {
+    return promise.catch(function (error) {
+      if (error.unauthorized) {
+        throw error;
+      }
+      return fallback;
+    });
+  }
+
+  function cell(text, className) {
+    var td = document.createElement('td');
+    td.textContent = text === undefined || text === null ? '' : String(text);
+    if (className) {
+      td.className = className;
+    }
+    return td;
+  }
+
+  function fillTable(id, rows, emptyText) {
+    var body = $(id);
+    body.textContent = '';
+    if (rows.length === 0) {
+      var tr = document.createElement('tr');
+      var td = cell(emptyText, 'muted');
+      td.colSpan = body.parentNode.querySelectorAll('th').length;
+      tr.appendChild(td);
+      body.appendChild(tr);
+      return;
+    }
+    rows.forEach(function (cells) {
+      var tr = document.createElement('tr');
+      cells.forEach(function (td) {
+        tr.appendChild(td);
+      });
+      body.appendChild(tr);
+    });
+  }
+
+  function time(seconds) {
+    return new Date(seconds * 1000).toLocaleString();
+  }
+
+  function isPeer(account) {
+    return account.routing_relation === 'Parent' || account.routing_relation === 'Peer' ||
+      Boolean(account.btp_uri || account.http_endpoint);
+  }
+
+  function render(data) {
+    var connections = {};
+    data.connections.forEach(function (connection) {
+      connections[connection.account_id] = connection;
+    });
+    var now = Date.now() / 1000;
+
+    fillTable('peers', data.accounts.filter(function (account) {
+      return isPeer(account) || connections[String(account.id)];
+    }).map(function (account) {
+      var id = String(account.id);
+      var connection = connections[id];
+      var windows = data.maintenance[id] || [];
+      var inMaintenance = windows.filter(function (window) {
+        return window.start <= now && now < window.end;
+      })[0];
+      return [
+        cell(id),
+        cell(account.ilp_address),
+        cell(account.routing_relation),
+        connection
+          ? cell('Connected since ' + time(connection.connected_at), 'up')
+          : cell(account.btp_uri ? 'Disconnected' : 'HTTP', account.btp_uri ? 'down' : 'muted'),
+        cell(connection ? connection.packets : ''),
+        inMaintenance
+          ? cell('Until ' + time(inMaintenance.end), 'down')
+          : cell(windows.length > 0 ? 'Scheduled' : '', 'muted')
+      ];
+    }), 'No peers');
+
+    fillTable('accounts', data.accounts.map(function (account) {
+      var balance = data.balances[String(account.id)];
+      return [
+        cell(account.id),
+        cell(account.ilp_address),
+        cell(account.asset_code + ' (scale ' + account.asset_scale + ')'),
+        cell(account.routing_relation),
+        cell(balance ? balance.display.formatted : 'Unavailable', 'amount')
+      ];
+    }), 'No accounts');
+
+    fillTable('routes', Object.keys(data.routes).sort().map(function (prefix) {
+      return [cell(prefix), cell(data.routes[prefix])];
+    }), 'No routes');
+
+    fillTable('payments', data.payments.map(function (payment) {
+      return [
+        cell(time(payment.updated_at)),
+        cell(payment.account_id),
+        cell(payment.connection_id),
+        cell(payment.tag),
+        cell(payment.metadata),
+        cell(payment.received_display ? payment.received_display.formatted : payment.received, 'amount'),
+        cell(payment.completed ? 'Yes' : 'No')
+      ];
+    }), 'No payments received');
+
+    fillTable('prefixes', data.prefixes.map(function (prefix) {
+      return [
+        cell(prefix.prefix),
+        cell(prefix.packets, 'amount'),
+        cell(prefix.fulfilled, 'amount'),
+        cell(Object.keys(prefix.amounts).sort().map(function (assetCode) {
+          return prefix.amounts[assetCode] + ' ' + assetCode;
+        }).join(', '), 'amount')
+      ];
+    }), 'No packets forwarded');
+  }
+
+  function load() {
+    return Promise.all([
+      api('/accounts'),
+      optional(api('/balances'), { balances: [] }),
+      optional(api('/payments?limit=' + RECENT_PAYMENTS), { payments: [] }),
+      optional(api('/routes'), {}),
+      optional(api('/connections'), { connections: [] }),
+      optional(api('/maintenance'), { accounts: {} }),
+      optional(api('/stats/prefixes'), { prefixes: [] })
+    ]).then(function (results) {
+      var balances = {};
+      results[1].balances.forEach(function (balance) {
+        balances[balance.account_id] = balance;
+      });
+      return {
+        accounts: results[0],
+        balances: balances,
+        payments: results[2].payments,
+        routes: results[3],
+        connections: results[4].connections,
+        maintenance: results[5].accounts,
+        prefixes: results[6].prefixes
+      };
+    });
+  }
+
+  function refresh() {
+    return load().then(function (data) {
+      render(data);
+      $('status').textContent = 'Updated ' + new Date().toLocaleTimeString();
+    }, function (error) {
+      if (error.unauthorized) {
+        clearInterval(refreshTimer);
+      }
+      $('status').textContent = error.message;
+    });
+  }
+
+  refresh();
+  refreshTimer = setInterval(refresh, REFRESH_INTERVAL);
+})();
@@ -0,0 +1,219 @@
+use super::{AccountDetails, FieldError, NodeConstraints};
+use serde::{Deserialize, Serialize};
+use serde_json::{self, Value};
+
+/// A full account record, as written to and read from the NDJSON account export.
+///
+/// The field names match the way the stores serialize their accounts so that any
+/// store's accounts can be exported, and the records can be imported into any store.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AccountRecord {
+    /// The account's ID in the store it was exported from. Accounts are assigned new IDs when imported
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<Value>,
+    pub ilp_address: String,
+    pub asset_code: String,
+    pub asset_scale: u8,
+    pub max_packet_amount: u64,
+    #[serde(default)]
+    pub max_packet_data: Option<u16>,
+    #[serde(default)]
+    pub priority: Option<String>,
+    #[serde(default)]
+    pub max_value_per_second: Option<u64>,
+    #[serde(default)]
+    pub inactivity_timeout: Option<u64>,
+    #[serde(default)]
+    pub owner: Option<String>,
+    #[serde(default)]
+    pub pending: bool,
+    #[serde(default = "i64::min_value")]
+    pub min_balance: i64,
+    #[serde(default)]
+    pub http_endpoint: Option<String>,
+    #[serde(default)]
+    pub http_incoming_authorization: Option<String>,
+    #[serde(default)]
+    pub http_outgoing_authorization: Option<String>,
+    #[serde(default)]
+    pub btp_uri: Option<String>,
+    #[serde(default)]
+    pub btp_incoming_authorization: Option<String>,
+    #[serde(default)]
+    pub is_admin: bool,
+    #[serde(default)]
+    pub read_only: bool,
+    #[serde(default)]
+    pub xrp_address: Option<String>,
+    #[serde(default)]
+    pub settle_threshold: Option<i64>,
+    #[serde(default)]
+    pub settle_to: Option<i64>,
+    #[serde(default)]
+    pub send_routes: bool,
+    #[serde(default)]
+    pub receive_routes: bool,
+    #[serde(default)]
+    pub routing_relation: Option<String>,
+    /// The balance when the account was exported. This is informational only and is not imported
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub balance: Option<i64>,
+}
+
+impl AccountRecord {
+    /// Convert a serialized account into a record, optionally removing the credentials.
+    pub fn from_account<A: Serialize>(
+        account: &A,
+        balance: Option<i64>,
+        include_secrets: bool,
+    ) -> Result<Self, serde_json::Error> {
+        let mut record: AccountRecord = serde_json::from_value(serde_json::to_value(account)?)?;
+        record.balance = balance;
+        if !include_secrets {
+            record.http_incoming_authorization = None;
+            record.http_outgoing_authorization = None;
+            record.btp_incoming_authorization = None;
+            // The BTP URI may include the outgoing auth token
+            record.btp_uri = record.btp_uri.map(|uri| remove_credentials(&uri));
+        }
+        Ok(record)
+    }
+
+    pub fn into_details(self) -> AccountDetails {
+        AccountDetails {
+            ilp_address: self.ilp_address.into_bytes(),
+            asset_code: self.asset_code,
+            asset_scale: self.asset_scale,
+            max_packet_amount: self.max_packet_amount,
+            max_packet_data: self.max_packet_data,
+            priority: self.priority,
+            max_value_per_second: self.max_value_per_second,
+            inactivity_timeout: self.inactivity_timeout,
+            owner: self.owner,
+            pending: self.pending,
+            min_balance: self.min_balance,
+            http_endpoint: self.http_endpoint,
+            http_incoming_authorization: self.http_incoming_authorization,
+            http_outgoing_authorization: self.http_outgoing_authorization,
+            btp_uri: self.btp_uri,
+            btp_incoming_authorization: self.btp_incoming_authorization,
+            is_admin: self.is_admin,
+            read_only: self.read_only,
+            xrp_address: self.xrp_address,
+            settle_threshold: self.settle_threshold,
+            settle_to: self.settle_to,
+            send_routes: self.send_routes,
+            receive_routes: self.receive_routes,
+            routing_relation: self.routing_relation,
+        }
+    }
+}
+
+fn remove_credentials(uri: &str) -> String {
+    if let Ok(mut url) = url::Url::parse(uri) {
+        let _ = url.set_username("");
+        let _ = url.set_password(None);
+        url.into_string()
+    } else {
+        uri.to_string()
+    }
+}
+
+/// A problem with one of the lines of an account import.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ImportError {
+    /// Line number, starting from 1
+    pub line: usize,
+    pub errors: Vec<FieldError>,
+}
+
+/// Parse and validate every line of an NDJSON account import.
+/// Blank lines are skipped. If any line is invalid, all of the problems are returned.
+pub fn parse_import(
+    body: &str,
+    constraints: &NodeConstraints,
+) -> Result<Vec<AccountDetails>, Vec<ImportError>> {
+    let mut accounts = Vec::new();
+    let mut import_errors = Vec::new();
+    for (index, line) in body.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let result = serde_json::from_str::<AccountRecord>(line)
+            .map_err(|err| {
+                vec![FieldError {
+                    field: "record",
+                    message: err.to_string(),
+                }]
+            })
+            .and_then(|record| {
+                let details = record.into_details();
+                details
+                    .validate()
+                    .and_then(|_| constraints.check_account(&details))
+                    .map(|_| details)
+            });
+        match result {
+            Ok(details) => accounts.push(details),
+            Err(errors) => import_errors.push(ImportError {
+                line: index + 1,
+                errors,
+            }),
+        }
+    }
+    if import_errors.is_empty() {
+        Ok(accounts)
+    } else {
+        Err(import_errors)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn removes_secrets() {
+        let account = json!({
+            "id": 1,
+            "ilp_address": "example.alice",
+            "asset_code": "XYZ",
+            "asset_scale": 9,
+            "max_packet_amount": 100,
+            "min_balance": -100,
+            "http_incoming_authorization": "Bearer secret",
+            "btp_uri": "btp+ws://:token@example.com/btp",
+            "is_admin": false,
+            "routing_relation": "Child",
+            "send_routes": false,
+            "receive_routes": false,
+        });
+        let record = AccountRecord::from_account(&account, Some(5), false).unwrap();
+        assert_eq!(record.http_incoming_authorization, None);
+        assert_eq!(record.btp_uri, Some("btp+ws://example.com/btp".to_string()));
+        assert_eq!(record.balance, Some(5));
+
+        let record = AccountRecord::from_account(&account, None, true).unwrap();
+        assert_eq!(
+            record.http_incoming_authorization,
+            Some("Bearer secret".to_string())
+        );
+    }
+
+    #[test]
+    fn reports_invalid_lines() {
+        let body = r#"{"ilp_address":"example.alice","asset_code":"XYZ","asset_scale":9,"max_packet_amount":100}
+
+{"ilp_address":"example.bob"}
+{"ilp_address":"example.carl","asset_code":"XYZ","asset_scale":9,"max_packet_amount":0}"#;
+        let errors = parse_import(body, &NodeConstraints::default()).unwrap_err();
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[0].line, 3);
+        assert_eq!(errors[1].line, 4);
+        assert_eq!(errors[1].errors[0].field, "max_packet_amount");
+
+        let accounts =
+            parse_import(body.lines().next().unwrap(), &NodeConstraints::default()).unwrap();
+        assert_eq!(accounts[0].ilp_address, b"example.alice".to_vec());
+    }
+}
@@ -0,0 +1,151 @@
+use super::NodeStore;
+use futures::Future;
+use interledger_service::{Account as AccountTrait, StoreError};
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+/// The balance of an account at a point in time.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BalanceSnapshot {
+    /// Seconds since the UNIX epoch
+    pub timestamp: u64,
+    pub balance: i64,
+}
+
+/// Store that periodically records the balance of every account so it can be graphed over time.
+pub trait BalanceHistoryStore: NodeStore {
+    /// Record the current balance of every account.
+    /// Snapshots older than the store's retention period are discarded.
+    fn snapshot_balances(&self) -> Box<Future<Item = (), Error = StoreError> + Send>;
+
+    /// Get the snapshots recorded for the account between `from` and `to` (inclusive, in seconds
+    /// since the UNIX epoch), oldest first.
+    fn get_balance_history(
+        &self,
+        account_id: <Self::Account as AccountTrait>::AccountId,
+        from: u64,
+        to: u64,
+    ) -> Box<Future<Item = Vec<BalanceSnapshot>, Error = StoreError> + Send>;
+}
+
+/// One point on a balance graph, covering `resolution` seconds starting at `timestamp`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct BalanceHistoryPoint {
+    pub timestamp: u64,
+    /// The last balance recorded in the interval
+    pub balance: i64,
+    pub min: i64,
+    pub max: i64,
+}
+
+/// Group the snapshots into intervals of `resolution` seconds, starting from `from`.
+/// Intervals without any snapshots are omitted.
+pub fn downsample(
+    snapshots: Vec<BalanceSnapshot>,
+    from: u64,
+    resolution: u64,
+) -> Vec<BalanceHistoryPoint> {
+    let resolution = resolution.max(1);
+    let mut points: Vec<BalanceHistoryPoint> = Vec::new();
+    for snapshot in snapshots {
+        if snapshot.timestamp < from {
+            continue;
+        }
+        let timestamp = from + (snapshot.timestamp - from) / resolution * resolution;
+        if let Some(point) = points.last_mut() {
+            if point.timestamp == timestamp {
+                point.balance = snapshot.balance;
+                point.min = point.min.min(snapshot.balance);
+                point.max = point.max.max(snapshot.balance);
+                continue;
+            }
+        }
+        points.push(BalanceHistoryPoint {
+            timestamp,
+            balance: snapshot.balance,
+            min: snapshot.balance,
+            max: snapshot.balance,
+        });
+    }
+    points
+}
+
+/// Add up the snapshots of several accounts that were recorded at the same time,
+/// to get the history of their combined balance.
+pub fn sum_snapshots(histories: Vec<Vec<BalanceSnapshot>>) -> Vec<BalanceSnapshot> {
+    let mut totals: BTreeMap<u64, i64> = BTreeMap::new();
+    for snapshot in histories.into_iter().flatten() {
+        let total = totals.entry(snapshot.timestamp).or_insert(0);
+        *total = total.saturating_add(snapshot.balance);
+    }
+    totals
+        .into_iter()
+        .map(|(timestamp, balance)| BalanceSnapshot { timestamp, balance })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot(timestamp: u64, balance: i64) -> BalanceSnapshot {
+        BalanceSnapshot { timestamp, balance }
+    }
+
+    #[test]
+    fn groups_snapshots_into_intervals() {
+        let points = downsample(
+            vec![
+                snapshot(100, 5),
+                snapshot(130, -10),
+                snapshot(159, 20),
+                snapshot(160, 7),
+                snapshot(250, 3),
+            ],
+            100,
+            60,
+        );
+        assert_eq!(
+            points,
+            vec![
+                BalanceHistoryPoint {
+                    timestamp: 100,
+                    balance: 20,
+                    min: -10,
+                    max: 20,
+                },
+                BalanceHistoryPoint {
+                    timestamp: 160,
+                    balance: 7,
+                    min: 7,
+                    max: 7,
+                },
+                BalanceHistoryPoint {
+                    timestamp: 220,
+                    balance: 3,
+                    min: 3,
+                    max: 3,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn sums_snapshots_recorded_at_the_same_time() {
+        let total = sum_snapshots(vec![
+            vec![snapshot(100, 5), snapshot(160, 7)],
+            vec![snapshot(160, -2), snapshot(220, 1)],
+        ]);
+        assert_eq!(
+            total,
+            vec![snapshot(100, 5), snapshot(160, 5), snapshot(220, 1)]
+        );
+    }
+
+    #[test]
+    fn ignores_snapshots_before_start() {
+        let points = downsample(vec![snapshot(10, 1), snapshot(20, 2)], 15, 1);
+        assert_eq!(points.len(), 1);
+        assert_eq!(points[0].timestamp, 20);
+    }
+}
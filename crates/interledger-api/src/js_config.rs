@@ -0,0 +1,373 @@
+use super::export::AccountRecord;
+use serde::Serialize;
+use serde_json::{self, Value};
+use std::str::FromStr;
+
+// The JS connector exchanges routes with every account that is not a child by default
+static CHILD_RELATION: &str = "child";
+// moneyd's uplinks use asset scale 9 unless they say otherwise
+const MONEYD_ASSET_SCALE: u8 = 9;
+
+/// A problem with one of the accounts in a moneyd or ilp-connector config.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct JsConfigError {
+    /// The account's name in the config, or an empty string for problems with the whole file
+    pub account: String,
+    pub message: String,
+}
+
+/// Convert the accounts in a moneyd config (`~/.moneyd.json`) or an ilp-connector config
+/// (the JSON object with an `accounts` field) into records that can be sent to the
+/// account import endpoint.
+///
+/// Accounts are given the ILP address configured in their `ilpAddress` field or,
+/// if there is none, `{ilp_address}.{account name}`, where `ilp_address` defaults to the
+/// connector's own `ilpAddress`. Plugins that serve many accounts from one listener
+/// (mini-accounts and the XRP asymmetric server) cannot be mapped to a single account
+/// and are reported as errors, as are any other accounts that cannot be converted.
+pub fn records_from_js_config(
+    config: &str,
+    ilp_address: Option<&str>,
+) -> Result<Vec<AccountRecord>, Vec<JsConfigError>> {
+    let config: Value = serde_json::from_str(config).map_err(|err| {
+        vec![JsConfigError {
+            account: String::new(),
+            message: format!("Config is not valid JSON: {}", err),
+        }]
+    })?;
+    let ilp_address = ilp_address.or_else(|| config["ilpAddress"].as_str());
+
+    let mut records = Vec::new();
+    let mut errors = Vec::new();
+    let mut convert = |name: &str, result: Result<AccountRecord, String>| match result {
+        Ok(record) => records.push(record),
+        Err(message) => errors.push(JsConfigError {
+            account: name.to_string(),
+            message,
+        }),
+    };
+    match (config["accounts"].as_object(), config.as_object()) {
+        (Some(accounts), _) => {
+            for (name, account) in accounts.iter() {
+                convert(name, connector_account(name, account, ilp_address));
+            }
+        }
+        (None, Some(uplinks)) => {
+            // Everything except the version in a moneyd config is an uplink, keyed by currency
+            for (name, uplink) in uplinks.iter().filter(|(name, _)| *name != "version") {
+                convert(name, moneyd_uplink(name, uplink, ilp_address));
+            }
+        }
+        (None, None) => convert(
+            "",
+            Err(
+                "Config must be a JSON object with moneyd uplinks or connector accounts"
+                    .to_string(),
+            ),
+        ),
+    }
+
+    if errors.is_empty() {
+        Ok(records)
+    } else {
+        Err(errors)
+    }
+}
+
+fn connector_account(
+    name: &str,
+    account: &Value,
+    ilp_address: Option<&str>,
+) -> Result<AccountRecord, String> {
+    let relation = account["relation"]
+        .as_str()
+        .ok_or_else(|| "relation is required".to_string())?;
+    let routing_relation = match relation {
+        "parent" => "Parent",
+        "peer" => "Peer",
+        "child" => "Child",
+        _ => return Err(format!("Unknown relation: {}", relation)),
+    };
+    let plugin = account["plugin"].as_str().unwrap_or("");
+    if plugin.ends_with("mini-accounts") || plugin.ends_with("asym-server") {
+        return Err(format!(
+            "{} serves many accounts, add each of them individually instead",
+            plugin
+        ));
+    }
+    let asset_code = account["assetCode"]
+        .as_str()
+        .ok_or_else(|| "assetCode is required".to_string())?;
+    let asset_scale = parse_number::<u8>(&account["assetScale"], "assetScale")?
+        .ok_or_else(|| "assetScale is required".to_string())?;
+
+    let mut record = new_record(
+        account_address(name, account, ilp_address)?,
+        asset_code.to_string(),
+        asset_scale,
+    );
+    record.routing_relation = Some(routing_relation.to_string());
+    record.send_routes = account["sendRoutes"]
+        .as_bool()
+        .unwrap_or(relation != CHILD_RELATION);
+    record.receive_routes = account["receiveRoutes"]
+        .as_bool()
+        .unwrap_or(relation != CHILD_RELATION);
+    if let Some(max_packet_amount) = parse_number(&account["maxPacketAmount"], "maxPacketAmount")? {
+        record.max_packet_amount = max_packet_amount;
+    }
+    // The JS connector gives accounts without a minimum unlimited credit. Imported accounts
+    // get none unless the config sets a minimum, and that minimum must be a real limit
+    let balance = &account["balance"];
+    if let Some(minimum) = parse_number::<i64>(&balance["minimum"], "balance.minimum")? {
+        if minimum == i64::min_value() {
+            return Err("balance.minimum must be greater than the smallest i64".to_string());
+        }
+        record.min_balance = minimum;
+    }
+    record.settle_threshold = parse_number(&balance["settleThreshold"], "balance.settleThreshold")?;
+    record.settle_to = parse_number(&balance["settleTo"], "balance.settleTo")?;
+
+    // ilp-plugin-btp and the XRP payment channel plugins either connect to a BTP server
+    // or listen for the peer to connect with a shared secret. ilp-plugin-http has an
+    // incoming and an outgoing side with their own secrets
+    let options = &account["options"];
+    record.btp_uri = options["server"].as_str().map(|uri| uri.to_string());
+    record.btp_incoming_authorization = options["listener"]["secret"]
+        .as_str()
+        .map(|secret| secret.to_string());
+    record.http_endpoint = options["outgoing"]["url"]
+        .as_str()
+        .map(|url| url.to_string());
+    record.http_outgoing_authorization = options["outgoing"]["secret"]
+        .as_str()
+        .map(|secret| format!("Bearer {}", secret));
+    record.http_incoming_authorization = options["incoming"]["secret"]
+        .as_str()
+        .map(|secret| format!("Bearer {}", secret));
+    record.xrp_address = options["peerAddress"]
+        .as_str()
+        .map(|address| address.to_string());
+
+    if record.btp_uri.is_none()
+        && record.btp_incoming_authorization.is_none()
+        && record.http_endpoint.is_none()
+        && record.http_incoming_authorization.is_none()
+    {
+        return Err(format!(
+            "Plugin {} has no BTP server, BTP listener secret or HTTP endpoint",
+            plugin
+        ));
+    }
+    Ok(record)
+}
+
+fn moneyd_uplink(
+    name: &str,
+    uplink: &Value,
+    ilp_address: Option<&str>,
+) -> Result<AccountRecord, String> {
+    let parent = uplink["parent"]
+        .as_str()
+        .ok_or_else(|| "parent is required".to_string())?;
+    let asset_scale =
+        parse_number(&uplink["assetScale"], "assetScale")?.unwrap_or(MONEYD_ASSET_SCALE);
+    let mut record = new_record(
+        account_address(name, uplink, ilp_address)?,
+        name.to_uppercase(),
+        asset_scale,
+    );
+    // moneyd gets its routes from the parent over ILDCP rather than by exchanging them
+    record.routing_relation = Some("Parent".to_string());
+    record.btp_uri = Some(parent.to_string());
+    Ok(record)
+}
+
+fn account_address(
+    name: &str,
+    account: &Value,
+    ilp_address: Option<&str>,
+) -> Result<String, String> {
+    if let Some(address) = account["ilpAddress"].as_str() {
+        Ok(address.to_string())
+    } else if let Some(prefix) = ilp_address {
+        Ok(format!("{}.{}", prefix, name))
+    } else {
+        Err("ilpAddress is required because no ILP address prefix was given".to_string())
+    }
+}
+
+fn new_record(ilp_address: String, asset_code: String, asset_scale: u8) -> AccountRecord {
+    AccountRecord {
+        id: None,
+        ilp_address,
+        asset_code,
+        asset_scale,
+        max_packet_amount: u64::max_value(),
+        max_packet_data: None,
+        priority: None,
+        max_value_per_second: None,
+        inactivity_timeout: None,
+        owner: None,
+        pending: false,
+        min_balance: 0,
+        http_endpoint: None,
+        http_incoming_authorization: None,
+        http_outgoing_authorization: None,
+        btp_uri: None,
+        btp_incoming_authorization: None,
+        is_admin: false,
+        read_only: false,
+        xrp_address: None,
+        settle_threshold: None,
+        settle_to: None,
+        send_routes: false,
+        receive_routes: false,
+        routing_relation: None,
+        balance: None,
+    }
+}
+
+// The JS configs use strings for amounts so they can be larger than JS numbers allow
+fn parse_number<T: FromStr>(value: &Value, field: &str) -> Result<Option<T>, String> {
+    let number = match value {
+        Value::Null => return Ok(None),
+        Value::String(string) => T::from_str(string),
+        Value::Number(number) => T::from_str(&number.to_string()),
+        _ => return Err(format!("{} must be a number", field)),
+    };
+    number
+        .map(Some)
+        .map_err(|_| format!("{} is not a valid number", field))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_connector_accounts() {
+        let config = r#"{
+            "ilpAddress": "g.connector",
+            "accounts": {
+                "alice": {
+                    "relation": "child",
+                    "assetCode": "XRP",
+                    "assetScale": 9,
+                    "plugin": "ilp-plugin-btp",
+                    "options": { "listener": { "port": 7768, "secret": "alice_secret" } },
+                    "balance": { "minimum": "-1000", "settleThreshold": "-500", "settleTo": "0" }
+                },
+                "bob": {
+                    "relation": "peer",
+                    "assetCode": "USD",
+                    "assetScale": 2,
+                    "maxPacketAmount": "100",
+                    "plugin": "ilp-plugin-http",
+                    "ilpAddress": "g.bob",
+                    "options": {
+                        "incoming": { "port": 3000, "secret": "incoming_secret" },
+                        "outgoing": { "url": "https://bob.example/ilp", "secret": "outgoing_secret" }
+                    }
+                }
+            }
+        }"#;
+        let records = records_from_js_config(config, None).unwrap();
+        assert_eq!(records.len(), 2);
+
+        assert_eq!(records[0].ilp_address, "g.connector.alice");
+        assert_eq!(records[0].asset_code, "XRP");
+        assert_eq!(
+            records[0].btp_incoming_authorization,
+            Some("alice_secret".to_string())
+        );
+        assert_eq!(records[0].min_balance, -1000);
+        assert_eq!(records[0].settle_threshold, Some(-500));
+        assert_eq!(records[0].routing_relation, Some("Child".to_string()));
+        assert!(!records[0].send_routes);
+
+        assert_eq!(records[1].ilp_address, "g.bob");
+        assert_eq!(records[1].min_balance, 0);
+        assert_eq!(records[1].max_packet_amount, 100);
+        assert_eq!(
+            records[1].http_endpoint,
+            Some("https://bob.example/ilp".to_string())
+        );
+        assert_eq!(
+            records[1].http_outgoing_authorization,
+            Some("Bearer outgoing_secret".to_string())
+        );
+        assert_eq!(
+            records[1].http_incoming_authorization,
+            Some("Bearer incoming_secret".to_string())
+        );
+        assert!(records[1].send_routes && records[1].receive_routes);
+    }
+
+    #[test]
+    fn converts_moneyd_uplinks() {
+        let config = r#"{
+            "version": 1,
+            "xrp": { "parent": "btp+wss://:token@parent.example", "secret": "s123" }
+        }"#;
+        let records = records_from_js_config(config, Some("private.moneyd")).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].ilp_address, "private.moneyd.xrp");
+        assert_eq!(records[0].asset_code, "XRP");
+        assert_eq!(records[0].asset_scale, 9);
+        assert_eq!(
+            records[0].btp_uri,
+            Some("btp+wss://:token@parent.example".to_string())
+        );
+        assert_eq!(records[0].routing_relation, Some("Parent".to_string()));
+        assert_eq!(records[0].min_balance, 0);
+    }
+
+    #[test]
+    fn rejects_unlimited_minimum_balance() {
+        let config = r#"{
+            "ilpAddress": "g.connector",
+            "accounts": {
+                "alice": {
+                    "relation": "child",
+                    "assetCode": "XRP",
+                    "assetScale": 9,
+                    "plugin": "ilp-plugin-btp",
+                    "options": { "listener": { "port": 7768, "secret": "alice_secret" } },
+                    "balance": { "minimum": "-9223372036854775808" }
+                }
+            }
+        }"#;
+        let errors = records_from_js_config(config, None).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].account, "alice");
+        assert!(errors[0].message.starts_with("balance.minimum"));
+    }
+
+    #[test]
+    fn reports_accounts_that_cannot_be_converted() {
+        let config = r#"{
+            "accounts": {
+                "children": {
+                    "relation": "child",
+                    "assetCode": "XRP",
+                    "assetScale": 9,
+                    "plugin": "ilp-plugin-mini-accounts",
+                    "options": { "port": 7768 }
+                },
+                "parent": {
+                    "relation": "parent",
+                    "assetCode": "XRP",
+                    "assetScale": 9,
+                    "plugin": "ilp-plugin-btp",
+                    "options": { "server": "btp+wss://:token@parent.example" }
+                }
+            }
+        }"#;
+        let errors = records_from_js_config(config, None).unwrap_err();
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[0].account, "children");
+        assert_eq!(errors[1].account, "parent");
+        assert!(errors[1].message.starts_with("ilpAddress is required"));
+    }
+}
@@ -1,4 +1,4 @@
-#![recursion_limit = "256"]
+#![recursion_limit = "512"]
 #[macro_use]
 extern crate tower_web;
 #[macro_use]
@@ -8,27 +8,98 @@ extern crate serde_json;
 
 use bytes::Bytes;
 use futures::{
-    future::{err, ok, result, Either},
-    Future,
+    future::{err, join_all, ok, result, Either},
+    stream, Future, Stream,
 };
 use http::{Request, Response};
 use hyper::{body::Body, error::Error};
 use interledger_http::{HttpAccount, HttpServerService, HttpStore};
 use interledger_ildcp::IldcpAccount;
+use interledger_packet::redact::Redacted;
 use interledger_router::RouterStore;
-use interledger_service::{Account as AccountTrait, IncomingService};
-use interledger_service_util::BalanceStore;
-use interledger_spsp::{pay, SpspResponder};
-use serde::Serialize;
+use interledger_service::{Account as AccountTrait, IncomingService, StoreError};
+use interledger_service_util::{
+    AccountStats, BalanceStore, DailyStats, ExchangeRateStore, FeePolicy, LatencyStage,
+    LatencyStats, MaintenanceWindow, PacketDataStats, PrefixStats, PriorityStats, StatsStore,
+};
+use interledger_spsp::{
+    check_payment_pointer, hosting_config, pay_with_client, pay_with_options, SpspBalance,
+    SpspResponder,
+};
+use interledger_stream::{PaymentEndState, PaymentOutcome, SendMoneyOptions};
+use reqwest::{r#async::Client, RedirectPolicy};
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap, VecDeque},
+    fmt,
     iter::FromIterator,
     str::{self, FromStr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use url::Url;
+
+mod access;
+mod api_keys;
+mod assets;
+mod batch;
+mod constraints;
+mod dashboard;
+mod export;
+mod history;
+mod js_config;
+mod payment_links;
+mod payments;
+mod refunds;
+mod runs;
+mod schedules;
+mod sessions;
+mod validation;
+pub use self::access::AccessMiddleware;
+pub use self::api_keys::{
+    api_key_token, required_scope, ApiKey, ApiKeyStore, ApiScope, API_KEY_PREFIX,
+};
+pub use self::assets::{AssetMetadata, DisplayAmount, SymbolPosition};
+pub use self::batch::{
+    parse_account_batch, parse_route_batch, BatchEntryResult, BatchError, StaticRouteEntry,
+};
+use self::batch::{rejected_batch, store_error_status};
+pub use self::constraints::NodeConstraints;
+use self::dashboard::{basic_auth_token, dashboard_asset, dashboard_response, sign_in_response};
+pub use self::export::{AccountRecord, ImportError};
+use self::export::parse_import;
+pub use self::history::{BalanceHistoryStore, BalanceSnapshot};
+use self::history::{downsample, sum_snapshots, BalanceHistoryPoint};
+pub use self::js_config::{records_from_js_config, JsConfigError};
+pub use self::payment_links::{
+    PaymentLink, PaymentLinkPolicy, PaymentLinkStatus, PaymentLinkStore, PAYMENT_LINK_TAG_PREFIX,
+};
+pub use self::payments::{IncomingPayment, PaymentDataRecorder, PaymentNotifier, PaymentStore};
+use self::payments::MAX_METADATA_LENGTH;
+pub use self::refunds::{Refund, RefundStatus, RefundStore};
+pub use self::schedules::{
+    PaymentScheduler, ScheduleStatus, ScheduleStore, ScheduledPayment, ScheduledRun,
+    MAX_SCHEDULED_PAYMENT_ATTEMPTS,
+};
+pub use self::sessions::{
+    SessionPayment, SessionStatus, StreamingSession, StreamingSessionRunner,
+    StreamingSessionStore, MAX_STREAMING_SESSION_FAILURES,
 };
+pub use self::validation::{validate_address, FieldError};
 
 pub trait NodeAccount: HttpAccount {
     fn is_admin(&self) -> bool;
+
+    /// The customer or other owner the account belongs to, if any.
+    fn owner(&self) -> Option<&str>;
+
+    /// Whether the account was created for a peering request that has not been approved yet.
+    fn is_pending(&self) -> bool;
+
+    /// Whether the account may only view data through the API, such as an account for a
+    /// dashboard or an auditor.
+    fn is_read_only(&self) -> bool;
 }
 
 pub trait NodeStore: Clone + Send + Sync + 'static {
@@ -37,16 +108,56 @@ pub trait NodeStore: Clone + Send + Sync + 'static {
     fn insert_account(
         &self,
         account: AccountDetails,
-    ) -> Box<Future<Item = Self::Account, Error = ()> + Send>;
+    ) -> Box<Future<Item = Self::Account, Error = StoreError> + Send>;
+
+    /// Insert all of the accounts in one atomic operation, or none of them if any is invalid or
+    /// conflicts with an existing account or another account in the batch. The errors of the
+    /// failed entries are returned with their positions in the batch.
+    fn insert_accounts(
+        &self,
+        accounts: Vec<AccountDetails>,
+    ) -> Box<Future<Item = Vec<Self::Account>, Error = BatchError> + Send>;
+
+    /// Replace the details of an existing account. Its asset code cannot be changed.
+    fn update_account(
+        &self,
+        account_id: <Self::Account as AccountTrait>::AccountId,
+        account: AccountDetails,
+    ) -> Box<Future<Item = Self::Account, Error = StoreError> + Send>;
+
+    /// Remove the account, its balance and the routes to it, returning the removed account.
+    fn delete_account(
+        &self,
+        account_id: <Self::Account as AccountTrait>::AccountId,
+    ) -> Box<Future<Item = Self::Account, Error = StoreError> + Send>;
+
+    /// Change the ILP address of the account and of its children: the accounts whose routing relation
+    /// is `Child` and whose addresses start with its own.
+    /// Packets for the previous addresses are still routed to the accounts for a grace period.
+    /// Returns the accounts whose addresses were changed, or fails with `StoreError::Conflict` without
+    /// changing any if one of the new addresses is already used by another account.
+    fn change_ilp_address(
+        &self,
+        account_id: <Self::Account as AccountTrait>::AccountId,
+        ilp_address: Bytes,
+    ) -> Box<Future<Item = Vec<Self::Account>, Error = StoreError> + Send>;
 
     // TODO limit the number of results and page through them
-    fn get_all_accounts(&self) -> Box<Future<Item = Vec<Self::Account>, Error = ()> + Send>;
+    fn get_all_accounts(
+        &self,
+    ) -> Box<Future<Item = Vec<Self::Account>, Error = StoreError> + Send>;
 
-    fn set_rates<R>(&self, rates: R) -> Box<Future<Item = (), Error = ()> + Send>
+    /// Get the accounts that belong to the owner, which is empty if it has none.
+    fn get_accounts_by_owner(
+        &self,
+        owner: &str,
+    ) -> Box<Future<Item = Vec<Self::Account>, Error = StoreError> + Send>;
+
+    fn set_rates<R>(&self, rates: R) -> Box<Future<Item = (), Error = StoreError> + Send>
     where
         R: IntoIterator<Item = (String, f64)>;
 
-    fn set_static_routes<R>(&self, routes: R) -> Box<Future<Item = (), Error = ()> + Send>
+    fn set_static_routes<R>(&self, routes: R) -> Box<Future<Item = (), Error = StoreError> + Send>
     where
         R: IntoIterator<Item = (String, <Self::Account as AccountTrait>::AccountId)>;
 
@@ -54,7 +165,222 @@ pub trait NodeStore: Clone + Send + Sync + 'static {
         &self,
         prefix: String,
         account_id: <Self::Account as AccountTrait>::AccountId,
-    ) -> Box<Future<Item = (), Error = ()> + Send>;
+    ) -> Box<Future<Item = (), Error = StoreError> + Send>;
+
+    /// Add or replace the static routes for the given prefixes in one atomic operation,
+    /// keeping the static routes for other prefixes. Fails for the entries whose account does not exist.
+    fn add_static_routes(
+        &self,
+        routes: Vec<(String, <Self::Account as AccountTrait>::AccountId)>,
+    ) -> Box<Future<Item = (), Error = BatchError> + Send>;
+
+    /// Get the recorded versions of the routing table, newest first.
+    fn get_route_history(&self)
+        -> Box<Future<Item = Vec<RouteVersion>, Error = StoreError> + Send>;
+
+    /// Restore the routes and static routes of a recorded version by undoing the changes made
+    /// since, which is recorded as a new version. Fails with `NotFound` if the version is no
+    /// longer in the history.
+    fn rollback_routes(
+        &self,
+        version: u64,
+    ) -> Box<Future<Item = RouteVersion, Error = StoreError> + Send>;
+
+    /// Replace the fee policies. The keys are either "account:{id}" or "prefix:{ILP address prefix}".
+    fn set_fee_policies<R>(&self, policies: R) -> Box<Future<Item = (), Error = StoreError> + Send>
+    where
+        R: IntoIterator<Item = (String, FeePolicy)>;
+
+    /// Get the scheduled maintenance windows of every account that has any.
+    fn get_maintenance_windows(
+        &self,
+    ) -> Box<
+        Future<
+                Item = Vec<(<Self::Account as AccountTrait>::AccountId, Vec<MaintenanceWindow>)>,
+                Error = StoreError,
+            > + Send,
+    >;
+
+    /// Replace the maintenance windows of the given account. An empty list removes them.
+    fn set_maintenance_windows(
+        &self,
+        account_id: <Self::Account as AccountTrait>::AccountId,
+        windows: Vec<MaintenanceWindow>,
+    ) -> Box<Future<Item = (), Error = StoreError> + Send>;
+
+    /// Get the settlement claims and receipts recorded for the given account, newest first.
+    fn get_claims(
+        &self,
+        account_id: <Self::Account as AccountTrait>::AccountId,
+    ) -> Box<Future<Item = Vec<SettlementClaim>, Error = StoreError> + Send>;
+
+    /// Count the data held by the store and estimate the memory it uses.
+    fn get_store_usage(&self) -> Box<Future<Item = StoreUsage, Error = StoreError> + Send>;
+}
+
+/// Registry of idempotency keys shared by every node that uses the same store,
+/// so that retried requests are only processed once even if they are handled by
+/// different instances.
+pub trait IdempotencyStore: Clone + Send + Sync + 'static {
+    /// Atomically claim the given key for `ttl` seconds.
+    /// Resolves to `true` if the key was newly claimed and `false` if it was already claimed.
+    fn claim_idempotency_key(
+        &self,
+        key: String,
+        ttl: u64,
+    ) -> Box<Future<Item = bool, Error = ()> + Send>;
+
+    /// Release a claimed key, for example because the request failed, so it can be retried.
+    fn release_idempotency_key(&self, key: String) -> Box<Future<Item = (), Error = ()> + Send>;
+}
+
+const IDEMPOTENCY_KEY_TTL: u64 = 24 * 60 * 60; // 1 day
+
+/// An open connection to a peer, such as a BTP WebSocket connection.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ConnectionInfo {
+    pub account_id: String,
+    pub remote_address: Option<String>,
+    /// Seconds since the UNIX epoch
+    pub connected_at: u64,
+    /// Number of ILP Prepare packets sent and received over the connection
+    pub packets: u64,
+    /// The protocol version negotiated with the peer
+    pub protocol_version: u16,
+    /// The optional protocol features enabled for the connection
+    pub features: Vec<String>,
+}
+
+/// Lets the API list and close the node's open peer connections.
+pub trait ConnectionRegistry {
+    fn connections(&self) -> Vec<ConnectionInfo>;
+
+    /// Close the connection for the given account.
+    /// Returns false if there was no open connection for that account.
+    fn disconnect(&self, account_id: &str) -> bool;
+}
+
+/// Notified when accounts' ILP addresses are changed through the API, so that services which
+/// use an address outside of the store (such as the node's route manager) can switch to the new one.
+pub trait AddressListener {
+    fn address_changed(&self, account_id: &str, ilp_address: &[u8]);
+}
+
+/// Failures injected into the node's packet handling to test how it and its peers cope with them.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Extract, Response)]
+pub struct FailureSettings {
+    /// Percentage (0-100) of packets to drop
+    pub drop_percent: u8,
+    /// Milliseconds to delay each packet by
+    pub delay: u64,
+}
+
+/// Lets admins inject failures into a running node through the API, for resilience testing.
+pub trait FailureInjector {
+    fn failures(&self) -> FailureSettings;
+
+    fn inject_failures(&self, settings: FailureSettings);
+}
+
+/// A signed payment channel claim or on-ledger payment receipt, recorded by the
+/// settlement engine so there is a proof of what was paid if there is a dispute.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SettlementClaim {
+    /// Either "incoming" (the peer paid us) or "outgoing" (we paid the peer)
+    pub direction: String,
+    pub ledger: String,
+    /// Amount in the ledger's base units. For payment channel claims this is the cumulative amount of the channel
+    pub amount: String,
+    pub channel: Option<String>,
+    pub signature: Option<String>,
+    pub transaction_id: Option<String>,
+    /// Milliseconds since the UNIX epoch
+    pub timestamp: u64,
+}
+
+/// What changed the routing table.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RouteSource {
+    /// A route update from a peer
+    Ccp,
+    /// The static routes were set through the API
+    Api,
+    /// An account was added or removed, or its address changed
+    Account,
+    /// An earlier version was restored
+    Rollback,
+}
+
+impl RouteSource {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RouteSource::Ccp => "ccp",
+            RouteSource::Api => "api",
+            RouteSource::Account => "account",
+            RouteSource::Rollback => "rollback",
+        }
+    }
+}
+
+/// A version of the routing table, recorded whenever the routes change so that a bad
+/// update can be rolled back. Only the prefixes that changed are recorded.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Response)]
+pub struct RouteVersion {
+    pub version: u64,
+    /// Milliseconds since the UNIX epoch
+    pub timestamp: u64,
+    pub source: RouteSource,
+    /// Changes to the routes learned from peers and the routes to the accounts, by prefix
+    pub routes: HashMap<String, RouteChange>,
+    /// Changes to the static routes, by prefix
+    pub static_routes: HashMap<String, RouteChange>,
+}
+
+/// The accounts a prefix was routed to before and after a change.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RouteChange {
+    /// The account the prefix was routed to before, unless the route was added
+    #[serde(default)]
+    pub old: Option<String>,
+    /// The account the prefix is routed to now, unless the route was removed
+    #[serde(default)]
+    pub new: Option<String>,
+}
+
+/// How much data the store holds, so operators can plan its capacity.
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Response)]
+pub struct StoreUsage {
+    /// Total number of keys
+    pub keys: u64,
+    /// Bytes of memory used by the store, if it reports it
+    pub used_memory: Option<u64>,
+    pub accounts: u64,
+    /// Number of account balances (one per account that has an asset)
+    pub balances: u64,
+    pub routes: u64,
+    pub static_routes: u64,
+    /// Balance snapshots recorded for the accounts' balance history
+    pub balance_snapshots: u64,
+    /// Incoming STREAM payments recorded for the accounts
+    pub payments: u64,
+    pub settlement_claims: u64,
+    /// Balance updates whose packets have not been fulfilled or rejected yet
+    pub in_flight: u64,
+    /// Previous ILP addresses that are still routed to accounts during their grace period
+    pub address_aliases: u64,
+    /// The keys grouped by the part of their name before the first colon (such as "accounts"
+    /// or "stream_connections"), largest first. These are estimated from a sample of the keys.
+    pub key_groups: Vec<KeyGroupUsage>,
+}
+
+/// Estimated size of one group of keys in the store.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct KeyGroupUsage {
+    pub name: String,
+    pub keys: u64,
+    /// Bytes, if the store reports the memory used by each key
+    pub memory: Option<u64>,
 }
 
 /// The Account type for the RedisStore.
@@ -64,6 +390,28 @@ pub struct AccountDetails {
     pub asset_code: String,
     pub asset_scale: u8,
     pub max_packet_amount: u64,
+    /// Largest data field (in bytes) of packets exchanged with this account, if limited
+    #[serde(default)]
+    pub max_packet_data: Option<u16>,
+    /// Priority class of the account's packets when the node is busy: "high", "normal" (the default) or "low"
+    #[serde(default)]
+    pub priority: Option<String>,
+    /// Average value per second (in the account's units) that packets to and from this account
+    /// are delayed to stay within, if limited
+    #[serde(default)]
+    pub max_value_per_second: Option<u64>,
+    /// Seconds without any packets to or from this account after which it is deleted,
+    /// as long as its balance is zero. Accounts do not expire if this is not set
+    #[serde(default)]
+    pub inactivity_timeout: Option<u64>,
+    /// The customer or other owner the account belongs to, so the balances of all of
+    /// an owner's accounts can be viewed together
+    #[serde(default)]
+    pub owner: Option<String>,
+    /// Whether the account was created for a peering request that an admin has not approved yet.
+    /// Pending accounts cannot send or receive packets and are not routed to
+    #[serde(default)]
+    pub pending: bool,
     #[serde(default = "i64::min_value")]
     pub min_balance: i64,
     pub http_endpoint: Option<String>,
@@ -72,6 +420,10 @@ pub struct AccountDetails {
     pub btp_uri: Option<String>,
     pub btp_incoming_authorization: Option<String>,
     pub is_admin: bool,
+    /// Whether the account may only view data through the API. Requests it makes to
+    /// endpoints that change anything are rejected with 403
+    #[serde(default)]
+    pub read_only: bool,
     pub xrp_address: Option<String>,
     pub settle_threshold: Option<i64>,
     pub settle_to: Option<i64>,
@@ -82,6 +434,55 @@ pub struct AccountDetails {
     pub routing_relation: Option<String>,
 }
 
+/// Map the error returned by the store to the HTTP status code to respond with.
+fn error_response(err: StoreError) -> Response<()> {
+    let status = match err {
+        StoreError::NotFound => 404,
+        StoreError::Conflict => 409,
+        StoreError::InvalidData => 400,
+        StoreError::InsufficientBalance => 422,
+        StoreError::StoreUnavailable => 503,
+    };
+    Response::builder().status(status).body(()).unwrap()
+}
+
+// The number of entries in a batch request body, if it is a JSON array
+fn batch_len(body: &str) -> usize {
+    serde_json::from_str::<Vec<Value>>(body).map_or(0, |entries| entries.len())
+}
+
+fn batch_response((status, results): (u16, Vec<BatchEntryResult>)) -> Response<String> {
+    Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .body(json!({ "results": results }).to_string())
+        .unwrap()
+}
+
+fn batch_error_response(len: usize, err: BatchError) -> Response<String> {
+    match err {
+        BatchError::Entries(errors) => {
+            debug!("Batch was not applied because of entries: {:?}", errors);
+            let failed = errors
+                .into_iter()
+                .map(|(index, err)| {
+                    let status = store_error_status(err);
+                    let errors = vec![FieldError {
+                        field: "entry",
+                        message: err.to_string(),
+                    }];
+                    (index, status, errors)
+                })
+                .collect();
+            batch_response(rejected_batch(len, failed))
+        }
+        BatchError::Store(err) => {
+            error!("Error applying batch: {:?}", err);
+            error_response(err).map(|_| String::new())
+        }
+    }
+}
+
 #[derive(Response)]
 #[web(status = "200")]
 struct ServerStatus {
@@ -104,19 +505,245 @@ struct Rates(Vec<(String, f64)>);
 #[derive(Response)]
 #[web(status = "200")]
 struct BalanceResponse {
+    /// In the account's base units
+    balance: String,
+    display: DisplayAmount,
+}
+
+#[derive(Serialize, Response)]
+#[web(status = "200")]
+struct ConnectionsResponse {
+    connections: Vec<ConnectionInfo>,
+}
+
+#[derive(Response)]
+#[web(status = "200")]
+struct DisconnectedResponse {
+    disconnected: usize,
+}
+
+#[derive(Extract)]
+struct ExportQuery {
+    include_secrets: Option<bool>,
+}
+
+#[derive(Extract)]
+struct BalanceHistoryQuery {
+    from: Option<u64>,
+    to: Option<u64>,
+    resolution: Option<u64>,
+}
+
+#[derive(Serialize, Response)]
+#[web(status = "200")]
+struct BalanceHistoryResponse {
+    history: Vec<BalanceHistoryPoint>,
+}
+
+const DEFAULT_BALANCE_HISTORY_PERIOD: u64 = 24 * 60 * 60; // 1 day
+const MIN_SCHEDULED_PAYMENT_INTERVAL: u64 = 60;
+const DEFAULT_PAYMENT_LINK_EXPIRY: u64 = 24 * 60 * 60; // 1 day
+
+#[derive(Serialize, Response)]
+#[web(status = "200")]
+struct ClaimsResponse {
+    claims: Vec<SettlementClaim>,
+}
+
+#[derive(Serialize, Response)]
+#[web(status = "200")]
+struct RouteHistoryResponse {
+    history: Vec<RouteVersion>,
+}
+
+#[derive(Serialize, Response)]
+#[web(status = "200")]
+struct PaymentsResponse {
+    payments: Vec<PaymentResponse>,
+}
+
+#[derive(Serialize)]
+struct PaymentResponse {
+    #[serde(flatten)]
+    payment: IncomingPayment,
+    /// Payments recorded before the asset was stored have no display amount
+    #[serde(skip_serializing_if = "Option::is_none")]
+    received_display: Option<DisplayAmount>,
+}
+
+impl From<IncomingPayment> for PaymentResponse {
+    fn from(payment: IncomingPayment) -> Self {
+        let received_display = match (&payment.asset_code, payment.asset_scale) {
+            (Some(asset_code), Some(asset_scale)) => Some(DisplayAmount::new(
+                i128::from(payment.received),
+                asset_code,
+                asset_scale,
+            )),
+            _ => None,
+        };
+        PaymentResponse {
+            payment,
+            received_display,
+        }
+    }
+}
+
+#[derive(Serialize, Response)]
+#[web(status = "200")]
+struct RecentPaymentsResponse {
+    payments: Vec<PaymentResponse>,
+}
+
+#[derive(Extract)]
+struct RecentPaymentsQuery {
+    limit: Option<usize>,
+}
+
+const DEFAULT_RECENT_PAYMENTS: usize = 20;
+const MAX_RECENT_PAYMENTS: usize = 100;
+
+#[derive(Serialize)]
+struct OwnedAccountBalance {
+    account_id: String,
+    /// In the account's base units
+    balance: String,
+    display: DisplayAmount,
+}
+
+/// The combined balance of an owner's accounts denominated in one asset
+#[derive(Serialize)]
+struct AssetBalance {
+    asset_code: String,
+    asset_scale: u8,
     balance: String,
+    display: DisplayAmount,
+}
+
+#[derive(Serialize, Response)]
+#[web(status = "200")]
+struct BalancesResponse {
+    balances: Vec<OwnedAccountBalance>,
+}
+
+#[derive(Serialize, Response)]
+#[web(status = "200")]
+struct OwnerBalanceResponse {
+    owner: String,
+    totals: Vec<AssetBalance>,
+    accounts: Vec<OwnedAccountBalance>,
+}
+
+#[derive(Serialize)]
+struct AssetBalanceHistory {
+    asset_code: String,
+    asset_scale: u8,
+    history: Vec<BalanceHistoryPoint>,
+}
+
+#[derive(Serialize, Response)]
+#[web(status = "200")]
+struct OwnerBalanceHistoryResponse {
+    owner: String,
+    assets: Vec<AssetBalanceHistory>,
+}
+
+#[derive(Extract)]
+struct PaymentPointerQuery {
+    payment_pointer: String,
+}
+
+#[derive(Extract)]
+struct ScheduledPaymentRequest {
+    receiver: String,
+    source_amount: u64,
+    /// Seconds since the UNIX epoch. By default, the payment is sent straight away
+    start_at: Option<u64>,
+    /// Repeat the payment this many seconds apart
+    interval: Option<u64>,
+}
+
+#[derive(Extract)]
+struct RefundRequest {
+    /// In the account's base units. By default, everything that has not been refunded yet is sent back
+    amount: Option<u64>,
+}
+
+#[derive(Extract)]
+struct PaymentLinkRequest {
+    /// In the receiving account's base units
+    amount: u64,
+    /// Seconds until the link expires. By default, links expire after a day
+    expires_in: Option<u64>,
+    description: Option<String>,
+}
+
+#[derive(Extract)]
+struct StreamingSessionRequest {
+    receiver: String,
+    /// In the sending account's base units
+    rate_per_minute: u64,
+}
+
+#[derive(Extract)]
+struct StreamingRateRequest {
+    rate_per_minute: u64,
 }
 
 #[derive(Extract)]
 struct SpspPayRequest {
     receiver: String,
     source_amount: u64,
+    /// Stop the payment if the exchange rate is this much worse than the node's rates (0.01 is 1%)
+    max_slippage: Option<f64>,
+    /// Give up after this many seconds
+    timeout: Option<u64>,
+    /// Give up if nothing is delivered for this many seconds
+    max_idle: Option<u64>,
+    /// Add a random suffix to the receiver's address so that intermediaries cannot match it to
+    /// other payments that use the same receiver details. The receiver must support this
+    randomize_address: Option<bool>,
+    /// Payment pointer for the receiver to send refunds to
+    return_pointer: Option<String>,
+    /// Application data for the receiver, such as an invoice or order ID. At most 256 bytes
+    metadata: Option<String>,
 }
 
 #[derive(Response)]
 #[web(status = "200")]
 struct SpspPayResponse {
     amount_delivered: u64,
+    source_amount_sent: u64,
+    /// "completed", "partial" or "failed"
+    end_state: &'static str,
+    /// Why a partial payment stopped
+    reason: Option<String>,
+    /// The ILP error code that made the payment fail
+    error_code: Option<String>,
+    fulfilled_packets: u64,
+    rejected_packets: HashMap<String, u64>,
+}
+
+impl From<PaymentOutcome> for SpspPayResponse {
+    fn from(outcome: PaymentOutcome) -> Self {
+        let (end_state, reason, error_code) = match outcome.end_state {
+            PaymentEndState::Completed => ("completed", None, None),
+            PaymentEndState::Partial(reason) => ("partial", Some(reason), None),
+            PaymentEndState::FailedWithCode(code) => ("failed", None, Some(code.to_string())),
+        };
+        SpspPayResponse {
+            amount_delivered: outcome.amount_delivered,
+            source_amount_sent: outcome.source_amount_sent,
+            end_state,
+            reason,
+            error_code,
+            fulfilled_packets: outcome.fulfilled_packets,
+            rejected_packets: outcome
+                .rejected_packets
+                .into_iter()
+                .map(|(code, count)| (code.to_string(), count))
+                .collect(),
+        }
+    }
 }
 
 #[derive(Response)]
@@ -130,133 +757,1495 @@ struct SpspQueryResponse {
 #[web(status = "200")]
 struct Routes(HashMap<String, String>);
 
-pub struct NodeApi<T, S> {
+#[derive(Extract)]
+struct Fees(HashMap<String, FeePolicy>);
+
+#[derive(Extract)]
+struct MaintenanceWindows(Vec<MaintenanceWindow>);
+
+#[derive(Serialize, Response)]
+#[web(status = "200")]
+struct MaintenanceResponse {
+    accounts: HashMap<String, Vec<MaintenanceWindow>>,
+}
+
+#[derive(Extract)]
+struct ApiKeyRequest {
+    name: String,
+    scopes: Vec<String>,
+}
+
+#[derive(Extract)]
+struct StatsQuery {
+    days: Option<usize>,
+}
+
+#[derive(Serialize, Default)]
+struct AssetTotals {
+    incoming: u64,
+    outgoing: u64,
+    fees: u64,
+    /// Incoming minus outgoing volume. This is the spread (including fees) earned
+    /// when the incoming and outgoing accounts use the same asset
+    net: i64,
+}
+
+#[derive(Serialize, Response)]
+#[web(status = "200")]
+struct RevenueResponse {
+    totals: HashMap<String, AssetTotals>,
+    daily: Vec<DailyStats>,
+}
+
+const DEFAULT_STATS_DAYS: usize = 7;
+
+#[derive(Extract)]
+struct PrefixStatsQuery {
+    limit: Option<usize>,
+}
+
+#[derive(Serialize, Response)]
+#[web(status = "200")]
+struct PrefixStatsResponse {
+    prefixes: Vec<PrefixStats>,
+}
+
+const DEFAULT_PREFIX_STATS_LIMIT: usize = 20;
+const MAX_PREFIX_STATS_LIMIT: usize = 1000;
+
+fn asset_totals(stats: &[AccountStats]) -> HashMap<String, AssetTotals> {
+    let mut totals: HashMap<String, AssetTotals> = HashMap::new();
+    for account in stats {
+        let total = totals.entry(account.asset_code.clone()).or_insert_with(Default::default);
+        total.incoming += account.incoming;
+        total.outgoing += account.outgoing;
+        total.fees += account.fees;
+        total.net += account.incoming as i64 - account.outgoing as i64;
+    }
+    totals
+}
+
+/// Group the values by the asset of the account they belong to, because amounts
+/// in different assets cannot be added up.
+fn group_by_asset<A: IldcpAccount, V>(values: Vec<(A, V)>) -> BTreeMap<(String, u8), Vec<V>> {
+    let mut groups: BTreeMap<(String, u8), Vec<V>> = BTreeMap::new();
+    for (account, value) in values {
+        groups
+            .entry((account.asset_code().to_string(), account.asset_scale()))
+            .or_insert_with(Vec::new)
+            .push(value);
+    }
+    groups
+}
+
+fn account_balances<A: IldcpAccount>(balances: &[(A, i64)]) -> Vec<OwnedAccountBalance> {
+    balances
+        .iter()
+        .map(|(account, balance)| OwnedAccountBalance {
+            account_id: account.id().to_string(),
+            balance: balance.to_string(),
+            display: DisplayAmount::new(
+                i128::from(*balance),
+                account.asset_code(),
+                account.asset_scale(),
+            ),
+        })
+        .collect()
+}
+
+fn owner_balance<A: IldcpAccount>(owner: String, balances: Vec<(A, i64)>) -> OwnerBalanceResponse {
+    let accounts = account_balances(&balances);
+    let totals = group_by_asset(balances)
+        .into_iter()
+        .map(|((asset_code, asset_scale), balances)| {
+            let total: i128 = balances.into_iter().map(i128::from).sum();
+            AssetBalance {
+                display: DisplayAmount::new(total, &asset_code, asset_scale),
+                balance: total.to_string(),
+                asset_code,
+                asset_scale,
+            }
+        })
+        .collect();
+    OwnerBalanceResponse {
+        owner,
+        totals,
+        accounts,
+    }
+}
+
+/// Format the all-time stats as Prometheus counters
+fn prometheus_metrics(stats: &[AccountStats]) -> String {
+    let mut output = String::new();
+    for (name, help) in &[
+        ("ilp_forwarded_incoming_total", "Amount of incoming packets forwarded, in the account's base units"),
+        ("ilp_forwarded_outgoing_total", "Amount of outgoing packets forwarded, in the account's base units"),
+        ("ilp_fees_total", "Fees earned, in the incoming account's base units"),
+    ] {
+        output.push_str(&format!("# HELP {} {}\n# TYPE {} counter\n", name, help, name));
+        for account in stats {
+            let value = match *name {
+                "ilp_forwarded_incoming_total" => account.incoming,
+                "ilp_forwarded_outgoing_total" => account.outgoing,
+                _ => account.fees,
+            };
+            output.push_str(&format!(
+                "{}{{asset=\"{}\",account=\"{}\"}} {}\n",
+                name, account.asset_code, account.account_id, value
+            ));
+        }
+    }
+    output
+}
+
+/// Format whether each account with scheduled maintenance is currently in a maintenance window
+/// as a Prometheus gauge, so that alerts about those peers can be silenced
+fn maintenance_metrics<I: fmt::Display>(windows: &[(I, Vec<MaintenanceWindow>)], now: u64) -> String {
+    let mut output = String::from(
+        "# HELP ilp_account_in_maintenance Whether the account is in a scheduled maintenance window\n\
+         # TYPE ilp_account_in_maintenance gauge\n",
+    );
+    for (account_id, account_windows) in windows {
+        let in_maintenance = account_windows.iter().any(|window| window.contains(now));
+        output.push_str(&format!(
+            "ilp_account_in_maintenance{{account=\"{}\"}} {}\n",
+            account_id, in_maintenance as u8
+        ));
+    }
+    output
+}
+
+/// Format the counts of packets affected by the accounts' data limits as Prometheus counters
+fn packet_data_metrics(stats: &PacketDataStats) -> String {
+    format!(
+        "# HELP ilp_packet_data_rejected_total Prepare packets rejected because their data was too large\n\
+         # TYPE ilp_packet_data_rejected_total counter\n\
+         ilp_packet_data_rejected_total {}\n\
+         # HELP ilp_packet_data_stripped_total Fulfill and Reject packets whose data was removed because it was too large\n\
+         # TYPE ilp_packet_data_stripped_total counter\n\
+         ilp_packet_data_stripped_total {}\n",
+        stats.rejected(),
+        stats.stripped()
+    )
+}
+
+/// Format the numbers of queued, processed and rejected packets of each priority class
+/// as Prometheus metrics
+fn priority_metrics(stats: &PriorityStats) -> String {
+    let mut output = String::new();
+    for (name, metric_type, help) in &[
+        (
+            "ilp_priority_queued",
+            "gauge",
+            "Incoming packets waiting to be processed",
+        ),
+        (
+            "ilp_priority_processed_total",
+            "counter",
+            "Incoming packets processed",
+        ),
+        (
+            "ilp_priority_rejected_total",
+            "counter",
+            "Incoming packets rejected because the queue for their class was full",
+        ),
+    ] {
+        output.push_str(&format!(
+            "# HELP {} {}\n# TYPE {} {}\n",
+            name, help, name, metric_type
+        ));
+        for priority in PriorityStats::priorities() {
+            let value = match *name {
+                "ilp_priority_queued" => stats.queued(*priority),
+                "ilp_priority_processed_total" => stats.processed(*priority),
+                _ => stats.rejected(*priority),
+            };
+            output.push_str(&format!("{}{{class=\"{}\"}} {}\n", name, priority, value));
+        }
+    }
+    output
+}
+
+/// Format the time packets spent in each stage of the pipeline as Prometheus histograms
+fn latency_metrics(stats: &LatencyStats) -> String {
+    let name = "ilp_packet_stage_seconds";
+    let mut output = format!(
+        "# HELP {} Time packets spent in each stage of the pipeline, excluding the later stages\n\
+         # TYPE {} histogram\n",
+        name, name
+    );
+    for stage in LatencyStats::stages() {
+        for (bound, count) in stats.buckets(*stage) {
+            output.push_str(&format!(
+                "{}_bucket{{stage=\"{}\",le=\"{}\"}} {}\n",
+                name,
+                stage,
+                bound as f64 / 1_000_000.0,
+                count
+            ));
+        }
+        let count = stats.count(*stage);
+        let sum = stats.sum(*stage);
+        output.push_str(&format!(
+            "{}_bucket{{stage=\"{}\",le=\"+Inf\"}} {}\n\
+             {}_sum{{stage=\"{}\"}} {}\n\
+             {}_count{{stage=\"{}\"}} {}\n",
+            name,
+            stage,
+            count,
+            name,
+            stage,
+            sum.as_secs() as f64 + f64::from(sum.subsec_micros()) / 1_000_000.0,
+            name,
+            stage,
+            count
+        ));
+    }
+    output
+}
+
+/// An HttpStore that records how long the account lookups for ILP-over-HTTP requests take
+#[derive(Clone)]
+struct TimedHttpStore<T> {
     store: T,
-    incoming_handler: S,
-    server_secret: Bytes,
+    stats: Arc<LatencyStats>,
+}
+
+impl<T: HttpStore> HttpStore for TimedHttpStore<T> {
+    type Account = T::Account;
+
+    fn get_account_from_http_auth(
+        &self,
+        auth_header: &str,
+    ) -> Box<Future<Item = Self::Account, Error = ()> + Send> {
+        let stats = self.stats.clone();
+        let start = Instant::now();
+        Box::new(
+            self.store
+                .get_account_from_http_auth(auth_header)
+                .then(move |result| {
+                    stats.record(LatencyStage::Auth, start.elapsed());
+                    result
+                }),
+        )
+    }
 }
 
-impl_web! {
-    impl<T, S, A> NodeApi<T, S>
-    where T: NodeStore<Account = A> + HttpStore<Account = A> + BalanceStore<Account = A> + RouterStore,
-    S: IncomingService<A> + Clone + Send + Sync + 'static,
-    A: AccountTrait + HttpAccount + NodeAccount + IldcpAccount + Serialize + 'static,
+/// The URL of the node's SPSP endpoint for the given account
+fn spsp_url(public_url: &Url, account_id: &str) -> Url {
+    let mut url = public_url.clone();
+    if let Ok(mut segments) = url.path_segments_mut() {
+        segments.pop_if_empty().push("spsp").push(account_id);
+    }
+    url
+}
+
+/// Longer sub-receiver tags are rejected
+const MAX_SUB_RECEIVER_TAG_LENGTH: usize = 64;
+
+/// Split the ID in an SPSP URL such as `/spsp/1~invoice123` into the account ID
+/// and the sub-receiver tag
+fn split_sub_receiver_tag(id: &str) -> (&str, Option<&str>) {
+    match id.find('~') {
+        Some(index) => (&id[..index], Some(&id[index + 1..])),
+        None => (id, None),
+    }
+}
+
+/// The URL senders query to pay the payment link
+fn payment_link_url(public_url: &Url, link_id: &str) -> Url {
+    let mut url = public_url.clone();
+    if let Ok(mut segments) = url.path_segments_mut() {
+        segments.pop_if_empty().push("payment_links").push(link_id);
+    }
+    url
+}
+
+/// The number of peering requests the node accepts per `PEERING_REQUEST_WINDOW`, so that
+/// anyone who can reach the API cannot fill the store with pending accounts
+const MAX_PEERING_REQUESTS: usize = 10;
+const PEERING_REQUEST_WINDOW: Duration = Duration::from_secs(3600);
+
+pub struct NodeApi<T, S> {
+    store: T,
+    incoming_handler: S,
+    server_secret: Bytes,
+    public_url: Option<Url>,
+    constraints: NodeConstraints,
+    connections: Option<Arc<ConnectionRegistry + Send + Sync>>,
+    packet_data_stats: Option<Arc<PacketDataStats>>,
+    priority_stats: Option<Arc<PriorityStats>>,
+    latency_stats: Option<Arc<LatencyStats>>,
+    address_listener: Option<Arc<AddressListener + Send + Sync>>,
+    failure_injector: Option<Arc<FailureInjector + Send + Sync>>,
+    accept_peering_requests: bool,
+    peering_request_times: Arc<Mutex<VecDeque<Instant>>>,
+}
+
+impl_web! {
+    impl<T, S, A> NodeApi<T, S>
+    where T: NodeStore<Account = A> + HttpStore<Account = A> + BalanceStore<Account = A> + StatsStore<Account = A> + BalanceHistoryStore + PaymentStore + PaymentLinkStore + RefundStore + ScheduleStore + StreamingSessionStore + RouterStore + IdempotencyStore + ExchangeRateStore + ApiKeyStore,
+    S: IncomingService<A> + Clone + Send + Sync + 'static,
+    A: AccountTrait + HttpAccount + NodeAccount + IldcpAccount + Serialize + 'static,
+
+    {
+        pub fn new(server_secret: Bytes, store: T, incoming_handler: S) -> Self {
+            NodeApi {
+                store,
+                incoming_handler,
+                server_secret,
+                public_url: None,
+                constraints: NodeConstraints::default(),
+                connections: None,
+                packet_data_stats: None,
+                priority_stats: None,
+                latency_stats: None,
+                address_listener: None,
+                failure_injector: None,
+                accept_peering_requests: false,
+                peering_request_times: Arc::new(Mutex::new(VecDeque::new())),
+            }
+        }
+
+        // Set the node-wide constraints that new accounts and routes must follow.
+        pub fn constraints(mut self, constraints: NodeConstraints) -> Self {
+            self.constraints = constraints;
+            self
+        }
+
+        // Set the URL the node API is publicly reachable at, which payment pointers hosted
+        // on other domains redirect to. By default, the Host of the request is used.
+        pub fn public_url(mut self, url: Url) -> Self {
+            self.public_url = Some(url);
+            self
+        }
+
+        // Set the registry used to list and close peer connections via the API.
+        pub fn connection_registry<R>(mut self, registry: R) -> Self
+        where
+            R: ConnectionRegistry + Send + Sync + 'static,
+        {
+            self.connections = Some(Arc::new(registry));
+            self
+        }
+
+        // Include the counts of packets affected by the accounts' data limits in the metrics.
+        pub fn packet_data_stats(mut self, stats: Arc<PacketDataStats>) -> Self {
+            self.packet_data_stats = Some(stats);
+            self
+        }
+
+        // Include the numbers of queued, processed and rejected packets of each priority class in the metrics.
+        pub fn priority_stats(mut self, stats: Arc<PriorityStats>) -> Self {
+            self.priority_stats = Some(stats);
+            self
+        }
+
+        // Include the time packets spend in each stage of the pipeline in the metrics, and record
+        // how long the account lookups for ILP-over-HTTP requests take.
+        pub fn latency_stats(mut self, stats: Arc<LatencyStats>) -> Self {
+            self.latency_stats = Some(stats);
+            self
+        }
+
+        // Tell the given listener when accounts' ILP addresses are changed via the API.
+        pub fn address_listener<L>(mut self, listener: L) -> Self
+        where
+            L: AddressListener + Send + Sync + 'static,
+        {
+            self.address_listener = Some(Arc::new(listener));
+            self
+        }
+
+        // Let admins inject failures into the node with the `/chaos` endpoints.
+        // Without an injector, those endpoints respond with 404.
+        pub fn failure_injector<I>(mut self, injector: I) -> Self
+        where
+            I: FailureInjector + Send + Sync + 'static,
+        {
+            self.failure_injector = Some(Arc::new(injector));
+            self
+        }
+
+        // Let anyone request to peer with the node by posting their account details to
+        // `/peering_requests`. The accounts are created pending an admin's approval, and cannot
+        // send or receive packets until then. Without this, that endpoint responds with 404.
+        // At most 10 requests are accepted per hour, and the rest get 429 responses.
+        pub fn accept_peering_requests(mut self, accept: bool) -> Self {
+            self.accept_peering_requests = accept;
+            self
+        }
+
+        fn validate_admin(&self, authorization: String) -> impl Future<Item = T, Error = Response<()>> {
+            let store = self.store.clone();
+            self.store.get_account_from_http_auth(&authorization)
+                .and_then(|account| if account.is_admin() {
+                    Ok(store)
+                } else {
+                    Err(())
+                })
+                .map_err(|_| Response::builder().status(401).body(()).unwrap())
+        }
+
+        // Count a peering request against the limit, or return false if the limit was reached.
+        fn allow_peering_request(&self) -> bool {
+            let now = Instant::now();
+            let mut times = self.peering_request_times.lock().unwrap();
+            while times.front().map(|time| now.duration_since(*time) >= PEERING_REQUEST_WINDOW).unwrap_or(false) {
+                times.pop_front();
+            }
+            if times.len() < MAX_PEERING_REQUESTS {
+                times.push_back(now);
+                true
+            } else {
+                false
+            }
+        }
+
+        // Get the pending account with the given ID if the request is authorized by an admin.
+        fn pending_account(&self, id: String, authorization: String) -> impl Future<Item = (T, A), Error = Response<()>> {
+            self.validate_admin(authorization)
+                .and_then(move |store| {
+                    let account_id = A::AccountId::from_str(&id)
+                        .map_err(|_| Response::builder().status(400).body(()).unwrap())?;
+                    Ok((store, account_id))
+                })
+                .and_then(|(store, account_id)| store.get_accounts(vec![account_id])
+                    .map_err(|_| Response::builder().status(500).body(()).unwrap())
+                    .and_then(move |mut accounts| match accounts.pop() {
+                        Some(Some(ref account)) if account.is_pending() => Ok((store, account.clone())),
+                        _ => Err(Response::builder().status(404).body(()).unwrap()),
+                    }))
+        }
+
+        // Get the account with the given ID if the request is authorized by that account or an admin.
+        fn authorized_account(&self, id: String, authorization: String) -> impl Future<Item = A, Error = Response<()>> {
+            let store = self.store.clone();
+            let parsed_id: Result<A::AccountId, ()> = A::AccountId::from_str(&id).map_err(|_| error!("Invalid id"));
+            result(parsed_id)
+                .map_err(|_| Response::builder().status(400).body(()).unwrap())
+                .and_then(move |id| {
+                    store.clone().get_account_from_http_auth(&authorization)
+                        .and_then(move |account|
+                            if account.id() == id {
+                                Either::A(ok(account))
+                            } else if account.is_admin() {
+                                Either::B(store.get_accounts(vec![id]).and_then(|mut accounts| accounts.remove(0).ok_or(())))
+                            } else {
+                                Either::A(err(()))
+                            })
+                        .map_err(move |_| {
+                            debug!("No account found with auth: {}", Redacted(&authorization));
+                            Response::builder().status(401).body(()).unwrap()
+                        })
+                })
+        }
+
+        // Get the scheduled payment with the given ID if it was scheduled by the account
+        // and the request is authorized by that account or an admin.
+        fn scheduled_payment(&self, id: String, payment_id: String, authorization: String) -> impl Future<Item = (T, ScheduledPayment), Error = Response<()>> {
+            let store = self.store.clone();
+            self.authorized_account(id, authorization)
+                .and_then(move |account| u64::from_str(&payment_id)
+                    .map(|payment_id| (account, payment_id))
+                    .map_err(|_| Response::builder().status(400).body(()).unwrap()))
+                .and_then(move |(account, payment_id)| store.get_scheduled_payment(payment_id)
+                    .map_err(error_response)
+                    .and_then(move |payment| match payment {
+                        Some(payment) if payment.account_id == account.id().to_string() => Ok((store, payment)),
+                        _ => Err(Response::builder().status(404).body(()).unwrap()),
+                    }))
+        }
+
+        // Get the streaming session with the given ID if it belongs to the account
+        // and the request is authorized by that account or an admin.
+        fn streaming_session(&self, id: String, session_id: String, authorization: String) -> impl Future<Item = (T, StreamingSession), Error = Response<()>> {
+            let store = self.store.clone();
+            self.authorized_account(id, authorization)
+                .and_then(move |account| u64::from_str(&session_id)
+                    .map(|session_id| (account, session_id))
+                    .map_err(|_| Response::builder().status(400).body(()).unwrap()))
+                .and_then(move |(account, session_id)| store.get_streaming_session(session_id)
+                    .map_err(error_response)
+                    .and_then(move |session| match session {
+                        Some(session) if session.account_id == account.id().to_string() => Ok((store, session)),
+                        _ => Err(Response::builder().status(404).body(()).unwrap()),
+                    }))
+        }
+
+        // Get the payment link with the given ID if it belongs to the account
+        // and the request is authorized by that account or an admin.
+        fn payment_link(&self, id: String, link_id: String, authorization: String) -> impl Future<Item = PaymentLink, Error = Response<()>> {
+            let store = self.store.clone();
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+            self.authorized_account(id, authorization)
+                .and_then(move |account| store.get_payment_link(&link_id)
+                    .map_err(error_response)
+                    .and_then(move |link| match link {
+                        Some(link) if link.account_id == account.id().to_string() => Ok(link.check_expiry(now)),
+                        _ => Err(Response::builder().status(404).body(()).unwrap()),
+                    }))
+        }
+
+        // If the request included an Idempotency-Key header, make sure it has not been used before.
+        // Keys are scoped to the account's credentials (which the store hashes) so different users
+        // cannot collide. Resolves to the claimed key, which should be released if the request fails
+        // so it can be retried. Errors with the HTTP status code to respond with.
+        fn check_idempotency_key(&self, idempotency_key: Option<String>, authorization: &str) -> impl Future<Item = Option<String>, Error = u16> {
+            if let Some(key) = idempotency_key {
+                let claimed = format!("api:{}:{}", authorization, key);
+                Either::A(self.store.claim_idempotency_key(claimed.clone(), IDEMPOTENCY_KEY_TTL)
+                    .map_err(|_| 500)
+                    .and_then(move |is_new| if is_new {
+                        Ok(Some(claimed))
+                    } else {
+                        debug!("Rejecting request with duplicate idempotency key: {}", key);
+                        Err(409)
+                    }))
+            } else {
+                Either::B(ok(None))
+            }
+        }
+
+        #[get("/")]
+        #[content_type("application/json")]
+        fn get_root(&self) -> Result<ServerStatus, ()> {
+            Ok(ServerStatus {
+                status: "Ready".to_string(),
+            })
+        }
+
+        // The dashboard is behind the admin token like the endpoints it loads its data from.
+        // Browsers sign in to it with HTTP Basic auth, because a page cannot be loaded with a bearer token
+        #[get("/dashboard")]
+        fn get_dashboard(&self, authorization: Option<String>) -> impl Future<Item = Response<String>, Error = Response<()>> {
+            self.get_dashboard_file(String::new(), authorization)
+        }
+
+        #[get("/dashboard/:file")]
+        fn get_dashboard_file(&self, file: String, authorization: Option<String>) -> impl Future<Item = Response<String>, Error = Response<()>> {
+            let token = match authorization.as_ref().and_then(|authorization| basic_auth_token(authorization)) {
+                Some(token) => token,
+                None => return Either::A(err(sign_in_response())),
+            };
+            Either::B(self.validate_admin(format!("Bearer {}", token))
+                .map_err(|_| sign_in_response())
+                .and_then(move |_| dashboard_asset(&file, &token)
+                    .map(dashboard_response)
+                    .ok_or_else(|| Response::builder().status(404).body(()).unwrap())))
+        }
+
+        #[post("/accounts")]
+        #[content_type("application/json")]
+        fn post_accounts(&self, body: AccountDetails, authorization: String, idempotency_key: Option<String>) -> impl Future<Item = Value, Error = Response<String>> {
+            // TODO don't allow accounts to be overwritten
+            // TODO add option for non-admin signups (maybe with invite code)
+            let check_idempotency_key = self.check_idempotency_key(idempotency_key, &authorization);
+            let validation = body.validate()
+                .and_then(|_| self.constraints.check_account(&body))
+                .map_err(|errors| {
+                    debug!("Invalid account details: {:?}", errors);
+                    Response::builder()
+                        .status(400)
+                        .header("Content-Type", "application/json")
+                        .body(json!({ "errors": errors }).to_string())
+                        .unwrap()
+                });
+            self.validate_admin(authorization)
+                .map_err(|response| response.map(|_| String::new()))
+                .and_then(move |store| validation.and_then(|_| Ok(store)))
+                .and_then(move |store| check_idempotency_key
+                    .map_err(|status| Response::builder().status(status).body(String::new()).unwrap())
+                    .and_then(|claimed| Ok((store, claimed))))
+                .and_then(move |(store, claimed)| store.clone().insert_account(body)
+                // TODO make all Accounts (de)serializable with Serde so all the details can be returned here
+                .and_then(|account| Ok(json!(account)))
+                .or_else(move |error| {
+                    let response = error_response(error).map(|_| String::new());
+                    match claimed {
+                        Some(key) => Either::A(store.release_idempotency_key(key).then(move |_| Err(response))),
+                        None => Either::B(err(response)),
+                    }
+                }))
+        }
+
+        // Note this must come before the /accounts/:id route so "batch" is not treated as an ID
+        #[post("/accounts/batch")]
+        #[content_type("application/json")]
+        fn post_accounts_batch(&self, body: String, authorization: String) -> impl Future<Item = Value, Error = Response<String>> {
+            let accounts = parse_account_batch(&body, &self.constraints)
+                .map_err(|errors| {
+                    debug!("Invalid account batch: {:?}", errors);
+                    let len = batch_len(&body).max(errors.iter().map(|(index, _)| index + 1).max().unwrap_or(0));
+                    let failed = errors.into_iter().map(|(index, errors)| (index, 400, errors)).collect();
+                    batch_response(rejected_batch(len, failed))
+                });
+            self.validate_admin(authorization)
+                .map_err(|response| response.map(|_| String::new()))
+                .and_then(move |store| accounts.map(|accounts| (store, accounts)))
+                .and_then(|(store, accounts)| {
+                    let len = accounts.len();
+                    store.insert_accounts(accounts)
+                        .map_err(move |err| batch_error_response(len, err))
+                })
+                .and_then(|accounts| Ok(json!({
+                    "results": accounts.into_iter()
+                        .enumerate()
+                        .map(|(index, account)| BatchEntryResult::applied(index, json!(account)))
+                        .collect::<Vec<_>>()
+                })))
+        }
+
+        #[get("/accounts")]
+        #[content_type("application/json")]
+        fn get_accounts(&self, authorization: String) -> impl Future<Item = Value, Error = Response<()>> {
+            let store = self.store.clone();
+            self.store.get_account_from_http_auth(&authorization)
+                .map_err(move |_| {
+                    debug!("No account found with auth: {}", Redacted(&authorization));
+                    Response::builder().status(401).body(()).unwrap()
+                })
+                .and_then(move |account| if account.is_admin() {
+                    Either::A(store.get_all_accounts()
+                        .map_err(error_response))
+                } else {
+                    Either::B(store.get_accounts(vec![account.id()])
+                        .map_err(|_| Response::builder().status(500).body(()).unwrap())
+                        .and_then(|accounts| accounts.into_iter().collect::<Option<Vec<A>>>()
+                            .ok_or_else(|| Response::builder().status(404).body(()).unwrap())))
+                })
+                .and_then(|accounts| Ok(json!(accounts)))
+        }
+
+        // Note this must come before the /accounts/:id route so "export" is not treated as an ID
+        #[get("/accounts/export")]
+        fn get_accounts_export(&self, query_string: ExportQuery, authorization: String) -> impl Future<Item = Response<Body>, Error = Response<()>> {
+            let include_secrets = query_string.include_secrets.unwrap_or(false);
+            self.validate_admin(authorization)
+                .and_then(|store| store.get_all_accounts()
+                    .map_err(error_response)
+                    .and_then(move |accounts| {
+                        let balances = join_all(accounts.iter().map(|account| store.get_balance(account.clone())
+                            // Accounts without a balance are still exported
+                            .then(|result| -> Result<Option<i64>, Response<()>> { Ok(result.ok()) }))
+                            .collect::<Vec<_>>());
+                        balances.map(move |balances| (accounts, balances))
+                    }))
+                .and_then(move |(accounts, balances)| {
+                    let lines: Result<Vec<String>, serde_json::Error> = accounts.iter()
+                        .zip(balances.into_iter())
+                        .map(|(account, balance)| AccountRecord::from_account(account, balance, include_secrets)
+                            .and_then(|record| serde_json::to_string(&record))
+                            .map(|line| line + "\n"))
+                        .collect();
+                    lines.map_err(|err| {
+                        error!("Error serializing accounts for export: {:?}", err);
+                        Response::builder().status(500).body(()).unwrap()
+                    })
+                })
+                .and_then(|lines| Ok(Response::builder()
+                    .header("Content-Type", "application/x-ndjson")
+                    .body(Body::wrap_stream(stream::iter_ok::<_, Error>(lines)))
+                    .unwrap()))
+        }
+
+        #[post("/accounts/import")]
+        #[content_type("application/json")]
+        fn post_accounts_import(&self, body: String, authorization: String) -> impl Future<Item = Value, Error = Response<String>> {
+            let accounts = parse_import(&body, &self.constraints)
+                .map_err(|errors| {
+                    debug!("Invalid account import: {:?}", errors);
+                    Response::builder()
+                        .status(400)
+                        .header("Content-Type", "application/json")
+                        .body(json!({ "errors": errors }).to_string())
+                        .unwrap()
+                });
+            self.validate_admin(authorization)
+                .map_err(|response| response.map(|_| String::new()))
+                .and_then(move |store| accounts.map(|accounts| (store, accounts)))
+                .and_then(|(store, accounts)| {
+                    // Insert the accounts one at a time so they are assigned IDs in the same order as the export
+                    stream::iter_ok(accounts)
+                        .and_then(move |details| store.insert_account(details))
+                        .collect()
+                        .map_err(|err| error_response(err).map(|_| String::new()))
+                })
+                .and_then(|accounts| Ok(json!({ "imported": accounts.len() })))
+        }
+
+        #[get("/accounts/:id")]
+        #[content_type("application/json")]
+        fn get_account(&self, id: String, authorization: String) -> impl Future<Item = Value, Error = Response<()>> {
+            let store = self.store.clone();
+            let store_clone = store.clone();
+            let parsed_id: Result<A::AccountId, ()> = A::AccountId::from_str(&id).map_err(|_| error!("Invalid id"));
+            result(parsed_id)
+                .map_err(|_| Response::builder().status(400).body(()).unwrap())
+                .and_then(move |id| {
+                    store.clone().get_account_from_http_auth(&authorization)
+                        .and_then(move |account|
+                            if account.id() == id {
+                                Either::A(ok(json!(account)))
+                            } else if account.is_admin() {
+                                Either::B(store_clone.get_accounts(vec![id]).and_then(|mut accounts| accounts.remove(0).map(|account| json!(account)).ok_or(())))
+                            } else {
+                                Either::A(err(()))
+                            })
+                        .map_err(move |_| {
+                            debug!("No account found with auth: {}", Redacted(&authorization));
+                            Response::builder().status(401).body(()).unwrap()
+                        })
+                })
+        }
+
+        #[put("/accounts/:id")]
+        #[content_type("application/json")]
+        fn put_account(&self, id: String, body: AccountDetails, authorization: String) -> impl Future<Item = Value, Error = Response<String>> {
+            let validation = body.validate()
+                .and_then(|_| self.constraints.check_account(&body))
+                .map_err(|errors| {
+                    debug!("Invalid account details: {:?}", errors);
+                    Response::builder()
+                        .status(400)
+                        .header("Content-Type", "application/json")
+                        .body(json!({ "errors": errors }).to_string())
+                        .unwrap()
+                });
+            self.validate_admin(authorization)
+                .map_err(|response| response.map(|_| String::new()))
+                .and_then(move |store| {
+                    let account_id = A::AccountId::from_str(&id)
+                        .map_err(|_| Response::builder().status(400).body(String::new()).unwrap())?;
+                    validation.map(|_| (store, account_id))
+                })
+                .and_then(move |(store, account_id)| store.update_account(account_id, body)
+                    .and_then(|account| Ok(json!(account)))
+                    .map_err(|err| error_response(err).map(|_| String::new())))
+        }
+
+        #[delete("/accounts/:id")]
+        #[content_type("application/json")]
+        fn delete_account(&self, id: String, authorization: String) -> impl Future<Item = Value, Error = Response<()>> {
+            self.validate_admin(authorization)
+                .and_then(move |store| {
+                    let account_id = A::AccountId::from_str(&id)
+                        .map_err(|_| Response::builder().status(400).body(()).unwrap())?;
+                    Ok((store, account_id))
+                })
+                .and_then(|(store, account_id)| store.delete_account(account_id)
+                    .and_then(|account| Ok(json!(account)))
+                    .map_err(error_response))
+        }
+
+        // Anyone can request to peer with the node if it accepts peering requests, so the
+        // account cannot be an admin and stays pending until an admin approves it. The node
+        // does not connect out to the requester either, so it has to connect to us
+        #[post("/peering_requests")]
+        #[content_type("application/json")]
+        fn post_peering_request(&self, body: AccountDetails) -> impl Future<Item = Value, Error = Response<String>> {
+            let mut details = body;
+            details.is_admin = false;
+            details.pending = true;
+            details.btp_uri = None;
+            let accept = if self.accept_peering_requests {
+                Ok(())
+            } else {
+                Err(Response::builder().status(404).body(String::new()).unwrap())
+            };
+            let validation = details.validate()
+                .and_then(|_| self.constraints.check_account(&details))
+                .map_err(|errors| {
+                    debug!("Invalid peering request: {:?}", errors);
+                    Response::builder()
+                        .status(400)
+                        .header("Content-Type", "application/json")
+                        .body(json!({ "errors": errors }).to_string())
+                        .unwrap()
+                });
+            let limit = accept.and(validation).and_then(|_| if self.allow_peering_request() {
+                Ok(())
+            } else {
+                warn!("Rejecting peering request because too many were sent recently");
+                Err(Response::builder().status(429).body(String::new()).unwrap())
+            });
+            let store = self.store.clone();
+            result(limit)
+                .and_then(move |_| store.insert_account(details)
+                    .map_err(|err| error_response(err).map(|_| String::new())))
+                .and_then(|account| {
+                    info!("Got peering request for account {}", account.id());
+                    Ok(json!(account))
+                })
+        }
+
+        #[get("/peering_requests")]
+        #[content_type("application/json")]
+        fn get_peering_requests(&self, authorization: String) -> impl Future<Item = Value, Error = Response<()>> {
+            self.validate_admin(authorization)
+                .and_then(|store| store.get_all_accounts()
+                    .map_err(error_response))
+                .and_then(|accounts| {
+                    let pending: Vec<A> = accounts.into_iter()
+                        .filter(|account| account.is_pending())
+                        .collect();
+                    Ok(json!(pending))
+                })
+        }
+
+        // Approving a request lets the account send and receive packets, and adds the route to it
+        #[post("/peering_requests/:id/approve")]
+        #[content_type("application/json")]
+        fn approve_peering_request(&self, id: String, authorization: String) -> impl Future<Item = Value, Error = Response<()>> {
+            self.pending_account(id, authorization)
+                .and_then(|(store, account)| {
+                    let mut details = AccountRecord::from_account(&account, None, true)
+                        .map_err(|err| {
+                            error!("Unable to convert account to account details: {:?}", err);
+                            Response::builder().status(500).body(()).unwrap()
+                        })?
+                        .into_details();
+                    details.pending = false;
+                    Ok((store, account.id(), details))
+                })
+                .and_then(|(store, account_id, details)| store.update_account(account_id, details)
+                    .map_err(error_response))
+                .and_then(|account| {
+                    info!("Approved peering request for account {}", account.id());
+                    Ok(json!(account))
+                })
+        }
+
+        #[delete("/peering_requests/:id")]
+        #[content_type("application/json")]
+        fn reject_peering_request(&self, id: String, authorization: String) -> impl Future<Item = Value, Error = Response<()>> {
+            self.pending_account(id, authorization)
+                .and_then(|(store, account)| store.delete_account(account.id())
+                    .map_err(error_response))
+                .and_then(|account| {
+                    info!("Rejected peering request for account {}", account.id());
+                    Ok(json!(account))
+                })
+        }
+
+        // The account's children are given new addresses under its new one. The accounts with open
+        // connections are disconnected, so that they get their new address via ILDCP when they reconnect
+        #[put("/accounts/:id/ilp_address")]
+        #[content_type("application/json")]
+        fn put_ilp_address(&self, id: String, body: String, authorization: String) -> impl Future<Item = Value, Error = Response<()>> {
+            let registry = self.connections.clone();
+            let listener = self.address_listener.clone();
+            self.validate_admin(authorization)
+                .and_then(move |store| {
+                    let account_id = A::AccountId::from_str(&id)
+                        .map_err(|_| Response::builder().status(400).body(()).unwrap())?;
+                    if let Err(message) = validate_address(body.as_bytes()) {
+                        debug!("Invalid ILP address {}: {}", body, message);
+                        return Err(Response::builder().status(400).body(()).unwrap());
+                    }
+                    Ok((store, account_id, Bytes::from(body)))
+                })
+                .and_then(|(store, account_id, ilp_address)| store.change_ilp_address(account_id, ilp_address)
+                    .map_err(error_response))
+                .and_then(move |accounts| {
+                    for account in accounts.iter() {
+                        let account_id = account.id().to_string();
+                        if let Some(ref listener) = listener {
+                            listener.address_changed(&account_id, account.client_address());
+                        }
+                        if let Some(ref registry) = registry {
+                            registry.disconnect(&account_id);
+                        }
+                    }
+                    Ok(json!(accounts))
+                })
+        }
+
+        // TODO should this be combined into the account record?
+        #[get("/accounts/:id/balance")]
+        #[content_type("application/json")]
+        fn get_balance(&self, id: String, authorization: String) -> impl Future<Item = BalanceResponse, Error = Response<()>> {
+            let store = self.store.clone();
+            let store_clone = store.clone();
+            let parsed_id: Result<A::AccountId, ()> = A::AccountId::from_str(&id).map_err(|_| error!("Invalid id"));
+            result(parsed_id)
+                .map_err(|_| Response::builder().status(400).body(()).unwrap())
+                .and_then(move |id| {
+                    store.clone().get_account_from_http_auth(&authorization)
+                        .and_then(move |account|
+                            if account.id() == id {
+                                Either::A(ok(account))
+                            } else if account.is_admin() {
+                                Either::B(store_clone.get_accounts(vec![id]).and_then(|mut accounts| accounts.remove(0).ok_or(())))
+                            } else {
+                                Either::A(err(()))
+                            })
+                        .map_err(move |_| {
+                            debug!("No account found with auth: {}", Redacted(&authorization));
+                            Response::builder().status(401).body(()).unwrap()
+                        })
+                        .and_then(move |account| store.get_balance(account.clone())
+                        .and_then(move |balance| Ok(BalanceResponse {
+                            balance: balance.to_string(),
+                            display: DisplayAmount::new(i128::from(balance), account.asset_code(), account.asset_scale()),
+                        }))
+                        .map_err(error_response))
+                })
+        }
+
+        // The balances of all accounts in one request, for the dashboard
+        #[get("/balances")]
+        #[content_type("application/json")]
+        fn get_balances(&self, authorization: String) -> impl Future<Item = BalancesResponse, Error = Response<()>> {
+            self.validate_admin(authorization)
+                .and_then(move |store| store.get_all_accounts()
+                    .and_then(move |accounts| join_all(accounts.into_iter().map(move |account| store.get_balance(account.clone())
+                        .map(move |balance| (account, balance)))))
+                    .map_err(error_response))
+                .and_then(|balances| Ok(BalancesResponse {
+                    balances: account_balances(&balances),
+                }))
+        }
+
+        #[get("/accounts/:id/balance/history")]
+        #[content_type("application/json")]
+        fn get_balance_history(&self, id: String, query_string: BalanceHistoryQuery, authorization: String) -> impl Future<Item = BalanceHistoryResponse, Error = Response<()>> {
+            let store = self.store.clone();
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+            let to = query_string.to.unwrap_or(now);
+            let from = query_string.from.unwrap_or_else(|| to.saturating_sub(DEFAULT_BALANCE_HISTORY_PERIOD));
+            // Return every snapshot if no resolution is given
+            let resolution = query_string.resolution.unwrap_or(1);
+            let parsed_id: Result<A::AccountId, ()> = A::AccountId::from_str(&id).map_err(|_| error!("Invalid id"));
+            result(parsed_id)
+                .and_then(move |id| if from <= to && resolution > 0 {
+                    Ok(id)
+                } else {
+                    debug!("Invalid balance history query. from: {}, to: {}, resolution: {}", from, to, resolution);
+                    Err(())
+                })
+                .map_err(|_| Response::builder().status(400).body(()).unwrap())
+                .and_then(move |id| {
+                    store.clone().get_account_from_http_auth(&authorization)
+                        .and_then(move |account|
+                            if account.id() == id || account.is_admin() {
+                                Ok(id)
+                            } else {
+                                Err(())
+                            })
+                        .map_err(move |_| {
+                            debug!("No account found with auth: {}", Redacted(&authorization));
+                            Response::builder().status(401).body(()).unwrap()
+                        })
+                        .and_then(move |id| store.get_balance_history(id, from, to)
+                        .and_then(move |snapshots| Ok(BalanceHistoryResponse {
+                            history: downsample(snapshots, from, resolution),
+                        }))
+                        .map_err(error_response))
+                })
+        }
+
+        #[get("/accounts/:id/claims")]
+        #[content_type("application/json")]
+        fn get_claims(&self, id: String, authorization: String) -> impl Future<Item = ClaimsResponse, Error = Response<()>> {
+            let store = self.store.clone();
+            let parsed_id: Result<A::AccountId, ()> = A::AccountId::from_str(&id).map_err(|_| error!("Invalid id"));
+            result(parsed_id)
+                .map_err(|_| Response::builder().status(400).body(()).unwrap())
+                .and_then(move |id| {
+                    store.clone().get_account_from_http_auth(&authorization)
+                        .and_then(move |account|
+                            if account.id() == id || account.is_admin() {
+                                Ok(id)
+                            } else {
+                                Err(())
+                            })
+                        .map_err(move |_| {
+                            debug!("No account found with auth: {}", Redacted(&authorization));
+                            Response::builder().status(401).body(()).unwrap()
+                        })
+                        .and_then(move |id| store.get_claims(id)
+                        .and_then(|claims| Ok(ClaimsResponse { claims }))
+                        .map_err(error_response))
+                })
+        }
+
+        #[get("/accounts/:id/payments")]
+        #[content_type("application/json")]
+        fn get_payments(&self, id: String, authorization: String) -> impl Future<Item = PaymentsResponse, Error = Response<()>> {
+            let store = self.store.clone();
+            let parsed_id: Result<A::AccountId, ()> = A::AccountId::from_str(&id).map_err(|_| error!("Invalid id"));
+            result(parsed_id)
+                .map_err(|_| Response::builder().status(400).body(()).unwrap())
+                .and_then(move |id| {
+                    store.clone().get_account_from_http_auth(&authorization)
+                        .and_then(move |account|
+                            if account.id() == id || account.is_admin() {
+                                Ok(id)
+                            } else {
+                                Err(())
+                            })
+                        .map_err(move |_| {
+                            debug!("No account found with auth: {}", Redacted(&authorization));
+                            Response::builder().status(401).body(()).unwrap()
+                        })
+                        .and_then(move |id| store.get_incoming_payments(id)
+                        .and_then(|payments| Ok(PaymentsResponse {
+                            payments: payments.into_iter().map(PaymentResponse::from).collect(),
+                        }))
+                        .map_err(error_response))
+                })
+        }
+
+        // The latest payments received by any account, for the dashboard
+        #[get("/payments")]
+        #[content_type("application/json")]
+        fn get_recent_payments(&self, query_string: RecentPaymentsQuery, authorization: String) -> impl Future<Item = RecentPaymentsResponse, Error = Response<()>> {
+            let limit = query_string.limit.unwrap_or(DEFAULT_RECENT_PAYMENTS).min(MAX_RECENT_PAYMENTS);
+            self.validate_admin(authorization)
+                .and_then(move |store| store.get_recent_payments(limit)
+                    .map_err(error_response))
+                .and_then(|payments| Ok(RecentPaymentsResponse {
+                    payments: payments.into_iter().map(PaymentResponse::from).collect(),
+                }))
+        }
+
+        // The refund is sent from the account that received the payment to the return pointer
+        // its sender gave. Payments are only kept, and can only be refunded, for a day.
+        // Return pointers come from the sender, so they must be payment pointers whose host
+        // the node is allowed to connect to, and redirects are not followed
+        #[post("/accounts/:id/payments/:connection_id/refunds")]
+        #[content_type("application/json")]
+        fn post_refund(&self, id: String, connection_id: String, body: RefundRequest, authorization: String) -> impl Future<Item = Value, Error = Response<()>> {
+            let store = self.store.clone();
+            let service = self.incoming_handler.clone();
+            let constraints = self.constraints.clone();
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+            self.authorized_account(id, authorization)
+                .and_then(move |account| store.add_refund(&connection_id, &account.id().to_string(), body.amount, now)
+                    .map_err(error_response)
+                    .and_then(move |refund| {
+                        info!("Refunding {} of payment {} to {}", refund.amount, refund.connection_id, refund.return_pointer);
+                        let amount = refund.amount;
+                        constraints.check_payment_pointer_host(&refund.return_pointer)
+                            .and_then(move |url| {
+                                let client = Client::builder()
+                                    .redirect(RedirectPolicy::none())
+                                    .build()
+                                    .map_err(|err| format!("{:?}", err))?;
+                                Ok(pay_with_client(&client, service, account, url.as_str(), amount, SendMoneyOptions::new())
+                                    .map_err(|err| format!("{:?}", err)))
+                            })
+                            .flatten()
+                            .then(move |result| {
+                                let mut refund = refund;
+                                match result {
+                                    Ok(outcome) => refund.finish(&outcome),
+                                    Err(err) => {
+                                        error!("Error sending refund {}: {}", refund.id, err);
+                                        refund.fail(err);
+                                    }
+                                }
+                                store.finish_refund(refund.clone())
+                                    .map_err(error_response)
+                                    .map(move |_| json!(refund))
+                            })
+                    }))
+        }
+
+        #[get("/accounts/:id/payments/:connection_id/refunds")]
+        #[content_type("application/json")]
+        fn get_refunds(&self, id: String, connection_id: String, authorization: String) -> impl Future<Item = Value, Error = Response<()>> {
+            let store = self.store.clone();
+            self.authorized_account(id, authorization)
+                .and_then(move |account| store.get_incoming_payment(connection_id)
+                    .map_err(error_response)
+                    .and_then(move |payment| match payment {
+                        Some(payment) if payment.account_id == account.id().to_string() => Ok((store, payment)),
+                        _ => Err(Response::builder().status(404).body(()).unwrap()),
+                    }))
+                .and_then(|(store, payment)| store.get_refunds(&payment.connection_id)
+                    .map_err(error_response))
+                .and_then(|refunds| {
+                    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+                    let refunds: Vec<Refund> = refunds.into_iter().map(|refund| refund.check_interrupted(now)).collect();
+                    Ok(json!(refunds))
+                })
+        }
+
+        // Payments that run while the node is down are sent when it starts again.
+        // Recurring payments run at the start time plus multiples of the interval
+        #[post("/accounts/:id/scheduled_payments")]
+        #[content_type("application/json")]
+        fn post_scheduled_payment(&self, id: String, body: ScheduledPaymentRequest, authorization: String) -> impl Future<Item = Value, Error = Response<()>> {
+            let store = self.store.clone();
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+            let is_valid = body.source_amount > 0
+                && !body.receiver.is_empty()
+                && body.interval.map(|interval| interval >= MIN_SCHEDULED_PAYMENT_INTERVAL).unwrap_or(true);
+            self.authorized_account(id, authorization)
+                .and_then(move |account| if is_valid {
+                    Ok(account)
+                } else {
+                    debug!("Invalid scheduled payment for account {}", account.id());
+                    Err(Response::builder().status(400).body(()).unwrap())
+                })
+                .and_then(move |account| {
+                    let payment = ScheduledPayment::new(
+                        account.id().to_string(),
+                        body.receiver,
+                        body.source_amount,
+                        body.start_at.unwrap_or(now),
+                        body.interval,
+                        now,
+                    );
+                    store.add_scheduled_payment(payment)
+                        .map_err(error_response)
+                })
+                .and_then(|payment| {
+                    info!("Scheduled payment {} from account {} to {}", payment.id, payment.account_id, payment.receiver);
+                    Ok(json!(payment))
+                })
+        }
+
+        #[get("/accounts/:id/scheduled_payments")]
+        #[content_type("application/json")]
+        fn get_scheduled_payments(&self, id: String, authorization: String) -> impl Future<Item = Value, Error = Response<()>> {
+            let store = self.store.clone();
+            self.authorized_account(id, authorization)
+                .and_then(move |account| store.get_scheduled_payments(account.id())
+                    .map_err(error_response))
+                .and_then(|payments| Ok(json!(payments)))
+        }
+
+        #[get("/accounts/:id/scheduled_payments/:payment_id")]
+        #[content_type("application/json")]
+        fn get_scheduled_payment(&self, id: String, payment_id: String, authorization: String) -> impl Future<Item = Value, Error = Response<()>> {
+            self.scheduled_payment(id, payment_id, authorization)
+                .and_then(|(_store, payment)| Ok(json!(payment)))
+        }
+
+        // Only payments that are still scheduled can be cancelled. A run that is being sent
+        // when the payment is cancelled is finished, but there are no further runs or retries
+        #[delete("/accounts/:id/scheduled_payments/:payment_id")]
+        #[content_type("application/json")]
+        fn cancel_scheduled_payment(&self, id: String, payment_id: String, authorization: String) -> impl Future<Item = Value, Error = Response<()>> {
+            self.scheduled_payment(id, payment_id, authorization)
+                .and_then(|(store, mut payment)| if payment.status == ScheduleStatus::Scheduled {
+                    payment.status = ScheduleStatus::Cancelled;
+                    Ok((store, payment))
+                } else {
+                    Err(Response::builder().status(409).body(()).unwrap())
+                })
+                // Conflicts with a run being recorded at the same time are reported as 409s
+                .and_then(|(store, payment)| store.update_scheduled_payment(payment)
+                    .map_err(error_response)
+                    .and_then(|payment| {
+                        info!("Cancelled scheduled payment {}", payment.id);
+                        Ok(json!(payment))
+                    }))
+        }
+
+        // The link's URL is a one-time SPSP endpoint for the amount, for example for an order
+        // in a checkout flow. The link is consumed once it is paid in full
+        #[post("/accounts/:id/payment_links")]
+        #[content_type("application/json")]
+        fn post_payment_link(&self, id: String, body: PaymentLinkRequest, authorization: String, host: Option<String>) -> impl Future<Item = Value, Error = Response<()>> {
+            let store = self.store.clone();
+            let public_url = self.public_url.clone()
+                .or_else(|| host.and_then(|host| Url::parse(&format!("https://{}/", host)).ok()));
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+            let expires_in = body.expires_in.unwrap_or(DEFAULT_PAYMENT_LINK_EXPIRY);
+            let is_valid = body.amount > 0 && expires_in > 0;
+            self.authorized_account(id, authorization)
+                .and_then(move |account| if is_valid {
+                    Ok(account)
+                } else {
+                    debug!("Invalid payment link for account {}", account.id());
+                    Err(Response::builder().status(400).body(()).unwrap())
+                })
+                .and_then(move |account| {
+                    let link = PaymentLink::new(
+                        account.id().to_string(),
+                        body.amount,
+                        body.description,
+                        now,
+                        now.saturating_add(expires_in),
+                    );
+                    store.add_payment_link(link)
+                        .map_err(error_response)
+                })
+                .and_then(move |link| {
+                    info!("Created payment link {} for account {}", link.id, link.account_id);
+                    let mut response = json!(link);
+                    if let Some(public_url) = public_url {
+                        response["url"] = json!(payment_link_url(&public_url, &link.id).to_string());
+                    }
+                    Ok(response)
+                })
+        }
+
+        #[get("/accounts/:id/payment_links")]
+        #[content_type("application/json")]
+        fn get_payment_links(&self, id: String, authorization: String) -> impl Future<Item = Value, Error = Response<()>> {
+            let store = self.store.clone();
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+            self.authorized_account(id, authorization)
+                .and_then(move |account| store.get_payment_links(account.id())
+                    .map_err(error_response))
+                .and_then(move |links| {
+                    let links: Vec<PaymentLink> = links.into_iter().map(|link| link.check_expiry(now)).collect();
+                    Ok(json!(links))
+                })
+        }
 
-    {
-        pub fn new(server_secret: Bytes, store: T, incoming_handler: S) -> Self {
-            NodeApi {
-                store,
-                incoming_handler,
-                server_secret,
-            }
+        #[get("/accounts/:id/payment_links/:link_id")]
+        #[content_type("application/json")]
+        fn get_payment_link(&self, id: String, link_id: String, authorization: String) -> impl Future<Item = Value, Error = Response<()>> {
+            self.payment_link(id, link_id, authorization)
+                .and_then(|link| Ok(json!(link)))
         }
 
-        fn validate_admin(&self, authorization: String) -> impl Future<Item = T, Error = Response<()>> {
+        // The amount owed is sent every few seconds until the session is stopped
+        #[post("/accounts/:id/streaming_sessions")]
+        #[content_type("application/json")]
+        fn post_streaming_session(&self, id: String, body: StreamingSessionRequest, authorization: String) -> impl Future<Item = Value, Error = Response<()>> {
             let store = self.store.clone();
-            self.store.get_account_from_http_auth(&authorization)
-                .and_then(|account| if account.is_admin() {
-                    Ok(store)
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+            let is_valid = body.rate_per_minute > 0 && !body.receiver.is_empty();
+            self.authorized_account(id, authorization)
+                .and_then(move |account| if is_valid {
+                    Ok(account)
                 } else {
-                    Err(())
+                    debug!("Invalid streaming session for account {}", account.id());
+                    Err(Response::builder().status(400).body(()).unwrap())
+                })
+                .and_then(move |account| {
+                    let session = StreamingSession::new(account.id().to_string(), body.receiver, body.rate_per_minute, now);
+                    store.add_streaming_session(session)
+                        .map_err(error_response)
+                })
+                .and_then(|session| {
+                    info!("Started streaming session {} from account {} to {}", session.id, session.account_id, session.receiver);
+                    Ok(json!(session))
                 })
-                .map_err(|_| Response::builder().status(401).body(()).unwrap())
         }
 
-        #[get("/")]
+        #[get("/accounts/:id/streaming_sessions")]
         #[content_type("application/json")]
-        fn get_root(&self) -> Result<ServerStatus, ()> {
-            Ok(ServerStatus {
-                status: "Ready".to_string(),
-            })
+        fn get_streaming_sessions(&self, id: String, authorization: String) -> impl Future<Item = Value, Error = Response<()>> {
+            let store = self.store.clone();
+            self.authorized_account(id, authorization)
+                .and_then(move |account| store.get_streaming_sessions(account.id())
+                    .map_err(error_response))
+                .and_then(|sessions| Ok(json!(sessions)))
         }
 
-        #[post("/accounts")]
+        #[get("/accounts/:id/streaming_sessions/:session_id")]
         #[content_type("application/json")]
-        fn post_accounts(&self, body: AccountDetails, authorization: String) -> impl Future<Item = Value, Error = Response<()>> {
-            // TODO don't allow accounts to be overwritten
-            // TODO add option for non-admin signups (maybe with invite code)
-            self.validate_admin(authorization)
-                .and_then(move |store| store.insert_account(body)
-                // TODO make all Accounts (de)serializable with Serde so all the details can be returned here
-                .and_then(|account| Ok(json!(account)))
-                .map_err(|_| Response::builder().status(500).body(()).unwrap()))
+        fn get_streaming_session(&self, id: String, session_id: String, authorization: String) -> impl Future<Item = Value, Error = Response<()>> {
+            self.streaming_session(id, session_id, authorization)
+                .and_then(|(_store, session)| Ok(json!(session)))
         }
 
-        #[get("/accounts")]
+        #[put("/accounts/:id/streaming_sessions/:session_id/rate")]
         #[content_type("application/json")]
-        fn get_accounts(&self, authorization: String) -> impl Future<Item = Value, Error = Response<()>> {
-            let store = self.store.clone();
-            self.store.get_account_from_http_auth(&authorization)
-                .map_err(move |_| {
-                    debug!("No account found with auth: {}", authorization);
-                    Response::builder().status(401).body(()).unwrap()
+        fn put_streaming_rate(&self, id: String, session_id: String, body: StreamingRateRequest, authorization: String) -> impl Future<Item = Value, Error = Response<()>> {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+            let rate_per_minute = body.rate_per_minute;
+            self.streaming_session(id, session_id, authorization)
+                .and_then(move |(store, mut session)| if rate_per_minute == 0 {
+                    Err(Response::builder().status(400).body(()).unwrap())
+                } else if session.status != SessionStatus::Active {
+                    Err(Response::builder().status(409).body(()).unwrap())
+                } else {
+                    session.set_rate(rate_per_minute, now);
+                    Ok((store, session))
                 })
-                .and_then(move |account| if account.is_admin() {
-                    Either::A(store.get_all_accounts()
-                        .map_err(|_| Response::builder().status(500).body(()).unwrap()))
+                .and_then(|(store, session)| store.update_streaming_session(session)
+                    .map_err(error_response)
+                    .and_then(|session| Ok(json!(session))))
+        }
+
+        #[post("/accounts/:id/streaming_sessions/:session_id/stop")]
+        #[content_type("application/json")]
+        fn stop_streaming_session(&self, id: String, session_id: String, authorization: String) -> impl Future<Item = Value, Error = Response<()>> {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+            self.streaming_session(id, session_id, authorization)
+                .and_then(move |(store, mut session)| if session.status == SessionStatus::Active {
+                    session.stop(SessionStatus::Stopped, now);
+                    Ok((store, session))
                 } else {
-                    Either::B(store.get_accounts(vec![account.id()])
-                        .map_err(|_| Response::builder().status(404).body(()).unwrap()))
+                    Err(Response::builder().status(409).body(()).unwrap())
                 })
-                .and_then(|accounts| Ok(json!(accounts)))
+                .and_then(|(store, session)| store.update_streaming_session(session)
+                    .map_err(error_response)
+                    .and_then(|session| {
+                        info!("Stopped streaming session {}", session.id);
+                        Ok(json!(session))
+                    }))
         }
 
-        #[get("/accounts/:id")]
+        // How to set up a payment pointer on another domain so that it resolves to this account
+        #[get("/accounts/:id/payment_pointer")]
         #[content_type("application/json")]
-        fn get_account(&self, id: String, authorization: String) -> impl Future<Item = Value, Error = Response<()>> {
-            let store = self.store.clone();
-            let store_clone = store.clone();
-            let parsed_id: Result<A::AccountId, ()> = A::AccountId::from_str(&id).map_err(|_| error!("Invalid id"));
-            result(parsed_id)
-                .map_err(|_| Response::builder().status(400).body(()).unwrap())
-                .and_then(move |id| {
-                    store.clone().get_account_from_http_auth(&authorization)
-                        .and_then(move |account|
-                            if account.id() == id {
-                                Either::A(ok(json!(account)))
-                            } else if account.is_admin() {
-                                Either::B(store_clone.get_accounts(vec![id]).and_then(|accounts| Ok(json!(accounts[0].clone()))))
-                            } else {
-                                Either::A(err(()))
-                            })
-                        .map_err(move |_| {
-                            debug!("No account found with auth: {}", authorization);
-                            Response::builder().status(401).body(()).unwrap()
+        fn get_payment_pointer(&self, id: String, query_string: PaymentPointerQuery, authorization: String, host: Option<String>) -> impl Future<Item = Value, Error = Response<()>> {
+            let public_url = self.public_url.clone()
+                .or_else(|| host.and_then(|host| Url::parse(&format!("https://{}/", host)).ok()));
+            self.authorized_account(id, authorization)
+                .and_then(move |account| {
+                    let spsp_url = public_url
+                        .map(|public_url| spsp_url(&public_url, &account.id().to_string()))
+                        .ok_or_else(|| {
+                            error!("Unable to tell the public URL of the node. Set it to generate payment pointer configurations");
+                            Response::builder().status(500).body(()).unwrap()
+                        })?;
+                    hosting_config(&query_string.payment_pointer, &spsp_url)
+                        .map(|config| json!(config))
+                        .map_err(|err| {
+                            debug!("Invalid payment pointer: {}", err);
+                            Response::builder().status(400).body(()).unwrap()
                         })
                 })
         }
 
-        // TODO should this be combined into the account record?
-        #[get("/accounts/:id/balance")]
+        // Query the payment pointer like a sender would, to check that its domain's TLS certificate
+        // and redirect are set up correctly and that it resolves to this account. Only admins can
+        // check payment pointers, because it makes the node send requests to the given domain. The
+        // domain must be one the node may connect to, and it may only redirect to the node itself
+        #[get("/accounts/:id/payment_pointer/check")]
         #[content_type("application/json")]
-        fn get_balance(&self, id: String, authorization: String) -> impl Future<Item = BalanceResponse, Error = Response<()>> {
-            let store = self.store.clone();
-            let store_clone = store.clone();
-            let parsed_id: Result<A::AccountId, ()> = A::AccountId::from_str(&id).map_err(|_| error!("Invalid id"));
-            result(parsed_id)
-                .map_err(|_| Response::builder().status(400).body(()).unwrap())
-                .and_then(move |id| {
-                    store.clone().get_account_from_http_auth(&authorization)
-                        .and_then(move |account|
-                            if account.id() == id {
-                                Either::A(ok(account))
-                            } else if account.is_admin() {
-                                Either::B(store_clone.get_accounts(vec![id]).and_then(|accounts| Ok(accounts[0].clone())))
-                            } else {
-                                Either::A(err(()))
-                            })
-                        .map_err(move |_| {
-                            debug!("No account found with auth: {}", authorization);
-                            Response::builder().status(401).body(()).unwrap()
+        fn get_payment_pointer_check(&self, id: String, query_string: PaymentPointerQuery, authorization: String) -> impl Future<Item = Value, Error = Response<()>> {
+            let public_url = self.public_url.clone();
+            let constraints = self.constraints.clone();
+            self.validate_admin(authorization)
+                .and_then(move |store| {
+                    let public_url = public_url.ok_or_else(|| {
+                        error!("Unable to tell the public URL of the node. Set it to check payment pointers");
+                        Response::builder().status(500).body(()).unwrap()
+                    })?;
+                    let account_id = A::AccountId::from_str(&id)
+                        .map_err(|_| Response::builder().status(400).body(()).unwrap())?;
+                    Ok(store.get_accounts(vec![account_id])
+                        .and_then(|mut accounts| accounts.remove(0).ok_or(()))
+                        .map_err(|_| Response::builder().status(404).body(()).unwrap())
+                        .map(move |account| (account, public_url)))
+                })
+                .flatten()
+                .and_then(move |(account, public_url)| {
+                    let spsp_url = spsp_url(&public_url, &account.id().to_string());
+                    let client_address = String::from_utf8_lossy(account.client_address()).to_string();
+                    let payment_pointer = query_string.payment_pointer;
+                    constraints.check_payment_pointer_host(&payment_pointer)
+                        .map_err(|err| {
+                            debug!("Not checking payment pointer: {}", err);
+                            Response::builder().status(400).body(()).unwrap()
                         })
-                        .and_then(move |account| store.get_balance(account)
-                        .and_then(|balance| Ok(BalanceResponse {
-                            balance: balance.to_string(),
+                        .and_then(move |_| check_payment_pointer(&payment_pointer, &spsp_url, &client_address)
+                            .map(|check| json!(check))
+                            .map_err(|err| {
+                                debug!("Invalid payment pointer: {}", err);
+                                Response::builder().status(400).body(()).unwrap()
+                            }))
+                })
+        }
+
+        // The balances of all of an owner's accounts and their totals in each asset,
+        // for operators of hosted wallets that give each customer several accounts
+        #[get("/owners/:owner/balance")]
+        #[content_type("application/json")]
+        fn get_owner_balance(&self, owner: String, authorization: String) -> impl Future<Item = OwnerBalanceResponse, Error = Response<()>> {
+            self.validate_admin(authorization)
+                .and_then(move |store| {
+                    store.get_accounts_by_owner(&owner)
+                        .map_err(error_response)
+                        .and_then(move |accounts| {
+                            if accounts.is_empty() {
+                                return Either::A(err(Response::builder().status(404).body(()).unwrap()));
+                            }
+                            Either::B(join_all(accounts.into_iter().map(move |account| store.get_balance(account.clone())
+                                .map(move |balance| (account, balance))))
+                                .map_err(error_response))
+                        })
+                        .and_then(move |balances| Ok(owner_balance(owner, balances)))
+                })
+        }
+
+        #[get("/owners/:owner/balance/history")]
+        #[content_type("application/json")]
+        fn get_owner_balance_history(&self, owner: String, query_string: BalanceHistoryQuery, authorization: String) -> impl Future<Item = OwnerBalanceHistoryResponse, Error = Response<()>> {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+            let to = query_string.to.unwrap_or(now);
+            let from = query_string.from.unwrap_or_else(|| to.saturating_sub(DEFAULT_BALANCE_HISTORY_PERIOD));
+            let resolution = query_string.resolution.unwrap_or(1);
+            let valid_query = if from <= to && resolution > 0 {
+                Ok(())
+            } else {
+                debug!("Invalid balance history query. from: {}, to: {}, resolution: {}", from, to, resolution);
+                Err(Response::builder().status(400).body(()).unwrap())
+            };
+            let validate_admin = self.validate_admin(authorization);
+            result(valid_query)
+                .and_then(move |_| validate_admin)
+                .and_then(move |store| {
+                    store.get_accounts_by_owner(&owner)
+                        .map_err(error_response)
+                        .and_then(move |accounts| {
+                            if accounts.is_empty() {
+                                return Either::A(err(Response::builder().status(404).body(()).unwrap()));
+                            }
+                            Either::B(join_all(accounts.into_iter().map(move |account| store.get_balance_history(account.id(), from, to)
+                                .map(move |snapshots| (account, snapshots))))
+                                .map_err(error_response))
+                        })
+                        .and_then(move |histories| Ok(OwnerBalanceHistoryResponse {
+                            owner,
+                            assets: group_by_asset(histories)
+                                .into_iter()
+                                .map(|((asset_code, asset_scale), histories)| AssetBalanceHistory {
+                                    asset_code,
+                                    asset_scale,
+                                    history: downsample(sum_snapshots(histories), from, resolution),
+                                })
+                                .collect(),
                         }))
-                        .map_err(|_| Response::builder().status(404).body(()).unwrap()))
                 })
         }
 
+        #[get("/maintenance")]
+        #[content_type("application/json")]
+        fn get_maintenance(&self, authorization: String) -> impl Future<Item = MaintenanceResponse, Error = Response<()>> {
+            self.validate_admin(authorization)
+                .and_then(|store| store.get_maintenance_windows()
+                    .map_err(error_response))
+                .and_then(|windows| Ok(MaintenanceResponse {
+                    accounts: HashMap::from_iter(windows.into_iter()
+                        .map(|(account_id, windows)| (account_id.to_string(), windows))),
+                }))
+        }
+
+        // Schedule when a peer will be down, so its packets are rejected and alerts can be silenced
+        #[put("/accounts/:id/maintenance")]
+        #[content_type("application/json")]
+        fn put_maintenance(&self, id: String, body: MaintenanceWindows, authorization: String) -> impl Future<Item = Success, Error = Response<()>> {
+            self.validate_admin(authorization)
+                .and_then(move |store| {
+                    let account_id = A::AccountId::from_str(&id)
+                        .map_err(|_| Response::builder().status(400).body(()).unwrap())?;
+                    if let Some(window) = body.0.iter().find(|window| window.end <= window.start) {
+                        debug!("Invalid maintenance window: {:?}", window);
+                        return Err(Response::builder().status(400).body(()).unwrap());
+                    }
+                    Ok((store, account_id, body.0))
+                })
+                .and_then(|(store, account_id, windows)| store.set_maintenance_windows(account_id, windows)
+                    .and_then(|_| Ok(Success))
+                    .map_err(|err| {
+                        error!("Error setting maintenance windows: {:?}", err);
+                        error_response(err)
+                    }))
+        }
+
         #[put("/rates")]
         #[content_type("application/json")]
         fn post_rates(&self, body: Rates, authorization: String) -> impl Future<Item = Success, Error = Response<()>> {
@@ -265,10 +2254,258 @@ impl_web! {
                 .and_then(|_| Ok(Success))
                 .map_err(|err| {
                     error!("Error setting rates: {:?}", err);
-                    Response::builder().status(500).body(()).unwrap()
+                    error_response(err)
+                }))
+        }
+
+        #[put("/fees")]
+        #[content_type("application/json")]
+        fn put_fees(&self, body: Fees, authorization: String) -> impl Future<Item = Success, Error = Response<()>> {
+            let constraints = self.constraints.clone();
+            self.validate_admin(authorization)
+                .and_then(move |store| {
+                    for key in body.0.keys() {
+                        let is_valid = if key.starts_with("account:") {
+                            A::AccountId::from_str(&key[8..]).is_ok()
+                        } else if key.starts_with("prefix:") {
+                            constraints.check_route_prefix(&key[7..]).is_ok()
+                        } else {
+                            false
+                        };
+                        if !is_valid {
+                            debug!("Invalid fee policy key: {}", key);
+                            return Err(Response::builder().status(400).body(()).unwrap());
+                        }
+                    }
+                    Ok((store, body.0))
+                })
+                .and_then(|(store, policies)| store.set_fee_policies(policies)
+                    .and_then(|_| Ok(Success))
+                    .map_err(|err| {
+                        error!("Error setting fee policies: {:?}", err);
+                        error_response(err)
+                    }))
+        }
+
+        #[get("/stats/revenue")]
+        #[content_type("application/json")]
+        fn get_revenue(&self, query_string: StatsQuery, authorization: String) -> impl Future<Item = RevenueResponse, Error = Response<()>> {
+            let days = query_string.days.unwrap_or(DEFAULT_STATS_DAYS);
+            self.validate_admin(authorization)
+                .and_then(move |store| store.get_total_stats()
+                    .join(store.get_daily_stats(days))
+                    .map_err(|_| Response::builder().status(500).body(()).unwrap()))
+                .and_then(|(total, daily)| Ok(RevenueResponse {
+                    totals: asset_totals(&total),
+                    daily,
+                }))
+        }
+
+        // The destination prefixes the node forwards the most packets to, for spotting abuse
+        #[get("/stats/prefixes")]
+        #[content_type("application/json")]
+        fn get_prefix_stats(&self, query_string: PrefixStatsQuery, authorization: String) -> impl Future<Item = PrefixStatsResponse, Error = Response<()>> {
+            let limit = query_string.limit.unwrap_or(DEFAULT_PREFIX_STATS_LIMIT).min(MAX_PREFIX_STATS_LIMIT);
+            self.validate_admin(authorization)
+                .and_then(move |store| store.get_prefix_stats(limit)
+                    .map_err(|_| Response::builder().status(500).body(()).unwrap()))
+                .and_then(|prefixes| Ok(PrefixStatsResponse { prefixes }))
+        }
+
+        // How much data the store holds, for planning its capacity
+        #[get("/stats/store")]
+        #[content_type("application/json")]
+        fn get_store_usage(&self, authorization: String) -> impl Future<Item = StoreUsage, Error = Response<()>> {
+            self.validate_admin(authorization)
+                .and_then(|store| store.get_store_usage()
+                    .map_err(|err| {
+                        error!("Error getting store usage: {:?}", err);
+                        error_response(err)
+                    }))
+        }
+
+        #[get("/metrics")]
+        fn get_metrics(&self, authorization: String) -> impl Future<Item = Response<String>, Error = Response<()>> {
+            let packet_data_stats = self.packet_data_stats.clone();
+            let priority_stats = self.priority_stats.clone();
+            let latency_stats = self.latency_stats.clone();
+            self.validate_admin(authorization)
+                .and_then(|store| store.get_total_stats()
+                    .join(store.get_maintenance_windows().map_err(|_| ()))
+                    .map_err(|_| Response::builder().status(500).body(()).unwrap()))
+                .and_then(move |(stats, maintenance_windows)| {
+                    let mut metrics = prometheus_metrics(&stats);
+                    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+                    metrics.push_str(&maintenance_metrics(&maintenance_windows, now));
+                    if let Some(packet_data_stats) = packet_data_stats {
+                        metrics.push_str(&packet_data_metrics(&packet_data_stats));
+                    }
+                    if let Some(priority_stats) = priority_stats {
+                        metrics.push_str(&priority_metrics(&priority_stats));
+                    }
+                    if let Some(latency_stats) = latency_stats {
+                        metrics.push_str(&latency_metrics(&latency_stats));
+                    }
+                    Ok(Response::builder()
+                        .header("Content-Type", "text/plain; version=0.0.4")
+                        .body(metrics)
+                        .unwrap())
+                })
+        }
+
+        #[get("/connections")]
+        #[content_type("application/json")]
+        fn get_connections(&self, authorization: String) -> impl Future<Item = ConnectionsResponse, Error = Response<()>> {
+            let registry = self.connections.clone();
+            self.validate_admin(authorization)
+                .and_then(move |_| Ok(ConnectionsResponse {
+                    connections: registry.map(|registry| registry.connections()).unwrap_or_default(),
                 }))
         }
 
+        #[delete("/connections/:account_id")]
+        #[content_type("application/json")]
+        fn delete_connection(&self, account_id: String, authorization: String) -> impl Future<Item = Success, Error = Response<()>> {
+            let registry = self.connections.clone();
+            self.validate_admin(authorization)
+                .and_then(move |_| {
+                    if registry.map(|registry| registry.disconnect(&account_id)).unwrap_or(false) {
+                        Ok(Success)
+                    } else {
+                        debug!("No open connection for account: {}", account_id);
+                        Err(Response::builder().status(404).body(()).unwrap())
+                    }
+                })
+        }
+
+        #[get("/chaos")]
+        #[content_type("application/json")]
+        fn get_chaos(&self, authorization: String) -> impl Future<Item = FailureSettings, Error = Response<()>> {
+            let injector = self.failure_injector.clone();
+            self.validate_admin(authorization)
+                .and_then(move |_| injector
+                    .map(|injector| injector.failures())
+                    .ok_or_else(|| Response::builder().status(404).body(()).unwrap()))
+        }
+
+        #[put("/chaos")]
+        #[content_type("application/json")]
+        fn put_chaos(&self, body: FailureSettings, authorization: String) -> impl Future<Item = Success, Error = Response<()>> {
+            let injector = self.failure_injector.clone();
+            self.validate_admin(authorization)
+                .and_then(move |_| {
+                    let injector = injector.ok_or_else(|| Response::builder().status(404).body(()).unwrap())?;
+                    if body.drop_percent > 100 {
+                        debug!("Invalid drop percentage: {}", body.drop_percent);
+                        return Err(Response::builder().status(400).body(()).unwrap());
+                    }
+                    warn!("Injecting failures: dropping {}% of packets and delaying them by {}ms", body.drop_percent, body.delay);
+                    injector.inject_failures(body);
+                    Ok(Success)
+                })
+        }
+
+        // Close all of the open peer connections, as if the network between the node and its peers failed.
+        #[post("/chaos/disconnect")]
+        #[content_type("application/json")]
+        fn post_chaos_disconnect(&self, authorization: String) -> impl Future<Item = DisconnectedResponse, Error = Response<()>> {
+            let injector = self.failure_injector.clone();
+            let registry = self.connections.clone();
+            self.validate_admin(authorization)
+                .and_then(move |_| {
+                    if injector.is_none() {
+                        return Err(Response::builder().status(404).body(()).unwrap());
+                    }
+                    let disconnected = registry.map(|registry| registry
+                        .connections()
+                        .iter()
+                        .filter(|connection| registry.disconnect(&connection.account_id))
+                        .count())
+                        .unwrap_or(0);
+                    warn!("Closed {} peer connections", disconnected);
+                    Ok(DisconnectedResponse { disconnected })
+                })
+        }
+
+        // The key acts on behalf of the admin that creates it. Its token is only included in this response
+        #[post("/api_keys")]
+        #[content_type("application/json")]
+        fn post_api_key(&self, body: ApiKeyRequest, authorization: String) -> impl Future<Item = Value, Error = Response<String>> {
+            let store = self.store.clone();
+            let mut errors = Vec::new();
+            if body.name.is_empty() {
+                errors.push(FieldError {
+                    field: "name",
+                    message: "Name must not be empty".to_string(),
+                });
+            }
+            let mut scopes: Vec<ApiScope> = Vec::with_capacity(body.scopes.len());
+            for scope in body.scopes.iter() {
+                match ApiScope::from_str(scope) {
+                    Ok(scope) if scopes.contains(&scope) => {}
+                    Ok(scope) => scopes.push(scope),
+                    Err(_) => errors.push(FieldError {
+                        field: "scopes",
+                        message: format!("Unknown scope: {}", scope),
+                    }),
+                }
+            }
+            let validation = if errors.is_empty() {
+                Ok(())
+            } else {
+                debug!("Invalid API key: {:?}", errors);
+                Err(Response::builder()
+                    .status(400)
+                    .header("Content-Type", "application/json")
+                    .body(json!({ "errors": errors }).to_string())
+                    .unwrap())
+            };
+            let name = body.name;
+            result(validation)
+                .and_then(move |_| store.get_account_from_http_auth(&authorization)
+                    .and_then(|account| if account.is_admin() {
+                        Ok((store, account))
+                    } else {
+                        Err(())
+                    })
+                    .map_err(|_| Response::builder().status(401).body(String::new()).unwrap()))
+                .and_then(move |(store, account)| store.create_api_key(name, account.id().to_string(), scopes)
+                    .map_err(|err| error_response(err).map(|_| String::new())))
+                .and_then(|(api_key, token)| {
+                    info!("Created API key {} with scopes: {:?}", api_key.id, api_key.scopes);
+                    Ok(json!({
+                        "api_key": api_key,
+                        "token": token,
+                    }))
+                })
+        }
+
+        #[get("/api_keys")]
+        #[content_type("application/json")]
+        fn get_api_keys(&self, authorization: String) -> impl Future<Item = Value, Error = Response<()>> {
+            self.validate_admin(authorization)
+                .and_then(|store| store.get_api_keys()
+                    .map_err(error_response))
+                .and_then(|api_keys| Ok(json!(api_keys)))
+        }
+
+        #[delete("/api_keys/:id")]
+        #[content_type("application/json")]
+        fn delete_api_key(&self, id: String, authorization: String) -> impl Future<Item = Value, Error = Response<()>> {
+            self.validate_admin(authorization)
+                .and_then(move |store| {
+                    let id = u64::from_str(&id)
+                        .map_err(|_| Response::builder().status(400).body(()).unwrap())?;
+                    Ok((store, id))
+                })
+                .and_then(|(store, id)| store.delete_api_key(id)
+                    .map_err(error_response))
+                .and_then(|api_key| {
+                    info!("Deleted API key {}", api_key.id);
+                    Ok(json!(api_key))
+                })
+        }
+
         #[get("/routes")]
         #[content_type("application/json")]
         fn get_routes(&self) -> impl Future<Item = Routes, Error = Response<()>> {
@@ -286,10 +2523,15 @@ impl_web! {
         #[put("/routes/static")]
         #[content_type("application/json")]
         fn post_static_routes(&self, body: Routes, authorization: String) -> impl Future<Item = Success, Error = Response<()>> {
+            let constraints = self.constraints.clone();
             self.validate_admin(authorization)
                 .and_then(move |store| {
                     let mut routes: HashMap<String, A::AccountId> = HashMap::with_capacity(body.0.len());
                     for (prefix, account_id) in body.0 {
+                        if let Err(message) = constraints.check_route_prefix(&prefix) {
+                            debug!("Invalid route prefix {}: {}", prefix, message);
+                            return Err(Response::builder().status(400).body(()).unwrap());
+                        }
                         if let Ok(account_id) = A::AccountId::from_str(account_id.as_str()) {
                             routes.insert(prefix, account_id);
                         } else {
@@ -303,50 +2545,145 @@ impl_web! {
                     .and_then(|_| Ok(Success))
                         .map_err(|err| {
                             error!("Error setting static routes: {:?}", err);
-                            Response::builder().status(500).body(()).unwrap()
+                            error_response(err)
                         })
             })
         }
 
+        // Note this must come before the /routes/static/:prefix route so "batch" is not treated as a prefix
+        #[put("/routes/static/batch")]
+        #[content_type("application/json")]
+        fn put_static_routes_batch(&self, body: String, authorization: String) -> impl Future<Item = Value, Error = Response<String>> {
+            let routes = parse_route_batch::<A::AccountId>(&body, &self.constraints)
+                .map_err(|errors| {
+                    debug!("Invalid static route batch: {:?}", errors);
+                    let len = batch_len(&body).max(errors.iter().map(|(index, _)| index + 1).max().unwrap_or(0));
+                    let failed = errors.into_iter().map(|(index, errors)| (index, 400, errors)).collect();
+                    batch_response(rejected_batch(len, failed))
+                });
+            self.validate_admin(authorization)
+                .map_err(|response| response.map(|_| String::new()))
+                .and_then(move |store| routes.map(|routes| (store, routes)))
+                .and_then(|(store, routes)| {
+                    let results: Vec<BatchEntryResult> = routes.iter()
+                        .enumerate()
+                        .map(|(index, (prefix, account_id))| BatchEntryResult::applied(index, json!({
+                            "prefix": prefix,
+                            "account_id": account_id.to_string(),
+                        })))
+                        .collect();
+                    let len = routes.len();
+                    store.add_static_routes(routes)
+                        .map_err(move |err| batch_error_response(len, err))
+                        .and_then(move |_| Ok(json!({ "results": results })))
+                })
+        }
+
         #[put("/routes/static/:prefix")]
         #[content_type("application/json")]
         fn post_static_route(&self, prefix: String, body: String, authorization: String) -> impl Future<Item = Success, Error = Response<()>> {
+            let constraints = self.constraints.clone();
             self.validate_admin(authorization)
                 .and_then(move |store| {
-                    if let Ok(account_id) = A::AccountId::from_str(body.as_str()) {
-                        Ok((store, account_id))
+                    if let Err(message) = constraints.check_route_prefix(&prefix) {
+                        debug!("Invalid route prefix {}: {}", prefix, message);
+                        Err(Response::builder().status(400).body(()).unwrap())
+                    } else if let Ok(account_id) = A::AccountId::from_str(body.as_str()) {
+                        Ok((store, prefix, account_id))
                     } else {
                         Err(Response::builder().status(400).body(()).unwrap())
                     }
                 })
-                .and_then(move |(store, account_id)| {
+                .and_then(move |(store, prefix, account_id)| {
                     store.set_static_route(prefix, account_id)
                     .and_then(|_| Ok(Success))
                         .map_err(|err| {
                             error!("Error setting static route: {:?}", err);
-                            Response::builder().status(500).body(()).unwrap()
+                            error_response(err)
                         })
                 })
         }
 
+        #[get("/routes/history")]
+        #[content_type("application/json")]
+        fn get_route_history(&self, authorization: String) -> impl Future<Item = RouteHistoryResponse, Error = Response<()>> {
+            self.validate_admin(authorization)
+                .and_then(|store| store.get_route_history()
+                    .and_then(|history| Ok(RouteHistoryResponse { history }))
+                    .map_err(|err| {
+                        error!("Error getting route history: {:?}", err);
+                        error_response(err)
+                    }))
+        }
+
+        // Restore the routes of an earlier version, for example when a route update broke traffic.
+        // Routes learned from peers are recalculated when they send their next update
+        #[post("/routes/history/:version/rollback")]
+        #[content_type("application/json")]
+        fn post_route_rollback(&self, version: u64, authorization: String) -> impl Future<Item = RouteVersion, Error = Response<()>> {
+            self.validate_admin(authorization)
+                .and_then(move |store| store.rollback_routes(version)
+                    .map_err(move |err| {
+                        error!("Error rolling back routes to version {}: {:?}", version, err);
+                        error_response(err)
+                    }))
+        }
+
         #[post("/pay")]
         #[content_type("application/json")]
         // TODO add a version that lets you specify the destination amount instead
-        fn post_pay(&self, body: SpspPayRequest, authorization: String) -> impl Future<Item = SpspPayResponse, Error = Response<String>> {
+        fn post_pay(&self, body: SpspPayRequest, authorization: String, idempotency_key: Option<String>) -> impl Future<Item = SpspPayResponse, Error = Response<String>> {
             let service = self.incoming_handler.clone();
-            self.store.get_account_from_http_auth(&authorization)
+            let mut options = SendMoneyOptions::new();
+            if let Some(max_slippage) = body.max_slippage {
+                let store = self.store.clone();
+                options = options.max_slippage(move |source_asset: &str, destination_asset: &str| {
+                    store.get_exchange_rates(&[source_asset, destination_asset])
+                        .ok()
+                        .map(|rates| rates[1] / rates[0])
+                }, max_slippage);
+            }
+            if let Some(timeout) = body.timeout {
+                options = options.timeout(Duration::from_secs(timeout));
+            }
+            if let Some(max_idle) = body.max_idle {
+                options = options.max_idle(Duration::from_secs(max_idle));
+            }
+            if let Some(randomize_address) = body.randomize_address {
+                options = options.randomize_address(randomize_address);
+            }
+            if let Some(return_pointer) = body.return_pointer.clone() {
+                options = options.return_pointer(return_pointer);
+            }
+            if let Some(metadata) = body.metadata.clone() {
+                // Receivers ignore longer metadata, so it would be lost without an error
+                if metadata.len() > MAX_METADATA_LENGTH {
+                    return Either::A(err(Response::builder().status(400).body(format!("Metadata must be at most {} bytes", MAX_METADATA_LENGTH)).unwrap()));
+                }
+                options = options.metadata(metadata);
+            }
+            let check_idempotency_key = self.check_idempotency_key(idempotency_key, &authorization);
+            let store = self.store.clone();
+            Either::B(self.store.get_account_from_http_auth(&authorization)
                 .map_err(|_| Response::builder().status(401).body("Unauthorized".to_string()).unwrap())
-                .and_then(move |account| {
-                    pay(service, account, &body.receiver, body.source_amount)
-                        .and_then(|amount_delivered| Ok(SpspPayResponse {
-                                amount_delivered,
-                            }))
-                        .map_err(|err| {
-                            error!("Error sending SPSP payment: {:?}", err);
+                .and_then(move |account| check_idempotency_key
+                    .map_err(|status| Response::builder().status(status).body("Duplicate request".to_string()).unwrap())
+                    .and_then(|claimed| Ok((account, claimed))))
+                .and_then(move |(account, claimed)| {
+                    pay_with_options(service, account, &body.receiver, body.source_amount, options)
+                        // Payments that stop part of the way through are reported in the response
+                        .and_then(|outcome| Ok(SpspPayResponse::from(outcome)))
+                        .or_else(move |error| {
+                            error!("Error sending SPSP payment: {:?}", error);
                             // TODO give a different error message depending on what type of error it is
-                            Response::builder().status(500).body(format!("Error sending SPSP payment: {:?}", err)).unwrap()
+                            let response = Response::builder().status(500).body(format!("Error sending SPSP payment: {:?}", error)).unwrap();
+                            // Nothing was delivered, so let the client retry with the same key
+                            match claimed {
+                                Some(key) => Either::A(store.release_idempotency_key(key).then(move |_| Err(response))),
+                                None => Either::B(err(response)),
+                            }
                         })
-                })
+                }))
         }
 
         #[post("/ilp")]
@@ -357,27 +2694,93 @@ impl_web! {
                 .header("Authorization", authorization)
                 .body(Body::from(body))
                 .unwrap();
-            HttpServerService::new(self.incoming_handler.clone(), self.store.clone()).handle_http_request(request)
+            if let Some(ref stats) = self.latency_stats {
+                let store = TimedHttpStore {
+                    store: self.store.clone(),
+                    stats: stats.clone(),
+                };
+                Either::A(HttpServerService::new(self.incoming_handler.clone(), store).handle_http_request(request))
+            } else {
+                Either::B(HttpServerService::new(self.incoming_handler.clone(), self.store.clone()).handle_http_request(request))
+            }
         }
 
+        // The ID can be followed by a sub-receiver tag, as in `/spsp/1~invoice123`, so one account
+        // can tell apart the payments sent to many payment pointers. The tag is recorded with the payments
         #[get("/spsp/:id")]
         fn get_spsp(&self, id: String) -> impl Future<Item = Response<Body>, Error = Response<()>> {
             let server_secret = self.server_secret.clone();
             let store = self.store.clone();
-            let id: Result<A::AccountId, ()> = A::AccountId::from_str(&id).map_err(|_| error!("Invalid id: {}", id));
-            result(id)
+            let (id, tag) = split_sub_receiver_tag(&id);
+            let tag = tag.map(|tag| tag.to_string());
+            if let Some(ref tag) = tag {
+                // Payment link tags are reserved so links cannot be paid through other endpoints
+                if tag.is_empty() || tag.len() > MAX_SUB_RECEIVER_TAG_LENGTH || tag.starts_with(PAYMENT_LINK_TAG_PREFIX) {
+                    error!("Invalid sub-receiver tag: {}", tag);
+                    return Either::A(err(Response::builder().status(400).body(()).unwrap()));
+                }
+            }
+            let id: Result<A::AccountId, ()> = A::AccountId::from_str(id).map_err(|_| error!("Invalid id: {}", id));
+            Either::B(result(id)
                 .map_err(|_| Response::builder().status(400).body(()).unwrap())
                 .and_then(move |id| store.get_accounts(vec![id])
+                .and_then(|mut accounts| accounts.remove(0).ok_or(()))
                 .map_err(move |_| {
                     error!("Account not found: {}", id);
                     Response::builder().status(404).body(()).unwrap()
                 }))
-                .and_then(move |accounts| {
-                    let ilp_address = Bytes::from(accounts[0].client_address());
+                .and_then(move |account| {
+                    let ilp_address = Bytes::from(account.client_address());
                     // TODO return the response without instantiating an SpspResponder (use a simple fn)
-                    Ok(SpspResponder::new(ilp_address, server_secret)
-                        .generate_http_response())
-                    })
+                    let responder = SpspResponder::new(ilp_address, server_secret);
+                    if let Some(tag) = tag {
+                        responder.generate_http_response_with_tag(tag.as_bytes())
+                            .map_err(|_| {
+                                error!("Invalid sub-receiver tag: {}", tag);
+                                Response::builder().status(400).body(()).unwrap()
+                            })
+                    } else {
+                        Ok(responder.generate_http_response())
+                    }
+                    }))
+        }
+
+        // Senders query the link like any other SPSP endpoint, which also tells them the amount
+        // and what it is for. Every connection it generates is tagged with the link so the
+        // receiver only accepts the link's amount on them
+        #[get("/payment_links/:link_id")]
+        fn get_payment_link_spsp(&self, link_id: String) -> impl Future<Item = Response<Body>, Error = Response<()>> {
+            let server_secret = self.server_secret.clone();
+            let store = self.store.clone();
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+            self.store.get_payment_link(&link_id)
+                .map_err(error_response)
+                .and_then(move |link| match link.map(|link| link.check_expiry(now)) {
+                    Some(ref link) if link.status != PaymentLinkStatus::Open => {
+                        debug!("Payment link {} is {:?}", link.id, link.status);
+                        Err(Response::builder().status(410).body(()).unwrap())
+                    }
+                    Some(link) => Ok(link),
+                    None => Err(Response::builder().status(404).body(()).unwrap()),
+                })
+                .and_then(move |link| {
+                    let account_id = A::AccountId::from_str(&link.account_id)
+                        .map_err(|_| Response::builder().status(500).body(()).unwrap())?;
+                    Ok((link, account_id))
+                })
+                .and_then(move |(link, account_id)| store.get_accounts(vec![account_id])
+                    .and_then(|mut accounts| accounts.remove(0).ok_or(()))
+                    .map_err(|_| Response::builder().status(404).body(()).unwrap())
+                    .and_then(move |account| {
+                        let ilp_address = Bytes::from(account.client_address());
+                        let balance = SpspBalance {
+                            maximum: link.amount.to_string(),
+                            current: link.received.to_string(),
+                        };
+                        SpspResponder::new(ilp_address, server_secret)
+                            .generate_http_response_for_amount(link.connection_tag().as_bytes(), balance, link.description.clone())
+                            .map_err(|_| Response::builder().status(500).body(()).unwrap())
+                    }))
         }
 
         // TODO resolve payment pointers with subdomains to the correct account
@@ -387,12 +2790,13 @@ impl_web! {
             let default_account = A::AccountId::default();
             let server_secret = self.server_secret.clone();
             self.store.get_accounts(vec![default_account])
+            .and_then(|mut accounts| accounts.remove(0).ok_or(()))
             .map_err(move |_| {
                 error!("Account not found: {}", default_account);
                 Response::builder().status(404).body(()).unwrap()
             })
-            .and_then(move |accounts| {
-                let ilp_address = Bytes::from(accounts[0].client_address());
+            .and_then(move |account| {
+                let ilp_address = Bytes::from(account.client_address());
                 Ok(SpspResponder::new(ilp_address, server_secret)
                     .generate_http_response())
                 })
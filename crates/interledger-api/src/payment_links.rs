@@ -0,0 +1,336 @@
+use super::{NodeStore, PaymentStore};
+use bytes::Bytes;
+use futures::{future::ok, Future};
+use interledger_service::{Account as AccountTrait, StoreError};
+use interledger_stream::{ConnectionPolicy, ConnectionPolicyRequest};
+use reqwest::r#async::Client;
+use serde::{Deserialize, Serialize};
+use std::{
+    str,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use url::Url;
+
+/// The connections generated for payment links are tagged with this followed by the link's ID
+/// (see `ConnectionGenerator::generate_address_and_secret_with_tag`).
+pub const PAYMENT_LINK_TAG_PREFIX: &str = "link-";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PaymentLinkStatus {
+    /// Waiting to be paid
+    Open,
+    /// The full amount was received, so the link does not accept any more payments
+    Paid,
+    /// The link was not paid in full before it expired
+    Expired,
+}
+
+/// A one-time SPSP endpoint that accepts a payment of a fixed amount until it expires,
+/// for example for an order in a checkout flow.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PaymentLink {
+    /// Assigned by the store. IDs are random, so links cannot be found by guessing them
+    pub id: String,
+    /// The account that is paid
+    pub account_id: String,
+    /// In the account's base units
+    pub amount: u64,
+    /// The amount received so far, which is at most `amount`
+    pub received: u64,
+    pub status: PaymentLinkStatus,
+    /// Shown to the sender, such as an order number
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// Seconds since the UNIX epoch
+    pub created_at: u64,
+    /// Seconds since the UNIX epoch
+    pub expires_at: u64,
+    /// Seconds since the UNIX epoch
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub paid_at: Option<u64>,
+}
+
+impl PaymentLink {
+    pub fn new(
+        account_id: String,
+        amount: u64,
+        description: Option<String>,
+        created_at: u64,
+        expires_at: u64,
+    ) -> Self {
+        PaymentLink {
+            id: String::new(),
+            account_id,
+            amount,
+            received: 0,
+            status: PaymentLinkStatus::Open,
+            description,
+            created_at,
+            expires_at,
+            paid_at: None,
+        }
+    }
+
+    /// The tag of the connections that pay the link.
+    pub fn connection_tag(&self) -> String {
+        format!("{}{}", PAYMENT_LINK_TAG_PREFIX, self.id)
+    }
+
+    /// Stores only record when links are paid, so open links that are past their
+    /// expiry are marked as expired when they are read.
+    pub fn check_expiry(mut self, now: u64) -> Self {
+        if self.status == PaymentLinkStatus::Open && now >= self.expires_at {
+            self.status = PaymentLinkStatus::Expired;
+        }
+        self
+    }
+}
+
+/// What is POSTed to the payment link webhook when a link is paid.
+#[derive(Debug, Serialize)]
+struct PaidPaymentLink {
+    #[serde(flatten)]
+    link: PaymentLink,
+    /// The metadata the sender attached to the payment that completed the link, if any
+    #[serde(skip_serializing_if = "Option::is_none")]
+    metadata: Option<String>,
+}
+
+/// Store that keeps the payment links created by each account.
+pub trait PaymentLinkStore: NodeStore {
+    /// Save a new payment link, returning it with the ID assigned to it.
+    fn add_payment_link(
+        &self,
+        link: PaymentLink,
+    ) -> Box<Future<Item = PaymentLink, Error = StoreError> + Send>;
+
+    fn get_payment_link(
+        &self,
+        id: &str,
+    ) -> Box<Future<Item = Option<PaymentLink>, Error = StoreError> + Send>;
+
+    /// Get the payment links created by the account, oldest first.
+    fn get_payment_links(
+        &self,
+        account_id: <Self::Account as AccountTrait>::AccountId,
+    ) -> Box<Future<Item = Vec<PaymentLink>, Error = StoreError> + Send>;
+
+    /// Add the amount to what the link has received, if the link belongs to the account,
+    /// is open at `now` and the amount does not take it over the link's amount. The link
+    /// is marked as paid when it has received the full amount.
+    ///
+    /// This must be atomic, so that links are not paid more than once when several
+    /// packets are received at the same time. Resolves to the updated link, or to
+    /// the reason the amount was not accepted.
+    fn pay_payment_link(
+        &self,
+        id: &str,
+        account_id: &str,
+        amount: u64,
+        now: u64,
+    ) -> Box<Future<Item = PaymentLink, Error = String> + Send>;
+
+    /// Take back an amount added by `pay_payment_link` for a packet that was not fulfilled
+    /// after all. A link that was marked as paid is opened again if it no longer has the full
+    /// amount.
+    fn undo_payment_link_payment(
+        &self,
+        id: &str,
+        amount: u64,
+    ) -> Box<Future<Item = (), Error = ()> + Send>;
+
+    /// Resolves to the link if it has been paid and this is the first time this is called for
+    /// it since, so that each payment is announced once.
+    fn take_paid_payment_link(
+        &self,
+        id: &str,
+    ) -> Box<Future<Item = Option<PaymentLink>, Error = ()> + Send>;
+}
+
+/// A `ConnectionPolicy` that only accepts money on the connections of payment links while the
+/// links are open, up to their amounts. Each link is marked as consumed once it is paid in full,
+/// and POSTed to a webhook URL, with the metadata of the payment that completed it, once the
+/// packet that completed it is fulfilled.
+///
+/// Packets on other connections are accepted.
+#[derive(Clone)]
+pub struct PaymentLinkPolicy<T> {
+    store: T,
+    webhook: Option<Url>,
+    client: Client,
+}
+
+impl<T> PaymentLinkPolicy<T> {
+    pub fn new(store: T, webhook: Option<Url>) -> Self {
+        PaymentLinkPolicy {
+            store,
+            webhook,
+            client: Client::new(),
+        }
+    }
+}
+
+// The ID of the payment link the connection was generated for, if any
+fn payment_link_id(connection_tag: &Option<Bytes>) -> Option<&str> {
+    let tag = str::from_utf8(connection_tag.as_ref()?).ok()?;
+    if tag.starts_with(PAYMENT_LINK_TAG_PREFIX) {
+        Some(&tag[PAYMENT_LINK_TAG_PREFIX.len()..])
+    } else {
+        None
+    }
+}
+
+// The ID of the payment link a packet with money is paying, if any
+fn paid_link_id(request: &ConnectionPolicyRequest) -> Option<&str> {
+    // Packets without money, such as the one closing the connection, are always accepted
+    if request.amount == 0 {
+        return None;
+    }
+    payment_link_id(&request.connection_tag)
+}
+
+impl<T, A> ConnectionPolicy<A> for PaymentLinkPolicy<T>
+where
+    T: PaymentLinkStore + PaymentStore,
+    A: AccountTrait,
+{
+    // The amount is added to the link before the packet is fulfilled so that links cannot be
+    // overpaid by packets arriving at the same time, and taken back if the packet is rejected
+    fn check_packet(
+        &self,
+        account: &A,
+        request: &ConnectionPolicyRequest,
+    ) -> Box<Future<Item = (), Error = String> + Send> {
+        let id = match paid_link_id(request) {
+            Some(id) => id,
+            None => return Box::new(ok(())),
+        };
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        Box::new(
+            self.store
+                .pay_payment_link(id, &account.id().to_string(), request.amount, now)
+                .map(|_| ()),
+        )
+    }
+
+    fn packet_fulfilled(
+        &self,
+        _account: &A,
+        request: &ConnectionPolicyRequest,
+    ) -> Box<Future<Item = (), Error = ()> + Send> {
+        let id = match paid_link_id(request) {
+            Some(id) => id,
+            None => return Box::new(ok(())),
+        };
+        let webhook = self.webhook.clone();
+        let client = self.client.clone();
+        let store = self.store.clone();
+        let connection_id = String::from_utf8_lossy(&request.connection_id[..]).to_string();
+        Box::new(self.store.take_paid_payment_link(id).map(move |link| {
+            let link = match link {
+                Some(link) => link,
+                None => return,
+            };
+            info!("Payment link {} was paid", link.id);
+            if let Some(webhook) = webhook {
+                // The webhook is sent in the background so it doesn't hold up the packet.
+                // The metadata was recorded before the packet was fulfilled
+                let load_metadata = store
+                    .get_incoming_payment(connection_id)
+                    .map(|payment| payment.and_then(|payment| payment.metadata))
+                    .or_else(|err| {
+                        error!("Error loading the payment that paid a link: {:?}", err);
+                        Ok(None)
+                    });
+                hyper::rt::spawn(
+                    load_metadata
+                        .and_then(move |metadata| {
+                            client
+                                .post(webhook.clone())
+                                .json(&PaidPaymentLink { link, metadata })
+                                .send()
+                                .map_err(move |err| {
+                                    error!(
+                                        "Error sending payment link webhook to {}: {:?}",
+                                        webhook, err
+                                    )
+                                })
+                        })
+                        .and_then(|response| {
+                            if !response.status().is_success() {
+                                warn!(
+                                    "Payment link webhook returned status: {}",
+                                    response.status()
+                                );
+                            }
+                            Ok(())
+                        }),
+                );
+            }
+        }))
+    }
+
+    fn packet_rejected(
+        &self,
+        _account: &A,
+        request: &ConnectionPolicyRequest,
+    ) -> Box<Future<Item = (), Error = ()> + Send> {
+        match paid_link_id(request) {
+            Some(id) => self.store.undo_payment_link_payment(id, request.amount),
+            None => Box::new(ok(())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_link_in_connection_tag() {
+        assert_eq!(
+            payment_link_id(&Some(Bytes::from("link-abc123"))),
+            Some("abc123")
+        );
+        assert_eq!(payment_link_id(&Some(Bytes::from("invoice1"))), None);
+        assert_eq!(payment_link_id(&None), None);
+    }
+
+    #[test]
+    fn sends_metadata_with_paid_link() {
+        let link = PaymentLink::new("1".to_string(), 100, None, 1000, 2000);
+        let paid = serde_json::to_value(PaidPaymentLink {
+            link: link.clone(),
+            metadata: Some("order-1".to_string()),
+        })
+        .unwrap();
+        assert_eq!(paid["account_id"], "1");
+        assert_eq!(paid["metadata"], "order-1");
+        let paid = serde_json::to_value(PaidPaymentLink {
+            link,
+            metadata: None,
+        })
+        .unwrap();
+        assert!(paid.get("metadata").is_none());
+    }
+
+    #[test]
+    fn open_links_expire() {
+        let link = PaymentLink::new("1".to_string(), 100, None, 1000, 2000);
+        assert_eq!(
+            link.clone().check_expiry(1999).status,
+            PaymentLinkStatus::Open
+        );
+        assert_eq!(
+            link.clone().check_expiry(2000).status,
+            PaymentLinkStatus::Expired
+        );
+        let mut paid = link;
+        paid.status = PaymentLinkStatus::Paid;
+        assert_eq!(paid.check_expiry(3000).status, PaymentLinkStatus::Paid);
+    }
+}
@@ -0,0 +1,273 @@
+use super::NodeStore;
+use bytes::Bytes;
+use futures::{future::ok, Future};
+use interledger_service::{Account as AccountTrait, StoreError};
+use interledger_stream::{
+    StreamConnectionStore, StreamDataHandler, StreamDataRequest, METADATA_STREAM_ID,
+    RETURN_POINTER_STREAM_ID,
+};
+use reqwest::r#async::Client;
+use serde::Serialize;
+use std::str;
+use url::Url;
+
+/// The money received on one STREAM connection.
+///
+/// Senders open a new connection for each payment, so the packets sent over
+/// a connection are grouped together into a single payment.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct IncomingPayment {
+    /// The last segment of the ILP address the payment was sent to
+    pub connection_id: String,
+    pub account_id: String,
+    /// Total received, in the account's base units
+    pub received: u64,
+    /// The asset the amount received is denominated in.
+    /// This is also advertised to the sender so it knows what was delivered.
+    /// It is not recorded for payments received before nodes advertised their asset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub asset_code: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub asset_scale: Option<u8>,
+    /// Seconds since the UNIX epoch
+    pub started_at: u64,
+    /// Seconds since the UNIX epoch
+    pub updated_at: u64,
+    /// Whether the sender closed the connection
+    pub completed: bool,
+    /// The payment pointer the sender asked for refunds to be sent to, if any
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub return_pointer: Option<String>,
+    /// How much of the amount received has been refunded (or is being refunded)
+    pub refunded: u64,
+    /// The tag of the sub-receiver the payment was sent to, such as `invoice123` for the
+    /// payment pointer `$node.example/spsp/1~invoice123`, or of the payment link it paid
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tag: Option<String>,
+    /// Application data the sender attached to the payment, such as an invoice or order ID
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<String>,
+}
+
+/// Store that keeps a record of the payments received by each account.
+pub trait PaymentStore: NodeStore {
+    /// Get the payments received by the account, newest first.
+    fn get_incoming_payments(
+        &self,
+        account_id: <Self::Account as AccountTrait>::AccountId,
+    ) -> Box<Future<Item = Vec<IncomingPayment>, Error = StoreError> + Send>;
+
+    fn get_incoming_payment(
+        &self,
+        connection_id: String,
+    ) -> Box<Future<Item = Option<IncomingPayment>, Error = StoreError> + Send>;
+
+    /// Get the most recent payments received by any account, newest first.
+    fn get_recent_payments(
+        &self,
+        limit: usize,
+    ) -> Box<Future<Item = Vec<IncomingPayment>, Error = StoreError> + Send>;
+
+    /// Record the payment pointer the sender of the payment asked for refunds to be sent to.
+    fn set_return_pointer(
+        &self,
+        connection_id: &[u8],
+        return_pointer: &str,
+    ) -> Box<Future<Item = (), Error = ()> + Send>;
+
+    /// Record the metadata the sender attached to the payment.
+    fn set_metadata(
+        &self,
+        connection_id: &[u8],
+        metadata: &str,
+    ) -> Box<Future<Item = (), Error = ()> + Send>;
+}
+
+/// Wraps the store used by the STREAM receiver and POSTs each payment
+/// to a webhook URL when it is completed.
+#[derive(Clone)]
+pub struct PaymentNotifier<T> {
+    store: T,
+    webhook: Option<Url>,
+    client: Client,
+}
+
+impl<T> PaymentNotifier<T> {
+    pub fn new(store: T, webhook: Option<Url>) -> Self {
+        PaymentNotifier {
+            store,
+            webhook,
+            client: Client::new(),
+        }
+    }
+}
+
+impl<T, A> StreamConnectionStore<A> for PaymentNotifier<T>
+where
+    T: StreamConnectionStore<A> + PaymentStore,
+    A: AccountTrait,
+{
+    fn add_amount_received(
+        &self,
+        account: &A,
+        connection_id: &[u8],
+        connection_tag: Option<&[u8]>,
+        amount: u64,
+    ) -> Box<Future<Item = u64, Error = ()> + Send> {
+        self.store
+            .add_amount_received(account, connection_id, connection_tag, amount)
+    }
+
+    fn close_connection(
+        &self,
+        account: &A,
+        connection_id: &[u8],
+    ) -> Box<Future<Item = (), Error = ()> + Send> {
+        let closed = self.store.close_connection(account, connection_id);
+        let webhook = if let Some(ref webhook) = self.webhook {
+            webhook.clone()
+        } else {
+            return closed;
+        };
+        let store = self.store.clone();
+        let client = self.client.clone();
+        let connection_id = String::from_utf8_lossy(connection_id).to_string();
+        Box::new(closed.and_then(move |_| {
+            // The webhook is sent in the background so it doesn't hold up the response to the sender
+            let notify = store
+                .get_incoming_payment(connection_id)
+                .map_err(|err| error!("Error loading completed payment: {:?}", err))
+                .and_then(move |payment| {
+                    if let Some(payment) = payment {
+                        Box::new(
+                            client
+                                .post(webhook.clone())
+                                .json(&payment)
+                                .send()
+                                .map_err(move |err| {
+                                    error!(
+                                        "Error sending payment webhook to {}: {:?}",
+                                        webhook, err
+                                    )
+                                })
+                                .and_then(|response| {
+                                    if !response.status().is_success() {
+                                        warn!(
+                                            "Payment webhook returned status: {}",
+                                            response.status()
+                                        );
+                                    }
+                                    Ok(())
+                                }),
+                        ) as Box<Future<Item = (), Error = ()> + Send>
+                    } else {
+                        Box::new(ok(()))
+                    }
+                });
+            hyper::rt::spawn(notify);
+            Ok(())
+        }))
+    }
+}
+
+/// Longer return pointers are ignored
+const MAX_RETURN_POINTER_LENGTH: usize = 256;
+/// Longer metadata is ignored, so the API does not send it
+pub(crate) const MAX_METADATA_LENGTH: usize = 256;
+
+/// A `StreamDataHandler` that records the data senders send with their payments: the return
+/// pointer sent on the `RETURN_POINTER_STREAM_ID` stream, so that the payments can be refunded,
+/// and the metadata sent on the `METADATA_STREAM_ID` stream.
+#[derive(Clone)]
+pub struct PaymentDataRecorder<T> {
+    store: T,
+}
+
+impl<T> PaymentDataRecorder<T> {
+    pub fn new(store: T) -> Self {
+        PaymentDataRecorder { store }
+    }
+}
+
+// The return pointer in the data, if it looks like a payment pointer or an SPSP URL
+fn parse_return_pointer(data: &[u8]) -> Option<&str> {
+    if data.len() > MAX_RETURN_POINTER_LENGTH {
+        return None;
+    }
+    let return_pointer = str::from_utf8(data).ok()?;
+    if (return_pointer.starts_with('$') && return_pointer.len() > 1)
+        || return_pointer.starts_with("https://")
+    {
+        Some(return_pointer)
+    } else {
+        None
+    }
+}
+
+// The metadata in the data, if it is text that is short enough
+fn parse_metadata(data: &[u8]) -> Option<&str> {
+    if data.is_empty() || data.len() > MAX_METADATA_LENGTH {
+        return None;
+    }
+    str::from_utf8(data).ok()
+}
+
+impl<T, A> StreamDataHandler<A> for PaymentDataRecorder<T>
+where
+    T: PaymentStore,
+    A: AccountTrait,
+{
+    fn handle_data(
+        &self,
+        _account: &A,
+        request: StreamDataRequest,
+    ) -> Box<Future<Item = Option<Bytes>, Error = ()> + Send> {
+        // Senders send the whole value in one frame, which may be repeated in several packets
+        if request.offset != 0 {
+            return Box::new(ok(None));
+        }
+        let connection_id = &request.connection_id[..];
+        let saved = match request.stream_id {
+            RETURN_POINTER_STREAM_ID => parse_return_pointer(&request.data[..])
+                .map(|return_pointer| self.store.set_return_pointer(connection_id, return_pointer)),
+            METADATA_STREAM_ID => parse_metadata(&request.data[..])
+                .map(|metadata| self.store.set_metadata(connection_id, metadata)),
+            _ => return Box::new(ok(None)),
+        };
+        match saved {
+            Some(saved) => Box::new(saved.map(|_| None)),
+            None => {
+                debug!("Ignoring invalid data on stream {}", request.stream_id);
+                Box::new(ok(None))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_accepts_payment_pointers_as_return_pointers() {
+        assert_eq!(
+            parse_return_pointer(b"$wallet.example/alice"),
+            Some("$wallet.example/alice")
+        );
+        assert_eq!(
+            parse_return_pointer(b"https://wallet.example/alice"),
+            Some("https://wallet.example/alice")
+        );
+        assert_eq!(parse_return_pointer(b"$"), None);
+        assert_eq!(parse_return_pointer(b"http://wallet.example"), None);
+        assert_eq!(parse_return_pointer(&[b'$'; 300][..]), None);
+    }
+
+    #[test]
+    fn only_accepts_short_text_as_metadata() {
+        assert_eq!(parse_metadata(b"order-1"), Some("order-1"));
+        assert_eq!(parse_metadata(b""), None);
+        assert_eq!(parse_metadata(&[0xff, 0xfe]), None);
+        assert_eq!(parse_metadata(&[b'a'; 300][..]), None);
+    }
+}
@@ -0,0 +1,179 @@
+use super::runs::INTERRUPTED_AFTER;
+use super::PaymentStore;
+use futures::Future;
+use interledger_service::StoreError;
+use interledger_stream::{PaymentEndState, PaymentOutcome};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RefundStatus {
+    /// The refund is being sent
+    Sending,
+    /// The whole amount was sent
+    Completed,
+    /// Only part of the amount was sent
+    Partial,
+    /// Nothing was sent
+    Failed,
+    /// The node stopped while the refund was being sent, so it is not known how much was sent.
+    /// None of the amount can be refunded again
+    Interrupted,
+}
+
+/// Money sent back to the sender of a received payment, at the return pointer
+/// the sender gave with the payment.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Refund {
+    /// Assigned by the store
+    pub id: u64,
+    /// The connection of the payment that is refunded
+    pub connection_id: String,
+    /// The account that received the payment and sends the refund
+    pub account_id: String,
+    pub return_pointer: String,
+    /// In the account's base units
+    pub amount: u64,
+    /// In the account's base units
+    pub source_amount_sent: u64,
+    /// In the sender's units, as reported by the sender
+    pub amount_delivered: u64,
+    pub status: RefundStatus,
+    /// Why the refund was not sent in full
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    /// Seconds since the UNIX epoch
+    pub created_at: u64,
+}
+
+impl Refund {
+    /// Record the result of sending the refund.
+    pub fn finish(&mut self, outcome: &PaymentOutcome) {
+        self.source_amount_sent = outcome.source_amount_sent;
+        self.amount_delivered = outcome.amount_delivered;
+        self.status = if outcome.is_complete() {
+            RefundStatus::Completed
+        } else if outcome.source_amount_sent > 0 {
+            RefundStatus::Partial
+        } else {
+            RefundStatus::Failed
+        };
+        self.reason = match outcome.end_state {
+            PaymentEndState::Completed => None,
+            PaymentEndState::Partial(ref reason) => Some(reason.clone()),
+            PaymentEndState::FailedWithCode(code) => Some(code.to_string()),
+        };
+    }
+
+    /// Record that the refund could not be sent at all.
+    pub fn fail(&mut self, reason: String) {
+        self.status = RefundStatus::Failed;
+        self.reason = Some(reason);
+    }
+
+    /// Stores only record when refunds finish, so refunds that have been sending for too
+    /// long are marked as interrupted when they are read.
+    pub fn check_interrupted(mut self, now: u64) -> Self {
+        if self.status == RefundStatus::Sending
+            && now.saturating_sub(self.created_at) >= INTERRUPTED_AFTER
+        {
+            self.status = RefundStatus::Interrupted;
+        }
+        self
+    }
+}
+
+/// Store that keeps the refunds of the payments received by each account.
+pub trait RefundStore: PaymentStore {
+    /// Reserve the amount of the payment for a new refund, or all of it that has not been
+    /// refunded yet if no amount is given. Errors with `NotFound` if the account did not
+    /// receive the payment, `InvalidData` if the sender did not give a return pointer,
+    /// and `InsufficientBalance` if the amount is more than what is left to refund.
+    ///
+    /// This must be atomic, so that concurrent refunds cannot add up to more than the payment.
+    fn add_refund(
+        &self,
+        connection_id: &str,
+        account_id: &str,
+        amount: Option<u64>,
+        created_at: u64,
+    ) -> Box<Future<Item = Refund, Error = StoreError> + Send>;
+
+    /// Save the result of sending the refund. Any of its amount that was not sent
+    /// can be refunded again.
+    fn finish_refund(&self, refund: Refund) -> Box<Future<Item = (), Error = StoreError> + Send>;
+
+    /// Get the refunds of the payment, oldest first.
+    fn get_refunds(
+        &self,
+        connection_id: &str,
+    ) -> Box<Future<Item = Vec<Refund>, Error = StoreError> + Send>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn outcome(source_amount_sent: u64, end_state: PaymentEndState) -> PaymentOutcome {
+        PaymentOutcome {
+            amount_delivered: source_amount_sent,
+            source_amount_sent,
+            receiver_asset: None,
+            response: None,
+            end_state,
+            fulfilled_packets: 1,
+            rejected_packets: Default::default(),
+        }
+    }
+
+    fn refund() -> Refund {
+        Refund {
+            id: 1,
+            connection_id: "abc".to_string(),
+            account_id: "1".to_string(),
+            return_pointer: "$sender.example".to_string(),
+            amount: 10,
+            source_amount_sent: 0,
+            amount_delivered: 0,
+            status: RefundStatus::Sending,
+            reason: None,
+            created_at: 100,
+        }
+    }
+
+    #[test]
+    fn records_how_much_was_sent() {
+        let mut completed = refund();
+        completed.finish(&outcome(10, PaymentEndState::Completed));
+        assert_eq!(completed.status, RefundStatus::Completed);
+        assert_eq!(completed.source_amount_sent, 10);
+        assert_eq!(completed.reason, None);
+
+        let mut partial = refund();
+        partial.finish(&outcome(
+            4,
+            PaymentEndState::Partial("Timed out".to_string()),
+        ));
+        assert_eq!(partial.status, RefundStatus::Partial);
+        assert_eq!(partial.reason, Some("Timed out".to_string()));
+
+        let mut failed = refund();
+        failed.finish(&outcome(
+            0,
+            PaymentEndState::Partial("Timed out".to_string()),
+        ));
+        assert_eq!(failed.status, RefundStatus::Failed);
+    }
+
+    #[test]
+    fn refunds_that_stay_sending_are_interrupted() {
+        assert_eq!(
+            refund().check_interrupted(200).status,
+            RefundStatus::Sending
+        );
+        assert_eq!(
+            refund().check_interrupted(100 + INTERRUPTED_AFTER).status,
+            RefundStatus::Interrupted
+        );
+    }
+}
@@ -0,0 +1,84 @@
+use futures::{
+    future::{loop_fn, result, Loop},
+    Future,
+};
+use interledger_service::{Account as AccountTrait, AccountStore, StoreError};
+use std::{
+    str::FromStr,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Seconds after which a payment that is still marked as being sent is assumed to have been
+/// interrupted, for example because the node stopped in the middle of it
+pub(crate) const INTERRUPTED_AFTER: u64 = 3600;
+
+/// How many times saving the result of a payment is attempted when the item keeps being
+/// changed (for example, stopped or cancelled) at the same time
+const MAX_SAVE_ATTEMPTS: usize = 5;
+
+/// Seconds since the UNIX epoch
+pub(crate) fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+/// Load the account a payment is sent from.
+pub(crate) fn load_account<T, A>(
+    store: T,
+    account_id: &str,
+) -> impl Future<Item = A, Error = String>
+where
+    T: AccountStore<Account = A>,
+    A: AccountTrait,
+{
+    result(A::AccountId::from_str(account_id))
+        .map_err(|_| "Invalid account ID".to_string())
+        .and_then(move |account_id| {
+            store
+                .get_accounts(vec![account_id])
+                .and_then(|mut accounts| accounts.remove(0).ok_or(()))
+                .map_err(|_| "Account not found".to_string())
+        })
+}
+
+/// Load the latest version of an item, change it and save it, starting over if someone else
+/// saved it in the meantime. `save` must fail with `StoreError::Conflict` in that case.
+///
+/// Scheduled payments and streaming sessions are marked as being sent before a payment is
+/// sent, and the result is recorded with this afterwards, so that changes made while the
+/// payment was being sent (such as cancelling it) are kept.
+pub(crate) fn update_latest<I, L, LF, S, SF, C>(
+    load: L,
+    save: S,
+    change: C,
+) -> impl Future<Item = I, Error = StoreError>
+where
+    L: Fn() -> LF,
+    LF: Future<Item = Option<I>, Error = StoreError>,
+    S: Fn(I) -> SF + Send + Sync + 'static,
+    SF: Future<Item = I, Error = StoreError>,
+    C: Fn(&mut I) + Send + Sync + 'static,
+{
+    let save = Arc::new(save);
+    let change = Arc::new(change);
+    loop_fn(0, move |attempt| {
+        let save = save.clone();
+        let change = change.clone();
+        load()
+            .and_then(|item| item.ok_or(StoreError::NotFound))
+            .and_then(move |mut item| {
+                change(&mut item);
+                save(item)
+            })
+            .then(move |saved| match saved {
+                Ok(item) => Ok(Loop::Break(item)),
+                Err(StoreError::Conflict) if attempt + 1 < MAX_SAVE_ATTEMPTS => {
+                    Ok(Loop::Continue(attempt + 1))
+                }
+                Err(err) => Err(err),
+            })
+    })
+}
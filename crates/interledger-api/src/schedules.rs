@@ -0,0 +1,495 @@
+use super::runs::{load_account, now, update_latest, INTERRUPTED_AFTER};
+use super::NodeStore;
+use futures::{
+    future::{join_all, ok, Either},
+    Future,
+};
+use interledger_service::{Account as AccountTrait, AccountStore, IncomingService, StoreError};
+use interledger_spsp::pay_with_options;
+use interledger_stream::{PaymentEndState, PaymentOutcome, SendMoneyOptions};
+use reqwest::r#async::Client;
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+/// How many times a run that sent nothing is attempted before it is given up on
+pub const MAX_SCHEDULED_PAYMENT_ATTEMPTS: u32 = 5;
+
+/// Seconds to wait before the first retry. The delay doubles with each attempt
+const RETRY_DELAY: u64 = 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ScheduleStatus {
+    /// Waiting for the next run
+    Scheduled,
+    /// A one-off payment that has been sent
+    Completed,
+    /// A one-off payment that could not be sent after all of its attempts
+    Failed,
+    Cancelled,
+}
+
+/// The result of the last attempt to send a scheduled payment.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ScheduledRun {
+    /// Seconds since the UNIX epoch
+    pub at: u64,
+    pub amount_delivered: u64,
+    pub source_amount_sent: u64,
+    /// "completed", "partial", "failed" or "interrupted"
+    pub end_state: String,
+    /// Why the payment stopped or failed
+    pub error: Option<String>,
+}
+
+/// An outgoing payment that is sent at a later time, and optionally repeated at a fixed interval.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ScheduledPayment {
+    /// Assigned by the store
+    pub id: u64,
+    /// The account the payment is sent from
+    pub account_id: String,
+    /// The payment pointer or SPSP URL to pay
+    pub receiver: String,
+    /// In the sending account's base units
+    pub source_amount: u64,
+    /// Seconds since the UNIX epoch of the first run. Recurring payments
+    /// run at this time plus multiples of the interval
+    pub start_at: u64,
+    /// Seconds between runs, if the payment is recurring
+    pub interval: Option<u64>,
+    /// Seconds since the UNIX epoch of the next run or retry
+    pub next_run: u64,
+    pub status: ScheduleStatus,
+    /// The number of times the current run has failed
+    pub failed_attempts: u32,
+    /// The number of runs that sent money
+    pub runs: u64,
+    pub last_run: Option<ScheduledRun>,
+    /// Seconds since the UNIX epoch
+    pub created_at: u64,
+    /// Seconds since the UNIX epoch that the run being sent started at, if one is
+    #[serde(default)]
+    pub sending_since: Option<u64>,
+    /// Incremented each time the payment is saved, so that the store can refuse to save
+    /// changes made to an outdated copy
+    #[serde(default)]
+    pub version: u64,
+}
+
+impl ScheduledPayment {
+    pub fn new(
+        account_id: String,
+        receiver: String,
+        source_amount: u64,
+        start_at: u64,
+        interval: Option<u64>,
+        created_at: u64,
+    ) -> Self {
+        ScheduledPayment {
+            id: 0,
+            account_id,
+            receiver,
+            source_amount,
+            start_at,
+            interval,
+            next_run: start_at,
+            status: ScheduleStatus::Scheduled,
+            failed_attempts: 0,
+            runs: 0,
+            last_run: None,
+            created_at,
+            sending_since: None,
+            version: 0,
+        }
+    }
+
+    /// Record the result of the run that was being sent. Payments that were cancelled
+    /// in the meantime stay cancelled.
+    pub fn finish_run(&mut self, now: u64, outcome: Result<PaymentOutcome, String>) {
+        let status = self.status;
+        self.sending_since = None;
+        self.record_run(now, outcome);
+        if status == ScheduleStatus::Cancelled {
+            self.status = status;
+        }
+    }
+
+    /// Record a run that was interrupted, for example because the node stopped in the middle of
+    /// it. The receiver may have been paid, so the run is not retried.
+    pub fn record_interrupted_run(&mut self, now: u64) {
+        self.sending_since = None;
+        self.failed_attempts = 0;
+        self.last_run = Some(ScheduledRun {
+            at: now,
+            amount_delivered: 0,
+            source_amount_sent: 0,
+            end_state: "interrupted".to_string(),
+            error: Some("The node stopped while the payment was being sent".to_string()),
+        });
+        if self.status == ScheduleStatus::Scheduled {
+            self.advance(now, ScheduleStatus::Failed);
+        }
+    }
+
+    /// Record the result of sending the payment and work out when it should run next.
+    ///
+    /// Runs that sent any money are not retried, even if the payment did not complete,
+    /// so that the receiver is never paid twice. Runs that sent nothing are retried with
+    /// an increasing delay, and skipped (or, for one-off payments, failed) after
+    /// `MAX_SCHEDULED_PAYMENT_ATTEMPTS` attempts.
+    pub fn record_run(&mut self, now: u64, outcome: Result<PaymentOutcome, String>) {
+        let run = match outcome {
+            Ok(outcome) => {
+                let (end_state, error) = match outcome.end_state {
+                    PaymentEndState::Completed => ("completed", None),
+                    PaymentEndState::Partial(reason) => ("partial", Some(reason)),
+                    PaymentEndState::FailedWithCode(code) => ("failed", Some(code.to_string())),
+                };
+                ScheduledRun {
+                    at: now,
+                    amount_delivered: outcome.amount_delivered,
+                    source_amount_sent: outcome.source_amount_sent,
+                    end_state: end_state.to_string(),
+                    error,
+                }
+            }
+            Err(error) => ScheduledRun {
+                at: now,
+                amount_delivered: 0,
+                source_amount_sent: 0,
+                end_state: "failed".to_string(),
+                error: Some(error),
+            },
+        };
+
+        if run.source_amount_sent > 0 || run.end_state == "completed" {
+            self.runs += 1;
+            self.failed_attempts = 0;
+            self.advance(now, ScheduleStatus::Completed);
+        } else {
+            self.failed_attempts += 1;
+            if self.failed_attempts < MAX_SCHEDULED_PAYMENT_ATTEMPTS {
+                self.next_run = now.saturating_add(RETRY_DELAY << (self.failed_attempts - 1));
+            } else {
+                self.failed_attempts = 0;
+                self.advance(now, ScheduleStatus::Failed);
+            }
+        }
+        self.last_run = Some(run);
+    }
+
+    // Move recurring payments on to their next run after now, and end one-off payments
+    // as well as recurring ones whose next run would be too far in the future to represent
+    fn advance(&mut self, now: u64, end_status: ScheduleStatus) {
+        let next_run = match self.interval {
+            Some(interval) if interval > 0 => {
+                if now < self.start_at {
+                    Some(self.start_at)
+                } else {
+                    ((now - self.start_at) / interval)
+                        .checked_add(1)
+                        .and_then(|runs| interval.checked_mul(runs))
+                        .and_then(|offset| self.start_at.checked_add(offset))
+                }
+            }
+            _ => None,
+        };
+        match next_run {
+            Some(next_run) => self.next_run = next_run,
+            None => self.status = end_status,
+        }
+    }
+}
+
+/// Store that keeps the payments scheduled by each account.
+pub trait ScheduleStore: NodeStore {
+    /// Save a new scheduled payment, returning it with the ID assigned to it.
+    fn add_scheduled_payment(
+        &self,
+        payment: ScheduledPayment,
+    ) -> Box<Future<Item = ScheduledPayment, Error = StoreError> + Send>;
+
+    /// Save the changes to the payment, unless it was saved by someone else since it was loaded
+    /// (its `version` is not the stored one), in which case this errors with `StoreError::Conflict`.
+    /// Returns the payment with its new version.
+    fn update_scheduled_payment(
+        &self,
+        payment: ScheduledPayment,
+    ) -> Box<Future<Item = ScheduledPayment, Error = StoreError> + Send>;
+
+    fn get_scheduled_payment(
+        &self,
+        id: u64,
+    ) -> Box<Future<Item = Option<ScheduledPayment>, Error = StoreError> + Send>;
+
+    /// Get the payments scheduled by the account, oldest first.
+    fn get_scheduled_payments(
+        &self,
+        account_id: <Self::Account as AccountTrait>::AccountId,
+    ) -> Box<Future<Item = Vec<ScheduledPayment>, Error = StoreError> + Send>;
+
+    /// Get the payments with the `Scheduled` status that should run at or before `now`.
+    fn get_due_scheduled_payments(
+        &self,
+        now: u64,
+    ) -> Box<Future<Item = Vec<ScheduledPayment>, Error = StoreError> + Send>;
+}
+
+/// Sends the scheduled payments that are due, and POSTs each one
+/// to a webhook URL after it runs.
+#[derive(Clone)]
+pub struct PaymentScheduler<T, S> {
+    store: T,
+    service: S,
+    webhook: Option<Url>,
+    client: Client,
+}
+
+impl<T, S, A> PaymentScheduler<T, S>
+where
+    T: ScheduleStore + NodeStore<Account = A> + AccountStore<Account = A>,
+    S: IncomingService<A> + Clone + Send + Sync + 'static,
+    A: AccountTrait + 'static,
+{
+    pub fn new(store: T, service: S, webhook: Option<Url>) -> Self {
+        PaymentScheduler {
+            store,
+            service,
+            webhook,
+            client: Client::new(),
+        }
+    }
+
+    /// Send all of the payments that are due.
+    ///
+    /// Each payment is marked as being sent before it is sent, so it is only sent once even if
+    /// this is called again (or by another node sharing the store) before the returned future
+    /// resolves. Runs that are still marked long after they started are recorded as
+    /// interrupted instead of being sent again.
+    pub fn send_due_payments(&self) -> impl Future<Item = (), Error = ()> {
+        let scheduler = self.clone();
+        self.store
+            .get_due_scheduled_payments(now())
+            .map_err(|err| error!("Error loading scheduled payments: {:?}", err))
+            .and_then(move |payments| {
+                // One failed payment should not stop the others from being sent
+                join_all(
+                    payments
+                        .into_iter()
+                        .map(move |payment| scheduler.send_payment(payment).then(|_| Ok(()))),
+                )
+            })
+            .map(|_| ())
+    }
+
+    fn send_payment(&self, payment: ScheduledPayment) -> impl Future<Item = (), Error = ()> {
+        let now = now();
+        let id = payment.id;
+        match payment.sending_since {
+            Some(since) if now.saturating_sub(since) >= INTERRUPTED_AFTER => {
+                warn!("Run of scheduled payment {} was interrupted", id);
+                let mut payment = payment;
+                payment.record_interrupted_run(now);
+                return Either::A(Either::A(
+                    self.store
+                        .update_scheduled_payment(payment)
+                        .map_err(move |err| {
+                            error!("Error saving scheduled payment {}: {:?}", id, err)
+                        })
+                        .and_then({
+                            let scheduler = self.clone();
+                            move |payment| scheduler.notify(payment)
+                        }),
+                ));
+            }
+            // Being sent by another call or node
+            Some(_) => return Either::A(Either::B(ok(()))),
+            None => {}
+        }
+
+        let mut payment = payment;
+        payment.sending_since = Some(now);
+        let scheduler = self.clone();
+        Either::B(
+            self.store
+                .update_scheduled_payment(payment)
+                .map_err(move |err| {
+                    // Conflicts mean that someone else is sending or just changed the payment
+                    debug!("Not sending scheduled payment {}: {:?}", id, err)
+                })
+                .and_then(move |payment| scheduler.pay(payment)),
+        )
+    }
+
+    fn pay(&self, payment: ScheduledPayment) -> impl Future<Item = (), Error = ()> {
+        debug!(
+            "Sending scheduled payment {} of {} from account {} to {}",
+            payment.id, payment.source_amount, payment.account_id, payment.receiver
+        );
+        let service = self.service.clone();
+        let scheduler = self.clone();
+        let receiver = payment.receiver.clone();
+        let source_amount = payment.source_amount;
+        load_account(self.store.clone(), &payment.account_id)
+            .and_then(move |account| {
+                pay_with_options(
+                    service,
+                    account,
+                    &receiver,
+                    source_amount,
+                    SendMoneyOptions::new(),
+                )
+                .map_err(|err| format!("{:?}", err))
+            })
+            .then(move |outcome| {
+                if let Err(ref err) = outcome {
+                    warn!("Error sending scheduled payment {}: {}", payment.id, err);
+                }
+                scheduler.finish(payment.id, outcome)
+            })
+    }
+
+    // The payment may have been cancelled while it was being sent
+    fn finish(
+        &self,
+        id: u64,
+        outcome: Result<PaymentOutcome, String>,
+    ) -> impl Future<Item = (), Error = ()> {
+        let store = self.store.clone();
+        let store_clone = self.store.clone();
+        let scheduler = self.clone();
+        update_latest(
+            move || store.get_scheduled_payment(id),
+            move |payment| store_clone.update_scheduled_payment(payment),
+            move |payment| payment.finish_run(now(), outcome.clone()),
+        )
+        .map_err(move |err| error!("Error saving scheduled payment {}: {:?}", id, err))
+        .and_then(move |payment| scheduler.notify(payment))
+    }
+
+    fn notify(&self, payment: ScheduledPayment) -> impl Future<Item = (), Error = ()> {
+        let client = self.client.clone();
+        match self.webhook.clone() {
+            Some(webhook) => Either::A(
+                client
+                    .post(webhook.clone())
+                    .json(&payment)
+                    .send()
+                    .map_err(move |err| {
+                        error!(
+                            "Error sending scheduled payment webhook to {}: {:?}",
+                            webhook, err
+                        )
+                    })
+                    .and_then(|response| {
+                        if !response.status().is_success() {
+                            warn!(
+                                "Scheduled payment webhook returned status: {}",
+                                response.status()
+                            );
+                        }
+                        Ok(())
+                    }),
+            ),
+            None => Either::B(ok(())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn outcome(source_amount_sent: u64, end_state: PaymentEndState) -> PaymentOutcome {
+        PaymentOutcome {
+            amount_delivered: source_amount_sent,
+            source_amount_sent,
+            receiver_asset: None,
+            response: None,
+            end_state,
+            fulfilled_packets: 1,
+            rejected_packets: Default::default(),
+        }
+    }
+
+    #[test]
+    fn completes_one_off_payments_and_advances_recurring_ones() {
+        let mut one_off =
+            ScheduledPayment::new("1".to_string(), "$x".to_string(), 10, 100, None, 0);
+        one_off.record_run(105, Ok(outcome(10, PaymentEndState::Completed)));
+        assert_eq!(one_off.status, ScheduleStatus::Completed);
+        assert_eq!(one_off.runs, 1);
+
+        let mut recurring =
+            ScheduledPayment::new("1".to_string(), "$x".to_string(), 10, 100, Some(50), 0);
+        // A partial payment is not retried, so the receiver is not paid twice
+        recurring.record_run(
+            260,
+            Ok(outcome(
+                4,
+                PaymentEndState::Partial("Timed out".to_string()),
+            )),
+        );
+        assert_eq!(recurring.status, ScheduleStatus::Scheduled);
+        assert_eq!(recurring.next_run, 300);
+        assert_eq!(recurring.last_run.as_ref().unwrap().end_state, "partial");
+    }
+
+    #[test]
+    fn retries_runs_that_sent_nothing_with_backoff() {
+        let mut payment =
+            ScheduledPayment::new("1".to_string(), "$x".to_string(), 10, 100, None, 0);
+        payment.record_run(100, Err("Unable to query receiver".to_string()));
+        assert_eq!(payment.next_run, 100 + RETRY_DELAY);
+        payment.record_run(200, Err("Unable to query receiver".to_string()));
+        assert_eq!(payment.next_run, 200 + 2 * RETRY_DELAY);
+        assert_eq!(payment.status, ScheduleStatus::Scheduled);
+        for _ in 2..MAX_SCHEDULED_PAYMENT_ATTEMPTS {
+            payment.record_run(1000, Err("Unable to query receiver".to_string()));
+        }
+        assert_eq!(payment.status, ScheduleStatus::Failed);
+        assert_eq!(payment.runs, 0);
+        assert_eq!(
+            payment.last_run.unwrap().error,
+            Some("Unable to query receiver".to_string())
+        );
+    }
+
+    #[test]
+    fn does_not_retry_interrupted_runs() {
+        let mut one_off =
+            ScheduledPayment::new("1".to_string(), "$x".to_string(), 10, 100, None, 0);
+        one_off.sending_since = Some(100);
+        one_off.record_interrupted_run(4000);
+        assert_eq!(one_off.status, ScheduleStatus::Failed);
+        assert_eq!(one_off.sending_since, None);
+        assert_eq!(one_off.last_run.unwrap().end_state, "interrupted");
+
+        let mut recurring =
+            ScheduledPayment::new("1".to_string(), "$x".to_string(), 10, 100, Some(50), 0);
+        recurring.sending_since = Some(100);
+        recurring.record_interrupted_run(120);
+        assert_eq!(recurring.status, ScheduleStatus::Scheduled);
+        assert_eq!(recurring.next_run, 150);
+    }
+
+    #[test]
+    fn ends_payments_without_a_representable_next_run() {
+        let mut zero_interval =
+            ScheduledPayment::new("1".to_string(), "$x".to_string(), 10, 100, Some(0), 0);
+        zero_interval.record_run(100, Ok(outcome(10, PaymentEndState::Completed)));
+        assert_eq!(zero_interval.status, ScheduleStatus::Completed);
+
+        let mut huge_interval = ScheduledPayment::new(
+            "1".to_string(),
+            "$x".to_string(),
+            10,
+            100,
+            Some(u64::max_value()),
+            0,
+        );
+        huge_interval.record_run(100, Ok(outcome(10, PaymentEndState::Completed)));
+        assert_eq!(huge_interval.status, ScheduleStatus::Completed);
+    }
+}
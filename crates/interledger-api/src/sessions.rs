@@ -0,0 +1,372 @@
+use super::runs::{load_account, now, update_latest, INTERRUPTED_AFTER};
+use super::NodeStore;
+use futures::{
+    future::{join_all, ok, Either},
+    Future,
+};
+use interledger_service::{Account as AccountTrait, AccountStore, IncomingService, StoreError};
+use interledger_spsp::pay_with_options;
+use interledger_stream::{PaymentEndState, PaymentOutcome, SendMoneyOptions};
+use serde::{Deserialize, Serialize};
+
+/// How many payments in a row can send nothing before a session is given up on
+pub const MAX_STREAMING_SESSION_FAILURES: u32 = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SessionStatus {
+    Active,
+    Stopped,
+    /// Stopped by the node because payments to the receiver kept failing
+    Failed,
+}
+
+/// A payment of a session that is being sent.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SessionPayment {
+    /// Seconds since the UNIX epoch
+    pub at: u64,
+    /// In the sending account's base units
+    pub amount: u64,
+}
+
+/// A payment that is streamed to a receiver at a fixed rate until it is stopped.
+///
+/// The amount owed builds up continuously and is sent every few seconds. The rate can be
+/// changed while the session is active, in which case only the time after the change is
+/// paid for at the new rate.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StreamingSession {
+    /// Assigned by the store
+    pub id: u64,
+    /// The account the payment is sent from
+    pub account_id: String,
+    /// The payment pointer or SPSP URL to pay
+    pub receiver: String,
+    /// In the sending account's base units
+    pub rate_per_minute: u64,
+    pub status: SessionStatus,
+    /// Seconds since the UNIX epoch
+    pub started_at: u64,
+    /// Seconds since the UNIX epoch
+    pub stopped_at: Option<u64>,
+    /// The amount owed for the time before `rate_since`
+    pub accrued_before: u64,
+    /// Seconds since the UNIX epoch the current rate has applied since
+    pub rate_since: u64,
+    /// In the sending account's base units
+    pub source_amount_sent: u64,
+    /// In the receiver's units
+    pub amount_delivered: u64,
+    /// The number of payments in a row that sent nothing
+    pub failed_attempts: u32,
+    pub last_error: Option<String>,
+    /// The payment that is being sent, if there is one
+    #[serde(default)]
+    pub sending: Option<SessionPayment>,
+    /// Incremented each time the session is saved, so that the store can refuse to save
+    /// changes made to an outdated copy
+    #[serde(default)]
+    pub version: u64,
+}
+
+impl StreamingSession {
+    pub fn new(account_id: String, receiver: String, rate_per_minute: u64, now: u64) -> Self {
+        StreamingSession {
+            id: 0,
+            account_id,
+            receiver,
+            rate_per_minute,
+            status: SessionStatus::Active,
+            started_at: now,
+            stopped_at: None,
+            accrued_before: 0,
+            rate_since: now,
+            source_amount_sent: 0,
+            amount_delivered: 0,
+            failed_attempts: 0,
+            last_error: None,
+            sending: None,
+            version: 0,
+        }
+    }
+
+    /// The total amount the session has owed the receiver up to `now`.
+    pub fn accrued(&self, now: u64) -> u64 {
+        if self.status != SessionStatus::Active {
+            return self.accrued_before;
+        }
+        let elapsed = u128::from(now.saturating_sub(self.rate_since));
+        let accrued = u128::from(self.rate_per_minute) * elapsed / 60;
+        self.accrued_before
+            .saturating_add(accrued.min(u128::from(u64::max_value())) as u64)
+    }
+
+    /// The amount accrued that has not been sent yet.
+    pub fn owed(&self, now: u64) -> u64 {
+        self.accrued(now).saturating_sub(self.source_amount_sent)
+    }
+
+    /// Pay at the new rate from `now` on.
+    pub fn set_rate(&mut self, rate_per_minute: u64, now: u64) {
+        self.accrued_before = self.accrued(now);
+        self.rate_since = now;
+        self.rate_per_minute = rate_per_minute;
+    }
+
+    /// Stop the session. Any amount owed that has not been sent yet is not sent.
+    pub fn stop(&mut self, status: SessionStatus, now: u64) {
+        self.accrued_before = self.accrued(now);
+        self.rate_since = now;
+        self.status = status;
+        self.stopped_at = Some(now);
+    }
+
+    /// Count the payment that was being sent when it was interrupted, for example because the
+    /// node stopped in the middle of it, as sent. It may have reached the receiver, and the
+    /// receiver should not be paid twice.
+    pub fn record_interrupted_payment(&mut self) {
+        if let Some(payment) = self.sending.take() {
+            self.source_amount_sent = self.source_amount_sent.saturating_add(payment.amount);
+            self.last_error = Some("The node stopped while a payment was being sent".to_string());
+        }
+    }
+
+    /// Add the amounts sent by the session's payment that was being sent, and stop the session
+    /// if too many payments in a row have sent nothing.
+    pub fn record_payment(&mut self, now: u64, outcome: Result<PaymentOutcome, String>) {
+        self.sending = None;
+        let (source_amount_sent, error) = match outcome {
+            Ok(outcome) => {
+                self.source_amount_sent += outcome.source_amount_sent;
+                self.amount_delivered += outcome.amount_delivered;
+                let error = match outcome.end_state {
+                    PaymentEndState::Completed => None,
+                    PaymentEndState::Partial(reason) => Some(reason),
+                    PaymentEndState::FailedWithCode(code) => Some(code.to_string()),
+                };
+                (outcome.source_amount_sent, error)
+            }
+            Err(error) => (0, Some(error)),
+        };
+        self.last_error = error;
+        if source_amount_sent > 0 {
+            self.failed_attempts = 0;
+        } else {
+            self.failed_attempts += 1;
+            if self.failed_attempts >= MAX_STREAMING_SESSION_FAILURES
+                && self.status == SessionStatus::Active
+            {
+                self.stop(SessionStatus::Failed, now);
+            }
+        }
+    }
+}
+
+/// Store that keeps the streaming payment sessions of each account.
+pub trait StreamingSessionStore: NodeStore {
+    /// Save a new session, returning it with the ID assigned to it.
+    fn add_streaming_session(
+        &self,
+        session: StreamingSession,
+    ) -> Box<Future<Item = StreamingSession, Error = StoreError> + Send>;
+
+    /// Save the changes to the session, unless it was saved by someone else since it was loaded
+    /// (its `version` is not the stored one), in which case this errors with `StoreError::Conflict`.
+    /// Returns the session with its new version.
+    fn update_streaming_session(
+        &self,
+        session: StreamingSession,
+    ) -> Box<Future<Item = StreamingSession, Error = StoreError> + Send>;
+
+    fn get_streaming_session(
+        &self,
+        id: u64,
+    ) -> Box<Future<Item = Option<StreamingSession>, Error = StoreError> + Send>;
+
+    /// Get the account's sessions, oldest first.
+    fn get_streaming_sessions(
+        &self,
+        account_id: <Self::Account as AccountTrait>::AccountId,
+    ) -> Box<Future<Item = Vec<StreamingSession>, Error = StoreError> + Send>;
+
+    fn get_active_streaming_sessions(
+        &self,
+    ) -> Box<Future<Item = Vec<StreamingSession>, Error = StoreError> + Send>;
+}
+
+/// Sends the amounts owed by the active streaming sessions.
+#[derive(Clone)]
+pub struct StreamingSessionRunner<T, S> {
+    store: T,
+    service: S,
+}
+
+impl<T, S, A> StreamingSessionRunner<T, S>
+where
+    T: StreamingSessionStore + NodeStore<Account = A> + AccountStore<Account = A>,
+    S: IncomingService<A> + Clone + Send + Sync + 'static,
+    A: AccountTrait + 'static,
+{
+    pub fn new(store: T, service: S) -> Self {
+        StreamingSessionRunner { store, service }
+    }
+
+    /// Send what each active session owes.
+    ///
+    /// Each payment is marked as being sent before it is sent, so it is only sent once even if
+    /// this is called again (or by another node sharing the store) before the returned future
+    /// resolves. Payments that are still marked long after they started are counted as sent.
+    pub fn send_owed_payments(&self) -> impl Future<Item = (), Error = ()> {
+        let runner = self.clone();
+        self.store
+            .get_active_streaming_sessions()
+            .map_err(|err| error!("Error loading streaming sessions: {:?}", err))
+            .and_then(move |sessions| {
+                // One failed payment should not stop the other sessions
+                join_all(
+                    sessions
+                        .into_iter()
+                        .map(move |session| runner.send_owed(session).then(|_| Ok(()))),
+                )
+            })
+            .map(|_| ())
+    }
+
+    fn send_owed(&self, session: StreamingSession) -> impl Future<Item = (), Error = ()> {
+        let now = now();
+        let id = session.id;
+        let mut session = session;
+        match session.sending {
+            Some(ref payment) if now.saturating_sub(payment.at) >= INTERRUPTED_AFTER => {
+                warn!("Payment for streaming session {} was interrupted", id);
+                session.record_interrupted_payment();
+                // The rest of what is owed is sent the next time
+                return Either::A(Either::B(
+                    self.store
+                        .update_streaming_session(session)
+                        .map(|_| ())
+                        .map_err(move |err| {
+                            error!("Error saving streaming session {}: {:?}", id, err)
+                        }),
+                ));
+            }
+            // Being sent by another call or node
+            Some(_) => return Either::A(Either::A(ok(()))),
+            None => {}
+        }
+        let owed = session.owed(now);
+        if owed == 0 {
+            return Either::A(Either::A(ok(())));
+        }
+        session.sending = Some(SessionPayment {
+            at: now,
+            amount: owed,
+        });
+        let runner = self.clone();
+        Either::B(
+            self.store
+                .update_streaming_session(session)
+                .map_err(move |err| {
+                    // Conflicts mean that someone else is sending or just changed the session
+                    debug!("Not streaming payment for session {}: {:?}", id, err)
+                })
+                .and_then(move |session| runner.pay(session, owed)),
+        )
+    }
+
+    fn pay(&self, session: StreamingSession, owed: u64) -> impl Future<Item = (), Error = ()> {
+        trace!(
+            "Streaming {} from account {} to {} for session {}",
+            owed,
+            session.account_id,
+            session.receiver,
+            session.id
+        );
+        let service = self.service.clone();
+        let runner = self.clone();
+        let id = session.id;
+        let receiver = session.receiver.clone();
+        load_account(self.store.clone(), &session.account_id)
+            .and_then(move |account| {
+                pay_with_options(service, account, &receiver, owed, SendMoneyOptions::new())
+                    .map_err(|err| format!("{:?}", err))
+            })
+            .then(move |outcome| {
+                if let Err(ref err) = outcome {
+                    warn!("Error streaming payment for session {}: {}", id, err);
+                }
+                runner.finish(id, outcome)
+            })
+    }
+
+    // The rate may have been changed or the session stopped while the payment was being sent
+    fn finish(
+        &self,
+        id: u64,
+        outcome: Result<PaymentOutcome, String>,
+    ) -> impl Future<Item = (), Error = ()> {
+        let store = self.store.clone();
+        let store_clone = self.store.clone();
+        update_latest(
+            move || store.get_streaming_session(id),
+            move |session| store_clone.update_streaming_session(session),
+            move |session| session.record_payment(now(), outcome.clone()),
+        )
+        .map(|_| ())
+        .map_err(move |err| error!("Error saving streaming session {}: {:?}", id, err))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accrues_at_the_current_rate_until_stopped() {
+        let mut session = StreamingSession::new("1".to_string(), "$x".to_string(), 120, 1000);
+        assert_eq!(session.owed(1030), 60);
+        session.set_rate(60, 1030);
+        assert_eq!(session.accrued(1090), 120);
+        session.source_amount_sent = 100;
+        assert_eq!(session.owed(1090), 20);
+        session.stop(SessionStatus::Stopped, 1090);
+        assert_eq!(session.accrued(5000), 120);
+        assert_eq!(session.stopped_at, Some(1090));
+    }
+
+    #[test]
+    fn fails_after_payments_keep_sending_nothing() {
+        let mut session = StreamingSession::new("1".to_string(), "$x".to_string(), 60, 0);
+        session.record_payment(
+            5,
+            Ok(PaymentOutcome {
+                amount_delivered: 8,
+                source_amount_sent: 5,
+                receiver_asset: None,
+                response: None,
+                end_state: PaymentEndState::Completed,
+                fulfilled_packets: 1,
+                rejected_packets: Default::default(),
+            }),
+        );
+        assert_eq!(session.amount_delivered, 8);
+        for attempt in 1..=MAX_STREAMING_SESSION_FAILURES {
+            assert_eq!(session.status, SessionStatus::Active);
+            session.record_payment(5 + u64::from(attempt), Err("No route".to_string()));
+        }
+        assert_eq!(session.status, SessionStatus::Failed);
+        assert_eq!(session.owed(1000), 5);
+        assert_eq!(session.last_error, Some("No route".to_string()));
+    }
+
+    #[test]
+    fn counts_interrupted_payments_as_sent() {
+        let mut session = StreamingSession::new("1".to_string(), "$x".to_string(), 60, 0);
+        session.sending = Some(SessionPayment { at: 10, amount: 10 });
+        session.record_interrupted_payment();
+        assert_eq!(session.sending, None);
+        assert_eq!(session.source_amount_sent, 10);
+        assert_eq!(session.owed(30), 20);
+    }
+}
@@ -0,0 +1,254 @@
+use super::AccountDetails;
+use bytes::Bytes;
+use interledger_packet::{Address, ParseError};
+use interledger_service_util::Priority;
+use serde::Serialize;
+use std::str::FromStr;
+use url::Url;
+
+// Scales larger than this cannot be converted between without overflowing a u64
+const MAX_ASSET_SCALE: u8 = 18;
+const MAX_ASSET_CODE_LENGTH: usize = 16;
+const MAX_OWNER_LENGTH: usize = 64;
+
+/// A problem with one of the fields in a request body.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FieldError {
+    pub field: &'static str,
+    pub message: String,
+}
+
+impl FieldError {
+    fn new(field: &'static str, message: &str) -> Self {
+        FieldError {
+            field,
+            message: message.to_string(),
+        }
+    }
+}
+
+/// Check that the given ILP address is well-formed (see RFC 15).
+pub fn validate_address(address: &[u8]) -> Result<(), String> {
+    match Address::try_from(Bytes::from(address)) {
+        Ok(_) => Ok(()),
+        Err(ParseError::InvalidAddress(message)) => Err(message),
+        Err(err) => Err(err.to_string()),
+    }
+}
+
+fn validate_url(
+    errors: &mut Vec<FieldError>,
+    field: &'static str,
+    url: &Option<String>,
+    schemes: &[&str],
+) {
+    if let Some(ref url) = url {
+        match Url::parse(url) {
+            Ok(url) => {
+                if !schemes.contains(&url.scheme()) {
+                    errors.push(FieldError {
+                        field,
+                        message: format!("URL scheme must be one of: {}", schemes.join(", ")),
+                    });
+                }
+            }
+            Err(err) => errors.push(FieldError {
+                field,
+                message: format!("Invalid URL: {}", err),
+            }),
+        }
+    }
+}
+
+impl AccountDetails {
+    /// Check the account details, returning every problem found rather than stopping at the first.
+    pub fn validate(&self) -> Result<(), Vec<FieldError>> {
+        let mut errors = Vec::new();
+
+        if let Err(message) = validate_address(&self.ilp_address[..]) {
+            errors.push(FieldError {
+                field: "ilp_address",
+                message,
+            });
+        }
+
+        if self.asset_code.is_empty()
+            || self.asset_code.len() > MAX_ASSET_CODE_LENGTH
+            || !self.asset_code.chars().all(|c| c.is_ascii_alphanumeric())
+        {
+            errors.push(FieldError {
+                field: "asset_code",
+                message: format!(
+                    "Asset code must be 1 to {} letters or digits",
+                    MAX_ASSET_CODE_LENGTH
+                ),
+            });
+        }
+
+        if self.asset_scale > MAX_ASSET_SCALE {
+            errors.push(FieldError {
+                field: "asset_scale",
+                message: format!("Asset scale must be at most {}", MAX_ASSET_SCALE),
+            });
+        }
+
+        if self.max_packet_amount == 0 {
+            errors.push(FieldError::new(
+                "max_packet_amount",
+                "Max packet amount must be greater than 0",
+            ));
+        }
+
+        validate_url(
+            &mut errors,
+            "http_endpoint",
+            &self.http_endpoint,
+            &["http", "https"],
+        );
+        validate_url(&mut errors, "btp_uri", &self.btp_uri, &["btp+ws", "btp+wss"]);
+
+        if let (Some(settle_threshold), Some(settle_to)) = (self.settle_threshold, self.settle_to)
+        {
+            if settle_to > settle_threshold {
+                errors.push(FieldError::new(
+                    "settle_to",
+                    "settle_to must not be greater than settle_threshold",
+                ));
+            }
+        }
+
+        if let Some(ref relation) = self.routing_relation {
+            match relation.to_lowercase().as_str() {
+                "parent" | "peer" | "child" => {}
+                _ => errors.push(FieldError::new(
+                    "routing_relation",
+                    "Routing relation must be one of: Parent, Peer, Child",
+                )),
+            }
+        }
+
+        if let Some(ref priority) = self.priority {
+            if Priority::from_str(priority).is_err() {
+                errors.push(FieldError::new(
+                    "priority",
+                    "Priority must be one of: high, normal, low",
+                ));
+            }
+        }
+
+        if self.max_value_per_second == Some(0) {
+            errors.push(FieldError::new(
+                "max_value_per_second",
+                "Max value per second must be greater than 0",
+            ));
+        }
+
+        if self.inactivity_timeout == Some(0) {
+            errors.push(FieldError::new(
+                "inactivity_timeout",
+                "Inactivity timeout must be greater than 0",
+            ));
+        }
+
+        if let Some(ref owner) = self.owner {
+            // Owners are used in the paths of the API's owner routes
+            let valid_chars = owner
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "-_.~".contains(c));
+            if owner.is_empty() || owner.len() > MAX_OWNER_LENGTH || !valid_chars {
+                errors.push(FieldError::new(
+                    "owner",
+                    "Owner must be 1 to 64 letters, digits, or any of: - _ . ~",
+                ));
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn details() -> AccountDetails {
+        AccountDetails {
+            ilp_address: b"example.alice".to_vec(),
+            asset_code: "XYZ".to_string(),
+            asset_scale: 9,
+            max_packet_amount: 1000,
+            max_packet_data: None,
+            priority: None,
+            max_value_per_second: None,
+            inactivity_timeout: None,
+            owner: None,
+            pending: false,
+            min_balance: 0,
+            http_endpoint: Some("https://example.com/ilp".to_string()),
+            http_incoming_authorization: None,
+            http_outgoing_authorization: None,
+            btp_uri: Some("btp+wss://example.com/btp".to_string()),
+            btp_incoming_authorization: None,
+            is_admin: false,
+            read_only: false,
+            xrp_address: None,
+            settle_threshold: Some(1000),
+            settle_to: Some(0),
+            send_routes: false,
+            receive_routes: false,
+            routing_relation: Some("Peer".to_string()),
+        }
+    }
+
+    #[test]
+    fn accepts_valid_details() {
+        assert!(details().validate().is_ok());
+    }
+
+    #[test]
+    fn rejects_invalid_addresses() {
+        assert!(validate_address(b"example").is_err());
+        assert!(validate_address(b"example.").is_err());
+        assert!(validate_address(b"unknown.alice").is_err());
+        assert!(validate_address(b"example.al ice").is_err());
+        assert!(validate_address(b"g.us-fed.ach.0.acmebank.~alice_1").is_ok());
+    }
+
+    #[test]
+    fn reports_every_invalid_field() {
+        let mut details = details();
+        details.asset_code = "US D".to_string();
+        details.asset_scale = 100;
+        details.btp_uri = Some("http://example.com".to_string());
+        details.http_endpoint = Some("not a url".to_string());
+        details.routing_relation = Some("Sibling".to_string());
+        details.priority = Some("urgent".to_string());
+        details.max_value_per_second = Some(0);
+        details.inactivity_timeout = Some(0);
+        details.owner = Some(String::new());
+        let fields: Vec<&str> = details
+            .validate()
+            .unwrap_err()
+            .iter()
+            .map(|err| err.field)
+            .collect();
+        assert_eq!(
+            fields,
+            vec![
+                "asset_code",
+                "asset_scale",
+                "http_endpoint",
+                "btp_uri",
+                "routing_relation",
+                "priority",
+                "max_value_per_second",
+                "inactivity_timeout",
+                "owner"
+            ]
+        );
+    }
+}
@@ -0,0 +1,157 @@
+use super::packet::{ContentType, ProtocolData};
+use std::{fmt::Write, str::FromStr};
+
+/// The name of the protocol data entry that peers advertise their capabilities in,
+/// alongside the auth data in the first message and in the response to it
+pub(crate) const CAPABILITIES_PROTOCOL: &str = "capabilities";
+
+/// The version of BTP spoken by this implementation. Peers that do not advertise
+/// their capabilities are treated as version 0
+pub const PROTOCOL_VERSION: u16 = 1;
+
+/// Seconds between the WebSocket pings sent to keep idle connections open
+pub const DEFAULT_KEEPALIVE_INTERVAL: u32 = 30;
+
+/// The protocol version and optional features of a BTP connection.
+///
+/// Each side advertises what it supports when the connection is opened, and only the
+/// features both sides support are used. The capabilities are sent as a list of
+/// space-separated tokens (such as `version=1 keepalive=30`), and tokens that are not
+/// understood are ignored, so that newer peers can add features without breaking older ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BtpCapabilities {
+    pub version: u16,
+    /// Seconds between WebSocket pings, if the peer wants the connection kept alive
+    pub keepalive_interval: Option<u32>,
+}
+
+impl Default for BtpCapabilities {
+    /// What this implementation supports.
+    fn default() -> Self {
+        BtpCapabilities {
+            version: PROTOCOL_VERSION,
+            keepalive_interval: Some(DEFAULT_KEEPALIVE_INTERVAL),
+        }
+    }
+}
+
+impl BtpCapabilities {
+    /// The capabilities of peers that do not take part in the negotiation.
+    pub fn legacy() -> Self {
+        BtpCapabilities {
+            version: 0,
+            keepalive_interval: None,
+        }
+    }
+
+    /// The capabilities that both this side and the peer support.
+    /// If both sides want keepalives, the longer of their intervals is used.
+    pub fn negotiate(&self, peer: &BtpCapabilities) -> BtpCapabilities {
+        BtpCapabilities {
+            version: self.version.min(peer.version),
+            keepalive_interval: match (self.keepalive_interval, peer.keepalive_interval) {
+                (Some(ours), Some(theirs)) => Some(ours.max(theirs)),
+                _ => None,
+            },
+        }
+    }
+
+    /// The names of the optional features, for display.
+    pub fn features(&self) -> Vec<&'static str> {
+        let mut features = Vec::new();
+        if self.keepalive_interval.is_some() {
+            features.push("keepalive");
+        }
+        features
+    }
+
+    pub(crate) fn to_protocol_data(&self) -> ProtocolData {
+        let mut data = format!("version={}", self.version);
+        if let Some(interval) = self.keepalive_interval {
+            write!(data, " keepalive={}", interval).unwrap();
+        }
+        ProtocolData {
+            protocol_name: CAPABILITIES_PROTOCOL.to_string(),
+            content_type: ContentType::TextPlainUtf8,
+            data: data.into_bytes(),
+        }
+    }
+
+    /// Find the capabilities the peer advertised among the protocol data of a BTP packet.
+    pub(crate) fn from_protocol_data(protocol_data: &[ProtocolData]) -> BtpCapabilities {
+        let data = protocol_data
+            .iter()
+            .find(|data| data.protocol_name == CAPABILITIES_PROTOCOL)
+            .and_then(|data| std::str::from_utf8(&data.data).ok());
+        let data = if let Some(data) = data {
+            data
+        } else {
+            return BtpCapabilities::legacy();
+        };
+
+        let mut capabilities = BtpCapabilities::legacy();
+        for token in data.split_whitespace() {
+            let mut parts = token.splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some("version"), Some(version)) => {
+                    capabilities.version = u16::from_str(version).unwrap_or(0)
+                }
+                (Some("keepalive"), Some(interval)) => {
+                    // An interval of 0 would mean pinging constantly
+                    capabilities.keepalive_interval = u32::from_str(interval)
+                        .ok()
+                        .filter(|interval| *interval > 0)
+                }
+                _ => trace!("Ignoring unknown BTP capability: {}", token),
+            }
+        }
+        capabilities
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_through_protocol_data() {
+        let capabilities = BtpCapabilities {
+            version: 3,
+            keepalive_interval: Some(15),
+        };
+        assert_eq!(
+            BtpCapabilities::from_protocol_data(&[capabilities.to_protocol_data()]),
+            capabilities
+        );
+    }
+
+    #[test]
+    fn treats_peers_without_capabilities_as_legacy() {
+        assert_eq!(
+            BtpCapabilities::from_protocol_data(&[]),
+            BtpCapabilities::legacy()
+        );
+        assert_eq!(
+            BtpCapabilities::default().negotiate(&BtpCapabilities::legacy()),
+            BtpCapabilities::legacy()
+        );
+    }
+
+    #[test]
+    fn negotiates_common_features_and_ignores_unknown_ones() {
+        let peer = BtpCapabilities::from_protocol_data(&[ProtocolData {
+            protocol_name: CAPABILITIES_PROTOCOL.to_string(),
+            content_type: ContentType::TextPlainUtf8,
+            data: b"version=2 batching keepalive=60 teleportation".to_vec(),
+        }]);
+        let ours = BtpCapabilities::default();
+        assert_eq!(
+            ours.negotiate(&peer),
+            BtpCapabilities {
+                version: 1,
+                keepalive_interval: Some(60),
+            }
+        );
+        assert_eq!(ours.negotiate(&peer).features(), vec!["keepalive"]);
+    }
+}
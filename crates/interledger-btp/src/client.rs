@@ -1,14 +1,43 @@
 use super::packet::*;
-use super::service::BtpOutgoingService;
-use super::BtpAccount;
-use futures::{future::join_all, Future, Sink};
+use super::service::{BtpOutgoingService, WsStream};
+use super::{BtpAccount, BtpCapabilities};
+use futures::{
+    future::{err, join_all, loop_fn, ok, Either, Loop},
+    Future, Sink, Stream,
+};
+use interledger_packet::redact::developer_mode;
 use interledger_service::*;
 use rand::random;
-use std::iter::IntoIterator;
+use std::{
+    cmp::min,
+    iter::IntoIterator,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio_executor::spawn;
+use tokio_timer::{Delay, Timeout};
 use tokio_tungstenite::connect_async;
 use tungstenite::Message;
 use url::{ParseError, Url};
 
+/// How long to wait for a peer's BTP server to accept the connection and our auth packet
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long to wait for the server to respond to our auth packet once it has been sent
+const AUTH_RESPONSE_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long to wait before reconnecting to a peer, doubled after each failed attempt
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
+
+/// Checks the URL of a BTP server each time before connecting to it, for example that the node
+/// may connect to the addresses its host currently resolves to. Connections are not made when
+/// the check fails.
+pub type ConnectCheck =
+    Arc<Fn(&Url) -> Box<Future<Item = (), Error = String> + Send> + Send + Sync>;
+
+fn no_check() -> ConnectCheck {
+    Arc::new(|_: &Url| Box::new(ok(())) as Box<Future<Item = (), Error = String> + Send>)
+}
+
 pub fn parse_btp_url(uri: &str) -> Result<Url, ParseError> {
     let uri = if uri.starts_with("btp+") {
         uri.split_at(4).1
@@ -18,6 +47,15 @@ pub fn parse_btp_url(uri: &str) -> Result<Url, ParseError> {
     Url::parse(uri)
 }
 
+/// The URL with the auth token hidden, for logging
+fn without_password(url: &Url) -> Url {
+    let mut url = url.clone();
+    if !developer_mode() && url.password().is_some() {
+        url.set_password(Some("REDACTED")).ok();
+    }
+    url
+}
+
 /// Create a BtpOutgoingService wrapping BTP connections to the accounts specified.
 /// Calling `handle_incoming` with an `IncomingService` will turn the returned
 /// BtpOutgoingService into a bidirectional handler.
@@ -29,55 +67,265 @@ where
     S: OutgoingService<A> + Clone + 'static,
     A: BtpAccount + 'static,
 {
-    join_all(accounts.into_iter().map(move |account| {
-        let mut url = account
-            .get_btp_uri()
-            .expect("Accounts must have BTP URLs")
-            .clone();
-        if url.scheme().starts_with("btp+") {
-            url.set_scheme(&url.scheme().replace("btp+", "")).unwrap();
-        }
-        debug!("Connecting to {}", url);
-        connect_async(url.clone())
-            .map_err(|err| error!("Error connecting to WebSocket server: {:?}", err))
-            .and_then(move |(connection, _)| {
-                debug!("Connected to {}, sending auth packet", url);
-                // Send BTP authentication
-                let auth_packet = Message::Binary(
-                    BtpPacket::Message(BtpMessage {
-                        request_id: random(),
-                        protocol_data: vec![
-                            ProtocolData {
-                                protocol_name: String::from("auth"),
-                                content_type: ContentType::ApplicationOctetStream,
-                                data: vec![],
-                            },
-                            ProtocolData {
-                                protocol_name: String::from("auth_username"),
-                                content_type: ContentType::TextPlainUtf8,
-                                data: String::from(url.username()).into_bytes(),
-                            },
-                            ProtocolData {
-                                protocol_name: String::from("auth_token"),
-                                content_type: ContentType::TextPlainUtf8,
-                                data: String::from(url.password().unwrap()).into_bytes(),
-                            },
-                        ],
-                    })
-                    .to_bytes(),
-                );
-
-                connection
-                    .send(auth_packet)
-                    .map_err(move |_| error!("Error sending auth packet on connection: {}", url))
-            })
-            .and_then(move |connection| Ok((account, connection)))
-    }))
+    let check = no_check();
+    join_all(
+        accounts
+            .into_iter()
+            .map(move |account| connect_account(account, &check)),
+    )
     .and_then(|connections| {
         let service = BtpOutgoingService::new(next_outgoing);
+        for (account, connection, capabilities) in connections.into_iter() {
+            let _ = service.add_connection(account, connection, None, capabilities);
+        }
+        Ok(service)
+    })
+}
+
+/// Connect to the BTP servers of the accounts specified and add the connections to an
+/// existing BtpOutgoingService, such as the one returned by `create_server`, so that the
+/// same service can accept connections from some accounts and connect to others.
+///
+/// Accounts that cannot be connected to are logged and skipped. Until the service is closed,
+/// the connections are reopened when they drop, and the skipped accounts are retried, with
+/// an increasing delay between attempts. The `check` is run before every connection attempt.
+pub fn connect_to_service_accounts<A, S>(
+    service: BtpOutgoingService<S, A>,
+    accounts: Vec<A>,
+    check: ConnectCheck,
+) -> impl Future<Item = BtpOutgoingService<S, A>, Error = ()>
+where
+    S: OutgoingService<A> + Clone + Send + 'static,
+    A: BtpAccount + 'static,
+{
+    let connects: Vec<_> = accounts
+        .into_iter()
+        .map(|account| {
+            connect_account(account.clone(), &check).then(move |result| Ok((account, result.ok())))
+        })
+        .collect();
+    join_all(connects).and_then(move |connections| {
         for (account, connection) in connections.into_iter() {
-            service.add_connection(account, connection);
+            match connection {
+                Some((account, connection, capabilities)) => {
+                    keep_connected(&service, account, connection, capabilities, check.clone())
+                }
+                None => reconnect(service.clone(), account, check.clone()),
+            }
         }
         Ok(service)
     })
 }
+
+/// Add the connection to the service and reconnect if it drops.
+fn keep_connected<A, S>(
+    service: &BtpOutgoingService<S, A>,
+    account: A,
+    connection: WsStream,
+    capabilities: BtpCapabilities,
+    check: ConnectCheck,
+) where
+    S: OutgoingService<A> + Clone + Send + 'static,
+    A: BtpAccount + 'static,
+{
+    let closed = service.add_connection(account.clone(), connection, None, capabilities);
+    let service = service.clone();
+    spawn(closed.then(move |result| {
+        if result.is_ok() {
+            warn!(
+                "BTP connection to account {} closed, reconnecting",
+                account.id()
+            );
+            reconnect(service, account, check);
+        }
+        Ok(())
+    }));
+}
+
+fn reconnect<A, S>(service: BtpOutgoingService<S, A>, account: A, check: ConnectCheck)
+where
+    S: OutgoingService<A> + Clone + Send + 'static,
+    A: BtpAccount + 'static,
+{
+    spawn(loop_fn(RECONNECT_DELAY, move |delay| {
+        let service = service.clone();
+        let account = account.clone();
+        let check = check.clone();
+        Delay::new(Instant::now() + delay)
+            .map_err(|err| error!("Timer error before reconnecting: {:?}", err))
+            .and_then(move |_| {
+                if service.is_closed() || service.is_connected(account.id()) {
+                    return Either::A(ok(Loop::Break(())));
+                }
+                let account_id = account.id();
+                Either::B(
+                    connect_account(account, &check).then(move |result| match result {
+                        Ok((account, connection, capabilities)) => {
+                            debug!("Reconnected to account {}", account_id);
+                            keep_connected(&service, account, connection, capabilities, check);
+                            Ok(Loop::Break(()))
+                        }
+                        Err(()) => {
+                            let delay = min(delay * 2, MAX_RECONNECT_DELAY);
+                            debug!(
+                                "Unable to reconnect to account {}, trying again in {:?}",
+                                account_id, delay
+                            );
+                            Ok(Loop::Continue(delay))
+                        }
+                    }),
+                )
+            })
+    }));
+}
+
+/// The URL to connect to for the account and the auth token to send.
+fn btp_url_and_token<A: BtpAccount>(account: &A) -> Result<(Url, String), ()> {
+    let mut url = account
+        .get_btp_uri()
+        .ok_or_else(|| error!("Account {} does not have a BTP URL", account.id()))?
+        .clone();
+    if url.scheme().starts_with("btp+") {
+        let scheme = url.scheme().replace("btp+", "");
+        url.set_scheme(&scheme).map_err(|_| {
+            error!(
+                "BTP URL of account {} has an invalid scheme: {}",
+                account.id(),
+                scheme
+            )
+        })?;
+    }
+    let token = url
+        .password()
+        .ok_or_else(|| error!("BTP URL of account {} has no auth token", account.id()))?
+        .to_string();
+    Ok((url, token))
+}
+
+fn connect_account<A: BtpAccount>(
+    account: A,
+    check: &ConnectCheck,
+) -> impl Future<Item = (A, WsStream, BtpCapabilities), Error = ()> {
+    let (url, token) = match btp_url_and_token(&account) {
+        Ok(url_and_token) => url_and_token,
+        Err(()) => return Either::A(err(())),
+    };
+    debug!("Connecting to {}", without_password(&url));
+    let timeout_url = url.clone();
+    let checked_url = url.clone();
+    let connect_url = url.clone();
+    let connect = check(&url)
+        .map_err(move |message| {
+            error!(
+                "Not connecting to {}: {}",
+                without_password(&checked_url),
+                message
+            )
+        })
+        .and_then(move |_| {
+            connect_async(connect_url)
+                .map_err(|err| error!("Error connecting to WebSocket server: {:?}", err))
+        })
+        .and_then(move |(connection, _)| {
+            debug!(
+                "Connected to {}, sending auth packet",
+                without_password(&url)
+            );
+            // Send BTP authentication, along with the capabilities we support
+            let request_id = random();
+            let auth_packet = Message::Binary(
+                BtpPacket::Message(BtpMessage {
+                    request_id,
+                    protocol_data: vec![
+                        ProtocolData {
+                            protocol_name: String::from("auth"),
+                            content_type: ContentType::ApplicationOctetStream,
+                            data: vec![],
+                        },
+                        ProtocolData {
+                            protocol_name: String::from("auth_username"),
+                            content_type: ContentType::TextPlainUtf8,
+                            data: String::from(url.username()).into_bytes(),
+                        },
+                        ProtocolData {
+                            protocol_name: String::from("auth_token"),
+                            content_type: ContentType::TextPlainUtf8,
+                            data: token.into_bytes(),
+                        },
+                        BtpCapabilities::default().to_protocol_data(),
+                    ],
+                })
+                .to_bytes(),
+            );
+
+            let url_clone = url.clone();
+            connection
+                .send(auth_packet)
+                .map_err(move |_| {
+                    error!(
+                        "Error sending auth packet on connection: {}",
+                        without_password(&url)
+                    )
+                })
+                .and_then(move |connection| {
+                    read_auth_response(connection, request_id).map_err(move |_| {
+                        error!(
+                            "Server did not accept auth packet on connection: {}",
+                            without_password(&url_clone)
+                        )
+                    })
+                })
+        })
+        .and_then(move |(connection, capabilities)| {
+            debug!(
+                "Negotiated BTP version {} with features {:?} for account {}",
+                capabilities.version,
+                capabilities.features(),
+                account.id()
+            );
+            Ok((account, connection, capabilities))
+        });
+    Either::B(Timeout::new(connect, CONNECT_TIMEOUT).map_err(move |err| {
+        if err.is_elapsed() {
+            error!("Timed out connecting to {}", without_password(&timeout_url))
+        }
+    }))
+}
+
+/// Wait for the response to the auth packet and negotiate the capabilities the server advertised in it.
+/// Servers that do not advertise any are treated as legacy peers.
+fn read_auth_response(
+    connection: WsStream,
+    request_id: u32,
+) -> impl Future<Item = (WsStream, BtpCapabilities), Error = ()> {
+    let response = Timeout::new(connection.into_future(), AUTH_RESPONSE_TIMEOUT).map_err(|err| {
+        if err.is_elapsed() {
+            error!("Timed out waiting for the auth response");
+        } else if let Some((err, _connection)) = err.into_inner() {
+            error!("Error reading auth response: {:?}", err);
+        } else {
+            error!("Timer error while waiting for the auth response");
+        }
+    });
+    response.and_then(move |(message, connection)| {
+        let response = if let Some(Message::Binary(data)) = message {
+            BtpPacket::from_bytes(&data).ok()
+        } else {
+            None
+        };
+        match response {
+            Some(BtpPacket::Response(ref response)) if response.request_id == request_id => {
+                let peer = BtpCapabilities::from_protocol_data(&response.protocol_data);
+                Ok((connection, BtpCapabilities::default().negotiate(&peer)))
+            }
+            Some(BtpPacket::Error(error)) => {
+                error!("Got BTP error in response to auth packet: {:?}", error);
+                Err(())
+            }
+            _ => {
+                error!("Expected a response to the auth packet");
+                Err(())
+            }
+        }
+    })
+}
@@ -18,6 +18,7 @@ use futures::Future;
 use interledger_service::Account;
 use url::Url;
 
+mod capabilities;
 mod client;
 mod errors;
 mod oer;
@@ -25,9 +26,10 @@ mod packet;
 mod server;
 mod service;
 
-pub use self::client::{connect_client, parse_btp_url};
+pub use self::capabilities::{BtpCapabilities, DEFAULT_KEEPALIVE_INTERVAL, PROTOCOL_VERSION};
+pub use self::client::{connect_client, connect_to_service_accounts, parse_btp_url, ConnectCheck};
 pub use self::server::{create_open_signup_server, create_server};
-pub use self::service::{BtpOutgoingService, BtpService};
+pub use self::service::{BtpConnectionInfo, BtpOutgoingService, BtpService};
 
 pub trait BtpAccount: Account {
     fn get_btp_uri(&self) -> Option<&Url>;
@@ -75,9 +77,9 @@ mod client_server {
     use interledger_service::*;
     use std::{
         sync::Arc,
-        time::{Duration, SystemTime},
+        time::{Duration, Instant, SystemTime},
     };
-    use tokio::runtime::Runtime;
+    use tokio::{runtime::Runtime, timer::Delay};
 
     #[derive(Clone, Debug)]
     pub struct TestAccount {
@@ -111,23 +113,16 @@ mod client_server {
         fn get_accounts(
             &self,
             account_ids: Vec<<<Self as AccountStore>::Account as Account>::AccountId>,
-        ) -> Box<Future<Item = Vec<Self::Account>, Error = ()> + Send> {
-            let accounts: Vec<TestAccount> = self
-                .accounts
+        ) -> Box<Future<Item = Vec<Option<Self::Account>>, Error = ()> + Send> {
+            Box::new(ok(account_ids
                 .iter()
-                .filter_map(|account| {
-                    if account_ids.contains(&account.id) {
-                        Some(account.clone())
-                    } else {
-                        None
-                    }
+                .map(|id| {
+                    self.accounts
+                        .iter()
+                        .find(|account| account.id == *id)
+                        .cloned()
                 })
-                .collect();
-            if accounts.len() == account_ids.len() {
-                Box::new(ok(accounts))
-            } else {
-                Box::new(err(()))
-            }
+                .collect()))
         }
     }
 
@@ -209,6 +204,11 @@ mod client_server {
             }),
         )
         .and_then(move |btp_service| {
+            // Both sides support the same features, so they are all enabled
+            assert_eq!(
+                btp_service.connections()[0].capabilities,
+                BtpCapabilities::default()
+            );
             let mut btp_service = btp_service.handle_incoming(incoming_service_fn(|_| {
                 Err(RejectBuilder {
                     code: ErrorCode::F02_UNREACHABLE,
@@ -240,4 +240,130 @@ mod client_server {
         });
         runtime.block_on(client).unwrap();
     }
+
+    fn reject_all<A: Account>() -> impl OutgoingService<A> + Clone {
+        outgoing_service_fn(|_| {
+            Err(RejectBuilder {
+                code: ErrorCode::F02_UNREACHABLE,
+                message: &[],
+                data: &[],
+                triggered_by: &[],
+            }
+            .build())
+        })
+    }
+
+    fn check_hosts(allowed: bool) -> ConnectCheck {
+        Arc::new(move |_: &Url| {
+            if allowed {
+                Box::new(ok(())) as Box<Future<Item = (), Error = String> + Send>
+            } else {
+                Box::new(err("Host is not allowed".to_string()))
+            }
+        })
+    }
+
+    #[test]
+    fn skips_accounts_that_cannot_be_connected_to() {
+        let mut runtime = Runtime::new().unwrap();
+        let accounts = vec![
+            TestAccount {
+                id: 0,
+                btp_uri: None,
+                btp_incoming_token: None,
+            },
+            TestAccount {
+                id: 1,
+                btp_uri: Some(Url::parse("btp+ws://127.0.0.1:12346").unwrap()),
+                btp_incoming_token: None,
+            },
+        ];
+        let service = runtime
+            .block_on(connect_to_service_accounts(
+                BtpOutgoingService::new(reject_all()),
+                accounts,
+                check_hosts(true),
+            ))
+            .unwrap();
+        assert!(service.connections().is_empty());
+        service.close();
+    }
+
+    #[test]
+    fn reconnects_when_the_connection_drops() {
+        let mut runtime = Runtime::new().unwrap();
+        let server_store = TestStore {
+            accounts: Arc::new(vec![TestAccount {
+                id: 0,
+                btp_incoming_token: Some("test_auth_token".to_string()),
+                btp_uri: None,
+            }]),
+        };
+        let server = runtime
+            .block_on(create_server(
+                "127.0.0.1:12347".parse().unwrap(),
+                server_store,
+                reject_all(),
+            ))
+            .unwrap();
+
+        let account = TestAccount {
+            id: 0,
+            btp_uri: Some(Url::parse("btp+ws://:test_auth_token@127.0.0.1:12347").unwrap()),
+            btp_incoming_token: None,
+        };
+        let client = runtime
+            .block_on(connect_to_service_accounts(
+                BtpOutgoingService::new(reject_all()),
+                vec![account],
+                check_hosts(true),
+            ))
+            .unwrap();
+        assert_eq!(client.connections().len(), 1);
+
+        assert!(server.disconnect(0));
+        runtime
+            .block_on(Delay::new(Instant::now() + Duration::from_secs(3)))
+            .unwrap();
+        assert_eq!(client.connections().len(), 1);
+        assert_eq!(server.connections().len(), 1);
+        client.close();
+        server.close();
+    }
+
+    #[test]
+    fn does_not_connect_to_hosts_that_fail_the_check() {
+        let mut runtime = Runtime::new().unwrap();
+        let server_store = TestStore {
+            accounts: Arc::new(vec![TestAccount {
+                id: 0,
+                btp_incoming_token: Some("test_auth_token".to_string()),
+                btp_uri: None,
+            }]),
+        };
+        let server = runtime
+            .block_on(create_server(
+                "127.0.0.1:12348".parse().unwrap(),
+                server_store,
+                reject_all(),
+            ))
+            .unwrap();
+
+        let account = TestAccount {
+            id: 0,
+            btp_uri: Some(Url::parse("btp+ws://:test_auth_token@127.0.0.1:12348").unwrap()),
+            btp_incoming_token: None,
+        };
+        let client = runtime
+            .block_on(connect_to_service_accounts(
+                BtpOutgoingService::new(reject_all()),
+                vec![account],
+                check_hosts(false),
+            ))
+            .unwrap();
+        assert!(client.connections().is_empty());
+        assert!(server.connections().is_empty());
+        client.close();
+        server.close();
+    }
 }
@@ -1,30 +1,62 @@
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 use bytes::{Buf, BufMut, Bytes, IntoBuf};
-use num_bigint::BigUint;
 use std::fmt::Debug;
-use std::io::{self, Read, Result, Write};
+use std::io::{self, Error, ErrorKind, Read, Result, Write};
 
 const HIGH_BIT: u8 = 0x80;
 const LOWER_SEVEN_BITS: u8 = 0x7f;
+const MAX_VAR_UINT_LEN: u64 = 8;
+
+/// Returns the number of bytes needed to encode the value as a VarUInt, which is at least 1
+#[inline]
+fn var_uint_size(value: u64) -> usize {
+    (8 - value.leading_zeros() as usize / 8).max(1)
+}
+
+/// Returns the size (in bytes) of the buffer that encodes a VarOctetString of
+/// `length` bytes.
+#[inline]
+pub fn predict_var_octet_string(length: usize) -> usize {
+    if length < 127 {
+        1 + length
+    } else {
+        1 + var_uint_size(length as u64) + length
+    }
+}
+
+/// Returns the size (in bytes) of the buffer that encodes the value as a VarUInt.
+#[inline]
+pub fn predict_var_uint(value: u64) -> usize {
+    1 + var_uint_size(value)
+}
 
 // TODO test traits
 pub trait ReadOerExt: Read + ReadBytesExt + Debug {
     #[inline]
-    fn read_var_octet_string(&mut self) -> Result<Vec<u8>> {
+    fn read_var_octet_string_length(&mut self) -> Result<u64> {
         let length: u8 = self.read_u8()?;
+        if length & HIGH_BIT != 0 {
+            let length_prefix_length = u64::from(length & LOWER_SEVEN_BITS);
+            if length_prefix_length == 0 || length_prefix_length > MAX_VAR_UINT_LEN {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "invalid length prefix length",
+                ));
+            }
+            // TODO check for canonical length
+            self.read_uint::<BigEndian>(length_prefix_length as usize)
+        } else {
+            Ok(u64::from(length))
+        }
+    }
 
-        if length == 0 {
+    #[inline]
+    fn read_var_octet_string(&mut self) -> Result<Vec<u8>> {
+        let actual_length = self.read_var_octet_string_length()?;
+        if actual_length == 0 {
             return Ok(vec![]);
         }
 
-        let actual_length: u64 = if length & HIGH_BIT != 0 {
-            let length_prefix_length = length & LOWER_SEVEN_BITS;
-            // TODO check for canonical length
-            self.read_uint::<BigEndian>(length_prefix_length as usize)? as u64
-        } else {
-            u64::from(length)
-        };
-
         // TODO handle if the length is too long
         let mut buf = Vec::with_capacity(actual_length as usize);
         self.take(actual_length).read_to_end(&mut buf)?;
@@ -32,9 +64,15 @@ pub trait ReadOerExt: Read + ReadBytesExt + Debug {
     }
 
     #[inline]
-    fn read_var_uint(&mut self) -> Result<BigUint> {
-        let contents = self.read_var_octet_string()?;
-        Ok(BigUint::from_bytes_be(&contents))
+    fn read_var_uint(&mut self) -> Result<u64> {
+        let size = self.read_var_octet_string_length()?;
+        if size == 0 {
+            Ok(0)
+        } else if size > MAX_VAR_UINT_LEN {
+            Err(Error::new(ErrorKind::InvalidData, "VarUInt is too large"))
+        } else {
+            self.read_uint::<BigEndian>(size as usize)
+        }
     }
 }
 
@@ -49,10 +87,9 @@ pub trait WriteOerExt: Write + WriteBytesExt + Debug {
         if length < 127 {
             self.write_u8(length as u8)?;
         } else {
-            let bit_length_of_length = format!("{:b}", length).chars().count();
-            let length_of_length = { bit_length_of_length as f32 / 8.0 }.ceil() as u8;
-            self.write_u8(HIGH_BIT | length_of_length)?;
-            self.write_uint::<BigEndian>(length as u64, length_of_length as usize)?;
+            let length_of_length = var_uint_size(length as u64);
+            self.write_u8(HIGH_BIT | length_of_length as u8)?;
+            self.write_uint::<BigEndian>(length as u64, length_of_length)?;
         }
         self.write_all(string)?;
         Ok(())
@@ -60,8 +97,10 @@ pub trait WriteOerExt: Write + WriteBytesExt + Debug {
 
     #[inline]
     // Write a u64 as an OER VarUInt
-    fn write_var_uint(&mut self, uint: &BigUint) -> Result<()> {
-        self.write_var_octet_string(&uint.to_bytes_be())?;
+    fn write_var_uint(&mut self, uint: u64) -> Result<()> {
+        let size = var_uint_size(uint);
+        self.write_u8(size as u8)?;
+        self.write_uint::<BigEndian>(uint, size)?;
         Ok(())
     }
 }
@@ -94,9 +133,14 @@ pub trait BufOerExt: Buf + Sized {
     }
 
     #[inline]
-    fn get_var_uint(&mut self) -> BigUint {
-        let contents = self.get_var_octet_string();
-        BigUint::from_bytes_be(&contents[..])
+    fn get_var_uint(&mut self) -> u64 {
+        let size = self.get_u8();
+        assert!(u64::from(size) <= MAX_VAR_UINT_LEN, "VarUInt is too large");
+        if size == 0 {
+            0
+        } else {
+            self.get_uint_be(size as usize)
+        }
     }
 }
 
@@ -109,23 +153,29 @@ pub trait MutBufOerExt: BufMut + Sized {
         B: IntoBuf,
     {
         let buf = buf.into_buf();
-        let length = buf.remaining();
+        self.put_var_octet_string_length(buf.remaining());
+        self.put(buf);
+    }
 
+    /// Puts the length prefix of a VarOctetString, so the contents can be written
+    /// straight into the buffer after it
+    #[inline]
+    fn put_var_octet_string_length(&mut self, length: usize) {
         if length < 127 {
             self.put_u8(length as u8);
         } else {
-            let bit_length_of_length = format!("{:b}", length).chars().count();
-            let length_of_length = { bit_length_of_length as f32 / 8.0 }.ceil() as u8;
-            self.put_u8(HIGH_BIT | length_of_length);
-            self.put_uint_be(length as u64, length_of_length as usize);
+            let length_of_length = var_uint_size(length as u64);
+            self.put_u8(HIGH_BIT | length_of_length as u8);
+            self.put_uint_be(length as u64, length_of_length);
         }
-        self.put(buf);
     }
 
     #[inline]
     // Write a u64 as an OER VarUInt
-    fn put_var_uint(&mut self, uint: &BigUint) {
-        self.put_var_octet_string(uint.to_bytes_be());
+    fn put_var_uint(&mut self, uint: u64) {
+        let size = var_uint_size(uint);
+        self.put_u8(size as u8);
+        self.put_uint_be(uint, size);
     }
 }
 
@@ -158,6 +208,50 @@ mod writer_ext {
     }
 }
 
+#[cfg(test)]
+mod var_uint {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn it_writes_and_reads_var_uints() {
+        let values: &[(u64, &[u8])] = &[
+            (0, &[0x01, 0x00]),
+            (255, &[0x01, 0xff]),
+            (256, &[0x02, 0x01, 0x00]),
+            (
+                u64::max_value(),
+                &[0x08, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff],
+            ),
+        ];
+        for (value, encoded) in values {
+            let mut written = vec![];
+            written.write_var_uint(*value).unwrap();
+            assert_eq!(&written[..], *encoded);
+            let mut put = vec![];
+            put.put_var_uint(*value);
+            assert_eq!(&put[..], *encoded);
+            assert_eq!(predict_var_uint(*value), encoded.len());
+            assert_eq!(Cursor::new(*encoded).read_var_uint().unwrap(), *value);
+        }
+    }
+
+    #[test]
+    fn it_rejects_var_uints_longer_than_8_bytes() {
+        let encoded = [0x09, 0x01, 0, 0, 0, 0, 0, 0, 0, 0];
+        assert!(Cursor::new(&encoded[..]).read_var_uint().is_err());
+    }
+
+    #[test]
+    fn it_predicts_var_octet_string_lengths() {
+        for length in &[0, 1, 126, 127, 128, 255, 256, 70_000] {
+            let mut buf = vec![];
+            buf.put_var_octet_string(vec![0; *length]);
+            assert_eq!(predict_var_octet_string(*length), buf.len());
+        }
+    }
+}
+
 #[cfg(test)]
 mod reader_ext {
     use super::*;
@@ -1,15 +1,18 @@
 use super::errors::ParseError;
-use super::oer::{MutBufOerExt, ReadOerExt};
+use super::oer::{predict_var_octet_string, predict_var_uint, MutBufOerExt, ReadOerExt};
 use byteorder::{BigEndian, ReadBytesExt};
 use bytes::BufMut;
 use chrono::{DateTime, TimeZone, Utc};
-use num_bigint::BigUint;
 use std::io::prelude::*;
 use std::io::Cursor;
-use std::ops::Add;
 use std::str;
 
 static GENERALIZED_TIME_FORMAT: &'static str = "%Y%m%d%H%M%S%.3fZ";
+/// Most packets carry one or two protocol data entries, so the entries are only preallocated
+/// up to this many in case a packet claims to have more than it does
+const MAX_PREALLOCATED_ENTRIES: u64 = 8;
+/// The packet type and request ID
+const HEADER_LEN: usize = 5;
 
 pub trait Serializable<T> {
     fn from_bytes(bytes: &[u8]) -> Result<T, ParseError>;
@@ -91,12 +94,9 @@ fn read_protocol_data<T>(reader: &mut T) -> Result<Vec<ProtocolData>, ParseError
 where
     T: ReadOerExt,
 {
-    let mut protocol_data = Vec::new();
-
     let num_entries = reader.read_var_uint()?;
-    let mut i = BigUint::from(0 as u32);
-    while i < num_entries {
-        i = i.add(BigUint::from(1 as u8));
+    let mut protocol_data = Vec::with_capacity(num_entries.min(MAX_PREALLOCATED_ENTRIES) as usize);
+    for _ in 0..num_entries {
         let protocol_name = String::from_utf8(reader.read_var_octet_string()?)?;
         let content_type = ContentType::from(reader.read_u8()?);
         let data = reader.read_var_octet_string()?;
@@ -113,8 +113,7 @@ fn put_protocol_data<T>(buf: &mut T, protocol_data: &[ProtocolData])
 where
     T: BufMut,
 {
-    let length = BigUint::from(protocol_data.len());
-    buf.put_var_uint(&length);
+    buf.put_var_uint(protocol_data.len() as u64);
     for entry in protocol_data {
         buf.put_var_octet_string(entry.protocol_name.as_bytes());
         buf.put_u8(entry.content_type.clone() as u8);
@@ -122,6 +121,39 @@ where
     }
 }
 
+/// Returns the number of bytes `put_protocol_data` writes
+fn protocol_data_len(protocol_data: &[ProtocolData]) -> usize {
+    protocol_data.iter().fold(
+        predict_var_uint(protocol_data.len() as u64),
+        |len, entry| {
+            len + predict_var_octet_string(entry.protocol_name.len())
+                + 1
+                + predict_var_octet_string(entry.data.len())
+        },
+    )
+}
+
+/// Allocates the whole packet up front and writes the contents straight into it
+/// instead of into a separate buffer that would be copied in afterwards
+fn serialize_packet<F>(
+    packet_type: PacketType,
+    request_id: u32,
+    contents_len: usize,
+    put_contents: F,
+) -> Vec<u8>
+where
+    F: FnOnce(&mut Vec<u8>),
+{
+    let len = HEADER_LEN + predict_var_octet_string(contents_len);
+    let mut buf = Vec::with_capacity(len);
+    buf.put_u8(packet_type as u8);
+    buf.put_u32_be(request_id);
+    buf.put_var_octet_string_length(contents_len);
+    put_contents(&mut buf);
+    debug_assert_eq!(buf.len(), len);
+    buf
+}
+
 #[derive(Debug, PartialEq, Clone)]
 pub struct BtpMessage {
     pub request_id: u32,
@@ -148,14 +180,12 @@ impl Serializable<BtpMessage> for BtpMessage {
     }
 
     fn to_bytes(&self) -> Vec<u8> {
-        let mut buf = Vec::new();
-        buf.put_u8(PacketType::Message as u8);
-        buf.put_u32_be(self.request_id);
-        // TODO make sure this isn't copying the contents
-        let mut contents = Vec::new();
-        put_protocol_data(&mut contents, &self.protocol_data);
-        buf.put_var_octet_string(&contents);
-        buf
+        serialize_packet(
+            PacketType::Message,
+            self.request_id,
+            protocol_data_len(&self.protocol_data),
+            |buf| put_protocol_data(buf, &self.protocol_data),
+        )
     }
 }
 
@@ -185,13 +215,12 @@ impl Serializable<BtpResponse> for BtpResponse {
     }
 
     fn to_bytes(&self) -> Vec<u8> {
-        let mut buf = Vec::new();
-        buf.put_u8(PacketType::Response as u8);
-        buf.put_u32_be(self.request_id);
-        let mut contents = Vec::new();
-        put_protocol_data(&mut contents, &self.protocol_data);
-        buf.put_var_octet_string(&contents);
-        buf
+        serialize_packet(
+            PacketType::Response,
+            self.request_id,
+            protocol_data_len(&self.protocol_data),
+            |buf| put_protocol_data(buf, &self.protocol_data),
+        )
     }
 }
 
@@ -235,23 +264,23 @@ impl Serializable<BtpError> for BtpError {
     }
 
     fn to_bytes(&self) -> Vec<u8> {
-        let mut buf = Vec::new();
-        buf.put_u8(PacketType::Error as u8);
-        buf.put_u32_be(self.request_id);
-        let mut contents = Vec::new();
-        // TODO check that the code is only 3 chars
-        contents.put(self.code.as_bytes());
-        contents.put_var_octet_string(self.name.as_bytes());
-        contents.put_var_octet_string(
-            self.triggered_at
-                .format(GENERALIZED_TIME_FORMAT)
-                .to_string()
-                .as_bytes(),
-        );
-        contents.put_var_octet_string(self.data.as_bytes());
-        put_protocol_data(&mut contents, &self.protocol_data);
-        buf.put_var_octet_string(&contents);
-        buf
+        let triggered_at = self
+            .triggered_at
+            .format(GENERALIZED_TIME_FORMAT)
+            .to_string();
+        let contents_len = self.code.len()
+            + predict_var_octet_string(self.name.len())
+            + predict_var_octet_string(triggered_at.len())
+            + predict_var_octet_string(self.data.len())
+            + protocol_data_len(&self.protocol_data);
+        serialize_packet(PacketType::Error, self.request_id, contents_len, |buf| {
+            // TODO check that the code is only 3 chars
+            buf.put(self.code.as_bytes());
+            buf.put_var_octet_string(self.name.as_bytes());
+            buf.put_var_octet_string(triggered_at.as_bytes());
+            buf.put_var_octet_string(self.data.as_bytes());
+            put_protocol_data(buf, &self.protocol_data);
+        })
     }
 }
 
@@ -295,6 +324,22 @@ mod tests {
         fn to_bytes() {
             assert_eq!(MESSAGE_1.to_bytes(), *MESSAGE_1_SERIALIZED);
         }
+
+        #[test]
+        fn round_trips_large_protocol_data() {
+            let message = BtpMessage {
+                request_id: 3,
+                protocol_data: vec![ProtocolData {
+                    protocol_name: String::from("ilp"),
+                    content_type: ContentType::ApplicationOctetStream,
+                    data: vec![0xab; 70_000],
+                }],
+            };
+            assert_eq!(
+                BtpMessage::from_bytes(&message.to_bytes()).unwrap(),
+                message
+            );
+        }
     }
 
     mod btp_response {
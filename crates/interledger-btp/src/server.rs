@@ -1,10 +1,12 @@
 use super::{
-    packet::*, BtpAccount, BtpOpenSignupAccount, BtpOpenSignupStore, BtpOutgoingService, BtpStore,
+    packet::*, BtpAccount, BtpCapabilities, BtpOpenSignupAccount, BtpOpenSignupStore,
+    BtpOutgoingService, BtpStore,
 };
 use base64;
 use bytes::{BufMut, Bytes, BytesMut};
 use futures::{future::result, Future, Sink, Stream};
 use interledger_ildcp::IldcpResponse;
+use interledger_packet::redact::Redacted;
 use interledger_service::*;
 use ring::digest::{digest, SHA256};
 use std::{net::SocketAddr, str};
@@ -46,6 +48,7 @@ where
             .for_each(move |stream| {
                 let service_clone = service_clone.clone();
                 let store = store.clone();
+                let remote_address = stream.peer_addr().ok();
                 accept_async_with_config(
                     MaybeTlsStream::Plain(stream),
                     Some(WebSocketConfig {
@@ -56,9 +59,20 @@ where
                 )
                 .map_err(|err| error!("Error accepting incoming WebSocket connection: {:?}", err))
                 .and_then(|connection| validate_auth(store, connection))
-                .and_then(move |(account, connection)| {
-                    debug!("Added connection for account: {:?}", account);
-                    service_clone.add_connection(account, connection);
+                .and_then(move |(account, connection, capabilities)| {
+                    debug!(
+                        "Added connection for account: {:?} (BTP version {} with features {:?})",
+                        account,
+                        capabilities.version,
+                        capabilities.features()
+                    );
+                    // Peers reconnect to us themselves, so we do not need to know when they close
+                    let _ = service_clone.add_connection(
+                        account,
+                        connection,
+                        remote_address,
+                        capabilities,
+                    );
                     Ok(())
                 })
             })
@@ -100,6 +114,7 @@ where
             .for_each(move |stream| {
                 let service_clone = service_clone.clone();
                 let store = store.clone();
+                let remote_address = stream.peer_addr().ok();
                 let ildcp_info = ildcp_info.clone();
                 accept_async_with_config(
                     MaybeTlsStream::Plain(stream),
@@ -111,9 +126,20 @@ where
                 )
                 .map_err(|err| error!("Error accepting incoming WebSocket connection: {:?}", err))
                 .and_then(move |connection| get_or_create_account(store, ildcp_info, connection))
-                .and_then(move |(account, connection)| {
-                    debug!("Added connection for account: {:?}", account);
-                    service_clone.add_connection(account, connection);
+                .and_then(move |(account, connection, capabilities)| {
+                    debug!(
+                        "Added connection for account: {:?} (BTP version {} with features {:?})",
+                        account,
+                        capabilities.version,
+                        capabilities.features()
+                    );
+                    // Peers reconnect to us themselves, so we do not need to know when they close
+                    let _ = service_clone.add_connection(
+                        account,
+                        connection,
+                        remote_address,
+                        capabilities,
+                    );
                     Ok(())
                 })
             });
@@ -127,9 +153,33 @@ struct Auth {
     request_id: u32,
     username: Option<String>,
     token: String,
+    /// What the peer advertised it supports
+    capabilities: BtpCapabilities,
 }
 
-fn validate_auth<U, C, A>(store: U, connection: C) -> impl Future<Item = (A, C), Error = ()>
+/// The response to the auth packet, along with the capabilities to use for the connection.
+/// Our capabilities are only advertised to peers that advertised theirs, because older
+/// peers do not expect any protocol data in the response
+fn accept_auth(request_id: u32, peer: BtpCapabilities) -> (Message, BtpCapabilities) {
+    let protocol_data = if peer == BtpCapabilities::legacy() {
+        Vec::new()
+    } else {
+        vec![BtpCapabilities::default().to_protocol_data()]
+    };
+    let response = Message::Binary(
+        BtpResponse {
+            request_id,
+            protocol_data,
+        }
+        .to_bytes(),
+    );
+    (response, BtpCapabilities::default().negotiate(&peer))
+}
+
+fn validate_auth<U, C, A>(
+    store: U,
+    connection: C,
+) -> impl Future<Item = (A, C, BtpCapabilities), Error = ()>
 where
     U: BtpStore<Account = A> + 'static,
     C: Stream<Item = Message> + Sink<SinkItem = Message>,
@@ -139,19 +189,18 @@ where
         let token = auth.token.clone();
         store
             .get_account_from_btp_token(&auth.token)
-            .map_err(move |_| warn!("Got unauthorized connection with token: {}", token))
+            .map_err(move |_| {
+                warn!(
+                    "Got unauthorized connection with token: {}",
+                    Redacted(&token)
+                )
+            })
             .and_then(move |account| {
-                let auth_response = Message::Binary(
-                    BtpResponse {
-                        request_id: auth.request_id,
-                        protocol_data: Vec::new(),
-                    }
-                    .to_bytes(),
-                );
+                let (auth_response, capabilities) = accept_auth(auth.request_id, auth.capabilities);
                 connection
                     .send(auth_response)
                     .map_err(|_err| error!("Error sending auth response"))
-                    .and_then(|connection| Ok((account, connection)))
+                    .and_then(move |connection| Ok((account, connection, capabilities)))
             })
     })
 }
@@ -160,7 +209,7 @@ fn get_or_create_account<A, C, U>(
     store: U,
     ildcp_info: IldcpResponse,
     connection: C,
-) -> impl Future<Item = (A, C), Error = ()>
+) -> impl Future<Item = (A, C, BtpCapabilities), Error = ()>
 where
     U: BtpStore<Account = A> + BtpOpenSignupStore<Account = A> + 'static,
     C: Stream<Item = Message> + Sink<SinkItem = Message>,
@@ -168,6 +217,7 @@ where
 {
     get_auth(connection).and_then(move |(auth, connection)| {
         let request_id = auth.request_id;
+        let peer_capabilities = auth.capabilities;
         store
             .get_account_from_btp_token(&auth.token)
             .or_else(move |_| {
@@ -199,17 +249,11 @@ where
                     })
             })
             .and_then(move |account| {
-                let auth_response = Message::Binary(
-                    BtpResponse {
-                        request_id,
-                        protocol_data: Vec::new(),
-                    }
-                    .to_bytes(),
-                );
+                let (auth_response, capabilities) = accept_auth(request_id, peer_capabilities);
                 connection
                     .send(auth_response)
                     .map_err(|_err| error!("Error sending auth response"))
-                    .and_then(|connection| Ok((account, connection)))
+                    .and_then(move |connection| Ok((account, connection, capabilities)))
             })
     })
 }
@@ -251,6 +295,7 @@ fn parse_auth(ws_packet: Option<Message>) -> Option<Auth> {
                     request_id,
                     token,
                     username,
+                    capabilities: BtpCapabilities::from_protocol_data(&message.protocol_data),
                 });
             }
         }
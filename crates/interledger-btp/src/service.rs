@@ -1,4 +1,4 @@
-use super::packet::*;
+use super::{packet::*, BtpCapabilities};
 use bytes::BytesMut;
 use futures::{
     future::err,
@@ -15,24 +15,52 @@ use std::{
     io::{Error as IoError, ErrorKind},
     iter::IntoIterator,
     marker::PhantomData,
-    sync::Arc,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 use stream_cancel::{Trigger, Valve, Valved};
 use tokio_executor::spawn;
 use tokio_tcp::TcpStream;
+use tokio_timer::Interval;
 use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
 use tungstenite::{error::Error as WebSocketError, Message};
 
-type WsStream = WebSocketStream<MaybeTlsStream<TcpStream>>;
+pub(crate) type WsStream = WebSocketStream<MaybeTlsStream<TcpStream>>;
 type IlpResultChannel = oneshot::Sender<Result<Fulfill, Reject>>;
 type IncomingRequestBuffer<A> = UnboundedReceiver<(A, u32, Prepare)>;
 
+/// Details about an open BTP connection.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BtpConnectionInfo<I> {
+    pub account_id: I,
+    /// The peer's address, if it connected to our BTP server
+    pub remote_address: Option<SocketAddr>,
+    /// Seconds since the UNIX epoch
+    pub connected_at: u64,
+    /// Number of ILP Prepare packets sent and received over the connection
+    pub packets: usize,
+    /// The protocol version and features negotiated with the peer when it connected
+    pub capabilities: BtpCapabilities,
+}
+
+struct Connection {
+    sender: UnboundedSender<Message>,
+    remote_address: Option<SocketAddr>,
+    connected_at: u64,
+    packets: Arc<AtomicUsize>,
+    capabilities: BtpCapabilities,
+}
+
 /// A container for BTP/WebSocket connections that implements OutgoingService
 /// for sending outgoing ILP Prepare packets over one of the connected BTP connections.
 #[derive(Clone)]
 pub struct BtpOutgoingService<T, A: Account> {
     // TODO support multiple connections per account
-    connections: Arc<RwLock<HashMap<A::AccountId, UnboundedSender<Message>>>>,
+    connections: Arc<RwLock<HashMap<A::AccountId, Connection>>>,
     pending_outgoing: Arc<Mutex<HashMap<u32, IlpResultChannel>>>,
     pending_incoming: Arc<Mutex<Option<IncomingRequestBuffer<A>>>>,
     incoming_sender: UnboundedSender<(A, u32, Prepare)>,
@@ -72,8 +100,20 @@ where
     /// incoming Prepare packets are buffered in a channel (until an IncomingService is added
     /// via the handle_incoming method), and ILP Fulfill and Reject packets will be
     /// sent back to the Future that sent the outgoing request originally.
-    pub(crate) fn add_connection(&self, account: A, connection: WsStream) {
+    ///
+    /// If keepalives were negotiated with the peer, the connection is pinged at the agreed interval.
+    ///
+    /// The returned receiver resolves if the connection closes on its own, rather than being
+    /// replaced, disconnected, or closed along with all the others.
+    pub(crate) fn add_connection(
+        &self,
+        account: A,
+        connection: WsStream,
+        remote_address: Option<SocketAddr>,
+        capabilities: BtpCapabilities,
+    ) -> oneshot::Receiver<()> {
         let account_id = account.id();
+        let packets = Arc::new(AtomicUsize::new(0));
 
         // Set up a channel to forward outgoing packets to the WebSocket connection
         let (tx, rx) = unbounded();
@@ -96,10 +136,17 @@ where
         // TODO do we need all this cloning?
         let pending_requests = self.pending_outgoing.clone();
         let incoming_sender = self.incoming_sender.clone();
+        let incoming_packets = packets.clone();
         let handle_incoming = stream.map_err(move |err| error!("Error reading from WebSocket stream for account {}: {:?}", account_id, err)).for_each(move |message| {
+          match message {
+            // Pings are answered automatically and pongs are the answers to our keepalives
+            Message::Ping(_) | Message::Pong(_) => return Ok(()),
+            _ => {}
+          }
           // Handle the packets based on whether they are an incoming request or a response to something we sent
           match parse_ilp_packet(message) {
             Ok((request_id, Packet::Prepare(prepare))) => {
+                incoming_packets.fetch_add(1, Ordering::Relaxed);
                 incoming_sender.clone().unbounded_send((account.clone(), request_id, prepare))
                     .map_err(|err| error!("Unable to buffer incoming request: {:?}", err))
             },
@@ -129,12 +176,22 @@ where
 
         let connections = self.connections.clone();
         let keep_connections_open = self.close_all_connections.clone();
+        let connection_packets = packets.clone();
+        let (closed, closed_receiver) = oneshot::channel();
         let handle_connection = handle_incoming
             .select(forward_to_connection)
             .then(move |_| {
                 let _ = keep_connections_open;
                 let mut connections = connections.write();
-                connections.remove(&account_id);
+                // Leave the connection that replaced this one, if there is one
+                let is_current = match connections.get(&account_id) {
+                    Some(connection) => Arc::ptr_eq(&connection.packets, &connection_packets),
+                    None => false,
+                };
+                if is_current {
+                    connections.remove(&account_id);
+                    let _ = closed.send(());
+                }
                 debug!(
                     "WebSocket connection closed for account {} ({} connections still open)",
                     account_id,
@@ -142,10 +199,88 @@ where
                 );
                 Ok(())
             });
+
+        // Save the sender side of the channel so we have a way to forward outgoing requests to the WebSocket.
+        // This happens before the tasks below are spawned, because they stop once they cannot find the connection
+        let connected_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs())
+            .unwrap_or(0);
+        self.connections.write().insert(
+            account_id,
+            Connection {
+                sender: tx,
+                remote_address,
+                connected_at,
+                packets: packets.clone(),
+                capabilities,
+            },
+        );
         spawn(handle_connection);
 
-        // Save the sender side of the channel so we have a way to forward outgoing requests to the WebSocket
-        self.connections.write().insert(account_id, tx);
+        if let Some(interval) = capabilities.keepalive_interval {
+            let connections = self.connections.clone();
+            let keepalive_packets = packets;
+            let keepalive = Interval::new_interval(Duration::from_secs(u64::from(interval)))
+                .map_err(|err| error!("Error in WebSocket keepalive timer: {:?}", err))
+                .for_each(move |_| {
+                    // Stop once this connection is closed or replaced by a new one for the same account
+                    match connections.read().get(&account_id) {
+                        Some(connection)
+                            if Arc::ptr_eq(&connection.packets, &keepalive_packets) =>
+                        {
+                            connection
+                                .sender
+                                .unbounded_send(Message::Ping(Vec::new()))
+                                .map_err(|_| ())
+                        }
+                        _ => Err(()),
+                    }
+                })
+                .then(move |_| {
+                    trace!("Stopped sending keepalives for account {}", account_id);
+                    Ok(())
+                });
+            spawn(keepalive);
+        }
+        closed_receiver
+    }
+
+    /// Whether `close` has been called.
+    pub(crate) fn is_closed(&self) -> bool {
+        self.close_all_connections.lock().is_none()
+    }
+
+    /// Whether there is an open connection for the given account.
+    pub(crate) fn is_connected(&self, account_id: A::AccountId) -> bool {
+        self.connections.read().contains_key(&account_id)
+    }
+
+    /// List the open WebSocket connections.
+    pub fn connections(&self) -> Vec<BtpConnectionInfo<A::AccountId>> {
+        self.connections
+            .read()
+            .iter()
+            .map(|(account_id, connection)| BtpConnectionInfo {
+                account_id: *account_id,
+                remote_address: connection.remote_address,
+                connected_at: connection.connected_at,
+                packets: connection.packets.load(Ordering::Relaxed),
+                capabilities: connection.capabilities,
+            })
+            .collect()
+    }
+
+    /// Close the WebSocket connection for the given account.
+    /// Returns false if there was no open connection for that account.
+    pub fn disconnect(&self, account_id: A::AccountId) -> bool {
+        // Dropping the sender ends the forwarding task, which closes the connection
+        if self.connections.write().remove(&account_id).is_some() {
+            debug!("Disconnecting WebSocket connection for account {}", account_id);
+            true
+        } else {
+            false
+        }
     }
 
     /// Convert this BtpOutgoingService into a bidirectional BtpService by adding a handler for incoming requests.
@@ -181,20 +316,26 @@ where
                             Err(reject) => Packet::Reject(reject),
                         };
                         let message = ilp_packet_to_ws_message(request_id, packet);
-                        connections_clone
-                            .read()
-                            .get(&account_id)
-                            .expect(
-                                "No connection for account (something very strange has happened)",
-                            )
-                            .clone()
-                            .unbounded_send(message)
-                            .map_err(|err| {
-                                error!(
-                                    "Error sending response to account: {} {:?}",
-                                    account_id, err
-                                )
-                            })
+                        if let Some(connection) = connections_clone.read().get(&account_id) {
+                            connection
+                                .sender
+                                .clone()
+                                .unbounded_send(message)
+                                .map_err(|err| {
+                                    error!(
+                                        "Error sending response to account: {} {:?}",
+                                        account_id, err
+                                    )
+                                })
+                        } else {
+                            // The connection may have been closed (or disconnected by an admin)
+                            // while the request was being handled
+                            debug!(
+                                "Connection for account {} was closed before the response could be sent",
+                                account_id
+                            );
+                            Ok(())
+                        }
                     })
             })
             .then(move |_| {
@@ -224,6 +365,7 @@ where
     fn send_request(&mut self, request: OutgoingRequest<A>) -> Self::Future {
         if let Some(connection) = (*self.connections.read()).get(&request.to.id()) {
             let request_id = random::<u32>();
+            connection.packets.fetch_add(1, Ordering::Relaxed);
 
             // Clone the trigger so that the connections stay open until we've
             // gotten the response to our outgoing request
@@ -231,7 +373,7 @@ where
 
             debug!("Sending outgoing request: {:?}", request);
 
-            match connection.unbounded_send(ilp_packet_to_ws_message(
+            match connection.sender.unbounded_send(ilp_packet_to_ws_message(
                 request_id,
                 Packet::Prepare(request.prepare),
             )) {
@@ -301,6 +443,17 @@ where
     pub fn close(&self) {
         self.outgoing.close();
     }
+
+    /// List the open WebSocket connections.
+    pub fn connections(&self) -> Vec<BtpConnectionInfo<A::AccountId>> {
+        self.outgoing.connections()
+    }
+
+    /// Close the WebSocket connection for the given account.
+    /// Returns false if there was no open connection for that account.
+    pub fn disconnect(&self, account_id: A::AccountId) -> bool {
+        self.outgoing.disconnect(account_id)
+    }
 }
 
 impl<S, T, A> OutgoingService<A> for BtpService<S, T, A>
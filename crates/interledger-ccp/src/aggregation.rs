@@ -0,0 +1,256 @@
+use crate::packet::Route;
+use bytes::Bytes;
+use hashbrown::{HashMap, HashSet};
+
+/// The prefix one segment shorter than the given one ("example.a.b" -> "example.a")
+fn parent_prefix(prefix: &Bytes) -> Option<Bytes> {
+    let index = prefix.iter().rposition(|c| c == &b'.')?;
+    Some(prefix.slice_to(index))
+}
+
+/// Summarize the routes we forward to peers by replacing sibling prefixes with their parent prefix.
+///
+/// The routes under a parent prefix are replaced with a single route for the parent if there
+/// are at least `threshold` routes for its direct children and all of the routes under it go
+/// through the same account. The aggregate's path includes every address from the paths of the
+/// routes it replaces, so that peers can still detect routing loops. Parents that are not longer
+/// than the global prefix are never advertised.
+pub(crate) fn aggregate_routes<I>(
+    mut routes: Vec<(I, Route)>,
+    threshold: usize,
+    global_prefix: &[u8],
+) -> Vec<Route>
+where
+    I: Copy + PartialEq,
+{
+    // Sorting makes the aggregates' paths the same every time
+    routes.sort_unstable_by(|(_, a), (_, b)| a.prefix.cmp(&b.prefix));
+
+    let mut children: HashMap<Bytes, usize> = HashMap::new();
+    for (_account_id, route) in routes.iter() {
+        if let Some(parent) = parent_prefix(&route.prefix) {
+            if parent.len() > global_prefix.len() && parent.starts_with(global_prefix) {
+                *children.entry(parent).or_insert(0) += 1;
+            }
+        }
+    }
+    let mut parents: Vec<Bytes> = children
+        .into_iter()
+        .filter(|(_parent, count)| *count >= threshold)
+        .map(|(parent, _count)| parent)
+        .collect();
+    // Aggregate the shortest parents first so that each route is only summarized once
+    parents.sort_unstable_by_key(|parent| parent.len());
+
+    // Find the routes under each parent by walking up from each route's prefix,
+    // so this takes time proportional to the number of routes times their depth
+    let mut routes_under: HashMap<Bytes, Vec<usize>> = parents
+        .iter()
+        .map(|parent| (parent.clone(), Vec::new()))
+        .collect();
+    for (index, (_account_id, route)) in routes.iter().enumerate() {
+        let mut prefix = Some(route.prefix.clone());
+        while let Some(current) = prefix {
+            if current.len() <= global_prefix.len() {
+                break;
+            }
+            if let Some(members) = routes_under.get_mut(&current) {
+                members.push(index);
+            }
+            prefix = parent_prefix(&current);
+        }
+    }
+
+    let mut aggregated = vec![false; routes.len()];
+    let mut aggregates: Vec<Route> = Vec::with_capacity(parents.len());
+    for parent in parents {
+        let members: Vec<usize> = routes_under
+            .remove(&parent)
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|index| !aggregated[*index])
+            .collect();
+        if members.is_empty() {
+            continue;
+        }
+        let account_id = routes[members[0]].0;
+        if members.iter().any(|index| routes[*index].0 != account_id) {
+            continue;
+        }
+
+        let first = &routes[members[0]].1;
+        let mut path: Vec<Bytes> = Vec::new();
+        let mut in_path: HashSet<&Bytes> = HashSet::new();
+        for index in members.iter() {
+            for address in routes[*index].1.path.iter() {
+                if in_path.insert(address) {
+                    path.push(address.clone());
+                }
+            }
+        }
+        // Only keep the properties if the routes agree on them
+        let props = if members
+            .iter()
+            .all(|index| routes[*index].1.props == first.props)
+        {
+            first.props.clone()
+        } else {
+            Vec::new()
+        };
+        debug!(
+            "Aggregating {} routes into prefix: {}",
+            members.len(),
+            String::from_utf8_lossy(&parent[..])
+        );
+        aggregates.push(Route {
+            prefix: parent,
+            path,
+            auth: first.auth,
+            props,
+        });
+        for index in members {
+            aggregated[index] = true;
+        }
+    }
+
+    routes
+        .into_iter()
+        .zip(aggregated.into_iter())
+        .filter(|(_, aggregated)| !aggregated)
+        .map(|((_account_id, route), _)| route)
+        .chain(aggregates.into_iter())
+        .collect()
+}
+
+/// Compare the routes we advertised before and after an update. Returns the routes
+/// that are new or changed and the prefixes that are no longer advertised
+pub(crate) fn diff_routes(before: &[Route], after: Vec<Route>) -> (Vec<Route>, Vec<Bytes>) {
+    let before: HashMap<&Bytes, &Route> =
+        before.iter().map(|route| (&route.prefix, route)).collect();
+    let after_prefixes: HashSet<Bytes> = after.iter().map(|route| route.prefix.clone()).collect();
+    let withdrawn_routes = before
+        .keys()
+        .filter(|prefix| !after_prefixes.contains(**prefix))
+        .map(|prefix| (*prefix).clone())
+        .collect();
+    let new_routes = after
+        .into_iter()
+        .filter(|route| before.get(&route.prefix) != Some(&route))
+        .collect();
+    (new_routes, withdrawn_routes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn route(prefix: &str, path: &[&str]) -> Route {
+        Route {
+            prefix: Bytes::from(prefix),
+            path: path.iter().map(|address| Bytes::from(*address)).collect(),
+            auth: [0; 32],
+            props: Vec::new(),
+        }
+    }
+
+    fn prefixes(routes: &[Route]) -> Vec<&[u8]> {
+        let mut prefixes: Vec<&[u8]> = routes.iter().map(|route| &route.prefix[..]).collect();
+        prefixes.sort();
+        prefixes
+    }
+
+    #[test]
+    fn aggregates_siblings_through_same_account() {
+        let routes = vec![
+            (1, route("example.a.one", &["example.me", "example.a"])),
+            (1, route("example.a.two", &["example.me", "example.a"])),
+            (1, route("example.a.three", &["example.me", "example.b"])),
+            (2, route("example.c", &["example.me"])),
+        ];
+        let aggregated = aggregate_routes(routes, 3, b"example.");
+        assert_eq!(
+            prefixes(&aggregated),
+            vec![&b"example.a"[..], &b"example.c"[..]]
+        );
+        let aggregate = aggregated
+            .iter()
+            .find(|route| route.prefix == "example.a")
+            .unwrap();
+        assert_eq!(
+            aggregate.path,
+            vec![
+                Bytes::from("example.me"),
+                Bytes::from("example.a"),
+                Bytes::from("example.b")
+            ]
+        );
+    }
+
+    #[test]
+    fn doesnt_aggregate_below_threshold() {
+        let routes = vec![
+            (1, route("example.a.one", &["example.me"])),
+            (1, route("example.a.two", &["example.me"])),
+        ];
+        let aggregated = aggregate_routes(routes, 3, b"example.");
+        assert_eq!(
+            prefixes(&aggregated),
+            vec![&b"example.a.one"[..], &b"example.a.two"[..]]
+        );
+    }
+
+    #[test]
+    fn doesnt_aggregate_routes_through_different_accounts() {
+        let routes = vec![
+            (1, route("example.a.one", &["example.me"])),
+            (1, route("example.a.two", &["example.me"])),
+            (2, route("example.a.two.x", &["example.me"])),
+        ];
+        let aggregated = aggregate_routes(routes, 2, b"example.");
+        assert_eq!(aggregated.len(), 3);
+    }
+
+    #[test]
+    fn aggregates_longer_parent_if_shorter_one_goes_through_different_accounts() {
+        let routes = vec![
+            (1, route("example.a.b.one", &["example.me"])),
+            (1, route("example.a.b.two", &["example.me"])),
+            (2, route("example.a.c", &["example.me"])),
+            (2, route("example.a.d", &["example.me"])),
+        ];
+        let aggregated = aggregate_routes(routes, 2, b"example.");
+        assert_eq!(
+            prefixes(&aggregated),
+            vec![
+                &b"example.a.b"[..],
+                &b"example.a.c"[..],
+                &b"example.a.d"[..]
+            ]
+        );
+    }
+
+    #[test]
+    fn doesnt_aggregate_into_global_prefix() {
+        let routes = vec![
+            (1, route("example.one", &["example.me"])),
+            (1, route("example.two", &["example.me"])),
+        ];
+        let aggregated = aggregate_routes(routes, 2, b"example.");
+        assert_eq!(aggregated.len(), 2);
+    }
+
+    #[test]
+    fn diffs_advertised_routes() {
+        let before = vec![
+            route("example.a", &["example.me"]),
+            route("example.b", &["example.me"]),
+        ];
+        let after = vec![
+            route("example.a", &["example.me"]),
+            route("example.c", &["example.me"]),
+        ];
+        let (new_routes, withdrawn_routes) = diff_routes(&before, after);
+        assert_eq!(prefixes(&new_routes), vec![&b"example.c"[..]]);
+        assert_eq!(withdrawn_routes, vec![Bytes::from("example.b")]);
+    }
+}
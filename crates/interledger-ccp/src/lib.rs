@@ -15,12 +15,14 @@ extern crate log;
 extern crate lazy_static;
 
 use bytes::Bytes;
-use futures::Future;
+use futures::{future::ok, Future};
 use hashbrown::HashMap;
 use interledger_ildcp::IldcpAccount;
+use interledger_router::RouteHint;
 use interledger_service::Account;
 use std::{str::FromStr, string::ToString};
 
+mod aggregation;
 #[cfg(test)]
 mod fixtures;
 mod packet;
@@ -29,7 +31,8 @@ mod server;
 #[cfg(test)]
 mod test_helpers;
 
-pub use server::CcpRouteManager;
+pub use packet::PATH_HINT_PROP_ID;
+pub use server::{CcpRouteManager, DEFAULT_BROADCAST_INTERVAL};
 
 #[repr(u8)]
 #[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
@@ -96,4 +99,14 @@ pub trait RouteManagerStore: Clone {
     fn set_routes<R>(&mut self, routes: R) -> Box<Future<Item = (), Error = ()> + Send>
     where
         R: IntoIterator<Item = (Bytes, Self::Account)>;
+
+    /// Save the price and liquidity hints of the routes our peers advertised for each prefix,
+    /// which the `Router` uses to pick the next hop that delivers the most.
+    /// Stores that do not keep hints can ignore them.
+    fn set_route_hints(
+        &mut self,
+        _hints: HashMap<Bytes, Vec<RouteHint<<Self::Account as Account>::AccountId>>>,
+    ) -> Box<Future<Item = (), Error = ()> + Send> {
+        Box::new(ok(()))
+    }
 }
@@ -23,6 +23,10 @@ const FLAG_OPTIONAL: u8 = 0x80;
 const FLAG_TRANSITIVE: u8 = 0x40;
 const FLAG_PARTIAL: u8 = 0x20;
 const FLAG_UTF8: u8 = 0x10;
+/// The ID of the route property that carries a price and liquidity hint for the route's path.
+/// Its value is "<asset code> <rate>" or "<asset code> <rate> <max amount>", where the rate is how
+/// many units of the destination's asset one unit of the asset delivers along the path
+pub const PATH_HINT_PROP_ID: u16 = 0x0100;
 
 lazy_static! {
     pub static ref CCP_RESPONSE: Fulfill = FulfillBuilder {
@@ -184,6 +188,63 @@ impl RouteProp {
     }
 }
 
+/// The price and liquidity hint advertised along with a route
+#[derive(Clone, PartialEq, Debug)]
+pub struct PathHint {
+    pub(crate) asset_code: String,
+    pub(crate) rate: f64,
+    pub(crate) max_amount: Option<f64>,
+}
+
+impl PathHint {
+    pub fn try_from(prop: &RouteProp) -> Result<Self, ParseError> {
+        let value = str::from_utf8(&prop.value[..])
+            .map_err(|_| ParseError::InvalidPacket("Path hint is not utf8".to_string()))?;
+        let invalid = || ParseError::InvalidPacket(format!("Invalid path hint: {}", value));
+        let mut parts = value.split(' ');
+        let asset_code = parts
+            .next()
+            .filter(|code| !code.is_empty())
+            .ok_or_else(invalid)?;
+        let rate = parts
+            .next()
+            .and_then(|rate| rate.parse::<f64>().ok())
+            .filter(|rate| rate.is_finite() && *rate >= 0.0)
+            .ok_or_else(invalid)?;
+        let max_amount = match parts.next() {
+            Some(max_amount) => Some(
+                max_amount
+                    .parse::<f64>()
+                    .ok()
+                    .filter(|amount| amount.is_finite() && *amount >= 0.0)
+                    .ok_or_else(invalid)?,
+            ),
+            None => None,
+        };
+        Ok(PathHint {
+            asset_code: asset_code.to_string(),
+            rate,
+            max_amount,
+        })
+    }
+
+    pub fn to_prop(&self) -> RouteProp {
+        let value = if let Some(max_amount) = self.max_amount {
+            format!("{} {} {}", self.asset_code, self.rate, max_amount)
+        } else {
+            format!("{} {}", self.asset_code, self.rate)
+        };
+        RouteProp {
+            is_optional: true,
+            is_transitive: true,
+            is_partial: false,
+            id: PATH_HINT_PROP_ID,
+            is_utf8: true,
+            value: Bytes::from(value),
+        }
+    }
+}
+
 #[derive(Clone, PartialEq, Debug)]
 pub struct Route {
     pub(crate) prefix: Bytes,
@@ -233,6 +294,21 @@ impl Route {
             prop.write_to(buf);
         }
     }
+
+    /// The price and liquidity hint advertised for this route's path, if it has a valid one
+    pub fn path_hint(&self) -> Option<PathHint> {
+        let prop = self
+            .props
+            .iter()
+            .find(|prop| prop.id == PATH_HINT_PROP_ID)?;
+        match PathHint::try_from(prop) {
+            Ok(hint) => Some(hint),
+            Err(err) => {
+                debug!("Ignoring path hint for route: {:?}", err);
+                None
+            }
+        }
+    }
 }
 
 #[derive(Clone, PartialEq, Debug)]
@@ -506,4 +582,45 @@ mod route_update_request {
 
         assert_eq!(route, Route::try_from(&mut &serialized[..]).unwrap());
     }
+
+    #[test]
+    fn path_hint() {
+        let hint = PathHint {
+            asset_code: "XYZ".to_string(),
+            rate: 0.5,
+            max_amount: Some(1000.0),
+        };
+        let route = Route {
+            prefix: Bytes::from("example.some-prefix-for-alice"),
+            path: Vec::new(),
+            auth: [0; 32],
+            props: vec![hint.to_prop()],
+        };
+
+        let mut serialized = Vec::new();
+        route.write_to(&mut serialized);
+
+        let route = Route::try_from(&mut &serialized[..]).unwrap();
+        assert_eq!(route.path_hint().unwrap(), hint);
+    }
+
+    #[test]
+    fn ignores_invalid_path_hint() {
+        for value in &["XYZ not-a-rate", "XYZ 1.0 NaN", "XYZ 1.0 -5"] {
+            let route = Route {
+                prefix: Bytes::from("example.some-prefix-for-alice"),
+                path: Vec::new(),
+                auth: [0; 32],
+                props: vec![RouteProp {
+                    is_optional: true,
+                    is_partial: false,
+                    is_utf8: true,
+                    is_transitive: true,
+                    value: Bytes::from(*value),
+                    id: PATH_HINT_PROP_ID,
+                }],
+            };
+            assert!(route.path_hint().is_none());
+        }
+    }
 }
@@ -104,11 +104,30 @@ where
             .insert(route.prefix.clone(), (account, route))
     }
 
+    /// Replace the first hop in the path of every route that has one, for example when
+    /// our own address that is added to the routes we forward has changed. Returns the updated routes
+    pub fn set_first_hop(&mut self, address: Bytes) -> Vec<Route> {
+        self.prefix_map
+            .map
+            .values_mut()
+            .filter_map(|(_account, route)| {
+                let first_hop = route.path.first_mut()?;
+                *first_hop = address.clone();
+                Some(route.clone())
+            })
+            .collect()
+    }
+
     /// Get the best route we have for the given prefix
     pub fn get_route(&self, prefix: &[u8]) -> Option<&(A, Route)> {
         self.prefix_map.resolve(prefix)
     }
 
+    /// Iterate over all of the routes in the table along with the accounts they go through
+    pub fn routes(&self) -> impl Iterator<Item = &(A, Route)> {
+        self.prefix_map.map.values()
+    }
+
     pub fn get_simplified_table(&self) -> HashMap<Bytes, A> {
         HashMap::from_iter(
             self.prefix_map
@@ -1,11 +1,17 @@
-use crate::{packet::*, routing_table::RoutingTable, CcpRoutingAccount, RouteManagerStore};
+use crate::{
+    aggregation::{aggregate_routes, diff_routes},
+    packet::*,
+    routing_table::RoutingTable,
+    CcpRoutingAccount, RouteManagerStore, RoutingRelation,
+};
 use bytes::Bytes;
 use futures::{
-    future::{err, join_all, ok, Either},
+    future::{err, join_all, ok, Either, Shared},
     Future, Stream,
 };
 use hashbrown::HashMap;
 use interledger_packet::*;
+use interledger_router::RouteHint;
 use interledger_service::{
     Account, BoxedIlpFuture, IncomingRequest, IncomingService, OutgoingRequest, OutgoingService,
 };
@@ -21,7 +27,8 @@ use tokio_executor::spawn;
 use tokio_timer::Interval;
 
 const DEFAULT_ROUTE_EXPIRY_TIME: u32 = 45000;
-const DEFAULT_BROADCAST_INTERVAL: u64 = 30000;
+/// How often routes are broadcast to peers, in milliseconds
+pub const DEFAULT_BROADCAST_INTERVAL: u64 = 30000;
 
 fn hash(preimage: &[u8; 32]) -> [u8; 32] {
     let mut out = [0; 32];
@@ -31,6 +38,8 @@ fn hash(preimage: &[u8; 32]) -> [u8; 32] {
 
 type NewAndWithDrawnRoutes = (Vec<Route>, Vec<Bytes>);
 
+type RouteControlFuture = Box<Future<Item = (), Error = ()> + Send>;
+
 /// The Routing Manager Service.
 ///
 /// This implements the Connector-to-Connector Protocol (CCP)
@@ -41,8 +50,9 @@ type NewAndWithDrawnRoutes = (Vec<Route>, Vec<Bytes>);
 #[derive(Clone)]
 pub struct CcpRouteManager<S, T, U, A: Account> {
     account: A,
-    ilp_address: Bytes,
-    global_prefix: Bytes,
+    /// Our own address, which can be changed while the node is running
+    ilp_address: Arc<RwLock<Bytes>>,
+    global_prefix: Arc<RwLock<Bytes>>,
     /// The next request handler that will be used both to pass on requests that are not CCP messages.
     next_incoming: S,
     /// The outgoing request handler that will be used to send outgoing CCP messages.
@@ -64,6 +74,17 @@ pub struct CcpRouteManager<S, T, U, A: Account> {
     /// Updates from peers are applied to our local_table if they are better than the
     /// existing best route and if they do not attempt to overwrite configured routes.
     incoming_tables: Arc<RwLock<HashMap<A::AccountId, RoutingTable<A>>>>,
+    /// The price and liquidity hints of the routes in the incoming tables that were last saved to the Store
+    route_hints: Arc<RwLock<HashMap<Bytes, Vec<RouteHint<A::AccountId>>>>>,
+    /// If set, sibling prefixes are summarized into their parent prefix when there are at
+    /// least this many of them and they all go through the same account
+    aggregation_threshold: Option<usize>,
+    /// The summarized routes we last advertised, which each update is compared to
+    /// when aggregation is enabled
+    advertised_routes: Arc<RwLock<Vec<Route>>>,
+    /// The Route Control Requests we are waiting on the responses to. A peer whose updates
+    /// have gaps is only sent one request at a time, which all of the updates share.
+    route_control_requests: Arc<Mutex<HashMap<A::AccountId, Shared<RouteControlFuture>>>>,
     store: U,
     /// If true, tasks will be spawned to process Route Update Requests and respond
     /// to Route Control Requests. If false, the response to the incoming request
@@ -108,18 +129,13 @@ where
         next_incoming: S,
         spawn_tasks: bool,
     ) -> Self {
-        // The global prefix is the first part of the address (for example "g." for the global address space, "example", "test", etc)
         let ilp_address = Bytes::from(account.client_address());
-        let global_prefix: Bytes = ilp_address
-            .iter()
-            .position(|c| c == &b'.')
-            .map(|index| ilp_address.slice_to(index + 1))
-            .unwrap_or_else(|| ilp_address.clone());
+        let global_prefix = global_prefix(&ilp_address);
 
         CcpRouteManager {
             account,
-            ilp_address,
-            global_prefix,
+            ilp_address: Arc::new(RwLock::new(ilp_address)),
+            global_prefix: Arc::new(RwLock::new(global_prefix)),
             next_incoming,
             outgoing,
             forwarding_table: Arc::new(RwLock::new(RoutingTable::default())),
@@ -127,23 +143,92 @@ where
             last_epoch_updates_sent_for: Arc::new(Mutex::new(0)),
             local_table: Arc::new(RwLock::new(RoutingTable::default())),
             incoming_tables: Arc::new(RwLock::new(HashMap::new())),
+            route_hints: Arc::new(RwLock::new(HashMap::new())),
+            aggregation_threshold: None,
+            advertised_routes: Arc::new(RwLock::new(Vec::new())),
+            route_control_requests: Arc::new(Mutex::new(HashMap::new())),
             store,
             spawn_tasks,
         }
     }
 
+    /// Summarize the routes we advertise to peers by replacing sibling prefixes with their
+    /// parent prefix when there are at least `threshold` of them and all of the routes under
+    /// the parent go through the same account. This reduces the size of the routing tables
+    /// we send, for example for nodes with many children.
+    pub fn aggregate_routes(mut self, threshold: usize) -> Self {
+        self.aggregation_threshold = Some(threshold);
+        self
+    }
+
+    fn ilp_address(&self) -> Bytes {
+        self.ilp_address.read().clone()
+    }
+
+    fn global_prefix(&self) -> Bytes {
+        self.global_prefix.read().clone()
+    }
+
+    /// Start using a new ILP address, for example because the node's address was changed
+    /// while it is running. The routes we forward are advertised again with the new address
+    /// in their paths, and the updates are sent to peers right away instead of waiting for
+    /// the next broadcast.
+    pub fn set_ilp_address(&self, ilp_address: Bytes) -> impl Future<Item = (), Error = ()> {
+        debug!(
+            "Changing our ILP address to: {}",
+            str::from_utf8(&ilp_address[..]).unwrap_or("<not utf8>")
+        );
+        *self.global_prefix.write() = global_prefix(&ilp_address);
+        *self.ilp_address.write() = ilp_address.clone();
+        {
+            let mut forwarding_table = self.forwarding_table.write();
+            let mut new_routes = forwarding_table.set_first_hop(ilp_address);
+            if let Some(threshold) = self.aggregation_threshold {
+                new_routes = advertised_routes(&forwarding_table, threshold, &self.global_prefix());
+                *self.advertised_routes.write() = new_routes.clone();
+            }
+            let epoch = forwarding_table.increment_epoch();
+            self.forwarding_table_updates
+                .write()
+                .insert(epoch, (new_routes, Vec::new()));
+        }
+
+        let clone = self.clone();
+        self.update_best_routes(None)
+            .and_then(move |_| clone.send_route_updates())
+    }
+
     /// Returns a future that will trigger this service to update its routes and broadcast
     /// updates to peers on the given interval.
     pub fn broadcast_routes(&self, interval: u64) -> impl Future<Item = (), Error = ()> {
+        self.broadcast_routes_when(interval, || true)
+    }
+
+    /// Like `broadcast_routes`, but skips the intervals in which `should_broadcast` returns false,
+    /// for example because another instance of the node that shares the store is broadcasting.
+    pub fn broadcast_routes_when<F>(
+        &self,
+        interval: u64,
+        should_broadcast: F,
+    ) -> impl Future<Item = (), Error = ()>
+    where
+        F: Fn() -> bool + Send + 'static,
+    {
         let clone = self.clone();
         Interval::new(Instant::now(), Duration::from_millis(interval))
             .map_err(|err| error!("Interval error, no longer sending route updates: {:?}", err))
             .for_each(move |_| {
+                if !should_broadcast() {
+                    trace!("Not broadcasting routes during this interval");
+                    return Either::B(ok(()));
+                }
                 let clone = clone.clone();
-                clone
-                    .clone()
-                    .update_best_routes(None)
-                    .and_then(move |_| clone.send_route_updates())
+                Either::A(
+                    clone
+                        .clone()
+                        .update_best_routes(None)
+                        .and_then(move |_| clone.send_route_updates()),
+                )
             })
     }
 
@@ -157,7 +242,7 @@ where
             return Either::A(err(RejectBuilder {
                 code: ErrorCode::F00_BAD_REQUEST,
                 message: b"We are not configured to send routes to you, sorry",
-                triggered_by: &self.ilp_address[..],
+                triggered_by: &self.ilp_address()[..],
                 data: &[],
             }
             .build()));
@@ -168,7 +253,7 @@ where
             return Either::A(err(RejectBuilder {
                 code: ErrorCode::F00_BAD_REQUEST,
                 message: b"Invalid route control request",
-                triggered_by: &self.ilp_address[..],
+                triggered_by: &self.ilp_address()[..],
                 data: &[],
             }
             .build()));
@@ -195,7 +280,7 @@ where
             };
 
             if !self.spawn_tasks {
-                let ilp_address = self.ilp_address.clone();
+                let ilp_address = self.ilp_address();
                 return Either::B(
                     self.send_route_update(request.from.clone(), from_epoch_index, to_epoch_index)
                         .map_err(move |_| {
@@ -221,24 +306,73 @@ where
         Either::A(ok(CCP_RESPONSE.clone()))
     }
 
-    /// Remove invalid routes before processing the Route Update Request
-    fn filter_routes(&self, mut update: RouteUpdateRequest) -> RouteUpdateRequest {
+    /// Remove invalid routes before processing the Route Update Request, including the ones
+    /// for address space that the account sending them does not have:
+    ///
+    /// - Children can only advertise routes within their own address
+    /// - Peers and parents cannot advertise routes within our address, or within the address
+    ///   of another account we are connected to unless the route goes through that account
+    fn filter_routes(&self, from: &A, mut update: RouteUpdateRequest) -> RouteUpdateRequest {
+        let ilp_address = self.ilp_address();
+        let global_prefix = self.global_prefix();
+        // The address space of the other accounts with local routes, which are not learned from peers
+        let other_accounts: Vec<Bytes> = self
+            .local_table
+            .read()
+            .routes()
+            .filter(|(account, route)| route.path.is_empty() && account.id() != from.id())
+            .map(|(account, _)| Bytes::from(account.client_address()))
+            .collect();
+        let from_address = from.client_address();
         update.new_routes = update
             .new_routes
             .into_iter()
             .filter(|route| {
-                if !route.prefix.starts_with(&self.global_prefix) {
+                if !route.prefix.starts_with(&global_prefix) {
                     warn!("Got route for a different global prefix: {:?}", route);
                     false
-                } else if route.prefix.len() <= self.global_prefix.len() {
+                } else if route.prefix.len() <= global_prefix.len() {
                     warn!("Got route broadcast for the global prefix: {:?}", route);
                     false
-                } else if route.path.contains(&self.ilp_address) {
+                } else if Address::try_from(route.prefix.clone()).is_err() {
+                    warn!("Got route with an invalid prefix: {:?}", route);
+                    false
+                } else if route.path.contains(&ilp_address) {
                     error!(
                         "Got route broadcast with a routing loop (path includes us): {:?}",
                         route
                     );
                     false
+                } else if is_within_prefix(&route.prefix, &ilp_address)
+                    && !is_within_prefix(&route.prefix, from_address)
+                {
+                    warn!(
+                        "Got route from account {} for our own address space: {:?}",
+                        from.id(),
+                        route
+                    );
+                    false
+                } else if from.routing_relation() == RoutingRelation::Child
+                    && !is_within_prefix(&route.prefix, from_address)
+                {
+                    warn!(
+                        "Got route from child account {} outside of its address space: {:?}",
+                        from.id(),
+                        route
+                    );
+                    false
+                } else if let Some(other) = other_accounts.iter().find(|other| {
+                    is_within_prefix(&route.prefix, other)
+                        && !is_within_prefix(&route.prefix, from_address)
+                        && !route.path.contains(other)
+                }) {
+                    warn!(
+                        "Got route from account {} for the address space of {} that does not go through it: {:?}",
+                        from.id(),
+                        str::from_utf8(&other[..]).unwrap_or("<not utf8>"),
+                        route
+                    );
+                    false
                 } else {
                     true
                 }
@@ -256,7 +390,7 @@ where
             return Box::new(err(RejectBuilder {
                 code: ErrorCode::F00_BAD_REQUEST,
                 message: b"Your route broadcasts are not accepted here",
-                triggered_by: &self.ilp_address[..],
+                triggered_by: &self.ilp_address()[..],
                 data: &[],
             }
             .build()));
@@ -267,7 +401,7 @@ where
             return Box::new(err(RejectBuilder {
                 code: ErrorCode::F00_BAD_REQUEST,
                 message: b"Invalid route update request",
-                triggered_by: &self.ilp_address[..],
+                triggered_by: &self.ilp_address()[..],
                 data: &[],
             }
             .build()));
@@ -279,7 +413,7 @@ where
             update
         );
 
-        let update = self.filter_routes(update);
+        let update = self.filter_routes(&request.from, update);
 
         let mut incoming_tables = self.incoming_tables.write();
         if !&incoming_tables.contains_key(&request.from.id()) {
@@ -288,7 +422,7 @@ where
                 RoutingTable::new(update.routing_table_id),
             );
         }
-        let ilp_address = self.ilp_address.clone();
+        let ilp_address = self.ilp_address();
         match (*incoming_tables)
             .get_mut(&request.from.id())
             .expect("Should have inserted a routing table for this account")
@@ -300,7 +434,7 @@ where
                     spawn(future);
                     Box::new(ok(CCP_RESPONSE.clone()))
                 } else {
-                    let ilp_address = self.ilp_address.clone();
+                    let ilp_address = self.ilp_address();
                     Box::new(
                         future
                             .map_err(move |_| {
@@ -342,6 +476,9 @@ where
 
     /// Request a Route Update from the specified peer. This is sent when we get
     /// a Route Update Request from them with a gap in the epochs since the last one we saw.
+    ///
+    /// If a request to the peer is already waiting for a response, no other one is sent
+    /// and the returned future resolves when that one does.
     fn send_route_control_request(
         &self,
         to: A,
@@ -349,31 +486,51 @@ where
         last_known_epoch: u32,
     ) -> impl Future<Item = (), Error = ()> {
         let to_id = to.id();
-        let control = RouteControlRequest {
-            mode: Mode::Sync,
-            last_known_routing_table_id,
-            last_known_epoch,
-            features: Vec::new(),
+        let mut route_control_requests = self.route_control_requests.lock();
+        let request = if let Some(request) = route_control_requests.get(&to_id) {
+            debug!(
+                "Already waiting for a response to the Route Control Request sent to account: {}",
+                to_id
+            );
+            request.clone()
+        } else {
+            let control = RouteControlRequest {
+                mode: Mode::Sync,
+                last_known_routing_table_id,
+                last_known_epoch,
+                features: Vec::new(),
+            };
+            debug!("Sending Route Control Request to account: {}, last known table id: {}, last known epoch: {}", to_id, hex::encode(&last_known_routing_table_id[..]), last_known_epoch);
+            let prepare = control.to_prepare();
+            let in_flight = self.route_control_requests.clone();
+            let request: RouteControlFuture = Box::new(
+                self.clone()
+                    .outgoing
+                    .send_request(OutgoingRequest {
+                        from: self.account.clone(),
+                        to,
+                        prepare,
+                    })
+                    .then(move |result| {
+                        in_flight.lock().remove(&to_id);
+                        result
+                    })
+                    .map_err(move |reject| {
+                        error!(
+                            "Error sending Route Control Request to account {}: {:?}",
+                            to_id, reject
+                        )
+                    })
+                    .and_then(move |_| {
+                        trace!("Sent Route Control Request to account: {}", to_id);
+                        Ok(())
+                    }),
+            );
+            let request = request.shared();
+            route_control_requests.insert(to_id, request.clone());
+            request
         };
-        debug!("Sending Route Control Request to account: {}, last known table id: {}, last known epoch: {}", to.id(), hex::encode(&last_known_routing_table_id[..]), last_known_epoch);
-        let prepare = control.to_prepare();
-        self.clone()
-            .outgoing
-            .send_request(OutgoingRequest {
-                from: self.account.clone(),
-                to,
-                prepare,
-            })
-            .map_err(move |reject| {
-                error!(
-                    "Error sending Route Control Request to account {}: {:?}",
-                    to_id, reject
-                )
-            })
-            .and_then(move |_| {
-                trace!("Sent Route Control Request to account: {}", to_id);
-                Ok(())
-            })
+        request.then(|result| result.map(|_| ()).map_err(|_| ()))
     }
 
     /// Check whether the Local Routing Table currently has the best routes for the
@@ -389,13 +546,16 @@ where
         let forwarding_table = self.forwarding_table.clone();
         let forwarding_table_updates = self.forwarding_table_updates.clone();
         let incoming_tables = self.incoming_tables.clone();
-        let ilp_address = self.ilp_address.clone();
-        let global_prefix = self.global_prefix.clone();
+        let saved_route_hints = self.route_hints.clone();
+        let aggregation_threshold = self.aggregation_threshold;
+        let advertised = self.advertised_routes.clone();
+        let ilp_address = self.ilp_address();
+        let global_prefix = self.global_prefix();
         let mut store = self.store.clone();
 
         self.store.get_local_and_configured_routes().and_then(
             move |(ref local_routes, ref configured_routes)| {
-                let (better_routes, withdrawn_routes) = {
+                let (better_routes, withdrawn_routes, route_hints) = {
                     // Note we only use a read lock here and later get a write lock if we need to update the table
                     let local_table = local_table.read();
                     let incoming_tables = incoming_tables.read();
@@ -434,7 +594,22 @@ where
                             withdrawn_routes.push(prefix);
                         }
                     }
-                    (better_routes, withdrawn_routes)
+
+                    // Only save the hints if they changed since the last time
+                    let route_hints = get_route_hints(local_routes, configured_routes, &incoming_tables);
+                    let route_hints = if *saved_route_hints.read() != route_hints {
+                        Some(route_hints)
+                    } else {
+                        None
+                    };
+                    (better_routes, withdrawn_routes, route_hints)
+                };
+
+                let save_route_hints = if let Some(route_hints) = route_hints {
+                    *saved_route_hints.write() = route_hints.clone();
+                    Either::A(store.set_route_hints(route_hints))
+                } else {
+                    Either::B(ok(()))
                 };
 
                 // Update the local and forwarding tables
@@ -481,13 +656,24 @@ where
                         forwarding_table.delete_route(prefix);
                     }
 
+                    // With aggregation, peers are sent the changes to the summarized routes instead
+                    let (new_routes, withdrawn_routes) = if let Some(threshold) = aggregation_threshold {
+                        let after = advertised_routes(&forwarding_table, threshold, &global_prefix);
+                        let mut advertised = advertised.write();
+                        let changes = diff_routes(&advertised, after.clone());
+                        *advertised = after;
+                        changes
+                    } else {
+                        (new_routes, withdrawn_routes)
+                    };
+
                     let epoch = forwarding_table.increment_epoch();
                     forwarding_table_updates.insert(epoch, (new_routes, withdrawn_routes));
 
-                    Either::A(store.set_routes(local_table.get_simplified_table()))
+                    Either::A(store.set_routes(local_table.get_simplified_table()).join(save_route_hints).map(|_| ()))
                 } else {
                     // The routing table hasn't changed
-                    Either::B(ok(()))
+                    Either::B(save_route_hints)
                 }
             },
         )
@@ -617,7 +803,7 @@ where
             current_epoch_index,
             new_routes: new_routes.clone(),
             withdrawn_routes: withdrawn_routes.clone(),
-            speaker: self.ilp_address.clone(),
+            speaker: self.ilp_address(),
             hold_down_time: DEFAULT_ROUTE_EXPIRY_TIME,
         }
     }
@@ -654,6 +840,28 @@ where
     }
 }
 
+/// The routes in the forwarding table, with sibling prefixes summarized into their parent prefix
+fn advertised_routes<A: CcpRoutingAccount>(
+    forwarding_table: &RoutingTable<A>,
+    threshold: usize,
+    global_prefix: &[u8],
+) -> Vec<Route> {
+    let routes = forwarding_table
+        .routes()
+        .map(|(account, route)| (account.id(), route.clone()))
+        .collect();
+    aggregate_routes(routes, threshold, global_prefix)
+}
+
+// The global prefix is the first part of the address (for example "g." for the global address space, "example", "test", etc)
+fn global_prefix(ilp_address: &Bytes) -> Bytes {
+    ilp_address
+        .iter()
+        .position(|c| c == &b'.')
+        .map(|index| ilp_address.slice_to(index + 1))
+        .unwrap_or_else(|| ilp_address.clone())
+}
+
 fn get_best_route_for_prefix<A: CcpRoutingAccount>(
     local_routes: &HashMap<Bytes, A>,
     configured_routes: &HashMap<Bytes, A>,
@@ -673,13 +881,19 @@ fn get_best_route_for_prefix<A: CcpRoutingAccount>(
         ));
     }
     if let Some(account) = local_routes.get(prefix) {
+        // Packets to our own accounts arrive in their asset, so we advertise a rate of 1
+        let hint = PathHint {
+            asset_code: account.asset_code().to_string(),
+            rate: 1.0,
+            max_amount: None,
+        };
         return Some((
             account.clone(),
             Route {
                 prefix: Bytes::from(account.client_address()),
                 auth: [0; 32],
                 path: Vec::new(),
-                props: Vec::new(),
+                props: vec![hint.to_prop()],
             },
         ));
     }
@@ -719,6 +933,38 @@ fn get_best_route_for_prefix<A: CcpRoutingAccount>(
     }
 }
 
+/// Collect the price and liquidity hints from the routes our peers advertised to us.
+/// Prefixes we have configured or local routes for are left out because those routes always take precedence
+fn get_route_hints<A: CcpRoutingAccount>(
+    local_routes: &HashMap<Bytes, A>,
+    configured_routes: &HashMap<Bytes, A>,
+    incoming_tables: &HashMap<A::AccountId, RoutingTable<A>>,
+) -> HashMap<Bytes, Vec<RouteHint<A::AccountId>>> {
+    let mut route_hints: HashMap<Bytes, Vec<RouteHint<A::AccountId>>> = HashMap::new();
+    for (account, route) in incoming_tables.values().flat_map(|table| table.routes()) {
+        if configured_routes.contains_key(&route.prefix) || local_routes.contains_key(&route.prefix)
+        {
+            continue;
+        }
+        if let Some(hint) = route.path_hint() {
+            route_hints
+                .entry(route.prefix.clone())
+                .or_insert_with(Vec::new)
+                .push(RouteHint {
+                    account_id: account.id(),
+                    asset_code: hint.asset_code,
+                    rate: hint.rate,
+                    max_amount: hint.max_amount,
+                });
+        }
+    }
+    // Sort the hints so they can be compared with the ones saved before
+    for hints in route_hints.values_mut() {
+        hints.sort_unstable_by_key(|hint| hint.account_id.to_string());
+    }
+    route_hints
+}
+
 impl<S, T, U, A> IncomingService<A> for CcpRouteManager<S, T, U, A>
 where
     S: IncomingService<A> + Clone + Send + Sync + 'static,
@@ -851,6 +1097,49 @@ mod ranking_routes {
         let best_route = get_best_route_for_prefix(&LOCAL, &CONFIGURED, &INCOMING, b"example.z");
         assert!(best_route.is_none());
     }
+
+    #[test]
+    fn advertises_path_hint_for_local_routes() {
+        let (_account, route) =
+            get_best_route_for_prefix(&LOCAL, &CONFIGURED, &INCOMING, b"example.c").unwrap();
+        let hint = route.path_hint().unwrap();
+        assert_eq!(hint.asset_code, "XYZ");
+        assert_eq!(hint.rate, 1.0);
+    }
+
+    #[test]
+    fn collects_route_hints_from_incoming_tables() {
+        let hint = PathHint {
+            asset_code: "ABC".to_string(),
+            rate: 2.5,
+            max_amount: None,
+        };
+        let mut peer_table = RoutingTable::default();
+        for prefix in &["example.f", "example.a"] {
+            peer_table.add_route(
+                TestAccount::new(9, "example.peer3"),
+                Route {
+                    prefix: Bytes::from(*prefix),
+                    path: vec![Bytes::from("example.one")],
+                    auth: [0; 32],
+                    props: vec![hint.to_prop()],
+                },
+            );
+        }
+        let incoming = HashMap::from_iter(vec![(9, peer_table)]);
+
+        let route_hints = get_route_hints(&LOCAL, &CONFIGURED, &incoming);
+        assert_eq!(route_hints.len(), 1);
+        assert_eq!(
+            route_hints[&Bytes::from("example.f")],
+            vec![RouteHint {
+                account_id: 9,
+                asset_code: "ABC".to_string(),
+                rate: 2.5,
+                max_amount: None,
+            }]
+        );
+    }
 }
 
 #[cfg(test)]
@@ -1062,7 +1351,7 @@ mod handle_route_update_request {
             auth: [0; 32],
             props: Vec::new(),
         });
-        let request = service.filter_routes(request);
+        let request = service.filter_routes(&ROUTING_ACCOUNT, request);
         assert_eq!(request.new_routes.len(), 1);
         assert_eq!(request.new_routes[0].prefix, Bytes::from("example.valid"));
     }
@@ -1083,7 +1372,28 @@ mod handle_route_update_request {
             auth: [0; 32],
             props: Vec::new(),
         });
-        let request = service.filter_routes(request);
+        let request = service.filter_routes(&ROUTING_ACCOUNT, request);
+        assert_eq!(request.new_routes.len(), 1);
+        assert_eq!(request.new_routes[0].prefix, Bytes::from("example.valid"));
+    }
+
+    #[test]
+    fn filters_routes_with_invalid_prefix() {
+        let service = test_service();
+        let mut request = UPDATE_REQUEST_SIMPLE.clone();
+        request.new_routes.push(Route {
+            prefix: Bytes::from("example.valid"),
+            path: Vec::new(),
+            auth: [0; 32],
+            props: Vec::new(),
+        });
+        request.new_routes.push(Route {
+            prefix: Bytes::from("example.in valid"),
+            path: Vec::new(),
+            auth: [0; 32],
+            props: Vec::new(),
+        });
+        let request = service.filter_routes(&ROUTING_ACCOUNT, request);
         assert_eq!(request.new_routes.len(), 1);
         assert_eq!(request.new_routes[0].prefix, Bytes::from("example.valid"));
     }
@@ -1096,7 +1406,7 @@ mod handle_route_update_request {
             prefix: Bytes::from("example.valid"),
             path: vec![
                 Bytes::from("example.a"),
-                service.ilp_address.clone(),
+                service.ilp_address(),
                 Bytes::from("example.b"),
             ],
             auth: [0; 32],
@@ -1108,11 +1418,73 @@ mod handle_route_update_request {
             auth: [0; 32],
             props: Vec::new(),
         });
-        let request = service.filter_routes(request);
+        let request = service.filter_routes(&ROUTING_ACCOUNT, request);
         assert_eq!(request.new_routes.len(), 1);
         assert_eq!(request.new_routes[0].prefix, Bytes::from("example.valid"));
     }
 
+    #[test]
+    fn filters_routes_outside_child_address_space() {
+        let service = test_service();
+        let child = TestAccount {
+            id: 5,
+            ilp_address: Bytes::from("example.connector.child"),
+            send_routes: true,
+            receive_routes: true,
+            relation: RoutingRelation::Child,
+        };
+        let mut request = UPDATE_REQUEST_SIMPLE.clone();
+        for prefix in &[
+            "example.connector.child.a",
+            "example.connector.other",
+            "example.b",
+        ] {
+            request.new_routes.push(Route {
+                prefix: Bytes::from(*prefix),
+                path: Vec::new(),
+                auth: [0; 32],
+                props: Vec::new(),
+            });
+        }
+        let request = service.filter_routes(&child, request);
+        assert_eq!(request.new_routes.len(), 1);
+        assert_eq!(
+            request.new_routes[0].prefix,
+            Bytes::from("example.connector.child.a")
+        );
+    }
+
+    #[test]
+    fn filters_routes_for_address_space_of_others() {
+        let (service, _outgoing_requests) = test_service_with_routes();
+        service.update_best_routes(None).wait().unwrap();
+        let mut request = UPDATE_REQUEST_SIMPLE.clone();
+        let routes = vec![
+            ("example.connector.x", Vec::new()),
+            ("example.local.1.x", Vec::new()),
+            ("example.local.1.y", vec![Bytes::from("example.local.1")]),
+            ("example.valid", Vec::new()),
+        ];
+        for (prefix, path) in routes {
+            request.new_routes.push(Route {
+                prefix: Bytes::from(prefix),
+                path,
+                auth: [0; 32],
+                props: Vec::new(),
+            });
+        }
+        let request = service.filter_routes(&TestAccount::new(9, "example.peer"), request);
+        let prefixes: Vec<&[u8]> = request
+            .new_routes
+            .iter()
+            .map(|route| route.prefix.as_ref())
+            .collect();
+        assert_eq!(
+            prefixes,
+            vec![&b"example.local.1.y"[..], &b"example.valid"[..]]
+        );
+    }
+
     #[test]
     fn updates_local_routing_table() {
         let mut service = test_service();
@@ -1337,6 +1709,30 @@ mod handle_route_update_request {
         let control = RouteControlRequest::try_from(&request.prepare).unwrap();
         assert_eq!(control.last_known_epoch, 1);
     }
+
+    #[test]
+    fn sends_one_control_request_at_a_time_per_peer() {
+        let (mut service, outgoing_requests) = test_service_with_routes();
+
+        let mut request = UPDATE_REQUEST_COMPLEX.clone();
+        request.to_epoch_index = 8;
+        request.from_epoch_index = 7;
+        let first = service.handle_request(IncomingRequest {
+            from: ROUTING_ACCOUNT.clone(),
+            prepare: request.to_prepare(),
+        });
+        request.to_epoch_index = 9;
+        request.from_epoch_index = 8;
+        let second = service.handle_request(IncomingRequest {
+            from: ROUTING_ACCOUNT.clone(),
+            prepare: request.to_prepare(),
+        });
+        assert_eq!(outgoing_requests.lock().len(), 1);
+
+        assert!(first.wait().is_err());
+        assert!(second.wait().is_err());
+        assert!(service.route_control_requests.lock().is_empty());
+    }
 }
 
 #[cfg(test)]
@@ -1508,6 +1904,54 @@ mod send_route_updates {
         assert!(prefixes.contains(&"example.remote"));
     }
 
+    #[test]
+    fn broadcasts_aggregated_routes() {
+        let (service, outgoing_requests) = test_service_with_routes();
+        let service = service.aggregate_routes(2);
+
+        // This is normally spawned as a task when the service is created
+        service.update_best_routes(None).wait().unwrap();
+
+        let routes = ["example.remote.a", "example.remote.b"]
+            .iter()
+            .map(|prefix| Route {
+                prefix: Bytes::from(*prefix),
+                path: vec![Bytes::from("example.peer")],
+                auth: [0; 32],
+                props: Vec::new(),
+            })
+            .collect();
+        service
+            .handle_route_update_request(IncomingRequest {
+                from: TestAccount::new(10, "example.peer"),
+                prepare: RouteUpdateRequest {
+                    routing_table_id: [0; 16],
+                    current_epoch_index: 1,
+                    from_epoch_index: 0,
+                    to_epoch_index: 1,
+                    hold_down_time: 30000,
+                    speaker: Bytes::from("example.remote"),
+                    new_routes: routes,
+                    withdrawn_routes: Vec::new(),
+                }
+                .to_prepare(),
+            })
+            .wait()
+            .unwrap();
+
+        service.send_route_updates().wait().unwrap();
+        let update = RouteUpdateRequest::try_from(&outgoing_requests.lock()[0].prepare).unwrap();
+        assert_eq!(update.new_routes.len(), 3);
+        let prefixes: Vec<&str> = update
+            .new_routes
+            .iter()
+            .map(|route| str::from_utf8(route.prefix.as_ref()).unwrap())
+            .collect();
+        assert!(prefixes.contains(&"example.local.1"));
+        assert!(prefixes.contains(&"example.configured.1"));
+        assert!(prefixes.contains(&"example.remote"));
+    }
+
     #[test]
     fn broadcasts_withdrawn_routes() {
         let (service, outgoing_requests) = test_service_with_routes();
@@ -1572,4 +2016,26 @@ mod send_route_updates {
             "example.remote"
         );
     }
+
+    #[test]
+    fn readvertises_routes_with_new_address() {
+        let (service, outgoing_requests) = test_service_with_routes();
+
+        // This is normally spawned as a task when the service is created
+        service.update_best_routes(None).wait().unwrap();
+        service.send_route_updates().wait().unwrap();
+
+        service
+            .set_ilp_address(Bytes::from("example.renamed"))
+            .wait()
+            .unwrap();
+        let update =
+            RouteUpdateRequest::try_from(&outgoing_requests.lock().last().unwrap().prepare)
+                .unwrap();
+        assert_eq!(update.speaker, Bytes::from("example.renamed"));
+        assert_eq!(update.new_routes.len(), 2);
+        for route in update.new_routes.iter() {
+            assert_eq!(route.path, vec![Bytes::from("example.renamed")]);
+        }
+    }
 }
@@ -0,0 +1,300 @@
+use super::{
+    serde_helpers::option_secret::parse_secret, ClockConfig, ConfigError, IpNetwork, NodeConfig,
+    ReplicaConfig, SettlementConfig,
+};
+use std::{env, fmt::Display, str::FromStr, time::Duration};
+use url::Url;
+
+const PREFIX: &str = "ILP_";
+
+impl NodeConfig {
+    /// Override settings with the `ILP_`-prefixed environment variables of the process
+    /// (see `NodeConfig::apply_env_vars`).
+    pub fn with_env_overrides(mut self) -> Result<Self, Vec<ConfigError>> {
+        // Variables that are not valid unicode cannot be settings, so they are skipped
+        let vars = env::vars_os().filter_map(|(name, value)| {
+            Some((name.into_string().ok()?, value.into_string().ok()?))
+        });
+        self.apply_env_vars(vars)?;
+        Ok(self)
+    }
+
+    /// Override settings with the given `ILP_`-prefixed variables, which are named after the
+    /// settings in upper case (for example, `ILP_BTP_ADDRESS`, or `ILP_LEADER_LEASE` for
+    /// `store.leader_lease`). Durations use the same units as config files, lists are
+    /// comma-separated and setting an optional setting's variable to an empty string unsets it.
+    /// Variables that do not correspond to a setting are ignored, and the `tls` settings can
+    /// only be set in config files.
+    ///
+    /// Returns every variable that could not be parsed.
+    pub fn apply_env_vars<I>(&mut self, vars: I) -> Result<(), Vec<ConfigError>>
+    where
+        I: IntoIterator<Item = (String, String)>,
+    {
+        // The replica's staleness bound is applied after its URI, which may set up the replica
+        let (staleness, others): (Vec<_>, Vec<_>) = vars
+            .into_iter()
+            .filter(|(name, _)| name.starts_with(PREFIX))
+            .partition(|(name, _)| name == "ILP_REPLICA_MAX_STALENESS");
+        let mut errors = Vec::new();
+        for (name, value) in others.into_iter().chain(staleness) {
+            if let Err(error) = self.apply_env_var(&name, &name[PREFIX.len()..], &value) {
+                errors.push(error);
+            }
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    fn apply_env_var(&mut self, name: &str, setting: &str, value: &str) -> Result<(), ConfigError> {
+        match setting {
+            "REDIS_URI" => self.redis_uri = parse(name, value)?,
+            "REDIS_REPLICA_URI" => {
+                let uri: Option<Url> = optional(value, |value| parse(name, value))?;
+                self.read_replica = uri.map(|uri| match self.read_replica.take() {
+                    Some(replica) => ReplicaConfig { uri, ..replica },
+                    None => ReplicaConfig::new(uri),
+                });
+            }
+            "REPLICA_MAX_STALENESS" => {
+                let max_staleness = millis(name, value)?;
+                match self.read_replica {
+                    Some(ref mut replica) => replica.max_staleness = max_staleness,
+                    None => {
+                        return Err(ConfigError::new(
+                            name,
+                            format!("{} requires a replica to be configured", name),
+                        ))
+                    }
+                }
+            }
+            "SERVER_SECRET" => {
+                self.server_secret = optional(value, |value| {
+                    parse_secret(value).map_err(|message| ConfigError::new(name, message))
+                })?
+            }
+            "BTP_ADDRESS" => self.btp_address = parse(name, value)?,
+            "HTTP_ADDRESS" => self.http_address = optional(value, |value| parse(name, value))?,
+            "ALLOWED_ASSET_CODES" => {
+                self.constraints.allowed_asset_codes = optional(value, |value| {
+                    Ok(value
+                        .split(',')
+                        .map(|code| code.trim().to_string())
+                        .collect())
+                })?
+            }
+            "MAX_ASSET_SCALE" => {
+                self.constraints.max_asset_scale = optional(value, |value| parse(name, value))?
+            }
+            "CHILD_ADDRESS_PREFIX" => {
+                self.constraints.child_address_prefix =
+                    optional(value, |value| Ok(value.to_string()))?
+            }
+            "ALLOWED_ENDPOINT_NETWORKS" => {
+                self.constraints.allowed_endpoint_networks =
+                    optional(value, |value| networks(name, value))?
+            }
+            "DENIED_ENDPOINT_NETWORKS" => {
+                self.constraints.denied_endpoint_networks =
+                    optional(value, |value| networks(name, value))?.unwrap_or_default()
+            }
+            "BALANCE_SNAPSHOT_INTERVAL" => {
+                self.balance_snapshot_interval = Duration::from_secs(parse(name, value)?)
+            }
+            "PUBLIC_URL" => self.public_url = optional(value, |value| parse(name, value))?,
+            "PAYMENT_WEBHOOK" => {
+                self.payment_webhook = optional(value, |value| parse(name, value))?
+            }
+            "SCHEDULED_PAYMENT_WEBHOOK" => {
+                self.scheduled_payment_webhook = optional(value, |value| parse(name, value))?
+            }
+            "PAYMENT_LINK_WEBHOOK" => {
+                self.payment_link_webhook = optional(value, |value| parse(name, value))?
+            }
+            "SETTLEMENT_ENGINE_URL" => {
+                self.settlement
+                    .get_or_insert_with(SettlementConfig::default)
+                    .engine_url = optional(value, |value| parse(name, value))?
+            }
+            "SETTLEMENT_LEDGER" => {
+                self.settlement
+                    .get_or_insert_with(SettlementConfig::default)
+                    .ledger = optional(value, |value| Ok(value.to_string()))?
+            }
+            "SETTLEMENT_LEDGER_ADDRESS" => {
+                self.settlement
+                    .get_or_insert_with(SettlementConfig::default)
+                    .ledger_address = optional(value, |value| Ok(value.to_string()))?
+            }
+            "MIN_INCOMING_PACKET_AMOUNT" => self.min_incoming_packet_amount = parse(name, value)?,
+            "MAX_IN_FLIGHT_PACKETS" => self.max_in_flight_packets = parse(name, value)?,
+            "PACKET_SHARDS" => self.packet_shards = parse(name, value)?,
+            "SELF_TEST_AMOUNT" => {
+                self.self_test_amount = optional(value, |value| parse(name, value))?
+            }
+            "ACCEPT_PEERING_REQUESTS" => self.accept_peering_requests = parse(name, value)?,
+            "READ_ONLY" => self.read_only = parse(name, value)?,
+            "ROUTE_AGGREGATION_THRESHOLD" => {
+                self.route_aggregation_threshold = optional(value, |value| parse(name, value))?
+            }
+            "ROUTE_POLL_INTERVAL" => {
+                self.store.route_poll_interval = optional(value, |value| millis(name, value))?
+            }
+            "RATE_POLL_INTERVAL" => {
+                self.store.rate_poll_interval = optional(value, |value| millis(name, value))?
+            }
+            "POLL_JITTER" => self.store.poll_jitter = millis(name, value)?,
+            "GC_INTERVAL" => self.store.gc_interval = optional(value, |value| millis(name, value))?,
+            "LEADER_LEASE" => {
+                self.store.leader_lease = optional(value, |value| millis(name, value))?
+            }
+            // Any of the clock settings turns the clock checks on. An empty NTP server turns them off
+            "NTP_SERVER" => {
+                let server = optional(value, |value| Ok(value.trim().to_string()))?;
+                self.clock = server.map(|ntp_server| ClockConfig {
+                    ntp_server,
+                    ..self.clock.take().unwrap_or_default()
+                });
+            }
+            "CLOCK_CHECK_INTERVAL" => {
+                self.clock
+                    .get_or_insert_with(ClockConfig::default)
+                    .check_interval = millis(name, value)?
+            }
+            "MAX_CLOCK_SKEW" => {
+                self.clock.get_or_insert_with(ClockConfig::default).max_skew = millis(name, value)?
+            }
+            "COMPENSATE_CLOCK_SKEW" => {
+                self.clock
+                    .get_or_insert_with(ClockConfig::default)
+                    .compensate = parse(name, value)?
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+fn parse<T>(name: &str, value: &str) -> Result<T, ConfigError>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    value
+        .trim()
+        .parse()
+        .map_err(|err| ConfigError::new(name, format!("Invalid value for {}: {}", name, err)))
+}
+
+fn millis(name: &str, value: &str) -> Result<Duration, ConfigError> {
+    parse(name, value).map(Duration::from_millis)
+}
+
+// A comma-separated list of CIDR ranges
+fn networks(name: &str, value: &str) -> Result<Vec<IpNetwork>, ConfigError> {
+    value
+        .split(',')
+        .map(|network| parse(name, network))
+        .collect()
+}
+
+// An empty value unsets an optional setting
+fn optional<T, F>(value: &str, parse: F) -> Result<Option<T>, ConfigError>
+where
+    F: FnOnce(&str) -> Result<T, ConfigError>,
+{
+    if value.trim().is_empty() {
+        Ok(None)
+    } else {
+        parse(value).map(Some)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+        vars.iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn overrides_settings() {
+        let mut config = NodeConfig::default();
+        config
+            .apply_env_vars(vars(&[
+                ("ILP_HTTP_ADDRESS", "0.0.0.0:7770"),
+                ("ILP_REPLICA_MAX_STALENESS", "500"),
+                ("ILP_REDIS_REPLICA_URI", "redis://replica:6379"),
+                ("ILP_ALLOWED_ASSET_CODES", "USD, EUR"),
+                ("ILP_DENIED_ENDPOINT_NETWORKS", "10.0.0.0/8, fd00::/8"),
+                ("ILP_LEADER_LEASE", "3000"),
+                ("ILP_GC_INTERVAL", ""),
+                ("ILP_SELF_TEST_AMOUNT", "10"),
+                ("ILP_ACCEPT_PEERING_REQUESTS", "true"),
+                ("ILP_READ_ONLY", "true"),
+                ("ILP_ROUTE_AGGREGATION_THRESHOLD", "8"),
+                ("ILP_PACKET_SHARDS", "4"),
+                ("ILP_NTP_SERVER", "time.example:123"),
+                ("ILP_COMPENSATE_CLOCK_SKEW", "true"),
+                ("ILP_SCHEDULED_PAYMENT_WEBHOOK", "http://hooks/scheduled"),
+                ("ILP_PAYMENT_LINK_WEBHOOK", "http://hooks/links"),
+                ("ILP_BTP_URI", "btp+ws://localhost:7768"),
+                ("PATH", "/usr/bin"),
+            ]))
+            .unwrap();
+        assert_eq!(config.http_address, Some(([0, 0, 0, 0], 7770).into()));
+        let replica = config.read_replica.unwrap();
+        assert_eq!(replica.uri.as_str(), "redis://replica:6379/");
+        assert_eq!(replica.max_staleness, Duration::from_millis(500));
+        assert_eq!(
+            config.constraints.allowed_asset_codes,
+            Some(vec!["USD".to_string(), "EUR".to_string()])
+        );
+        assert_eq!(
+            config.constraints.denied_endpoint_networks,
+            vec![
+                "10.0.0.0/8".parse::<IpNetwork>().unwrap(),
+                "fd00::/8".parse().unwrap()
+            ]
+        );
+        assert_eq!(config.store.leader_lease, Some(Duration::from_secs(3)));
+        assert_eq!(config.store.gc_interval, None);
+        assert_eq!(config.self_test_amount, Some(10));
+        assert!(config.accept_peering_requests);
+        assert!(config.read_only);
+        assert_eq!(config.route_aggregation_threshold, Some(8));
+        assert_eq!(config.packet_shards, 4);
+        let clock = config.clock.unwrap();
+        assert_eq!(clock.ntp_server, "time.example:123");
+        assert!(clock.compensate);
+        assert_eq!(
+            config.scheduled_payment_webhook,
+            Some(Url::parse("http://hooks/scheduled").unwrap())
+        );
+        assert_eq!(
+            config.payment_link_webhook,
+            Some(Url::parse("http://hooks/links").unwrap())
+        );
+    }
+
+    #[test]
+    fn reports_every_invalid_variable() {
+        let mut config = NodeConfig::default();
+        let errors = config
+            .apply_env_vars(vars(&[
+                ("ILP_BTP_ADDRESS", "localhost"),
+                ("ILP_MIN_INCOMING_PACKET_AMOUNT", "100"),
+                ("ILP_SERVER_SECRET", "not hex"),
+            ]))
+            .unwrap_err();
+        let fields: Vec<String> = errors.into_iter().map(|error| error.field).collect();
+        assert_eq!(fields, vec!["ILP_BTP_ADDRESS", "ILP_SERVER_SECRET"]);
+        assert_eq!(config.min_incoming_packet_amount, 100);
+    }
+}
@@ -0,0 +1,46 @@
+//! # interledger-config
+//!
+//! Strongly typed configuration for an Interledger node, shared by the node binary,
+//! the CLI and the embedded `NodeBuilder` so that they all use the same defaults,
+//! units and validation rules.
+//!
+//! A `NodeConfig` can be loaded from a JSON file, in which any setting that is left out
+//! takes its default value, and each setting can be overridden with an `ILP_`-prefixed
+//! environment variable (see `NodeConfig::apply_env_vars`).
+
+mod env;
+mod network;
+mod node;
+mod serde_helpers;
+mod store;
+mod tls;
+
+pub use self::network::{forbidden_networks, loopback_networks, IpNetwork};
+pub use self::node::{ClockConfig, ConstraintsConfig, NodeConfig, SettlementConfig};
+pub use self::store::{ReplicaConfig, StoreConfig};
+pub use self::tls::{AcmeConfig, CertificateConfig, TlsConfig};
+
+use std::fmt;
+
+/// A problem with a setting, either because it could not be parsed or because its value is invalid.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigError {
+    /// The name of the setting, or of the environment variable it was read from
+    pub field: String,
+    pub message: String,
+}
+
+impl ConfigError {
+    pub(crate) fn new(field: &str, message: impl Into<String>) -> Self {
+        ConfigError {
+            field: field.to_string(),
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
@@ -0,0 +1,191 @@
+use serde::{de::Error as _, Deserialize, Deserializer, Serialize, Serializer};
+use std::{
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    str::FromStr,
+};
+
+/// A range of IP addresses in CIDR notation, such as `10.0.0.0/8` or `fd00::/8`.
+/// A single address can be given without a prefix length.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpNetwork {
+    address: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpNetwork {
+    pub fn new(address: IpAddr, prefix_len: u8) -> Result<Self, String> {
+        let max_len = match address {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        if prefix_len > max_len {
+            return Err(format!(
+                "The prefix length of {} must be at most {}",
+                address, max_len
+            ));
+        }
+        Ok(IpNetwork {
+            address,
+            prefix_len,
+        })
+    }
+
+    /// Whether the address is in this network. IPv4 addresses mapped to IPv6
+    /// (`::ffff:a.b.c.d`) are treated as IPv4 addresses.
+    pub fn contains(&self, address: IpAddr) -> bool {
+        match (self.address, unmap(address)) {
+            (IpAddr::V4(network), IpAddr::V4(address)) => {
+                prefix_matches(&network.octets(), &address.octets(), self.prefix_len)
+            }
+            (IpAddr::V6(network), IpAddr::V6(address)) => {
+                prefix_matches(&network.octets(), &address.octets(), self.prefix_len)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn unmap(address: IpAddr) -> IpAddr {
+    match address {
+        IpAddr::V6(v6) => match v6.segments() {
+            [0, 0, 0, 0, 0, 0xffff, high, low] => IpAddr::V4(Ipv4Addr::new(
+                (high >> 8) as u8,
+                high as u8,
+                (low >> 8) as u8,
+                low as u8,
+            )),
+            _ => IpAddr::V6(v6),
+        },
+        v4 => v4,
+    }
+}
+
+fn prefix_matches(network: &[u8], address: &[u8], prefix_len: u8) -> bool {
+    let full_bytes = (prefix_len / 8) as usize;
+    let remaining_bits = prefix_len % 8;
+    if network[..full_bytes] != address[..full_bytes] {
+        return false;
+    }
+    if remaining_bits == 0 {
+        return true;
+    }
+    let mask = 0xffu8 << (8 - remaining_bits);
+    network[full_bytes] & mask == address[full_bytes] & mask
+}
+
+impl FromStr for IpNetwork {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(2, '/');
+        let address: IpAddr = parts
+            .next()
+            .unwrap_or("")
+            .parse()
+            .map_err(|_| format!("{} is not an IP address or CIDR range", s))?;
+        let prefix_len = match parts.next() {
+            Some(len) => len
+                .parse()
+                .map_err(|_| format!("{} has an invalid prefix length", s))?,
+            None if address.is_ipv4() => 32,
+            None => 128,
+        };
+        IpNetwork::new(address, prefix_len)
+    }
+}
+
+impl fmt::Display for IpNetwork {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.address, self.prefix_len)
+    }
+}
+
+impl Serialize for IpNetwork {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for IpNetwork {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(D::Error::custom)
+    }
+}
+
+/// Networks that outgoing connections are never made to, whatever else is allowed:
+/// link-local addresses, which include the metadata services of cloud providers,
+/// and the unspecified addresses, which connect to the local host.
+pub fn forbidden_networks() -> Vec<IpNetwork> {
+    vec![
+        IpNetwork {
+            address: IpAddr::V4(Ipv4Addr::new(169, 254, 0, 0)),
+            prefix_len: 16,
+        },
+        IpNetwork {
+            address: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            prefix_len: 32,
+        },
+        IpNetwork {
+            address: IpAddr::V6(Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 0)),
+            prefix_len: 10,
+        },
+        // The AWS metadata service's IPv6 address
+        IpNetwork {
+            address: IpAddr::V6(Ipv6Addr::new(0xfd00, 0xec2, 0, 0, 0, 0, 0, 0x254)),
+            prefix_len: 128,
+        },
+        IpNetwork {
+            address: IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+            prefix_len: 128,
+        },
+    ]
+}
+
+/// The loopback networks, which connect to services on the node's own host.
+pub fn loopback_networks() -> Vec<IpNetwork> {
+    vec![
+        IpNetwork {
+            address: IpAddr::V4(Ipv4Addr::new(127, 0, 0, 0)),
+            prefix_len: 8,
+        },
+        IpNetwork {
+            address: IpAddr::V6(Ipv6Addr::LOCALHOST),
+            prefix_len: 128,
+        },
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_ranges_and_addresses() {
+        let network: IpNetwork = "10.0.0.0/8".parse().unwrap();
+        assert!(network.contains("10.1.2.3".parse().unwrap()));
+        assert!(!network.contains("11.0.0.1".parse().unwrap()));
+        assert!(network.contains("::ffff:10.0.0.1".parse().unwrap()));
+        let single: IpNetwork = "fd00::1".parse().unwrap();
+        assert_eq!(single.to_string(), "fd00::1/128");
+        assert!(single.contains("fd00::1".parse().unwrap()));
+        assert!(!single.contains("fd00::2".parse().unwrap()));
+        assert!("10.0.0.0/33".parse::<IpNetwork>().is_err());
+        assert!("example.com/8".parse::<IpNetwork>().is_err());
+    }
+
+    #[test]
+    fn matches_partial_bytes() {
+        let network: IpNetwork = "172.16.0.0/12".parse().unwrap();
+        assert!(network.contains("172.31.255.255".parse().unwrap()));
+        assert!(!network.contains("172.32.0.0".parse().unwrap()));
+        assert!(forbidden_networks()
+            .iter()
+            .any(|network| network.contains("169.254.169.254".parse().unwrap())));
+        assert!(loopback_networks()
+            .iter()
+            .any(|network| network.contains("::ffff:127.0.0.1".parse().unwrap())));
+    }
+}
@@ -0,0 +1,470 @@
+use super::{serde_helpers::*, ConfigError, IpNetwork, ReplicaConfig, StoreConfig, TlsConfig};
+use interledger_packet::{Address, ParseError};
+use serde::{Deserialize, Serialize};
+use std::{fs, net::SocketAddr, path::Path, time::Duration};
+use url::Url;
+
+/// Node-wide rules that every account and route must follow.
+/// See `interledger_api::NodeConstraints`, which these are turned into.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ConstraintsConfig {
+    /// If set, only accounts denominated in one of these assets can be created
+    pub allowed_asset_codes: Option<Vec<String>>,
+    /// If set, accounts cannot have a larger asset scale than this
+    pub max_asset_scale: Option<u8>,
+    /// If set, the addresses of child accounts must start with this prefix
+    pub child_address_prefix: Option<String>,
+    /// If set, accounts' HTTP and BTP URLs must only resolve to addresses in these networks.
+    /// Otherwise, they can resolve to any address except loopback ones
+    pub allowed_endpoint_networks: Option<Vec<IpNetwork>>,
+    /// Accounts' HTTP and BTP URLs must not resolve to addresses in these networks.
+    /// Link-local addresses, including cloud metadata services, are always denied
+    pub denied_endpoint_networks: Vec<IpNetwork>,
+}
+
+/// The settlement details the node sends its peers in `peer.settle` messages, which peers
+/// answer with theirs. The thresholds the node settles each account at are added to them.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SettlementConfig {
+    /// Where peers' settlement engines can reach ours
+    #[serde(with = "option_url_str")]
+    pub engine_url: Option<Url>,
+    /// The ledger the node settles on, such as "XRP"
+    pub ledger: Option<String>,
+    /// The node's address on that ledger
+    pub ledger_address: Option<String>,
+    /// How often to send the details to peers and parents again, so changes reach them
+    #[serde(with = "millis")]
+    pub refresh_interval: Duration,
+}
+
+impl Default for SettlementConfig {
+    fn default() -> Self {
+        SettlementConfig {
+            engine_url: None,
+            ledger: None,
+            ledger_address: None,
+            refresh_interval: Duration::from_secs(60 * 60),
+        }
+    }
+}
+
+/// How the node checks its clock, which packets' expiries are checked against.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ClockConfig {
+    /// The NTP server to compare the clock with, as a host and port
+    pub ntp_server: String,
+    /// How often to check the clock again after the node starts
+    #[serde(with = "millis")]
+    pub check_interval: Duration,
+    /// Warn if the clock is off by more than this
+    #[serde(with = "millis")]
+    pub max_skew: Duration,
+    /// Correct for the measured skew when checking packets' expiries,
+    /// if the node's clock cannot be synchronized
+    pub compensate: bool,
+}
+
+impl Default for ClockConfig {
+    fn default() -> Self {
+        ClockConfig {
+            ntp_server: "pool.ntp.org:123".to_string(),
+            check_interval: Duration::from_secs(60 * 60),
+            max_skew: Duration::from_secs(1),
+            compensate: false,
+        }
+    }
+}
+
+/// The configuration of a node that uses the Redis store.
+///
+/// In config files, `server_secret` is hex-encoded, `balance_snapshot_interval` is a number
+/// of seconds and all of the other durations are numbers of milliseconds.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NodeConfig {
+    #[serde(with = "url_str")]
+    pub redis_uri: Url,
+    /// Look up accounts and poll for routes and rates using this replica of the database
+    pub read_replica: Option<ReplicaConfig>,
+    pub store: StoreConfig,
+    /// Secret used to generate STREAM receiver credentials and API tokens.
+    /// A random one is generated when the node starts if this is not set
+    #[serde(with = "option_secret")]
+    pub server_secret: Option<[u8; 32]>,
+    pub btp_address: SocketAddr,
+    /// Serve the node API (including ILP-over-HTTP) on this address
+    pub http_address: Option<SocketAddr>,
+    /// Serve the node API over TLS instead of plain HTTP
+    pub tls: Option<TlsConfig>,
+    /// The URL the node API is publicly reachable at, if it is behind a proxy or uses another name
+    #[serde(with = "option_url_str")]
+    pub public_url: Option<Url>,
+    pub constraints: ConstraintsConfig,
+    /// How often to record account balances
+    #[serde(with = "secs")]
+    pub balance_snapshot_interval: Duration,
+    /// POST each incoming payment to this URL as JSON once the sender closes the connection
+    #[serde(with = "option_url_str")]
+    pub payment_webhook: Option<Url>,
+    /// POST each scheduled payment to this URL as JSON after every attempt to send it
+    #[serde(with = "option_url_str")]
+    pub scheduled_payment_webhook: Option<Url>,
+    /// POST each payment link to this URL as JSON once it is paid in full
+    #[serde(with = "option_url_str")]
+    pub payment_link_webhook: Option<Url>,
+    /// Reject STREAM packets for the node's accounts that carry less than this amount,
+    /// in the units of the account the packet arrived from
+    pub min_incoming_packet_amount: u64,
+    /// Process at most this many incoming packets at a time
+    pub max_in_flight_packets: usize,
+    /// Exchange settlement details with peers
+    pub settlement: Option<SettlementConfig>,
+    /// When starting, send a STREAM payment of this amount from the default account to itself
+    /// through the whole service stack, and do not start if it is not delivered
+    pub self_test_amount: Option<u64>,
+    /// Let anyone request to peer with the node through the API. The accounts created for
+    /// peering requests do not send or receive packets until an admin approves them
+    pub accept_peering_requests: bool,
+    /// Reject every API request that would change anything with 403 Forbidden, for example
+    /// because this is a standby instance. This includes ILP packets sent over HTTP
+    pub read_only: bool,
+    /// Summarize the routes advertised to peers into their parent prefix when there are at least
+    /// this many sibling prefixes that all go through the same account
+    pub route_aggregation_threshold: Option<usize>,
+    /// Process incoming packets on this many worker threads, each handling a fixed subset of
+    /// the accounts. If it is 0, packets are processed on the shared runtime
+    pub packet_shards: usize,
+    /// Check the clock against an NTP server when starting and periodically
+    pub clock: Option<ClockConfig>,
+}
+
+impl Default for NodeConfig {
+    fn default() -> Self {
+        NodeConfig {
+            redis_uri: Url::parse("redis://127.0.0.1:6379").unwrap(),
+            read_replica: None,
+            store: StoreConfig::default(),
+            server_secret: None,
+            btp_address: ([127, 0, 0, 1], 7768).into(),
+            http_address: None,
+            tls: None,
+            public_url: None,
+            constraints: ConstraintsConfig::default(),
+            balance_snapshot_interval: Duration::from_secs(300),
+            payment_webhook: None,
+            scheduled_payment_webhook: None,
+            payment_link_webhook: None,
+            min_incoming_packet_amount: 0,
+            max_in_flight_packets: 10_000,
+            settlement: None,
+            self_test_amount: None,
+            accept_peering_requests: false,
+            read_only: false,
+            route_aggregation_threshold: None,
+            packet_shards: 0,
+            clock: None,
+        }
+    }
+}
+
+impl NodeConfig {
+    /// Parse a JSON config. Settings that are left out take their default values.
+    pub fn from_json(json: &str) -> Result<Self, ConfigError> {
+        serde_json::from_str(json)
+            .map_err(|err| ConfigError::new("config", format!("Invalid config: {}", err)))
+    }
+
+    /// Read a JSON config file (see `NodeConfig::from_json`).
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, ConfigError> {
+        let path = path.as_ref();
+        let json = fs::read_to_string(path).map_err(|err| {
+            ConfigError::new(
+                "config",
+                format!("Unable to read config file {}: {}", path.display(), err),
+            )
+        })?;
+        NodeConfig::from_json(&json)
+    }
+
+    /// Check that the settings are consistent with each other and usable.
+    /// Returns every problem that was found.
+    pub fn validate(&self) -> Result<(), Vec<ConfigError>> {
+        let mut errors = Vec::new();
+        if Some(self.btp_address) == self.http_address {
+            errors.push(ConfigError::new(
+                "http_address",
+                format!(
+                    "The BTP server and the node API cannot both listen on {}",
+                    self.btp_address
+                ),
+            ));
+        }
+        if let Some(ref tls) = self.tls {
+            if self.http_address.is_none() {
+                errors.push(ConfigError::new(
+                    "tls",
+                    "TLS can only be used if the node API is served (see http_address)",
+                ));
+            }
+            if let Some(ref acme) = tls.acme {
+                let address = acme.challenge_address;
+                if address == self.btp_address || Some(address) == self.http_address {
+                    errors.push(ConfigError::new(
+                        "tls.acme.challenge_address",
+                        format!(
+                            "The ACME challenges cannot be answered on {}, which is already in use",
+                            address
+                        ),
+                    ));
+                }
+            }
+            tls.check(&mut errors);
+        }
+        if self.balance_snapshot_interval < Duration::from_secs(1) {
+            errors.push(ConfigError::new(
+                "balance_snapshot_interval",
+                "The balance snapshot interval must be at least 1 second",
+            ));
+        }
+        if self.max_in_flight_packets == 0 {
+            errors.push(ConfigError::new(
+                "max_in_flight_packets",
+                "The node must process at least 1 packet at a time",
+            ));
+        }
+        if self.self_test_amount == Some(0) {
+            errors.push(ConfigError::new(
+                "self_test_amount",
+                "The self-test payment must be for more than 0",
+            ));
+        }
+        if let Some(threshold) = self.route_aggregation_threshold {
+            if threshold < 2 {
+                errors.push(ConfigError::new(
+                    "route_aggregation_threshold",
+                    "Routes can only be aggregated if there are at least 2 of them",
+                ));
+            }
+        }
+        if let Some(ref clock) = self.clock {
+            if clock.check_interval < Duration::from_secs(1) {
+                errors.push(ConfigError::new(
+                    "clock.check_interval",
+                    "The clock check interval must be at least 1 second",
+                ));
+            }
+        }
+        self.store.check(&mut errors);
+        if let Some(ref replica) = self.read_replica {
+            replica.check(&mut errors);
+        }
+        if let Some(ref url) = self.public_url {
+            if url.scheme() != "http" && url.scheme() != "https" {
+                errors.push(ConfigError::new(
+                    "public_url",
+                    format!(
+                        "The public URL must be an http or https URL, not {}",
+                        url.scheme()
+                    ),
+                ));
+            }
+        }
+        if let Some(ref url) = self.payment_webhook {
+            if url.scheme() != "http" && url.scheme() != "https" {
+                errors.push(ConfigError::new(
+                    "payment_webhook",
+                    format!(
+                        "The payment webhook must be an http or https URL, not {}",
+                        url.scheme()
+                    ),
+                ));
+            }
+        }
+        if let Some(ref url) = self.scheduled_payment_webhook {
+            if url.scheme() != "http" && url.scheme() != "https" {
+                errors.push(ConfigError::new(
+                    "scheduled_payment_webhook",
+                    format!(
+                        "The scheduled payment webhook must be an http or https URL, not {}",
+                        url.scheme()
+                    ),
+                ));
+            }
+        }
+        if let Some(ref url) = self.payment_link_webhook {
+            if url.scheme() != "http" && url.scheme() != "https" {
+                errors.push(ConfigError::new(
+                    "payment_link_webhook",
+                    format!(
+                        "The payment link webhook must be an http or https URL, not {}",
+                        url.scheme()
+                    ),
+                ));
+            }
+        }
+        if let Some(ref codes) = self.constraints.allowed_asset_codes {
+            if codes.iter().any(|code| code.is_empty()) {
+                errors.push(ConfigError::new(
+                    "constraints.allowed_asset_codes",
+                    "The allowed asset codes cannot include an empty code",
+                ));
+            }
+        }
+        if let Some(ref prefix) = self.constraints.child_address_prefix {
+            let address = if prefix.ends_with('.') {
+                &prefix[..prefix.len() - 1]
+            } else {
+                &prefix[..]
+            };
+            let message = match address.parse::<Address>() {
+                Ok(_) => None,
+                Err(ParseError::InvalidAddress(message)) => Some(message),
+                Err(err) => Some(err.to_string()),
+            };
+            if let Some(message) = message {
+                errors.push(ConfigError::new(
+                    "constraints.child_address_prefix",
+                    format!(
+                        "The child address prefix is not a valid ILP address: {}",
+                        message
+                    ),
+                ));
+            }
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn defaults_are_valid() {
+        assert!(NodeConfig::default().validate().is_ok());
+    }
+
+    #[test]
+    fn fills_in_missing_settings_with_defaults() {
+        let config = NodeConfig::from_json(
+            r#"{
+                "http_address": "0.0.0.0:7770",
+                "server_secret": "0000000000000000000000000000000000000000000000000000000000000001",
+                "balance_snapshot_interval": 60,
+                "read_replica": { "uri": "redis://replica:6379" },
+                "store": { "rate_poll_interval": null, "leader_lease": 3000 }
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(config.http_address, Some(([0, 0, 0, 0], 7770).into()));
+        assert_eq!(config.server_secret.unwrap()[31], 1);
+        assert_eq!(config.balance_snapshot_interval, Duration::from_secs(60));
+        assert_eq!(
+            config.read_replica.unwrap().max_staleness,
+            Duration::from_secs(1)
+        );
+        assert_eq!(config.store.rate_poll_interval, None);
+        assert_eq!(
+            config.store.route_poll_interval,
+            StoreConfig::default().route_poll_interval
+        );
+        assert_eq!(config.store.leader_lease, Some(Duration::from_secs(3)));
+        assert_eq!(config.btp_address, NodeConfig::default().btp_address);
+    }
+
+    #[test]
+    fn parses_tls_settings() {
+        let config = NodeConfig::from_json(
+            r#"{
+                "http_address": "0.0.0.0:443",
+                "tls": {
+                    "certificates": [
+                        { "cert_path": "node.crt", "key_path": "node.key" }
+                    ],
+                    "acme": { "domains": ["node.example.com"], "email": "admin@example.com" }
+                }
+            }"#,
+        )
+        .unwrap();
+        assert!(config.validate().is_ok());
+        let tls = config.tls.unwrap();
+        assert!(tls.certificates[0].domains.is_empty());
+        assert_eq!(tls.reload_interval, TlsConfig::default().reload_interval);
+        let acme = tls.acme.unwrap();
+        assert_eq!(acme.challenge_address, ([0, 0, 0, 0], 80).into());
+        assert_eq!(
+            acme.directory_url.host_str(),
+            Some("acme-v02.api.letsencrypt.org")
+        );
+    }
+
+    #[test]
+    fn checks_tls_settings() {
+        let mut config = NodeConfig::default();
+        config.tls = Some(TlsConfig::default());
+        let fields: Vec<String> = config
+            .validate()
+            .unwrap_err()
+            .into_iter()
+            .map(|error| error.field)
+            .collect();
+        assert_eq!(fields, vec!["tls", "tls"]);
+    }
+
+    #[test]
+    fn rejects_invalid_secret() {
+        assert!(NodeConfig::from_json(r#"{ "server_secret": "abcd" }"#).is_err());
+    }
+
+    #[test]
+    fn round_trips_through_json() {
+        let mut config = NodeConfig::default();
+        config.server_secret = Some([7; 32]);
+        config.store.leader_lease = Some(Duration::from_millis(1500));
+        let json = serde_json::to_string(&config).unwrap();
+        assert_eq!(NodeConfig::from_json(&json).unwrap(), config);
+    }
+
+    #[test]
+    fn reports_every_invalid_setting() {
+        let mut config = NodeConfig::default();
+        config.http_address = Some(config.btp_address);
+        config.max_in_flight_packets = 0;
+        config.self_test_amount = Some(0);
+        config.route_aggregation_threshold = Some(1);
+        config.clock = Some(ClockConfig {
+            check_interval: Duration::from_millis(0),
+            ..ClockConfig::default()
+        });
+        config.store.leader_lease = Some(Duration::from_millis(2));
+        config.store.gc_interval = Some(Duration::from_millis(0));
+        config.constraints.child_address_prefix = Some("example..node".to_string());
+        let fields: Vec<String> = config
+            .validate()
+            .unwrap_err()
+            .into_iter()
+            .map(|error| error.field)
+            .collect();
+        assert_eq!(
+            fields,
+            vec![
+                "http_address",
+                "max_in_flight_packets",
+                "self_test_amount",
+                "route_aggregation_threshold",
+                "clock.check_interval",
+                "gc_interval",
+                "leader_lease",
+                "constraints.child_address_prefix",
+            ]
+        );
+    }
+}
@@ -0,0 +1,117 @@
+//! (De)serializers for the settings that are written as plain numbers or strings in
+//! config files but have richer types in the config structs.
+
+/// A `Duration` written as a number of milliseconds
+pub mod millis {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::time::Duration;
+
+    pub fn serialize<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u64(duration.as_millis() as u64)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+        u64::deserialize(deserializer).map(Duration::from_millis)
+    }
+}
+
+/// An optional `Duration` written as a number of milliseconds, or `null`
+pub mod option_millis {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use std::time::Duration;
+
+    pub fn serialize<S: Serializer>(
+        duration: &Option<Duration>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        duration
+            .map(|duration| duration.as_millis() as u64)
+            .serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Duration>, D::Error> {
+        Option::<u64>::deserialize(deserializer).map(|millis| millis.map(Duration::from_millis))
+    }
+}
+
+/// A `Duration` written as a number of seconds
+pub mod secs {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::time::Duration;
+
+    pub fn serialize<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u64(duration.as_secs())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+        u64::deserialize(deserializer).map(Duration::from_secs)
+    }
+}
+
+/// A `Url` written as a string
+pub mod url_str {
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+    use url::Url;
+
+    pub fn serialize<S: Serializer>(url: &Url, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(url.as_str())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Url, D::Error> {
+        let url = String::deserialize(deserializer)?;
+        Url::parse(&url).map_err(D::Error::custom)
+    }
+}
+
+/// An optional `Url` written as a string, or `null`
+pub mod option_url_str {
+    use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
+    use url::Url;
+
+    pub fn serialize<S: Serializer>(url: &Option<Url>, serializer: S) -> Result<S::Ok, S::Error> {
+        url.as_ref().map(Url::as_str).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Url>, D::Error> {
+        match Option::<String>::deserialize(deserializer)? {
+            Some(url) => Url::parse(&url).map(Some).map_err(D::Error::custom),
+            None => Ok(None),
+        }
+    }
+}
+
+/// An optional 32-byte secret written as a hex string, or `null`
+pub mod option_secret {
+    use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<S: Serializer>(
+        secret: &Option<[u8; 32]>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        secret.map(hex::encode).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<[u8; 32]>, D::Error> {
+        match Option::<String>::deserialize(deserializer)? {
+            Some(secret) => parse_secret(&secret).map(Some).map_err(D::Error::custom),
+            None => Ok(None),
+        }
+    }
+
+    pub fn parse_secret(secret: &str) -> Result<[u8; 32], String> {
+        let decoded =
+            hex::decode(secret).map_err(|_| "The server secret must be hex-encoded".to_string())?;
+        if decoded.len() != 32 {
+            return Err("The server secret must be 32 bytes".to_string());
+        }
+        let mut secret = [0; 32];
+        secret.copy_from_slice(&decoded);
+        Ok(secret)
+    }
+}
@@ -0,0 +1,128 @@
+use super::{serde_helpers::*, ConfigError};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use url::Url;
+
+const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(60);
+const DEFAULT_GC_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const DEFAULT_MAX_STALENESS: Duration = Duration::from_secs(1);
+
+/// How the Redis store keeps its in-memory copies of the routing table, exchange rates
+/// and fee policies up to date, and which background tasks it runs.
+///
+/// All durations are written in milliseconds in config files.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct StoreConfig {
+    /// How often to poll for routing table updates, as a fallback for the subscription to them.
+    /// `None` disables polling, in which case the routing table is only reloaded when
+    /// the store gets a published change to it
+    #[serde(with = "option_millis")]
+    pub route_poll_interval: Option<Duration>,
+    /// How often to poll for exchange rate and fee policy updates. `None` disables polling,
+    /// in which case the rates are only reloaded when the store gets a published change
+    /// to them, and the fee policies are only loaded when the store connects
+    #[serde(with = "option_millis")]
+    pub rate_poll_interval: Option<Duration>,
+    /// Delay the start of each polling loop by a random amount up to this long,
+    /// so that nodes sharing a database do not all poll it at the same time
+    #[serde(with = "millis")]
+    pub poll_jitter: Duration,
+    /// How often to remove expired entries that Redis does not clean up on its own.
+    /// `None` disables the periodic cleanup
+    #[serde(with = "option_millis")]
+    pub gc_interval: Option<Duration>,
+    /// If set, elect a leader among the instances that share the database, which holds
+    /// a lease of this length. `None` makes every instance act as the leader
+    #[serde(with = "option_millis")]
+    pub leader_lease: Option<Duration>,
+}
+
+impl Default for StoreConfig {
+    fn default() -> Self {
+        StoreConfig {
+            route_poll_interval: Some(DEFAULT_POLL_INTERVAL),
+            rate_poll_interval: Some(DEFAULT_POLL_INTERVAL),
+            poll_jitter: Duration::from_millis(0),
+            gc_interval: Some(DEFAULT_GC_INTERVAL),
+            leader_lease: None,
+        }
+    }
+}
+
+impl StoreConfig {
+    /// Check that the intervals and the lease are usable. Returns every problem that was found.
+    pub fn validate(&self) -> Result<(), Vec<ConfigError>> {
+        let mut errors = Vec::new();
+        self.check(&mut errors);
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    pub(crate) fn check(&self, errors: &mut Vec<ConfigError>) {
+        let intervals = [
+            (
+                "route_poll_interval",
+                "route poll",
+                self.route_poll_interval,
+            ),
+            ("rate_poll_interval", "rate poll", self.rate_poll_interval),
+            ("gc_interval", "garbage collection", self.gc_interval),
+        ];
+        for (field, name, interval) in intervals.iter() {
+            if *interval == Some(Duration::from_millis(0)) {
+                errors.push(ConfigError::new(
+                    field,
+                    format!("The {} interval must be at least 1 millisecond", name),
+                ));
+            }
+        }
+        // The lease is renewed every third of it
+        if let Some(lease) = self.leader_lease {
+            if lease < Duration::from_millis(3) {
+                errors.push(ConfigError::new(
+                    "leader_lease",
+                    "The leader lease must be at least 3 milliseconds",
+                ));
+            }
+        }
+    }
+}
+
+/// A read-only replica of the Redis database to look up accounts and poll for
+/// routes and rates from, while writes go to the primary.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ReplicaConfig {
+    #[serde(with = "url_str")]
+    pub uri: Url,
+    /// Only read from the replica while it is at most this far behind the primary
+    #[serde(default = "default_max_staleness", with = "millis")]
+    pub max_staleness: Duration,
+}
+
+impl ReplicaConfig {
+    /// Read from the replica at the given URI while it is at most 1 second behind the primary.
+    pub fn new(uri: Url) -> Self {
+        ReplicaConfig {
+            uri,
+            max_staleness: DEFAULT_MAX_STALENESS,
+        }
+    }
+
+    pub(crate) fn check(&self, errors: &mut Vec<ConfigError>) {
+        // The replica is checked every third of its staleness bound
+        if self.max_staleness < Duration::from_millis(3) {
+            errors.push(ConfigError::new(
+                "read_replica.max_staleness",
+                "The replica's maximum staleness must be at least 3 milliseconds",
+            ));
+        }
+    }
+}
+
+fn default_max_staleness() -> Duration {
+    DEFAULT_MAX_STALENESS
+}
@@ -0,0 +1,133 @@
+use super::{serde_helpers::*, ConfigError};
+use serde::{Deserialize, Serialize};
+use std::{net::SocketAddr, path::PathBuf, time::Duration};
+use url::Url;
+
+const DEFAULT_RELOAD_INTERVAL: Duration = Duration::from_secs(60);
+const DEFAULT_RENEW_BEFORE: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+const LETS_ENCRYPT_DIRECTORY: &str = "https://acme-v02.api.letsencrypt.org/directory";
+
+/// Serve the node's HTTP endpoints (the node API, SPSP and ILP-over-HTTP) over TLS.
+///
+/// Certificates are chosen by the server name the client requests (SNI) and are reloaded
+/// when their files change, so they can be rotated without restarting the node.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TlsConfig {
+    pub certificates: Vec<CertificateConfig>,
+    /// How often to check whether the certificate files have changed
+    #[serde(with = "millis")]
+    pub reload_interval: Duration,
+    /// Obtain and renew certificates automatically from an ACME provider
+    pub acme: Option<AcmeConfig>,
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        TlsConfig {
+            certificates: Vec::new(),
+            reload_interval: DEFAULT_RELOAD_INTERVAL,
+            acme: None,
+        }
+    }
+}
+
+/// A PEM-encoded certificate chain and its private key (PKCS#8 or RSA).
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CertificateConfig {
+    /// The names to serve this certificate for, which may start with a `*.` wildcard.
+    /// A certificate without any is served to clients that ask for another name or none
+    #[serde(default)]
+    pub domains: Vec<String>,
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+}
+
+/// Obtain certificates from an ACME provider such as Let's Encrypt, answering its HTTP-01
+/// challenges on `challenge_address`, and renew them before they expire.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AcmeConfig {
+    /// The names to request a certificate for. The first one is the certificate's primary name
+    pub domains: Vec<String>,
+    /// Contact address for the ACME account
+    pub email: String,
+    #[serde(default = "default_directory_url", with = "url_str")]
+    pub directory_url: Url,
+    /// Where to answer the provider's challenges. The provider connects to port 80
+    #[serde(default = "default_challenge_address")]
+    pub challenge_address: SocketAddr,
+    /// Where to keep the account key and the certificates between restarts
+    #[serde(default = "default_cache_dir")]
+    pub cache_dir: PathBuf,
+    /// Renew certificates this long before they expire (written in seconds)
+    #[serde(default = "default_renew_before", with = "secs")]
+    pub renew_before: Duration,
+}
+
+impl AcmeConfig {
+    /// Use Let's Encrypt, answering challenges on port 80 and renewing certificates
+    /// 30 days before they expire.
+    pub fn new(domains: Vec<String>, email: String) -> Self {
+        AcmeConfig {
+            domains,
+            email,
+            directory_url: default_directory_url(),
+            challenge_address: default_challenge_address(),
+            cache_dir: default_cache_dir(),
+            renew_before: DEFAULT_RENEW_BEFORE,
+        }
+    }
+}
+
+impl TlsConfig {
+    pub(crate) fn check(&self, errors: &mut Vec<ConfigError>) {
+        if self.certificates.is_empty() && self.acme.is_none() {
+            errors.push(ConfigError::new(
+                "tls",
+                "TLS requires at least one certificate or ACME to be configured",
+            ));
+        }
+        if self.reload_interval == Duration::from_millis(0) {
+            errors.push(ConfigError::new(
+                "tls.reload_interval",
+                "The certificate reload interval must be at least 1 millisecond",
+            ));
+        }
+        if let Some(ref acme) = self.acme {
+            if acme.domains.is_empty() {
+                errors.push(ConfigError::new(
+                    "tls.acme.domains",
+                    "ACME requires at least one domain to request a certificate for",
+                ));
+            }
+            if acme.email.is_empty() {
+                errors.push(ConfigError::new(
+                    "tls.acme.email",
+                    "ACME requires a contact email address",
+                ));
+            }
+            if acme.directory_url.scheme() != "https" {
+                errors.push(ConfigError::new(
+                    "tls.acme.directory_url",
+                    "The ACME directory URL must be an https URL",
+                ));
+            }
+        }
+    }
+}
+
+fn default_directory_url() -> Url {
+    Url::parse(LETS_ENCRYPT_DIRECTORY).unwrap()
+}
+
+fn default_challenge_address() -> SocketAddr {
+    ([0, 0, 0, 0], 80).into()
+}
+
+fn default_cache_dir() -> PathBuf {
+    PathBuf::from("acme")
+}
+
+fn default_renew_before() -> Duration {
+    DEFAULT_RENEW_BEFORE
+}
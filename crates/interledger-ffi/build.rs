@@ -0,0 +1,9 @@
+use std::{env, path::PathBuf};
+
+fn main() {
+    let crate_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
+    cbindgen::generate(&crate_dir)
+        .expect("Unable to generate C bindings")
+        .write_to_file(out_dir.join("interledger.h"));
+}
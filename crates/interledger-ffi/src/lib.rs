@@ -0,0 +1,274 @@
+//! # interledger-ffi
+//!
+//! C bindings for sending SPSP payments and running an Interledger.rs node inside a
+//! non-Rust application. The header is generated by cbindgen into `interledger.h` in the
+//! build's `OUT_DIR` when the crate is built (or run `cbindgen` in this directory to write it elsewhere).
+//!
+//! All functions that return `int` return `ILP_OK` on success or a negative error code.
+//! When a function fails, `ilp_last_error` returns a message describing why.
+
+#[macro_use]
+extern crate log;
+
+use futures::{Future, Stream};
+use interledger::{
+    cli::send_spsp_payment_btp,
+    node::NodeBuilder,
+    packet::{ErrorCode, FulfillBuilder, RejectBuilder},
+};
+use interledger_store_redis::IntoConnectionInfo;
+use std::{
+    cell::RefCell,
+    ffi::{CStr, CString},
+    os::raw::{c_char, c_int, c_void},
+    panic::{catch_unwind, AssertUnwindSafe},
+    ptr,
+};
+use tokio::runtime::Runtime;
+
+pub const ILP_OK: c_int = 0;
+/// One of the arguments was null or not valid UTF-8
+pub const ILP_INVALID_ARGUMENT: c_int = -1;
+/// The payment failed or the node could not be started
+pub const ILP_ERROR: c_int = -2;
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = RefCell::new(None);
+}
+
+/// Called for each ILP Prepare packet sent to the node's local account.
+///
+/// To accept the packet, write the 32-byte fulfillment to `fulfillment` and return a nonzero value.
+/// Returning zero rejects the packet. This is called from the node's worker threads, so
+/// `user_data` must be safe to use from other threads.
+pub type IlpReceiveCallback = extern "C" fn(
+    user_data: *mut c_void,
+    amount: u64,
+    destination: *const u8,
+    destination_len: usize,
+    data: *const u8,
+    data_len: usize,
+    fulfillment: *mut u8,
+) -> c_int;
+
+/// A node running on its own thread pool.
+pub struct IlpNode {
+    runtime: Runtime,
+}
+
+struct UserData(*mut c_void);
+
+// The caller is responsible for making the user data thread-safe, as documented on the callback
+unsafe impl Send for UserData {}
+
+fn set_last_error(message: String) {
+    debug!("{}", message);
+    // Messages never contain null bytes, but fall back to an empty one rather than failing
+    let message = CString::new(message).unwrap_or_default();
+    LAST_ERROR.with(|last_error| *last_error.borrow_mut() = Some(message));
+}
+
+/// Run `call`, turning a panic into an error so that it does not unwind into the caller's code.
+fn catch_panic<T, F: FnOnce() -> T>(call: F, on_panic: T) -> T {
+    catch_unwind(AssertUnwindSafe(call)).unwrap_or_else(|_| {
+        set_last_error("Internal error (panic) in interledger-ffi".to_string());
+        on_panic
+    })
+}
+
+unsafe fn to_str<'a>(string: *const c_char, name: &str) -> Result<&'a str, String> {
+    if string.is_null() {
+        Err(format!("{} must not be null", name))
+    } else {
+        CStr::from_ptr(string)
+            .to_str()
+            .map_err(|_| format!("{} is not valid UTF-8", name))
+    }
+}
+
+/// The message describing the last error returned on this thread, or null if there was none.
+///
+/// The string belongs to the library and stays valid until the next failed call on the same thread.
+#[no_mangle]
+pub extern "C" fn ilp_last_error() -> *const c_char {
+    LAST_ERROR.with(|last_error| {
+        last_error
+            .borrow()
+            .as_ref()
+            .map(|message| message.as_ptr())
+            .unwrap_or(ptr::null())
+    })
+}
+
+/// Send an SPSP payment through the BTP server (for example, moneyd) at `btp_server`.
+///
+/// Blocks until the payment finishes. The amount delivered, in the receiver's units,
+/// is written to `delivered` if it is not null.
+#[no_mangle]
+pub unsafe extern "C" fn ilp_send_spsp_payment(
+    btp_server: *const c_char,
+    receiver: *const c_char,
+    amount: u64,
+    delivered: *mut u64,
+) -> c_int {
+    let (btp_server, receiver) = match (
+        to_str(btp_server, "btp_server"),
+        to_str(receiver, "receiver"),
+    ) {
+        (Ok(btp_server), Ok(receiver)) => (btp_server, receiver),
+        (Err(message), _) | (_, Err(message)) => {
+            set_last_error(message);
+            return ILP_INVALID_ARGUMENT;
+        }
+    };
+    catch_panic(
+        || {
+            let mut runtime = match Runtime::new() {
+                Ok(runtime) => runtime,
+                Err(err) => {
+                    set_last_error(format!("Unable to start runtime: {:?}", err));
+                    return ILP_ERROR;
+                }
+            };
+            match runtime.block_on(send_spsp_payment_btp(btp_server, receiver, amount, true)) {
+                Ok(amount_delivered) => {
+                    if !delivered.is_null() {
+                        *delivered = amount_delivered;
+                    }
+                    ILP_OK
+                }
+                Err(message) => {
+                    set_last_error(message);
+                    ILP_ERROR
+                }
+            }
+        },
+        ILP_ERROR,
+    )
+}
+
+/// Start a node that uses the Redis store at `redis_uri`, with the BTP server and
+/// node API listening on the given ports.
+///
+/// If `receive_callback` is not null, packets routed to the account `receive_account_id`
+/// are passed to it. Returns null if the node could not be started.
+#[no_mangle]
+pub unsafe extern "C" fn ilp_node_start(
+    redis_uri: *const c_char,
+    btp_port: u16,
+    http_port: u16,
+    receive_account_id: u64,
+    receive_callback: Option<IlpReceiveCallback>,
+    user_data: *mut c_void,
+) -> *mut IlpNode {
+    let redis_uri = match to_str(redis_uri, "redis_uri") {
+        Ok(redis_uri) => redis_uri,
+        Err(message) => {
+            set_last_error(message);
+            return ptr::null_mut();
+        }
+    };
+    let user_data = UserData(user_data);
+    catch_panic(
+        move || {
+            start_node(
+                redis_uri,
+                btp_port,
+                http_port,
+                receive_account_id,
+                receive_callback,
+                user_data,
+            )
+        },
+        ptr::null_mut(),
+    )
+}
+
+fn start_node(
+    redis_uri: &str,
+    btp_port: u16,
+    http_port: u16,
+    receive_account_id: u64,
+    receive_callback: Option<IlpReceiveCallback>,
+    user_data: UserData,
+) -> *mut IlpNode {
+    let redis_uri = match redis_uri.into_connection_info() {
+        Ok(redis_uri) => redis_uri,
+        Err(err) => {
+            set_last_error(format!("Invalid redis_uri: {}", err));
+            return ptr::null_mut();
+        }
+    };
+    let mut runtime = match Runtime::new() {
+        Ok(runtime) => runtime,
+        Err(err) => {
+            set_last_error(format!("Unable to start runtime: {:?}", err));
+            return ptr::null_mut();
+        }
+    };
+
+    let mut builder = NodeBuilder::new(redis_uri)
+        .btp_address(([0, 0, 0, 0], btp_port).into())
+        .http_address(([0, 0, 0, 0], http_port).into());
+    if receive_callback.is_some() {
+        builder = builder.local_accounts(vec![receive_account_id]);
+    }
+    let node = match runtime.block_on(builder.run()) {
+        Ok(node) => node,
+        Err(_) => {
+            // The node logs the details of what went wrong
+            set_last_error("Unable to start the node (see the log for details)".to_string());
+            return ptr::null_mut();
+        }
+    };
+
+    if let Some(callback) = receive_callback {
+        runtime.spawn(node.local_requests.for_each(move |(request, respond)| {
+            let prepare = request.prepare;
+            let mut fulfillment = [0; 32];
+            let accepted = callback(
+                user_data.0,
+                prepare.amount(),
+                prepare.destination().as_ptr(),
+                prepare.destination().len(),
+                prepare.data().as_ptr(),
+                prepare.data().len(),
+                fulfillment.as_mut_ptr(),
+            );
+            let result = if accepted != 0 {
+                Ok(FulfillBuilder {
+                    fulfillment: &fulfillment,
+                    data: &[],
+                }
+                .build())
+            } else {
+                Err(RejectBuilder {
+                    code: ErrorCode::F99_APPLICATION_ERROR,
+                    message: &[],
+                    triggered_by: &[],
+                    data: &[],
+                }
+                .build())
+            };
+            let _ = respond.send(result);
+            Ok(())
+        }));
+    }
+
+    Box::into_raw(Box::new(IlpNode { runtime }))
+}
+
+/// Stop a node started with `ilp_node_start` and free it.
+#[no_mangle]
+pub unsafe extern "C" fn ilp_node_stop(node: *mut IlpNode) {
+    if node.is_null() {
+        return;
+    }
+    let node = Box::from_raw(node);
+    catch_panic(
+        move || {
+            let _ = node.runtime.shutdown_now().wait();
+        },
+        (),
+    )
+}
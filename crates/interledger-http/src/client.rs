@@ -125,8 +125,8 @@ fn parse_packet_from_response(
         })
     })
     .and_then(|body: Chunk| {
-        // TODO can we get the body as a BytesMut so we don't need to copy?
-        let body = BytesMut::from(body.to_vec());
+        // The body is copied once, straight into the buffer the packet is parsed from
+        let body = BytesMut::from(&body[..]);
         match Packet::try_from(body) {
             Ok(Packet::Fulfill(fulfill)) => Ok(fulfill),
             Ok(Packet::Reject(reject)) => Err(reject),
@@ -8,7 +8,7 @@ use futures::{
 use hyper::{
     body::Body, header::AUTHORIZATION, service::Service as HttpService, Error, Request, Response,
 };
-use interledger_packet::{Fulfill, Prepare, Reject};
+use interledger_packet::{redact::Redacted, Fulfill, Prepare, Reject};
 use interledger_service::*;
 
 /// Max message size that is allowed to transfer from a request or a message.
@@ -46,7 +46,10 @@ where
                 self.store
                     .get_account_from_http_auth(&authorization)
                     .map_err(move |_err| {
-                        error!("Authorization not found in the DB: {}", authorization);
+                        error!(
+                            "Authorization not found in the DB: {}",
+                            Redacted(&authorization)
+                        );
                         Response::builder().status(401).body(Body::empty()).unwrap()
                     }),
             )
@@ -1,6 +1,10 @@
 use super::packet::*;
-use futures::Future;
+use futures::{future::Shared, Future};
 use interledger_service::*;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
 
 /// Get the ILP address and asset details for a given account.
 pub fn get_ildcp_info<S, A>(
@@ -30,3 +34,137 @@ where
             Ok(response)
         })
 }
+
+/// A service that only sends one ILDCP request at a time for each account. Requests made
+/// while one for the same account is waiting for its response share that response instead
+/// of being sent again, so that many children connecting at once do not each send their own
+/// request to the parent. Other requests are passed through.
+///
+/// Requests are only shared between the users of the same `IldcpClient` (and its clones),
+/// so it should be part of the service stack rather than created for each request.
+#[derive(Clone)]
+pub struct IldcpClient<S, A: Account> {
+    next: S,
+    in_flight: Arc<Mutex<HashMap<A::AccountId, Shared<BoxedIlpFuture>>>>,
+}
+
+impl<S, A> IldcpClient<S, A>
+where
+    S: IncomingService<A> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+    A: Account + 'static,
+{
+    pub fn new(next: S) -> Self {
+        IldcpClient {
+            next,
+            in_flight: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Get the ILP address and asset details for the account, sharing the response of a
+    /// request for it that is already in flight
+    pub fn get_ildcp_info(&self, account: A) -> impl Future<Item = IldcpResponse, Error = ()> {
+        get_ildcp_info(&mut self.clone(), account)
+    }
+}
+
+impl<S, A> IncomingService<A> for IldcpClient<S, A>
+where
+    S: IncomingService<A> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+    A: Account + 'static,
+{
+    type Future = BoxedIlpFuture;
+
+    fn handle_request(&mut self, request: IncomingRequest<A>) -> Self::Future {
+        if !is_ildcp_request(&request.prepare) {
+            return Box::new(self.next.handle_request(request));
+        }
+        let account_id = request.from.id();
+        let mut in_flight = self.in_flight.lock().unwrap();
+        let response = if let Some(response) = in_flight.get(&account_id) {
+            debug!(
+                "Sharing the ILDCP request already in flight for account: {}",
+                account_id
+            );
+            response.clone()
+        } else {
+            let in_flight_clone = self.in_flight.clone();
+            let response: BoxedIlpFuture =
+                Box::new(self.next.handle_request(request).then(move |result| {
+                    in_flight_clone.lock().unwrap().remove(&account_id);
+                    result
+                }));
+            let response = response.shared();
+            in_flight.insert(account_id, response.clone());
+            response
+        };
+        Box::new(
+            response
+                .map(|fulfill| (*fulfill).clone())
+                .map_err(|reject| (*reject).clone()),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::sync::oneshot;
+    use interledger_packet::Fulfill;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[derive(Clone, Debug)]
+    struct TestAccount;
+
+    impl Account for TestAccount {
+        type AccountId = u64;
+
+        fn id(&self) -> u64 {
+            0
+        }
+    }
+
+    #[test]
+    fn shares_requests_in_flight() {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let requests_clone = requests.clone();
+        let (respond, response) = oneshot::channel::<()>();
+        let response = response.shared();
+        let client = IldcpClient::new(incoming_service_fn(
+            move |_request: IncomingRequest<TestAccount>| {
+                requests_clone.fetch_add(1, Ordering::SeqCst);
+                response.clone().then(|_| {
+                    Ok(Fulfill::from(
+                        IldcpResponseBuilder {
+                            client_address: b"example.child",
+                            asset_code: "XYZ",
+                            asset_scale: 9,
+                        }
+                        .build(),
+                    ))
+                })
+            },
+        ));
+
+        let first = client.get_ildcp_info(TestAccount);
+        // Requests that are passed through the client in a service stack are shared too
+        let second = client
+            .clone()
+            .handle_request(IncomingRequest {
+                from: TestAccount,
+                prepare: IldcpRequest::new().to_prepare(),
+            })
+            .map_err(|_| ());
+        respond.send(()).unwrap();
+        let (first, second) = first.join(second).wait().unwrap();
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+        assert_eq!(first.client_address(), &b"example.child"[..]);
+        let second = IldcpResponse::try_from(second.into_data().freeze()).unwrap();
+        assert_eq!(second.client_address(), &b"example.child"[..]);
+
+        // Once the response came back, the next request is sent again
+        client.get_ildcp_info(TestAccount).wait().unwrap();
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+    }
+}
@@ -15,7 +15,7 @@ mod client;
 mod packet;
 mod server;
 
-pub use client::get_ildcp_info;
+pub use client::{get_ildcp_info, IldcpClient};
 pub use packet::*;
 pub use server::IldcpService;
 
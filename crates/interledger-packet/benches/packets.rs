@@ -4,6 +4,7 @@ use bytes::BytesMut;
 use chrono::{DateTime, Utc};
 use criterion::{criterion_group, criterion_main, Criterion};
 use lazy_static::lazy_static;
+use std::time::Duration;
 
 use ilp::{ErrorCode, Fulfill, Prepare, Reject};
 use ilp::{FulfillBuilder, PrepareBuilder, RejectBuilder};
@@ -97,6 +98,19 @@ fn benchmark_deserialize(c: &mut Criterion) {
     });
 }
 
+fn benchmark_forward(c: &mut Criterion) {
+    let prepare_bytes = BytesMut::from(PREPARE.build());
+    let expires_at = PREPARE.expires_at - Duration::from_secs(1);
+    c.bench_function("Prepare (forward)", move |b| {
+        b.iter(|| {
+            let mut prepare = Prepare::try_from(prepare_bytes.clone()).unwrap();
+            prepare.set_amount(prepare.amount() * 2);
+            prepare.set_expires_at(expires_at).unwrap();
+            assert_eq!(prepare.expires_at(), expires_at);
+        });
+    });
+}
+
 criterion_group! {
     name = benches;
     config = Criterion::default()
@@ -104,6 +118,7 @@ criterion_group! {
     targets =
         benchmark_serialize,
         benchmark_deserialize,
+        benchmark_forward,
 }
 
 criterion_main!(benches);
@@ -0,0 +1,175 @@
+use super::ParseError;
+use bytes::Bytes;
+use std::fmt;
+use std::str::{self, FromStr};
+
+const MAX_ADDRESS_LENGTH: usize = 1023;
+const SCHEMES: &[&str] = &[
+    "g", "private", "example", "peer", "self", "test", "test1", "test2", "test3", "local",
+];
+
+/// An ILP address that has been checked to be well-formed, as defined in
+/// [RFC 15](https://github.com/interledger/rfcs/blob/master/0015-ilp-addresses/0015-ilp-addresses.md).
+///
+/// Addresses are at most 1023 bytes long, start with one of the allocation schemes
+/// (`g`, `private`, `example`, `peer`, `self`, `test`, `test1`, `test2`, `test3` or `local`),
+/// and have at least one more segment. Segments are separated by `.` and may only contain
+/// ASCII letters, digits, `_`, `~` and `-`.
+#[derive(Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Address(Bytes);
+
+impl Address {
+    // TODO change this to `TryFrom` when it is stabilized
+    pub fn try_from(bytes: Bytes) -> Result<Self, ParseError> {
+        validate(&bytes[..]).map_err(ParseError::InvalidAddress)?;
+        Ok(Address(bytes))
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0[..]
+    }
+
+    pub fn as_str(&self) -> &str {
+        // The address was checked to be ASCII when it was created
+        str::from_utf8(&self.0[..]).unwrap_or("")
+    }
+
+    pub fn to_bytes(&self) -> Bytes {
+        self.0.clone()
+    }
+
+    /// The allocation scheme, for example `g` or `private`
+    pub fn scheme(&self) -> &str {
+        self.segments().next().unwrap_or("")
+    }
+
+    /// The parts of the address separated by `.`, starting with the scheme
+    pub fn segments(&self) -> str::Split<char> {
+        self.as_str().split('.')
+    }
+
+    /// Create a new address by appending a segment to this one.
+    pub fn with_suffix(&self, suffix: &[u8]) -> Result<Self, ParseError> {
+        let mut address = Vec::with_capacity(self.0.len() + 1 + suffix.len());
+        address.extend_from_slice(self.as_bytes());
+        address.push(b'.');
+        address.extend_from_slice(suffix);
+        Address::try_from(Bytes::from(address))
+    }
+}
+
+/// Whether the address is the prefix itself or one of the addresses under it.
+/// For example, `example.alice.1` is within `example.alice` but `example.alice2` is not.
+pub fn is_within_prefix(address: &[u8], prefix: &[u8]) -> bool {
+    address.starts_with(prefix)
+        && (address.len() == prefix.len()
+            || address[prefix.len()] == b'.'
+            || prefix.last() == Some(&b'.'))
+}
+
+fn validate(address: &[u8]) -> Result<(), String> {
+    let address = str::from_utf8(address).map_err(|_| "Address must be UTF-8".to_string())?;
+    if address.len() > MAX_ADDRESS_LENGTH {
+        return Err(format!(
+            "Address must be at most {} bytes",
+            MAX_ADDRESS_LENGTH
+        ));
+    }
+    let mut segments = address.split('.');
+    let scheme = segments.next().unwrap_or("");
+    if !SCHEMES.contains(&scheme) {
+        return Err(format!("Unknown address scheme: {}", scheme));
+    }
+    let mut num_segments = 0;
+    for segment in segments {
+        num_segments += 1;
+        if segment.is_empty()
+            || !segment
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '~' || c == '-')
+        {
+            return Err(format!("Invalid address segment: \"{}\"", segment));
+        }
+    }
+    if num_segments == 0 {
+        return Err("Address must have at least one segment after the scheme".to_string());
+    }
+    Ok(())
+}
+
+impl FromStr for Address {
+    type Err = ParseError;
+
+    fn from_str(address: &str) -> Result<Self, ParseError> {
+        Address::try_from(Bytes::from(address))
+    }
+}
+
+impl AsRef<[u8]> for Address {
+    fn as_ref(&self) -> &[u8] {
+        self.as_bytes()
+    }
+}
+
+impl From<Address> for Bytes {
+    fn from(address: Address) -> Bytes {
+        address.0
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl fmt::Debug for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Address({})", self.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_valid_addresses() {
+        let address = Address::from_str("g.us-fed.ach.0.acmebank.~alice_1").unwrap();
+        assert_eq!(address.scheme(), "g");
+        assert_eq!(address.segments().count(), 6);
+        assert_eq!(address.to_string(), "g.us-fed.ach.0.acmebank.~alice_1");
+        assert!(Address::from_str("private.local").is_ok());
+    }
+
+    #[test]
+    fn rejects_invalid_addresses() {
+        assert!(Address::from_str("example").is_err());
+        assert!(Address::from_str("example.").is_err());
+        assert!(Address::from_str("example..alice").is_err());
+        assert!(Address::from_str("unknown.alice").is_err());
+        assert!(Address::from_str("example.al ice").is_err());
+        assert!(Address::try_from(Bytes::from(&b"example.\xff"[..])).is_err());
+        let too_long = format!("g.{}", "a".repeat(1022));
+        assert!(Address::from_str(&too_long).is_err());
+    }
+
+    #[test]
+    fn appends_segments() {
+        let address = Address::from_str("example.connector").unwrap();
+        assert_eq!(
+            address.with_suffix(b"alice").unwrap().as_str(),
+            "example.connector.alice"
+        );
+        assert!(address.with_suffix(b"al.").is_err());
+    }
+
+    #[test]
+    fn checks_addresses_are_within_prefix() {
+        assert!(is_within_prefix(b"example.alice", b"example.alice"));
+        assert!(is_within_prefix(b"example.alice.1", b"example.alice"));
+        assert!(is_within_prefix(b"example.alice", b"example."));
+        assert!(!is_within_prefix(b"example.alice2", b"example.alice"));
+        assert!(!is_within_prefix(b"example", b"example.alice"));
+    }
+}
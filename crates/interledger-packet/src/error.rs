@@ -1,7 +1,7 @@
 use std::fmt;
 use std::str;
 
-#[derive(Clone, Copy, Eq, PartialEq)]
+#[derive(Clone, Copy, Eq, Hash, PartialEq)]
 pub struct ErrorCode([u8; 3]);
 
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
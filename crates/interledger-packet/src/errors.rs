@@ -34,6 +34,10 @@ quick_error! {
             description(descr)
             display("Invalid Packet {}", descr)
         }
+        InvalidAddress(descr: String) {
+            description(descr)
+            display("Invalid Address {}", descr)
+        }
         Other(err: Box<std::error::Error>) {
             cause(&**err)
             description(err.description())
@@ -1,14 +1,23 @@
 //! # interledger-packet
 //!
 //! Interledger packet serialization/deserialization.
+//!
+//! This crate builds for wasm32 but needs `std`. A `no_std` core was requested and withdrawn:
+//! packets are backed by bytes 0.4 and parsed through `std::io`, and bytes 0.4, hex 0.3 and
+//! quick-error all require `std`, so it would mean replacing the buffer and timestamp types of
+//! the public API that every other crate in the workspace uses.
 
+mod address;
 mod error;
 mod errors;
 #[cfg(test)]
 mod fixtures;
 pub mod oer;
 mod packet;
+pub mod redact;
+mod timestamp;
 
+pub use self::address::{is_within_prefix, Address};
 pub use self::error::{ErrorClass, ErrorCode};
 pub use self::errors::ParseError;
 
@@ -96,8 +96,26 @@ impl<'a> BufOerExt<'a> for &'a [u8] {
         let length = self.read_u8()?;
         if length & HIGH_BIT != 0 {
             let length_prefix_length = (length & LOWER_SEVEN_BITS) as usize;
-            // TODO check for canonical length
-            Ok(self.read_uint::<BigEndian>(length_prefix_length)? as usize)
+            if length_prefix_length == 0 || length_prefix_length > 8 {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "invalid length prefix length",
+                ));
+            }
+            if cfg!(feature = "strict") && self.first() == Some(&0) {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "length prefix has leading zeros",
+                ));
+            }
+            let length = self.read_uint::<BigEndian>(length_prefix_length)? as usize;
+            if cfg!(feature = "strict") && length < 128 {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "length prefix should use the short form",
+                ));
+            }
+            Ok(length)
         } else {
             Ok(length as usize)
         }
@@ -109,6 +127,13 @@ impl<'a> BufOerExt<'a> for &'a [u8] {
         let size = self.read_var_octet_string_length()?;
         if size == 0 {
             Err(Error::new(ErrorKind::InvalidData, "zero-length VarUInt"))
+        } else if size > 8 {
+            Err(Error::new(ErrorKind::InvalidData, "VarUInt is too large"))
+        } else if cfg!(feature = "strict") && size > 1 && self.first() == Some(&0) {
+            Err(Error::new(
+                ErrorKind::InvalidData,
+                "VarUInt has leading zeros",
+            ))
         } else {
             Ok(self.read_uint::<BigEndian>(size)?)
         }
@@ -387,3 +412,118 @@ mod buf_mut_oer_ext {
         }
     }
 }
+
+/// Canonical encodings from the ILP and OER specifications, which other implementations
+/// produce and expect byte-for-byte.
+#[cfg(test)]
+mod test_vectors {
+    use super::*;
+
+    const VAR_UINTS: &[(&str, u64)] = &[
+        ("0100", 0),
+        ("0101", 1),
+        ("017f", 127),
+        ("0180", 128),
+        ("01ff", 255),
+        ("020100", 256),
+        ("02ffff", 65535),
+        ("03010000", 65536),
+        ("03ffffff", 0x00ff_ffff),
+        ("04ffffffff", 0xffff_ffff),
+        ("08ffffffffffffffff", u64::MAX),
+    ];
+
+    const VAR_OCTET_STRING_LENGTHS: &[(&str, usize)] = &[
+        ("00", 0),
+        ("01", 1),
+        ("7f", 127),
+        ("8180", 128),
+        ("81ff", 255),
+        ("820100", 256),
+        ("82ffff", 65535),
+        ("83010000", 65536),
+    ];
+
+    /// Decodable, but not the shortest encoding of the value
+    const NON_CANONICAL_VAR_UINTS: &[&str] =
+        &["020001", "0400ffffff", "0800000000000000ff", "810101"];
+    const NON_CANONICAL_LENGTHS: &[&str] = &["8100", "817f", "82007f", "83000100"];
+
+    #[test]
+    fn var_uints() {
+        for (encoded, value) in VAR_UINTS {
+            let buffer = hex::decode(encoded).unwrap();
+            let mut reader = &buffer[..];
+            assert_eq!(reader.read_var_uint().unwrap(), *value, "{}", encoded);
+            assert!(reader.is_empty());
+            let mut writer = Vec::new();
+            writer.put_var_uint(*value);
+            assert_eq!(hex::encode(&writer), *encoded);
+        }
+    }
+
+    #[test]
+    fn var_octet_string_lengths() {
+        for (encoded, length) in VAR_OCTET_STRING_LENGTHS {
+            let buffer = hex::decode(encoded).unwrap();
+            let mut reader = &buffer[..];
+            assert_eq!(
+                reader.read_var_octet_string_length().unwrap(),
+                *length,
+                "{}",
+                encoded
+            );
+            let mut writer = Vec::new();
+            writer.put_var_octet_string_length(*length);
+            assert_eq!(hex::encode(&writer), *encoded);
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_length_prefixes() {
+        // Would overflow a u64
+        assert!((&hex::decode("89010203040506070809").unwrap()[..])
+            .read_var_octet_string_length()
+            .is_err());
+        assert!((&hex::decode("80").unwrap()[..])
+            .read_var_octet_string_length()
+            .is_err());
+        assert!((&hex::decode("09010203040506070809").unwrap()[..])
+            .read_var_uint()
+            .is_err());
+    }
+
+    #[cfg(feature = "strict")]
+    #[test]
+    fn strict_rejects_non_canonical_encodings() {
+        for encoded in NON_CANONICAL_VAR_UINTS {
+            let buffer = hex::decode(encoded).unwrap();
+            assert!((&buffer[..]).read_var_uint().is_err(), "{}", encoded);
+        }
+        for encoded in NON_CANONICAL_LENGTHS {
+            let buffer = hex::decode(encoded).unwrap();
+            assert!(
+                (&buffer[..]).read_var_octet_string_length().is_err(),
+                "{}",
+                encoded
+            );
+        }
+    }
+
+    #[cfg(not(feature = "strict"))]
+    #[test]
+    fn accepts_non_canonical_encodings() {
+        for encoded in NON_CANONICAL_VAR_UINTS {
+            let buffer = hex::decode(encoded).unwrap();
+            assert!((&buffer[..]).read_var_uint().is_ok(), "{}", encoded);
+        }
+        for encoded in NON_CANONICAL_LENGTHS {
+            let buffer = hex::decode(encoded).unwrap();
+            assert!(
+                (&buffer[..]).read_var_octet_string_length().is_ok(),
+                "{}",
+                encoded
+            );
+        }
+    }
+}
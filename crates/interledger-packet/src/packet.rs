@@ -7,19 +7,19 @@ use std::time::SystemTime;
 
 use byteorder::{BigEndian, ReadBytesExt};
 use bytes::{BufMut, BytesMut};
-use chrono::{DateTime, TimeZone, Utc};
+use chrono::{DateTime, Utc};
 
 use super::oer::{self, BufOerExt, MutBufOerExt};
+use super::redact::Redacted;
+use super::timestamp::{self, TIMESTAMP_LEN};
 use super::{ErrorCode, ParseError};
 
 const AMOUNT_LEN: usize = 8;
-const EXPIRY_LEN: usize = 17;
+const EXPIRY_LEN: usize = TIMESTAMP_LEN;
 const CONDITION_LEN: usize = 32;
 const FULFILLMENT_LEN: usize = 32;
 const ERROR_CODE_LEN: usize = 3;
 
-static INTERLEDGER_TIMESTAMP_FORMAT: &'static str = "%Y%m%d%H%M%S%3f";
-
 // TODO TryFrom([u8])
 #[derive(Clone, Copy, Debug, PartialEq)]
 #[repr(u8)]
@@ -118,12 +118,9 @@ impl Prepare {
         let content_len = content.len();
         let amount = content.read_u64::<BigEndian>()?;
 
-        let mut expires_at = [0x00; 17];
+        let mut expires_at = [0x00; EXPIRY_LEN];
         content.read_exact(&mut expires_at)?;
-        let expires_at = str::from_utf8(&expires_at[..])?;
-        let expires_at: DateTime<Utc> =
-            Utc.datetime_from_str(&expires_at, INTERLEDGER_TIMESTAMP_FORMAT)?;
-        let expires_at = SystemTime::from(expires_at);
+        let expires_at = timestamp::read_timestamp(&expires_at[..])?;
 
         // Skip execution condition.
         content.skip(CONDITION_LEN)?;
@@ -161,16 +158,14 @@ impl Prepare {
         self.expires_at
     }
 
+    /// Rewrites only the expiry bytes, leaving the rest of the packet as it is.
+    /// Errors without changing the packet if the expiry is after the year 9999.
     #[inline]
-    pub fn set_expires_at(&mut self, expires_at: SystemTime) {
-        self.expires_at = expires_at;
+    pub fn set_expires_at(&mut self, expires_at: SystemTime) -> Result<(), ParseError> {
         let offset = self.content_offset + AMOUNT_LEN;
-        write!(
-            &mut self.buffer[offset..],
-            "{}",
-            DateTime::<Utc>::from(expires_at).format(INTERLEDGER_TIMESTAMP_FORMAT),
-        )
-        .unwrap();
+        timestamp::write_timestamp(&mut self.buffer[offset..offset + EXPIRY_LEN], expires_at)?;
+        self.expires_at = expires_at;
+        Ok(())
     }
 
     /// The returned value always has a length of 32.
@@ -213,6 +208,8 @@ impl fmt::Debug for Prepare {
 }
 
 impl<'a> PrepareBuilder<'a> {
+    /// Expiries after the year 9999 cannot be encoded, so they are set to the last
+    /// millisecond of that year instead.
     pub fn build(&self) -> Prepare {
         const STATIC_LEN: usize = AMOUNT_LEN + EXPIRY_LEN + CONDITION_LEN;
         let destination_size = oer::predict_var_octet_string(self.destination.len());
@@ -225,16 +222,13 @@ impl<'a> PrepareBuilder<'a> {
         buffer.put_var_octet_string_length(content_len);
         let content_offset = buffer.len();
         buffer.put_u64_be(self.amount);
-
-        let mut writer = buffer.writer();
-        write!(
-            writer,
-            "{}",
-            DateTime::<Utc>::from(self.expires_at).format(INTERLEDGER_TIMESTAMP_FORMAT),
-        )
-        .unwrap();
-        let mut buffer = writer.into_inner();
-
+        let mut expires_at = self.expires_at;
+        let mut expiry = [0x00; EXPIRY_LEN];
+        if timestamp::write_timestamp(&mut expiry, expires_at).is_err() {
+            expires_at = timestamp::max_timestamp();
+            timestamp::write_timestamp(&mut expiry, expires_at).unwrap();
+        }
+        buffer.put_slice(&expiry[..]);
         buffer.put_slice(&self.execution_condition[..]);
         buffer.put_var_octet_string(self.destination);
         buffer.put_var_octet_string(self.data);
@@ -243,7 +237,7 @@ impl<'a> PrepareBuilder<'a> {
             buffer,
             content_offset,
             amount: self.amount,
-            expires_at: self.expires_at,
+            expires_at,
             data_offset: buf_size - data_size,
         }
     }
@@ -308,7 +302,7 @@ impl fmt::Debug for Fulfill {
         write!(
             f,
             "Fulfill {{ fulfillment: \"{}\", data_length: {} }}",
-            hex::encode(self.fulfillment()),
+            Redacted(hex::encode(self.fulfillment())),
             self.data().len()
         )
     }
@@ -576,6 +570,7 @@ mod test_packet {
 mod test_prepare {
     use super::*;
     use crate::fixtures::{self, PREPARE, PREPARE_BUILDER, PREPARE_BYTES};
+    use std::time::Duration;
 
     #[test]
     fn test_try_from() {
@@ -645,11 +640,30 @@ mod test_prepare {
             ..*PREPARE_BUILDER
         }
         .build();
-        prepare.set_expires_at(target_expiry);
+        prepare.set_expires_at(target_expiry).unwrap();
         assert_eq!(prepare.expires_at(), target_expiry);
         assert_eq!(BytesMut::from(prepare), *PREPARE_BYTES);
     }
 
+    #[test]
+    fn test_expiry_after_year_9999() {
+        let max_expiry = timestamp::max_timestamp();
+        let too_late = max_expiry + Duration::from_secs(1);
+        let mut prepare = PREPARE_BUILDER.build();
+        assert!(prepare.set_expires_at(too_late).is_err());
+        assert_eq!(prepare.expires_at(), PREPARE_BUILDER.expires_at);
+        assert_eq!(BytesMut::from(prepare), *PREPARE_BYTES);
+
+        let prepare = PrepareBuilder {
+            expires_at: too_late,
+            ..*PREPARE_BUILDER
+        }
+        .build();
+        assert_eq!(prepare.expires_at(), max_expiry);
+        let parsed = Prepare::try_from(BytesMut::from(prepare)).unwrap();
+        assert_eq!(parsed.expires_at(), max_expiry);
+    }
+
     #[test]
     fn test_execution_condition() {
         assert_eq!(PREPARE.execution_condition(), fixtures::EXECUTION_CONDITION,);
@@ -0,0 +1,65 @@
+//! Hiding secrets such as auth tokens and fulfillments from log output.
+//!
+//! Values wrapped in `Redacted` are printed as `[REDACTED]` unless developer mode
+//! has been turned on with `set_developer_mode`, which is meant for debugging locally.
+
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static DEVELOPER_MODE: AtomicBool = AtomicBool::new(false);
+
+const REDACTED: &str = "[REDACTED]";
+
+/// Turn developer mode on or off. While it is on, `Redacted` values are logged as-is.
+pub fn set_developer_mode(enabled: bool) {
+    DEVELOPER_MODE.store(enabled, Ordering::Relaxed);
+}
+
+/// Returns true if secrets should be printed in logs.
+pub fn developer_mode() -> bool {
+    DEVELOPER_MODE.load(Ordering::Relaxed)
+}
+
+/// Wraps a secret value so that it can be passed to the logging macros
+/// without revealing it.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Redacted<T>(pub T);
+
+impl<T: fmt::Display> fmt::Display for Redacted<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if developer_mode() {
+            self.0.fmt(f)
+        } else {
+            f.write_str(REDACTED)
+        }
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for Redacted<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if developer_mode() {
+            self.0.fmt(f)
+        } else {
+            f.write_str(REDACTED)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Both cases are in one test because the developer mode flag is global
+    #[test]
+    fn only_prints_secrets_in_developer_mode() {
+        assert_eq!(format!("{}", Redacted("secret")), "[REDACTED]");
+        assert_eq!(format!("{:?}", Redacted(Some("secret"))), "[REDACTED]");
+        set_developer_mode(true);
+        assert_eq!(format!("{}", Redacted("secret")), "secret");
+        assert_eq!(
+            format!("{:?}", Redacted(Some("secret"))),
+            "Some(\"secret\")"
+        );
+        set_developer_mode(false);
+    }
+}
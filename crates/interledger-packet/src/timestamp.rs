@@ -0,0 +1,122 @@
+//! Reading and writing the fixed-width `YYYYMMDDHHmmSSfff` timestamps used for Prepare expiries.
+//!
+//! Every forwarded Prepare has its expiry parsed and rewritten, so these avoid going through
+//! chrono's format string parser and write the digits straight into the packet buffer.
+
+use std::time::SystemTime;
+
+use chrono::{DateTime, Datelike, NaiveDate, Timelike, Utc};
+
+use super::ParseError;
+
+pub const TIMESTAMP_LEN: usize = 17;
+/// Years after this one do not fit in the four digits of the format
+const MAX_YEAR: i32 = 9999;
+
+#[inline]
+fn write_digits(buf: &mut [u8], mut value: u32) {
+    for byte in buf.iter_mut().rev() {
+        *byte = b'0' + (value % 10) as u8;
+        value /= 10;
+    }
+}
+
+#[inline]
+fn read_digits(buf: &[u8]) -> Option<u32> {
+    buf.iter().try_fold(0u32, |value, byte| {
+        if byte.is_ascii_digit() {
+            Some(value * 10 + u32::from(byte - b'0'))
+        } else {
+            None
+        }
+    })
+}
+
+/// Write the timestamp into the first 17 bytes of the buffer.
+/// Errors without writing anything if the year does not fit in four digits.
+#[inline]
+pub fn write_timestamp(buf: &mut [u8], time: SystemTime) -> Result<(), ParseError> {
+    let time = DateTime::<Utc>::from(time);
+    if time.year() < 0 || time.year() > MAX_YEAR {
+        return Err(ParseError::InvalidPacket(format!(
+            "Timestamp does not fit in four year digits: {}",
+            time.to_rfc3339()
+        )));
+    }
+    write_digits(&mut buf[0..4], time.year() as u32);
+    write_digits(&mut buf[4..6], time.month());
+    write_digits(&mut buf[6..8], time.day());
+    write_digits(&mut buf[8..10], time.hour());
+    write_digits(&mut buf[10..12], time.minute());
+    write_digits(&mut buf[12..14], time.second());
+    write_digits(&mut buf[14..17], time.timestamp_subsec_millis());
+    Ok(())
+}
+
+/// The latest time that can be written as a timestamp
+pub fn max_timestamp() -> SystemTime {
+    read_timestamp(b"99991231235959999").unwrap()
+}
+
+#[inline]
+pub fn read_timestamp(buf: &[u8]) -> Result<SystemTime, ParseError> {
+    let invalid = || ParseError::InvalidPacket(format!("Invalid timestamp: {:?}", buf));
+    if buf.len() != TIMESTAMP_LEN {
+        return Err(invalid());
+    }
+    let field = |start: usize, end: usize| read_digits(&buf[start..end]).ok_or_else(invalid);
+    let date = NaiveDate::from_ymd_opt(field(0, 4)? as i32, field(4, 6)?, field(6, 8)?)
+        .and_then(|date| {
+            date.and_hms_milli_opt(
+                field(8, 10).ok()?,
+                field(10, 12).ok()?,
+                field(12, 14).ok()?,
+                field(14, 17).ok()?,
+            )
+        })
+        .ok_or_else(invalid)?;
+    Ok(SystemTime::from(DateTime::<Utc>::from_utc(date, Utc)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use std::time::Duration;
+
+    #[test]
+    fn matches_chrono_format() {
+        let times = [
+            Utc.ymd(2017, 12, 23).and_hms_milli(1, 21, 40, 549),
+            Utc.ymd(2000, 1, 1).and_hms_milli(0, 0, 0, 0),
+            Utc.ymd(2099, 2, 28).and_hms_milli(23, 59, 59, 999),
+        ];
+        for time in times.iter() {
+            let mut buf = [0; TIMESTAMP_LEN];
+            write_timestamp(&mut buf, SystemTime::from(*time)).unwrap();
+            assert_eq!(
+                &buf[..],
+                time.format("%Y%m%d%H%M%S%3f").to_string().as_bytes()
+            );
+            assert_eq!(read_timestamp(&buf).unwrap(), SystemTime::from(*time));
+        }
+    }
+
+    #[test]
+    fn rejects_years_after_9999() {
+        let mut buf = [0; TIMESTAMP_LEN];
+        write_timestamp(&mut buf, max_timestamp()).unwrap();
+        assert_eq!(&buf[..], b"99991231235959999");
+        assert!(write_timestamp(&mut buf, max_timestamp() + Duration::from_millis(1)).is_err());
+        assert_eq!(&buf[..], b"99991231235959999");
+    }
+
+    #[test]
+    fn rejects_invalid_timestamps() {
+        assert!(read_timestamp(b"2017122301214054").is_err());
+        assert!(read_timestamp(b"2017122301214054x").is_err());
+        assert!(read_timestamp(b"20171323012140549").is_err());
+        assert!(read_timestamp(b"20171223250000000").is_err());
+        assert!(read_timestamp(b"2017-12-23T01:21:").is_err());
+    }
+}
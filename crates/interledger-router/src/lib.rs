@@ -23,6 +23,20 @@ mod router;
 
 pub use self::router::Router;
 
+/// A hint about how much an alternative next hop for a prefix delivers, learned from the
+/// routes it advertised to us.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RouteHint<I> {
+    /// The account the route goes through
+    pub account_id: I,
+    /// The asset the rate is quoted in
+    pub asset_code: String,
+    /// How many units of the destination's asset one unit of `asset_code` delivers along the path
+    pub rate: f64,
+    /// The largest amount, in units of `asset_code`, the path has the liquidity to carry
+    pub max_amount: Option<f64>,
+}
+
 /// A trait for Store implmentations that have ILP routing tables.
 pub trait RouterStore: AccountStore + Clone + Send + Sync + 'static {
     /// **Synchronously** return a copy of the routing table.
@@ -31,4 +45,18 @@ pub trait RouterStore: AccountStore + Clone + Send + Sync + 'static {
     /// This ensures that individual packets can be routed without hitting the underlying store.
     // TODO avoid using HashMap because it means it'll be cloned a lot
     fn routing_table(&self) -> HashMap<Bytes, <Self::Account as Account>::AccountId>;
+
+    /// **Synchronously** return the hints for the alternative routes to the prefix.
+    /// When there are hints for the prefix a packet is routed by, the Router prefers
+    /// the next hop that delivers the most to the destination over the one in the routing table.
+    fn route_hints(&self, _prefix: &[u8]) -> Vec<RouteHint<<Self::Account as Account>::AccountId>> {
+        Vec::new()
+    }
+
+    /// **Synchronously** return how many units of the given asset one unit of a packet amount
+    /// from the account (in the account's asset and scale) is worth at our local exchange rates,
+    /// or None if there is no rate for either asset.
+    fn local_rate(&self, _from: &Self::Account, _asset_code: &str) -> Option<f64> {
+        None
+    }
 }
@@ -1,10 +1,17 @@
-use super::RouterStore;
+use super::{RouteHint, RouterStore};
 use bytes::Bytes;
-use futures::{future::err, Future};
+use futures::{
+    future::{err, Either},
+    Future,
+};
 use interledger_packet::{ErrorCode, RejectBuilder};
 use interledger_service::*;
 use std::str;
 
+/// Hints that claim to deliver more than this many times as much as the routing table's next
+/// hop are ignored, so that a peer cannot attract all of the traffic with an implausible rate.
+const MAX_HINTED_IMPROVEMENT: f64 = 1.5;
+
 /// The router implements the IncomingService trait and uses the routing table
 /// to determine the `to` (or "next hop") Account for the given request.
 ///
@@ -12,6 +19,10 @@ use std::str;
 ///   - apply exchange rates or fees to the Prepare packet
 ///   - adjust account balances
 ///   - reduce the Prepare packet's expiry
+///
+/// When the store has hints about the alternative routes to the prefix a packet is
+/// routed by, including one for the routing table's next hop, the router sends it to the
+/// next hop that delivers the most units of the destination's asset for the packet's amount.
 #[derive(Clone)]
 pub struct Router<T, S> {
     store: T,
@@ -38,6 +49,7 @@ where
     fn handle_request(&mut self, request: IncomingRequest<T::Account>) -> Self::Future {
         let destination = Bytes::from(request.prepare.destination());
         let mut next_hop: Option<<T::Account as Account>::AccountId> = None;
+        let mut matching_prefix = Bytes::new();
         let routing_table = self.store.routing_table();
 
         // Check if we have a direct path for that account or if we need to scan through the routing table
@@ -48,8 +60,8 @@ where
                 account_id
             );
             next_hop = Some(*account_id);
+            matching_prefix = destination.clone();
         } else if !routing_table.is_empty() {
-            let mut matching_prefix = Bytes::new();
             for route in self.store.routing_table() {
                 trace!(
                     "Checking route: \"{}\" -> {}",
@@ -76,15 +88,38 @@ where
             warn!("Unable to route request because routing table is empty");
         }
 
+        if let Some(account_id) = next_hop {
+            let hints = self.store.route_hints(&matching_prefix);
+            if !hints.is_empty() {
+                if let Some(best_account_id) = best_hinted_next_hop(
+                    &self.store,
+                    &request.from,
+                    request.prepare.amount(),
+                    account_id,
+                    &hints,
+                ) {
+                    if best_account_id != account_id {
+                        debug!(
+                            "Routing to account {} instead of {} because it delivers more for prefix: \"{}\"",
+                            best_account_id,
+                            account_id,
+                            str::from_utf8(&matching_prefix[..]).unwrap_or("<not utf8>"),
+                        );
+                    }
+                    next_hop = Some(best_account_id);
+                }
+            }
+        }
+
         if let Some(account_id) = next_hop {
             let mut next = self.next.clone();
             Box::new(
                 self.store
                     .get_accounts(vec![account_id])
                     .map_err(move |_| {
-                        error!("No record found for account: {}", account_id);
+                        error!("Error loading account: {}", account_id);
                         RejectBuilder {
-                            code: ErrorCode::F02_UNREACHABLE,
+                            code: ErrorCode::T00_INTERNAL_ERROR,
                             message: &[],
                             triggered_by: &[],
                             data: &[],
@@ -92,8 +127,19 @@ where
                         .build()
                     })
                     .and_then(move |mut accounts| {
-                        let request = request.into_outgoing(accounts.remove(0));
-                        next.send_request(request)
+                        if let Some(account) = accounts.remove(0) {
+                            let request = request.into_outgoing(account);
+                            Either::A(next.send_request(request))
+                        } else {
+                            error!("No record found for account: {}", account_id);
+                            Either::B(err(RejectBuilder {
+                                code: ErrorCode::F02_UNREACHABLE,
+                                message: &[],
+                                triggered_by: &[],
+                                data: &[],
+                            }
+                            .build()))
+                        }
                     }),
             )
         } else {
@@ -109,6 +155,63 @@ where
     }
 }
 
+/// Pick the hinted next hop that delivers the most units of the destination's asset for the
+/// given amount, skipping the account the packet came from and the ones without the liquidity
+/// to carry it. Ties go to the current next hop.
+///
+/// The current next hop's hint is the reference for how much the others may plausibly deliver,
+/// so the current next hop is kept if it has none.
+fn best_hinted_next_hop<T: RouterStore>(
+    store: &T,
+    from: &T::Account,
+    amount: u64,
+    current: <T::Account as Account>::AccountId,
+    hints: &[RouteHint<<T::Account as Account>::AccountId>],
+) -> Option<<T::Account as Account>::AccountId> {
+    let delivered = |hint: &RouteHint<_>| {
+        if !hint.rate.is_finite() || hint.rate < 0.0 {
+            return None;
+        }
+        let source_amount = amount as f64 * store.local_rate(from, &hint.asset_code)?;
+        Some((source_amount, source_amount * hint.rate))
+    };
+    let max_delivered = hints
+        .iter()
+        .find(|hint| hint.account_id == current)
+        .and_then(&delivered)
+        .map(|(_, delivered)| delivered * MAX_HINTED_IMPROVEMENT)?;
+    hints
+        .iter()
+        .filter(|hint| hint.account_id != from.id())
+        .filter_map(|hint| {
+            let (source_amount, delivered) = delivered(hint)?;
+            if let Some(max_amount) = hint.max_amount {
+                // NaN maximums do not carry anything
+                if !(source_amount <= max_amount) {
+                    return None;
+                }
+            }
+            if delivered > max_delivered {
+                debug!(
+                    "Ignoring hint that account {} delivers {}, which is implausibly more than the routing table's next hop",
+                    hint.account_id, delivered
+                );
+                return None;
+            }
+            Some((hint.account_id, delivered))
+        })
+        .fold(None, |best, (account_id, delivered)| match best {
+            Some((best_account_id, best_delivered))
+                if best_delivered > delivered
+                    || (best_delivered == delivered && best_account_id == current) =>
+            {
+                best
+            }
+            _ => Some((account_id, delivered)),
+        })
+        .map(|(account_id, _delivered)| account_id)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -134,6 +237,7 @@ mod tests {
     #[derive(Clone)]
     struct TestStore {
         routes: HashMap<Bytes, u64>,
+        hints: HashMap<Bytes, Vec<RouteHint<u64>>>,
     }
 
     impl AccountStore for TestStore {
@@ -142,8 +246,11 @@ mod tests {
         fn get_accounts(
             &self,
             account_ids: Vec<<<Self as AccountStore>::Account as Account>::AccountId>,
-        ) -> Box<Future<Item = Vec<TestAccount>, Error = ()> + Send> {
-            Box::new(ok(account_ids.into_iter().map(TestAccount).collect()))
+        ) -> Box<Future<Item = Vec<Option<TestAccount>>, Error = ()> + Send> {
+            Box::new(ok(account_ids
+                .into_iter()
+                .map(|id| Some(TestAccount(id)))
+                .collect()))
         }
     }
 
@@ -151,6 +258,18 @@ mod tests {
         fn routing_table(&self) -> HashMap<Bytes, u64> {
             self.routes.clone()
         }
+
+        fn route_hints(&self, prefix: &[u8]) -> Vec<RouteHint<u64>> {
+            self.hints.get(prefix).cloned().unwrap_or_default()
+        }
+
+        fn local_rate(&self, _from: &TestAccount, asset_code: &str) -> Option<f64> {
+            match asset_code {
+                "ABC" => Some(1.0),
+                "XYZ" => Some(2.0),
+                _ => None,
+            }
+        }
     }
 
     #[test]
@@ -158,6 +277,7 @@ mod tests {
         let mut router = Router::new(
             TestStore {
                 routes: HashMap::new(),
+                hints: HashMap::new(),
             },
             outgoing_service_fn(|_| {
                 Ok(FulfillBuilder {
@@ -189,6 +309,7 @@ mod tests {
         let mut router = Router::new(
             TestStore {
                 routes: HashMap::from_iter(vec![(Bytes::from("example.other"), 1)].into_iter()),
+                hints: HashMap::new(),
             },
             outgoing_service_fn(|_| {
                 Ok(FulfillBuilder {
@@ -222,6 +343,7 @@ mod tests {
                 routes: HashMap::from_iter(
                     vec![(Bytes::from("example.destination"), 1)].into_iter(),
                 ),
+                hints: HashMap::new(),
             },
             outgoing_service_fn(|_| {
                 Ok(FulfillBuilder {
@@ -253,6 +375,7 @@ mod tests {
         let mut router = Router::new(
             TestStore {
                 routes: HashMap::from_iter(vec![(Bytes::from(""), 0)].into_iter()),
+                hints: HashMap::new(),
             },
             outgoing_service_fn(|_| {
                 Ok(FulfillBuilder {
@@ -284,6 +407,7 @@ mod tests {
         let mut router = Router::new(
             TestStore {
                 routes: HashMap::from_iter(vec![(Bytes::from("example."), 1)].into_iter()),
+                hints: HashMap::new(),
             },
             outgoing_service_fn(|_| {
                 Ok(FulfillBuilder {
@@ -324,6 +448,7 @@ mod tests {
                     ]
                     .into_iter(),
                 ),
+                hints: HashMap::new(),
             },
             outgoing_service_fn(move |request: OutgoingRequest<TestAccount>| {
                 *to_clone.lock() = Some(request.to.clone());
@@ -352,4 +477,143 @@ mod tests {
         assert!(result.is_ok());
         assert_eq!(to.lock().take().unwrap().0, 2);
     }
+
+    fn route_to_with_hints(hints: Vec<RouteHint<u64>>, amount: u64) -> u64 {
+        route_from_to_with_hints(0, hints, amount)
+    }
+
+    fn route_from_to_with_hints(from: u64, hints: Vec<RouteHint<u64>>, amount: u64) -> u64 {
+        let to: Arc<Mutex<Option<TestAccount>>> = Arc::new(Mutex::new(None));
+        let to_clone = to.clone();
+        let mut router = Router::new(
+            TestStore {
+                routes: HashMap::from_iter(vec![(Bytes::from("example."), 1)].into_iter()),
+                hints: HashMap::from_iter(vec![(Bytes::from("example."), hints)].into_iter()),
+            },
+            outgoing_service_fn(move |request: OutgoingRequest<TestAccount>| {
+                *to_clone.lock() = Some(request.to.clone());
+
+                Ok(FulfillBuilder {
+                    fulfillment: &[0; 32],
+                    data: &[],
+                }
+                .build())
+            }),
+        );
+
+        let result = router
+            .handle_request(IncomingRequest {
+                from: TestAccount(from),
+                prepare: PrepareBuilder {
+                    destination: b"example.destination",
+                    amount,
+                    execution_condition: &[1; 32],
+                    expires_at: UNIX_EPOCH,
+                    data: &[],
+                }
+                .build(),
+            })
+            .wait();
+        assert!(result.is_ok());
+        let to_account = to.lock().take().unwrap();
+        to_account.0
+    }
+
+    #[test]
+    fn prefers_route_that_delivers_most() {
+        let hints = vec![
+            RouteHint {
+                account_id: 1,
+                asset_code: "ABC".to_string(),
+                rate: 1.5,
+                max_amount: None,
+            },
+            RouteHint {
+                account_id: 2,
+                asset_code: "XYZ".to_string(),
+                rate: 1.0,
+                max_amount: None,
+            },
+        ];
+        assert_eq!(route_to_with_hints(hints, 100), 2);
+    }
+
+    #[test]
+    fn skips_routes_without_enough_liquidity() {
+        let hints = vec![
+            RouteHint {
+                account_id: 1,
+                asset_code: "ABC".to_string(),
+                rate: 1.5,
+                max_amount: None,
+            },
+            RouteHint {
+                account_id: 2,
+                asset_code: "XYZ".to_string(),
+                rate: 1.0,
+                max_amount: Some(100.0),
+            },
+        ];
+        assert_eq!(route_to_with_hints(hints, 100), 1);
+    }
+
+    #[test]
+    fn keeps_routing_table_next_hop_on_tie() {
+        let hints = vec![
+            RouteHint {
+                account_id: 2,
+                asset_code: "ABC".to_string(),
+                rate: 2.0,
+                max_amount: None,
+            },
+            RouteHint {
+                account_id: 1,
+                asset_code: "XYZ".to_string(),
+                rate: 1.0,
+                max_amount: None,
+            },
+        ];
+        assert_eq!(route_to_with_hints(hints, 100), 1);
+    }
+
+    #[test]
+    fn ignores_hints_without_local_rate() {
+        let hints = vec![RouteHint {
+            account_id: 2,
+            asset_code: "EUR".to_string(),
+            rate: 100.0,
+            max_amount: None,
+        }];
+        assert_eq!(route_to_with_hints(hints, 100), 1);
+    }
+
+    fn hint(account_id: u64, rate: f64) -> RouteHint<u64> {
+        RouteHint {
+            account_id,
+            asset_code: "ABC".to_string(),
+            rate,
+            max_amount: None,
+        }
+    }
+
+    #[test]
+    fn does_not_route_back_to_sender() {
+        let hints = vec![hint(1, 1.0), hint(2, 1.2)];
+        assert_eq!(route_from_to_with_hints(2, hints.clone(), 100), 1);
+        assert_eq!(route_from_to_with_hints(0, hints, 100), 2);
+    }
+
+    #[test]
+    fn ignores_implausible_hints() {
+        assert_eq!(
+            route_to_with_hints(vec![hint(1, 1.0), hint(2, 100.0)], 100),
+            1
+        );
+        assert_eq!(
+            route_to_with_hints(vec![hint(1, 1.0), hint(2, std::f64::NAN)], 100),
+            1
+        );
+        // Without a hint for the routing table's next hop, there is nothing to compare with
+        assert_eq!(route_to_with_hints(vec![hint(2, 1.2)], 100), 1);
+    }
 }
@@ -0,0 +1,183 @@
+use bytes::Bytes;
+use futures::Future;
+use interledger_ccp::{CcpRoutingAccount, RoutingRelation};
+use interledger_packet::{is_within_prefix, Reject, RejectBuilder};
+use interledger_service::*;
+use std::{
+    str,
+    sync::{Arc, RwLock},
+};
+
+/// # Address Space Service
+///
+/// Stops peers from impersonating other parts of the network in the Reject packets they
+/// send back. The `triggered_by` address of a Reject from an account is replaced with the
+/// account's own address if it claims address space the account does not have:
+///
+/// - Our own address or the addresses under it, unless they are also under the account's address
+/// - For our children, any address outside of the child's own address
+///
+/// This should be the last OutgoingService before the packets are sent to the peers,
+/// because the Rejects of the services after it are also checked.
+#[derive(Clone)]
+pub struct AddressSpaceService<S> {
+    next: S,
+    ilp_address: Arc<RwLock<Bytes>>,
+}
+
+impl<S> AddressSpaceService<S> {
+    /// Create the service with our own address. The address can be changed while the node
+    /// is running by writing to the lock, which is shared with the clones of the service.
+    pub fn new(ilp_address: Arc<RwLock<Bytes>>, next: S) -> Self {
+        AddressSpaceService { next, ilp_address }
+    }
+}
+
+// Whether the account is not allowed to claim that the address triggered its Reject
+fn is_spoofed(
+    triggered_by: &[u8],
+    account_address: &[u8],
+    relation: RoutingRelation,
+    ilp_address: &[u8],
+) -> bool {
+    if triggered_by.is_empty() || is_within_prefix(triggered_by, account_address) {
+        return false;
+    }
+    relation == RoutingRelation::Child || is_within_prefix(triggered_by, ilp_address)
+}
+
+fn check_reject(
+    reject: Reject,
+    account_address: &[u8],
+    relation: RoutingRelation,
+    ilp_address: &[u8],
+) -> Reject {
+    if !is_spoofed(
+        reject.triggered_by(),
+        account_address,
+        relation,
+        ilp_address,
+    ) {
+        return reject;
+    }
+    warn!(
+        "Reject from {} claimed to be triggered by {}, replacing it with the account's address",
+        str::from_utf8(account_address).unwrap_or("<not utf8>"),
+        str::from_utf8(reject.triggered_by()).unwrap_or("<not utf8>"),
+    );
+    RejectBuilder {
+        code: reject.code(),
+        message: reject.message(),
+        triggered_by: account_address,
+        data: reject.data(),
+    }
+    .build()
+}
+
+impl<S, A> OutgoingService<A> for AddressSpaceService<S>
+where
+    S: OutgoingService<A>,
+    A: CcpRoutingAccount,
+{
+    type Future = BoxedIlpFuture;
+
+    fn send_request(&mut self, request: OutgoingRequest<A>) -> Self::Future {
+        let account_address = Bytes::from(request.to.client_address());
+        let relation = request.to.routing_relation();
+        let ilp_address = self.ilp_address.clone();
+        Box::new(self.next.send_request(request).map_err(move |reject| {
+            check_reject(
+                reject,
+                &account_address,
+                relation,
+                &ilp_address.read().unwrap(),
+            )
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PEER: RoutingRelation = RoutingRelation::Peer;
+    const CHILD: RoutingRelation = RoutingRelation::Child;
+
+    #[test]
+    fn peers_cannot_claim_our_address_space() {
+        let ilp_address = b"example.connector";
+        assert!(is_spoofed(
+            b"example.connector",
+            b"example.peer",
+            PEER,
+            ilp_address
+        ));
+        assert!(is_spoofed(
+            b"example.connector.child",
+            b"example.peer",
+            PEER,
+            ilp_address
+        ));
+        assert!(!is_spoofed(
+            b"example.other",
+            b"example.peer",
+            PEER,
+            ilp_address
+        ));
+        assert!(!is_spoofed(
+            b"example.peer.x",
+            b"example.peer",
+            PEER,
+            ilp_address
+        ));
+        assert!(!is_spoofed(b"", b"example.peer", PEER, ilp_address));
+    }
+
+    #[test]
+    fn children_can_only_claim_their_own_address_space() {
+        let ilp_address = b"example.connector";
+        let child = b"example.connector.child";
+        assert!(!is_spoofed(
+            b"example.connector.child.x",
+            child,
+            CHILD,
+            ilp_address
+        ));
+        assert!(is_spoofed(
+            b"example.connector.other",
+            child,
+            CHILD,
+            ilp_address
+        ));
+        assert!(is_spoofed(b"example.peer", child, CHILD, ilp_address));
+        // The relation is configured, so children whose addresses are not under ours are
+        // still children
+        assert!(is_spoofed(
+            b"example.peer",
+            b"other.child",
+            CHILD,
+            ilp_address
+        ));
+        // and peers whose addresses are under ours are still peers
+        assert!(!is_spoofed(
+            b"example.other",
+            b"example.connector.peer",
+            PEER,
+            ilp_address
+        ));
+    }
+
+    #[test]
+    fn replaces_spoofed_triggered_by() {
+        let reject = RejectBuilder {
+            code: interledger_packet::ErrorCode::F02_UNREACHABLE,
+            message: b"No route",
+            triggered_by: b"example.connector.other",
+            data: &[],
+        }
+        .build();
+        let reject = check_reject(reject, b"example.peer", PEER, b"example.connector");
+        assert_eq!(reject.triggered_by(), b"example.peer");
+        assert_eq!(reject.message(), b"No route");
+    }
+}
@@ -0,0 +1,227 @@
+use futures::{future::err, Future};
+use interledger_packet::{ErrorCode, RejectBuilder};
+use interledger_service::*;
+use rand::{thread_rng, Rng};
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime},
+};
+use tokio::timer::Delay;
+
+/// The failures a `FailureInjectionService` introduces. These can be changed while the
+/// service is running, for example through the node's API. By default no failures are injected.
+#[derive(Debug, Default)]
+pub struct FailureInjection {
+    drop_percent: AtomicUsize,
+    delay: AtomicUsize,
+}
+
+impl FailureInjection {
+    /// Percentage (0-100) of packets that are dropped
+    pub fn drop_percent(&self) -> u8 {
+        self.drop_percent.load(Ordering::Relaxed) as u8
+    }
+
+    /// Drop this percentage of the packets. Values over 100 drop every packet.
+    pub fn set_drop_percent(&self, percent: u8) {
+        self.drop_percent
+            .store(percent.min(100) as usize, Ordering::Relaxed);
+    }
+
+    /// Milliseconds that each packet is delayed by
+    pub fn delay(&self) -> u64 {
+        self.delay.load(Ordering::Relaxed) as u64
+    }
+
+    pub fn set_delay(&self, delay: u64) {
+        self.delay.store(delay as usize, Ordering::Relaxed);
+    }
+
+    fn should_drop(&self) -> bool {
+        let percent = self.drop_percent.load(Ordering::Relaxed);
+        percent > 0 && thread_rng().gen_range(0, 100) < percent
+    }
+}
+
+/// # Failure Injection Service
+///
+/// Drops and delays packets according to a shared `FailureInjection`, so operators and CI
+/// can check how the node and its peers behave when parts of the network fail.
+///
+/// Dropped packets are not passed on. They are rejected with `R00: Transfer Timed Out` once
+/// they expire, as if they were lost on the way to the next hop. Delayed packets wait before
+/// they are passed on, so placing this service in front of one that calls the store simulates
+/// a slow database.
+///
+/// This is only available with the `chaos` feature and should never be enabled in production.
+#[derive(Clone)]
+pub struct FailureInjectionService<S> {
+    next: S,
+    failures: Arc<FailureInjection>,
+}
+
+impl<S> FailureInjectionService<S> {
+    pub fn new(failures: Arc<FailureInjection>, next: S) -> Self {
+        FailureInjectionService { next, failures }
+    }
+}
+
+fn drop_until(expires_at: SystemTime) -> BoxedIlpFuture {
+    let remaining = expires_at
+        .duration_since(SystemTime::now())
+        .unwrap_or_else(|_| Duration::from_secs(0));
+    debug!(
+        "Dropping packet (it will be rejected when it expires in {}ms)",
+        remaining.as_millis()
+    );
+    Box::new(Delay::new(Instant::now() + remaining).then(|_| {
+        err(RejectBuilder {
+            code: ErrorCode::R00_TRANSFER_TIMED_OUT,
+            message: b"Packet was dropped by failure injection",
+            triggered_by: &[],
+            data: &[],
+        }
+        .build())
+    }))
+}
+
+impl<S, A> IncomingService<A> for FailureInjectionService<S>
+where
+    S: IncomingService<A> + Clone + Send + 'static,
+    A: Account + 'static,
+{
+    type Future = BoxedIlpFuture;
+
+    fn handle_request(&mut self, request: IncomingRequest<A>) -> Self::Future {
+        if self.failures.should_drop() {
+            return drop_until(request.prepare.expires_at());
+        }
+        let delay = self.failures.delay();
+        if delay == 0 {
+            return Box::new(self.next.handle_request(request));
+        }
+        let mut next = self.next.clone();
+        Box::new(
+            Delay::new(Instant::now() + Duration::from_millis(delay))
+                .then(move |_| next.handle_request(request)),
+        )
+    }
+}
+
+impl<S, A> OutgoingService<A> for FailureInjectionService<S>
+where
+    S: OutgoingService<A> + Clone + Send + 'static,
+    A: Account + 'static,
+{
+    type Future = BoxedIlpFuture;
+
+    fn send_request(&mut self, request: OutgoingRequest<A>) -> Self::Future {
+        if self.failures.should_drop() {
+            return drop_until(request.prepare.expires_at());
+        }
+        let delay = self.failures.delay();
+        if delay == 0 {
+            return Box::new(self.next.send_request(request));
+        }
+        let mut next = self.next.clone();
+        Box::new(
+            Delay::new(Instant::now() + Duration::from_millis(delay))
+                .then(move |_| next.send_request(request)),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use interledger_packet::{Fulfill, FulfillBuilder, PrepareBuilder, Reject};
+    use tokio::runtime::Runtime;
+
+    #[derive(Clone, Debug)]
+    struct TestAccount(u64);
+
+    impl Account for TestAccount {
+        type AccountId = u64;
+
+        fn id(&self) -> u64 {
+            self.0
+        }
+    }
+
+    fn request(expires_in: Duration) -> IncomingRequest<TestAccount> {
+        IncomingRequest {
+            from: TestAccount(0),
+            prepare: PrepareBuilder {
+                destination: b"example.destination",
+                amount: 100,
+                expires_at: SystemTime::now() + expires_in,
+                execution_condition: &[0; 32],
+                data: &[],
+            }
+            .build(),
+        }
+    }
+
+    fn fulfill() -> Result<Fulfill, Reject> {
+        Ok(FulfillBuilder {
+            fulfillment: &[0; 32],
+            data: &[],
+        }
+        .build())
+    }
+
+    #[test]
+    fn passes_packets_through_by_default() {
+        let mut service = FailureInjectionService::new(
+            Arc::new(FailureInjection::default()),
+            incoming_service_fn(|_| fulfill()),
+        );
+        let result = Runtime::new()
+            .unwrap()
+            .block_on(service.handle_request(request(Duration::from_secs(30))));
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn rejects_dropped_packets_when_they_expire() {
+        let failures = Arc::new(FailureInjection::default());
+        failures.set_drop_percent(100);
+        let mut service = FailureInjectionService::new(
+            failures,
+            incoming_service_fn(|_| -> Result<Fulfill, Reject> {
+                panic!("Dropped packet should not be passed on")
+            }),
+        );
+        let start = Instant::now();
+        let reject = Runtime::new()
+            .unwrap()
+            .block_on(service.handle_request(request(Duration::from_millis(100))))
+            .unwrap_err();
+        assert_eq!(reject.code(), ErrorCode::R00_TRANSFER_TIMED_OUT);
+        assert!(start.elapsed() >= Duration::from_millis(90));
+    }
+
+    #[test]
+    fn delays_packets() {
+        let failures = Arc::new(FailureInjection::default());
+        failures.set_delay(100);
+        let mut service =
+            FailureInjectionService::new(failures, incoming_service_fn(|_| fulfill()));
+        let start = Instant::now();
+        let result = Runtime::new()
+            .unwrap()
+            .block_on(service.handle_request(request(Duration::from_secs(30))));
+        assert!(result.is_ok());
+        assert!(start.elapsed() >= Duration::from_millis(100));
+    }
+
+    #[test]
+    fn drop_percent_is_capped_at_100() {
+        let failures = FailureInjection::default();
+        failures.set_drop_percent(150);
+        assert_eq!(failures.drop_percent(), 100);
+    }
+}
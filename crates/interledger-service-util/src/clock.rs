@@ -0,0 +1,263 @@
+use futures::{
+    future::{err, loop_fn, result, Either, Loop},
+    sync::oneshot,
+    Future,
+};
+use ring::rand::{SecureRandom, SystemRandom};
+use std::{
+    net::{SocketAddr, ToSocketAddrs},
+    sync::{
+        atomic::{AtomicBool, AtomicIsize, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::{net::UdpSocket, prelude::FutureExt};
+
+// Seconds between the NTP epoch (1900) and the UNIX epoch (1970)
+const NTP_UNIX_OFFSET: i64 = 2_208_988_800;
+const NTP_PACKET_LENGTH: usize = 48;
+const NTP_TIMEOUT: Duration = Duration::from_secs(5);
+// The most the node's time is corrected by, so that a wrong measurement cannot make it accept
+// long-expired packets or reject all of them
+const MAX_COMPENSATION_MILLIS: i64 = 60_000;
+
+/// How far the node's clock is from a reference clock, such as an NTP server.
+///
+/// Packets' expiries are checked against the node's clock, so a node whose clock is behind
+/// accepts packets that have already expired and one whose clock is ahead rejects packets
+/// that have not. If compensation is enabled, `now` corrects for the last measured skew.
+#[derive(Debug, Default)]
+pub struct ClockSkew {
+    offset: AtomicIsize,
+    compensate: AtomicBool,
+}
+
+impl ClockSkew {
+    /// Create a skew of 0 that is only applied to the time if `compensate` is set.
+    pub fn new(compensate: bool) -> Self {
+        ClockSkew {
+            offset: AtomicIsize::new(0),
+            compensate: AtomicBool::new(compensate),
+        }
+    }
+
+    /// Milliseconds to add to the node's clock to get the reference time.
+    /// This is positive if the node's clock is behind.
+    pub fn offset(&self) -> i64 {
+        self.offset.load(Ordering::Relaxed) as i64
+    }
+
+    pub fn set_offset(&self, offset: i64) {
+        self.offset.store(offset as isize, Ordering::Relaxed);
+    }
+
+    /// The current time, corrected by the measured skew if compensation is enabled.
+    /// The correction is at most a minute either way.
+    pub fn now(&self) -> SystemTime {
+        let now = SystemTime::now();
+        if !self.compensate.load(Ordering::Relaxed) {
+            return now;
+        }
+        let offset = self
+            .offset()
+            .max(-MAX_COMPENSATION_MILLIS)
+            .min(MAX_COMPENSATION_MILLIS);
+        if offset >= 0 {
+            now + Duration::from_millis(offset as u64)
+        } else {
+            now - Duration::from_millis(offset.abs() as u64)
+        }
+    }
+}
+
+fn millis_since_unix_epoch(time: SystemTime) -> i64 {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(duration) => duration.as_millis() as i64,
+        Err(err) => -(err.duration().as_millis() as i64),
+    }
+}
+
+// An NTP timestamp is 32 bits of seconds since 1900 followed by 32 bits of fractions of a second
+fn ntp_timestamp_millis(bytes: &[u8]) -> i64 {
+    let mut seconds = [0; 4];
+    let mut fraction = [0; 4];
+    seconds.copy_from_slice(&bytes[..4]);
+    fraction.copy_from_slice(&bytes[4..8]);
+    let seconds = i64::from(u32::from_be_bytes(seconds)) - NTP_UNIX_OFFSET;
+    let fraction = i64::from(u32::from_be_bytes(fraction));
+    seconds * 1000 + ((fraction * 1000) >> 32)
+}
+
+/// The offset of the local clock from the server's in an NTP response, given the transmit
+/// timestamp of the request, which the server echoes, and when the request was sent and the
+/// response was received (in milliseconds since the UNIX epoch).
+fn ntp_offset(response: &[u8], nonce: &[u8], sent: i64, received: i64) -> Result<i64, ()> {
+    if response.len() < NTP_PACKET_LENGTH {
+        warn!("NTP response is too short: {} bytes", response.len());
+        return Err(());
+    }
+    if &response[24..32] != nonce {
+        warn!("NTP response is not for the request the node sent");
+        return Err(());
+    }
+    // The server's receive and transmit timestamps. A transmit timestamp of 0 means the
+    // server did not answer the request, for example because it is rate limiting us
+    let server_received = ntp_timestamp_millis(&response[32..40]);
+    if response[40..48].iter().all(|byte| *byte == 0) {
+        warn!("NTP server did not send its time");
+        return Err(());
+    }
+    let server_sent = ntp_timestamp_millis(&response[40..48]);
+    Ok(((server_received - sent) + (server_sent - received)) / 2)
+}
+
+/// Resolve the NTP server's host on another thread, because the standard library's
+/// resolver blocks.
+fn resolve(server: &str) -> impl Future<Item = SocketAddr, Error = ()> {
+    let (sender, receiver) = oneshot::channel();
+    let server_name = server.to_string();
+    thread::spawn(move || {
+        let address = server_name
+            .to_socket_addrs()
+            .map_err(|err| warn!("Unable to resolve NTP server {}: {:?}", server_name, err))
+            .and_then(|mut addresses| {
+                addresses
+                    .next()
+                    .ok_or_else(|| warn!("NTP server {} has no addresses", server_name))
+            });
+        let _ = sender.send(address);
+    });
+    receiver
+        .map_err(|_| warn!("NTP server was not resolved"))
+        .and_then(|address| address)
+}
+
+/// Measure the offset of the node's clock from the time of the NTP server at `server`
+/// (a host and port, such as `pool.ntp.org:123`), in milliseconds. The offset is positive
+/// if the node's clock is behind.
+///
+/// Only responses from the server's address that echo the random transmit timestamp of the
+/// request are used, so that other hosts cannot easily spoof the time.
+pub fn measure_clock_skew(server: &str) -> impl Future<Item = i64, Error = ()> {
+    // Version 4, client mode
+    let mut request = vec![0; NTP_PACKET_LENGTH];
+    request[0] = 0x23;
+    if SystemRandom::new().fill(&mut request[40..48]).is_err() {
+        warn!("Unable to generate the transmit timestamp of the NTP request");
+        return Either::A(err(()));
+    }
+    let nonce = request[40..48].to_vec();
+    let server_name = server.to_string();
+    Either::B(
+        resolve(server)
+            .and_then(move |address| {
+                let local: SocketAddr = if address.is_ipv4() {
+                    ([0, 0, 0, 0], 0).into()
+                } else {
+                    ([0u16; 8], 0).into()
+                };
+                result(UdpSocket::bind(&local))
+                    .and_then(move |socket| {
+                        let sent = millis_since_unix_epoch(SystemTime::now());
+                        socket
+                            .send_dgram(request, &address)
+                            .map(move |(socket, _)| (socket, sent))
+                    })
+                    .and_then(move |(socket, sent)| {
+                        loop_fn(socket, move |socket| {
+                            socket.recv_dgram(vec![0; NTP_PACKET_LENGTH]).map(
+                                move |(socket, response, length, from)| {
+                                    if from != address {
+                                        debug!("Ignoring datagram from {}", from);
+                                        return Loop::Continue(socket);
+                                    }
+                                    let received = millis_since_unix_epoch(SystemTime::now());
+                                    Loop::Break((response, length, sent, received))
+                                },
+                            )
+                        })
+                    })
+                    .timeout(NTP_TIMEOUT)
+                    .map_err(move |err| {
+                        warn!("Error querying NTP server {}: {:?}", server_name, err)
+                    })
+            })
+            .and_then(move |(response, length, sent, received)| {
+                ntp_offset(&response[..length], &nonce, sent, received)
+            }),
+    )
+}
+
+/// Measure the node's clock skew against the NTP server and save it, warning if it is more
+/// than `max_skew`. If the server cannot be reached, the last measured skew is kept.
+pub fn check_clock_skew(
+    clock: Arc<ClockSkew>,
+    server: &str,
+    max_skew: Duration,
+) -> impl Future<Item = (), Error = ()> {
+    let server_name = server.to_string();
+    measure_clock_skew(server).map(move |offset| {
+        clock.set_offset(offset);
+        if offset.abs() as u128 > max_skew.as_millis() {
+            warn!(
+                "The clock is {}ms {} {}. Packets' expiries are not checked correctly unless the clock is synchronized or skew compensation is enabled",
+                offset.abs(),
+                if offset > 0 { "behind" } else { "ahead of" },
+                server_name
+            );
+        } else {
+            debug!("The clock is {}ms off from {}", offset, server_name);
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ntp_timestamp(millis: i64) -> [u8; 8] {
+        let seconds = (millis / 1000 + NTP_UNIX_OFFSET) as u32;
+        let fraction = (((millis % 1000) << 32) / 1000) as u32;
+        let mut bytes = [0; 8];
+        bytes[..4].copy_from_slice(&seconds.to_be_bytes());
+        bytes[4..].copy_from_slice(&fraction.to_be_bytes());
+        bytes
+    }
+
+    #[test]
+    fn calculates_offset_from_ntp_response() {
+        // The server's clock is 1000ms ahead and each direction takes 50ms
+        let mut response = vec![0; NTP_PACKET_LENGTH];
+        response[32..40].copy_from_slice(&ntp_timestamp(1_560_000_001_050));
+        response[40..48].copy_from_slice(&ntp_timestamp(1_560_000_001_060));
+        response[24..32].copy_from_slice(&[1; 8]);
+        let offset = ntp_offset(&response, &[1; 8], 1_560_000_000_000, 1_560_000_000_110).unwrap();
+        assert!((offset - 1000).abs() <= 1);
+        // Responses must echo the request's transmit timestamp
+        assert!(ntp_offset(&response, &[2; 8], 1_560_000_000_000, 1_560_000_000_110).is_err());
+    }
+
+    #[test]
+    fn rejects_response_without_time() {
+        let response = vec![0; NTP_PACKET_LENGTH];
+        assert!(ntp_offset(&response, &[0; 8], 0, 0).is_err());
+        assert!(ntp_offset(&response[..10], &[0; 8], 0, 0).is_err());
+    }
+
+    #[test]
+    fn only_compensates_if_enabled() {
+        let clock = ClockSkew::new(false);
+        clock.set_offset(60_000);
+        assert!(clock.now() < SystemTime::now() + Duration::from_secs(30));
+        let clock = ClockSkew::new(true);
+        clock.set_offset(60_000);
+        assert!(clock.now() > SystemTime::now() + Duration::from_secs(30));
+        clock.set_offset(-60_000);
+        assert!(clock.now() < SystemTime::now() - Duration::from_secs(30));
+        // The correction is capped
+        clock.set_offset(3_600_000);
+        assert!(clock.now() < SystemTime::now() + Duration::from_secs(90));
+    }
+}
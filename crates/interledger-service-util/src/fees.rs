@@ -0,0 +1,52 @@
+use interledger_service::AccountStore;
+use serde::{Deserialize, Serialize};
+use std::{fmt, str::FromStr};
+
+/// A fee charged for forwarding a packet, in addition to the exchange rate spread.
+///
+/// The fee is denominated in the asset of the account the packet came from and
+/// is deducted from the amount before it is converted to the outgoing asset.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+pub struct FeePolicy {
+    /// Fixed amount charged per packet
+    #[serde(default)]
+    pub fixed: u64,
+    /// Fraction of the packet amount charged (for example, 0.001 is 0.1%)
+    #[serde(default)]
+    pub proportional: f64,
+}
+
+impl FeePolicy {
+    /// Calculate the fee for a packet of the given amount.
+    pub fn fee(&self, amount: u64) -> u64 {
+        let proportional = (amount as f64 * self.proportional) as u64;
+        self.fixed.saturating_add(proportional)
+    }
+}
+
+/// Fee policies are stored as "{fixed}:{proportional}"
+impl FromStr for FeePolicy {
+    type Err = ();
+
+    fn from_str(string: &str) -> Result<Self, ()> {
+        let mut parts = string.splitn(2, ':');
+        let fixed = parts.next().ok_or(())?.parse().map_err(|_| ())?;
+        let proportional = parts.next().ok_or(())?.parse().map_err(|_| ())?;
+        Ok(FeePolicy {
+            fixed,
+            proportional,
+        })
+    }
+}
+
+impl fmt::Display for FeePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.fixed, self.proportional)
+    }
+}
+
+pub trait FeeStore: AccountStore {
+    /// Get the fee policy that applies to packets from the given account to the given destination.
+    /// Policies configured for the account take precedence over ones configured for a destination prefix.
+    fn get_fee_policy(&self, from_account: &Self::Account, destination: &[u8]) -> Option<FeePolicy>;
+}
@@ -0,0 +1,163 @@
+use super::sha256;
+use futures::Future;
+use interledger_packet::{ErrorCode, Fulfill, Reject, RejectBuilder};
+use interledger_service::*;
+use ring::{
+    constant_time::verify_slices_are_equal,
+    digest::{digest, SHA256},
+};
+
+/// Returns true if the SHA-256 hash of the fulfillment matches the condition.
+///
+/// The hash is compared in constant time so that the check does not leak how much of a
+/// guessed fulfillment was correct. It is computed with the CPU's SHA extensions where
+/// they are available.
+pub fn verify_fulfillment(fulfillment: &[u8], condition: &[u8]) -> bool {
+    match sha256::digest_fulfillment(fulfillment) {
+        Some(generated_condition) => {
+            verify_slices_are_equal(&generated_condition[..], condition).is_ok()
+        }
+        None => {
+            let generated_condition = digest(&SHA256, fulfillment);
+            verify_slices_are_equal(generated_condition.as_ref(), condition).is_ok()
+        }
+    }
+}
+
+/// Checks that the Fulfill packets returned by the next service match the
+/// execution condition of the Prepare they are responding to.
+///
+/// Fulfill packets with the wrong fulfillment are turned into `F05` rejections,
+/// so services in front of this one (for example the balance service) never
+/// credit or debit an account for a packet that was not actually fulfilled.
+#[derive(Clone)]
+pub struct FulfillmentValidatorService<S> {
+    next: S,
+}
+
+impl<S> FulfillmentValidatorService<S> {
+    pub fn new(next: S) -> Self {
+        FulfillmentValidatorService { next }
+    }
+}
+
+fn check_fulfillment(condition: [u8; 32], fulfill: Fulfill) -> Result<Fulfill, Reject> {
+    if verify_fulfillment(fulfill.fulfillment(), &condition[..]) {
+        Ok(fulfill)
+    } else {
+        error!(
+            "Fulfillment did not match condition. Fulfillment: {}, condition: {}",
+            hex::encode(fulfill.fulfillment()),
+            hex::encode(&condition[..])
+        );
+        Err(RejectBuilder {
+            code: ErrorCode::F05_WRONG_CONDITION,
+            message: b"Fulfillment did not match condition",
+            triggered_by: &[],
+            data: &[],
+        }
+        .build())
+    }
+}
+
+impl<S, A> IncomingService<A> for FulfillmentValidatorService<S>
+where
+    S: IncomingService<A>,
+    A: Account,
+{
+    type Future = BoxedIlpFuture;
+
+    fn handle_request(&mut self, request: IncomingRequest<A>) -> Self::Future {
+        let mut condition: [u8; 32] = [0; 32];
+        condition[..].copy_from_slice(request.prepare.execution_condition());
+        Box::new(
+            self.next
+                .handle_request(request)
+                .and_then(move |fulfill| check_fulfillment(condition, fulfill)),
+        )
+    }
+}
+
+impl<S, A> OutgoingService<A> for FulfillmentValidatorService<S>
+where
+    S: OutgoingService<A>,
+    A: Account,
+{
+    type Future = BoxedIlpFuture;
+
+    fn send_request(&mut self, request: OutgoingRequest<A>) -> Self::Future {
+        let mut condition: [u8; 32] = [0; 32];
+        condition[..].copy_from_slice(request.prepare.execution_condition());
+        Box::new(
+            self.next
+                .send_request(request)
+                .and_then(move |fulfill| check_fulfillment(condition, fulfill)),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use interledger_packet::{FulfillBuilder, PrepareBuilder};
+    use std::time::{Duration, SystemTime};
+
+    #[derive(Clone, Debug)]
+    struct TestAccount(u64);
+
+    impl Account for TestAccount {
+        type AccountId = u64;
+
+        fn id(&self) -> u64 {
+            self.0
+        }
+    }
+
+    // SHA-256 of 32 zero bytes
+    const CONDITION: [u8; 32] = [
+        102, 104, 122, 173, 248, 98, 189, 119, 108, 143, 193, 139, 142, 159, 142, 32, 8, 151, 20,
+        133, 110, 226, 51, 179, 144, 42, 89, 29, 13, 95, 41, 37,
+    ];
+
+    fn send_with_fulfillment(fulfillment: [u8; 32]) -> Result<Fulfill, Reject> {
+        let mut service = FulfillmentValidatorService::new(outgoing_service_fn(move |_| {
+            Ok(FulfillBuilder {
+                fulfillment: &fulfillment,
+                data: &[],
+            }
+            .build())
+        }));
+        service
+            .send_request(OutgoingRequest {
+                from: TestAccount(1),
+                to: TestAccount(2),
+                prepare: PrepareBuilder {
+                    destination: b"example.destination",
+                    amount: 100,
+                    expires_at: SystemTime::now() + Duration::from_secs(30),
+                    execution_condition: &CONDITION,
+                    data: &[],
+                }
+                .build(),
+            })
+            .wait()
+    }
+
+    #[test]
+    fn verifies_fulfillments() {
+        assert!(verify_fulfillment(&[0; 32], &CONDITION));
+        assert!(!verify_fulfillment(&[1; 32], &CONDITION));
+        assert!(!verify_fulfillment(&[0; 32], &CONDITION[..31]));
+    }
+
+    #[test]
+    fn lets_through_matching_fulfill() {
+        assert!(send_with_fulfillment([0; 32]).is_ok());
+    }
+
+    #[test]
+    fn rejects_wrong_fulfillment_with_f05() {
+        let reject = send_with_fulfillment([1; 32]).unwrap_err();
+        assert_eq!(reject.code(), ErrorCode::F05_WRONG_CONDITION);
+    }
+}
@@ -0,0 +1,444 @@
+use futures::Future;
+use interledger_packet::{Fulfill, Prepare, Reject};
+use interledger_service::*;
+use std::{
+    collections::{hash_map::RandomState, HashMap},
+    fmt,
+    hash::{BuildHasher, Hash, Hasher},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+/// Upper bounds of the histogram buckets, in microseconds
+const BUCKETS: [usize; 15] = [
+    100, 250, 500, 1_000, 2_500, 5_000, 10_000, 25_000, 50_000, 100_000, 250_000, 500_000,
+    1_000_000, 2_500_000, 5_000_000,
+];
+
+/// Number of locks the traces of packets in flight are split across, so packets
+/// rarely wait for each other
+const TRACE_SHARDS: usize = 32;
+
+/// A part of the packet processing pipeline whose time is measured.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum LatencyStage {
+    /// Looking up the account from the credentials of an ILP-over-HTTP request or BTP connection
+    Auth,
+    /// From the router until the balance updates begin
+    Route,
+    /// Updating the balances and converting the amount
+    Balance,
+    /// Processing in the outgoing pipeline, such as the STREAM receiver and shaping delays
+    Outgoing,
+    /// Sending the packet to the next hop and waiting for its response
+    Transport,
+}
+
+const STAGES: [LatencyStage; 5] = [
+    LatencyStage::Auth,
+    LatencyStage::Route,
+    LatencyStage::Balance,
+    LatencyStage::Outgoing,
+    LatencyStage::Transport,
+];
+
+impl LatencyStage {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            LatencyStage::Auth => "auth",
+            LatencyStage::Route => "route",
+            LatencyStage::Balance => "balance",
+            LatencyStage::Outgoing => "outgoing",
+            LatencyStage::Transport => "transport",
+        }
+    }
+
+    fn index(self) -> usize {
+        match self {
+            LatencyStage::Auth => 0,
+            LatencyStage::Route => 1,
+            LatencyStage::Balance => 2,
+            LatencyStage::Outgoing => 3,
+            LatencyStage::Transport => 4,
+        }
+    }
+}
+
+impl fmt::Display for LatencyStage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Debug, Default)]
+struct Histogram {
+    buckets: [AtomicUsize; 15],
+    count: AtomicUsize,
+    sum_micros: AtomicUsize,
+}
+
+impl Histogram {
+    fn record(&self, micros: usize) {
+        if let Some(index) = BUCKETS.iter().position(|bound| micros <= *bound) {
+            self.buckets[index].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros.fetch_add(micros, Ordering::Relaxed);
+    }
+}
+
+// The time from entering each stage until the response came back, including the later stages
+type Trace = [Option<Duration>; 5];
+
+// Identifies a packet across the stages by a hash of its sender, execution condition and
+// expiry, none of which change as it moves through the pipeline
+type TraceKey = u64;
+
+/// Histograms of the time packets spend in each stage of the pipeline.
+///
+/// A packet's time in a stage excludes the time it spent in the stages after it. Packets are
+/// matched up across the stages by their sender, execution condition and expiry, and the
+/// breakdown of each one is logged at the trace level when its response comes back.
+#[derive(Debug)]
+pub struct LatencyStats {
+    stages: [Histogram; 5],
+    traces: Vec<Mutex<HashMap<TraceKey, Trace>>>,
+    hasher: RandomState,
+}
+
+impl Default for LatencyStats {
+    fn default() -> Self {
+        LatencyStats {
+            stages: Default::default(),
+            traces: (0..TRACE_SHARDS).map(|_| Mutex::default()).collect(),
+            hasher: RandomState::new(),
+        }
+    }
+}
+
+impl LatencyStats {
+    /// Record the time one packet spent in the stage
+    pub fn record(&self, stage: LatencyStage, duration: Duration) {
+        let micros = duration.as_secs() as usize * 1_000_000 + duration.subsec_micros() as usize;
+        self.stages[stage.index()].record(micros);
+    }
+
+    /// Cumulative counts of packets whose time in the stage was at most each of the bucket
+    /// bounds, in microseconds. Packets that took longer than the last bound are only
+    /// included in the `count`.
+    pub fn buckets(&self, stage: LatencyStage) -> Vec<(usize, usize)> {
+        let mut total = 0;
+        BUCKETS
+            .iter()
+            .zip(self.stages[stage.index()].buckets.iter())
+            .map(|(bound, count)| {
+                total += count.load(Ordering::Relaxed);
+                (*bound, total)
+            })
+            .collect()
+    }
+
+    /// Number of packets that were timed in the stage
+    pub fn count(&self, stage: LatencyStage) -> usize {
+        self.stages[stage.index()].count.load(Ordering::Relaxed)
+    }
+
+    /// Total time packets spent in the stage
+    pub fn sum(&self, stage: LatencyStage) -> Duration {
+        let micros = self.stages[stage.index()]
+            .sum_micros
+            .load(Ordering::Relaxed);
+        Duration::from_micros(micros as u64)
+    }
+
+    /// The stages, for reporting the histograms of each one
+    pub fn stages() -> &'static [LatencyStage] {
+        &STAGES
+    }
+
+    fn trace_key<A: Account>(&self, from: &A, prepare: &Prepare) -> TraceKey {
+        let mut hasher = self.hasher.build_hasher();
+        from.id().hash(&mut hasher);
+        prepare.execution_condition().hash(&mut hasher);
+        prepare.expires_at().hash(&mut hasher);
+        hasher.finish()
+    }
+
+    fn traces(&self, key: TraceKey) -> &Mutex<HashMap<TraceKey, Trace>> {
+        &self.traces[key as usize % TRACE_SHARDS]
+    }
+
+    // Returns true if this stage started the packet's trace, in which case it must finish it
+    fn start_trace(&self, key: TraceKey) -> bool {
+        let mut traces = self.traces(key).lock().unwrap();
+        if traces.contains_key(&key) {
+            false
+        } else {
+            traces.insert(key, [None; 5]);
+            true
+        }
+    }
+
+    fn add_to_trace(&self, key: TraceKey, stage: LatencyStage, elapsed: Duration) {
+        if let Some(trace) = self.traces(key).lock().unwrap().get_mut(&key) {
+            trace[stage.index()].get_or_insert(elapsed);
+        }
+    }
+
+    fn remove_trace(&self, key: TraceKey) -> Option<Trace> {
+        self.traces(key).lock().unwrap().remove(&key)
+    }
+
+    fn finish_trace(&self, key: TraceKey, condition: &[u8]) {
+        let trace = match self.remove_trace(key) {
+            Some(trace) => trace,
+            None => return,
+        };
+        let mut breakdown = Vec::with_capacity(STAGES.len());
+        let mut later: Option<Duration> = None;
+        for stage in STAGES.iter().rev() {
+            if let Some(elapsed) = trace[stage.index()] {
+                let spent = match later {
+                    // The clocks are read separately, so the difference is clamped to 0
+                    Some(later) => elapsed.checked_sub(later).unwrap_or_default(),
+                    None => elapsed,
+                };
+                self.record(*stage, spent);
+                breakdown.push(format!("{}={:?}", stage, spent));
+                later = Some(elapsed);
+            }
+        }
+        breakdown.reverse();
+        trace!(
+            "Time spent on packet with condition {}: {}",
+            hex::encode(condition),
+            breakdown.join(" ")
+        );
+    }
+}
+
+/// # Latency Service
+///
+/// Times how long packets take from reaching the `next` service until their response comes
+/// back, and adds it to the packet's trace in the `LatencyStats`. The first of these services
+/// a packet reaches starts its trace and records the breakdown when the response arrives.
+///
+/// The service should be placed in front of the service that begins the stage. It can be used
+/// as an IncomingService or an OutgoingService.
+#[derive(Clone)]
+pub struct LatencyService<S> {
+    stats: Arc<LatencyStats>,
+    stage: LatencyStage,
+    next: S,
+}
+
+impl<S> LatencyService<S> {
+    pub fn new(stats: Arc<LatencyStats>, stage: LatencyStage, next: S) -> Self {
+        LatencyService { stats, stage, next }
+    }
+
+    // The trace must be started before the next service is called, because it may
+    // call the later stages right away
+    fn start_trace<A: Account>(
+        &self,
+        from: &A,
+        prepare: &Prepare,
+    ) -> (TraceKey, Option<TraceGuard>) {
+        let key = self.stats.trace_key(from, prepare);
+        if self.stats.start_trace(key) {
+            let mut condition = [0; 32];
+            condition.copy_from_slice(prepare.execution_condition());
+            let guard = TraceGuard {
+                stats: self.stats.clone(),
+                key,
+                condition,
+            };
+            (key, Some(guard))
+        } else {
+            (key, None)
+        }
+    }
+
+    fn time<F, N>(
+        &mut self,
+        (key, trace): (TraceKey, Option<TraceGuard>),
+        call_next: N,
+    ) -> BoxedIlpFuture
+    where
+        F: Future<Item = Fulfill, Error = Reject> + Send + 'static,
+        N: FnOnce(&mut S) -> F,
+    {
+        let stats = self.stats.clone();
+        let stage = self.stage;
+        let start = Instant::now();
+        Box::new(call_next(&mut self.next).then(move |result| {
+            stats.add_to_trace(key, stage, start.elapsed());
+            if let Some(trace) = trace {
+                trace.finish();
+            }
+            result
+        }))
+    }
+}
+
+// Removes the packet's trace if its response never comes back, for example because
+// the future was dropped when the connection closed
+struct TraceGuard {
+    stats: Arc<LatencyStats>,
+    key: TraceKey,
+    condition: [u8; 32],
+}
+
+impl TraceGuard {
+    fn finish(self) {
+        self.stats.finish_trace(self.key, &self.condition);
+    }
+}
+
+impl Drop for TraceGuard {
+    fn drop(&mut self) {
+        self.stats.remove_trace(self.key);
+    }
+}
+
+impl<S, A> IncomingService<A> for LatencyService<S>
+where
+    S: IncomingService<A>,
+    A: Account,
+{
+    type Future = BoxedIlpFuture;
+
+    fn handle_request(&mut self, request: IncomingRequest<A>) -> Self::Future {
+        let trace = self.start_trace(&request.from, &request.prepare);
+        self.time(trace, move |next| next.handle_request(request))
+    }
+}
+
+impl<S, A> OutgoingService<A> for LatencyService<S>
+where
+    S: OutgoingService<A>,
+    A: Account,
+{
+    type Future = BoxedIlpFuture;
+
+    fn send_request(&mut self, request: OutgoingRequest<A>) -> Self::Future {
+        let trace = self.start_trace(&request.from, &request.prepare);
+        self.time(trace, move |next| next.send_request(request))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use interledger_packet::{FulfillBuilder, PrepareBuilder};
+    use std::{thread, time::SystemTime};
+
+    #[derive(Clone, Debug)]
+    struct TestAccount(u64);
+
+    impl Account for TestAccount {
+        type AccountId = u64;
+
+        fn id(&self) -> u64 {
+            self.0
+        }
+    }
+
+    fn request() -> IncomingRequest<TestAccount> {
+        IncomingRequest {
+            from: TestAccount(1),
+            prepare: PrepareBuilder {
+                destination: b"example.destination",
+                amount: 100,
+                expires_at: SystemTime::now() + Duration::from_secs(30),
+                execution_condition: &[1; 32],
+                data: &[],
+            }
+            .build(),
+        }
+    }
+
+    #[test]
+    fn records_time_spent_in_each_stage() {
+        let stats = Arc::new(LatencyStats::default());
+        let transport = LatencyService::new(
+            stats.clone(),
+            LatencyStage::Transport,
+            incoming_service_fn(|_request: IncomingRequest<TestAccount>| {
+                thread::sleep(Duration::from_millis(20));
+                Ok(FulfillBuilder {
+                    fulfillment: &[0; 32],
+                    data: &[],
+                }
+                .build())
+            }),
+        );
+        let mut transport_clone = transport.clone();
+        let mut service = LatencyService::new(
+            stats.clone(),
+            LatencyStage::Route,
+            incoming_service_fn(move |request: IncomingRequest<TestAccount>| {
+                thread::sleep(Duration::from_millis(5));
+                transport_clone.handle_request(request).wait()
+            }),
+        );
+
+        service.handle_request(request()).wait().unwrap();
+
+        assert_eq!(stats.count(LatencyStage::Route), 1);
+        assert_eq!(stats.count(LatencyStage::Transport), 1);
+        assert_eq!(stats.count(LatencyStage::Balance), 0);
+        assert!(stats.sum(LatencyStage::Transport) >= Duration::from_millis(20));
+        assert!(stats.sum(LatencyStage::Route) >= Duration::from_millis(5));
+        assert!(stats.sum(LatencyStage::Route) < Duration::from_millis(20));
+        assert!(stats
+            .traces
+            .iter()
+            .all(|traces| traces.lock().unwrap().is_empty()));
+    }
+
+    #[test]
+    fn traces_packets_from_different_senders_separately() {
+        let stats = Arc::new(LatencyStats::default());
+        let service = LatencyService::new(
+            stats.clone(),
+            LatencyStage::Route,
+            incoming_service_fn(|_request: IncomingRequest<TestAccount>| {
+                Ok(FulfillBuilder {
+                    fulfillment: &[0; 32],
+                    data: &[],
+                }
+                .build())
+            }),
+        );
+        let prepare = request().prepare;
+        let (first, first_trace) = service.start_trace(&TestAccount(1), &prepare);
+        let (second, second_trace) = service.start_trace(&TestAccount(2), &prepare);
+        assert_ne!(first, second);
+        assert!(first_trace.is_some());
+        assert!(second_trace.is_some());
+
+        drop(first_trace);
+        drop(second_trace);
+        assert!(stats
+            .traces
+            .iter()
+            .all(|traces| traces.lock().unwrap().is_empty()));
+    }
+
+    #[test]
+    fn buckets_are_cumulative() {
+        let stats = LatencyStats::default();
+        stats.record(LatencyStage::Auth, Duration::from_micros(50));
+        stats.record(LatencyStage::Auth, Duration::from_millis(3));
+        stats.record(LatencyStage::Auth, Duration::from_secs(10));
+        let buckets = stats.buckets(LatencyStage::Auth);
+        assert_eq!(buckets[0], (100, 1));
+        assert_eq!(buckets[5], (5_000, 2));
+        assert_eq!(buckets[14], (5_000_000, 2));
+        assert_eq!(stats.count(LatencyStage::Auth), 3);
+    }
+}
@@ -5,12 +5,48 @@
 #[macro_use]
 extern crate log;
 
+mod address_space;
+#[cfg(feature = "chaos")]
+mod chaos;
+mod clock;
+mod fees;
+mod fulfillment;
+mod latency;
+mod lock;
+mod maintenance;
 mod max_packet_amount;
+mod max_packet_data;
+mod priority;
 mod rates_and_balances;
+mod settlement;
+mod sha256;
+mod shaping;
+mod shards;
+mod stats;
 mod validator;
 
+pub use self::address_space::AddressSpaceService;
+#[cfg(feature = "chaos")]
+pub use self::chaos::{FailureInjection, FailureInjectionService};
+pub use self::clock::{check_clock_skew, measure_clock_skew, ClockSkew};
+pub use self::fees::{FeePolicy, FeeStore};
+pub use self::fulfillment::{verify_fulfillment, FulfillmentValidatorService};
+pub use self::latency::{LatencyService, LatencyStage, LatencyStats};
+pub use self::lock::{with_lock, Lock, LockStore};
+pub use self::maintenance::{MaintenanceService, MaintenanceStore, MaintenanceWindow};
 pub use self::max_packet_amount::{MaxPacketAmountAccount, MaxPacketAmountService};
+pub use self::max_packet_data::{MaxPacketDataAccount, MaxPacketDataService, PacketDataStats};
+pub use self::priority::{
+    Priority, PriorityAccount, PriorityService, PriorityStats, DEFAULT_MAX_QUEUED,
+};
 pub use self::rates_and_balances::{
     BalanceStore, ExchangeRateAndBalanceService, ExchangeRateStore,
 };
+pub use self::settlement::{
+    exchange_settlement_info, SettlementAccount, SettlementInfo, SettlementInfoService,
+    SettlementInfoStore, XrpAccount, SETTLEMENT_INFO_DESTINATION,
+};
+pub use self::shaping::{ShapingAccount, ShapingService};
+pub use self::shards::{ShardedService, DEFAULT_SHARD_QUEUE};
+pub use self::stats::{AccountStats, DailyStats, PrefixStats, StatsStore};
 pub use self::validator::ValidatorService;
@@ -0,0 +1,240 @@
+use futures::{future::Either, Future, IntoFuture, Stream};
+use std::{
+    cmp::max,
+    time::{Duration, Instant},
+};
+use tokio::timer::Interval;
+
+/// A lock on a named resource that is shared by all of the instances using the same store.
+///
+/// The token increases each time the lock is acquired, so that a holder whose lock expired
+/// cannot extend or release the lock of the next holder.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Lock {
+    pub name: String,
+    pub token: u64,
+}
+
+/// A store that can hold locks across all of the instances that share it, so that background
+/// tasks such as sweepers and exporters do not run on more than one instance at a time.
+///
+/// Locks expire after their time to live so that they are not held forever by instances that
+/// stop while holding them.
+pub trait LockStore {
+    /// Acquire the lock with the given name for `ttl`.
+    /// Resolves to `None` if another holder already has it.
+    fn acquire_lock(
+        &self,
+        name: &str,
+        ttl: Duration,
+    ) -> Box<Future<Item = Option<Lock>, Error = ()> + Send>;
+
+    /// Make the lock expire `ttl` from now. Resolves to false if it is no longer held,
+    /// because it expired and may have been acquired by another holder.
+    fn extend_lock(
+        &self,
+        lock: &Lock,
+        ttl: Duration,
+    ) -> Box<Future<Item = bool, Error = ()> + Send>;
+
+    /// Release the lock, unless it already expired and was acquired by another holder.
+    fn release_lock(&self, lock: Lock) -> Box<Future<Item = (), Error = ()> + Send>;
+}
+
+/// Run the task with the lock, if no one else holds it, and release the lock afterwards.
+/// Resolves to `None` without running the task if the lock is held.
+///
+/// The lock is extended every third of `ttl` while the task runs, so that it only expires
+/// if this instance stops. If it cannot be extended, for example because the store was
+/// unavailable for longer than `ttl`, another holder may run at the same time; the task
+/// is still run to completion and a warning is logged.
+pub fn with_lock<T, F, R>(
+    store: T,
+    name: &str,
+    ttl: Duration,
+    task: F,
+) -> impl Future<Item = Option<R::Item>, Error = ()>
+where
+    T: LockStore + Clone + Send + 'static,
+    F: FnOnce(Lock) -> R + Send + 'static,
+    R: IntoFuture<Error = ()>,
+    R::Future: Send + 'static,
+    R::Item: Send + 'static,
+{
+    let name = name.to_string();
+    store
+        .acquire_lock(&name, ttl)
+        .and_then(move |lock| match lock {
+            Some(lock) => {
+                let renewal = renew_lock(store.clone(), lock.clone(), ttl);
+                let task = task(lock.clone())
+                    .into_future()
+                    .select2(renewal)
+                    .then(|result| match result {
+                        Ok(Either::A((item, _renewal))) => Either::A(Ok(item).into_future()),
+                        Err(Either::A((error, _renewal))) => Either::A(Err(error).into_future()),
+                        // The lock was lost, but the task is not interrupted halfway through
+                        Ok(Either::B((_, task))) | Err(Either::B((_, task))) => Either::B(task),
+                    });
+                Either::A(task.then(move |result| {
+                    // Release the lock even if the task failed, so it can be retried right away
+                    store.release_lock(lock).then(|_| result.map(Some))
+                }))
+            }
+            None => {
+                trace!("Not running task because lock {} is held", name);
+                Either::B(Ok(None).into_future())
+            }
+        })
+}
+
+/// Extend the lock every third of its time to live. Only resolves if the lock is lost.
+fn renew_lock<T>(store: T, lock: Lock, ttl: Duration) -> impl Future<Item = (), Error = ()>
+where
+    T: LockStore + Send + 'static,
+{
+    let period = max(ttl / 3, Duration::from_millis(1));
+    Interval::new(Instant::now() + period, period)
+        .map_err(|err| error!("Timer error renewing lock: {:?}", err))
+        .for_each(move |_| {
+            let name = lock.name.clone();
+            store.extend_lock(&lock, ttl).and_then(move |held| {
+                if held {
+                    Ok(())
+                } else {
+                    warn!(
+                        "Lock {} expired while the task holding it was running",
+                        name
+                    );
+                    Err(())
+                }
+            })
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future::{err, ok};
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+    use tokio::{runtime::Runtime, timer::Delay};
+
+    #[derive(Clone, Default)]
+    struct TestStore {
+        locks: Arc<Mutex<HashMap<String, u64>>>,
+        next_token: Arc<Mutex<u64>>,
+        extensions: Arc<Mutex<u32>>,
+    }
+
+    impl LockStore for TestStore {
+        fn acquire_lock(
+            &self,
+            name: &str,
+            _ttl: Duration,
+        ) -> Box<Future<Item = Option<Lock>, Error = ()> + Send> {
+            let mut locks = self.locks.lock().unwrap();
+            if locks.contains_key(name) {
+                return Box::new(ok(None));
+            }
+            let mut next_token = self.next_token.lock().unwrap();
+            *next_token += 1;
+            locks.insert(name.to_string(), *next_token);
+            Box::new(ok(Some(Lock {
+                name: name.to_string(),
+                token: *next_token,
+            })))
+        }
+
+        fn extend_lock(
+            &self,
+            lock: &Lock,
+            _ttl: Duration,
+        ) -> Box<Future<Item = bool, Error = ()> + Send> {
+            *self.extensions.lock().unwrap() += 1;
+            let held = self.locks.lock().unwrap().get(&lock.name) == Some(&lock.token);
+            Box::new(ok(held))
+        }
+
+        fn release_lock(&self, lock: Lock) -> Box<Future<Item = (), Error = ()> + Send> {
+            let mut locks = self.locks.lock().unwrap();
+            if locks.get(&lock.name) == Some(&lock.token) {
+                locks.remove(&lock.name);
+            }
+            Box::new(ok(()))
+        }
+    }
+
+    #[test]
+    fn runs_task_and_releases_lock() {
+        let store = TestStore::default();
+        let result = with_lock(store.clone(), "task", Duration::from_secs(1), |lock| {
+            Ok(lock.token)
+        })
+        .wait()
+        .unwrap();
+        assert_eq!(result, Some(1));
+        assert!(store.locks.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn skips_task_if_lock_is_held() {
+        let store = TestStore::default();
+        let held = store
+            .acquire_lock("task", Duration::from_secs(1))
+            .wait()
+            .unwrap()
+            .unwrap();
+        let result = with_lock(
+            store.clone(),
+            "task",
+            Duration::from_secs(1),
+            |_lock| Ok(()),
+        )
+        .wait()
+        .unwrap();
+        assert!(result.is_none());
+        assert!(store
+            .extend_lock(&held, Duration::from_secs(1))
+            .wait()
+            .unwrap());
+    }
+
+    #[test]
+    fn releases_lock_if_task_fails() {
+        let store = TestStore::default();
+        let result = with_lock(store.clone(), "task", Duration::from_secs(1), |_lock| {
+            err::<(), ()>(())
+        })
+        .wait();
+        assert!(result.is_err());
+        let lock = store
+            .acquire_lock("task", Duration::from_secs(1))
+            .wait()
+            .unwrap()
+            .unwrap();
+        // Tokens keep increasing so stale holders can be told apart
+        assert_eq!(lock.token, 2);
+    }
+
+    #[test]
+    fn extends_lock_while_task_runs() {
+        let store = TestStore::default();
+        let mut runtime = Runtime::new().unwrap();
+        let result = runtime
+            .block_on(with_lock(
+                store.clone(),
+                "task",
+                Duration::from_millis(30),
+                |_lock| {
+                    Delay::new(Instant::now() + Duration::from_millis(100))
+                        .map(|_| "done")
+                        .map_err(|_| ())
+                },
+            ))
+            .unwrap();
+        assert_eq!(result, Some("done"));
+        assert!(*store.extensions.lock().unwrap() >= 3);
+        assert!(store.locks.lock().unwrap().is_empty());
+    }
+}
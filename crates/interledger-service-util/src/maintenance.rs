@@ -0,0 +1,225 @@
+use futures::future::err;
+use interledger_packet::{ErrorCode, Reject, RejectBuilder};
+use interledger_service::*;
+use serde::{Deserialize, Serialize};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A period during which a peer is expected to be unavailable, for example
+/// because it announced planned downtime.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MaintenanceWindow {
+    /// Seconds since the UNIX epoch
+    pub start: u64,
+    /// Seconds since the UNIX epoch
+    pub end: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+impl MaintenanceWindow {
+    /// Whether the given time (in seconds since the UNIX epoch) is within the window.
+    pub fn contains(&self, time: u64) -> bool {
+        self.start <= time && time < self.end
+    }
+}
+
+pub trait MaintenanceStore: AccountStore {
+    /// Get the maintenance window the account is in at the given time (in seconds since the UNIX epoch), if any.
+    fn get_maintenance_window(
+        &self,
+        account: &Self::Account,
+        time: u64,
+    ) -> Option<MaintenanceWindow>;
+}
+
+/// # Maintenance Service
+///
+/// Rejects packets from and to accounts that are in one of their scheduled
+/// maintenance windows with `T01: Peer Unreachable`, so senders retry later
+/// or use another route instead of waiting for the packets to time out.
+#[derive(Clone)]
+pub struct MaintenanceService<S, T> {
+    store: T,
+    next: S,
+}
+
+impl<S, T> MaintenanceService<S, T>
+where
+    T: MaintenanceStore,
+{
+    pub fn new(store: T, next: S) -> Self {
+        MaintenanceService { store, next }
+    }
+
+    fn reject_if_in_maintenance(&self, account: &T::Account) -> Option<Reject> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        if let Some(window) = self.store.get_maintenance_window(account, now) {
+            debug!(
+                "Rejecting packet because account {} is in maintenance until {}",
+                account.id(),
+                window.end
+            );
+            let message = format!("Peer is in maintenance until {}", window.end);
+            Some(
+                RejectBuilder {
+                    code: ErrorCode::T01_PEER_UNREACHABLE,
+                    message: message.as_bytes(),
+                    triggered_by: &[],
+                    data: &[],
+                }
+                .build(),
+            )
+        } else {
+            None
+        }
+    }
+}
+
+impl<S, T, A> IncomingService<A> for MaintenanceService<S, T>
+where
+    S: IncomingService<A>,
+    T: MaintenanceStore<Account = A>,
+    A: Account,
+{
+    type Future = BoxedIlpFuture;
+
+    fn handle_request(&mut self, request: IncomingRequest<A>) -> Self::Future {
+        if let Some(reject) = self.reject_if_in_maintenance(&request.from) {
+            return Box::new(err(reject));
+        }
+        Box::new(self.next.handle_request(request))
+    }
+}
+
+impl<S, T, A> OutgoingService<A> for MaintenanceService<S, T>
+where
+    S: OutgoingService<A>,
+    T: MaintenanceStore<Account = A>,
+    A: Account,
+{
+    type Future = BoxedIlpFuture;
+
+    fn send_request(&mut self, request: OutgoingRequest<A>) -> Self::Future {
+        if let Some(reject) = self.reject_if_in_maintenance(&request.to) {
+            return Box::new(err(reject));
+        }
+        Box::new(self.next.send_request(request))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::Future;
+    use interledger_packet::{Fulfill, FulfillBuilder, PrepareBuilder};
+    use std::time::Duration;
+
+    #[derive(Clone, Debug)]
+    struct TestAccount(u64);
+
+    impl Account for TestAccount {
+        type AccountId = u64;
+
+        fn id(&self) -> u64 {
+            self.0
+        }
+    }
+
+    // Account 1 is always in maintenance
+    #[derive(Clone)]
+    struct TestStore;
+
+    impl AccountStore for TestStore {
+        type Account = TestAccount;
+
+        fn get_accounts(
+            &self,
+            account_ids: Vec<u64>,
+        ) -> Box<Future<Item = Vec<Option<TestAccount>>, Error = ()> + Send> {
+            Box::new(futures::future::ok(
+                account_ids
+                    .into_iter()
+                    .map(|id| Some(TestAccount(id)))
+                    .collect(),
+            ))
+        }
+    }
+
+    impl MaintenanceStore for TestStore {
+        fn get_maintenance_window(
+            &self,
+            account: &TestAccount,
+            time: u64,
+        ) -> Option<MaintenanceWindow> {
+            if account.0 == 1 {
+                Some(MaintenanceWindow {
+                    start: time - 60,
+                    end: time + 60,
+                    reason: None,
+                })
+            } else {
+                None
+            }
+        }
+    }
+
+    fn request(from: u64) -> IncomingRequest<TestAccount> {
+        IncomingRequest {
+            from: TestAccount(from),
+            prepare: PrepareBuilder {
+                destination: b"example.destination",
+                amount: 100,
+                expires_at: SystemTime::now() + Duration::from_secs(30),
+                execution_condition: &[0; 32],
+                data: &[],
+            }
+            .build(),
+        }
+    }
+
+    fn fulfill() -> Result<Fulfill, Reject> {
+        Ok(FulfillBuilder {
+            fulfillment: &[0; 32],
+            data: &[],
+        }
+        .build())
+    }
+
+    #[test]
+    fn window_contains_start_but_not_end() {
+        let window = MaintenanceWindow {
+            start: 100,
+            end: 200,
+            reason: Some("upgrade".to_string()),
+        };
+        assert!(!window.contains(99));
+        assert!(window.contains(100));
+        assert!(window.contains(199));
+        assert!(!window.contains(200));
+    }
+
+    #[test]
+    fn rejects_packets_from_account_in_maintenance() {
+        let mut service = MaintenanceService::new(TestStore, incoming_service_fn(|_| fulfill()));
+        let reject = service.handle_request(request(1)).wait().unwrap_err();
+        assert_eq!(reject.code(), ErrorCode::T01_PEER_UNREACHABLE);
+        assert!(service.handle_request(request(0)).wait().is_ok());
+    }
+
+    #[test]
+    fn rejects_packets_to_account_in_maintenance() {
+        let mut service = MaintenanceService::new(TestStore, outgoing_service_fn(|_| fulfill()));
+        let reject = service
+            .send_request(request(0).into_outgoing(TestAccount(1)))
+            .wait()
+            .unwrap_err();
+        assert_eq!(reject.code(), ErrorCode::T01_PEER_UNREACHABLE);
+        assert!(service
+            .send_request(request(0).into_outgoing(TestAccount(2)))
+            .wait()
+            .is_ok());
+    }
+}
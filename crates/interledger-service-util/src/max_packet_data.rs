@@ -0,0 +1,282 @@
+use futures::{future::err, Future};
+use interledger_packet::{ErrorCode, Fulfill, FulfillBuilder, Reject, RejectBuilder};
+use interledger_service::*;
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
+/// Accounts that limit how much data the ILP packets exchanged with them may carry.
+pub trait MaxPacketDataAccount: Account {
+    /// The largest `data` field, in bytes, of packets sent to or received from this account,
+    /// or `None` if it is only limited by the ILP packet format.
+    fn max_packet_data(&self) -> Option<u16>;
+}
+
+/// Counters of the packets affected by the per-account data limits, exposed as metrics.
+#[derive(Debug, Default)]
+pub struct PacketDataStats {
+    rejected: AtomicUsize,
+    stripped: AtomicUsize,
+}
+
+impl PacketDataStats {
+    /// Number of Prepare packets rejected because their data was too large
+    pub fn rejected(&self) -> usize {
+        self.rejected.load(Ordering::Relaxed)
+    }
+
+    /// Number of Fulfill or Reject packets whose data was removed because it was too large
+    pub fn stripped(&self) -> usize {
+        self.stripped.load(Ordering::Relaxed)
+    }
+}
+
+/// # Max Packet Data Service
+///
+/// Enforces each account's `max_packet_data` so that peers with little memory cannot
+/// be made to buffer or relay maximum-size (32KB) packets.
+///
+/// As an IncomingService, it rejects Prepare packets carrying more data than the sending
+/// account allows, and removes the data from Fulfill and Reject packets going back to that
+/// account if it is over the limit. As an OutgoingService, it rejects Prepare packets that
+/// carry more data than the next account accepts.
+///
+/// Packets are rejected with `F00: Bad Request` rather than `F08: Amount Too Large`,
+/// because senders (for example STREAM) interpret the data of F08 rejections as the
+/// received and maximum amounts.
+#[derive(Clone)]
+pub struct MaxPacketDataService<S> {
+    next: S,
+    stats: Arc<PacketDataStats>,
+}
+
+impl<S> MaxPacketDataService<S> {
+    pub fn new(next: S) -> Self {
+        MaxPacketDataService::with_stats(Arc::new(PacketDataStats::default()), next)
+    }
+
+    /// Count the rejected and stripped packets in the given (possibly shared) stats
+    pub fn with_stats(stats: Arc<PacketDataStats>, next: S) -> Self {
+        MaxPacketDataService { next, stats }
+    }
+}
+
+fn data_too_large(data_length: usize, max_data: u16) -> Reject {
+    let message = format!(
+        "Packet data too large: {} bytes (maximum: {} bytes)",
+        data_length, max_data
+    );
+    RejectBuilder {
+        code: ErrorCode::F00_BAD_REQUEST,
+        message: message.as_bytes(),
+        triggered_by: &[],
+        data: &[],
+    }
+    .build()
+}
+
+fn limit_fulfill_data(fulfill: Fulfill, max_data: u16, stats: &PacketDataStats) -> Fulfill {
+    if fulfill.data().len() <= max_data as usize {
+        return fulfill;
+    }
+    debug!(
+        "Removing {} bytes of data from Fulfill packet (maximum: {} bytes)",
+        fulfill.data().len(),
+        max_data
+    );
+    stats.stripped.fetch_add(1, Ordering::Relaxed);
+    let mut fulfillment = [0; 32];
+    fulfillment.copy_from_slice(fulfill.fulfillment());
+    FulfillBuilder {
+        fulfillment: &fulfillment,
+        data: &[],
+    }
+    .build()
+}
+
+fn limit_reject_data(reject: Reject, max_data: u16, stats: &PacketDataStats) -> Reject {
+    if reject.data().len() <= max_data as usize {
+        return reject;
+    }
+    debug!(
+        "Removing {} bytes of data from Reject packet (maximum: {} bytes)",
+        reject.data().len(),
+        max_data
+    );
+    stats.stripped.fetch_add(1, Ordering::Relaxed);
+    RejectBuilder {
+        code: reject.code(),
+        message: reject.message(),
+        triggered_by: reject.triggered_by(),
+        data: &[],
+    }
+    .build()
+}
+
+impl<S, A> IncomingService<A> for MaxPacketDataService<S>
+where
+    S: IncomingService<A>,
+    A: MaxPacketDataAccount,
+{
+    type Future = BoxedIlpFuture;
+
+    fn handle_request(&mut self, request: IncomingRequest<A>) -> Self::Future {
+        let max_data = match request.from.max_packet_data() {
+            Some(max_data) => max_data,
+            None => return Box::new(self.next.handle_request(request)),
+        };
+        let data_length = request.prepare.data().len();
+        if data_length > max_data as usize {
+            debug!(
+                "Rejecting packet from account {} with {} bytes of data (maximum: {} bytes)",
+                request.from.id(),
+                data_length,
+                max_data
+            );
+            self.stats.rejected.fetch_add(1, Ordering::Relaxed);
+            return Box::new(err(data_too_large(data_length, max_data)));
+        }
+
+        let stats = self.stats.clone();
+        Box::new(
+            self.next
+                .handle_request(request)
+                .then(move |result| match result {
+                    Ok(fulfill) => Ok(limit_fulfill_data(fulfill, max_data, &stats)),
+                    Err(reject) => Err(limit_reject_data(reject, max_data, &stats)),
+                }),
+        )
+    }
+}
+
+impl<S, A> OutgoingService<A> for MaxPacketDataService<S>
+where
+    S: OutgoingService<A>,
+    A: MaxPacketDataAccount,
+{
+    type Future = BoxedIlpFuture;
+
+    fn send_request(&mut self, request: OutgoingRequest<A>) -> Self::Future {
+        if let Some(max_data) = request.to.max_packet_data() {
+            let data_length = request.prepare.data().len();
+            if data_length > max_data as usize {
+                debug!(
+                    "Not forwarding packet with {} bytes of data to account {} (maximum: {} bytes)",
+                    data_length,
+                    request.to.id(),
+                    max_data
+                );
+                self.stats.rejected.fetch_add(1, Ordering::Relaxed);
+                return Box::new(err(data_too_large(data_length, max_data)));
+            }
+        }
+        Box::new(self.next.send_request(request))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use interledger_packet::PrepareBuilder;
+    use std::time::{Duration, SystemTime};
+
+    #[derive(Clone, Debug)]
+    struct TestAccount(u64, Option<u16>);
+
+    impl Account for TestAccount {
+        type AccountId = u64;
+
+        fn id(&self) -> u64 {
+            self.0
+        }
+    }
+
+    impl MaxPacketDataAccount for TestAccount {
+        fn max_packet_data(&self) -> Option<u16> {
+            self.1
+        }
+    }
+
+    fn incoming_request(from: TestAccount, data: &[u8]) -> IncomingRequest<TestAccount> {
+        IncomingRequest {
+            from,
+            prepare: PrepareBuilder {
+                destination: b"example.destination",
+                amount: 100,
+                expires_at: SystemTime::now() + Duration::from_secs(30),
+                execution_condition: &[0; 32],
+                data,
+            }
+            .build(),
+        }
+    }
+
+    #[test]
+    fn rejects_incoming_packet_with_too_much_data() {
+        let stats = Arc::new(PacketDataStats::default());
+        let mut service = MaxPacketDataService::with_stats(
+            stats.clone(),
+            incoming_service_fn(|_| -> Result<Fulfill, Reject> {
+                panic!("Should not forward the packet")
+            }),
+        );
+        let reject = service
+            .handle_request(incoming_request(TestAccount(0, Some(4)), b"too much data"))
+            .wait()
+            .unwrap_err();
+        assert_eq!(reject.code(), ErrorCode::F00_BAD_REQUEST);
+        assert_eq!(stats.rejected(), 1);
+    }
+
+    #[test]
+    fn strips_response_data_over_the_limit() {
+        let stats = Arc::new(PacketDataStats::default());
+        let mut service = MaxPacketDataService::with_stats(
+            stats.clone(),
+            incoming_service_fn(|_| {
+                Ok(FulfillBuilder {
+                    fulfillment: &[1; 32],
+                    data: b"a rather large response",
+                }
+                .build())
+            }),
+        );
+        let fulfill = service
+            .handle_request(incoming_request(TestAccount(0, Some(8)), b"data"))
+            .wait()
+            .unwrap();
+        assert_eq!(fulfill.fulfillment(), &[1; 32][..]);
+        assert!(fulfill.data().is_empty());
+        assert_eq!(stats.stripped(), 1);
+        assert_eq!(stats.rejected(), 0);
+    }
+
+    #[test]
+    fn rejects_outgoing_packet_the_next_account_does_not_accept() {
+        let mut service =
+            MaxPacketDataService::new(outgoing_service_fn(|_| -> Result<Fulfill, Reject> {
+                panic!("Should not send the packet")
+            }));
+        let request = incoming_request(TestAccount(0, None), b"too much data")
+            .into_outgoing(TestAccount(1, Some(4)));
+        let reject = service.send_request(request).wait().unwrap_err();
+        assert_eq!(reject.code(), ErrorCode::F00_BAD_REQUEST);
+    }
+
+    #[test]
+    fn lets_through_packets_without_a_limit() {
+        let mut service = MaxPacketDataService::new(incoming_service_fn(|_| {
+            Ok(FulfillBuilder {
+                fulfillment: &[0; 32],
+                data: b"test data",
+            }
+            .build())
+        }));
+        let fulfill = service
+            .handle_request(incoming_request(TestAccount(0, None), b"test data"))
+            .wait()
+            .unwrap();
+        assert_eq!(fulfill.data(), b"test data");
+    }
+}
@@ -0,0 +1,454 @@
+use futures::{future::err, sync::oneshot, Future};
+use interledger_packet::{ErrorCode, Reject, RejectBuilder};
+use interledger_service::*;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::VecDeque,
+    fmt,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::SystemTime,
+};
+
+/// How many packets of each priority class may wait for a slot by default
+pub const DEFAULT_MAX_QUEUED: usize = 1000;
+
+/// The service class of an account's packets, which determines the order in which
+/// they are processed when the node is busy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    High,
+    Normal,
+    Low,
+}
+
+const PRIORITIES: [Priority; 3] = [Priority::High, Priority::Normal, Priority::Low];
+
+// Out of every 7 packets taken from the queues while all of them have packets waiting,
+// 4 are high priority, 2 normal and 1 low, so bulk traffic is slowed down but never starved
+const SCHEDULE: [Priority; 7] = [
+    Priority::High,
+    Priority::Normal,
+    Priority::High,
+    Priority::Low,
+    Priority::High,
+    Priority::Normal,
+    Priority::High,
+];
+
+impl Priority {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Priority::High => "high",
+            Priority::Normal => "normal",
+            Priority::Low => "low",
+        }
+    }
+
+    fn index(self) -> usize {
+        match self {
+            Priority::High => 0,
+            Priority::Normal => 1,
+            Priority::Low => 2,
+        }
+    }
+}
+
+impl Default for Priority {
+    fn default() -> Self {
+        Priority::Normal
+    }
+}
+
+impl FromStr for Priority {
+    type Err = ();
+
+    fn from_str(string: &str) -> Result<Self, ()> {
+        match string.to_lowercase().as_str() {
+            "high" => Ok(Priority::High),
+            "normal" => Ok(Priority::Normal),
+            "low" => Ok(Priority::Low),
+            _ => Err(()),
+        }
+    }
+}
+
+impl fmt::Display for Priority {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Accounts whose packets are given a priority class.
+pub trait PriorityAccount: Account {
+    fn priority(&self) -> Priority;
+}
+
+#[derive(Debug, Default)]
+struct ClassStats {
+    queued: AtomicUsize,
+    processed: AtomicUsize,
+    rejected: AtomicUsize,
+}
+
+/// Counters of the packets handled by the `PriorityService` in each priority class, exposed as metrics.
+#[derive(Debug, Default)]
+pub struct PriorityStats {
+    classes: [ClassStats; 3],
+}
+
+impl PriorityStats {
+    /// Number of packets of the class that are currently waiting to be processed
+    pub fn queued(&self, priority: Priority) -> usize {
+        self.classes[priority.index()]
+            .queued
+            .load(Ordering::Relaxed)
+    }
+
+    /// Number of packets of the class that were passed on to be processed
+    pub fn processed(&self, priority: Priority) -> usize {
+        self.classes[priority.index()]
+            .processed
+            .load(Ordering::Relaxed)
+    }
+
+    /// Number of packets of the class that were rejected because its queue was full
+    pub fn rejected(&self, priority: Priority) -> usize {
+        self.classes[priority.index()]
+            .rejected
+            .load(Ordering::Relaxed)
+    }
+
+    /// The priority classes, for reporting the counters of each one
+    pub fn priorities() -> &'static [Priority] {
+        &PRIORITIES
+    }
+}
+
+#[derive(Default)]
+struct Queues {
+    in_flight: usize,
+    waiting: [VecDeque<oneshot::Sender<()>>; 3],
+    next_turn: usize,
+}
+
+impl Queues {
+    // Give the slot of a packet that finished to the next waiting packet, if there is one
+    fn hand_over(&mut self, stats: &PriorityStats) {
+        loop {
+            let turn = (0..SCHEDULE.len())
+                .map(|offset| (self.next_turn + offset) % SCHEDULE.len())
+                .find(|turn| !self.waiting[SCHEDULE[*turn].index()].is_empty());
+            let turn = match turn {
+                Some(turn) => turn,
+                None => {
+                    self.in_flight -= 1;
+                    return;
+                }
+            };
+            self.next_turn = (turn + 1) % SCHEDULE.len();
+            let index = SCHEDULE[turn].index();
+            let waiting = self.waiting[index].pop_front().unwrap();
+            stats.classes[index].queued.fetch_sub(1, Ordering::Relaxed);
+            // The sender may have stopped waiting for the response, in which case it is skipped
+            if waiting.send(()).is_ok() {
+                return;
+            }
+        }
+    }
+}
+
+// Held while a packet is being processed, and handed over when the response comes back
+// (or the request is dropped)
+struct Slot {
+    queues: Arc<Mutex<Queues>>,
+    stats: Arc<PriorityStats>,
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.queues.lock().unwrap().hand_over(&self.stats);
+    }
+}
+
+/// # Priority Service
+///
+/// Limits how many incoming packets are processed at a time. When the node is at that limit,
+/// packets wait in a queue for their account's priority class and are taken from the queues
+/// in a weighted round-robin order, so high priority peers' packets are processed ahead of
+/// bulk traffic without starving it.
+///
+/// Packets are rejected with `T03: Connector Busy` if the queue for their class is full,
+/// and with `R00: Transfer Timed Out` if they expire while waiting.
+#[derive(Clone)]
+pub struct PriorityService<S> {
+    next: S,
+    max_in_flight: usize,
+    max_queued: usize,
+    queues: Arc<Mutex<Queues>>,
+    stats: Arc<PriorityStats>,
+}
+
+impl<S> PriorityService<S> {
+    /// Process at most `max_in_flight` packets at a time.
+    pub fn new(max_in_flight: usize, next: S) -> Self {
+        PriorityService::with_stats(Arc::new(PriorityStats::default()), max_in_flight, next)
+    }
+
+    /// Count the queued, processed and rejected packets in the given (possibly shared) stats
+    pub fn with_stats(stats: Arc<PriorityStats>, max_in_flight: usize, next: S) -> Self {
+        PriorityService {
+            next,
+            max_in_flight,
+            max_queued: DEFAULT_MAX_QUEUED,
+            queues: Arc::new(Mutex::new(Queues::default())),
+            stats,
+        }
+    }
+
+    /// How many packets of each priority class may wait for a slot
+    pub fn max_queued(mut self, max_queued: usize) -> Self {
+        self.max_queued = max_queued;
+        self
+    }
+}
+
+fn reject(code: ErrorCode, message: &[u8]) -> Reject {
+    RejectBuilder {
+        code,
+        message,
+        triggered_by: &[],
+        data: &[],
+    }
+    .build()
+}
+
+impl<S> PriorityService<S> {
+    fn process<A>(&mut self, request: IncomingRequest<A>, priority: Priority) -> BoxedIlpFuture
+    where
+        S: IncomingService<A> + Clone + Send + 'static,
+        A: PriorityAccount + 'static,
+    {
+        let slot = Slot {
+            queues: self.queues.clone(),
+            stats: self.stats.clone(),
+        };
+        if request.prepare.expires_at() < SystemTime::now() {
+            debug!("Packet expired while waiting to be processed");
+            return Box::new(err(reject(ErrorCode::R00_TRANSFER_TIMED_OUT, &[])));
+        }
+        self.stats.classes[priority.index()]
+            .processed
+            .fetch_add(1, Ordering::Relaxed);
+        Box::new(self.next.handle_request(request).then(move |result| {
+            drop(slot);
+            result
+        }))
+    }
+}
+
+impl<S, A> IncomingService<A> for PriorityService<S>
+where
+    S: IncomingService<A> + Clone + Send + 'static,
+    A: PriorityAccount + 'static,
+{
+    type Future = BoxedIlpFuture;
+
+    fn handle_request(&mut self, request: IncomingRequest<A>) -> Self::Future {
+        let priority = request.from.priority();
+        let index = priority.index();
+        let receiver = {
+            let mut queues = self.queues.lock().unwrap();
+            if queues.in_flight < self.max_in_flight {
+                queues.in_flight += 1;
+                None
+            } else if queues.waiting[index].len() >= self.max_queued {
+                self.stats.classes[index]
+                    .rejected
+                    .fetch_add(1, Ordering::Relaxed);
+                debug!(
+                    "Rejecting packet from account {} because the {} priority queue is full",
+                    request.from.id(),
+                    priority
+                );
+                return Box::new(err(reject(
+                    ErrorCode::T03_CONNECTOR_BUSY,
+                    b"Too many packets waiting to be processed",
+                )));
+            } else {
+                let (sender, receiver) = oneshot::channel();
+                queues.waiting[index].push_back(sender);
+                self.stats.classes[index]
+                    .queued
+                    .fetch_add(1, Ordering::Relaxed);
+                Some(receiver)
+            }
+        };
+
+        if let Some(receiver) = receiver {
+            let mut service = self.clone();
+            Box::new(
+                receiver
+                    .map_err(|_| reject(ErrorCode::T00_INTERNAL_ERROR, &[]))
+                    .and_then(move |_| service.process(request, priority)),
+            )
+        } else {
+            self.process(request, priority)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::sync::mpsc::unbounded;
+    use futures::Stream;
+    use interledger_packet::{Fulfill, FulfillBuilder, PrepareBuilder};
+    use std::time::Duration;
+
+    #[derive(Clone, Debug)]
+    struct TestAccount(u64, Priority);
+
+    impl Account for TestAccount {
+        type AccountId = u64;
+
+        fn id(&self) -> u64 {
+            self.0
+        }
+    }
+
+    impl PriorityAccount for TestAccount {
+        fn priority(&self) -> Priority {
+            self.1
+        }
+    }
+
+    fn request(id: u64, priority: Priority) -> IncomingRequest<TestAccount> {
+        IncomingRequest {
+            from: TestAccount(id, priority),
+            prepare: PrepareBuilder {
+                destination: b"example.destination",
+                amount: 100,
+                expires_at: SystemTime::now() + Duration::from_secs(30),
+                execution_condition: &[0; 32],
+                data: &[],
+            }
+            .build(),
+        }
+    }
+
+    fn fulfill() -> Fulfill {
+        FulfillBuilder {
+            fulfillment: &[0; 32],
+            data: &[],
+        }
+        .build()
+    }
+
+    #[test]
+    fn parses_priorities() {
+        assert_eq!(Priority::from_str("High"), Ok(Priority::High));
+        assert_eq!(Priority::from_str("low"), Ok(Priority::Low));
+        assert!(Priority::from_str("urgent").is_err());
+        assert_eq!(Priority::default(), Priority::Normal);
+    }
+
+    #[test]
+    fn processes_waiting_packets_by_priority() {
+        // The first packet holds the only slot until it is answered
+        let (hold_sender, hold_receiver) = oneshot::channel::<()>();
+        let hold_receiver = Arc::new(Mutex::new(Some(hold_receiver)));
+        let (order_sender, order_receiver) = unbounded();
+        let stats = Arc::new(PriorityStats::default());
+        let mut service = PriorityService::with_stats(
+            stats.clone(),
+            1,
+            incoming_service_fn(move |request: IncomingRequest<TestAccount>| {
+                order_sender.unbounded_send(request.from.0).unwrap();
+                let hold = hold_receiver.lock().unwrap().take();
+                let result: BoxedIlpFuture = if let Some(hold) = hold {
+                    Box::new(
+                        hold.map_err(|_| reject(ErrorCode::T00_INTERNAL_ERROR, &[]))
+                            .map(|_| fulfill()),
+                    )
+                } else {
+                    Box::new(futures::future::ok(fulfill()))
+                };
+                result
+            }),
+        );
+
+        let first = service.handle_request(request(0, Priority::Low));
+        let low = service.handle_request(request(1, Priority::Low));
+        let normal = service.handle_request(request(2, Priority::Normal));
+        let high = service.handle_request(request(3, Priority::High));
+        assert_eq!(stats.queued(Priority::Low), 1);
+        assert_eq!(stats.queued(Priority::High), 1);
+
+        hold_sender.send(()).unwrap();
+        first.join4(low, normal, high).wait().unwrap();
+        drop(service);
+        let order: Vec<u64> = order_receiver.collect().wait().unwrap();
+        assert_eq!(order, vec![0, 3, 2, 1]);
+        assert_eq!(stats.processed(Priority::Low), 2);
+        assert_eq!(stats.queued(Priority::Low), 0);
+    }
+
+    #[test]
+    fn rejects_packets_when_queue_is_full() {
+        let (_hold_sender, hold_receiver) = oneshot::channel::<()>();
+        let hold_receiver = Arc::new(Mutex::new(Some(hold_receiver)));
+        let stats = Arc::new(PriorityStats::default());
+        let mut service = PriorityService::with_stats(
+            stats.clone(),
+            1,
+            incoming_service_fn(move |_| {
+                let hold = hold_receiver.lock().unwrap().take().unwrap();
+                hold.map_err(|_| reject(ErrorCode::T00_INTERNAL_ERROR, &[]))
+                    .map(|_| fulfill())
+            }),
+        )
+        .max_queued(1);
+
+        let _first = service.handle_request(request(0, Priority::Normal));
+        let _queued = service.handle_request(request(1, Priority::Normal));
+        let reject = service
+            .handle_request(request(2, Priority::Normal))
+            .wait()
+            .unwrap_err();
+        assert_eq!(reject.code(), ErrorCode::T03_CONNECTOR_BUSY);
+        assert_eq!(stats.rejected(Priority::Normal), 1);
+        assert_eq!(stats.rejected(Priority::High), 0);
+    }
+
+    #[test]
+    fn low_priority_packets_are_not_starved() {
+        let mut queues = Queues {
+            in_flight: 1,
+            ..Queues::default()
+        };
+        let stats = PriorityStats::default();
+        let mut receivers = Vec::new();
+        for priority in [Priority::High, Priority::Low].iter() {
+            for _ in 0..7 {
+                let (sender, receiver) = oneshot::channel();
+                queues.waiting[priority.index()].push_back(sender);
+                stats.classes[priority.index()]
+                    .queued
+                    .fetch_add(1, Ordering::Relaxed);
+                receivers.push(receiver);
+            }
+        }
+        // Without normal priority packets waiting, their turns go to the high priority
+        // packets, but low priority packets still get 1 of every 5 slots
+        for _ in 0..5 {
+            queues.hand_over(&stats);
+        }
+        assert_eq!(stats.queued(Priority::High), 3);
+        assert_eq!(stats.queued(Priority::Low), 6);
+    }
+}
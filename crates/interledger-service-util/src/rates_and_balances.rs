@@ -1,32 +1,48 @@
+use super::fees::FeeStore;
+use super::stats::StatsStore;
 use futures::{future::err, Future};
 use interledger_ildcp::IldcpAccount;
 use interledger_packet::{ErrorCode, Fulfill, Reject, RejectBuilder};
 use interledger_service::*;
+use std::time::SystemTime;
 
 pub trait BalanceStore: AccountStore {
     /// Fetch the current balance for the given account.
-    fn get_balance(&self, account: Self::Account) -> Box<Future<Item = i64, Error = ()> + Send>;
+    fn get_balance(
+        &self,
+        account: Self::Account,
+    ) -> Box<Future<Item = i64, Error = StoreError> + Send>;
 
     /// Subtract the `incoming_amount` from the `from_account`'s balance.
     /// Add the `outgoing_amount` to the `to_account`'s balance.
+    ///
+    /// The update is journaled as in flight until it is committed or undone, so that
+    /// the store can roll it back if the node stops before the packet, which expires
+    /// at `expires_at`, is resolved. Resolves to the ID of the journaled update.
     fn update_balances(
         &self,
         from_account: Self::Account,
         incoming_amount: u64,
         to_account: Self::Account,
         outgoing_amount: u64,
-    ) -> Box<Future<Item = (), Error = ()> + Send>;
+        expires_at: SystemTime,
+    ) -> Box<Future<Item = u64, Error = StoreError> + Send>;
+
+    /// Keep the effect of a previous `update_balances` call, because the packet was fulfilled.
+    /// Once this resolves, the update is never rolled back.
+    fn commit_balance_update(
+        &self,
+        update_id: u64,
+    ) -> Box<Future<Item = (), Error = StoreError> + Send>;
 
-    /// Roll back the effect of a previous `update_balances` call.
-    /// Add the `incoming_amount` to the `from_account`'s balance.
-    /// Subtract the `outgoing_amount` from the `to_account`'s balance.
+    /// Roll back the effect of a previous `update_balances` call:
+    /// add the incoming amount back to the `from_account`'s balance and
+    /// subtract the outgoing amount from the `to_account`'s balance.
+    /// Does nothing if the update was already rolled back.
     fn undo_balance_update(
         &self,
-        from_account: Self::Account,
-        incoming_amount: u64,
-        to_account: Self::Account,
-        outgoing_amount: u64,
-    ) -> Box<Future<Item = (), Error = ()> + Send>;
+        update_id: u64,
+    ) -> Box<Future<Item = (), Error = StoreError> + Send>;
 }
 
 pub trait ExchangeRateStore {
@@ -43,7 +59,7 @@ pub struct ExchangeRateAndBalanceService<S, T> {
 impl<S, T> ExchangeRateAndBalanceService<S, T>
 where
     S: OutgoingService<T::Account>,
-    T: ExchangeRateStore + BalanceStore,
+    T: ExchangeRateStore + BalanceStore + FeeStore + StatsStore,
 {
     pub fn new(store: T, next: S) -> Self {
         ExchangeRateAndBalanceService { next, store }
@@ -54,8 +70,8 @@ impl<S, T> OutgoingService<T::Account> for ExchangeRateAndBalanceService<S, T>
 where
     // TODO can we make these non-'static?
     S: OutgoingService<T::Account> + Send + Clone + 'static,
-    T: BalanceStore + ExchangeRateStore + Clone + Send + Sync + 'static,
-    T::Account: IldcpAccount + Send + Sync + 'static,
+    T: BalanceStore + ExchangeRateStore + FeeStore + StatsStore + Clone + Send + Sync + 'static,
+    <T as AccountStore>::Account: IldcpAccount + Send + Sync + 'static,
 {
     type Future = BoxedIlpFuture;
 
@@ -63,17 +79,38 @@ where
         &mut self,
         mut request: OutgoingRequest<<T as AccountStore>::Account>,
     ) -> Box<Future<Item = Fulfill, Error = Reject> + Send> {
+        let incoming_amount = request.prepare.amount();
+        let fee = self
+            .store
+            .get_fee_policy(&request.from, request.prepare.destination())
+            .map(|policy| policy.fee(incoming_amount))
+            .unwrap_or(0);
+        if fee >= incoming_amount && fee > 0 {
+            debug!(
+                "Rejecting packet because amount {} does not cover the fee of {}",
+                incoming_amount, fee
+            );
+            return Box::new(err(RejectBuilder {
+                code: ErrorCode::F99_APPLICATION_ERROR,
+                message: b"Amount does not cover fee",
+                triggered_by: &[],
+                data: &[],
+            }
+            .build()));
+        }
+        let amount_after_fee = incoming_amount - fee;
+
         let scale_change = u32::from(request.to.asset_scale() - request.from.asset_scale());
         let outgoing_amount = if request.from.asset_code() == request.to.asset_code() {
             debug!("Same currency. Forwarding request.");
-            request.prepare.amount() * 10u64.pow(scale_change)
+            amount_after_fee * 10u64.pow(scale_change)
         } else if let Ok(rates) = self
             .store
             .get_exchange_rates(&[&request.from.asset_code(), &request.to.asset_code()])
         {
             // TODO use bignums to make sure none of these numbers overflow
             let outgoing_amount = (rates[1] / rates[0]
-                * request.prepare.amount() as f64
+                * amount_after_fee as f64
                 * 10u64.pow(scale_change) as f64) as u64;
             debug!("Converted incoming amount of {} {} (scale: {}) to outgoing amount of {} {} (scale: {})", request.prepare.amount(), request.from.asset_code(), request.from.asset_scale(), outgoing_amount, request.to.asset_code(), request.to.asset_scale());
             outgoing_amount
@@ -96,29 +133,68 @@ where
         let store = self.store.clone();
         let from = request.from.clone();
         let to = request.to.clone();
-        let incoming_amount = request.prepare.amount();
+        let stats_store = self.store.clone();
+        let stats_from = request.from.clone();
+        let stats_to = request.to.clone();
+        let reject_stats_store = self.store.clone();
+        let reject_stats_to = request.to.clone();
+        let destination = request.prepare.destination().to_vec();
+        let reject_destination = destination.clone();
+        let expires_at = request.prepare.expires_at();
 
         request.prepare.set_amount(outgoing_amount);
         Box::new(
             self.store
-                .update_balances(from.clone(), incoming_amount, to.clone(), outgoing_amount)
-                .map_err(|_| {
-                    debug!("Rejecting packet because it would exceed a balance limit");
+                .update_balances(from.clone(), incoming_amount, to.clone(), outgoing_amount, expires_at)
+                .map_err(|err| {
+                    let code = if err == StoreError::InsufficientBalance {
+                        debug!("Rejecting packet because it would exceed a balance limit");
+                        ErrorCode::T04_INSUFFICIENT_LIQUIDITY
+                    } else {
+                        error!("Error updating balances: {}", err);
+                        ErrorCode::T00_INTERNAL_ERROR
+                    };
                     RejectBuilder {
-                        code: ErrorCode::T04_INSUFFICIENT_LIQUIDITY,
+                        code,
                         message: &[],
                         triggered_by: &[],
                         data: &[],
                     }
                     .build()
                 })
-                .and_then(move |_| {
+                .and_then(move |update_id| {
                     next.send_request(request)
-                        .or_else(move |err| store.undo_balance_update(from.clone(), incoming_amount, to.clone(), outgoing_amount)
+                        .and_then(move |fulfill| {
+                            debug!(
+                                "Forwarded packet account_id={} destination={} amount={}",
+                                stats_from.id(),
+                                String::from_utf8_lossy(&destination),
+                                incoming_amount
+                            );
+                            stats_store.record_forwarded_packet(&stats_from, incoming_amount, &stats_to, outgoing_amount, fee);
+                            stats_store.record_destination_packet(&destination, &stats_to, outgoing_amount, true);
+                            stats_store
+                                .commit_balance_update(update_id)
+                                .then(move |result| {
+                                    if result.is_err() {
+                                        error!("Error committing balance update {}", update_id);
+                                    }
+                                    Ok(fulfill)
+                                })
+                        })
+                        .or_else(move |err| store.undo_balance_update(update_id)
                         .then(move |result| {
+                            debug!(
+                                "Rejected packet account_id={} destination={} amount={} code={}",
+                                from.id(),
+                                String::from_utf8_lossy(&reject_destination),
+                                incoming_amount,
+                                err.code()
+                            );
                             if result.is_err() {
                                 error!("Error rolling back balance change for accounts: {} and {}. Incoming amount was: {}, outgoing amount was: {}", from.id(), to.id(), incoming_amount, outgoing_amount);
                             }
+                            reject_stats_store.record_destination_packet(&reject_destination, &reject_stats_to, 0, false);
                             Err(err)
                         }))
                 }),
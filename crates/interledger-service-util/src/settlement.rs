@@ -0,0 +1,381 @@
+use futures::{
+    future::{err, ok, result, Either},
+    Future,
+};
+use interledger_packet::{ErrorCode, FulfillBuilder, PrepareBuilder, Reject, RejectBuilder};
+use interledger_service::*;
+use serde::{Deserialize, Serialize};
+use std::{
+    marker::PhantomData,
+    time::{Duration, SystemTime},
+};
+
+/// The destination of the packets peers exchange their settlement details with
+pub const SETTLEMENT_INFO_DESTINATION: &[u8] = b"peer.settle";
+// Like the other peer protocols, messages are fulfilled with 32 zero bytes
+const PEER_PROTOCOL_FULFILLMENT: [u8; 32] = [0; 32];
+const PEER_PROTOCOL_CONDITION: [u8; 32] = [
+    102, 104, 122, 173, 248, 98, 189, 119, 108, 143, 193, 139, 142, 159, 142, 32, 8, 151, 20, 133,
+    110, 226, 51, 179, 144, 42, 89, 29, 13, 95, 41, 37,
+];
+const SETTLEMENT_INFO_EXPIRY: Duration = Duration::from_secs(30);
+
+/// Accounts that are settled by a settlement engine.
+pub trait SettlementAccount: Account {
+    /// The balance at which the engine should send a settlement to the account, if any.
+    fn settle_threshold(&self) -> Option<i64>;
+
+    /// The balance the engine should bring the account back to when it settles.
+    fn settle_to(&self) -> Option<i64>;
+}
+
+/// Accounts that settle on the XRP Ledger.
+pub trait XrpAccount: SettlementAccount {
+    /// The XRP Ledger address that settlements for the account are sent to and received from.
+    fn xrp_address(&self) -> Option<&str>;
+}
+
+/// The details a peer needs to settle with us, which peers send each other in
+/// `peer.settle` messages so they do not have to be configured by hand.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SettlementInfo {
+    /// Where the peer's settlement engine can reach ours
+    pub engine_url: Option<String>,
+    /// The ledger settlements are made on, such as "XRP"
+    pub ledger: Option<String>,
+    /// Our address on that ledger
+    pub ledger_address: Option<String>,
+    /// The balance at which we settle with the peer
+    pub settle_threshold: Option<i64>,
+    /// The balance we settle down to
+    pub settle_to: Option<i64>,
+}
+
+impl SettlementInfo {
+    /// The details to send to the given account, including the thresholds we settle it at.
+    pub fn for_account<A: SettlementAccount>(&self, account: &A) -> Self {
+        SettlementInfo {
+            settle_threshold: account.settle_threshold(),
+            settle_to: account.settle_to(),
+            ..self.clone()
+        }
+    }
+}
+
+pub trait SettlementInfoStore {
+    type Account: Account;
+
+    /// Save the settlement details the peer sent, replacing the ones it sent before.
+    fn set_peer_settlement_info(
+        &self,
+        account_id: <Self::Account as Account>::AccountId,
+        info: SettlementInfo,
+    ) -> Box<Future<Item = (), Error = ()> + Send>;
+
+    /// Get the settlement details the peer sent most recently, if it sent any.
+    fn get_peer_settlement_info(
+        &self,
+        account_id: <Self::Account as Account>::AccountId,
+    ) -> Box<Future<Item = Option<SettlementInfo>, Error = ()> + Send>;
+}
+
+/// # Settlement Info Service
+///
+/// Answers `peer.settle` messages by saving the settlement details the peer sent and
+/// responding with ours. Use `exchange_settlement_info` to send ours to a peer.
+#[derive(Clone)]
+pub struct SettlementInfoService<S, T, A> {
+    next: S,
+    store: T,
+    info: SettlementInfo,
+    account_type: PhantomData<A>,
+}
+
+impl<S, T, A> SettlementInfoService<S, T, A>
+where
+    S: IncomingService<A>,
+    T: SettlementInfoStore<Account = A>,
+    A: SettlementAccount,
+{
+    pub fn new(info: SettlementInfo, store: T, next: S) -> Self {
+        SettlementInfoService {
+            next,
+            store,
+            info,
+            account_type: PhantomData,
+        }
+    }
+}
+
+impl<S, T, A> IncomingService<A> for SettlementInfoService<S, T, A>
+where
+    S: IncomingService<A>,
+    T: SettlementInfoStore<Account = A> + Clone + Send + 'static,
+    A: SettlementAccount + 'static,
+{
+    type Future = BoxedIlpFuture;
+
+    fn handle_request(&mut self, request: IncomingRequest<A>) -> Self::Future {
+        if request.prepare.destination() != SETTLEMENT_INFO_DESTINATION {
+            return Box::new(self.next.handle_request(request));
+        }
+        let info: SettlementInfo = match serde_json::from_slice(request.prepare.data()) {
+            Ok(info) if request.prepare.execution_condition() == PEER_PROTOCOL_CONDITION => info,
+            _ => {
+                return Box::new(err(reject(
+                    ErrorCode::F01_INVALID_PACKET,
+                    "Invalid settlement info message",
+                )))
+            }
+        };
+        debug!(
+            "Got settlement info from account {:?}: {:?}",
+            request.from.id(),
+            info
+        );
+        let response = serde_json::to_vec(&self.info.for_account(&request.from)).unwrap();
+        Box::new(
+            save_peer_settlement_info(self.store.clone(), request.from.id(), info)
+                .map_err(|_| {
+                    reject(
+                        ErrorCode::T00_INTERNAL_ERROR,
+                        "Unable to save settlement info",
+                    )
+                })
+                .map(move |_| {
+                    FulfillBuilder {
+                        fulfillment: &PEER_PROTOCOL_FULFILLMENT,
+                        data: &response[..],
+                    }
+                    .build()
+                }),
+        )
+    }
+}
+
+/// Send our settlement details to the peer through the given service and save the ones it
+/// responds with. `from` is the account the message is sent from, usually the node's own.
+pub fn exchange_settlement_info<S, T, A>(
+    mut service: S,
+    store: T,
+    info: &SettlementInfo,
+    from: A,
+    to: A,
+) -> impl Future<Item = SettlementInfo, Error = ()>
+where
+    S: OutgoingService<A>,
+    T: SettlementInfoStore<Account = A>,
+    A: SettlementAccount,
+{
+    let data = serde_json::to_vec(&info.for_account(&to)).unwrap();
+    let to_id = to.id();
+    let prepare = PrepareBuilder {
+        destination: SETTLEMENT_INFO_DESTINATION,
+        amount: 0,
+        expires_at: SystemTime::now() + SETTLEMENT_INFO_EXPIRY,
+        execution_condition: &PEER_PROTOCOL_CONDITION,
+        data: &data[..],
+    }
+    .build();
+    service
+        .send_request(OutgoingRequest { from, to, prepare })
+        .map_err(move |reject| {
+            debug!(
+                "Account {:?} rejected our settlement info: {:?}",
+                to_id, reject
+            )
+        })
+        .and_then(move |fulfill| {
+            result(
+                serde_json::from_slice::<SettlementInfo>(fulfill.data()).map_err(|err| {
+                    warn!(
+                        "Account {:?} responded with invalid settlement info: {:?}",
+                        to_id, err
+                    )
+                }),
+            )
+        })
+        .and_then(move |info| {
+            if info == SettlementInfo::default() {
+                // The peer has nothing to tell us, so keep what it sent before
+                Either::A(ok(info))
+            } else {
+                Either::B(save_peer_settlement_info(store, to_id, info.clone()).map(move |_| info))
+            }
+        })
+}
+
+// Peers send their details every time they exchange them, so they are only saved when they changed
+fn save_peer_settlement_info<T, A>(
+    store: T,
+    account_id: A::AccountId,
+    info: SettlementInfo,
+) -> impl Future<Item = (), Error = ()>
+where
+    T: SettlementInfoStore<Account = A>,
+    A: Account,
+{
+    store
+        .get_peer_settlement_info(account_id)
+        .and_then(move |saved| {
+            if saved.as_ref() == Some(&info) {
+                return Either::A(ok(()));
+            }
+            info!(
+                "Account {:?} sent new settlement details: {:?}",
+                account_id, info
+            );
+            Either::B(store.set_peer_settlement_info(account_id, info))
+        })
+}
+
+fn reject(code: ErrorCode, message: &str) -> Reject {
+    RejectBuilder {
+        code,
+        message: message.as_bytes(),
+        triggered_by: &[],
+        data: &[],
+    }
+    .build()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use interledger_packet::Fulfill;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Debug)]
+    struct TestAccount(u64);
+
+    impl Account for TestAccount {
+        type AccountId = u64;
+
+        fn id(&self) -> u64 {
+            self.0
+        }
+    }
+
+    impl SettlementAccount for TestAccount {
+        fn settle_threshold(&self) -> Option<i64> {
+            Some(1000)
+        }
+
+        fn settle_to(&self) -> Option<i64> {
+            Some(0)
+        }
+    }
+
+    #[derive(Clone, Default)]
+    struct TestStore(Arc<Mutex<Vec<(u64, SettlementInfo)>>>);
+
+    impl SettlementInfoStore for TestStore {
+        type Account = TestAccount;
+
+        fn set_peer_settlement_info(
+            &self,
+            account_id: u64,
+            info: SettlementInfo,
+        ) -> Box<Future<Item = (), Error = ()> + Send> {
+            self.0.lock().unwrap().push((account_id, info));
+            Box::new(ok(()))
+        }
+
+        fn get_peer_settlement_info(
+            &self,
+            account_id: u64,
+        ) -> Box<Future<Item = Option<SettlementInfo>, Error = ()> + Send> {
+            let saved = self.0.lock().unwrap();
+            Box::new(ok(saved
+                .iter()
+                .rev()
+                .find(|(id, _)| *id == account_id)
+                .map(|(_, info)| info.clone())))
+        }
+    }
+
+    fn info(ledger_address: &str) -> SettlementInfo {
+        SettlementInfo {
+            engine_url: Some("https://engine.example".to_string()),
+            ledger: Some("XRP".to_string()),
+            ledger_address: Some(ledger_address.to_string()),
+            ..SettlementInfo::default()
+        }
+    }
+
+    #[test]
+    fn exchanges_settlement_info() {
+        let alice_store = TestStore::default();
+        let bob_store = TestStore::default();
+        // Bob's service answers the messages Alice sends
+        let bob = SettlementInfoService::new(
+            info("rBob"),
+            bob_store.clone(),
+            incoming_service_fn(|_| -> Result<Fulfill, Reject> {
+                panic!("Settlement info messages should not be passed on")
+            }),
+        );
+        let bob = Arc::new(Mutex::new(bob));
+        let outgoing = outgoing_service_fn(move |request: OutgoingRequest<TestAccount>| {
+            bob.lock().unwrap().handle_request(IncomingRequest {
+                from: TestAccount(1),
+                prepare: request.prepare,
+            })
+        });
+
+        let received = exchange_settlement_info(
+            outgoing,
+            alice_store.clone(),
+            &info("rAlice"),
+            TestAccount(0),
+            TestAccount(2),
+        )
+        .wait()
+        .unwrap();
+        assert_eq!(received.ledger_address, Some("rBob".to_string()));
+        assert_eq!(received.settle_threshold, Some(1000));
+        assert_eq!(alice_store.0.lock().unwrap()[0], (2, received));
+
+        let sent = bob_store.0.lock().unwrap()[0].clone();
+        assert_eq!(sent.0, 1);
+        assert_eq!(sent.1.ledger_address, Some("rAlice".to_string()));
+        assert_eq!(sent.1.settle_to, Some(0));
+    }
+
+    #[test]
+    fn only_saves_changed_settlement_info() {
+        let store = TestStore::default();
+        save_peer_settlement_info(store.clone(), 1, info("rBob"))
+            .and_then(|_| save_peer_settlement_info(store.clone(), 1, info("rBob")))
+            .and_then(|_| save_peer_settlement_info(store.clone(), 1, info("rBob2")))
+            .wait()
+            .unwrap();
+        let saved = store.0.lock().unwrap();
+        assert_eq!(*saved, vec![(1, info("rBob")), (1, info("rBob2"))]);
+    }
+
+    #[test]
+    fn rejects_invalid_messages() {
+        let mut service = SettlementInfoService::new(
+            info("rBob"),
+            TestStore::default(),
+            incoming_service_fn(|_| -> Result<Fulfill, Reject> { unreachable!() }),
+        );
+        let reject = service
+            .handle_request(IncomingRequest {
+                from: TestAccount(1),
+                prepare: PrepareBuilder {
+                    destination: SETTLEMENT_INFO_DESTINATION,
+                    amount: 0,
+                    expires_at: SystemTime::now() + SETTLEMENT_INFO_EXPIRY,
+                    execution_condition: &PEER_PROTOCOL_CONDITION,
+                    data: b"not json",
+                }
+                .build(),
+            })
+            .wait()
+            .unwrap_err();
+        assert_eq!(reject.code(), ErrorCode::F01_INVALID_PACKET);
+    }
+}
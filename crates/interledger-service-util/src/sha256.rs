@@ -0,0 +1,136 @@
+//! SHA-256 of fulfillments using the CPU's SHA extensions.
+//!
+//! Fulfillments are always 32 bytes, so they fit in a single block and the hash takes one
+//! pass of the compression function. Fulfillments are checked on every packet, so on CPUs
+//! with the extensions this is used instead of ring's general-purpose implementation.
+
+/// Returns the SHA-256 hash of the 32-byte input, or `None` if the input has another length
+/// or the CPU does not support the SHA extensions.
+#[cfg(target_arch = "x86_64")]
+pub fn digest_fulfillment(input: &[u8]) -> Option<[u8; 32]> {
+    if input.len() != 32 || !x86::is_supported() {
+        return None;
+    }
+    let mut fulfillment = [0; 32];
+    fulfillment.copy_from_slice(input);
+    // Safe because the CPU was checked for the features the function uses
+    Some(unsafe { x86::digest_32(&fulfillment) })
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+pub fn digest_fulfillment(_input: &[u8]) -> Option<[u8; 32]> {
+    None
+}
+
+#[cfg(target_arch = "x86_64")]
+mod x86 {
+    use std::arch::x86_64::*;
+
+    const INITIAL_STATE: [u32; 8] = [
+        0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab,
+        0x5be0cd19,
+    ];
+
+    const K: [u32; 64] = [
+        0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4,
+        0xab1c5ed5, 0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe,
+        0x9bdc06a7, 0xc19bf174, 0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f,
+        0x4a7484aa, 0x5cb0a9dc, 0x76f988da, 0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7,
+        0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967, 0x27b70a85, 0x2e1b2138, 0x4d2c6dfc,
+        0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85, 0xa2bfe8a1, 0xa81a664b,
+        0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070, 0x19a4c116,
+        0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+        0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7,
+        0xc67178f2,
+    ];
+
+    pub fn is_supported() -> bool {
+        is_x86_feature_detected!("sha")
+            && is_x86_feature_detected!("sse2")
+            && is_x86_feature_detected!("ssse3")
+            && is_x86_feature_detected!("sse4.1")
+    }
+
+    /// SHA-256 of a 32-byte input, which fits in a single padded block.
+    /// Must only be called if `is_supported` returned true.
+    #[target_feature(enable = "sha,sse2,ssse3,sse4.1")]
+    pub unsafe fn digest_32(input: &[u8; 32]) -> [u8; 32] {
+        let mut block = [0u8; 64];
+        block[..32].copy_from_slice(&input[..]);
+        block[32] = 0x80;
+        // The length of the input in bits, big-endian
+        block[62] = 0x01;
+
+        // Swaps the bytes of each 32-bit word, since SHA-256 is big-endian
+        let mask = _mm_set_epi64x(0x0c0d_0e0f_0809_0a0b, 0x0405_0607_0001_0203);
+        let state = INITIAL_STATE.as_ptr() as *const __m128i;
+        let dcba = _mm_loadu_si128(state);
+        let efgh = _mm_loadu_si128(state.add(1));
+        let cdab = _mm_shuffle_epi32(dcba, 0xb1);
+        let efgh = _mm_shuffle_epi32(efgh, 0x1b);
+        let mut abef = _mm_alignr_epi8(cdab, efgh, 8);
+        let mut cdgh = _mm_blend_epi16(efgh, cdab, 0xf0);
+        let abef_initial = abef;
+        let cdgh_initial = cdgh;
+
+        let data = block.as_ptr() as *const __m128i;
+        let mut words = [
+            _mm_shuffle_epi8(_mm_loadu_si128(data), mask),
+            _mm_shuffle_epi8(_mm_loadu_si128(data.add(1)), mask),
+            _mm_shuffle_epi8(_mm_loadu_si128(data.add(2)), mask),
+            _mm_shuffle_epi8(_mm_loadu_si128(data.add(3)), mask),
+        ];
+        let k = K.as_ptr() as *const __m128i;
+        for i in 0..16 {
+            if i >= 4 {
+                // The message schedule for the next four rounds, from the previous sixteen words
+                let next = _mm_sha256msg1_epu32(words[i % 4], words[(i + 1) % 4]);
+                let next = _mm_add_epi32(
+                    next,
+                    _mm_alignr_epi8(words[(i + 3) % 4], words[(i + 2) % 4], 4),
+                );
+                words[i % 4] = _mm_sha256msg2_epu32(next, words[(i + 3) % 4]);
+            }
+            let rounds = _mm_add_epi32(words[i % 4], _mm_loadu_si128(k.add(i)));
+            cdgh = _mm_sha256rnds2_epu32(cdgh, abef, rounds);
+            abef = _mm_sha256rnds2_epu32(abef, cdgh, _mm_shuffle_epi32(rounds, 0x0e));
+        }
+        let abef = _mm_add_epi32(abef, abef_initial);
+        let cdgh = _mm_add_epi32(cdgh, cdgh_initial);
+
+        let feba = _mm_shuffle_epi32(abef, 0x1b);
+        let dchg = _mm_shuffle_epi32(cdgh, 0xb1);
+        let dcba = _mm_blend_epi16(feba, dchg, 0xf0);
+        let hgef = _mm_alignr_epi8(dchg, feba, 8);
+        let mut output = [0u8; 32];
+        let out = output.as_mut_ptr() as *mut __m128i;
+        _mm_storeu_si128(out, _mm_shuffle_epi8(dcba, mask));
+        _mm_storeu_si128(out.add(1), _mm_shuffle_epi8(hgef, mask));
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring::digest::{digest, SHA256};
+
+    #[test]
+    fn matches_ring() {
+        let mut input = [0; 32];
+        for i in 0..=255 {
+            for (j, byte) in input.iter_mut().enumerate() {
+                *byte = (i as u8).wrapping_mul(31).wrapping_add(j as u8);
+            }
+            if let Some(hash) = digest_fulfillment(&input) {
+                assert_eq!(&hash[..], digest(&SHA256, &input).as_ref());
+            }
+        }
+    }
+
+    #[test]
+    fn only_hashes_32_bytes() {
+        assert_eq!(digest_fulfillment(&[0; 31]), None);
+        assert_eq!(digest_fulfillment(&[0; 33]), None);
+    }
+}
@@ -0,0 +1,309 @@
+use futures::{future::err, Future};
+use interledger_packet::{ErrorCode, Reject, RejectBuilder};
+use interledger_service::*;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+use tokio::timer::Delay;
+
+/// How much longer than its delay a packet must have before it expires,
+/// so that it can still be forwarded and fulfilled once it is sent on
+const MIN_TIME_REMAINING: Duration = Duration::from_millis(500);
+
+/// Accounts whose traffic is smoothed to a maximum value per second.
+pub trait ShapingAccount: Account {
+    /// The largest amount, in the account's units, that should be sent to or received from
+    /// this account each second on average, or `None` if its traffic is not shaped.
+    fn max_value_per_second(&self) -> Option<u64>;
+}
+
+/// # Shaping Service
+///
+/// Keeps the value of the packets exchanged with each account within its
+/// `max_value_per_second` by delaying packets rather than rejecting them, so streaming
+/// payments to or from a peer are slowed down instead of failing. Up to one second's worth
+/// of value is passed on without delay, so short bursts are not smoothed out.
+///
+/// Packets are only rejected, with `T05: Rate Limited`, if they would expire before their
+/// turn to be sent (leaving a little time to forward them). Rejected packets do not count
+/// towards the account's value.
+///
+/// As an IncomingService, it shapes the packets received from each account, and as an
+/// OutgoingService, the packets sent to each account.
+pub struct ShapingService<S, A: Account> {
+    next: S,
+    // When each account's already scheduled packets will have been "paid off" at its rate
+    schedules: Arc<Mutex<HashMap<A::AccountId, Instant>>>,
+}
+
+impl<S, A> Clone for ShapingService<S, A>
+where
+    S: Clone,
+    A: Account,
+{
+    fn clone(&self) -> Self {
+        ShapingService {
+            next: self.next.clone(),
+            schedules: self.schedules.clone(),
+        }
+    }
+}
+
+impl<S, A> ShapingService<S, A>
+where
+    A: ShapingAccount,
+{
+    pub fn new(next: S) -> Self {
+        ShapingService {
+            next,
+            schedules: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// How long to wait before passing on a packet of the given amount, or a reject
+    /// if the packet would expire before then
+    fn delay(&self, account: &A, amount: u64, expires_at: SystemTime) -> Result<Duration, Reject> {
+        let rate = match account.max_value_per_second() {
+            Some(rate) if rate > 0 => rate,
+            _ => return Ok(Duration::from_secs(0)),
+        };
+        let now = Instant::now();
+        let mut schedules = self.schedules.lock().unwrap();
+        let paid_off = schedules
+            .get(&account.id())
+            .cloned()
+            .unwrap_or(now)
+            .max(now);
+        let nanos = u128::from(amount) * 1_000_000_000 / u128::from(rate);
+        let cost = Duration::from_nanos(nanos.min(u128::from(u64::max_value())) as u64);
+        let next_paid_off = paid_off + cost;
+        // Allow a burst of one second's worth of value
+        let send_at = next_paid_off
+            .checked_sub(Duration::from_secs(1))
+            .unwrap_or(now)
+            .max(now);
+        let delay = send_at - now;
+
+        let remaining = expires_at
+            .duration_since(SystemTime::now())
+            .unwrap_or_else(|_| Duration::from_secs(0));
+        if delay + MIN_TIME_REMAINING > remaining {
+            debug!(
+                "Rejecting packet for account {} because it would expire before it could be sent (delay: {}ms)",
+                account.id(),
+                delay.as_millis()
+            );
+            return Err(RejectBuilder {
+                code: ErrorCode::T05_RATE_LIMITED,
+                message: b"Exceeded the account's maximum value per second",
+                triggered_by: &[],
+                data: &[],
+            }
+            .build());
+        }
+
+        schedules.insert(account.id(), next_paid_off);
+        Ok(delay)
+    }
+}
+
+impl<S, A> IncomingService<A> for ShapingService<S, A>
+where
+    S: IncomingService<A> + Clone + Send + 'static,
+    A: ShapingAccount + 'static,
+{
+    type Future = BoxedIlpFuture;
+
+    fn handle_request(&mut self, request: IncomingRequest<A>) -> Self::Future {
+        let delay = match self.delay(
+            &request.from,
+            request.prepare.amount(),
+            request.prepare.expires_at(),
+        ) {
+            Ok(delay) => delay,
+            Err(reject) => return Box::new(err(reject)),
+        };
+        if delay == Duration::from_secs(0) {
+            return Box::new(self.next.handle_request(request));
+        }
+        trace!(
+            "Delaying packet from account {} by {}ms",
+            request.from.id(),
+            delay.as_millis()
+        );
+        let mut next = self.next.clone();
+        Box::new(Delay::new(Instant::now() + delay).then(move |_| next.handle_request(request)))
+    }
+}
+
+impl<S, A> OutgoingService<A> for ShapingService<S, A>
+where
+    S: OutgoingService<A> + Clone + Send + 'static,
+    A: ShapingAccount + 'static,
+{
+    type Future = BoxedIlpFuture;
+
+    fn send_request(&mut self, request: OutgoingRequest<A>) -> Self::Future {
+        let delay = match self.delay(
+            &request.to,
+            request.prepare.amount(),
+            request.prepare.expires_at(),
+        ) {
+            Ok(delay) => delay,
+            Err(reject) => return Box::new(err(reject)),
+        };
+        if delay == Duration::from_secs(0) {
+            return Box::new(self.next.send_request(request));
+        }
+        trace!(
+            "Delaying packet to account {} by {}ms",
+            request.to.id(),
+            delay.as_millis()
+        );
+        let mut next = self.next.clone();
+        Box::new(Delay::new(Instant::now() + delay).then(move |_| next.send_request(request)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use interledger_packet::{Fulfill, FulfillBuilder, PrepareBuilder};
+    use tokio::runtime::Runtime;
+
+    #[derive(Clone, Debug)]
+    struct TestAccount(u64, Option<u64>);
+
+    impl Account for TestAccount {
+        type AccountId = u64;
+
+        fn id(&self) -> u64 {
+            self.0
+        }
+    }
+
+    impl ShapingAccount for TestAccount {
+        fn max_value_per_second(&self) -> Option<u64> {
+            self.1
+        }
+    }
+
+    fn request(
+        from: TestAccount,
+        amount: u64,
+        expires_in: Duration,
+    ) -> IncomingRequest<TestAccount> {
+        IncomingRequest {
+            from,
+            prepare: PrepareBuilder {
+                destination: b"example.destination",
+                amount,
+                expires_at: SystemTime::now() + expires_in,
+                execution_condition: &[0; 32],
+                data: &[],
+            }
+            .build(),
+        }
+    }
+
+    fn fulfill() -> Result<Fulfill, Reject> {
+        Ok(FulfillBuilder {
+            fulfillment: &[0; 32],
+            data: &[],
+        }
+        .build())
+    }
+
+    #[test]
+    fn does_not_delay_unshaped_accounts() {
+        let service =
+            ShapingService::new(incoming_service_fn(|_: IncomingRequest<TestAccount>| {
+                fulfill()
+            }));
+        let delay = service.delay(
+            &TestAccount(0, None),
+            u64::max_value(),
+            SystemTime::now() + Duration::from_secs(30),
+        );
+        assert_eq!(delay.unwrap(), Duration::from_secs(0));
+    }
+
+    #[test]
+    fn allows_one_second_burst() {
+        let service =
+            ShapingService::new(incoming_service_fn(|_: IncomingRequest<TestAccount>| {
+                fulfill()
+            }));
+        let account = TestAccount(0, Some(1000));
+        let expires_at = SystemTime::now() + Duration::from_secs(30);
+        assert_eq!(
+            service.delay(&account, 600, expires_at).unwrap(),
+            Duration::from_secs(0)
+        );
+        assert_eq!(
+            service.delay(&account, 400, expires_at).unwrap(),
+            Duration::from_secs(0)
+        );
+        let delay = service.delay(&account, 500, expires_at).unwrap();
+        assert!(delay > Duration::from_millis(400) && delay <= Duration::from_millis(500));
+    }
+
+    #[test]
+    fn shapes_accounts_separately() {
+        let service =
+            ShapingService::new(incoming_service_fn(|_: IncomingRequest<TestAccount>| {
+                fulfill()
+            }));
+        let expires_at = SystemTime::now() + Duration::from_secs(30);
+        service
+            .delay(&TestAccount(0, Some(100)), 1000, expires_at)
+            .unwrap();
+        assert_eq!(
+            service
+                .delay(&TestAccount(1, Some(100)), 100, expires_at)
+                .unwrap(),
+            Duration::from_secs(0)
+        );
+    }
+
+    #[test]
+    fn rejects_packets_that_would_expire_while_delayed() {
+        let mut service = ShapingService::new(incoming_service_fn(|_| fulfill()));
+        let account = TestAccount(0, Some(100));
+        service
+            .delay(&account, 200, SystemTime::now() + Duration::from_secs(30))
+            .unwrap();
+        let reject = Runtime::new()
+            .unwrap()
+            .block_on(service.handle_request(request(account.clone(), 100, Duration::from_secs(1))))
+            .unwrap_err();
+        assert_eq!(reject.code(), ErrorCode::T05_RATE_LIMITED);
+
+        // The rejected packet did not use up any of the account's value
+        let delay = service
+            .delay(&account, 100, SystemTime::now() + Duration::from_secs(30))
+            .unwrap();
+        assert!(delay > Duration::from_millis(1900) && delay <= Duration::from_secs(2));
+    }
+
+    #[test]
+    fn delays_packets_over_the_rate() {
+        let mut service = ShapingService::new(incoming_service_fn(|_| fulfill()));
+        let account = TestAccount(0, Some(1000));
+        let start = Instant::now();
+        let mut runtime = Runtime::new().unwrap();
+        runtime
+            .block_on(service.handle_request(request(
+                account.clone(),
+                1000,
+                Duration::from_secs(30),
+            )))
+            .unwrap();
+        runtime
+            .block_on(service.handle_request(request(account, 100, Duration::from_secs(30))))
+            .unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(90));
+    }
+}
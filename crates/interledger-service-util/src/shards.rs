@@ -0,0 +1,235 @@
+use futures::{
+    future::err,
+    sync::{mpsc, oneshot},
+    Future, Stream,
+};
+use interledger_packet::{ErrorCode, Fulfill, Reject, RejectBuilder};
+use interledger_service::*;
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    thread,
+};
+use tokio::runtime::current_thread;
+
+/// How many packets may wait for each shard by default
+pub const DEFAULT_SHARD_QUEUE: usize = 1000;
+
+type Job<A> = (IncomingRequest<A>, oneshot::Sender<Result<Fulfill, Reject>>);
+
+/// Jump consistent hash (Lamping and Veach), which maps the key to one of the buckets
+/// and only moves 1/n of the keys when the number of buckets changes
+fn jump_hash(mut key: u64, buckets: usize) -> usize {
+    let mut bucket: i64 = -1;
+    let mut next: i64 = 0;
+    while next < buckets as i64 {
+        bucket = next;
+        key = key.wrapping_mul(2_862_933_555_777_941_757).wrapping_add(1);
+        next = ((bucket + 1) as f64 * ((1u64 << 31) as f64 / ((key >> 33) + 1) as f64)) as i64;
+    }
+    bucket as usize
+}
+
+fn reject(code: ErrorCode, message: &[u8]) -> Reject {
+    RejectBuilder {
+        code,
+        message,
+        triggered_by: &[],
+        data: &[],
+    }
+    .build()
+}
+
+/// # Sharded Service
+///
+/// Spreads the processing of incoming packets across worker threads. Each account's packets
+/// always go to the same shard, picked by consistent hashing on the account ID, and each shard
+/// runs its own single-threaded executor with its own clone of the next service. State that
+/// the next services keep by value is therefore only ever touched by one thread, and packets
+/// from one account are never processed concurrently on different cores.
+///
+/// Packets are rejected with `T03: Connector Busy` if their shard's queue is full.
+#[derive(Clone)]
+pub struct ShardedService<S, A: Account> {
+    next: S,
+    shards: Vec<mpsc::Sender<Job<A>>>,
+}
+
+impl<S, A> ShardedService<S, A>
+where
+    S: IncomingService<A> + Clone + Send + 'static,
+    A: Account + 'static,
+{
+    /// Process packets on `num_shards` worker threads. If it is 0, packets are
+    /// passed straight to the next service instead.
+    pub fn new(num_shards: usize, next: S) -> Self {
+        ShardedService::with_queue(num_shards, DEFAULT_SHARD_QUEUE, next)
+    }
+
+    /// Like `new`, but with at most `max_queued` packets waiting for each shard
+    pub fn with_queue(num_shards: usize, max_queued: usize, next: S) -> Self {
+        let shards = (0..num_shards)
+            .map(|index| {
+                let (sender, receiver) = mpsc::channel::<Job<A>>(max_queued);
+                let mut service = next.clone();
+                thread::Builder::new()
+                    .name(format!("ilp-shard-{}", index))
+                    .spawn(move || {
+                        let mut runtime = current_thread::Runtime::new()
+                            .expect("Unable to start runtime for shard");
+                        // The shard stops when all of the senders are dropped
+                        let process = receiver.for_each(move |(request, responder)| {
+                            current_thread::spawn(service.handle_request(request).then(
+                                move |result| {
+                                    // The sender may have stopped waiting for the response
+                                    let _ = responder.send(result);
+                                    Ok(())
+                                },
+                            ));
+                            Ok(())
+                        });
+                        let _ = runtime.block_on(process);
+                        debug!("Shard {} stopped", index);
+                    })
+                    .expect("Unable to spawn thread for shard");
+                sender
+            })
+            .collect();
+        ShardedService { next, shards }
+    }
+
+    /// The shard the account's packets are processed on
+    pub fn shard_for(&self, account_id: A::AccountId) -> Option<usize> {
+        if self.shards.is_empty() {
+            return None;
+        }
+        let mut hasher = DefaultHasher::new();
+        account_id.hash(&mut hasher);
+        Some(jump_hash(hasher.finish(), self.shards.len()))
+    }
+}
+
+impl<S, A> IncomingService<A> for ShardedService<S, A>
+where
+    S: IncomingService<A> + Clone + Send + 'static,
+    A: Account + 'static,
+{
+    type Future = BoxedIlpFuture;
+
+    fn handle_request(&mut self, request: IncomingRequest<A>) -> Self::Future {
+        let index = match self.shard_for(request.from.id()) {
+            Some(index) => index,
+            None => return Box::new(self.next.handle_request(request)),
+        };
+        let (responder, response) = oneshot::channel();
+        let account_id = request.from.id();
+        if let Err(error) = self.shards[index].try_send((request, responder)) {
+            return if error.is_full() {
+                debug!(
+                    "Rejecting packet from account {} because shard {} is busy",
+                    account_id, index
+                );
+                Box::new(err(reject(
+                    ErrorCode::T03_CONNECTOR_BUSY,
+                    b"Too many packets waiting to be processed",
+                )))
+            } else {
+                error!("Shard {} is no longer running", index);
+                Box::new(err(reject(ErrorCode::T00_INTERNAL_ERROR, &[])))
+            };
+        }
+        Box::new(
+            response
+                .map_err(|_| reject(ErrorCode::T00_INTERNAL_ERROR, &[]))
+                .and_then(|result| result),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use interledger_packet::{FulfillBuilder, PrepareBuilder};
+    use std::{
+        sync::{Arc, Mutex},
+        time::{Duration, SystemTime},
+    };
+
+    #[derive(Clone, Debug)]
+    struct TestAccount(u64);
+
+    impl Account for TestAccount {
+        type AccountId = u64;
+
+        fn id(&self) -> u64 {
+            self.0
+        }
+    }
+
+    fn request(id: u64) -> IncomingRequest<TestAccount> {
+        IncomingRequest {
+            from: TestAccount(id),
+            prepare: PrepareBuilder {
+                destination: b"example.destination",
+                amount: 100,
+                expires_at: SystemTime::now() + Duration::from_secs(30),
+                execution_condition: &[0; 32],
+                data: &[],
+            }
+            .build(),
+        }
+    }
+
+    #[test]
+    fn moves_few_keys_when_buckets_are_added() {
+        let moved = (0..1000u64)
+            .filter(|key| jump_hash(*key, 10) != jump_hash(*key, 11))
+            .count();
+        assert!(moved < 200);
+        assert!((0..1000u64).all(|key| jump_hash(key, 10) < 10));
+    }
+
+    #[test]
+    fn processes_each_account_on_one_shard() {
+        let threads: Arc<Mutex<Vec<(u64, String)>>> = Arc::new(Mutex::new(Vec::new()));
+        let threads_clone = threads.clone();
+        let mut service = ShardedService::new(
+            4,
+            incoming_service_fn(move |request: IncomingRequest<TestAccount>| {
+                let name = thread::current().name().unwrap().to_string();
+                threads_clone.lock().unwrap().push((request.from.0, name));
+                Ok(FulfillBuilder {
+                    fulfillment: &[0; 32],
+                    data: &[],
+                }
+                .build())
+            }),
+        );
+
+        for id in 0..20 {
+            for _ in 0..3 {
+                service.handle_request(request(id)).wait().unwrap();
+            }
+        }
+
+        let threads = threads.lock().unwrap();
+        assert_eq!(threads.len(), 60);
+        for (id, name) in threads.iter() {
+            let shard = service.shard_for(*id).unwrap();
+            assert_eq!(name, &format!("ilp-shard-{}", shard));
+        }
+    }
+
+    #[test]
+    fn passes_packets_through_without_shards() {
+        let mut service = ShardedService::new(
+            0,
+            incoming_service_fn(|_request: IncomingRequest<TestAccount>| {
+                Err(reject(ErrorCode::F02_UNREACHABLE, &[]))
+            }),
+        );
+        assert!(service.shard_for(1).is_none());
+        let reject = service.handle_request(request(1)).wait().unwrap_err();
+        assert_eq!(reject.code(), ErrorCode::F02_UNREACHABLE);
+    }
+}
@@ -0,0 +1,80 @@
+use futures::Future;
+use interledger_service::AccountStore;
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+/// Volume forwarded from and to a single account, and the fees it was charged.
+/// All amounts are denominated in the account's asset and scale.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct AccountStats {
+    pub account_id: String,
+    pub asset_code: String,
+    /// Amount received from this account in packets that were forwarded and fulfilled
+    pub incoming: u64,
+    /// Amount sent to this account in packets that were fulfilled
+    pub outgoing: u64,
+    /// Fees charged to this account
+    pub fees: u64,
+}
+
+/// Forwarding stats for a single (UTC) day.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DailyStats {
+    /// Formatted as YYYY-MM-DD
+    pub date: String,
+    pub accounts: Vec<AccountStats>,
+}
+
+/// Traffic to one destination prefix, which is the first few segments of the destination addresses.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct PrefixStats {
+    pub prefix: String,
+    /// Packets forwarded to this prefix, whether or not they were fulfilled
+    pub packets: u64,
+    pub fulfilled: u64,
+    /// Amount sent in fulfilled packets by the asset code of the accounts they were forwarded to
+    pub amounts: BTreeMap<String, u64>,
+    /// Approximate number of distinct destination addresses packets were sent to
+    pub unique_destinations: u64,
+}
+
+pub trait StatsStore: AccountStore {
+    /// Record that a packet was forwarded and fulfilled.
+    ///
+    /// This is called for every packet, so stores should add it up in memory and write
+    /// the totals in batches instead of writing each packet. Amounts that do not fit
+    /// in the store are capped rather than wrapped around.
+    fn record_forwarded_packet(
+        &self,
+        from_account: &Self::Account,
+        incoming_amount: u64,
+        to_account: &Self::Account,
+        outgoing_amount: u64,
+        fee: u64,
+    );
+
+    /// Get the stats for each of the last `days` days, starting with today,
+    /// including the packets that were recorded but not written yet.
+    fn get_daily_stats(&self, days: usize) -> Box<Future<Item = Vec<DailyStats>, Error = ()> + Send>;
+
+    /// Get the stats for all packets forwarded since the node started tracking them,
+    /// including the packets that were recorded but not written yet.
+    fn get_total_stats(&self) -> Box<Future<Item = Vec<AccountStats>, Error = ()> + Send>;
+
+    /// Record that a packet was forwarded to the destination, whether or not it was fulfilled.
+    /// Like `record_forwarded_packet`, this is called for every packet.
+    fn record_destination_packet(
+        &self,
+        destination: &[u8],
+        to_account: &Self::Account,
+        amount: u64,
+        fulfilled: bool,
+    );
+
+    /// Get the stats for the destination prefixes that were sent the most packets, busiest first,
+    /// including the packets that were recorded but not written yet.
+    ///
+    /// Stores may only keep track of a limited number of prefixes, in which case
+    /// the counts are approximate.
+    fn get_prefix_stats(&self, limit: usize) -> Box<Future<Item = Vec<PrefixStats>, Error = ()> + Send>;
+}
@@ -1,18 +1,28 @@
+use super::{clock::ClockSkew, fulfillment::verify_fulfillment};
 use futures::{future::err, Future};
 use hex;
 use interledger_packet::{ErrorCode, RejectBuilder};
 use interledger_service::*;
-use ring::digest::{digest, SHA256};
 use std::marker::PhantomData;
-use std::time::{Duration, SystemTime};
+use std::sync::Arc;
+use std::time::Duration;
 use tokio::prelude::FutureExt;
 
 #[derive(Clone)]
 pub struct ValidatorService<S, A> {
     next: S,
+    clock: Arc<ClockSkew>,
     account_type: PhantomData<A>,
 }
 
+impl<S, A> ValidatorService<S, A> {
+    /// Check packets' expiries against the given clock, which can correct for the node's clock skew.
+    pub fn with_clock(mut self, clock: Arc<ClockSkew>) -> Self {
+        self.clock = clock;
+        self
+    }
+}
+
 impl<S, A> ValidatorService<S, A>
 where
     S: IncomingService<A>,
@@ -21,6 +31,7 @@ where
     pub fn incoming(next: S) -> Self {
         ValidatorService {
             next,
+            clock: Arc::new(ClockSkew::default()),
             account_type: PhantomData,
         }
     }
@@ -34,6 +45,7 @@ where
     pub fn outgoing(next: S) -> Self {
         ValidatorService {
             next,
+            clock: Arc::new(ClockSkew::default()),
             account_type: PhantomData,
         }
     }
@@ -47,17 +59,17 @@ where
     type Future = BoxedIlpFuture;
 
     fn handle_request(&mut self, request: IncomingRequest<A>) -> Self::Future {
-        if request.prepare.expires_at() >= SystemTime::now() {
+        let now = self.clock.now();
+        if request.prepare.expires_at() >= now {
             Box::new(self.next.handle_request(request))
         } else {
             error!(
                 "Incoming packet expired {}ms ago at {:?} (time now: {:?})",
-                SystemTime::now()
-                    .duration_since(request.prepare.expires_at())
+                now.duration_since(request.prepare.expires_at())
                     .unwrap_or_else(|_| Duration::from_secs(0))
                     .as_millis(),
                 request.prepare.expires_at(),
-                SystemTime::now()
+                now
             );
             let result = Box::new(err(RejectBuilder {
                 code: ErrorCode::R00_TRANSFER_TIMED_OUT,
@@ -82,11 +94,8 @@ where
         let mut condition: [u8; 32] = [0; 32];
         condition[..].copy_from_slice(request.prepare.execution_condition());
 
-        if let Ok(time_left) = request
-            .prepare
-            .expires_at()
-            .duration_since(SystemTime::now())
-        {
+        let now = self.clock.now();
+        if let Ok(time_left) = request.prepare.expires_at().duration_since(now) {
             Box::new(
                 self.next
                     .send_request(request)
@@ -110,11 +119,10 @@ where
                         }
                     })
                     .and_then(move |fulfill| {
-                        let generated_condition = digest(&SHA256, fulfill.fulfillment());
-                        if generated_condition.as_ref() == condition {
+                        if verify_fulfillment(fulfill.fulfillment(), &condition[..]) {
                             Ok(fulfill)
                         } else {
-                            error!("Fulfillment did not match condition. Fulfillment: {}, hash: {}, actual condition: {}", hex::encode(fulfill.fulfillment()), hex::encode(generated_condition), hex::encode(condition));
+                            error!("Fulfillment did not match condition. Fulfillment: {}, actual condition: {}", hex::encode(fulfill.fulfillment()), hex::encode(condition));
                             Err(RejectBuilder {
                                 code: ErrorCode::F09_INVALID_PEER_RESPONSE,
                                 message: b"Fulfillment did not match condition",
@@ -128,8 +136,7 @@ where
         } else {
             error!(
                 "Outgoing packet expired {}ms ago",
-                SystemTime::now()
-                    .duration_since(request.prepare.expires_at())
+                now.duration_since(request.prepare.expires_at())
                     .unwrap_or_default()
                     .as_millis(),
             );
@@ -236,6 +243,38 @@ mod incoming {
             ErrorCode::R00_TRANSFER_TIMED_OUT
         );
     }
+
+    #[test]
+    fn checks_expiry_against_compensated_clock() {
+        // The node's clock is a minute behind, so the packet has already expired
+        let clock = Arc::new(ClockSkew::new(true));
+        clock.set_offset(60_000);
+        let mut validator = ValidatorService::incoming(incoming_service_fn(|_request| {
+            Ok(FulfillBuilder {
+                fulfillment: &[0; 32],
+                data: b"test data",
+            }
+            .build())
+        }))
+        .with_clock(clock);
+        let result = validator
+            .handle_request(IncomingRequest {
+                from: TestAccount(0),
+                prepare: PrepareBuilder {
+                    destination: b"example.destination",
+                    amount: 100,
+                    expires_at: SystemTime::now() + Duration::from_secs(30),
+                    execution_condition: &[0; 32],
+                    data: b"test data",
+                }
+                .build(),
+            })
+            .wait();
+        assert_eq!(
+            result.unwrap_err().code(),
+            ErrorCode::R00_TRANSFER_TIMED_OUT
+        );
+    }
 }
 
 #[cfg(test)]
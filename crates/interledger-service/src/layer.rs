@@ -0,0 +1,241 @@
+//! Combinators for building a stack of services out of reusable middleware.
+//!
+//! A `Layer` wraps a service in another service. Layers can be written once and
+//! applied to any service (incoming or outgoing) that meets their requirements,
+//! and the `ServiceBuilder` applies a list of them in order:
+//!
+//! ```rust
+//! # use interledger_service::*;
+//! # use interledger_packet::{ErrorCode, RejectBuilder};
+//! # #[derive(Clone, Debug)]
+//! # struct TestAccount;
+//! # impl Account for TestAccount {
+//! #     type AccountId = u64;
+//! #     fn id(&self) -> u64 { 0 }
+//! # }
+//! /// Logs every request before passing it on
+//! #[derive(Clone)]
+//! struct Logger<S> {
+//!     next: S,
+//! }
+//!
+//! impl<S, A> IncomingService<A> for Logger<S>
+//! where
+//!     S: IncomingService<A>,
+//!     A: Account,
+//! {
+//!     type Future = S::Future;
+//!
+//!     fn handle_request(&mut self, request: IncomingRequest<A>) -> Self::Future {
+//!         println!("Handling request from account {}", request.from.id());
+//!         self.next.handle_request(request)
+//!     }
+//! }
+//!
+//! let handler = incoming_service_fn(|_request: IncomingRequest<TestAccount>| {
+//!     Err(RejectBuilder {
+//!         code: ErrorCode::F02_UNREACHABLE,
+//!         message: &[],
+//!         triggered_by: &[],
+//!         data: &[],
+//!     }
+//!     .build())
+//! });
+//! // The first layer added is the outermost, so it sees each request first
+//! let service = ServiceBuilder::new()
+//!     .layer(layer_fn(|next| Logger { next }))
+//!     .service(handler);
+//! ```
+
+/// Wraps a service in another service, for example to add middleware to a packet pipeline.
+pub trait Layer<S> {
+    type Service;
+
+    fn layer(&self, inner: S) -> Self::Service;
+}
+
+/// Create a `Layer` from a function that wraps the inner service.
+pub fn layer_fn<F>(f: F) -> LayerFn<F> {
+    LayerFn { f }
+}
+
+/// A layer created by `layer_fn`
+#[derive(Clone, Copy, Debug)]
+pub struct LayerFn<F> {
+    f: F,
+}
+
+impl<F, S, Out> Layer<S> for LayerFn<F>
+where
+    F: Fn(S) -> Out,
+{
+    type Service = Out;
+
+    fn layer(&self, inner: S) -> Out {
+        (self.f)(inner)
+    }
+}
+
+/// A layer that does not modify the service.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Identity;
+
+impl<S> Layer<S> for Identity {
+    type Service = S;
+
+    fn layer(&self, inner: S) -> S {
+        inner
+    }
+}
+
+/// Two layers applied one after the other, with `outer` wrapping the result of `inner`.
+#[derive(Clone, Copy, Debug)]
+pub struct Stack<Inner, Outer> {
+    inner: Inner,
+    outer: Outer,
+}
+
+impl<S, Inner, Outer> Layer<S> for Stack<Inner, Outer>
+where
+    Inner: Layer<S>,
+    Outer: Layer<Inner::Service>,
+{
+    type Service = Outer::Service;
+
+    fn layer(&self, service: S) -> Self::Service {
+        self.outer.layer(self.inner.layer(service))
+    }
+}
+
+/// Builds a service by wrapping it in a series of layers.
+///
+/// Layers are applied in the order they are added, so the first layer is the
+/// outermost one and handles each request before the layers added after it.
+#[derive(Clone, Debug)]
+pub struct ServiceBuilder<L> {
+    layer: L,
+}
+
+impl ServiceBuilder<Identity> {
+    pub fn new() -> Self {
+        ServiceBuilder { layer: Identity }
+    }
+}
+
+impl Default for ServiceBuilder<Identity> {
+    fn default() -> Self {
+        ServiceBuilder::new()
+    }
+}
+
+impl<L> ServiceBuilder<L> {
+    /// Add a layer inside of the ones that were already added.
+    pub fn layer<T>(self, layer: T) -> ServiceBuilder<Stack<T, L>> {
+        ServiceBuilder {
+            layer: Stack {
+                inner: layer,
+                outer: self.layer,
+            },
+        }
+    }
+
+    /// Wrap the given service in all of the layers.
+    pub fn service<S>(&self, service: S) -> L::Service
+    where
+        L: Layer<S>,
+    {
+        self.layer.layer(service)
+    }
+
+    /// Get the combined layer, for example to apply it to multiple services.
+    pub fn into_inner(self) -> L {
+        self.layer
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::*;
+    use futures::Future;
+    use interledger_packet::{FulfillBuilder, PrepareBuilder};
+    use std::{
+        sync::{Arc, Mutex},
+        time::SystemTime,
+    };
+
+    #[derive(Clone, Debug)]
+    struct TestAccount;
+
+    impl Account for TestAccount {
+        type AccountId = u64;
+
+        fn id(&self) -> u64 {
+            0
+        }
+    }
+
+    /// Records its name when it handles a request
+    #[derive(Clone)]
+    struct Recorder<S> {
+        name: &'static str,
+        log: Arc<Mutex<Vec<&'static str>>>,
+        next: S,
+    }
+
+    impl<S> IncomingService<TestAccount> for Recorder<S>
+    where
+        S: IncomingService<TestAccount>,
+    {
+        type Future = S::Future;
+
+        fn handle_request(&mut self, request: IncomingRequest<TestAccount>) -> Self::Future {
+            self.log.lock().unwrap().push(self.name);
+            self.next.handle_request(request)
+        }
+    }
+
+    fn recorder<S>(
+        name: &'static str,
+        log: &Arc<Mutex<Vec<&'static str>>>,
+    ) -> impl Layer<S, Service = Recorder<S>> {
+        let log = log.clone();
+        layer_fn(move |next| Recorder {
+            name,
+            log: log.clone(),
+            next,
+        })
+    }
+
+    #[test]
+    fn applies_first_layer_outermost() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let handler = incoming_service_fn(|_request: IncomingRequest<TestAccount>| {
+            Ok(FulfillBuilder {
+                fulfillment: &[0; 32],
+                data: &[],
+            }
+            .build())
+        });
+        let mut service = ServiceBuilder::new()
+            .layer(recorder("outer", &log))
+            .layer(Identity)
+            .layer(recorder("inner", &log))
+            .service(handler);
+        service
+            .handle_request(IncomingRequest {
+                from: TestAccount,
+                prepare: PrepareBuilder {
+                    destination: b"example.destination",
+                    amount: 100,
+                    execution_condition: &[0; 32],
+                    expires_at: SystemTime::now(),
+                    data: &[],
+                }
+                .build(),
+            })
+            .wait()
+            .unwrap();
+        assert_eq!(*log.lock().unwrap(), vec!["outer", "inner"]);
+    }
+}
@@ -25,17 +25,26 @@
 //! ### STREAM Receiver
 //!
 //! HttpServerService --> ValidatorService --> StreamReceiverService
+//!
+//! ## Middleware
+//!
+//! Custom services can be inserted into these chains by implementing them as a `Layer`.
+//! The `ServiceBuilder` composes layers in order and applies them to the innermost service.
 
 use futures::{Future, IntoFuture};
 use interledger_packet::{Fulfill, Prepare, Reject};
 use std::{
     cmp::Eq,
-    fmt::{Debug, Display},
+    error::Error as StdError,
+    fmt::{self, Debug, Display},
     hash::Hash,
     marker::PhantomData,
     str::FromStr,
 };
 
+mod layer;
+pub use self::layer::{layer_fn, Identity, Layer, LayerFn, ServiceBuilder, Stack};
+
 /// The base trait that Account types from other Services extend.
 /// This trait only assumes that the account has an ID that can be compared with others.
 ///
@@ -98,10 +107,47 @@ pub type BoxedIlpFuture = Box<Future<Item = Fulfill, Error = Reject> + Send + 's
 pub trait AccountStore {
     type Account: Account;
 
+    /// Load the accounts with the given IDs.
+    ///
+    /// The result has one entry for each ID, in the same order, which is `None` if there is
+    /// no account with that ID. This only errors if the store itself could not be queried.
     fn get_accounts(
         &self,
         account_ids: Vec<<<Self as AccountStore>::Account as Account>::AccountId>,
-    ) -> Box<Future<Item = Vec<Self::Account>, Error = ()> + Send>;
+    ) -> Box<Future<Item = Vec<Option<Self::Account>>, Error = ()> + Send>;
+}
+
+/// Errors returned by Stores so that callers can tell why an operation failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StoreError {
+    /// The requested record does not exist
+    NotFound,
+    /// A record with the same unique details already exists
+    Conflict,
+    /// The data given to the store was invalid
+    InvalidData,
+    /// The operation would have put an account below its minimum balance
+    InsufficientBalance,
+    /// The underlying database could not be reached or returned an unexpected error
+    StoreUnavailable,
+}
+
+impl Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.description())
+    }
+}
+
+impl StdError for StoreError {
+    fn description(&self) -> &str {
+        match *self {
+            StoreError::NotFound => "Not found",
+            StoreError::Conflict => "Conflict with existing record",
+            StoreError::InvalidData => "Invalid data",
+            StoreError::InsufficientBalance => "Insufficient balance",
+            StoreError::StoreUnavailable => "Store unavailable",
+        }
+    }
 }
 
 /// Create an IncomingService that calls the given handler for each request.
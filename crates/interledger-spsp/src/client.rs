@@ -1,13 +1,19 @@
 use super::{Error, SpspResponse};
 use futures::Future;
 use interledger_service::{Account, IncomingService};
-use interledger_stream::send_money;
+use interledger_stream::{send_money_with_options, PaymentOutcome, SendMoneyOptions};
 use reqwest::r#async::Client;
 
 pub fn query(server: &str) -> impl Future<Item = SpspResponse, Error = Error> {
+    query_with_client(&Client::new(), server)
+}
+
+fn query_with_client(
+    client: &Client,
+    server: &str,
+) -> impl Future<Item = SpspResponse, Error = Error> {
     let server = payment_pointer_to_url(server);
 
-    let client = Client::new();
     client
         .get(&server)
         .header("Accept", "application/spsp4+json")
@@ -21,45 +27,92 @@ pub fn query(server: &str) -> impl Future<Item = SpspResponse, Error = Error> {
 
 /// Query the details of the given Payment Pointer and send a payment using the STREAM protocol.
 ///
-/// This returns the amount delivered, as reported by the receiver and in the receiver's asset's units.
+/// This returns a `PaymentOutcome` with the amount delivered, as reported by the receiver and
+/// in the receiver's asset's units, and whether the full amount was sent.
 pub fn pay<S, A>(
     service: S,
     from_account: A,
     receiver: &str,
     source_amount: u64,
-) -> impl Future<Item = u64, Error = Error>
+) -> impl Future<Item = PaymentOutcome, Error = Error>
+where
+    S: IncomingService<A> + Clone,
+    A: Account,
+{
+    pay_with_options(
+        service,
+        from_account,
+        receiver,
+        source_amount,
+        SendMoneyOptions::default(),
+    )
+}
+
+/// Same as `pay` but with additional options for the STREAM sender.
+pub fn pay_with_options<S, A>(
+    service: S,
+    from_account: A,
+    receiver: &str,
+    source_amount: u64,
+    options: SendMoneyOptions,
+) -> impl Future<Item = PaymentOutcome, Error = Error>
+where
+    S: IncomingService<A> + Clone,
+    A: Account,
+{
+    pay_with_client(
+        &Client::new(),
+        service,
+        from_account,
+        receiver,
+        source_amount,
+        options,
+    )
+}
+
+/// Same as `pay_with_options` but queries the receiver with the given HTTP client, for example
+/// one that does not follow redirects when the receiver was given by someone else.
+pub fn pay_with_client<S, A>(
+    client: &Client,
+    service: S,
+    from_account: A,
+    receiver: &str,
+    source_amount: u64,
+    options: SendMoneyOptions,
+) -> impl Future<Item = PaymentOutcome, Error = Error>
 where
     S: IncomingService<A> + Clone,
     A: Account,
 {
     trace!("Querying receiver: {}", receiver);
-    query(receiver).and_then(move |spsp| {
+    query_with_client(client, receiver).and_then(move |spsp| {
         debug!(
             "Sending SPSP payment to address: {}",
             spsp.destination_account
         );
-        send_money(
+        send_money_with_options(
             service,
             &from_account,
             spsp.destination_account.as_bytes(),
             &spsp.shared_secret,
             source_amount,
+            options,
         )
-        .map(move |(amount_delivered, _plugin)| {
+        .map(|(outcome, _plugin)| {
             debug!(
-                "Sent SPSP payment of {} and delivered {} of the receiver's units",
-                source_amount, amount_delivered
+                "Sent SPSP payment of {} and delivered {} of the receiver's units ({:?})",
+                outcome.source_amount_sent, outcome.amount_delivered, outcome.end_state
             );
-            amount_delivered
+            outcome
         })
-        .map_err(move |err| {
+        .map_err(|err| {
             error!("Error sending payment: {:?}", err);
-            Error::SendMoneyError(source_amount)
+            Error::StreamError(err)
         })
     })
 }
 
-fn payment_pointer_to_url(payment_pointer: &str) -> String {
+pub(crate) fn payment_pointer_to_url(payment_pointer: &str) -> String {
     let mut url: String = if payment_pointer.starts_with('$') {
         let mut url = "https://".to_string();
         url.push_str(&payment_pointer[1..]);
@@ -68,7 +121,9 @@ fn payment_pointer_to_url(payment_pointer: &str) -> String {
         payment_pointer.to_string()
     };
 
-    let num_slashes = url.matches('/').count();
+    // Only the slashes after the scheme say whether there is a path
+    let host_and_path = url.splitn(2, "://").last().unwrap_or_default();
+    let num_slashes = host_and_path.matches('/').count();
     if num_slashes == 0 {
         url.push_str("/.well-known/pay");
     } else if num_slashes == 1 && url.ends_with('/') {
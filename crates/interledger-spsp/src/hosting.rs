@@ -0,0 +1,261 @@
+//! Helpers for hosting payment pointers on other domains.
+//!
+//! A payment pointer such as `$example.com` is resolved by querying `https://example.com/.well-known/pay`.
+//! To use a domain that is not served by the node, that URL should redirect to the node's SPSP
+//! endpoint for the account. SPSP clients follow the redirect, so the domain's web server does not
+//! need to know anything about Interledger.
+
+use super::{Error, SpspResponse};
+use futures::{
+    future::{loop_fn, ok, result, Loop},
+    Future,
+};
+use reqwest::{
+    header::{CONTENT_TYPE, LOCATION},
+    r#async::Client,
+    RedirectPolicy,
+};
+use url::Url;
+
+type CheckStep = Box<Future<Item = Loop<HostingCheck, (HostingCheck, Url)>, Error = Error> + Send>;
+
+/// How to configure a domain so that a payment pointer on it resolves to an account on the node.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct HostingConfig {
+    pub payment_pointer: String,
+    /// The URL that SPSP clients query for the payment pointer
+    pub url: String,
+    /// The node's SPSP endpoint for the account, which `url` must redirect to
+    pub redirect_to: String,
+    /// An nginx `location` block that sets up the redirect
+    pub nginx: String,
+}
+
+/// The result of querying a payment pointer the way an SPSP client would.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct HostingCheck {
+    pub payment_pointer: String,
+    /// Every URL that was requested, starting with the one the payment pointer resolves to
+    pub urls: Vec<String>,
+    /// The ILP address the payment pointer resolved to, if it returned a valid SPSP response
+    pub destination_account: Option<String>,
+    /// Empty if the payment pointer resolves to the expected account
+    pub problems: Vec<String>,
+}
+
+impl HostingCheck {
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+/// Parse a payment pointer (such as `$example.com` or `$example.com/alice`) into the URL SPSP clients query.
+pub fn payment_pointer_url(payment_pointer: &str) -> Result<Url, Error> {
+    if !payment_pointer.starts_with('$') {
+        return Err(Error::InvalidPaymentPointerError(format!(
+            "{} does not start with $",
+            payment_pointer
+        )));
+    }
+    let url =
+        Url::parse(&super::client::payment_pointer_to_url(payment_pointer)).map_err(|err| {
+            Error::InvalidPaymentPointerError(format!("{}: {}", payment_pointer, err))
+        })?;
+    if url.host_str().is_none()
+        || url.username() != ""
+        || url.port().is_some()
+        || url.query().is_some()
+        || url.fragment().is_some()
+    {
+        return Err(Error::InvalidPaymentPointerError(format!(
+            "{} must only have a domain and a path",
+            payment_pointer
+        )));
+    }
+    Ok(url)
+}
+
+/// Generate the configuration that makes the payment pointer resolve to the given SPSP endpoint.
+pub fn hosting_config(payment_pointer: &str, spsp_url: &Url) -> Result<HostingConfig, Error> {
+    let url = payment_pointer_url(payment_pointer)?;
+    let nginx = format!(
+        "location = {} {{\n    add_header Access-Control-Allow-Origin *;\n    return 302 {};\n}}\n",
+        url.path(),
+        spsp_url
+    );
+    Ok(HostingConfig {
+        payment_pointer: payment_pointer.to_string(),
+        url: url.to_string(),
+        redirect_to: spsp_url.to_string(),
+        nginx,
+    })
+}
+
+/// Query the payment pointer and check that it resolves to an ILP address starting with `destination_prefix`.
+///
+/// The only redirect that is followed is one to `spsp_url`, the node's own SPSP endpoint for the
+/// account, so that the check cannot be used to make the node request other URLs. Every URL must
+/// use HTTPS with a valid certificate. Problems with the hosting are reported in the
+/// `HostingCheck`; this only fails if the payment pointer cannot be parsed.
+pub fn check_payment_pointer(
+    payment_pointer: &str,
+    spsp_url: &Url,
+    destination_prefix: &str,
+) -> impl Future<Item = HostingCheck, Error = Error> {
+    let check = HostingCheck {
+        payment_pointer: payment_pointer.to_string(),
+        urls: Vec::new(),
+        destination_account: None,
+        problems: Vec::new(),
+    };
+    let destination_prefix = destination_prefix.to_string();
+    let spsp_url = spsp_url.clone();
+    // Redirects are followed here instead of by the client so that each one can be checked
+    let client = Client::builder()
+        .redirect(RedirectPolicy::none())
+        .build()
+        .map_err(|err| Error::HttpError(format!("Unable to create HTTP client: {:?}", err)));
+    result(payment_pointer_url(payment_pointer).and_then(|url| client.map(|client| (client, url))))
+        .and_then(move |(client, url)| {
+            loop_fn((check, url), move |(mut check, url)| -> CheckStep {
+                check.urls.push(url.to_string());
+                if url.scheme() != "https" {
+                    check
+                        .problems
+                        .push(format!("{} does not use HTTPS", url));
+                    return Box::new(ok(Loop::Break(check)));
+                }
+                let destination_prefix = destination_prefix.clone();
+                let spsp_url = spsp_url.clone();
+                Box::new(
+                    client
+                        .get(url.clone())
+                        .header("Accept", "application/spsp4+json, application/spsp+json")
+                        .send()
+                        .then(move |response| -> CheckStep {
+                            let mut response = match response {
+                                Ok(response) => response,
+                                Err(err) => {
+                                    // This includes invalid certificates
+                                    check
+                                        .problems
+                                        .push(format!("Error requesting {}: {}", url, err));
+                                    return Box::new(ok(Loop::Break(check)));
+                                }
+                            };
+                            let status = response.status();
+                            if status.is_redirection() {
+                                let location = response
+                                    .headers()
+                                    .get(LOCATION)
+                                    .and_then(|location| location.to_str().ok())
+                                    .and_then(|location| url.join(location).ok());
+                                match location {
+                                    Some(ref next) if *next == spsp_url && check.urls.len() == 1 => {
+                                        return Box::new(ok(Loop::Continue((check, spsp_url.clone()))));
+                                    }
+                                    Some(next) => check.problems.push(format!(
+                                        "{} redirects to {} instead of {}",
+                                        url, next, spsp_url
+                                    )),
+                                    None => check.problems.push(format!(
+                                        "{} redirected without a valid Location header",
+                                        url
+                                    )),
+                                }
+                                return Box::new(ok(Loop::Break(check)));
+                            }
+                            if !status.is_success() {
+                                check
+                                    .problems
+                                    .push(format!("{} responded with status {}", url, status));
+                                return Box::new(ok(Loop::Break(check)));
+                            }
+                            let content_type = response
+                                .headers()
+                                .get(CONTENT_TYPE)
+                                .and_then(|content_type| content_type.to_str().ok())
+                                .unwrap_or("")
+                                .to_string();
+                            if !content_type.starts_with("application/spsp4+json")
+                                && !content_type.starts_with("application/spsp+json")
+                            {
+                                check.problems.push(format!(
+                                    "{} responded with Content-Type \"{}\" instead of application/spsp4+json",
+                                    url, content_type
+                                ));
+                            }
+                            Box::new(response.json::<SpspResponse>().then(move |spsp| {
+                                match spsp {
+                                    Ok(spsp) => {
+                                        if !is_address_of(&spsp.destination_account, &destination_prefix) {
+                                            check.problems.push(format!(
+                                                "The payment pointer resolves to {}, which is not an address of the account ({})",
+                                                spsp.destination_account, destination_prefix
+                                            ));
+                                        }
+                                        check.destination_account = Some(spsp.destination_account);
+                                    }
+                                    Err(err) => check.problems.push(format!(
+                                        "{} did not respond with valid SPSP details: {}",
+                                        url, err
+                                    )),
+                                }
+                                Ok(Loop::Break(check))
+                            }))
+                        }),
+                )
+            })
+        })
+}
+
+/// Whether the address is the given one or one of its children
+fn is_address_of(address: &str, prefix: &str) -> bool {
+    address == prefix || (address.starts_with(prefix) && address[prefix.len()..].starts_with('.'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn payment_pointer_urls() {
+        assert_eq!(
+            payment_pointer_url("$example.com").unwrap().as_str(),
+            "https://example.com/.well-known/pay"
+        );
+        assert_eq!(
+            payment_pointer_url("$example.com/alice").unwrap().as_str(),
+            "https://example.com/alice"
+        );
+        assert!(payment_pointer_url("example.com").is_err());
+        assert!(payment_pointer_url("$example.com:8443").is_err());
+        assert!(payment_pointer_url("$example.com/alice?x=1").is_err());
+    }
+
+    #[test]
+    fn generates_hosting_config() {
+        let spsp_url = Url::parse("https://node.example/spsp/1").unwrap();
+        let config = hosting_config("$example.com", &spsp_url).unwrap();
+        assert_eq!(config.url, "https://example.com/.well-known/pay");
+        assert_eq!(config.redirect_to, "https://node.example/spsp/1");
+        assert_eq!(
+            config.nginx,
+            "location = /.well-known/pay {\n    add_header Access-Control-Allow-Origin *;\n    return 302 https://node.example/spsp/1;\n}\n"
+        );
+    }
+
+    #[test]
+    fn checks_destination_prefix() {
+        assert!(is_address_of("example.node.alice", "example.node.alice"));
+        assert!(is_address_of(
+            "example.node.alice.abc123",
+            "example.node.alice"
+        ));
+        assert!(!is_address_of(
+            "example.node.alice2.abc123",
+            "example.node.alice"
+        ));
+        assert!(!is_address_of("example.node", "example.node.alice"));
+    }
+}
@@ -15,9 +15,13 @@ extern crate failure;
 use interledger_stream::Error as StreamError;
 
 mod client;
+mod hosting;
 mod server;
 
-pub use client::{pay, query};
+pub use client::{pay, pay_with_client, pay_with_options, query};
+pub use hosting::{
+    check_payment_pointer, hosting_config, payment_pointer_url, HostingCheck, HostingConfig,
+};
 pub use server::SpspResponder;
 
 #[derive(Fail, Debug)]
@@ -41,6 +45,42 @@ pub struct SpspResponse {
     destination_account: String,
     #[serde(with = "serde_base64")]
     shared_secret: Vec<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    balance: Option<SpspBalance>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    description: Option<String>,
+}
+
+/// How much a receiver that expects a fixed amount, such as for an invoice, will accept
+/// in total and has received so far, in the receiver's base units.
+///
+/// The amounts are strings, as in the SPSP RFC.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct SpspBalance {
+    pub maximum: String,
+    pub current: String,
+}
+
+impl SpspResponse {
+    /// The receiver's ILP address
+    pub fn destination_account(&self) -> &str {
+        &self.destination_account
+    }
+
+    /// The key used to authenticate STREAM packets sent to the receiver
+    pub fn shared_secret(&self) -> &[u8] {
+        &self.shared_secret[..]
+    }
+
+    /// The amount the receiver expects, if it expects a fixed amount
+    pub fn balance(&self) -> Option<&SpspBalance> {
+        self.balance.as_ref()
+    }
+
+    /// What the payment is for, if the receiver says
+    pub fn description(&self) -> Option<&str> {
+        self.description.as_ref().map(String::as_str)
+    }
 }
 
 // From https://github.com/serde-rs/json/issues/360#issuecomment-330095360
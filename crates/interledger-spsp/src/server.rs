@@ -1,4 +1,4 @@
-use super::SpspResponse;
+use super::{SpspBalance, SpspResponse};
 use bytes::Bytes;
 use futures::future::{ok, FutureResult, IntoFuture};
 use hyper::{service::Service as HttpService, Body, Error, Request, Response};
@@ -27,22 +27,70 @@ impl SpspResponder {
         let (destination_account, shared_secret) = self
             .connection_generator
             .generate_address_and_secret(&self.ilp_address[..]);
-        let destination_account = String::from_utf8(destination_account.to_vec()).unwrap();
-        debug!("Generated address and secret for: {}", destination_account);
-        let response = SpspResponse {
+        spsp_response(destination_account, shared_secret, None, None)
+    }
+
+    /// Respond with details for a connection tagged with an ID such as an invoice number, which
+    /// the receiver's `ConnectionPolicy` can check to only accept payments that are expected.
+    /// Returns an error if the tag is not a valid ILP address segment (or contains `~`).
+    pub fn generate_http_response_with_tag(
+        &self,
+        connection_tag: &[u8],
+    ) -> Result<Response<Body>, ()> {
+        let (destination_account, shared_secret) = self
+            .connection_generator
+            .generate_address_and_secret_with_tag(&self.ilp_address[..], connection_tag)?;
+        Ok(spsp_response(
+            destination_account,
+            shared_secret,
+            None,
+            None,
+        ))
+    }
+
+    /// Like `generate_http_response_with_tag`, but also tells the sender the amount the receiver
+    /// expects and has received so far, and what the payment is for.
+    pub fn generate_http_response_for_amount(
+        &self,
+        connection_tag: &[u8],
+        balance: SpspBalance,
+        description: Option<String>,
+    ) -> Result<Response<Body>, ()> {
+        let (destination_account, shared_secret) = self
+            .connection_generator
+            .generate_address_and_secret_with_tag(&self.ilp_address[..], connection_tag)?;
+        Ok(spsp_response(
             destination_account,
-            shared_secret: shared_secret.to_vec(),
-        };
-
-        Response::builder()
-            .header("Content-Type", "application/spsp4+json")
-            .header("Cache-Control", "max-age=60")
-            .status(200)
-            .body(Body::from(serde_json::to_string(&response).unwrap()))
-            .unwrap()
+            shared_secret,
+            Some(balance),
+            description,
+        ))
     }
 }
 
+fn spsp_response(
+    destination_account: Bytes,
+    shared_secret: [u8; 32],
+    balance: Option<SpspBalance>,
+    description: Option<String>,
+) -> Response<Body> {
+    let destination_account = String::from_utf8(destination_account.to_vec()).unwrap();
+    debug!("Generated address and secret for: {}", destination_account);
+    let response = SpspResponse {
+        destination_account,
+        shared_secret: shared_secret.to_vec(),
+        balance,
+        description,
+    };
+
+    Response::builder()
+        .header("Content-Type", "application/spsp4+json")
+        .header("Cache-Control", "max-age=60")
+        .status(200)
+        .body(Body::from(serde_json::to_string(&response).unwrap()))
+        .unwrap()
+}
+
 impl HttpService for SpspResponder {
     type ReqBody = Body;
     type ResBody = Body;
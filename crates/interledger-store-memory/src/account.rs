@@ -3,7 +3,9 @@ use interledger_btp::BtpAccount;
 use interledger_http::HttpAccount;
 use interledger_ildcp::IldcpAccount;
 use interledger_service::Account as AccountTrait;
-use interledger_service_util::MaxPacketAmountAccount;
+use interledger_service_util::{
+    MaxPacketAmountAccount, MaxPacketDataAccount, Priority, PriorityAccount, ShapingAccount,
+};
 use std::{fmt, str, sync::Arc};
 use url::Url;
 
@@ -78,6 +80,21 @@ impl AccountBuilder {
         self.details.max_packet_amount = amount;
         self
     }
+
+    pub fn max_packet_data(mut self, max_data: u16) -> Self {
+        self.details.max_packet_data = Some(max_data);
+        self
+    }
+
+    pub fn priority(mut self, priority: Priority) -> Self {
+        self.details.priority = priority;
+        self
+    }
+
+    pub fn max_value_per_second(mut self, max_value: u64) -> Self {
+        self.details.max_value_per_second = Some(max_value);
+        self
+    }
 }
 
 #[derive(Default, Clone)]
@@ -93,6 +110,9 @@ pub(crate) struct AccountDetails {
     pub(crate) btp_uri: Option<Url>,
     pub(crate) btp_incoming_token: Option<String>,
     pub(crate) max_packet_amount: u64,
+    pub(crate) max_packet_data: Option<u16>,
+    pub(crate) priority: Priority,
+    pub(crate) max_value_per_second: Option<u64>,
 }
 
 impl AccountDetails {
@@ -149,6 +169,24 @@ impl MaxPacketAmountAccount for Account {
     }
 }
 
+impl MaxPacketDataAccount for Account {
+    fn max_packet_data(&self) -> Option<u16> {
+        self.inner.max_packet_data
+    }
+}
+
+impl PriorityAccount for Account {
+    fn priority(&self) -> Priority {
+        self.inner.priority
+    }
+}
+
+impl ShapingAccount for Account {
+    fn max_value_per_second(&self) -> Option<u64> {
+        self.inner.max_value_per_second
+    }
+}
+
 impl HttpAccount for Account {
     fn get_http_url(&self) -> Option<&Url> {
         self.inner.http_endpoint.as_ref()
@@ -181,6 +219,9 @@ mod tests {
         assert_eq!(account.get_btp_uri(), None);
         assert_eq!(account.get_http_auth_header(), None);
         assert_eq!(account.max_packet_amount(), u64::max_value());
+        assert_eq!(account.max_packet_data(), None);
+        assert_eq!(account.priority(), Priority::Normal);
+        assert_eq!(account.max_value_per_second(), None);
         assert_eq!(account.client_address(), Bytes::from(""));
     }
 
@@ -199,6 +240,9 @@ mod tests {
             .http_outgoing_authorization("Bearer sodgiuoixfugoiudf".to_string())
             .btp_incoming_token("asdflkjsaldkfjoi".to_string())
             .max_packet_amount(7777)
+            .max_packet_data(1024)
+            .priority(Priority::High)
+            .max_value_per_second(5000)
             .build();
         assert_eq!(account.id(), 1);
         assert_eq!(account.asset_code(), "XYZ");
@@ -212,6 +256,9 @@ mod tests {
             Some("Bearer sodgiuoixfugoiudf")
         );
         assert_eq!(account.max_packet_amount(), 7777);
+        assert_eq!(account.max_packet_data(), Some(1024));
+        assert_eq!(account.priority(), Priority::High);
+        assert_eq!(account.max_value_per_second(), Some(5000));
         assert_eq!(account.client_address(), &b"example.address"[..]);
     }
 }
@@ -111,16 +111,12 @@ impl AccountStore for InMemoryStore {
     fn get_accounts(
         &self,
         accounts_ids: Vec<u64>,
-    ) -> Box<Future<Item = Vec<Account>, Error = ()> + Send> {
-        let accounts: Vec<Account> = accounts_ids
+    ) -> Box<Future<Item = Vec<Option<Account>>, Error = ()> + Send> {
+        let accounts = self.accounts.read();
+        Box::new(ok(accounts_ids
             .iter()
-            .filter_map(|account_id| self.accounts.read().get(account_id).cloned())
-            .collect();
-        if accounts.len() == accounts_ids.len() {
-            Box::new(ok(accounts))
-        } else {
-            Box::new(err(()))
-        }
+            .map(|account_id| accounts.get(account_id).cloned())
+            .collect()))
     }
 }
 
@@ -203,10 +199,12 @@ mod tests {
             AccountBuilder::new().id(4),
         ]);
         let accounts = store.get_accounts(vec![0, 4]).wait().unwrap();
-        assert_eq!(accounts[0].id(), 0);
-        assert_eq!(accounts[1].id(), 4);
+        assert_eq!(accounts[0].as_ref().unwrap().id(), 0);
+        assert_eq!(accounts[1].as_ref().unwrap().id(), 4);
 
-        assert!(store.get_accounts(vec![0, 5]).wait().is_err());
+        let accounts = store.get_accounts(vec![0, 5]).wait().unwrap();
+        assert!(accounts[0].is_some());
+        assert!(accounts[1].is_none());
     }
 
     #[test]
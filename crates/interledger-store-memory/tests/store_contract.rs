@@ -0,0 +1,40 @@
+use futures::Future;
+use interledger_store_memory::{AccountBuilder, InMemoryStore};
+use interledger_store_tests::{self as store_tests, ACCOUNTS};
+
+fn test_store() -> InMemoryStore {
+    InMemoryStore::from_accounts(ACCOUNTS.iter().enumerate().map(|(id, details)| {
+        AccountBuilder::new()
+            .id(id as u64)
+            .ilp_address(details.ilp_address)
+            .asset_code(details.asset_code.to_string())
+            .asset_scale(details.asset_scale)
+            .http_incoming_authorization(details.http_incoming_authorization.to_string())
+            .btp_incoming_token(details.btp_incoming_token.to_string())
+            .build()
+    }))
+}
+
+#[test]
+fn get_accounts() {
+    store_tests::get_accounts(test_store()).wait().unwrap();
+}
+
+#[test]
+fn account_from_http_auth() {
+    store_tests::account_from_http_auth(test_store())
+        .wait()
+        .unwrap();
+}
+
+#[test]
+fn account_from_btp_token() {
+    store_tests::account_from_btp_token(test_store())
+        .wait()
+        .unwrap();
+}
+
+#[test]
+fn routing_table() {
+    store_tests::routing_table(test_store());
+}
@@ -1,3 +1,4 @@
+use super::extensions::{ExtensionField, Extensions, Field};
 use bytes::Bytes;
 use interledger_api::{AccountDetails, NodeAccount};
 use interledger_btp::BtpAccount;
@@ -5,18 +6,32 @@ use interledger_ccp::{CcpRoutingAccount, RoutingRelation};
 use interledger_http::HttpAccount;
 use interledger_ildcp::IldcpAccount;
 use interledger_service::Account as AccountTrait;
-use interledger_service_util::MaxPacketAmountAccount;
+use interledger_service_util::{
+    MaxPacketAmountAccount, MaxPacketDataAccount, Priority, PriorityAccount, SettlementAccount,
+    ShapingAccount, XrpAccount,
+};
 use redis::{from_redis_value, ErrorKind, FromRedisValue, RedisError, ToRedisArgs, Value};
 use serde::Serializer;
 use std::{
     collections::HashMap,
+    fmt,
     str::{self, FromStr},
 };
 use url::Url;
 
-const ACCOUNT_DETAILS_FIELDS: usize = 18;
+const ACCOUNT_DETAILS_FIELDS: usize = 25;
+
+/// The fields that each integration adds to accounts, declared next to its account trait implementation.
+/// Supporting a new integration means declaring its fields, listing them here and implementing its trait.
+static EXTENSION_FIELDS: [&[&ExtensionField]; 5] = [
+    &HTTP_FIELDS,
+    &BTP_FIELDS,
+    &SETTLEMENT_FIELDS,
+    &XRP_FIELDS,
+    &NODE_FIELDS,
+];
 
-#[derive(Clone, Debug, Serialize)]
+#[derive(Clone, Serialize)]
 pub struct Account {
     pub(crate) id: u64,
     #[serde(serialize_with = "address_to_string")]
@@ -25,42 +40,54 @@ pub struct Account {
     pub(crate) asset_code: String,
     pub(crate) asset_scale: u8,
     pub(crate) max_packet_amount: u64,
+    pub(crate) max_packet_data: Option<u16>,
+    pub(crate) priority: Priority,
+    pub(crate) max_value_per_second: Option<u64>,
+    pub(crate) inactivity_timeout: Option<u64>,
+    pub(crate) pending: bool,
     pub(crate) min_balance: i64,
-    #[serde(serialize_with = "optional_url_to_string")]
-    pub(crate) http_endpoint: Option<Url>,
-    pub(crate) http_incoming_authorization: Option<String>,
-    pub(crate) http_outgoing_authorization: Option<String>,
-    #[serde(serialize_with = "optional_url_to_string")]
-    pub(crate) btp_uri: Option<Url>,
-    pub(crate) btp_incoming_authorization: Option<String>,
     pub(crate) is_admin: bool,
-    // TODO maybe take these out of the Account and insert them separately into the db
-    // since they're only meant for the settlement engine
-    pub(crate) xrp_address: Option<String>,
-    pub(crate) settle_threshold: Option<i64>,
-    pub(crate) settle_to: Option<i64>,
+    pub(crate) read_only: bool,
+    /// The fields used by transports, settlement engines and other integrations, which are read through their account traits
+    #[serde(flatten)]
+    pub(crate) extensions: Extensions,
     #[serde(serialize_with = "routing_relation_to_string")]
     pub(crate) routing_relation: RoutingRelation,
     pub(crate) send_routes: bool,
     pub(crate) receive_routes: bool,
 }
 
-fn address_to_string<S>(address: &Bytes, serializer: S) -> Result<S::Ok, S::Error>
-where
-    S: Serializer,
-{
-    serializer.serialize_str(str::from_utf8(address.as_ref()).unwrap_or(""))
+// Implemented by hand so that the auth tokens (including the ones in the URLs) are redacted
+// (the extension fields redact their own)
+impl fmt::Debug for Account {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Account")
+            .field("id", &self.id)
+            .field("ilp_address", &self.ilp_address)
+            .field("asset_code", &self.asset_code)
+            .field("asset_scale", &self.asset_scale)
+            .field("max_packet_amount", &self.max_packet_amount)
+            .field("max_packet_data", &self.max_packet_data)
+            .field("priority", &self.priority)
+            .field("max_value_per_second", &self.max_value_per_second)
+            .field("inactivity_timeout", &self.inactivity_timeout)
+            .field("pending", &self.pending)
+            .field("min_balance", &self.min_balance)
+            .field("is_admin", &self.is_admin)
+            .field("read_only", &self.read_only)
+            .field("extensions", &self.extensions)
+            .field("routing_relation", &self.routing_relation)
+            .field("send_routes", &self.send_routes)
+            .field("receive_routes", &self.receive_routes)
+            .finish()
+    }
 }
 
-fn optional_url_to_string<S>(url: &Option<Url>, serializer: S) -> Result<S::Ok, S::Error>
+fn address_to_string<S>(address: &Bytes, serializer: S) -> Result<S::Ok, S::Error>
 where
     S: Serializer,
 {
-    if let Some(ref url) = url {
-        serializer.serialize_str(url.as_ref())
-    } else {
-        serializer.serialize_none()
-    }
+    serializer.serialize_str(str::from_utf8(address.as_ref()).unwrap_or(""))
 }
 
 fn routing_relation_to_string<S>(
@@ -75,37 +102,63 @@ where
 
 impl Account {
     pub fn try_from(id: u64, details: AccountDetails) -> Result<Account, ()> {
-        let http_endpoint = if let Some(ref url) = details.http_endpoint {
-            Some(Url::parse(url).map_err(|err| error!("Invalid URL: {:?}", err))?)
-        } else {
-            None
-        };
-        let btp_uri = if let Some(ref url) = details.btp_uri {
-            Some(Url::parse(url).map_err(|err| error!("Invalid URL: {:?}", err))?)
-        } else {
-            None
-        };
+        let extensions = Extensions::parse(
+            &EXTENSION_FIELDS,
+            vec![
+                (HTTP_ENDPOINT.name(), details.http_endpoint),
+                (
+                    HTTP_INCOMING_AUTHORIZATION.name(),
+                    details.http_incoming_authorization,
+                ),
+                (
+                    HTTP_OUTGOING_AUTHORIZATION.name(),
+                    details.http_outgoing_authorization,
+                ),
+                (BTP_URI.name(), details.btp_uri),
+                (
+                    BTP_INCOMING_AUTHORIZATION.name(),
+                    details.btp_incoming_authorization,
+                ),
+                (XRP_ADDRESS.name(), details.xrp_address),
+                (
+                    SETTLE_THRESHOLD.name(),
+                    details.settle_threshold.map(|value| value.to_string()),
+                ),
+                (
+                    SETTLE_TO.name(),
+                    details.settle_to.map(|value| value.to_string()),
+                ),
+                (OWNER.name(), details.owner),
+            ]
+            .into_iter()
+            .filter_map(|(name, value)| value.map(|value| (name, value))),
+        )
+        .map_err(|err| error!("{}", err))?;
         let routing_relation = if let Some(ref relation) = details.routing_relation {
             RoutingRelation::from_str(relation)?
         } else {
             RoutingRelation::Child
         };
+        let priority = if let Some(ref priority) = details.priority {
+            Priority::from_str(priority).map_err(|_| error!("Invalid priority: {}", priority))?
+        } else {
+            Priority::default()
+        };
         Ok(Account {
             id,
             ilp_address: Bytes::from(details.ilp_address),
             asset_code: details.asset_code.to_uppercase(),
             asset_scale: details.asset_scale,
             max_packet_amount: details.max_packet_amount,
+            max_packet_data: details.max_packet_data,
+            priority,
+            max_value_per_second: details.max_value_per_second,
+            inactivity_timeout: details.inactivity_timeout,
+            pending: details.pending,
             min_balance: details.min_balance,
-            http_endpoint,
-            http_incoming_authorization: details.http_incoming_authorization,
-            http_outgoing_authorization: details.http_outgoing_authorization,
-            btp_uri,
-            btp_incoming_authorization: details.btp_incoming_authorization,
             is_admin: details.is_admin,
-            xrp_address: details.xrp_address,
-            settle_threshold: details.settle_threshold,
-            settle_to: details.settle_to,
+            read_only: details.read_only,
+            extensions,
             send_routes: details.send_routes,
             receive_routes: details.receive_routes,
             routing_relation,
@@ -139,37 +192,33 @@ impl ToRedisArgs for Account {
         self.min_balance.write_redis_args(&mut rv);
 
         // Write optional fields
-        if let Some(http_endpoint) = self.http_endpoint.as_ref() {
-            "http_endpoint".write_redis_args(&mut rv);
-            http_endpoint.as_str().write_redis_args(&mut rv);
-        }
-        if let Some(http_incoming_authorization) = self.http_incoming_authorization.as_ref() {
-            "http_incoming_authorization".write_redis_args(&mut rv);
-            http_incoming_authorization.write_redis_args(&mut rv);
+        for (name, value) in self.extensions.to_strings() {
+            name.write_redis_args(&mut rv);
+            value.write_redis_args(&mut rv);
         }
-        if let Some(http_outgoing_authorization) = self.http_outgoing_authorization.as_ref() {
-            "http_outgoing_authorization".write_redis_args(&mut rv);
-            http_outgoing_authorization.write_redis_args(&mut rv);
+        if let Some(max_packet_data) = self.max_packet_data {
+            "max_packet_data".write_redis_args(&mut rv);
+            max_packet_data.write_redis_args(&mut rv);
         }
-        if let Some(btp_uri) = self.btp_uri.as_ref() {
-            "btp_uri".write_redis_args(&mut rv);
-            btp_uri.as_str().write_redis_args(&mut rv);
+        if self.priority != Priority::default() {
+            "priority".write_redis_args(&mut rv);
+            self.priority.as_str().write_redis_args(&mut rv);
         }
-        if let Some(btp_incoming_authorization) = self.btp_incoming_authorization.as_ref() {
-            "btp_incoming_authorization".write_redis_args(&mut rv);
-            btp_incoming_authorization.write_redis_args(&mut rv);
+        if let Some(max_value_per_second) = self.max_value_per_second {
+            "max_value_per_second".write_redis_args(&mut rv);
+            max_value_per_second.write_redis_args(&mut rv);
         }
-        if let Some(xrp_address) = self.xrp_address.as_ref() {
-            "xrp_address".write_redis_args(&mut rv);
-            xrp_address.write_redis_args(&mut rv);
+        if let Some(inactivity_timeout) = self.inactivity_timeout {
+            "inactivity_timeout".write_redis_args(&mut rv);
+            inactivity_timeout.write_redis_args(&mut rv);
         }
-        if let Some(settle_threshold) = self.settle_threshold {
-            "settle_threshold".write_redis_args(&mut rv);
-            settle_threshold.write_redis_args(&mut rv);
+        if self.pending {
+            "pending".write_redis_args(&mut rv);
+            self.pending.write_redis_args(&mut rv);
         }
-        if let Some(settle_to) = self.settle_to {
-            "settle_to".write_redis_args(&mut rv);
-            settle_to.write_redis_args(&mut rv);
+        if self.read_only {
+            "read_only".write_redis_args(&mut rv);
+            self.read_only.write_redis_args(&mut rv);
         }
         if self.send_routes {
             "send_routes".write_redis_args(&mut rv);
@@ -198,22 +247,36 @@ impl FromRedisValue for Account {
         } else {
             RoutingRelation::Child
         };
+        let priority: Option<String> = get_value_option("priority", &hash)?;
+        let priority = if let Some(priority) = priority {
+            Priority::from_str(priority.as_str())
+                .map_err(|_| RedisError::from((ErrorKind::TypeError, "Invalid priority")))?
+        } else {
+            Priority::default()
+        };
+        let mut extension_fields = Vec::new();
+        for name in Extensions::names(&EXTENSION_FIELDS) {
+            if let Some(value) = get_value_option::<String>(name, &hash)? {
+                extension_fields.push((name, value));
+            }
+        }
+        let extensions = Extensions::parse(&EXTENSION_FIELDS, extension_fields)
+            .map_err(|err| RedisError::from((ErrorKind::TypeError, "Invalid account", err)))?;
         Ok(Account {
             id: get_value("id", &hash)?,
             ilp_address: Bytes::from(ilp_address.as_bytes()),
             asset_code: get_value("asset_code", &hash)?,
             asset_scale: get_value("asset_scale", &hash)?,
-            http_endpoint: get_url_option("http_endpoint", &hash)?,
-            http_incoming_authorization: get_value_option("http_incoming_authorization", &hash)?,
-            http_outgoing_authorization: get_value_option("http_outgoing_authorization", &hash)?,
-            btp_uri: get_url_option("btp_uri", &hash)?,
-            btp_incoming_authorization: get_value_option("btp_incoming_authorization", &hash)?,
             max_packet_amount: get_value("max_packet_amount", &hash)?,
+            max_packet_data: get_value_option("max_packet_data", &hash)?,
+            priority,
+            max_value_per_second: get_value_option("max_value_per_second", &hash)?,
+            inactivity_timeout: get_value_option("inactivity_timeout", &hash)?,
+            pending: get_bool("pending", &hash),
             min_balance: get_value("min_balance", &hash)?,
             is_admin: get_bool("is_admin", &hash),
-            xrp_address: get_value_option("xrp_address", &hash)?,
-            settle_threshold: get_value_option("settle_threshold", &hash)?,
-            settle_to: get_value_option("settle_to", &hash)?,
+            read_only: get_bool("read_only", &hash),
+            extensions,
             routing_relation,
             send_routes: get_bool("send_routes", &hash),
             receive_routes: get_bool("receive_routes", &hash),
@@ -247,19 +310,6 @@ where
     }
 }
 
-fn get_url_option(key: &str, map: &HashMap<String, Value>) -> Result<Option<Url>, RedisError> {
-    if let Some(ref value) = map.get(key) {
-        let value: String = from_redis_value(value)?;
-        if let Ok(url) = Url::parse(&value) {
-            Ok(Some(url))
-        } else {
-            Err(RedisError::from((ErrorKind::TypeError, "Invalid URL")))
-        }
-    } else {
-        Ok(None)
-    }
-}
-
 fn get_bool(key: &str, map: &HashMap<String, Value>) -> bool {
     if let Some(ref value) = map.get(key) {
         if let Ok(value) = from_redis_value(value) as Result<String, RedisError> {
@@ -293,21 +343,59 @@ impl IldcpAccount for Account {
     }
 }
 
+const HTTP_ENDPOINT: Field<Url> = Field::secret("http_endpoint");
+pub(crate) const HTTP_INCOMING_AUTHORIZATION: Field<String> =
+    Field::secret("http_incoming_authorization");
+const HTTP_OUTGOING_AUTHORIZATION: Field<String> = Field::secret("http_outgoing_authorization");
+static HTTP_FIELDS: [&ExtensionField; 3] = [
+    &HTTP_ENDPOINT,
+    &HTTP_INCOMING_AUTHORIZATION,
+    &HTTP_OUTGOING_AUTHORIZATION,
+];
+
 impl HttpAccount for Account {
     fn get_http_url(&self) -> Option<&Url> {
-        self.http_endpoint.as_ref()
+        self.extensions.get(&HTTP_ENDPOINT)
     }
 
     fn get_http_auth_header(&self) -> Option<&str> {
-        self.http_outgoing_authorization
-            .as_ref()
-            .map(|s| s.as_str())
+        self.extensions
+            .get(&HTTP_OUTGOING_AUTHORIZATION)
+            .map(String::as_str)
     }
 }
 
+const BTP_URI: Field<Url> = Field::secret("btp_uri");
+pub(crate) const BTP_INCOMING_AUTHORIZATION: Field<String> =
+    Field::secret("btp_incoming_authorization");
+static BTP_FIELDS: [&ExtensionField; 2] = [&BTP_URI, &BTP_INCOMING_AUTHORIZATION];
+
 impl BtpAccount for Account {
     fn get_btp_uri(&self) -> Option<&Url> {
-        self.btp_uri.as_ref()
+        self.extensions.get(&BTP_URI)
+    }
+}
+
+const SETTLE_THRESHOLD: Field<i64> = Field::new("settle_threshold");
+const SETTLE_TO: Field<i64> = Field::new("settle_to");
+static SETTLEMENT_FIELDS: [&ExtensionField; 2] = [&SETTLE_THRESHOLD, &SETTLE_TO];
+
+impl SettlementAccount for Account {
+    fn settle_threshold(&self) -> Option<i64> {
+        self.extensions.get(&SETTLE_THRESHOLD).cloned()
+    }
+
+    fn settle_to(&self) -> Option<i64> {
+        self.extensions.get(&SETTLE_TO).cloned()
+    }
+}
+
+pub(crate) const XRP_ADDRESS: Field<String> = Field::new("xrp_address");
+static XRP_FIELDS: [&ExtensionField; 1] = [&XRP_ADDRESS];
+
+impl XrpAccount for Account {
+    fn xrp_address(&self) -> Option<&str> {
+        self.extensions.get(&XRP_ADDRESS).map(String::as_str)
     }
 }
 
@@ -317,10 +405,43 @@ impl MaxPacketAmountAccount for Account {
     }
 }
 
+impl MaxPacketDataAccount for Account {
+    fn max_packet_data(&self) -> Option<u16> {
+        self.max_packet_data
+    }
+}
+
+impl PriorityAccount for Account {
+    fn priority(&self) -> Priority {
+        self.priority
+    }
+}
+
+impl ShapingAccount for Account {
+    fn max_value_per_second(&self) -> Option<u64> {
+        self.max_value_per_second
+    }
+}
+
+const OWNER: Field<String> = Field::new("owner");
+static NODE_FIELDS: [&ExtensionField; 1] = [&OWNER];
+
 impl NodeAccount for Account {
     fn is_admin(&self) -> bool {
         self.is_admin
     }
+
+    fn owner(&self) -> Option<&str> {
+        self.extensions.get(&OWNER).map(String::as_str)
+    }
+
+    fn is_pending(&self) -> bool {
+        self.pending
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.read_only
+    }
 }
 
 impl CcpRoutingAccount for Account {
@@ -7,7 +7,10 @@ extern crate log;
 extern crate serde;
 
 mod account;
+mod extensions;
 mod store;
 
 pub use account::Account;
-pub use store::{connect, connect_with_poll_interval, IntoConnectionInfo, RedisStore};
+pub use store::{
+    connect, connect_with_poll_interval, IntoConnectionInfo, RedisStore, RedisStoreBuilder,
+};
@@ -1,35 +1,76 @@
 use super::account::*;
+use super::extensions::Field;
 use bytes::Bytes;
+use chrono::{Duration as ChronoDuration, NaiveDate, Utc};
 use futures::{
-    future::{err, ok, result, Either},
+    future::{err, loop_fn, ok, result, Either, Loop},
+    sync::mpsc::{unbounded, UnboundedReceiver, UnboundedSender},
     Future, Stream,
 };
 use hashbrown::{HashMap, HashSet};
-use interledger_api::{AccountDetails, NodeStore};
+use interledger_api::{
+    api_key_token, AccountDetails, ApiKey, ApiKeyStore, ApiScope, BalanceHistoryStore,
+    BalanceSnapshot, BatchError, IdempotencyStore, IncomingPayment, KeyGroupUsage, NodeStore,
+    PaymentLink, PaymentLinkStatus, PaymentLinkStore, PaymentStore, Refund, RefundStatus,
+    RefundStore, RouteSource, RouteVersion, ScheduleStatus, ScheduleStore, ScheduledPayment,
+    SessionStatus, SettlementClaim, StoreUsage, StreamingSession, StreamingSessionStore,
+    API_KEY_PREFIX,
+};
 use interledger_btp::BtpStore;
 use interledger_ccp::RouteManagerStore;
+use interledger_config::StoreConfig;
 use interledger_http::HttpStore;
-use interledger_router::RouterStore;
-use interledger_service::{Account as AccountTrait, AccountStore};
-use interledger_service_util::{BalanceStore, ExchangeRateStore};
-use parking_lot::RwLock;
-use redis::{self, cmd, r#async::SharedConnection, Client, PipelineCommands, Value};
+use interledger_packet::{is_within_prefix, redact::Redacted, Address};
+use interledger_router::{RouteHint, RouterStore};
+use interledger_service::{Account as AccountTrait, AccountStore, StoreError};
+use interledger_service_util::{
+    AccountStats, BalanceStore, DailyStats, ExchangeRateStore, FeePolicy, FeeStore, Lock,
+    LockStore, MaintenanceStore, MaintenanceWindow, PrefixStats, SettlementInfo,
+    SettlementInfoStore, StatsStore,
+};
+use interledger_stream::StreamConnectionStore;
+use parking_lot::{Mutex, RwLock};
+use rand::{thread_rng, Rng};
+use redis::{
+    self, cmd, r#async::SharedConnection, Client, ConnectionInfo, FromRedisValue, PipelineCommands,
+    RedisError, RedisResult, ToRedisArgs, Value,
+};
+use ring::digest;
 use std::{
     iter::FromIterator,
-    sync::Arc,
-    time::{Duration, Instant},
+    mem,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Weak,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 use tokio_executor::spawn;
 use tokio_timer::Interval;
 
-const POLL_INTERVAL: u64 = 60000; // 1 minute
-
 static ACCOUNT_FROM_INDEX: &str = "
 local id = redis.call('HGET', KEYS[1], ARGV[1])
 if not id then
     return nil
 end
 return redis.call('HGETALL', 'accounts:' .. id)";
+// Gets the account an API key acts on behalf of, or nil if the key or the account does not exist
+static ACCOUNT_FROM_API_KEY: &str = "
+local id = redis.call('HGET', KEYS[1], ARGV[1])
+if not id then
+    return nil
+end
+local api_key = redis.call('GET', 'api_keys:' .. id)
+if not api_key then
+    return nil
+end
+local account_key = 'accounts:' .. cjson.decode(api_key).account_id
+if redis.call('EXISTS', account_key) == 0 then
+    return nil
+end
+return redis.call('HGETALL', account_key)";
 static UPDATE_BALANCES: &str = "
 local from_asset_code = string.lower(ARGV[1])
 local from_id = ARGV[2]
@@ -47,590 +88,4832 @@ if min_balance then
 end
 local from_balance = redis.call('HINCRBY', 'balances:' .. from_asset_code, from_id, 0 - from_amount)
 local to_balance = redis.call('HINCRBY', 'balances:' .. to_asset_code, to_id, to_amount)
+local update_id = redis.call('INCR', 'next_in_flight_id')
+redis.call('HMSET', 'in_flight:' .. update_id,
+    'from_asset_code', from_asset_code, 'from_id', from_id, 'from_amount', from_amount,
+    'to_asset_code', to_asset_code, 'to_id', to_id, 'to_amount', to_amount)
+redis.call('ZADD', 'in_flight', ARGV[7], update_id)
+for _, id in ipairs({from_id, to_id}) do
+    local inactivity_timeout = redis.call('HGET', 'accounts:' .. id, 'inactivity_timeout')
+    if inactivity_timeout then
+        redis.call('ZADD', 'accounts:expiry', tonumber(ARGV[8]) + tonumber(inactivity_timeout), id)
+    end
+end
+return {from_balance, to_balance, update_id}";
+
+// Updates are journaled in a sorted set scored by the packet's expiry (in seconds),
+// with the balance changes stored in a hash per update. Undoing an update that is
+// no longer journaled does nothing, so an update is never rolled back twice
+static UNDO_BALANCE_UPDATE: &str = "
+local key = 'in_flight:' .. ARGV[1]
+local update = redis.call('HMGET', key, 'from_asset_code', 'from_id', 'from_amount', 'to_asset_code', 'to_id', 'to_amount')
+if not update[1] then
+    return nil
+end
+local from_balance = redis.call('HINCRBY', 'balances:' .. update[1], update[2], update[3])
+local to_balance = redis.call('HINCRBY', 'balances:' .. update[4], update[5], 0 - tonumber(update[6]))
+redis.call('DEL', key)
+redis.call('ZREM', 'in_flight', ARGV[1])
 return {from_balance, to_balance}";
 
+// Roll back the updates for packets that expired before the given time without being
+// fulfilled or rejected, for example because the node handling them crashed. At most
+// ARGV[2] updates are rolled back at a time
+static RECOVER_IN_FLIGHT: &str = "
+local update_ids = redis.call('ZRANGEBYSCORE', 'in_flight', '-inf', ARGV[1], 'LIMIT', 0, ARGV[2])
+for _, update_id in ipairs(update_ids) do
+    local key = 'in_flight:' .. update_id
+    local update = redis.call('HMGET', key, 'from_asset_code', 'from_id', 'from_amount', 'to_asset_code', 'to_id', 'to_amount')
+    if update[1] then
+        redis.call('HINCRBY', 'balances:' .. update[1], update[2], update[3])
+        redis.call('HINCRBY', 'balances:' .. update[4], update[5], 0 - tonumber(update[6]))
+        redis.call('DEL', key)
+    end
+    redis.call('ZREM', 'in_flight', update_id)
+end
+return #update_ids";
+
+// Keep a balance update because its packet was fulfilled, by removing it from the journal
+static COMMIT_BALANCE_UPDATE: &str = "
+redis.call('DEL', 'in_flight:' .. ARGV[1])
+return redis.call('ZREM', 'in_flight', ARGV[1])";
+
+// Inserts or replaces an account and keeps its secondary indexes and route in sync, checking
+// that none of the indexed values belong to another account, atomically.
+// The arguments are the account ID, whether it is an update, its balance key, whether to send
+// it routes, its ILP address, the current time and the number of indexes. Those are followed by
+// the key, the indexed field and the new value (or an empty string) of each index, and then the
+// account itself. Accounts pending approval are not routed to or sent routes
+static WRITE_ACCOUNT: &str = "
+local id = ARGV[1]
+local is_update = ARGV[2] == 'true'
+local balance_key = ARGV[3]
+local account_key = 'accounts:' .. id
+local exists = redis.call('EXISTS', account_key) == 1
+if is_update and not exists then
+    return redis.error_reply('No account with ID: ' .. id)
+elseif not is_update and (exists or redis.call('HEXISTS', balance_key, id) == 1) then
+    return redis.error_reply('An account already exists with the same ID: ' .. id)
+end
+if is_update and balance_key ~= 'balances:' .. string.lower(redis.call('HGET', account_key, 'asset_code')) then
+    return redis.error_reply('Cannot change the asset code of account: ' .. id)
+end
+local num_indexes = tonumber(ARGV[7])
+local indexes = {}
+for i = 0, num_indexes - 1 do
+    local index = {key = ARGV[8 + i * 3], field = ARGV[9 + i * 3], value = ARGV[10 + i * 3]}
+    if index.value ~= '' then
+        local owner = redis.call('HGET', index.key, index.value)
+        if owner and owner ~= id then
+            return redis.error_reply('An account already exists with the same ' .. index.field)
+        end
+    end
+    table.insert(indexes, index)
+end
+if is_update then
+    for _, index in ipairs(indexes) do
+        local old_value = redis.call('HGET', account_key, index.field)
+        if old_value and old_value ~= index.value and redis.call('HGET', index.key, old_value) == id then
+            redis.call('HDEL', index.key, old_value)
+        end
+    end
+    local old_address = redis.call('HGET', account_key, 'ilp_address')
+    if old_address and old_address ~= ARGV[5] and redis.call('HGET', 'routes', old_address) == id then
+        redis.call('HDEL', 'routes', old_address)
+    end
+    local old_owner = redis.call('HGET', account_key, 'owner')
+    if old_owner then
+        redis.call('SREM', 'owners:' .. old_owner, id)
+    end
+    redis.call('DEL', account_key)
+else
+    redis.call('HSET', balance_key, id, 0)
+end
+for _, index in ipairs(indexes) do
+    if index.value ~= '' then
+        redis.call('HSET', index.key, index.value, id)
+    end
+end
+redis.call('HMSET', account_key, unpack(ARGV, 8 + num_indexes * 3))
+redis.call('SADD', 'accounts:ids', id)
+local owner = redis.call('HGET', account_key, 'owner')
+if owner then
+    redis.call('SADD', 'owners:' .. owner, id)
+end
+local pending = redis.call('HEXISTS', account_key, 'pending') == 1
+if ARGV[4] == 'true' and not pending then
+    redis.call('SADD', 'send_routes_to', id)
+else
+    redis.call('SREM', 'send_routes_to', id)
+end
+if not pending then
+    redis.call('HSET', 'routes', ARGV[5], id)
+elseif redis.call('HGET', 'routes', ARGV[5]) == id then
+    redis.call('HDEL', 'routes', ARGV[5])
+end
+local inactivity_timeout = redis.call('HGET', account_key, 'inactivity_timeout')
+if inactivity_timeout then
+    redis.call('ZADD', 'accounts:expiry', tonumber(ARGV[6]) + tonumber(inactivity_timeout), id)
+else
+    redis.call('ZREM', 'accounts:expiry', id)
+end
+return id";
+
+// Inserts a batch of new accounts, atomically, if none of them conflict with existing accounts
+// or with each other. The writes are the same as WRITE_ACCOUNT makes for a new account.
+// The arguments are the current time and the number of indexes, followed by the key and the
+// indexed field of each index. Then for each account: its ID, its balance key, whether to send it
+// routes, its ILP address, the value (or an empty string) of each index, the number of account
+// fields and the fields themselves. Returns the (0-based) positions of the conflicting accounts,
+// which is empty if the accounts were inserted
+static INSERT_ACCOUNTS: &str = "
+local num_indexes = tonumber(ARGV[2])
+local indexes = {}
+for i = 0, num_indexes - 1 do
+    table.insert(indexes, {key = ARGV[3 + i * 2], field = ARGV[4 + i * 2]})
+end
+local accounts = {}
+local pos = 3 + num_indexes * 2
+while pos <= #ARGV do
+    local account = {id = ARGV[pos], balance_key = ARGV[pos + 1], send_routes = ARGV[pos + 2] == 'true', ilp_address = ARGV[pos + 3], values = {}}
+    for i = 1, num_indexes do
+        account.values[i] = ARGV[pos + 3 + i]
+    end
+    local num_fields = tonumber(ARGV[pos + 4 + num_indexes])
+    account.fields = {unpack(ARGV, pos + 5 + num_indexes, pos + 4 + num_indexes + num_fields)}
+    table.insert(accounts, account)
+    pos = pos + 5 + num_indexes + num_fields
+end
+local conflicts = {}
+local claimed = {}
+for n, account in ipairs(accounts) do
+    local conflict = redis.call('EXISTS', 'accounts:' .. account.id) == 1 or redis.call('HEXISTS', account.balance_key, account.id) == 1
+    for i, index in ipairs(indexes) do
+        local value = account.values[i]
+        if value ~= '' then
+            claimed[index.key] = claimed[index.key] or {}
+            if claimed[index.key][value] or redis.call('HEXISTS', index.key, value) == 1 then
+                conflict = true
+            end
+            claimed[index.key][value] = true
+        end
+    end
+    if conflict then
+        table.insert(conflicts, n - 1)
+    end
+end
+if #conflicts > 0 then
+    return conflicts
+end
+for _, account in ipairs(accounts) do
+    local account_key = 'accounts:' .. account.id
+    redis.call('HSET', account.balance_key, account.id, 0)
+    for i, index in ipairs(indexes) do
+        if account.values[i] ~= '' then
+            redis.call('HSET', index.key, account.values[i], account.id)
+        end
+    end
+    redis.call('HMSET', account_key, unpack(account.fields))
+    redis.call('SADD', 'accounts:ids', account.id)
+    local owner = redis.call('HGET', account_key, 'owner')
+    if owner then
+        redis.call('SADD', 'owners:' .. owner, account.id)
+    end
+    local pending = redis.call('HEXISTS', account_key, 'pending') == 1
+    if account.send_routes and not pending then
+        redis.call('SADD', 'send_routes_to', account.id)
+    end
+    if not pending then
+        redis.call('HSET', 'routes', account.ilp_address, account.id)
+    end
+    local inactivity_timeout = redis.call('HGET', account_key, 'inactivity_timeout')
+    if inactivity_timeout then
+        redis.call('ZADD', 'accounts:expiry', tonumber(ARGV[1]) + tonumber(inactivity_timeout), account.id)
+    end
+end
+return conflicts";
+
+// Adds static routes if all of the accounts they point to exist, so that an account that is deleted
+// at the same time cannot be left with routes to it. The arguments are the prefix and account ID
+// of each route. Returns the (0-based) positions of the routes whose accounts do not exist,
+// which is empty if the routes were added
+static ADD_STATIC_ROUTES: &str = "
+local missing = {}
+for i = 1, #ARGV, 2 do
+    if redis.call('EXISTS', 'accounts:' .. ARGV[i + 1]) == 0 then
+        table.insert(missing, (i - 1) / 2)
+    end
+end
+if #missing > 0 then
+    return missing
+end
+for i = 1, #ARGV, 2 do
+    redis.call('HSET', KEYS[1], ARGV[i], ARGV[i + 1])
+end
+return missing";
+
+// Removes an account along with its balance, its secondary index entries, the routes to it
+// and the settlement info it sent. Its payments, balance history and settlement claims are kept
+// until the garbage collection removes them, DELETED_ACCOUNT_RETENTION after it was deleted.
+// The arguments are the account ID, the current time and the number of indexes, followed by the
+// key and the indexed field of each index. If a time is given after those, the account is only
+// removed if it expired (for being inactive) by then and its balance is zero. Returns the deleted
+// account
+static DELETE_ACCOUNT: &str = "
+local id = ARGV[1]
+local account_key = 'accounts:' .. id
+if redis.call('EXISTS', account_key) == 0 then
+    return nil
+end
+local num_indexes = tonumber(ARGV[3])
+local expired_by = ARGV[4 + num_indexes * 2]
+local balance_key = 'balances:' .. string.lower(redis.call('HGET', account_key, 'asset_code'))
+if expired_by then
+    local expiry = redis.call('ZSCORE', 'accounts:expiry', id)
+    if not expiry or tonumber(expiry) > tonumber(expired_by) then
+        return nil
+    end
+    -- Never delete an account that owes or is owed money
+    if tonumber(redis.call('HGET', balance_key, id) or 0) ~= 0 then
+        return nil
+    end
+end
+for i = 0, num_indexes - 1 do
+    local index_key = ARGV[4 + i * 2]
+    local value = redis.call('HGET', account_key, ARGV[5 + i * 2])
+    if value and redis.call('HGET', index_key, value) == id then
+        redis.call('HDEL', index_key, value)
+    end
+end
+for _, routes_key in ipairs({'routes', 'routes:static', 'address_aliases'}) do
+    local routes = redis.call('HGETALL', routes_key)
+    for i = 1, #routes, 2 do
+        if routes[i + 1] == id then
+            redis.call('HDEL', routes_key, routes[i])
+        end
+    end
+end
+redis.call('SREM', 'send_routes_to', id)
+redis.call('ZREM', 'accounts:expiry', id)
+redis.call('SREM', 'accounts:ids', id)
+redis.call('ZADD', 'accounts:deleted', ARGV[2], id)
+local owner = redis.call('HGET', account_key, 'owner')
+if owner then
+    redis.call('SREM', 'owners:' .. owner, id)
+end
+redis.call('HDEL', balance_key, id)
+local account = redis.call('HGETALL', account_key)
+redis.call('DEL', account_key, 'settlement_info:' .. id)
+return account";
+
+// Makes the given instance the leader if there is none, or extends its lease if it already is.
+// Returns 1 if the instance is the leader
+static CLAIM_LEADERSHIP: &str = "
+local leader = redis.call('GET', 'leader')
+if leader == ARGV[1] then
+    redis.call('PEXPIRE', 'leader', ARGV[2])
+    return 1
+elseif not leader then
+    redis.call('SET', 'leader', ARGV[1], 'PX', ARGV[2])
+    return 1
+end
+return 0";
+
+// Acquires the lock if no one holds it, with a token that is higher than any of the
+// lock's previous ones. Returns the token, or nil if the lock is held
+static ACQUIRE_LOCK: &str = "
+local token = redis.call('HINCRBY', KEYS[2], ARGV[1], 1)
+if redis.call('SET', KEYS[1], token, 'NX', 'PX', ARGV[2]) then
+    return token
+end
+return nil";
+
+// Extends the lock if it is still held with the given token. Returns 1 if it is
+static EXTEND_LOCK: &str = "
+if redis.call('GET', KEYS[1]) == ARGV[1] then
+    redis.call('PEXPIRE', KEYS[1], ARGV[2])
+    return 1
+end
+return 0";
+
+// Releases the lock if it is still held with the given token
+static RELEASE_LOCK: &str = "
+if redis.call('GET', KEYS[1]) == ARGV[1] then
+    redis.call('DEL', KEYS[1])
+end
+return 0";
+
+// Gets the IDs of the child accounts among a batch of accounts (ARGV[2] onwards) whose addresses
+// start with the prefix in ARGV[1]. Only accounts whose routing relation is Child are children
+static FIND_CHILD_ACCOUNTS: &str = "
+local prefix = ARGV[1]
+local children = {}
+for i = 2, #ARGV do
+    local fields = redis.call('HMGET', 'accounts:' .. ARGV[i], 'ilp_address', 'routing_relation')
+    if fields[1] and fields[2] == 'Child' and string.sub(fields[1], 1, #prefix) == prefix then
+        table.insert(children, ARGV[i])
+    end
+end
+return children";
+
+// Changes the ILP address of an account and of its children (ARGV[4] onwards, as found with
+// FIND_CHILD_ACCOUNTS), whose addresses start with its own. The children are checked again,
+// because they may have changed since they were found.
+// The old addresses are kept as aliases until the given expiry time, so packets sent to them
+// are still routed to the accounts during the grace period. Nothing is changed if one of the new
+// addresses is routed to, or is an alias of, another account. Returns {CHANGED, accounts} with
+// the accounts that were changed, or one of the other codes below on its own
+static CHANGE_ILP_ADDRESS: &str = "
+local CHANGED, NO_ACCOUNT, ADDRESS_TAKEN = 0, 1, 2
+local id = ARGV[1]
+local new_address = ARGV[2]
+local alias_expiry = ARGV[3]
+local old_address = redis.call('HGET', 'accounts:' .. id, 'ilp_address')
+if not old_address then
+    return {NO_ACCOUNT}
+elseif old_address == new_address then
+    return {CHANGED, {}}
+end
+local changes = {{id, old_address, new_address}}
+local prefix = old_address .. '.'
+for i = 4, #ARGV do
+    local child = ARGV[i]
+    local fields = redis.call('HMGET', 'accounts:' .. child, 'ilp_address', 'routing_relation')
+    if child ~= id and fields[1] and fields[2] == 'Child' and string.sub(fields[1], 1, #prefix) == prefix then
+        table.insert(changes, {child, fields[1], new_address .. string.sub(fields[1], #old_address + 1)})
+    end
+end
+-- Check every new address before changing any, because the script is not rolled back
+for _, change in ipairs(changes) do
+    local account_id, to = change[1], change[3]
+    local route = redis.call('HGET', 'routes', to)
+    local alias = redis.call('HGET', 'address_aliases', to)
+    if (route and route ~= account_id) or (alias and alias ~= account_id) then
+        return {ADDRESS_TAKEN}
+    end
+end
+local changed = {}
+for _, change in ipairs(changes) do
+    local account_id, from, to = change[1], change[2], change[3]
+    local account_key = 'accounts:' .. account_id
+    redis.call('HSET', account_key, 'ilp_address', to)
+    if redis.call('HGET', 'routes', from) == account_id then
+        redis.call('HDEL', 'routes', from)
+    end
+    redis.call('HSET', 'routes', to, account_id)
+    redis.call('HDEL', 'address_aliases', to)
+    redis.call('ZREM', 'address_aliases:expiry', to)
+    redis.call('HSET', 'address_aliases', from, account_id)
+    redis.call('ZADD', 'address_aliases:expiry', alias_expiry, from)
+    table.insert(changed, redis.call('HGETALL', account_key))
+end
+return {CHANGED, changed}";
+const ADDRESS_CHANGED: u8 = 0;
+const ADDRESS_NO_ACCOUNT: u8 = 1;
+const ADDRESS_TAKEN: u8 = 2;
+
+// Reads the accounts with the given IDs (a batch of the index of account IDs), leaving out the
+// ones that were deleted since the IDs were read
+static GET_ACCOUNTS_IN_BATCH: &str = "
+local accounts = {}
+for _, id in ipairs(ARGV) do
+    local account = redis.call('HGETALL', 'accounts:' .. id)
+    if #account > 0 then
+        table.insert(accounts, account)
+    end
+end
+return accounts";
+
+// Reads the accounts in the owner's index (KEYS[1]) in one script, so none of them can be
+// deleted between reading the index and the accounts
+static GET_ACCOUNTS_BY_OWNER: &str = "
+local accounts = {}
+for _, id in ipairs(redis.call('SMEMBERS', KEYS[1])) do
+    table.insert(accounts, redis.call('HGETALL', 'accounts:' .. id))
+end
+return accounts";
+// Gets the IDs of the most recent payments to any of the accounts in the index (KEYS[1]), newest
+// first. Only the ARGV[1] newest payments of each account can be among them
+static GET_RECENT_PAYMENTS: &str = "
+local limit = tonumber(ARGV[1])
+local recent = {}
+for _, id in ipairs(redis.call('SMEMBERS', KEYS[1])) do
+    local payments = redis.call('ZREVRANGE', 'payments:' .. id, 0, limit - 1, 'WITHSCORES')
+    for i = 1, #payments, 2 do
+        table.insert(recent, {payments[i], tonumber(payments[i + 1])})
+    end
+end
+table.sort(recent, function(a, b) return a[2] > b[2] end)
+local connection_ids = {}
+for i = 1, math.min(limit, #recent) do
+    connection_ids[i] = recent[i][1]
+end
+return connection_ids";
+
+// Adds the changes to the routes and static routes since they were last recorded to the history
+// as a new version. The recorded copies of both tables are kept so that only the prefixes that
+// changed have to be stored. If ARGV[4] is a version number, the changes made since that version
+// are first undone, newest first, leaving out routes to accounts that were deleted since and
+// keeping the routes to accounts' own addresses. Returns the latest version, or nil if the
+// version to restore is no longer in the history
+static RECORD_ROUTES: &str = "
+local history_key = KEYS[1]
+local version_key = KEYS[2]
+local routes_key = KEYS[3]
+local static_routes_key = KEYS[4]
+local recorded_routes_key = KEYS[5]
+local recorded_static_routes_key = KEYS[6]
+local max_versions = tonumber(ARGV[1])
+local timestamp = tonumber(ARGV[2])
+local source = ARGV[3]
+local restore = tonumber(ARGV[4])
+local function read_routes(key)
+    local routes = {}
+    local fields = redis.call('HGETALL', key)
+    for i = 1, #fields, 2 do
+        routes[fields[i]] = fields[i + 1]
+    end
+    return routes
+end
+local function undo(key, changes)
+    for prefix, change in pairs(changes) do
+        local id = redis.call('HGET', key, prefix)
+        local own_address = key == routes_key and id
+            and redis.call('HGET', 'accounts:' .. id, 'ilp_address') == prefix
+        if not own_address then
+            if change.old and redis.call('EXISTS', 'accounts:' .. change.old) == 1 then
+                redis.call('HSET', key, prefix, change.old)
+            else
+                redis.call('HDEL', key, prefix)
+            end
+        end
+    end
+end
+local function diff(key, recorded_key)
+    local current = read_routes(key)
+    local recorded = read_routes(recorded_key)
+    local changes = {}
+    local changed = false
+    for prefix, id in pairs(current) do
+        if recorded[prefix] ~= id then
+            changes[prefix] = {old = recorded[prefix], new = id}
+            redis.call('HSET', recorded_key, prefix, id)
+            changed = true
+        end
+    end
+    for prefix, id in pairs(recorded) do
+        if not current[prefix] then
+            changes[prefix] = {old = id}
+            redis.call('HDEL', recorded_key, prefix)
+            changed = true
+        end
+    end
+    return changes, changed
+end
+if restore then
+    local newer = {}
+    local found = false
+    for _, entry in ipairs(redis.call('LRANGE', history_key, 0, -1)) do
+        local version = cjson.decode(entry)
+        if version.version == restore then
+            found = true
+            break
+        end
+        table.insert(newer, version)
+    end
+    if not found then
+        return false
+    end
+    for _, version in ipairs(newer) do
+        undo(routes_key, version.routes)
+        undo(static_routes_key, version.static_routes)
+    end
+end
+local routes, routes_changed = diff(routes_key, recorded_routes_key)
+local static_routes, static_routes_changed = diff(static_routes_key, recorded_static_routes_key)
+local latest = redis.call('LINDEX', history_key, 0)
+if latest and not routes_changed and not static_routes_changed then
+    return latest
+end
+local entry = cjson.encode({
+    version = redis.call('INCR', version_key),
+    timestamp = timestamp,
+    source = source,
+    routes = routes,
+    static_routes = static_routes
+})
+redis.call('LPUSH', history_key, entry)
+redis.call('LTRIM', history_key, 0, max_versions - 1)
+return entry";
+
+// Balance snapshots are stored in sorted sets scored by timestamp, with members in the form "{timestamp}:{balance}".
+// The arguments are the current time, the oldest time to keep and the IDs of a batch of accounts
+static SNAPSHOT_BALANCES: &str = "
+local now = ARGV[1]
+local oldest = ARGV[2]
+local recorded = 0
+for i = 3, #ARGV do
+    local account_id = ARGV[i]
+    local asset_code = redis.call('HGET', 'accounts:' .. account_id, 'asset_code')
+    if asset_code then
+        local balance = redis.call('HGET', 'balances:' .. string.lower(asset_code), account_id) or 0
+        local key = 'balance_history:' .. account_id
+        redis.call('ZADD', key, now, now .. ':' .. balance)
+        redis.call('ZREMRANGEBYSCORE', key, '-inf', '(' .. oldest)
+        recorded = recorded + 1
+    end
+end
+return recorded";
+
+// Adds up the stats of the packets forwarded since the last batch. Redis cannot store amounts
+// over the largest i64, so they are capped there instead of failing the whole batch.
+//
+// Only the MAX_TRACKED_PREFIXES busiest destination prefixes are kept, using the Space-Saving
+// algorithm: a new prefix replaces the one with the fewest packets and starts from its count, so
+// counts may be overestimated but a busy prefix is never missed. The distinct destinations
+// of each prefix are counted with a HyperLogLog, which uses at most 12KB per prefix
+static RECORD_STATS: &str = "
+local total_key = KEYS[1]
+local packets_key = KEYS[2]
+local fulfilled_key = KEYS[3]
+local ttl = ARGV[1]
+local max_prefixes = tonumber(ARGV[2])
+local max_amount = '9223372036854775807'
+local amounts_prefix = 'stats:prefixes:amounts:'
+local destinations_prefix = 'stats:prefixes:destinations:'
+local function add(key, field, amount)
+    local result = redis.pcall('HINCRBY', key, field, amount)
+    if type(result) == 'table' and result.err then
+        redis.call('HSET', key, field, max_amount)
+    end
+end
+local i = 4
+for _ = 1, tonumber(ARGV[3]) do
+    local daily_key, field, amount = ARGV[i], ARGV[i + 1], ARGV[i + 2]
+    add(daily_key, field, amount)
+    add(total_key, field, amount)
+    redis.call('EXPIRE', daily_key, ttl)
+    i = i + 3
+end
+local num_prefixes = tonumber(ARGV[i])
+i = i + 1
+for _ = 1, num_prefixes do
+    local prefix, packets, fulfilled = ARGV[i], ARGV[i + 1], ARGV[i + 2]
+    i = i + 3
+    if not redis.call('ZSCORE', packets_key, prefix) and redis.call('ZCARD', packets_key) >= max_prefixes then
+        local least_busy = redis.call('ZRANGE', packets_key, 0, 0, 'WITHSCORES')
+        redis.call('ZREM', packets_key, least_busy[1])
+        redis.call('ZREM', fulfilled_key, least_busy[1])
+        redis.call('DEL', amounts_prefix .. least_busy[1], destinations_prefix .. least_busy[1])
+        redis.call('ZADD', packets_key, least_busy[2], prefix)
+    end
+    redis.call('ZINCRBY', packets_key, packets, prefix)
+    redis.call('ZINCRBY', fulfilled_key, fulfilled, prefix)
+    -- Amounts are kept per asset, because a prefix may be reached through accounts with different assets
+    for _ = 1, tonumber(ARGV[i]) do
+        add(amounts_prefix .. prefix, ARGV[i + 1], ARGV[i + 2])
+        i = i + 2
+    end
+    i = i + 1
+    for _ = 1, tonumber(ARGV[i]) do
+        redis.call('PFADD', destinations_prefix .. prefix, ARGV[i + 1])
+        i = i + 1
+    end
+    i = i + 1
+    redis.call('EXPIRE', destinations_prefix .. prefix, ttl)
+end";
+
+// Adds the amount to the total received on a STREAM connection and returns the new total, or
+// fails without changing anything if the total would not fit in an i64. The connection's details
+// and its entry in the account's payments only need to be written with its first packet
+static ADD_AMOUNT_RECEIVED: &str = "
+local key = KEYS[1]
+local payments_key = KEYS[2]
+local now = tonumber(ARGV[2])
+local retention = tonumber(ARGV[3])
+local is_new = redis.call('EXISTS', key) == 0
+local total = redis.pcall('HINCRBY', key, 'received', ARGV[1])
+if type(total) == 'table' and total.err then
+    return redis.error_reply('Total received on connection would overflow')
+end
+if is_new then
+    redis.call('HMSET', key, 'account_id', ARGV[5], 'asset_code', ARGV[6], 'asset_scale', ARGV[7], 'started_at', now)
+    if ARGV[8] then
+        redis.call('HSET', key, 'tag', ARGV[8])
+    end
+    redis.call('ZADD', payments_key, now, ARGV[4])
+    redis.call('ZREMRANGEBYSCORE', payments_key, '-inf', now - retention)
+end
+redis.call('HSET', key, 'updated_at', now)
+redis.call('EXPIRE', key, retention)
+return total";
+
+// Adds the amount to what the payment link has received, returning its fields, or the reason
+// the amount is not accepted. The link is marked as paid once it has received the full amount
+static PAY_PAYMENT_LINK: &str = "
+local link = KEYS[1]
+local amount = tonumber(ARGV[2])
+local now = tonumber(ARGV[3])
+local account_id, status, total, received, expires_at =
+    unpack(redis.call('HMGET', link, 'account_id', 'status', 'amount', 'received', 'expires_at'))
+if account_id ~= ARGV[1] then
+    return 'Unknown payment link'
+end
+if status ~= 'open' then
+    return 'Payment link was already paid'
+end
+if tonumber(expires_at) <= now then
+    return 'Payment link has expired'
+end
+if tonumber(received) + amount > tonumber(total) then
+    return 'Amount is more than the payment link is for'
+end
+if redis.call('HINCRBY', link, 'received', amount) >= tonumber(total) then
+    redis.call('HMSET', link, 'status', 'paid', 'paid_at', now)
+end
+return redis.call('HGETALL', link)";
+
+// Takes back an amount added to what the payment link has received, opening the link again
+// if it was marked as paid
+static UNDO_PAYMENT_LINK_PAYMENT: &str = "
+local link = KEYS[1]
+if redis.call('EXISTS', link) == 0 then
+    return 0
+end
+local received = redis.call('HINCRBY', link, 'received', -tonumber(ARGV[1]))
+if received < tonumber(redis.call('HGET', link, 'amount')) then
+    redis.call('HSET', link, 'status', 'open')
+    redis.call('HDEL', link, 'paid_at', 'notified')
+end
+return received";
+
+// Returns the payment link's fields if it has been paid and was not returned by this already
+static TAKE_PAID_PAYMENT_LINK: &str = "
+local link = KEYS[1]
+if redis.call('HGET', link, 'status') ~= 'paid' then
+    return nil
+end
+if redis.call('HSETNX', link, 'notified', 1) == 0 then
+    return nil
+end
+return redis.call('HGETALL', link)";
+
+// Reserves part of a received payment for a refund. An empty amount means everything that has
+// not been refunded yet. Returns {RESERVED, id, amount, return_pointer}, or one of the other
+// codes below on its own
+static RESERVE_REFUND: &str = "
+local RESERVED, NO_PAYMENT, NO_RETURN_POINTER, TOO_MUCH = 0, 1, 2, 3
+local payment = KEYS[1]
+local account_id, received, refunded, return_pointer =
+    unpack(redis.call('HMGET', payment, 'account_id', 'received', 'refunded', 'return_pointer'))
+if account_id ~= ARGV[1] then
+    return {NO_PAYMENT}
+end
+if not return_pointer then
+    return {NO_RETURN_POINTER}
+end
+local available = tonumber(received) - tonumber(refunded or 0)
+local amount = available
+if ARGV[2] ~= '' then
+    amount = tonumber(ARGV[2])
+end
+if amount <= 0 or amount > available then
+    return {TOO_MUCH}
+end
+redis.call('HINCRBY', payment, 'refunded', amount)
+return {RESERVED, redis.call('INCR', KEYS[2]), amount, return_pointer}";
+const REFUND_RESERVED: u8 = 0;
+const REFUND_NO_PAYMENT: u8 = 1;
+const REFUND_NO_RETURN_POINTER: u8 = 2;
+const REFUND_TOO_MUCH: u8 = 3;
+
+// Idempotency keys, STREAM connections, payment links and daily stats expire on their own. This removes the
+// entries that only get trimmed when new data is written for the same account, which otherwise
+// stay around forever for accounts that go quiet. The arguments are the oldest payment and
+// balance snapshot to keep and the IDs of a batch of accounts
+static COLLECT_GARBAGE: &str = "
+local payments_oldest = ARGV[1]
+local history_oldest = ARGV[2]
+local removed = 0
+for i = 3, #ARGV do
+    local account_id = ARGV[i]
+    local payments = 'payments:' .. account_id
+    removed = removed + redis.call('ZREMRANGEBYSCORE', payments, '-inf', '(' .. payments_oldest)
+    for _, connection_id in ipairs(redis.call('ZRANGE', payments, 0, -1)) do
+        if redis.call('EXISTS', 'stream_connections:' .. connection_id) == 0 then
+            removed = removed + redis.call('ZREM', payments, connection_id)
+        end
+    end
+    removed = removed + redis.call('ZREMRANGEBYSCORE', 'balance_history:' .. account_id, '-inf', '(' .. history_oldest)
+    local links = 'payment_links_by_account:' .. account_id
+    for _, link_id in ipairs(redis.call('ZRANGE', links, 0, -1)) do
+        if redis.call('EXISTS', 'payment_links:' .. link_id) == 0 then
+            removed = removed + redis.call('ZREM', links, link_id)
+        end
+    end
+end
+return removed";
+
+// Removes the ILP address aliases that expired, and the payments, balance history and
+// settlement claims of up to ARGV[3] accounts that were deleted before ARGV[2]. Counts each of
+// those accounts' keys as one entry
+static COLLECT_DELETED: &str = "
+local now = ARGV[1]
+local removed = 0
+for _, alias in ipairs(redis.call('ZRANGEBYSCORE', 'address_aliases:expiry', '-inf', now)) do
+    redis.call('HDEL', 'address_aliases', alias)
+    removed = removed + redis.call('ZREM', 'address_aliases:expiry', alias)
+end
+for _, id in ipairs(redis.call('ZRANGEBYSCORE', 'accounts:deleted', '-inf', ARGV[2], 'LIMIT', 0, ARGV[3])) do
+    removed = removed + redis.call('DEL', 'payments:' .. id, 'balance_history:' .. id, 'payment_links_by_account:' .. id, 'claims:' .. id)
+    redis.call('ZREM', 'accounts:deleted', id)
+end
+return removed";
+
+// Adds the existing accounts to the indexes of account IDs and of the accounts of each owner, for
+// databases that were written before the indexes were kept. Each call indexes the next ARGV[1]
+// IDs, so that no single call blocks Redis for long, and records where the next one starts.
+// Returns the number of accounts that were indexed and whether all of them are now, after which
+// the script does nothing
+static INDEX_ACCOUNT_IDS: &str = "
+if redis.call('EXISTS', 'accounts:ids:indexed') == 1 then
+    return {0, 1}
+end
+local from_id = tonumber(redis.call('GET', 'accounts:ids:next_to_index') or 0)
+local next_id = tonumber(redis.call('GET', 'next_account_id') or 0)
+local to_id = math.min(from_id + tonumber(ARGV[1]), next_id)
+local indexed = 0
+for id = from_id, to_id - 1 do
+    if redis.call('EXISTS', 'accounts:' .. id) == 1 then
+        redis.call('SADD', 'accounts:ids', id)
+        local owner = redis.call('HGET', 'accounts:' .. id, 'owner')
+        if owner then
+            redis.call('SADD', 'owners:' .. owner, id)
+        end
+        indexed = indexed + 1
+    end
+end
+if to_id >= next_id then
+    redis.call('SET', 'accounts:ids:indexed', 1)
+    redis.call('DEL', 'accounts:ids:next_to_index')
+    return {indexed, 1}
+end
+redis.call('SET', 'accounts:ids:next_to_index', to_id)
+return {indexed, 0}";
+
+// Counts the data that is not kept per account. Each of these is a single command that takes
+// constant time, so the script does not block Redis for long
+static STORE_USAGE: &str = "
+return {
+    redis.call('DBSIZE'),
+    redis.call('SCARD', 'accounts:ids'),
+    redis.call('HLEN', 'routes'),
+    redis.call('HLEN', 'routes:static'),
+    redis.call('ZCARD', 'in_flight'),
+    redis.call('HLEN', 'address_aliases')
+}";
+
+// Counts the balances, balance snapshots, payments and settlement claims of a batch of accounts
+// (the arguments)
+static ACCOUNT_STORE_USAGE: &str = "
+local balances, balance_snapshots, payments, claims = 0, 0, 0, 0
+for _, id in ipairs(ARGV) do
+    local asset_code = redis.call('HGET', 'accounts:' .. id, 'asset_code')
+    if asset_code then
+        balances = balances + redis.call('HEXISTS', 'balances:' .. string.lower(asset_code), id)
+        balance_snapshots = balance_snapshots + redis.call('ZCARD', 'balance_history:' .. id)
+        payments = payments + redis.call('ZCARD', 'payments:' .. id)
+        claims = claims + redis.call('LLEN', 'claims:' .. id)
+    end
+end
+return {balances, balance_snapshots, payments, claims}";
+
+// Saves the JSON-encoded item unless it was saved by someone else since its version was loaded,
+// and adds it to (or removes it from) an index. Returns 1 if it was saved, 0 if the version
+// changed and -1 if the item no longer exists
+static SAVE_IF_UNCHANGED: &str = "
+local current = redis.call('GET', KEYS[1])
+if not current then
+    return -1
+end
+if (cjson.decode(current).version or 0) ~= tonumber(ARGV[1]) then
+    return 0
+end
+redis.call('SET', KEYS[1], ARGV[2])
+local index_command = ARGV[3]
+if index_command == 'ZADD' then
+    redis.call('ZADD', KEYS[2], ARGV[4], ARGV[5])
+elseif index_command == 'ZREM' or index_command == 'SADD' or index_command == 'SREM' then
+    redis.call(index_command, KEYS[2], ARGV[4])
+else
+    return redis.error_reply('Unknown index command: ' .. index_command)
+end
+return 1";
+
+static SCRIPTS: [(&str, &str); 33] = [
+    ("ACCOUNT_FROM_INDEX", ACCOUNT_FROM_INDEX),
+    ("ACCOUNT_FROM_API_KEY", ACCOUNT_FROM_API_KEY),
+    ("UPDATE_BALANCES", UPDATE_BALANCES),
+    ("UNDO_BALANCE_UPDATE", UNDO_BALANCE_UPDATE),
+    ("RECOVER_IN_FLIGHT", RECOVER_IN_FLIGHT),
+    ("COMMIT_BALANCE_UPDATE", COMMIT_BALANCE_UPDATE),
+    ("WRITE_ACCOUNT", WRITE_ACCOUNT),
+    ("INSERT_ACCOUNTS", INSERT_ACCOUNTS),
+    ("ADD_STATIC_ROUTES", ADD_STATIC_ROUTES),
+    ("DELETE_ACCOUNT", DELETE_ACCOUNT),
+    ("CLAIM_LEADERSHIP", CLAIM_LEADERSHIP),
+    ("ACQUIRE_LOCK", ACQUIRE_LOCK),
+    ("EXTEND_LOCK", EXTEND_LOCK),
+    ("RELEASE_LOCK", RELEASE_LOCK),
+    ("FIND_CHILD_ACCOUNTS", FIND_CHILD_ACCOUNTS),
+    ("CHANGE_ILP_ADDRESS", CHANGE_ILP_ADDRESS),
+    ("GET_ACCOUNTS_IN_BATCH", GET_ACCOUNTS_IN_BATCH),
+    ("GET_ACCOUNTS_BY_OWNER", GET_ACCOUNTS_BY_OWNER),
+    ("GET_RECENT_PAYMENTS", GET_RECENT_PAYMENTS),
+    ("RECORD_ROUTES", RECORD_ROUTES),
+    ("SNAPSHOT_BALANCES", SNAPSHOT_BALANCES),
+    ("RECORD_STATS", RECORD_STATS),
+    ("ADD_AMOUNT_RECEIVED", ADD_AMOUNT_RECEIVED),
+    ("PAY_PAYMENT_LINK", PAY_PAYMENT_LINK),
+    ("UNDO_PAYMENT_LINK_PAYMENT", UNDO_PAYMENT_LINK_PAYMENT),
+    ("TAKE_PAID_PAYMENT_LINK", TAKE_PAID_PAYMENT_LINK),
+    ("RESERVE_REFUND", RESERVE_REFUND),
+    ("COLLECT_GARBAGE", COLLECT_GARBAGE),
+    ("COLLECT_DELETED", COLLECT_DELETED),
+    ("INDEX_ACCOUNT_IDS", INDEX_ACCOUNT_IDS),
+    ("STORE_USAGE", STORE_USAGE),
+    ("ACCOUNT_STORE_USAGE", ACCOUNT_STORE_USAGE),
+    ("SAVE_IF_UNCHANGED", SAVE_IF_UNCHANGED),
+];
+
 static ROUTES_KEY: &str = "routes";
 static RATES_KEY: &str = "rates";
+// Instances that share the database are told about changes to the routing table and rates
+// on these channels, so they reload them right away instead of at the next poll
+static ROUTES_CHANNEL: &str = "routes:updates";
+static RATES_CHANNEL: &str = "rates:updates";
+// How long the subscription waits for updates before checking whether the store was dropped
+const SUBSCRIPTION_READ_TIMEOUT: Duration = Duration::from_secs(1);
+const RESUBSCRIBE_DELAY: Duration = Duration::from_secs(5);
 static STATIC_ROUTES_KEY: &str = "routes:static";
+static ROUTE_HISTORY_KEY: &str = "routes:history";
+static ROUTE_VERSION_KEY: &str = "routes:version";
+// Copies of the routes and static routes as of the latest version, which new versions are compared to
+static RECORDED_ROUTES_KEY: &str = "routes:recorded";
+static RECORDED_STATIC_ROUTES_KEY: &str = "routes:static:recorded";
+// Hash of the price and liquidity hints of the alternative routes to each prefix
+static ROUTE_HINTS_KEY: &str = "routes:hints";
+const ROUTE_HISTORY_LENGTH: usize = 100;
 static NEXT_ACCOUNT_ID_KEY: &str = "next_account_id";
+// Sorted set of the accounts with an inactivity timeout, scored by when they expire (in seconds)
+static ACCOUNT_EXPIRY_KEY: &str = "accounts:expiry";
+// Set of the IDs of the existing accounts, which is scanned in batches to go through all of them
+static ACCOUNT_IDS_KEY: &str = "accounts:ids";
+const ACCOUNT_BATCH_SIZE: usize = 100;
+static FEES_KEY: &str = "fees";
+static MAINTENANCE_KEY: &str = "maintenance";
+static TOTAL_STATS_KEY: &str = "stats:total";
+const STATS_RETENTION_DAYS: u64 = 32;
+// Forwarded packets are added up in memory and written this often, so the stats of up to
+// this long are lost if the node stops
+const STATS_FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+static PREFIX_PACKETS_KEY: &str = "stats:prefixes:packets";
+static PREFIX_FULFILLED_KEY: &str = "stats:prefixes:fulfilled";
+// Destinations are grouped by their first 3 segments, for example "g.exchange.alice"
+const PREFIX_STATS_SEGMENTS: usize = 3;
+const MAX_TRACKED_PREFIXES: usize = 1000;
+const BALANCE_HISTORY_RETENTION: u64 = 30 * 24 * 60 * 60; // 30 days
+const STREAM_CONNECTION_RETENTION: u64 = 24 * 60 * 60; // 1 day
+const PAYMENT_LINK_RETENTION: u64 = 30 * 24 * 60 * 60; // 30 days after they expire
+const DELETED_ACCOUNT_RETENTION: u64 = 30 * 24 * 60 * 60; // 30 days, in case their claims are disputed
+static NEXT_SCHEDULED_PAYMENT_ID_KEY: &str = "next_scheduled_payment_id";
+// Sorted set of the IDs of the payments waiting to be sent, scored by when they should run (in seconds)
+static DUE_SCHEDULED_PAYMENTS_KEY: &str = "scheduled_payments:due";
+static NEXT_REFUND_ID_KEY: &str = "next_refund_id";
+static NEXT_STREAMING_SESSION_ID_KEY: &str = "next_streaming_session_id";
+static ACTIVE_STREAMING_SESSIONS_KEY: &str = "streaming_sessions:active";
+static NEXT_API_KEY_ID_KEY: &str = "next_api_key_id";
+// Hash of the SHA-256 hashes of the API keys' tokens to their IDs
+static API_KEY_TOKENS_KEY: &str = "api_key_tokens";
+// Hash of the locks' names to their last tokens
+static LOCK_TOKENS_KEY: &str = "lock_tokens";
+static GC_LOCK: &str = "gc";
+
+// How long after a packet expires its balance update is rolled back if it is still in flight.
+// Nodes reject packets when they expire, so this only applies if the node stopped
+const IN_FLIGHT_RECOVERY_DELAY: u64 = 60;
+const MAX_RECOVERED_PER_RUN: usize = 1000;
+
+// How long packets for an account's previous ILP address are still routed to it
+const ADDRESS_CHANGE_GRACE_PERIOD: u64 = 24 * 60 * 60; // 1 day
+static ADDRESS_ALIASES_KEY: &str = "address_aliases";
+static ADDRESS_ALIAS_EXPIRY_KEY: &str = "address_aliases:expiry";
+
+// Milliseconds since the UNIX epoch, written to the primary to measure how far behind the replica is
+static REPLICATION_HEARTBEAT_KEY: &str = "replication_heartbeat";
+
+// How many random keys are checked to estimate the size of each group of keys
+const STORE_USAGE_SAMPLES: usize = 100;
+
+/// A hash that maps each value of a unique account field to the ID of the account with that value.
+/// Indexes are updated along with the accounts, so every entry points to an existing account.
+struct SecondaryIndex {
+    key: &'static str,
+    /// The indexed extension field
+    field: &'static Field<String>,
+}
+
+static BTP_AUTH_INDEX: SecondaryIndex = SecondaryIndex {
+    key: "btp_auth",
+    field: &BTP_INCOMING_AUTHORIZATION,
+};
+static HTTP_AUTH_INDEX: SecondaryIndex = SecondaryIndex {
+    key: "http_auth",
+    field: &HTTP_INCOMING_AUTHORIZATION,
+};
+static XRP_ADDRESS_INDEX: SecondaryIndex = SecondaryIndex {
+    key: "xrp_addresses",
+    field: &XRP_ADDRESS,
+};
+impl SecondaryIndex {
+    /// The account's value of the indexed field, or an empty string if it is not set
+    fn value<'a>(&self, account: &'a Account) -> &'a str {
+        account
+            .extensions
+            .get(self.field)
+            .map(String::as_str)
+            .unwrap_or("")
+    }
+}
+
+static SECONDARY_INDEXES: [&SecondaryIndex; 3] =
+    [&BTP_AUTH_INDEX, &HTTP_AUTH_INDEX, &XRP_ADDRESS_INDEX];
 
 fn account_details_key(account_id: u64) -> String {
     format!("accounts:{}", account_id)
 }
 
+// Idempotency keys can include the credentials of the requests, so only their hashes are saved
+fn idempotency_key(key: &str) -> String {
+    format!("idempotency:{}", hash_api_key_token(key))
+}
+
+fn claims_key(account_id: u64) -> String {
+    format!("claims:{}", account_id)
+}
+
+fn owner_accounts_key(owner: &str) -> String {
+    format!("owners:{}", owner)
+}
+
+fn settlement_info_key(account_id: u64) -> String {
+    format!("settlement_info:{}", account_id)
+}
+
+/// Record the changes to the routes in the route history (after restoring the given version,
+/// if any). Returns the latest version, or `None` if the version to restore is not in the history
+fn record_routes(
+    connection: SharedConnection,
+    source: RouteSource,
+    restore: Option<u64>,
+) -> impl Future<Item = (SharedConnection, Option<String>), Error = RedisError> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64;
+    cmd("EVAL")
+        .arg(RECORD_ROUTES)
+        .arg(6)
+        .arg(ROUTE_HISTORY_KEY)
+        .arg(ROUTE_VERSION_KEY)
+        .arg(ROUTES_KEY)
+        .arg(STATIC_ROUTES_KEY)
+        .arg(RECORDED_ROUTES_KEY)
+        .arg(RECORDED_STATIC_ROUTES_KEY)
+        .arg(ROUTE_HISTORY_LENGTH)
+        .arg(now)
+        .arg(source.as_str())
+        .arg(
+            restore
+                .map(|version| version.to_string())
+                .unwrap_or_default(),
+        )
+        .query_async(connection)
+}
+
+fn balance_history_key(account_id: u64) -> String {
+    format!("balance_history:{}", account_id)
+}
+
+fn payments_key(account_id: u64) -> String {
+    format!("payments:{}", account_id)
+}
+
+fn scheduled_payment_key(id: u64) -> String {
+    format!("scheduled_payments:{}", id)
+}
+
+fn account_scheduled_payments_key(account_id: u64) -> String {
+    format!("scheduled_payments_by_account:{}", account_id)
+}
+
+fn refund_key(id: u64) -> String {
+    format!("refunds:{}", id)
+}
+
+fn payment_refunds_key(connection_id: &str) -> String {
+    format!("refunds_by_payment:{}", connection_id)
+}
+
+fn payment_link_key(id: &str) -> String {
+    format!("payment_links:{}", id)
+}
+
+fn account_payment_links_key(account_id: u64) -> String {
+    format!("payment_links_by_account:{}", account_id)
+}
+
+fn streaming_session_key(id: u64) -> String {
+    format!("streaming_sessions:{}", id)
+}
+
+fn api_key_key(id: u64) -> String {
+    format!("api_keys:{}", id)
+}
+
+// Only the hashes of API keys' tokens are saved, so the tokens cannot be read from the database
+fn hash_api_key_token(token: &str) -> String {
+    digest::digest(&digest::SHA256, token.as_bytes())
+        .as_ref()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+fn lock_key(name: &str) -> String {
+    format!("locks:{}", name)
+}
+
+fn account_streaming_sessions_key(account_id: u64) -> String {
+    format!("streaming_sessions_by_account:{}", account_id)
+}
+
+fn stream_connection_key(connection_id: &[u8]) -> String {
+    format!(
+        "stream_connections:{}",
+        String::from_utf8_lossy(connection_id)
+    )
+}
+
+fn prefix_amounts_key(prefix: &str) -> String {
+    format!("stats:prefixes:amounts:{}", prefix)
+}
+
+fn prefix_destinations_key(prefix: &str) -> String {
+    format!("stats:prefixes:destinations:{}", prefix)
+}
+
+fn daily_stats_key(date: &NaiveDate) -> String {
+    format!("stats:{}", date.format("%Y-%m-%d"))
+}
+
 fn balance_key(asset_code: &str) -> String {
     format!("balances:{}", asset_code.to_lowercase())
 }
 
+fn write_account(
+    connection: SharedConnection,
+    account: Account,
+    is_update: bool,
+) -> impl Future<Item = (SharedConnection, Account), Error = StoreError> {
+    let mut script = cmd("EVAL");
+    script
+        .arg(WRITE_ACCOUNT)
+        .arg(0)
+        .arg(account.id)
+        .arg(if is_update { "true" } else { "false" })
+        .arg(balance_key(account.asset_code.as_str()))
+        .arg(if account.send_routes { "true" } else { "false" })
+        .arg(account.ilp_address.to_vec())
+        .arg(
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
+        )
+        .arg(SECONDARY_INDEXES.len());
+    for index in SECONDARY_INDEXES.iter() {
+        // The script takes empty strings in place of values that are not set
+        script
+            .arg(index.key)
+            .arg(index.field.name())
+            .arg(index.value(&account));
+    }
+    script.arg(account.clone());
+
+    script
+        .query_async(connection)
+        .map_err(|err| {
+            // The script errors with these messages if the account cannot be written
+            let message = err.to_string();
+            if message.contains("already exists") {
+                warn!("Cannot write account: {:?}", err);
+                StoreError::Conflict
+            } else if message.contains("No account") {
+                StoreError::NotFound
+            } else if message.contains("Cannot change") {
+                warn!("Cannot write account: {:?}", err);
+                StoreError::InvalidData
+            } else {
+                error!("Error writing account to DB: {:?}", err);
+                StoreError::StoreUnavailable
+            }
+        })
+        .and_then(move |(connection, _id): (SharedConnection, u64)| Ok((connection, account)))
+}
+
+/// The script that deletes the account. If `expired_by` is set, the account is only
+/// deleted if it expired by then and its balance is zero.
+fn delete_account_script(id: u64, expired_by: Option<u64>) -> redis::Cmd {
+    let mut script = cmd("EVAL");
+    script
+        .arg(DELETE_ACCOUNT)
+        .arg(0)
+        .arg(id)
+        .arg(
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
+        )
+        .arg(SECONDARY_INDEXES.len());
+    for index in SECONDARY_INDEXES.iter() {
+        script.arg(index.key).arg(index.field.name());
+    }
+    if let Some(expired_by) = expired_by {
+        script.arg(expired_by);
+    }
+    script
+}
+
 pub use redis::IntoConnectionInfo;
 
 pub fn connect<R>(redis_uri: R) -> impl Future<Item = RedisStore, Error = ()>
 where
     R: IntoConnectionInfo,
 {
-    connect_with_poll_interval(redis_uri, POLL_INTERVAL)
+    RedisStoreBuilder::new(redis_uri).connect()
 }
 
 #[doc(hidden)]
 pub fn connect_with_poll_interval<R>(
     redis_uri: R,
-    poll_interval: u64,
+    poll_interval: Duration,
 ) -> impl Future<Item = RedisStore, Error = ()>
 where
     R: IntoConnectionInfo,
 {
-    result(Client::open(redis_uri))
-        .map_err(|err| error!("Error creating Redis client: {:?}", err))
-        .and_then(|client| {
-            debug!("Connected to redis: {:?}", client);
-            client
-                .get_shared_async_connection()
-                .map_err(|err| error!("Error connecting to Redis: {:?}", err))
-        })
-        .and_then(move |connection| {
-            let store = RedisStore {
-                connection: Arc::new(connection),
-                exchange_rates: Arc::new(RwLock::new(HashMap::new())),
-                routes: Arc::new(RwLock::new(HashMap::new())),
-            };
+    RedisStoreBuilder::new(redis_uri)
+        .route_poll_interval(Some(poll_interval))
+        .rate_poll_interval(Some(poll_interval))
+        .connect()
+}
 
-            // Start polling for rate updates
-            // Note: if this behavior changes, make sure to update the Drop implementation
-            let connection_clone = Arc::downgrade(&store.connection);
-            let exchange_rates = store.exchange_rates.clone();
-            let poll_rates = Interval::new(Instant::now(), Duration::from_millis(poll_interval))
-                .map_err(|err| error!("Interval error: {:?}", err))
-                .for_each(move |_| {
-                    if let Some(connection) = connection_clone.upgrade() {
-                        Either::A(update_rates(
-                            connection.as_ref().clone(),
-                            exchange_rates.clone(),
-                        ))
-                    } else {
-                        debug!("Not polling rates anymore because connection was closed");
-                        // TODO make sure the interval stops
-                        Either::B(err(()))
-                    }
-                });
-            spawn(poll_rates);
+/// Configures how the RedisStore keeps its in-memory copies of the routing table,
+/// exchange rates and fee policies up to date.
+///
+/// The store loads them when it connects and then reloads the routing table and rates whenever
+/// another instance that shares the database publishes a change to them. It also polls Redis for
+/// updates, which keeps the copies up to date when the subscription drops and picks up
+/// changes written to the database directly. The defaults are those of `StoreConfig`.
+pub struct RedisStoreBuilder<R> {
+    redis_uri: R,
+    config: StoreConfig,
+    read_replica: Option<(RedisResult<ConnectionInfo>, Duration)>,
+}
 
-            // Poll for routing table updates
-            // Note: if this behavior changes, make sure to update the Drop implementation
-            let connection_clone = Arc::downgrade(&store.connection);
-            let routing_table = store.routes.clone();
-            let poll_routes = Interval::new(Instant::now(), Duration::from_millis(poll_interval))
-                .map_err(|err| error!("Interval error: {:?}", err))
-                .for_each(move |_| {
-                    if let Some(connection) = connection_clone.upgrade() {
-                        Either::A(update_routes(
-                            connection.as_ref().clone(),
-                            routing_table.clone(),
-                        ))
-                    } else {
-                        debug!("Not polling routes anymore because connection was closed");
-                        // TODO make sure the interval stops
-                        Either::B(err(()))
+impl<R> RedisStoreBuilder<R>
+where
+    R: IntoConnectionInfo,
+{
+    pub fn new(redis_uri: R) -> Self {
+        RedisStoreBuilder {
+            redis_uri,
+            config: StoreConfig::default(),
+            read_replica: None,
+        }
+    }
+
+    /// Use all of the given settings, replacing any that were set before.
+    pub fn config(mut self, config: StoreConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// How often to poll for routing table updates. `None` disables polling, in which case
+    /// the routing table is only reloaded when the store gets a published change to it.
+    pub fn route_poll_interval(mut self, interval: Option<Duration>) -> Self {
+        self.config.route_poll_interval = interval;
+        self
+    }
+
+    /// How often to poll for exchange rate and fee policy updates. `None` disables polling, in which
+    /// case the rates are only reloaded when the store gets a published change to them, and the
+    /// fee policies are only loaded when the store connects.
+    pub fn rate_poll_interval(mut self, interval: Option<Duration>) -> Self {
+        self.config.rate_poll_interval = interval;
+        self
+    }
+
+    /// Delay the start of each polling loop by a random amount up to this long,
+    /// so that nodes sharing a database do not all poll it at the same time.
+    pub fn poll_jitter(mut self, jitter: Duration) -> Self {
+        self.config.poll_jitter = jitter;
+        self
+    }
+
+    /// How often to remove expired entries that Redis does not clean up on its own
+    /// (see `RedisStore::collect_garbage`) and inactive accounts (see `RedisStore::expire_accounts`).
+    /// `None` disables the periodic cleanup.
+    pub fn gc_interval(mut self, interval: Option<Duration>) -> Self {
+        self.config.gc_interval = interval;
+        self
+    }
+
+    /// Elect a leader among the instances that share the database, so that tasks which should only
+    /// run once across the cluster (such as the garbage collection) only run on the leader
+    /// (see `RedisStore::is_leader`). The leader renews its lease three times per lease,
+    /// and another instance takes over if the lease runs out.
+    /// `None` (the default) makes every instance act as the leader.
+    pub fn leader_election(mut self, lease: Option<Duration>) -> Self {
+        self.config.leader_lease = lease;
+        self
+    }
+
+    /// Look up accounts and poll for routing table, rate and fee policy updates using a replica
+    /// of the database, while balance updates and other writes go to the primary. The replica
+    /// is only used while it is at most `max_staleness` behind the primary.
+    ///
+    /// Note that accounts inserted or updated on the primary may not be found in the replica
+    /// until it catches up, for up to `max_staleness`.
+    pub fn read_replica<U>(mut self, replica_uri: U, max_staleness: Duration) -> Self
+    where
+        U: IntoConnectionInfo,
+    {
+        self.read_replica = Some((replica_uri.into_connection_info(), max_staleness));
+        self
+    }
+
+    /// Disable polling for routing table, rate and fee policy updates. The store still
+    /// subscribes to routing table and rate updates.
+    pub fn disable_polling(self) -> Self {
+        self.route_poll_interval(None).rate_poll_interval(None)
+    }
+
+    pub fn connect(self) -> impl Future<Item = RedisStore, Error = ()> {
+        let route_poll_interval = self.config.route_poll_interval;
+        let rate_poll_interval = self.config.rate_poll_interval;
+        let poll_jitter = self.config.poll_jitter;
+        let gc_interval = self.config.gc_interval;
+        let leader_lease = self.config.leader_lease;
+        let (replica_uri, max_staleness) = match self.read_replica {
+            Some((replica_uri, max_staleness)) => (Some(replica_uri), max_staleness),
+            None => (None, Duration::from_millis(0)),
+        };
+        // The timers of the background tasks panic if their interval is zero
+        let config_checked = self.config.validate().map_err(|errors| {
+            for error in errors {
+                error!("Invalid store config: {}", error);
+            }
+        });
+        let client = Client::open(self.redis_uri);
+        result(config_checked)
+            .and_then(move |_| {
+                result(client).map_err(|err| error!("Error creating Redis client: {:?}", err))
+            })
+            .and_then(|client| {
+                debug!("Connected to redis: {:?}", client);
+                let subscription_client = client.clone();
+                client
+                    .get_shared_async_connection()
+                    .map_err(|err| error!("Error connecting to Redis: {:?}", err))
+                    .map(move |connection| (subscription_client, connection))
+            })
+            .join(connect_replica(replica_uri))
+            .and_then(move |((client, connection), replica)| {
+                let store = RedisStore {
+                    connection: Arc::new(connection),
+                    replica,
+                    exchange_rates: Arc::new(RwLock::new(HashMap::new())),
+                    routes: Arc::new(RwLock::new(HashMap::new())),
+                    route_hints: Arc::new(RwLock::new(HashMap::new())),
+                    fee_policies: Arc::new(RwLock::new(FeePolicies::default())),
+                    maintenance_windows: Arc::new(RwLock::new(HashMap::new())),
+                    is_leader: Arc::new(AtomicBool::new(leader_lease.is_none())),
+                    pending_stats: Arc::new(Mutex::new(PendingStats::default())),
+                };
+                let connection = store.connection.as_ref().clone();
+                update_rates(connection.clone(), store.exchange_rates.clone())
+                    .join4(
+                        update_fees(connection.clone(), store.fee_policies.clone()),
+                        update_maintenance_windows(
+                            connection.clone(),
+                            store.maintenance_windows.clone(),
+                        ),
+                        update_routes(connection.clone(), store.routes.clone())
+                            .join(update_route_hints(connection.clone(), store.route_hints.clone())),
+                    )
+                    .join3(
+                        recover_in_flight_packets(connection.clone()),
+                        index_account_ids(connection),
+                    )
+                    .and_then(|_| Ok((client, store)))
+            })
+            .and_then(move |(client, store)| {
+                if let Some(ref replica) = store.replica {
+                    let connection = store.connection.as_ref().clone();
+                    let replica = replica.clone();
+                    Either::A(
+                        check_replica(connection, replica, max_staleness)
+                            .and_then(|_| Ok((client, store))),
+                    )
+                } else {
+                    Either::B(ok((client, store)))
+                }
+            })
+            .and_then(move |(client, store)| {
+                {
+                    let connection_clone = Arc::downgrade(&store.connection);
+                    let routing_table = store.routes.clone();
+                    let route_hints = store.route_hints.clone();
+                    let exchange_rates = store.exchange_rates.clone();
+                    let updates = subscribe_to_updates(client, connection_clone.clone());
+                    let apply_updates = updates.for_each(move |channel| {
+                        if let Some(connection) = connection_clone.upgrade() {
+                            // The replica may not have the update yet
+                            let connection = connection.as_ref().clone();
+                            let update = if channel == ROUTES_CHANNEL {
+                                Either::A(
+                                    update_routes(connection.clone(), routing_table.clone())
+                                        .join(update_route_hints(connection, route_hints.clone()))
+                                        .map(|_| ()),
+                                )
+                            } else {
+                                Either::B(update_rates(connection, exchange_rates.clone()))
+                            };
+                            // The errors are logged, and must not end the subscription
+                            Either::A(update.then(|_| Ok(())))
+                        } else {
+                            debug!("Not applying updates anymore because connection closed");
+                            Either::B(err(()))
+                        }
+                    });
+                    spawn(apply_updates);
+                }
+
+                if let Some(poll_interval) = rate_poll_interval {
+                    // Note: if this behavior changes, make sure to update the Drop implementation
+                    let connection_clone = Arc::downgrade(&store.connection);
+                    let replica = store.replica.clone();
+                    let exchange_rates = store.exchange_rates.clone();
+                    let fee_policies = store.fee_policies.clone();
+                    let maintenance_windows = store.maintenance_windows.clone();
+                    let poll_rates = Interval::new(
+                        poll_start(poll_interval, poll_jitter),
+                        poll_interval,
+                    )
+                    .map_err(|err| error!("Interval error: {:?}", err))
+                    .for_each(move |_| {
+                        if let Some(connection) = connection_clone.upgrade() {
+                            let connection = read_connection(&connection, &replica);
+                            Either::A(
+                                update_rates(connection.clone(), exchange_rates.clone())
+                                    .join3(
+                                        update_fees(connection.clone(), fee_policies.clone()),
+                                        update_maintenance_windows(
+                                            connection,
+                                            maintenance_windows.clone(),
+                                        ),
+                                    )
+                                    .map(|_| ()),
+                            )
+                        } else {
+                            debug!("Not polling rates anymore because connection was closed");
+                            // TODO make sure the interval stops
+                            Either::B(err(()))
+                        }
+                    });
+                    spawn(poll_rates);
+                }
+
+                if let Some(poll_interval) = route_poll_interval {
+                    // Note: if this behavior changes, make sure to update the Drop implementation
+                    let connection_clone = Arc::downgrade(&store.connection);
+                    let replica = store.replica.clone();
+                    let routing_table = store.routes.clone();
+                    let route_hints = store.route_hints.clone();
+                    let poll_routes = Interval::new(
+                        poll_start(poll_interval, poll_jitter),
+                        poll_interval,
+                    )
+                    .map_err(|err| error!("Interval error: {:?}", err))
+                    .for_each(move |_| {
+                        if let Some(connection) = connection_clone.upgrade() {
+                            let connection = read_connection(&connection, &replica);
+                            Either::A(
+                                update_routes(connection.clone(), routing_table.clone())
+                                    .join(update_route_hints(connection, route_hints.clone()))
+                                    .map(|_| ()),
+                            )
+                        } else {
+                            debug!("Not polling routes anymore because connection was closed");
+                            // TODO make sure the interval stops
+                            Either::B(err(()))
+                        }
+                    });
+                    spawn(poll_routes);
+                }
+
+                if let Some(lease) = leader_lease {
+                    let connection_clone = Arc::downgrade(&store.connection);
+                    let is_leader = store.is_leader.clone();
+                    let instance_id = format!("{:016x}", thread_rng().gen::<u64>());
+                    let renew_interval = lease / 3;
+                    let renew_leadership = Interval::new(Instant::now(), renew_interval)
+                        .map_err(|err| error!("Interval error: {:?}", err))
+                        .for_each(move |_| {
+                            if let Some(connection) = connection_clone.upgrade() {
+                                Either::A(claim_leadership(
+                                    connection.as_ref().clone(),
+                                    &instance_id,
+                                    lease.as_millis() as u64,
+                                    is_leader.clone(),
+                                ))
+                            } else {
+                                debug!("Not claiming leadership anymore because connection closed");
+                                Either::B(err(()))
+                            }
+                        });
+                    spawn(renew_leadership);
+                }
+
+                let connection_clone = Arc::downgrade(&store.connection);
+                let pending_stats = store.pending_stats.clone();
+                let flush_stats = Interval::new(
+                    Instant::now() + STATS_FLUSH_INTERVAL,
+                    STATS_FLUSH_INTERVAL,
+                )
+                .map_err(|err| error!("Interval error: {:?}", err))
+                .for_each(move |_| {
+                    if let Some(connection) = connection_clone.upgrade() {
+                        Either::A(write_stats(
+                            connection.as_ref().clone(),
+                            pending_stats.clone(),
+                        ))
+                    } else {
+                        debug!("Not writing stats anymore because connection was closed");
+                        Either::B(err(()))
                     }
                 });
-            spawn(poll_routes);
+                spawn(flush_stats);
+
+                if let Some(gc_interval) = gc_interval {
+                    let connection_clone = Arc::downgrade(&store.connection);
+                    let is_leader = store.is_leader.clone();
+                    let routing_table = store.routes.clone();
+                    let gc = Interval::new(
+                        poll_start(gc_interval, poll_jitter),
+                        gc_interval,
+                    )
+                    .map_err(|err| error!("Interval error: {:?}", err))
+                    .for_each(move |_| {
+                        if let Some(connection) = connection_clone.upgrade() {
+                            // Only one instance in the cluster needs to clean up. The lock also
+                            // keeps instances from overlapping while the leadership changes
+                            if is_leader.load(Ordering::Relaxed) {
+                                let connection = connection.as_ref().clone();
+                                let routing_table = routing_table.clone();
+                                Either::A(Either::A(
+                                    acquire_lock(connection.clone(), GC_LOCK, gc_interval)
+                                        .and_then(move |lock| match lock {
+                                            Some(lock) => Either::A(
+                                                collect_garbage(connection.clone())
+                                                    .join3(
+                                                        recover_in_flight_packets(
+                                                            connection.clone(),
+                                                        ),
+                                                        expire_accounts(
+                                                            connection.clone(),
+                                                            routing_table,
+                                                        ),
+                                                    )
+                                                    .then(move |_| release_lock(connection, lock)),
+                                            ),
+                                            None => Either::B(ok(())),
+                                        }),
+                                ))
+                            } else {
+                                Either::A(Either::B(ok(())))
+                            }
+                        } else {
+                            debug!("Not collecting garbage anymore because connection was closed");
+                            Either::B(err(()))
+                        }
+                    });
+                    spawn(gc);
+                }
 
-            Ok(store)
+                if let Some(ref replica) = store.replica {
+                    let connection_clone = Arc::downgrade(&store.connection);
+                    let replica = replica.clone();
+                    // The heartbeat is written three times per staleness bound, so a replica
+                    // that is up to date is never considered stale
+                    let check_interval = (max_staleness / 3).max(Duration::from_millis(1));
+                    let check = Interval::new(Instant::now() + check_interval, check_interval)
+                        .map_err(|err| error!("Interval error: {:?}", err))
+                        .for_each(move |_| {
+                            if let Some(connection) = connection_clone.upgrade() {
+                                Either::A(check_replica(
+                                    connection.as_ref().clone(),
+                                    replica.clone(),
+                                    max_staleness,
+                                ))
+                            } else {
+                                debug!("Not checking the replica anymore because connection was closed");
+                                Either::B(err(()))
+                            }
+                        });
+                    spawn(check);
+                }
+
+                Ok(store)
+            })
+    }
+}
+
+/// A read-only replica of the database and whether it is up to date enough to read from.
+struct Replica {
+    connection: SharedConnection,
+    is_fresh: AtomicBool,
+}
+
+fn connect_replica(
+    replica_uri: Option<RedisResult<ConnectionInfo>>,
+) -> impl Future<Item = Option<Arc<Replica>>, Error = ()> {
+    let replica_uri = if let Some(replica_uri) = replica_uri {
+        replica_uri
+    } else {
+        return Either::A(ok(None));
+    };
+    Either::B(
+        result(replica_uri.and_then(Client::open))
+            .map_err(|err| error!("Error creating Redis replica client: {:?}", err))
+            .and_then(|client| {
+                client
+                    .get_shared_async_connection()
+                    .map_err(|err| error!("Error connecting to the Redis replica: {:?}", err))
+            })
+            .map(|connection| {
+                Some(Arc::new(Replica {
+                    connection,
+                    is_fresh: AtomicBool::new(false),
+                }))
+            }),
+    )
+}
+
+// The connection to use for reads that may be slightly out of date
+fn read_connection(primary: &SharedConnection, replica: &Option<Arc<Replica>>) -> SharedConnection {
+    match replica {
+        Some(ref replica) if replica.is_fresh.load(Ordering::Relaxed) => replica.connection.clone(),
+        _ => primary.clone(),
+    }
+}
+
+// Write the current time to the primary, then check how old the latest time that reached the
+// replica is. Data on the replica is at least as recent as that, so it is used while that age
+// is within the staleness bound.
+fn check_replica(
+    primary: SharedConnection,
+    replica: Arc<Replica>,
+    max_staleness: Duration,
+) -> impl Future<Item = (), Error = ()> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64;
+    cmd("SET")
+        .arg(REPLICATION_HEARTBEAT_KEY)
+        .arg(now)
+        .query_async(primary)
+        .map(|(_connection, _): (_, Value)| ())
+        .or_else(|err| {
+            error!("Error writing the replication heartbeat: {:?}", err);
+            Ok(())
+        })
+        .and_then(move |_| {
+            cmd("GET")
+                .arg(REPLICATION_HEARTBEAT_KEY)
+                .query_async(replica.connection.clone())
+                .then(move |result: Result<(_, Option<u64>), _>| {
+                    let staleness = match result {
+                        Ok((_connection, Some(heartbeat))) => Some(now.saturating_sub(heartbeat)),
+                        Ok((_connection, None)) => None,
+                        Err(err) => {
+                            warn!("Error checking the Redis replica: {:?}", err);
+                            None
+                        }
+                    };
+                    let is_fresh = staleness.map_or(false, |staleness| {
+                        Duration::from_millis(staleness) <= max_staleness
+                    });
+                    if replica.is_fresh.swap(is_fresh, Ordering::Relaxed) != is_fresh {
+                        if is_fresh {
+                            info!("Reading from the Redis replica");
+                        } else if let Some(staleness) = staleness {
+                            warn!(
+                                "Reading from the Redis primary because the replica is {}ms behind",
+                                staleness
+                            );
+                        } else {
+                            warn!("Reading from the Redis primary because the replica is not replicating it");
+                        }
+                    }
+                    Ok(())
+                })
         })
 }
 
+// The first poll happens one interval after the initial load, plus the jitter
+fn poll_start(poll_interval: Duration, poll_jitter: Duration) -> Instant {
+    let poll_jitter = poll_jitter.as_millis() as u64;
+    let jitter = if poll_jitter > 0 {
+        thread_rng().gen_range(0, poll_jitter)
+    } else {
+        0
+    };
+    Instant::now() + poll_interval + Duration::from_millis(jitter)
+}
+
 /// A Store that uses Redis as its underlying database.
 ///
 /// This store leverages atomic Redis transactions to do operations such as balance updates.
 ///
-/// Currently the RedisStore polls the database for the routing table and rate updates, but
-/// future versions of it will use PubSub to subscribe to updates.
+/// The RedisStore subscribes to routing table and rate updates with Redis Pub/Sub, and polls the
+/// database for them as a fallback (see `RedisStoreBuilder`).
 #[derive(Clone)]
 pub struct RedisStore {
     connection: Arc<SharedConnection>,
+    replica: Option<Arc<Replica>>,
     exchange_rates: Arc<RwLock<HashMap<String, f64>>>,
     routes: Arc<RwLock<HashMap<Bytes, u64>>>,
+    route_hints: Arc<RwLock<HashMap<Bytes, Vec<RouteHint<u64>>>>>,
+    fee_policies: Arc<RwLock<FeePolicies>>,
+    maintenance_windows: Arc<RwLock<HashMap<u64, Vec<MaintenanceWindow>>>>,
+    is_leader: Arc<AtomicBool>,
+    /// Stats of the packets forwarded since they were last written
+    pending_stats: Arc<Mutex<PendingStats>>,
+}
+
+#[derive(Default)]
+struct FeePolicies {
+    accounts: HashMap<u64, FeePolicy>,
+    prefixes: HashMap<Bytes, FeePolicy>,
+}
+
+impl RedisStore {
+    fn get_next_account_id(&self) -> impl Future<Item = u64, Error = ()> {
+        cmd("INCR")
+            .arg(NEXT_ACCOUNT_ID_KEY)
+            .query_async(self.connection.as_ref().clone())
+            .map_err(|err| error!("Error incrementing account ID: {:?}", err))
+            .and_then(|(_conn, next_account_id): (_, u64)| Ok(next_account_id - 1))
+    }
+
+    /// Whether this instance is the cluster's leader, which runs the tasks that should only run once
+    /// across all of the instances that share the database. This is always true unless leader
+    /// election is enabled with `RedisStoreBuilder::leader_election`.
+    pub fn is_leader(&self) -> bool {
+        self.is_leader.load(Ordering::Relaxed)
+    }
+
+    /// Whether account lookups are currently served from the replica configured with
+    /// `RedisStoreBuilder::read_replica`, because it is within the staleness bound.
+    pub fn is_reading_from_replica(&self) -> bool {
+        self.replica
+            .as_ref()
+            .map_or(false, |replica| replica.is_fresh.load(Ordering::Relaxed))
+    }
+
+    fn read_connection(&self) -> SharedConnection {
+        read_connection(&self.connection, &self.replica)
+    }
+
+    /// Load each of the store's Lua scripts into Redis to check that the server supports
+    /// scripting and accepts all of them. Fails with a description of the first script
+    /// that could not be loaded.
+    pub fn check_scripts(&self) -> impl Future<Item = (), Error = String> {
+        let connection = self.connection.as_ref().clone();
+        futures::stream::iter_ok(SCRIPTS.iter()).for_each(move |(name, script)| {
+            cmd("SCRIPT")
+                .arg("LOAD")
+                .arg(*script)
+                .query_async(connection.clone())
+                .map_err(move |err| format!("Unable to load the {} script: {}", name, err))
+                .map(|(_connection, _sha): (_, String)| ())
+        })
+    }
+
+    /// Roll back the balance updates of packets that are still in flight well after they expired,
+    /// because the node that was handling them stopped before they were fulfilled or rejected.
+    ///
+    /// This runs when the store connects and periodically along with the garbage collection.
+    /// Returns the number of updates that were rolled back.
+    pub fn recover_in_flight_packets(&self) -> impl Future<Item = u64, Error = ()> {
+        recover_in_flight_packets(self.connection.as_ref().clone())
+    }
+
+    /// Remove payment index entries and balance snapshots that are past their retention period,
+    /// as well as index entries for STREAM connections and ILP address aliases that have already expired.
+    /// The payments, balance history and settlement claims of deleted accounts are removed
+    /// 30 days after they were deleted.
+    ///
+    /// This runs periodically unless it is disabled with `RedisStoreBuilder::gc_interval`.
+    /// Returns the number of entries that were removed.
+    pub fn collect_garbage(&self) -> impl Future<Item = u64, Error = ()> {
+        collect_garbage(self.connection.as_ref().clone())
+    }
+
+    /// Delete the accounts that have had no packets for longer than their inactivity timeout,
+    /// except for the ones whose balance is not zero.
+    ///
+    /// This runs periodically along with the garbage collection.
+    /// Returns the number of accounts that were deleted.
+    pub fn expire_accounts(&self) -> impl Future<Item = u64, Error = ()> {
+        expire_accounts(self.connection.as_ref().clone(), self.routes.clone())
+    }
+}
+
+fn claim_leadership(
+    connection: SharedConnection,
+    instance_id: &str,
+    lease: u64,
+    is_leader: Arc<AtomicBool>,
+) -> impl Future<Item = (), Error = ()> {
+    cmd("EVAL")
+        .arg(CLAIM_LEADERSHIP)
+        .arg(0)
+        .arg(instance_id)
+        .arg(lease)
+        .query_async(connection)
+        .then(move |result: Result<(SharedConnection, bool), _>| {
+            // If the lease cannot be renewed, another instance may take over when it runs out
+            let leader = match result {
+                Ok((_connection, leader)) => leader,
+                Err(err) => {
+                    error!("Error claiming cluster leadership: {:?}", err);
+                    false
+                }
+            };
+            let was_leader = is_leader.swap(leader, Ordering::Relaxed);
+            if leader && !was_leader {
+                info!("This instance is now the cluster leader");
+            } else if !leader && was_leader {
+                warn!("This instance is no longer the cluster leader");
+            }
+            Ok(())
+        })
+}
+
+fn acquire_lock(
+    connection: SharedConnection,
+    name: &str,
+    ttl: Duration,
+) -> impl Future<Item = Option<Lock>, Error = ()> {
+    let name = name.to_string();
+    cmd("EVAL")
+        .arg(ACQUIRE_LOCK)
+        .arg(2)
+        .arg(lock_key(&name))
+        .arg(LOCK_TOKENS_KEY)
+        .arg(&name)
+        .arg(ttl.as_millis() as u64)
+        .query_async(connection)
+        .map_err(|err| error!("Error acquiring lock: {:?}", err))
+        .map(move |(_connection, token): (_, Option<u64>)| token.map(|token| Lock { name, token }))
+}
+
+fn release_lock(connection: SharedConnection, lock: Lock) -> impl Future<Item = (), Error = ()> {
+    cmd("EVAL")
+        .arg(RELEASE_LOCK)
+        .arg(1)
+        .arg(lock_key(&lock.name))
+        .arg(lock.token)
+        .query_async(connection)
+        .map_err(move |err| error!("Error releasing lock {}: {:?}", lock.name, err))
+        .map(|(_connection, _): (_, Value)| ())
+}
+
+fn recover_in_flight_packets(connection: SharedConnection) -> impl Future<Item = u64, Error = ()> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    cmd("EVAL")
+        .arg(RECOVER_IN_FLIGHT)
+        .arg(0)
+        .arg(now.saturating_sub(IN_FLIGHT_RECOVERY_DELAY))
+        .arg(MAX_RECOVERED_PER_RUN)
+        .query_async(connection)
+        .map_err(|err| error!("Error recovering in-flight packets: {:?}", err))
+        .and_then(|(_connection, recovered): (_, u64)| {
+            if recovered > 0 {
+                warn!(
+                    "Rolled back the balance updates of {} packets that were never resolved",
+                    recovered
+                );
+            }
+            Ok(recovered)
+        })
+}
+
+fn expire_accounts(
+    connection: SharedConnection,
+    routing_table: Arc<RwLock<HashMap<Bytes, u64>>>,
+) -> impl Future<Item = u64, Error = ()> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    cmd("ZRANGEBYSCORE")
+        .arg(ACCOUNT_EXPIRY_KEY)
+        .arg("-inf")
+        .arg(now)
+        .query_async(connection)
+        .map_err(|err| error!("Error loading expired accounts: {:?}", err))
+        .and_then(move |(connection, ids): (SharedConnection, Vec<u64>)| {
+            // The script checks each account again, so one that was used since it was
+            // loaded or that still has a balance is left alone
+            let connection_clone = connection.clone();
+            futures::stream::iter_ok(ids)
+                .fold(0, move |deleted, id| {
+                    delete_account_script(id, Some(now))
+                        .query_async(connection_clone.clone())
+                        .map_err(move |err| error!("Error expiring account {}: {:?}", id, err))
+                        .map(move |(_connection, account): (_, Option<Account>)| {
+                            if account.is_some() {
+                                info!("Deleted account {} because it was inactive", id);
+                                deleted + 1
+                            } else {
+                                debug!(
+                                    "Not expiring account {} because its balance is not zero",
+                                    id
+                                );
+                                deleted
+                            }
+                        })
+                })
+                .map(move |deleted| (connection, deleted))
+        })
+        .and_then(move |(connection, deleted)| {
+            if deleted > 0 {
+                Either::A(
+                    routes_changed(connection, routing_table, RouteSource::Account)
+                        .map(move |_| deleted),
+                )
+            } else {
+                Either::B(ok(deleted))
+            }
+        })
+}
+
+fn collect_garbage(connection: SharedConnection) -> impl Future<Item = u64, Error = ()> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    cmd("EVAL")
+        .arg(COLLECT_DELETED)
+        .arg(0)
+        .arg(now)
+        .arg(now.saturating_sub(DELETED_ACCOUNT_RETENTION))
+        .arg(ACCOUNT_BATCH_SIZE)
+        .query_async(connection)
+        .and_then(move |(connection, removed): (SharedConnection, u64)| {
+            for_each_account_batch(connection, move |ids| {
+                let mut script = cmd("EVAL");
+                script
+                    .arg(COLLECT_GARBAGE)
+                    .arg(0)
+                    .arg(now.saturating_sub(STREAM_CONNECTION_RETENTION))
+                    .arg(now.saturating_sub(BALANCE_HISTORY_RETENTION))
+                    .arg(ids);
+                script
+            })
+            .map(move |removed_from_accounts| removed + removed_from_accounts)
+        })
+        .map_err(|err| error!("Error collecting garbage: {:?}", err))
+        .and_then(|removed| {
+            debug!("Garbage collection removed {} expired entries", removed);
+            Ok(removed)
+        })
+}
+
+/// Go through the index of account IDs in batches, so that no single command or script blocks
+/// Redis for long, and pass each batch to `batch` along with what the previous batches resulted in.
+/// The index is scanned with SSCAN, so an ID may be in more than one batch
+fn fold_account_batches<T, F, R>(
+    connection: SharedConnection,
+    init: T,
+    batch: F,
+) -> impl Future<Item = (SharedConnection, T), Error = RedisError>
+where
+    T: Send + 'static,
+    F: Fn(SharedConnection, Vec<u64>, T) -> R + Send + 'static,
+    R: Future<Item = (SharedConnection, T), Error = RedisError> + Send + 'static,
+{
+    loop_fn(
+        (connection, batch, 0, init),
+        |(connection, batch, cursor, result): (_, F, u64, T)| {
+            cmd("SSCAN")
+                .arg(ACCOUNT_IDS_KEY)
+                .arg(cursor)
+                .arg("COUNT")
+                .arg(ACCOUNT_BATCH_SIZE)
+                .query_async(connection)
+                .and_then(
+                    move |(connection, (cursor, ids)): (SharedConnection, (u64, Vec<u64>))| {
+                        let next = if ids.is_empty() {
+                            Either::A(ok((connection, result)))
+                        } else {
+                            Either::B(batch(connection, ids, result))
+                        };
+                        next.map(move |(connection, result)| {
+                            if cursor == 0 {
+                                Loop::Break((connection, result))
+                            } else {
+                                Loop::Continue((connection, batch, cursor, result))
+                            }
+                        })
+                    },
+                )
+        },
+    )
+}
+
+/// Run the script returned for each batch of account IDs. Returns the sum of the scripts' results
+fn for_each_account_batch<F>(
+    connection: SharedConnection,
+    script: F,
+) -> impl Future<Item = u64, Error = RedisError>
+where
+    F: Fn(Vec<u64>) -> redis::Cmd + Send + 'static,
+{
+    fold_account_batches(connection, 0, move |connection, ids, total| {
+        script(ids)
+            .query_async(connection)
+            .map(move |(connection, result): (SharedConnection, u64)| (connection, total + result))
+    })
+    .map(|(_connection, total)| total)
+}
+
+/// Find the IDs of the child accounts of the given account, a batch of accounts at a time
+fn find_child_accounts(
+    connection: SharedConnection,
+    id: u64,
+) -> impl Future<Item = (SharedConnection, Vec<u64>), Error = RedisError> {
+    cmd("HGET")
+        .arg(account_details_key(id))
+        .arg("ilp_address")
+        .query_async(connection)
+        .and_then(
+            move |(connection, address): (SharedConnection, Option<Vec<u8>>)| match address {
+                Some(mut prefix) => {
+                    prefix.push(b'.');
+                    Either::A(
+                        fold_account_batches(
+                            connection,
+                            Vec::new(),
+                            move |connection, ids, mut children: Vec<u64>| {
+                                cmd("EVAL")
+                                    .arg(FIND_CHILD_ACCOUNTS)
+                                    .arg(0)
+                                    .arg(&prefix[..])
+                                    .arg(ids)
+                                    .query_async(connection)
+                                    .map(
+                                        move |(connection, batch): (SharedConnection, Vec<u64>)| {
+                                            children.extend(batch);
+                                            (connection, children)
+                                        },
+                                    )
+                            },
+                        )
+                        .map(|(connection, mut children)| {
+                            children.sort();
+                            children.dedup();
+                            (connection, children)
+                        }),
+                    )
+                }
+                // The change fails because the account does not exist
+                None => Either::B(ok((connection, Vec::new()))),
+            },
+        )
+}
+
+/// Read all of the accounts, a batch at a time, ordered by ID
+fn get_all_accounts(
+    connection: SharedConnection,
+) -> impl Future<Item = (SharedConnection, Vec<Account>), Error = RedisError> {
+    fold_account_batches(
+        connection,
+        Vec::new(),
+        |connection, ids, mut accounts: Vec<Account>| {
+            cmd("EVAL")
+                .arg(GET_ACCOUNTS_IN_BATCH)
+                .arg(0)
+                .arg(ids)
+                .query_async(connection)
+                .map(
+                    move |(connection, batch): (SharedConnection, Vec<Account>)| {
+                        accounts.extend(batch);
+                        (connection, accounts)
+                    },
+                )
+        },
+    )
+    .map(|(connection, mut accounts)| {
+        accounts.sort_by_key(|account| account.id);
+        accounts.dedup_by_key(|account| account.id);
+        (connection, accounts)
+    })
+}
+
+// Databases written before the index of account IDs was kept need it built once,
+// a batch of IDs at a time
+fn index_account_ids(connection: SharedConnection) -> impl Future<Item = (), Error = ()> {
+    loop_fn(
+        (connection, 0),
+        |(connection, total): (SharedConnection, u64)| {
+            cmd("EVAL")
+                .arg(INDEX_ACCOUNT_IDS)
+                .arg(0)
+                .arg(ACCOUNT_BATCH_SIZE)
+                .query_async(connection)
+                .map(move |(connection, (indexed, done)): (_, (u64, bool))| {
+                    if done {
+                        Loop::Break(total + indexed)
+                    } else {
+                        Loop::Continue((connection, total + indexed))
+                    }
+                })
+        },
+    )
+    .map_err(|err| error!("Error indexing account IDs: {:?}", err))
+    .and_then(|indexed| {
+        if indexed > 0 {
+            info!("Added {} accounts to the index of account IDs", indexed);
+        }
+        Ok(())
+    })
+}
+
+impl AccountStore for RedisStore {
+    type Account = Account;
+
+    // TODO cache results to avoid hitting Redis for each packet
+    fn get_accounts(
+        &self,
+        account_ids: Vec<<Self::Account as AccountTrait>::AccountId>,
+    ) -> Box<Future<Item = Vec<Option<Account>>, Error = ()> + Send> {
+        let mut pipe = redis::pipe();
+        for account_id in account_ids.iter() {
+            pipe.cmd("HGETALL").arg(account_details_key(*account_id));
+        }
+        Box::new(
+            pipe.query_async(self.read_connection())
+                .map_err(|err| error!("Error querying details for accounts: {:?}", err))
+                .and_then(move |(_conn, accounts): (_, Vec<Value>)| {
+                    // HGETALL returns an empty list for accounts that do not exist
+                    accounts
+                        .iter()
+                        .zip(account_ids.iter())
+                        .map(|(value, account_id)| match value {
+                            Value::Bulk(ref fields) if fields.is_empty() => Ok(None),
+                            _ => Account::from_redis_value(value).map(Some).map_err(|err| {
+                                error!("Invalid details for account {}: {:?}", account_id, err)
+                            }),
+                        })
+                        .collect::<Result<Vec<_>, ()>>()
+                }),
+        )
+    }
+}
+
+impl BalanceStore for RedisStore {
+    fn get_balance(&self, account: Account) -> Box<Future<Item = i64, Error = StoreError> + Send> {
+        Box::new(
+            cmd("HGET")
+                .arg(balance_key(account.asset_code.as_str()))
+                .arg(account.id)
+                .query_async(self.connection.as_ref().clone())
+                .map_err(move |err| {
+                    error!(
+                        "Error getting balance for account: {} {:?}",
+                        account.id, err
+                    );
+                    StoreError::StoreUnavailable
+                })
+                .and_then(|(_connection, balance): (_, Option<i64>)| {
+                    balance.ok_or(StoreError::NotFound)
+                }),
+        )
+    }
+
+    fn update_balances(
+        &self,
+        from_account: Account,
+        incoming_amount: u64,
+        to_account: Account,
+        outgoing_amount: u64,
+        expires_at: SystemTime,
+    ) -> Box<Future<Item = u64, Error = StoreError> + Send> {
+        let from_account_id = from_account.id();
+        let to_account_id = to_account.id();
+        let expires_at = expires_at
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+
+        debug!(
+            "Decreasing balance of account {} by: {}. Increasing balance of account {} by: {}",
+            from_account_id, incoming_amount, to_account_id, outgoing_amount
+        );
+
+        Box::new(
+            cmd("EVAL")
+                // Update the balance only if it does not exceed the max_balance configured on the account
+                .arg(UPDATE_BALANCES)
+                .arg(0)
+                .arg(from_account.asset_code)
+                .arg(from_account_id)
+                .arg(incoming_amount)
+                .arg(to_account.asset_code)
+                .arg(to_account_id)
+                .arg(outgoing_amount)
+                .arg(expires_at)
+                .arg(now)
+                .query_async(self.connection.as_ref().clone())
+                .map_err(move |err| {
+                    // The script errors with this message if the balance would go below the min_balance
+                    if err.to_string().contains("Cannot subtract") {
+                        debug!("Insufficient balance to update balances for accounts. from_account: {}, to_account: {}: {:?}", from_account_id, to_account_id, err);
+                        StoreError::InsufficientBalance
+                    } else {
+                        error!(
+                        "Error updating balances for accounts. from_account: {}, to_account: {}: {:?}",
+                        from_account_id,
+                        to_account_id,
+                        err
+                    );
+                        StoreError::StoreUnavailable
+                    }
+                })
+                .and_then(
+                    move |(_connection, (from_balance, to_balance, update_id)): (
+                        _,
+                        (i64, i64, u64),
+                    )| {
+                        debug!(
+                            "Updated account balances. Account {} has: {}, account {} has: {}",
+                            from_account_id, from_balance, to_account_id, to_balance
+                        );
+                        Ok(update_id)
+                    },
+                ),
+        )
+    }
+
+    fn commit_balance_update(
+        &self,
+        update_id: u64,
+    ) -> Box<Future<Item = (), Error = StoreError> + Send> {
+        Box::new(
+            cmd("EVAL")
+                .arg(COMMIT_BALANCE_UPDATE)
+                .arg(0)
+                .arg(update_id)
+                .query_async(self.connection.as_ref().clone())
+                .map_err(move |err| {
+                    error!("Error committing balance update {}: {:?}", update_id, err);
+                    StoreError::StoreUnavailable
+                })
+                .and_then(|(_connection, _): (_, Value)| Ok(())),
+        )
+    }
+
+    fn undo_balance_update(
+        &self,
+        update_id: u64,
+    ) -> Box<Future<Item = (), Error = StoreError> + Send> {
+        debug!("Rolling back balance update {}", update_id);
+
+        // Rolling back restores the balances from before the packet, so it is not checked
+        // against the balance limits
+        Box::new(
+            cmd("EVAL")
+                .arg(UNDO_BALANCE_UPDATE)
+                .arg(0)
+                .arg(update_id)
+                .query_async(self.connection.as_ref().clone())
+                .map_err(move |err| {
+                    error!("Error undoing balance update {}: {:?}", update_id, err);
+                    StoreError::StoreUnavailable
+                })
+                .and_then(move |(_connection, balances): (_, Option<(i64, i64)>)| {
+                    if let Some((from_balance, to_balance)) = balances {
+                        debug!(
+                            "Rolled back balance update {}. Balances are now: {} and {}",
+                            update_id, from_balance, to_balance
+                        );
+                    } else {
+                        debug!(
+                            "Balance update {} was already rolled back or committed",
+                            update_id
+                        );
+                    }
+                    Ok(())
+                }),
+        )
+    }
+}
+
+impl ExchangeRateStore for RedisStore {
+    fn get_exchange_rates(&self, asset_codes: &[&str]) -> Result<Vec<f64>, ()> {
+        let rates: Vec<f64> = asset_codes
+            .iter()
+            .filter_map(|code| {
+                (*self.exchange_rates.read())
+                    .get(&code.to_string())
+                    .cloned()
+            })
+            .collect();
+        if rates.len() == asset_codes.len() {
+            Ok(rates)
+        } else {
+            Err(())
+        }
+    }
+}
+
+impl BtpStore for RedisStore {
+    type Account = Account;
+
+    fn get_account_from_btp_token(
+        &self,
+        token: &str,
+    ) -> Box<Future<Item = Self::Account, Error = ()> + Send> {
+        // TODO make sure it can't do script injection!
+        // TODO cache the result so we don't hit redis for every packet (is that necessary if redis is often used as a cache?)
+        let token = token.to_string();
+        Box::new(
+            cmd("EVAL")
+                .arg(ACCOUNT_FROM_INDEX)
+                .arg(1)
+                .arg(BTP_AUTH_INDEX.key)
+                .arg(&token)
+                .query_async(self.read_connection())
+                .map_err(|err| error!("Error getting account from BTP token: {:?}", err))
+                .and_then(move |(_connection, account): (_, Option<Account>)| {
+                    if let Some(account) = account {
+                        if account.pending {
+                            warn!("Account {} is pending approval", account.id);
+                            Err(())
+                        } else {
+                            Ok(account)
+                        }
+                    } else {
+                        warn!("No account found with BTP token: {}", Redacted(&token));
+                        Err(())
+                    }
+                }),
+        )
+    }
+}
+
+impl HttpStore for RedisStore {
+    type Account = Account;
+
+    fn get_account_from_http_auth(
+        &self,
+        auth_header: &str,
+    ) -> Box<Future<Item = Self::Account, Error = ()> + Send> {
+        // TODO make sure it can't do script injection!
+        let auth_header = auth_header.to_string();
+        // API keys act on behalf of the account that created them
+        let lookup = if let Some(token) = api_key_token(&auth_header) {
+            cmd("EVAL")
+                .arg(ACCOUNT_FROM_API_KEY)
+                .arg(1)
+                .arg(API_KEY_TOKENS_KEY)
+                .arg(hash_api_key_token(token))
+                .clone()
+        } else {
+            cmd("EVAL")
+                .arg(ACCOUNT_FROM_INDEX)
+                .arg(1)
+                .arg(HTTP_AUTH_INDEX.key)
+                .arg(&auth_header)
+                .clone()
+        };
+        Box::new(
+            lookup
+                .query_async(self.read_connection())
+                .map_err(|err| error!("Error getting account from HTTP auth: {:?}", err))
+                .and_then(move |(_connection, account): (_, Option<Account>)| {
+                    if let Some(account) = account {
+                        if account.pending {
+                            warn!("Account {} is pending approval", account.id);
+                            Err(())
+                        } else {
+                            Ok(account)
+                        }
+                    } else {
+                        warn!(
+                            "No account found with HTTP auth: {}",
+                            Redacted(&auth_header)
+                        );
+                        Err(())
+                    }
+                }),
+        )
+    }
+}
+
+impl RouterStore for RedisStore {
+    fn routing_table(&self) -> HashMap<Bytes, u64> {
+        self.routes.read().clone()
+    }
+
+    fn route_hints(&self, prefix: &[u8]) -> Vec<RouteHint<u64>> {
+        self.route_hints
+            .read()
+            .get(prefix)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    fn local_rate(&self, from: &Account, asset_code: &str) -> Option<f64> {
+        let scale = 10f64.powi(i32::from(from.asset_scale));
+        if from.asset_code == asset_code {
+            Some(1.0 / scale)
+        } else {
+            let rates = self
+                .get_exchange_rates(&[from.asset_code.as_str(), asset_code])
+                .ok()?;
+            Some(rates[1] / rates[0] / scale)
+        }
+    }
+}
+
+impl NodeStore for RedisStore {
+    type Account = Account;
+
+    fn insert_account(
+        &self,
+        account: AccountDetails,
+    ) -> Box<Future<Item = Account, Error = StoreError> + Send> {
+        debug!("Inserting account: {:?}", account);
+        let connection = self.connection.clone();
+        let routing_table = self.routes.clone();
+
+        Box::new(
+            self.get_next_account_id()
+                .map_err(|_| StoreError::StoreUnavailable)
+                .and_then(|id| {
+                    debug!("Next account id is: {}", id);
+                    Account::try_from(id, account).map_err(|_| StoreError::InvalidData)
+                })
+                .and_then(move |account| {
+                    // The uniqueness checks and the writes happen in one script so that
+                    // concurrent inserts with the same credentials cannot both succeed
+                    write_account(connection.as_ref().clone(), account, false).and_then(
+                        move |(connection, account)| {
+                            routes_changed(connection, routing_table, RouteSource::Account)
+                                .map_err(|_| StoreError::StoreUnavailable)
+                                .and_then(move |_| Ok(account))
+                        },
+                    )
+                }),
+        )
+    }
+
+    fn insert_accounts(
+        &self,
+        accounts: Vec<AccountDetails>,
+    ) -> Box<Future<Item = Vec<Account>, Error = BatchError> + Send> {
+        debug!("Inserting batch of {} accounts", accounts.len());
+        if accounts.is_empty() {
+            return Box::new(ok(Vec::new()));
+        }
+        let count = accounts.len() as u64;
+        let routing_table = self.routes.clone();
+
+        Box::new(
+            cmd("INCRBY")
+                .arg(NEXT_ACCOUNT_ID_KEY)
+                .arg(count)
+                .query_async(self.connection.as_ref().clone())
+                .map_err(|err| {
+                    error!("Error reserving account IDs: {:?}", err);
+                    BatchError::Store(StoreError::StoreUnavailable)
+                })
+                .and_then(
+                    move |(connection, next_account_id): (SharedConnection, u64)| {
+                        // The IDs are used up even if the batch is not inserted
+                        let first_id = next_account_id - count;
+                        let mut invalid = Vec::new();
+                        let accounts: Vec<Account> = accounts
+                            .into_iter()
+                            .enumerate()
+                            .filter_map(|(index, details)| {
+                                Account::try_from(first_id + index as u64, details)
+                                    .map_err(|_| invalid.push((index, StoreError::InvalidData)))
+                                    .ok()
+                            })
+                            .collect();
+                        if invalid.is_empty() {
+                            Ok((connection, accounts))
+                        } else {
+                            Err(BatchError::Entries(invalid))
+                        }
+                    },
+                )
+                .and_then(|(connection, accounts)| {
+                    let mut script = cmd("EVAL");
+                    script
+                        .arg(INSERT_ACCOUNTS)
+                        .arg(0)
+                        .arg(
+                            SystemTime::now()
+                                .duration_since(UNIX_EPOCH)
+                                .unwrap()
+                                .as_secs(),
+                        )
+                        .arg(SECONDARY_INDEXES.len());
+                    for index in SECONDARY_INDEXES.iter() {
+                        script.arg(index.key).arg(index.field.name());
+                    }
+                    for account in accounts.iter() {
+                        script
+                            .arg(account.id)
+                            .arg(balance_key(account.asset_code.as_str()))
+                            .arg(if account.send_routes { "true" } else { "false" })
+                            .arg(account.ilp_address.to_vec());
+                        for index in SECONDARY_INDEXES.iter() {
+                            script.arg(index.value(account));
+                        }
+                        let fields = account.to_redis_args();
+                        script.arg(fields.len());
+                        for field in fields {
+                            script.arg(field);
+                        }
+                    }
+                    script
+                        .query_async(connection)
+                        .map_err(|err| {
+                            error!("Error inserting batch of accounts: {:?}", err);
+                            BatchError::Store(StoreError::StoreUnavailable)
+                        })
+                        .and_then(
+                            move |(connection, conflicts): (SharedConnection, Vec<usize>)| {
+                                if conflicts.is_empty() {
+                                    Ok((connection, accounts))
+                                } else {
+                                    warn!(
+                                        "Cannot insert batch of accounts because these conflict with other accounts: {:?}",
+                                        conflicts
+                                    );
+                                    Err(BatchError::Entries(
+                                        conflicts
+                                            .into_iter()
+                                            .map(|index| (index, StoreError::Conflict))
+                                            .collect(),
+                                    ))
+                                }
+                            },
+                        )
+                })
+                .and_then(move |(connection, accounts)| {
+                    routes_changed(connection, routing_table, RouteSource::Account)
+                        .map_err(|_| BatchError::Store(StoreError::StoreUnavailable))
+                        .and_then(move |_| Ok(accounts))
+                }),
+        )
+    }
+
+    fn update_account(
+        &self,
+        id: u64,
+        account: AccountDetails,
+    ) -> Box<Future<Item = Account, Error = StoreError> + Send> {
+        debug!("Updating account {}: {:?}", id, account);
+        let connection = self.connection.as_ref().clone();
+        let routing_table = self.routes.clone();
+
+        Box::new(
+            result(Account::try_from(id, account).map_err(|_| StoreError::InvalidData))
+                .and_then(move |account| write_account(connection, account, true))
+                .and_then(move |(connection, account)| {
+                    routes_changed(connection, routing_table, RouteSource::Account)
+                        .map_err(|_| StoreError::StoreUnavailable)
+                        .and_then(move |_| Ok(account))
+                }),
+        )
+    }
+
+    fn delete_account(&self, id: u64) -> Box<Future<Item = Account, Error = StoreError> + Send> {
+        debug!("Deleting account {}", id);
+        let routing_table = self.routes.clone();
+
+        Box::new(
+            delete_account_script(id, None)
+                .query_async(self.connection.as_ref().clone())
+                .map_err(move |err| {
+                    error!("Error deleting account {}: {:?}", id, err);
+                    StoreError::StoreUnavailable
+                })
+                .and_then(|(connection, account): (_, Option<Account>)| {
+                    let account = account.ok_or(StoreError::NotFound)?;
+                    Ok((connection, account))
+                })
+                .and_then(move |(connection, account)| {
+                    routes_changed(connection, routing_table, RouteSource::Account)
+                        .map_err(|_| StoreError::StoreUnavailable)
+                        .and_then(move |_| Ok(account))
+                }),
+        )
+    }
+
+    fn change_ilp_address(
+        &self,
+        id: u64,
+        ilp_address: Bytes,
+    ) -> Box<Future<Item = Vec<Account>, Error = StoreError> + Send> {
+        debug!(
+            "Changing the ILP address of account {} to: {:?}",
+            id, ilp_address
+        );
+        let routing_table = self.routes.clone();
+        let alias_expiry = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            + ADDRESS_CHANGE_GRACE_PERIOD;
+        let new_address = ilp_address.clone();
+
+        Box::new(
+            find_child_accounts(self.connection.as_ref().clone(), id)
+                .and_then(move |(connection, children)| {
+                    cmd("EVAL")
+                        .arg(CHANGE_ILP_ADDRESS)
+                        .arg(0)
+                        .arg(id)
+                        .arg(&new_address[..])
+                        .arg(alias_expiry)
+                        .arg(children)
+                        .query_async(connection)
+                })
+                .map_err(move |err| {
+                    error!(
+                        "Error changing the ILP address of account {}: {:?}",
+                        id, err
+                    );
+                    StoreError::StoreUnavailable
+                })
+                .and_then(move |(connection, changed): (_, Vec<Value>)| {
+                    let code = changed
+                        .get(0)
+                        .and_then(|code| u8::from_redis_value(code).ok());
+                    match code {
+                        Some(ADDRESS_CHANGED) => {
+                            <(u8, Vec<Account>)>::from_redis_value(&Value::Bulk(changed))
+                                .map(|(_, accounts)| (connection, accounts))
+                                .map_err(|err| {
+                                    error!("Invalid changed accounts: {:?}", err);
+                                    StoreError::StoreUnavailable
+                                })
+                        }
+                        Some(ADDRESS_NO_ACCOUNT) => Err(StoreError::NotFound),
+                        Some(ADDRESS_TAKEN) => {
+                            debug!(
+                                "Address {:?} is already used by another account",
+                                ilp_address
+                            );
+                            Err(StoreError::Conflict)
+                        }
+                        _ => {
+                            error!("Unexpected result changing ILP address: {:?}", changed);
+                            Err(StoreError::StoreUnavailable)
+                        }
+                    }
+                })
+                .and_then(move |(connection, accounts)| {
+                    routes_changed(connection, routing_table, RouteSource::Account)
+                        .map_err(|_| StoreError::StoreUnavailable)
+                        .and_then(move |_| Ok(accounts))
+                }),
+        )
+    }
+
+    // TODO limit the number of results and page through them
+    fn get_all_accounts(
+        &self,
+    ) -> Box<Future<Item = Vec<Self::Account>, Error = StoreError> + Send> {
+        Box::new(
+            get_all_accounts(self.connection.as_ref().clone())
+                .map(|(_connection, accounts)| accounts)
+                .map_err(|err| {
+                    error!("Error getting all accounts: {:?}", err);
+                    StoreError::StoreUnavailable
+                }),
+        )
+    }
+
+    fn get_accounts_by_owner(
+        &self,
+        owner: &str,
+    ) -> Box<Future<Item = Vec<Self::Account>, Error = StoreError> + Send> {
+        Box::new(
+            cmd("EVAL")
+                .arg(GET_ACCOUNTS_BY_OWNER)
+                .arg(1)
+                .arg(owner_accounts_key(owner))
+                .query_async(self.connection.as_ref().clone())
+                .and_then(|(_connection, accounts): (_, Vec<Self::Account>)| Ok(accounts))
+                .map_err(|err| {
+                    error!("Error getting accounts by owner: {:?}", err);
+                    StoreError::StoreUnavailable
+                }),
+        )
+    }
+
+    fn set_rates<R>(&self, rates: R) -> Box<Future<Item = (), Error = StoreError> + Send>
+    where
+        R: IntoIterator<Item = (String, f64)>,
+    {
+        let rates: Vec<(String, f64)> = rates.into_iter().collect();
+        let exchange_rates = self.exchange_rates.clone();
+        let mut pipe = redis::pipe();
+        pipe.atomic()
+            .cmd("DEL")
+            .arg(RATES_KEY)
+            .ignore()
+            .cmd("HMSET")
+            .arg(RATES_KEY)
+            .arg(rates)
+            .ignore();
+        Box::new(
+            pipe.query_async(self.connection.as_ref().clone())
+                .map_err(|err| {
+                    error!("Error setting rates: {:?}", err);
+                    StoreError::StoreUnavailable
+                })
+                .and_then(move |(connection, _): (SharedConnection, Value)| {
+                    rates_changed(connection, exchange_rates)
+                        .map_err(|_| StoreError::StoreUnavailable)
+                }),
+        )
+    }
+
+    // TODO fix inconsistency betwen this method and set_routes which
+    // takes the prefixes as Bytes and the account as an Account object
+    fn set_static_routes<R>(&self, routes: R) -> Box<Future<Item = (), Error = StoreError> + Send>
+    where
+        R: IntoIterator<Item = (String, u64)>,
+    {
+        let routes: Vec<(String, u64)> = routes.into_iter().collect();
+        let accounts: HashSet<u64> =
+            HashSet::from_iter(routes.iter().map(|(_prefix, account_id)| *account_id));
+        let mut pipe = redis::pipe();
+        for account_id in accounts {
+            pipe.cmd("EXISTS").arg(account_details_key(account_id));
+        }
+
+        let routing_table = self.routes.clone();
+        Box::new(pipe.query_async(self.connection.as_ref().clone())
+            .map_err(|err| {
+                error!("Error checking if accounts exist while setting static routes: {:?}", err);
+                StoreError::StoreUnavailable
+            })
+            .and_then(|(connection, accounts_exist): (SharedConnection, Vec<bool>)| {
+                if accounts_exist.iter().all(|a| *a) {
+                    Ok(connection)
+                } else {
+                    error!("Error setting static routes because not all of the given accounts exist");
+                    Err(StoreError::NotFound)
+                }
+            })
+            .and_then(move |connection| {
+        let mut pipe = redis::pipe();
+        pipe.atomic()
+            .cmd("DEL")
+            .arg(STATIC_ROUTES_KEY)
+            .ignore()
+            .cmd("HMSET")
+            .arg(STATIC_ROUTES_KEY)
+            .arg(routes)
+            .ignore();
+            pipe.query_async(connection)
+                .map_err(|err| {
+                    error!("Error setting static routes: {:?}", err);
+                    StoreError::StoreUnavailable
+                })
+                .and_then(move |(connection, _): (SharedConnection, Value)| {
+                    routes_changed(connection, routing_table, RouteSource::Api)
+                        .map_err(|_| StoreError::StoreUnavailable)
+                })
+            }))
+    }
+
+    fn set_static_route(
+        &self,
+        prefix: String,
+        account_id: u64,
+    ) -> Box<Future<Item = (), Error = StoreError> + Send> {
+        let routing_table = self.routes.clone();
+        let prefix_clone = prefix.clone();
+        Box::new(
+        cmd("EXISTS")
+            .arg(account_details_key(account_id))
+            .query_async(self.connection.as_ref().clone())
+            .map_err(|err| {
+                error!("Error checking if account exists before setting static route: {:?}", err);
+                StoreError::StoreUnavailable
+            })
+            .and_then(move |(connection, exists): (SharedConnection, bool)| {
+                if exists {
+                    Ok(connection)
+                } else {
+                    error!("Cannot set static route for prefix: {} because account {} does not exist", prefix_clone, account_id);
+                    Err(StoreError::NotFound)
+                }
+            })
+            .and_then(move |connection| {
+                let mut pipe = redis::pipe();
+                pipe.atomic()
+                    .cmd("HSET")
+                    .arg(STATIC_ROUTES_KEY)
+                    .arg(prefix)
+                    .arg(account_id)
+                    .ignore();
+                pipe.query_async(connection)
+                    .map_err(|err| {
+                        error!("Error setting static route: {:?}", err);
+                        StoreError::StoreUnavailable
+                    })
+                    .and_then(move |(connection, _): (SharedConnection, Value)| {
+                        routes_changed(connection, routing_table, RouteSource::Api)
+                            .map_err(|_| StoreError::StoreUnavailable)
+                    })
+            })
+        )
+    }
+
+    fn add_static_routes(
+        &self,
+        routes: Vec<(String, u64)>,
+    ) -> Box<Future<Item = (), Error = BatchError> + Send> {
+        if routes.is_empty() {
+            return Box::new(ok(()));
+        }
+        let routing_table = self.routes.clone();
+        let mut pipe = redis::pipe();
+        pipe.atomic()
+            .cmd("EVAL")
+            .arg(ADD_STATIC_ROUTES)
+            .arg(1)
+            .arg(STATIC_ROUTES_KEY)
+            .arg(routes);
+        Box::new(
+            pipe.query_async(self.connection.as_ref().clone())
+                .map_err(|err| {
+                    error!("Error adding static routes: {:?}", err);
+                    BatchError::Store(StoreError::StoreUnavailable)
+                })
+                .and_then(
+                    |(connection, (missing,)): (SharedConnection, (Vec<usize>,))| {
+                        if missing.is_empty() {
+                            Ok(connection)
+                        } else {
+                            warn!("Cannot add static routes because not all of the given accounts exist");
+                            Err(BatchError::Entries(
+                                missing
+                                    .into_iter()
+                                    .map(|index| (index, StoreError::NotFound))
+                                    .collect(),
+                            ))
+                        }
+                    },
+                )
+                .and_then(move |connection| {
+                    routes_changed(connection, routing_table, RouteSource::Api)
+                        .map_err(|_| BatchError::Store(StoreError::StoreUnavailable))
+                }),
+        )
+    }
+
+    fn get_route_history(
+        &self,
+    ) -> Box<Future<Item = Vec<RouteVersion>, Error = StoreError> + Send> {
+        Box::new(
+            cmd("LRANGE")
+                .arg(ROUTE_HISTORY_KEY)
+                .arg(0)
+                .arg(-1)
+                .query_async(self.connection.as_ref().clone())
+                .map_err(|err| {
+                    error!("Error getting route history: {:?}", err);
+                    StoreError::StoreUnavailable
+                })
+                .and_then(|(_connection, versions): (_, Vec<String>)| {
+                    Ok(versions
+                        .iter()
+                        .filter_map(|version| match serde_json::from_str(version) {
+                            Ok(version) => Some(version),
+                            Err(err) => {
+                                warn!("Ignoring invalid route version: {:?}", err);
+                                None
+                            }
+                        })
+                        .collect())
+                }),
+        )
+    }
+
+    fn rollback_routes(
+        &self,
+        version: u64,
+    ) -> Box<Future<Item = RouteVersion, Error = StoreError> + Send> {
+        let routing_table = self.routes.clone();
+        Box::new(
+            record_routes(
+                self.connection.as_ref().clone(),
+                RouteSource::Rollback,
+                Some(version),
+            )
+            .map_err(move |err| {
+                error!(
+                    "Error rolling back routes to version {}: {:?}",
+                    version, err
+                );
+                StoreError::StoreUnavailable
+            })
+            .and_then(|(connection, entry)| {
+                let entry = entry.ok_or(StoreError::NotFound)?;
+                let entry = serde_json::from_str(&entry).map_err(|err| {
+                    error!("Recorded an invalid route version: {:?}", err);
+                    StoreError::StoreUnavailable
+                })?;
+                Ok((connection, entry))
+            })
+            .and_then(move |(connection, entry): (_, RouteVersion)| {
+                debug!("Rolled back routes, now at version {}", entry.version);
+                // The rollback was already recorded
+                reload_routes(connection, routing_table)
+                    .map_err(|_| StoreError::StoreUnavailable)
+                    .and_then(move |_| Ok(entry))
+            }),
+        )
+    }
+
+    fn set_fee_policies<R>(&self, policies: R) -> Box<Future<Item = (), Error = StoreError> + Send>
+    where
+        R: IntoIterator<Item = (String, FeePolicy)>,
+    {
+        let policies: Vec<(String, String)> = policies
+            .into_iter()
+            .map(|(key, policy)| (key, policy.to_string()))
+            .collect();
+        let fee_policies = self.fee_policies.clone();
+        let mut pipe = redis::pipe();
+        pipe.atomic().cmd("DEL").arg(FEES_KEY).ignore();
+        if !policies.is_empty() {
+            pipe.cmd("HMSET").arg(FEES_KEY).arg(policies).ignore();
+        }
+        Box::new(
+            pipe.query_async(self.connection.as_ref().clone())
+                .map_err(|err| {
+                    error!("Error setting fee policies: {:?}", err);
+                    StoreError::StoreUnavailable
+                })
+                .and_then(move |(connection, _): (SharedConnection, Value)| {
+                    update_fees(connection, fee_policies).map_err(|_| StoreError::StoreUnavailable)
+                }),
+        )
+    }
+
+    fn get_maintenance_windows(
+        &self,
+    ) -> Box<Future<Item = Vec<(u64, Vec<MaintenanceWindow>)>, Error = StoreError> + Send> {
+        Box::new(
+            cmd("HGETALL")
+                .arg(MAINTENANCE_KEY)
+                .query_async(self.connection.as_ref().clone())
+                .map_err(|err| {
+                    error!("Error getting maintenance windows: {:?}", err);
+                    StoreError::StoreUnavailable
+                })
+                .and_then(|(_connection, entries): (_, Vec<(u64, String)>)| {
+                    Ok(parse_maintenance_windows(entries).into_iter().collect())
+                }),
+        )
+    }
+
+    fn set_maintenance_windows(
+        &self,
+        account_id: u64,
+        windows: Vec<MaintenanceWindow>,
+    ) -> Box<Future<Item = (), Error = StoreError> + Send> {
+        let maintenance_windows = self.maintenance_windows.clone();
+        let mut pipe = redis::pipe();
+        if windows.is_empty() {
+            pipe.cmd("HDEL")
+                .arg(MAINTENANCE_KEY)
+                .arg(account_id)
+                .ignore();
+        } else {
+            let json = serde_json::to_string(&windows).unwrap();
+            pipe.cmd("HSET")
+                .arg(MAINTENANCE_KEY)
+                .arg(account_id)
+                .arg(json)
+                .ignore();
+        }
+        Box::new(
+            pipe.query_async(self.connection.as_ref().clone())
+                .map_err(|err| {
+                    error!("Error setting maintenance windows: {:?}", err);
+                    StoreError::StoreUnavailable
+                })
+                .and_then(move |(connection, _): (SharedConnection, Value)| {
+                    update_maintenance_windows(connection, maintenance_windows)
+                        .map_err(|_| StoreError::StoreUnavailable)
+                }),
+        )
+    }
+
+    fn get_claims(
+        &self,
+        account_id: u64,
+    ) -> Box<Future<Item = Vec<SettlementClaim>, Error = StoreError> + Send> {
+        Box::new(
+            cmd("LRANGE")
+                .arg(claims_key(account_id))
+                .arg(0)
+                .arg(-1)
+                .query_async(self.connection.as_ref().clone())
+                .map_err(move |err| {
+                    error!(
+                        "Error getting settlement claims for account {}: {:?}",
+                        account_id, err
+                    );
+                    StoreError::StoreUnavailable
+                })
+                .and_then(move |(_connection, claims): (_, Vec<String>)| {
+                    Ok(claims
+                        .iter()
+                        .filter_map(|claim| match serde_json::from_str(claim) {
+                            Ok(claim) => Some(claim),
+                            Err(err) => {
+                                warn!(
+                                    "Ignoring invalid settlement claim for account {}: {:?}",
+                                    account_id, err
+                                );
+                                None
+                            }
+                        })
+                        .collect())
+                }),
+        )
+    }
+
+    fn get_store_usage(&self) -> Box<Future<Item = StoreUsage, Error = StoreError> + Send> {
+        let connection = self.connection.as_ref().clone();
+        let counts = cmd("EVAL")
+            .arg(STORE_USAGE)
+            .arg(0)
+            .query_async(connection.clone())
+            .and_then(|(connection, counts): (_, StoreCounts)| {
+                // The data kept per account is counted a batch of accounts at a time
+                fold_account_batches(
+                    connection,
+                    AccountCounts::default(),
+                    |connection, ids, total| {
+                        cmd("EVAL")
+                            .arg(ACCOUNT_STORE_USAGE)
+                            .arg(0)
+                            .arg(ids)
+                            .query_async(connection)
+                            .map(
+                                move |(connection, counts): (SharedConnection, AccountCounts)| {
+                                    (connection, add_account_counts(total, counts))
+                                },
+                            )
+                    },
+                )
+                .map(move |(_connection, account_counts)| (counts, account_counts))
+            })
+            .map_err(|err| {
+                error!("Error counting the data in the store: {:?}", err);
+                StoreError::StoreUnavailable
+            });
+        let used_memory = cmd("INFO")
+            .arg("memory")
+            .query_async(connection.clone())
+            .then(|result: Result<(_, String), _>| match result {
+                Ok((_connection, info)) => Ok(parse_used_memory(&info)),
+                Err(err) => {
+                    warn!("Unable to get the memory used by Redis: {:?}", err);
+                    Ok(None)
+                }
+            });
+        Box::new(counts.join3(used_memory, sample_keys(connection)).map(
+            |((counts, account_counts), used_memory, sample)| {
+                let (keys, accounts, routes, static_routes, in_flight, address_aliases) = counts;
+                let (balances, balance_snapshots, payments, settlement_claims) = account_counts;
+                StoreUsage {
+                    keys,
+                    used_memory,
+                    accounts,
+                    balances,
+                    routes,
+                    static_routes,
+                    balance_snapshots,
+                    payments,
+                    settlement_claims,
+                    in_flight,
+                    address_aliases,
+                    key_groups: estimate_key_groups(keys, sample),
+                }
+            },
+        ))
+    }
+}
+
+// The values returned by the STORE_USAGE script, in order
+type StoreCounts = (u64, u64, u64, u64, u64, u64);
+// The values returned by the ACCOUNT_STORE_USAGE script, in order
+type AccountCounts = (u64, u64, u64, u64);
+
+fn add_account_counts(a: AccountCounts, b: AccountCounts) -> AccountCounts {
+    (a.0 + b.0, a.1 + b.1, a.2 + b.2, a.3 + b.3)
+}
+
+fn parse_used_memory(info: &str) -> Option<u64> {
+    info.lines()
+        .find(|line| line.starts_with("used_memory:"))
+        .and_then(|line| u64::from_str(line["used_memory:".len()..].trim()).ok())
+}
+
+// Get random keys and the memory each one uses. MEMORY USAGE was added in Redis 4.0,
+// so the memory is left out if it is not supported
+fn sample_keys(
+    connection: SharedConnection,
+) -> impl Future<Item = Vec<(String, Option<u64>)>, Error = StoreError> {
+    let mut pipe = redis::pipe();
+    for _ in 0..STORE_USAGE_SAMPLES {
+        pipe.cmd("RANDOMKEY");
+    }
+    pipe.query_async(connection)
+        .map_err(|err| {
+            error!("Error sampling keys: {:?}", err);
+            StoreError::StoreUnavailable
+        })
+        .and_then(|(connection, keys): (_, Vec<Option<String>>)| {
+            // RANDOMKEY returns nil if the database is empty
+            let keys: Vec<String> = keys.into_iter().filter_map(|key| key).collect();
+            if keys.is_empty() {
+                return Either::A(ok(Vec::new()));
+            }
+            let mut pipe = redis::pipe();
+            for key in keys.iter() {
+                pipe.cmd("MEMORY").arg("USAGE").arg(key.as_str());
+            }
+            Either::B(pipe.query_async(connection).then(
+                move |result: Result<(_, Vec<Option<u64>>), _>| {
+                    let memory = match result {
+                        Ok((_connection, memory)) => memory,
+                        Err(err) => {
+                            warn!("Unable to get the memory used by each key: {:?}", err);
+                            vec![None; keys.len()]
+                        }
+                    };
+                    Ok(keys.into_iter().zip(memory.into_iter()).collect())
+                },
+            ))
+        })
+}
+
+// Extrapolate the number of keys in each group (the part of the key before the first colon)
+// and the memory they use from a sample of the keys
+fn estimate_key_groups(total_keys: u64, sample: Vec<(String, Option<u64>)>) -> Vec<KeyGroupUsage> {
+    let sampled = sample.len() as u64;
+    let mut groups: HashMap<String, (u64, Option<u64>)> = HashMap::new();
+    for (key, memory) in sample {
+        let name = key.split(':').next().unwrap_or("").to_string();
+        let group = groups.entry(name).or_insert((0, Some(0)));
+        group.0 += 1;
+        group.1 = match (group.1, memory) {
+            (Some(total), Some(memory)) => Some(total + memory),
+            _ => None,
+        };
+    }
+    let mut groups: Vec<KeyGroupUsage> = groups
+        .into_iter()
+        .map(|(name, (keys, memory))| KeyGroupUsage {
+            name,
+            keys: keys * total_keys / sampled,
+            memory: memory.map(|memory| memory * total_keys / sampled),
+        })
+        .collect();
+    groups.sort_by(|a, b| b.keys.cmp(&a.keys).then_with(|| a.name.cmp(&b.name)));
+    groups
+}
+
+impl FeeStore for RedisStore {
+    fn get_fee_policy(&self, from_account: &Account, destination: &[u8]) -> Option<FeePolicy> {
+        let fee_policies = self.fee_policies.read();
+        if let Some(policy) = fee_policies.accounts.get(&from_account.id) {
+            return Some(*policy);
+        }
+        // Use the policy for the longest matching prefix
+        fee_policies
+            .prefixes
+            .iter()
+            .filter(|(prefix, _)| is_within_prefix(destination, &prefix[..]))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(_, policy)| *policy)
+    }
+}
+
+impl MaintenanceStore for RedisStore {
+    fn get_maintenance_window(&self, account: &Account, time: u64) -> Option<MaintenanceWindow> {
+        self.maintenance_windows
+            .read()
+            .get(&account.id)
+            .and_then(|windows| windows.iter().find(|window| window.contains(time)).cloned())
+    }
+}
+
+impl SettlementInfoStore for RedisStore {
+    type Account = Account;
+
+    fn set_peer_settlement_info(
+        &self,
+        account_id: u64,
+        info: SettlementInfo,
+    ) -> Box<Future<Item = (), Error = ()> + Send> {
+        Box::new(
+            cmd("SET")
+                .arg(settlement_info_key(account_id))
+                .arg(serde_json::to_string(&info).unwrap())
+                .query_async(self.connection.as_ref().clone())
+                .map_err(move |err| {
+                    error!(
+                        "Error saving settlement info of account {}: {:?}",
+                        account_id, err
+                    )
+                })
+                .map(|(_connection, _): (_, Value)| ()),
+        )
+    }
+
+    fn get_peer_settlement_info(
+        &self,
+        account_id: u64,
+    ) -> Box<Future<Item = Option<SettlementInfo>, Error = ()> + Send> {
+        Box::new(
+            cmd("GET")
+                .arg(settlement_info_key(account_id))
+                .query_async(self.connection.as_ref().clone())
+                .map_err(move |err| {
+                    error!(
+                        "Error getting settlement info of account {}: {:?}",
+                        account_id, err
+                    )
+                })
+                .and_then(move |(_connection, info): (_, Option<String>)| {
+                    Ok(info.and_then(|info| match serde_json::from_str(&info) {
+                        Ok(info) => Some(info),
+                        Err(err) => {
+                            warn!(
+                                "Ignoring invalid settlement info of account {}: {:?}",
+                                account_id, err
+                            );
+                            None
+                        }
+                    }))
+                }),
+        )
+    }
+}
+
+impl LockStore for RedisStore {
+    fn acquire_lock(
+        &self,
+        name: &str,
+        ttl: Duration,
+    ) -> Box<Future<Item = Option<Lock>, Error = ()> + Send> {
+        Box::new(acquire_lock(self.connection.as_ref().clone(), name, ttl))
+    }
+
+    fn extend_lock(
+        &self,
+        lock: &Lock,
+        ttl: Duration,
+    ) -> Box<Future<Item = bool, Error = ()> + Send> {
+        let name = lock.name.clone();
+        Box::new(
+            cmd("EVAL")
+                .arg(EXTEND_LOCK)
+                .arg(1)
+                .arg(lock_key(&lock.name))
+                .arg(lock.token)
+                .arg(ttl.as_millis() as u64)
+                .query_async(self.connection.as_ref().clone())
+                .map_err(move |err| error!("Error extending lock {}: {:?}", name, err))
+                .map(|(_connection, held): (_, bool)| held),
+        )
+    }
+
+    fn release_lock(&self, lock: Lock) -> Box<Future<Item = (), Error = ()> + Send> {
+        Box::new(release_lock(self.connection.as_ref().clone(), lock))
+    }
+}
+
+impl StatsStore for RedisStore {
+    fn record_forwarded_packet(
+        &self,
+        from_account: &Account,
+        incoming_amount: u64,
+        to_account: &Account,
+        outgoing_amount: u64,
+        fee: u64,
+    ) {
+        let date = Utc::today().naive_utc();
+        let mut pending_stats = self.pending_stats.lock();
+        pending_stats.add(date, stats_field(from_account, "incoming"), incoming_amount);
+        pending_stats.add(date, stats_field(to_account, "outgoing"), outgoing_amount);
+        if fee > 0 {
+            pending_stats.add(date, stats_field(from_account, "fees"), fee);
+        }
+    }
+
+    fn get_daily_stats(
+        &self,
+        days: usize,
+    ) -> Box<Future<Item = Vec<DailyStats>, Error = ()> + Send> {
+        let today = Utc::today().naive_utc();
+        let dates: Vec<NaiveDate> = (0..days.min(STATS_RETENTION_DAYS as usize))
+            .map(|days_ago| today - ChronoDuration::days(days_ago as i64))
+            .collect();
+        let mut pipe = redis::pipe();
+        for date in dates.iter() {
+            pipe.cmd("HGETALL").arg(daily_stats_key(date));
+        }
+        let connection = self.connection.as_ref().clone();
+        Box::new(
+            write_stats(connection.clone(), self.pending_stats.clone())
+                .and_then(move |_| {
+                    pipe.query_async(connection)
+                        .map_err(|err| error!("Error getting daily stats: {:?}", err))
+                })
+                .and_then(
+                    move |(_connection, buckets): (_, Vec<Vec<(String, u64)>>)| {
+                        Ok(dates
+                            .into_iter()
+                            .zip(buckets.into_iter())
+                            .map(|(date, bucket)| DailyStats {
+                                date: date.format("%Y-%m-%d").to_string(),
+                                accounts: parse_stats(bucket),
+                            })
+                            .collect())
+                    },
+                ),
+        )
+    }
+
+    fn get_total_stats(&self) -> Box<Future<Item = Vec<AccountStats>, Error = ()> + Send> {
+        let connection = self.connection.as_ref().clone();
+        Box::new(
+            write_stats(connection.clone(), self.pending_stats.clone())
+                .and_then(|_| {
+                    cmd("HGETALL")
+                        .arg(TOTAL_STATS_KEY)
+                        .query_async(connection)
+                        .map_err(|err| error!("Error getting total stats: {:?}", err))
+                })
+                .and_then(|(_connection, bucket): (_, Vec<(String, u64)>)| Ok(parse_stats(bucket))),
+        )
+    }
+
+    fn record_destination_packet(
+        &self,
+        destination: &[u8],
+        to_account: &Account,
+        amount: u64,
+        fulfilled: bool,
+    ) {
+        let destination = String::from_utf8_lossy(destination);
+        let prefix: Vec<&str> = destination
+            .splitn(PREFIX_STATS_SEGMENTS + 1, '.')
+            .take(PREFIX_STATS_SEGMENTS)
+            .collect();
+        let mut pending_stats = self.pending_stats.lock();
+        let stats = pending_stats
+            .prefixes
+            .entry(prefix.join("."))
+            .or_insert_with(PendingPrefixStats::default);
+        stats.packets += 1;
+        if fulfilled {
+            stats.fulfilled += 1;
+            let total = stats
+                .amounts
+                .entry(to_account.asset_code.clone())
+                .or_insert(0);
+            *total = total.saturating_add(amount);
+        }
+        stats.destinations.insert(destination.into_owned());
+    }
+
+    fn get_prefix_stats(
+        &self,
+        limit: usize,
+    ) -> Box<Future<Item = Vec<PrefixStats>, Error = ()> + Send> {
+        if limit == 0 {
+            return Box::new(ok(Vec::new()));
+        }
+        let connection = self.connection.as_ref().clone();
+        Box::new(
+            write_stats(connection.clone(), self.pending_stats.clone())
+                .and_then(move |_| {
+                    cmd("ZREVRANGE")
+                        .arg(PREFIX_PACKETS_KEY)
+                        .arg(0)
+                        .arg(limit - 1)
+                        .arg("WITHSCORES")
+                        .query_async(connection)
+                        .map_err(|err| error!("Error getting the busiest prefixes: {:?}", err))
+                })
+                .and_then(|(connection, prefixes): (_, Vec<(String, f64)>)| {
+                    if prefixes.is_empty() {
+                        return Either::A(ok(Vec::new()));
+                    }
+                    let mut pipe = redis::pipe();
+                    for (prefix, _) in prefixes.iter() {
+                        pipe.cmd("ZSCORE")
+                            .arg(PREFIX_FULFILLED_KEY)
+                            .arg(prefix.as_str())
+                            .cmd("HGETALL")
+                            .arg(prefix_amounts_key(prefix))
+                            .cmd("PFCOUNT")
+                            .arg(prefix_destinations_key(prefix));
+                    }
+                    Either::B(
+                        pipe.query_async(connection)
+                            .map_err(|err| error!("Error getting prefix stats: {:?}", err))
+                            .and_then(
+                                move |(_connection, details): (
+                                    _,
+                                    Vec<(Option<f64>, Vec<(String, u64)>, u64)>,
+                                )| {
+                                    Ok(prefixes
+                                        .into_iter()
+                                        .zip(details.into_iter())
+                                        .map(
+                                            |(
+                                                (prefix, packets),
+                                                (fulfilled, amounts, unique_destinations),
+                                            )| {
+                                                PrefixStats {
+                                                    prefix,
+                                                    packets: packets as u64,
+                                                    fulfilled: fulfilled.unwrap_or(0.0) as u64,
+                                                    amounts: amounts.into_iter().collect(),
+                                                    unique_destinations,
+                                                }
+                                            },
+                                        )
+                                        .collect())
+                                },
+                            ),
+                    )
+                }),
+        )
+    }
+}
+
+// Stats are stored in hashes where the fields are "{asset_code}:{account_id}:{incoming|outgoing|fees}"
+fn stats_field(account: &Account, kind: &str) -> String {
+    format!("{}:{}:{}", account.asset_code, account.id, kind)
+}
+
+fn parse_stats(bucket: Vec<(String, u64)>) -> Vec<AccountStats> {
+    let mut accounts: HashMap<(String, String), AccountStats> = HashMap::new();
+    for (field, amount) in bucket {
+        let parts: Vec<&str> = field.splitn(3, ':').collect();
+        if parts.len() != 3 {
+            warn!("Ignoring invalid stats field: {}", field);
+            continue;
+        }
+        let stats = accounts
+            .entry((parts[0].to_string(), parts[1].to_string()))
+            .or_insert_with(|| AccountStats {
+                account_id: parts[1].to_string(),
+                asset_code: parts[0].to_string(),
+                ..Default::default()
+            });
+        match parts[2] {
+            "incoming" => stats.incoming = amount,
+            "outgoing" => stats.outgoing = amount,
+            "fees" => stats.fees = amount,
+            _ => warn!("Ignoring invalid stats field: {}", field),
+        }
+    }
+    let mut accounts: Vec<AccountStats> = accounts.into_iter().map(|(_, stats)| stats).collect();
+    // Account IDs are numbers, so "10" comes after "9"
+    accounts.sort_by_key(|stats| {
+        (
+            stats.account_id.parse::<u64>().ok(),
+            stats.asset_code.clone(),
+        )
+    });
+    accounts
+}
+
+/// Stats of the packets forwarded since they were last written.
+#[derive(Default)]
+struct PendingStats {
+    /// Amounts by day and stats field
+    amounts: HashMap<(NaiveDate, String), u64>,
+    prefixes: HashMap<String, PendingPrefixStats>,
+}
+
+#[derive(Default)]
+struct PendingPrefixStats {
+    packets: u64,
+    fulfilled: u64,
+    /// Amounts of the fulfilled packets by asset code
+    amounts: HashMap<String, u64>,
+    destinations: HashSet<String>,
+}
+
+impl PendingStats {
+    fn add(&mut self, date: NaiveDate, field: String, amount: u64) {
+        let total = self.amounts.entry((date, field)).or_insert(0);
+        *total = total.saturating_add(amount);
+    }
+
+    fn merge(&mut self, other: PendingStats) {
+        for ((date, field), amount) in other.amounts {
+            self.add(date, field, amount);
+        }
+        for (prefix, other) in other.prefixes {
+            let stats = self
+                .prefixes
+                .entry(prefix)
+                .or_insert_with(PendingPrefixStats::default);
+            stats.packets += other.packets;
+            stats.fulfilled += other.fulfilled;
+            for (asset_code, amount) in other.amounts {
+                let total = stats.amounts.entry(asset_code).or_insert(0);
+                *total = total.saturating_add(amount);
+            }
+            stats.destinations.extend(other.destinations);
+        }
+    }
+}
+
+// Redis stores amounts as i64
+fn capped_amount(amount: u64) -> u64 {
+    amount.min(i64::max_value() as u64)
+}
+
+/// Write the stats of the packets forwarded since they were last written in one script.
+/// If that fails, they are put back so they are written with the next batch.
+fn write_stats(
+    connection: SharedConnection,
+    pending_stats: Arc<Mutex<PendingStats>>,
+) -> impl Future<Item = (), Error = ()> {
+    let stats = mem::replace(&mut *pending_stats.lock(), PendingStats::default());
+    if stats.amounts.is_empty() && stats.prefixes.is_empty() {
+        return Either::A(ok(()));
+    }
+    let mut script = cmd("EVAL");
+    script
+        .arg(RECORD_STATS)
+        .arg(3)
+        .arg(TOTAL_STATS_KEY)
+        .arg(PREFIX_PACKETS_KEY)
+        .arg(PREFIX_FULFILLED_KEY)
+        .arg(STATS_RETENTION_DAYS * 24 * 60 * 60)
+        .arg(MAX_TRACKED_PREFIXES)
+        .arg(stats.amounts.len());
+    for ((date, field), amount) in stats.amounts.iter() {
+        script
+            .arg(daily_stats_key(date))
+            .arg(field.as_str())
+            .arg(capped_amount(*amount));
+    }
+    script.arg(stats.prefixes.len());
+    for (prefix, prefix_stats) in stats.prefixes.iter() {
+        script
+            .arg(prefix.as_str())
+            .arg(prefix_stats.packets)
+            .arg(prefix_stats.fulfilled)
+            .arg(prefix_stats.amounts.len());
+        for (asset_code, amount) in prefix_stats.amounts.iter() {
+            script.arg(asset_code.as_str()).arg(capped_amount(*amount));
+        }
+        script.arg(prefix_stats.destinations.len());
+        for destination in prefix_stats.destinations.iter() {
+            script.arg(destination.as_str());
+        }
+    }
+    Either::B(script.query_async(connection).then(
+        move |result: Result<(SharedConnection, Value), _>| {
+            if let Err(err) = result {
+                error!("Error writing stats for forwarded packets: {:?}", err);
+                pending_stats.lock().merge(stats);
+            }
+            Ok(())
+        },
+    ))
+}
+
+impl BalanceHistoryStore for RedisStore {
+    fn snapshot_balances(&self) -> Box<Future<Item = (), Error = StoreError> + Send> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        Box::new(
+            for_each_account_batch(self.connection.as_ref().clone(), move |ids| {
+                let mut script = cmd("EVAL");
+                script
+                    .arg(SNAPSHOT_BALANCES)
+                    .arg(0)
+                    .arg(now)
+                    .arg(now.saturating_sub(BALANCE_HISTORY_RETENTION))
+                    .arg(ids);
+                script
+            })
+            .map_err(|err| {
+                error!("Error recording balance snapshots: {:?}", err);
+                StoreError::StoreUnavailable
+            })
+            .and_then(|num_accounts| {
+                trace!("Recorded balance snapshots for {} accounts", num_accounts);
+                Ok(())
+            }),
+        )
+    }
+
+    fn get_balance_history(
+        &self,
+        account_id: u64,
+        from: u64,
+        to: u64,
+    ) -> Box<Future<Item = Vec<BalanceSnapshot>, Error = StoreError> + Send> {
+        Box::new(
+            cmd("ZRANGEBYSCORE")
+                .arg(balance_history_key(account_id))
+                .arg(from)
+                .arg(to)
+                .query_async(self.connection.as_ref().clone())
+                .map_err(move |err| {
+                    error!(
+                        "Error getting balance history for account {}: {:?}",
+                        account_id, err
+                    );
+                    StoreError::StoreUnavailable
+                })
+                .and_then(move |(_connection, snapshots): (_, Vec<String>)| {
+                    Ok(snapshots
+                        .into_iter()
+                        .filter_map(|snapshot| {
+                            let mut parts = snapshot.splitn(2, ':');
+                            let timestamp = parts.next().and_then(|t| u64::from_str(t).ok());
+                            let balance = parts.next().and_then(|b| i64::from_str(b).ok());
+                            if let (Some(timestamp), Some(balance)) = (timestamp, balance) {
+                                Some(BalanceSnapshot { timestamp, balance })
+                            } else {
+                                warn!(
+                                    "Ignoring invalid balance snapshot for account {}: {}",
+                                    account_id, snapshot
+                                );
+                                None
+                            }
+                        })
+                        .collect())
+                }),
+        )
+    }
+}
+
+impl IdempotencyStore for RedisStore {
+    fn claim_idempotency_key(
+        &self,
+        key: String,
+        ttl: u64,
+    ) -> Box<Future<Item = bool, Error = ()> + Send> {
+        // SET NX returns OK if the key was set and nil if it already existed
+        Box::new(
+            cmd("SET")
+                .arg(idempotency_key(key.as_str()))
+                .arg(1)
+                .arg("NX")
+                .arg("EX")
+                .arg(ttl)
+                .query_async(self.connection.as_ref().clone())
+                .map_err(|err| error!("Error claiming idempotency key: {:?}", err))
+                .and_then(|(_connection, result): (_, Option<String>)| Ok(result.is_some())),
+        )
+    }
+
+    fn release_idempotency_key(&self, key: String) -> Box<Future<Item = (), Error = ()> + Send> {
+        Box::new(
+            cmd("DEL")
+                .arg(idempotency_key(key.as_str()))
+                .query_async(self.connection.as_ref().clone())
+                .map_err(|err| error!("Error releasing idempotency key: {:?}", err))
+                .and_then(|(_connection, _): (_, Value)| Ok(())),
+        )
+    }
+}
+
+// API keys are saved with the hash of their token. The token is only returned when they are created
+#[derive(Serialize, Deserialize)]
+struct StoredApiKey {
+    #[serde(flatten)]
+    api_key: ApiKey,
+    token_hash: String,
+}
+
+fn parse_api_key(api_key: &str) -> Option<StoredApiKey> {
+    match serde_json::from_str(api_key) {
+        Ok(api_key) => Some(api_key),
+        Err(err) => {
+            warn!("Ignoring invalid API key: {:?}", err);
+            None
+        }
+    }
+}
+
+impl ApiKeyStore for RedisStore {
+    fn create_api_key(
+        &self,
+        name: String,
+        account_id: String,
+        scopes: Vec<ApiScope>,
+    ) -> Box<Future<Item = (ApiKey, String), Error = StoreError> + Send> {
+        let connection = self.connection.as_ref().clone();
+        let token = format!(
+            "{}{:016x}{:016x}",
+            API_KEY_PREFIX,
+            thread_rng().gen::<u64>(),
+            thread_rng().gen::<u64>()
+        );
+        let created_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        Box::new(
+            cmd("INCR")
+                .arg(NEXT_API_KEY_ID_KEY)
+                .query_async(self.connection.as_ref().clone())
+                .and_then(move |(_connection, id): (_, u64)| {
+                    let api_key = ApiKey {
+                        id,
+                        name,
+                        account_id,
+                        scopes,
+                        created_at,
+                    };
+                    let stored = StoredApiKey {
+                        api_key: api_key.clone(),
+                        token_hash: hash_api_key_token(&token),
+                    };
+                    let mut pipe = redis::pipe();
+                    pipe.atomic()
+                        .cmd("SET")
+                        .arg(api_key_key(id))
+                        .arg(serde_json::to_string(&stored).unwrap())
+                        .ignore()
+                        .cmd("HSET")
+                        .arg(API_KEY_TOKENS_KEY)
+                        .arg(&stored.token_hash)
+                        .arg(id)
+                        .ignore();
+                    pipe.query_async(connection)
+                        .map(move |(_connection, _): (_, Value)| (api_key, token))
+                })
+                .map_err(|err| {
+                    error!("Error creating API key: {:?}", err);
+                    StoreError::StoreUnavailable
+                }),
+        )
+    }
+
+    fn get_api_key(&self, token: &str) -> Box<Future<Item = Option<ApiKey>, Error = ()> + Send> {
+        let connection = self.read_connection();
+        Box::new(
+            cmd("HGET")
+                .arg(API_KEY_TOKENS_KEY)
+                .arg(hash_api_key_token(token))
+                .query_async(self.read_connection())
+                .and_then(move |(_connection, id): (_, Option<u64>)| {
+                    if let Some(id) = id {
+                        Either::A(
+                            cmd("GET")
+                                .arg(api_key_key(id))
+                                .query_async(connection)
+                                .map(|(_connection, api_key): (_, Option<String>)| api_key),
+                        )
+                    } else {
+                        Either::B(ok(None))
+                    }
+                })
+                .map_err(|err| error!("Error getting API key: {:?}", err))
+                .map(|api_key| {
+                    api_key
+                        .and_then(|api_key| parse_api_key(&api_key))
+                        .map(|stored| stored.api_key)
+                }),
+        )
+    }
+
+    fn get_api_keys(&self) -> Box<Future<Item = Vec<ApiKey>, Error = StoreError> + Send> {
+        let connection = self.read_connection();
+        Box::new(
+            cmd("HVALS")
+                .arg(API_KEY_TOKENS_KEY)
+                .query_async(self.read_connection())
+                .and_then(move |(_connection, mut ids): (_, Vec<u64>)| {
+                    if ids.is_empty() {
+                        return Either::A(ok(Vec::new()));
+                    }
+                    ids.sort();
+                    let keys: Vec<String> = ids.into_iter().map(api_key_key).collect();
+                    Either::B(
+                        cmd("MGET")
+                            .arg(keys)
+                            .query_async(connection)
+                            .map(|(_connection, api_keys): (_, Vec<Option<String>>)| api_keys),
+                    )
+                })
+                .map_err(|err| {
+                    error!("Error getting API keys: {:?}", err);
+                    StoreError::StoreUnavailable
+                })
+                .map(|api_keys| {
+                    api_keys
+                        .into_iter()
+                        .filter_map(|api_key| api_key)
+                        .filter_map(|api_key| parse_api_key(&api_key))
+                        .map(|stored| stored.api_key)
+                        .collect()
+                }),
+        )
+    }
+
+    fn delete_api_key(&self, id: u64) -> Box<Future<Item = ApiKey, Error = StoreError> + Send> {
+        let connection = self.connection.as_ref().clone();
+        Box::new(
+            cmd("GET")
+                .arg(api_key_key(id))
+                .query_async(self.connection.as_ref().clone())
+                .map_err(move |err| {
+                    error!("Error getting API key {}: {:?}", id, err);
+                    StoreError::StoreUnavailable
+                })
+                .and_then(|(_connection, api_key): (_, Option<String>)| {
+                    api_key
+                        .and_then(|api_key| parse_api_key(&api_key))
+                        .ok_or(StoreError::NotFound)
+                })
+                .and_then(move |stored| {
+                    let mut pipe = redis::pipe();
+                    pipe.atomic()
+                        .cmd("DEL")
+                        .arg(api_key_key(id))
+                        .ignore()
+                        .cmd("HDEL")
+                        .arg(API_KEY_TOKENS_KEY)
+                        .arg(&stored.token_hash)
+                        .ignore();
+                    pipe.query_async(connection)
+                        .map_err(move |err| {
+                            error!("Error deleting API key {}: {:?}", id, err);
+                            StoreError::StoreUnavailable
+                        })
+                        .map(move |(_connection, _): (_, Value)| stored.api_key)
+                }),
+        )
+    }
+}
+
+impl StreamConnectionStore<Account> for RedisStore {
+    fn add_amount_received(
+        &self,
+        account: &Account,
+        connection_id: &[u8],
+        connection_tag: Option<&[u8]>,
+        amount: u64,
+    ) -> Box<Future<Item = u64, Error = ()> + Send> {
+        let key = stream_connection_key(connection_id);
+        if amount == 0 {
+            return Box::new(
+                cmd("HGET")
+                    .arg(&key)
+                    .arg("received")
+                    .query_async(self.connection.as_ref().clone())
+                    .map_err(|err| error!("Error getting amount received on connection: {:?}", err))
+                    .and_then(|(_connection, total): (_, Option<u64>)| Ok(total.unwrap_or(0))),
+            );
+        }
+
+        // The connection state is only kept for a day after the last packet
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let mut script = cmd("EVAL");
+        script
+            .arg(ADD_AMOUNT_RECEIVED)
+            .arg(2)
+            .arg(&key)
+            .arg(payments_key(account.id))
+            .arg(amount)
+            .arg(now)
+            .arg(STREAM_CONNECTION_RETENTION)
+            .arg(connection_id)
+            .arg(account.id)
+            .arg(&account.asset_code)
+            .arg(account.asset_scale);
+        if let Some(connection_tag) = connection_tag {
+            script.arg(connection_tag);
+        }
+        Box::new(
+            script
+                .query_async(self.connection.as_ref().clone())
+                .map_err(|err| error!("Error updating amount received on connection: {:?}", err))
+                .and_then(|(_connection, total): (_, u64)| Ok(total)),
+        )
+    }
+
+    fn close_connection(
+        &self,
+        _account: &Account,
+        connection_id: &[u8],
+    ) -> Box<Future<Item = (), Error = ()> + Send> {
+        let key = stream_connection_key(connection_id);
+        let mut pipe = redis::pipe();
+        pipe.atomic()
+            .cmd("HSET")
+            .arg(&key)
+            .arg("completed")
+            .arg(1)
+            .ignore()
+            .cmd("EXPIRE")
+            .arg(&key)
+            .arg(STREAM_CONNECTION_RETENTION)
+            .ignore();
+        Box::new(
+            pipe.query_async(self.connection.as_ref().clone())
+                .map_err(|err| error!("Error closing STREAM connection: {:?}", err))
+                .and_then(|(_connection, _): (_, Value)| Ok(())),
+        )
+    }
+}
+
+fn parse_payment(connection_id: String, fields: Vec<(String, String)>) -> Option<IncomingPayment> {
+    let fields: HashMap<String, String> = HashMap::from_iter(fields.into_iter());
+    let get_u64 = |name: &str| fields.get(name).and_then(|value| u64::from_str(value).ok());
+    Some(IncomingPayment {
+        connection_id,
+        account_id: fields.get("account_id")?.to_string(),
+        received: get_u64("received")?,
+        asset_code: fields.get("asset_code").cloned(),
+        asset_scale: fields
+            .get("asset_scale")
+            .and_then(|value| u8::from_str(value).ok()),
+        started_at: get_u64("started_at")?,
+        updated_at: get_u64("updated_at")?,
+        completed: fields.get("completed").map(|c| c == "1").unwrap_or(false),
+        return_pointer: fields.get("return_pointer").cloned(),
+        refunded: get_u64("refunded").unwrap_or(0),
+        tag: fields.get("tag").cloned(),
+        metadata: fields.get("metadata").cloned(),
+    })
+}
+
+/// Load the payments received on the given connections, in the same order
+fn get_payments(
+    connection: SharedConnection,
+    connection_ids: Vec<String>,
+) -> impl Future<Item = Vec<IncomingPayment>, Error = RedisError> {
+    let mut pipe = redis::pipe();
+    for connection_id in connection_ids.iter() {
+        pipe.cmd("HGETALL")
+            .arg(stream_connection_key(connection_id.as_bytes()));
+    }
+    pipe.query_async(connection).and_then(
+        move |(_connection, payments): (_, Vec<Vec<(String, String)>>)| {
+            Ok(connection_ids
+                .into_iter()
+                .zip(payments.into_iter())
+                .filter_map(|(connection_id, fields)| parse_payment(connection_id, fields))
+                .collect())
+        },
+    )
+}
+
+impl PaymentStore for RedisStore {
+    fn get_incoming_payments(
+        &self,
+        account_id: u64,
+    ) -> Box<Future<Item = Vec<IncomingPayment>, Error = StoreError> + Send> {
+        Box::new(
+            cmd("ZREVRANGE")
+                .arg(payments_key(account_id))
+                .arg(0)
+                .arg(-1)
+                .query_async(self.connection.as_ref().clone())
+                .and_then(|(connection, connection_ids): (_, Vec<String>)| {
+                    get_payments(connection, connection_ids)
+                })
+                .map_err(move |err| {
+                    error!(
+                        "Error getting payments for account {}: {:?}",
+                        account_id, err
+                    );
+                    StoreError::StoreUnavailable
+                }),
+        )
+    }
+
+    fn get_incoming_payment(
+        &self,
+        connection_id: String,
+    ) -> Box<Future<Item = Option<IncomingPayment>, Error = StoreError> + Send> {
+        Box::new(
+            cmd("HGETALL")
+                .arg(stream_connection_key(connection_id.as_bytes()))
+                .query_async(self.connection.as_ref().clone())
+                .map_err(|err| {
+                    error!("Error getting payment: {:?}", err);
+                    StoreError::StoreUnavailable
+                })
+                .and_then(move |(_connection, fields): (_, Vec<(String, String)>)| {
+                    Ok(parse_payment(connection_id, fields))
+                }),
+        )
+    }
+
+    fn get_recent_payments(
+        &self,
+        limit: usize,
+    ) -> Box<Future<Item = Vec<IncomingPayment>, Error = StoreError> + Send> {
+        Box::new(
+            cmd("EVAL")
+                .arg(GET_RECENT_PAYMENTS)
+                .arg(1)
+                .arg(ACCOUNT_IDS_KEY)
+                .arg(limit)
+                .query_async(self.connection.as_ref().clone())
+                .and_then(|(connection, connection_ids): (_, Vec<String>)| {
+                    get_payments(connection, connection_ids)
+                })
+                .map_err(|err| {
+                    error!("Error getting recent payments: {:?}", err);
+                    StoreError::StoreUnavailable
+                }),
+        )
+    }
+
+    fn set_return_pointer(
+        &self,
+        connection_id: &[u8],
+        return_pointer: &str,
+    ) -> Box<Future<Item = (), Error = ()> + Send> {
+        Box::new(set_payment_field(
+            self.connection.as_ref().clone(),
+            connection_id,
+            "return_pointer",
+            return_pointer,
+        ))
+    }
+
+    fn set_metadata(
+        &self,
+        connection_id: &[u8],
+        metadata: &str,
+    ) -> Box<Future<Item = (), Error = ()> + Send> {
+        Box::new(set_payment_field(
+            self.connection.as_ref().clone(),
+            connection_id,
+            "metadata",
+            metadata,
+        ))
+    }
 }
 
-impl RedisStore {
-    fn get_next_account_id(&self) -> impl Future<Item = u64, Error = ()> {
-        cmd("INCR")
-            .arg(NEXT_ACCOUNT_ID_KEY)
-            .query_async(self.connection.as_ref().clone())
-            .map_err(|err| error!("Error incrementing account ID: {:?}", err))
-            .and_then(|(_conn, next_account_id): (_, u64)| Ok(next_account_id - 1))
-    }
+// Set a field of the payment received on the connection, which is kept as long as the rest of it
+fn set_payment_field(
+    connection: SharedConnection,
+    connection_id: &[u8],
+    field: &'static str,
+    value: &str,
+) -> impl Future<Item = (), Error = ()> {
+    let key = stream_connection_key(connection_id);
+    let mut pipe = redis::pipe();
+    pipe.atomic()
+        .cmd("HSET")
+        .arg(&key)
+        .arg(field)
+        .arg(value)
+        .ignore()
+        .cmd("EXPIRE")
+        .arg(&key)
+        .arg(STREAM_CONNECTION_RETENTION)
+        .ignore();
+    pipe.query_async(connection)
+        .map_err(move |err| error!("Error saving {} of payment: {:?}", field, err))
+        .and_then(|(_connection, _): (_, Value)| Ok(()))
 }
 
-impl AccountStore for RedisStore {
-    type Account = Account;
+fn parse_refunds(refunds: Vec<Option<String>>) -> Vec<Refund> {
+    refunds
+        .into_iter()
+        .filter_map(|refund| refund)
+        .filter_map(|refund| match serde_json::from_str(&refund) {
+            Ok(refund) => Some(refund),
+            Err(err) => {
+                warn!("Ignoring invalid refund: {:?}", err);
+                None
+            }
+        })
+        .collect()
+}
 
-    // TODO cache results to avoid hitting Redis for each packet
-    fn get_accounts(
+// Save the refund. Refunds are kept as long as the payment they refund
+fn save_refund(pipe: &mut redis::Pipeline, refund: &Refund) {
+    let refunds_key = payment_refunds_key(&refund.connection_id);
+    pipe.cmd("SET")
+        .arg(refund_key(refund.id))
+        .arg(serde_json::to_string(refund).unwrap())
+        .arg("EX")
+        .arg(STREAM_CONNECTION_RETENTION)
+        .ignore()
+        .cmd("SADD")
+        .arg(&refunds_key)
+        .arg(refund.id)
+        .ignore()
+        .cmd("EXPIRE")
+        .arg(&refunds_key)
+        .arg(STREAM_CONNECTION_RETENTION)
+        .ignore();
+}
+
+impl RefundStore for RedisStore {
+    fn add_refund(
         &self,
-        account_ids: Vec<<Self::Account as AccountTrait>::AccountId>,
-    ) -> Box<Future<Item = Vec<Account>, Error = ()> + Send> {
-        let num_accounts = account_ids.len();
+        connection_id: &str,
+        account_id: &str,
+        amount: Option<u64>,
+        created_at: u64,
+    ) -> Box<Future<Item = Refund, Error = StoreError> + Send> {
+        let connection = self.connection.as_ref().clone();
+        let connection_id = connection_id.to_string();
+        let account_id = account_id.to_string();
+        Box::new(
+            cmd("EVAL")
+                .arg(RESERVE_REFUND)
+                .arg(2)
+                .arg(stream_connection_key(connection_id.as_bytes()))
+                .arg(NEXT_REFUND_ID_KEY)
+                .arg(&account_id)
+                .arg(amount.map(|amount| amount.to_string()).unwrap_or_default())
+                .query_async(self.connection.as_ref().clone())
+                .map_err(|err| {
+                    error!("Error reserving refund: {:?}", err);
+                    StoreError::StoreUnavailable
+                })
+                .and_then(|(_connection, reserved): (_, Vec<Value>)| {
+                    let code = reserved
+                        .get(0)
+                        .and_then(|code| u8::from_redis_value(code).ok());
+                    match code {
+                        Some(REFUND_RESERVED) => {
+                            <(u8, u64, u64, String)>::from_redis_value(&Value::Bulk(reserved))
+                                .map(|(_, id, amount, return_pointer)| (id, amount, return_pointer))
+                                .map_err(|err| {
+                                    error!("Invalid refund reservation: {:?}", err);
+                                    StoreError::StoreUnavailable
+                                })
+                        }
+                        Some(REFUND_NO_PAYMENT) => Err(StoreError::NotFound),
+                        Some(REFUND_NO_RETURN_POINTER) => Err(StoreError::InvalidData),
+                        Some(REFUND_TOO_MUCH) => Err(StoreError::InsufficientBalance),
+                        _ => {
+                            error!("Unexpected refund reservation: {:?}", reserved);
+                            Err(StoreError::StoreUnavailable)
+                        }
+                    }
+                })
+                .and_then(move |(id, amount, return_pointer)| {
+                    let refund = Refund {
+                        id,
+                        connection_id,
+                        account_id,
+                        return_pointer,
+                        amount,
+                        source_amount_sent: 0,
+                        amount_delivered: 0,
+                        status: RefundStatus::Sending,
+                        reason: None,
+                        created_at,
+                    };
+                    let mut pipe = redis::pipe();
+                    pipe.atomic();
+                    save_refund(&mut pipe, &refund);
+                    pipe.query_async(connection)
+                        .map_err(|err| {
+                            error!("Error saving refund: {:?}", err);
+                            StoreError::StoreUnavailable
+                        })
+                        .map(move |(_connection, _): (_, Value)| refund)
+                }),
+        )
+    }
+
+    fn finish_refund(&self, refund: Refund) -> Box<Future<Item = (), Error = StoreError> + Send> {
+        let id = refund.id;
         let mut pipe = redis::pipe();
-        for account_id in account_ids.iter() {
-            pipe.cmd("HGETALL").arg(account_details_key(*account_id));
+        pipe.atomic();
+        save_refund(&mut pipe, &refund);
+        // Whatever was not sent can be refunded again
+        let unsent = refund.amount.saturating_sub(refund.source_amount_sent);
+        if unsent > 0 {
+            pipe.cmd("HINCRBY")
+                .arg(stream_connection_key(refund.connection_id.as_bytes()))
+                .arg("refunded")
+                .arg(-(unsent as i64))
+                .ignore();
         }
         Box::new(
             pipe.query_async(self.connection.as_ref().clone())
                 .map_err(move |err| {
-                    error!(
-                        "Error querying details for accounts: {:?} {:?}",
-                        account_ids, err
-                    )
+                    error!("Error saving refund {}: {:?}", id, err);
+                    StoreError::StoreUnavailable
                 })
-                .and_then(move |(_conn, accounts): (_, Vec<Account>)| {
-                    if accounts.len() == num_accounts {
-                        Ok(accounts)
-                    } else {
-                        Err(())
+                .map(|(_connection, _): (_, Value)| ()),
+        )
+    }
+
+    fn get_refunds(
+        &self,
+        connection_id: &str,
+    ) -> Box<Future<Item = Vec<Refund>, Error = StoreError> + Send> {
+        let connection = self.connection.as_ref().clone();
+        Box::new(
+            cmd("SMEMBERS")
+                .arg(payment_refunds_key(connection_id))
+                .query_async(self.connection.as_ref().clone())
+                .and_then(move |(_connection, mut ids): (_, Vec<u64>)| {
+                    ids.sort();
+                    let keys: Vec<String> = ids.into_iter().map(refund_key).collect();
+                    if keys.is_empty() {
+                        return Either::A(ok(Vec::new()));
                     }
+                    Either::B(cmd("MGET").arg(keys).query_async(connection).map(
+                        |(_connection, refunds): (_, Vec<Option<String>>)| parse_refunds(refunds),
+                    ))
+                })
+                .map_err(|err| {
+                    error!("Error getting refunds: {:?}", err);
+                    StoreError::StoreUnavailable
                 }),
         )
     }
 }
 
-impl BalanceStore for RedisStore {
-    fn get_balance(&self, account: Account) -> Box<Future<Item = i64, Error = ()> + Send> {
+fn parse_scheduled_payments(payments: Vec<Option<String>>) -> Vec<ScheduledPayment> {
+    payments
+        .into_iter()
+        .filter_map(|payment| payment)
+        .filter_map(|payment| match serde_json::from_str(&payment) {
+            Ok(payment) => Some(payment),
+            Err(err) => {
+                warn!("Ignoring invalid scheduled payment: {:?}", err);
+                None
+            }
+        })
+        .collect()
+}
+
+// Get the scheduled payments with the given IDs, skipping any that no longer exist
+fn get_scheduled_payments_by_id(
+    connection: SharedConnection,
+    ids: Vec<u64>,
+) -> impl Future<Item = Vec<ScheduledPayment>, Error = StoreError> {
+    if ids.is_empty() {
+        return Either::A(ok(Vec::new()));
+    }
+    let keys: Vec<String> = ids.into_iter().map(scheduled_payment_key).collect();
+    Either::B(
+        cmd("MGET")
+            .arg(keys)
+            .query_async(connection)
+            .map_err(|err| {
+                error!("Error getting scheduled payments: {:?}", err);
+                StoreError::StoreUnavailable
+            })
+            .and_then(|(_connection, payments): (_, Vec<Option<String>>)| {
+                Ok(parse_scheduled_payments(payments))
+            }),
+    )
+}
+
+// Save the payment and add it to (or remove it from) the payments waiting to be sent
+fn save_scheduled_payment(pipe: &mut redis::Pipeline, payment: &ScheduledPayment) {
+    pipe.cmd("SET")
+        .arg(scheduled_payment_key(payment.id))
+        .arg(serde_json::to_string(payment).unwrap())
+        .ignore();
+    if payment.status == ScheduleStatus::Scheduled {
+        pipe.cmd("ZADD")
+            .arg(DUE_SCHEDULED_PAYMENTS_KEY)
+            .arg(payment.next_run)
+            .arg(payment.id)
+            .ignore();
+    } else {
+        pipe.cmd("ZREM")
+            .arg(DUE_SCHEDULED_PAYMENTS_KEY)
+            .arg(payment.id)
+            .ignore();
+    }
+}
+
+// Save the item (which must have been serialized with its version already incremented) using
+// the SAVE_IF_UNCHANGED script, and update the index with the given command and arguments
+fn save_if_unchanged(
+    connection: SharedConnection,
+    key: String,
+    loaded_version: u64,
+    item: String,
+    index: &'static str,
+    index_command: &'static str,
+    index_args: &[u64],
+) -> impl Future<Item = (), Error = StoreError> {
+    cmd("EVAL")
+        .arg(SAVE_IF_UNCHANGED)
+        .arg(2)
+        .arg(&key)
+        .arg(index)
+        .arg(loaded_version)
+        .arg(item)
+        .arg(index_command)
+        .arg(index_args)
+        .query_async(connection)
+        .map_err(move |err| {
+            error!("Error saving {}: {:?}", key, err);
+            StoreError::StoreUnavailable
+        })
+        .and_then(|(_connection, saved): (_, i64)| match saved {
+            1 => Ok(()),
+            0 => Err(StoreError::Conflict),
+            _ => Err(StoreError::NotFound),
+        })
+}
+
+impl ScheduleStore for RedisStore {
+    fn add_scheduled_payment(
+        &self,
+        payment: ScheduledPayment,
+    ) -> Box<Future<Item = ScheduledPayment, Error = StoreError> + Send> {
+        let account_id = match u64::from_str(&payment.account_id) {
+            Ok(account_id) => account_id,
+            Err(_) => {
+                error!("Invalid account ID: {}", payment.account_id);
+                return Box::new(err(StoreError::InvalidData));
+            }
+        };
+        let connection = self.connection.as_ref().clone();
         Box::new(
-            cmd("HGET")
-                .arg(balance_key(account.asset_code.as_str()))
-                .arg(account.id)
+            cmd("INCR")
+                .arg(NEXT_SCHEDULED_PAYMENT_ID_KEY)
                 .query_async(self.connection.as_ref().clone())
-                .map_err(move |err| {
-                    error!(
-                        "Error getting balance for account: {} {:?}",
-                        account.id, err
-                    )
+                .and_then(move |(_connection, id): (_, u64)| {
+                    let mut payment = payment;
+                    payment.id = id;
+                    let mut pipe = redis::pipe();
+                    pipe.atomic();
+                    save_scheduled_payment(&mut pipe, &payment);
+                    pipe.cmd("SADD")
+                        .arg(account_scheduled_payments_key(account_id))
+                        .arg(id)
+                        .ignore();
+                    pipe.query_async(connection)
+                        .map(move |(_connection, _): (_, Value)| payment)
                 })
-                .and_then(|(_connection, balance): (_, i64)| Ok(balance)),
+                .map_err(|err| {
+                    error!("Error adding scheduled payment: {:?}", err);
+                    StoreError::StoreUnavailable
+                }),
         )
     }
 
-    fn update_balances(
+    fn update_scheduled_payment(
         &self,
-        from_account: Account,
-        incoming_amount: u64,
-        to_account: Account,
-        outgoing_amount: u64,
-    ) -> Box<Future<Item = (), Error = ()> + Send> {
-        let from_account_id = from_account.id();
-        let to_account_id = to_account.id();
+        payment: ScheduledPayment,
+    ) -> Box<Future<Item = ScheduledPayment, Error = StoreError> + Send> {
+        let loaded_version = payment.version;
+        let mut payment = payment;
+        payment.version += 1;
+        let (index_command, index_args) = if payment.status == ScheduleStatus::Scheduled {
+            ("ZADD", vec![payment.next_run, payment.id])
+        } else {
+            ("ZREM", vec![payment.id])
+        };
+        Box::new(
+            save_if_unchanged(
+                self.connection.as_ref().clone(),
+                scheduled_payment_key(payment.id),
+                loaded_version,
+                serde_json::to_string(&payment).unwrap(),
+                DUE_SCHEDULED_PAYMENTS_KEY,
+                index_command,
+                &index_args,
+            )
+            .map(move |_| payment),
+        )
+    }
 
-        debug!(
-            "Decreasing balance of account {} by: {}. Increasing balance of account {} by: {}",
-            from_account_id, incoming_amount, to_account_id, outgoing_amount
-        );
+    fn get_scheduled_payment(
+        &self,
+        id: u64,
+    ) -> Box<Future<Item = Option<ScheduledPayment>, Error = StoreError> + Send> {
+        Box::new(
+            get_scheduled_payments_by_id(self.connection.as_ref().clone(), vec![id])
+                .map(|mut payments| payments.pop()),
+        )
+    }
 
+    fn get_scheduled_payments(
+        &self,
+        account_id: u64,
+    ) -> Box<Future<Item = Vec<ScheduledPayment>, Error = StoreError> + Send> {
+        let connection = self.connection.as_ref().clone();
         Box::new(
-            cmd("EVAL")
-                // Update the balance only if it does not exceed the max_balance configured on the account
-                .arg(UPDATE_BALANCES)
-                .arg(0)
-                .arg(from_account.asset_code)
-                .arg(from_account_id)
-                .arg(incoming_amount)
-                .arg(to_account.asset_code)
-                .arg(to_account_id)
-                .arg(outgoing_amount)
+            cmd("SMEMBERS")
+                .arg(account_scheduled_payments_key(account_id))
                 .query_async(self.connection.as_ref().clone())
                 .map_err(move |err| {
                     error!(
-                    "Error updating balances for accounts. from_account: {}, to_account: {}: {:?}",
-                    from_account_id,
-                    to_account_id,
-                    err
-                )
+                        "Error getting scheduled payments for account {}: {:?}",
+                        account_id, err
+                    );
+                    StoreError::StoreUnavailable
                 })
-                .and_then(
-                    move |(_connection, (from_balance, to_balance)): (_, (i64, i64))| {
-                        debug!(
-                            "Updated account balances. Account {} has: {}, account {} has: {}",
-                            from_account_id, from_balance, to_account_id, to_balance
-                        );
-                        Ok(())
-                    },
-                ),
+                .and_then(move |(_connection, mut ids): (_, Vec<u64>)| {
+                    ids.sort();
+                    get_scheduled_payments_by_id(connection, ids)
+                }),
         )
     }
 
-    fn undo_balance_update(
+    fn get_due_scheduled_payments(
         &self,
-        from_account: Account,
-        incoming_amount: u64,
-        to_account: Account,
-        outgoing_amount: u64,
-    ) -> Box<Future<Item = (), Error = ()> + Send> {
-        let from_account_id = from_account.id();
-        let to_account_id = to_account.id();
+        now: u64,
+    ) -> Box<Future<Item = Vec<ScheduledPayment>, Error = StoreError> + Send> {
+        let connection = self.connection.as_ref().clone();
+        Box::new(
+            cmd("ZRANGEBYSCORE")
+                .arg(DUE_SCHEDULED_PAYMENTS_KEY)
+                .arg("-inf")
+                .arg(now)
+                .query_async(self.connection.as_ref().clone())
+                .map_err(|err| {
+                    error!("Error getting due scheduled payments: {:?}", err);
+                    StoreError::StoreUnavailable
+                })
+                .and_then(move |(_connection, ids): (_, Vec<u64>)| {
+                    get_scheduled_payments_by_id(connection, ids)
+                })
+                .map(|payments| {
+                    payments
+                        .into_iter()
+                        .filter(|payment| payment.status == ScheduleStatus::Scheduled)
+                        .collect()
+                }),
+        )
+    }
+}
 
-        debug!(
-            "Rolling back transaction. Increasing balance of account {} by: {}. Decreasing balance of account {} by: {}",
-            from_account_id, incoming_amount, to_account_id, outgoing_amount
-        );
+fn parse_payment_link(id: String, fields: Vec<(String, String)>) -> Option<PaymentLink> {
+    let fields: HashMap<String, String> = HashMap::from_iter(fields.into_iter());
+    let get_u64 = |name: &str| fields.get(name).and_then(|value| u64::from_str(value).ok());
+    let status = match fields.get("status")?.as_str() {
+        "open" => PaymentLinkStatus::Open,
+        "paid" => PaymentLinkStatus::Paid,
+        _ => return None,
+    };
+    Some(PaymentLink {
+        id,
+        account_id: fields.get("account_id")?.to_string(),
+        amount: get_u64("amount")?,
+        received: get_u64("received")?,
+        status,
+        description: fields.get("description").cloned(),
+        created_at: get_u64("created_at")?,
+        expires_at: get_u64("expires_at")?,
+        paid_at: get_u64("paid_at"),
+    })
+}
 
-        // TODO check against balance limit
+impl PaymentLinkStore for RedisStore {
+    fn add_payment_link(
+        &self,
+        link: PaymentLink,
+    ) -> Box<Future<Item = PaymentLink, Error = StoreError> + Send> {
+        let account_id = match u64::from_str(&link.account_id) {
+            Ok(account_id) => account_id,
+            Err(_) => {
+                error!("Invalid account ID: {}", link.account_id);
+                return Box::new(err(StoreError::InvalidData));
+            }
+        };
+        let mut link = link;
+        // Random, because anyone who knows the ID can pay the link
+        link.id = format!(
+            "{:016x}{:016x}",
+            thread_rng().gen::<u64>(),
+            thread_rng().gen::<u64>()
+        );
+        let key = payment_link_key(&link.id);
         let mut pipe = redis::pipe();
         pipe.atomic()
-            .cmd("HINCRBY")
-            .arg(balance_key(from_account.asset_code.as_str()))
-            .arg(from_account_id)
-            .arg(incoming_amount as i64)
-            .cmd("HINCRBY")
-            .arg(balance_key(to_account.asset_code.as_str()))
-            .arg(to_account_id)
-            // TODO make sure this doesn't overflow
-            .arg(0i64 - outgoing_amount as i64);
-
+            .cmd("HMSET")
+            .arg(&key)
+            .arg("account_id")
+            .arg(&link.account_id)
+            .arg("amount")
+            .arg(link.amount)
+            .arg("received")
+            .arg(link.received)
+            .arg("status")
+            .arg("open")
+            .arg("created_at")
+            .arg(link.created_at)
+            .arg("expires_at")
+            .arg(link.expires_at)
+            .ignore();
+        if let Some(ref description) = link.description {
+            pipe.cmd("HSET")
+                .arg(&key)
+                .arg("description")
+                .arg(description)
+                .ignore();
+        }
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        pipe.cmd("EXPIRE")
+            .arg(&key)
+            .arg(
+                link.expires_at
+                    .saturating_sub(now)
+                    .saturating_add(PAYMENT_LINK_RETENTION),
+            )
+            .ignore();
+        pipe.cmd("ZADD")
+            .arg(account_payment_links_key(account_id))
+            .arg(link.created_at)
+            .arg(&link.id)
+            .ignore();
         Box::new(
             pipe.query_async(self.connection.as_ref().clone())
-                .map_err(move |err| {
-                    error!(
-                    "Error undoing balance update for accounts. from_account: {}, to_account: {}: {:?}",
-                    from_account_id,
-                    to_account_id,
-                    err
-                )
+                .map_err(|err| {
+                    error!("Error adding payment link: {:?}", err);
+                    StoreError::StoreUnavailable
                 })
-                .and_then(move |(_connection, balances): (_, Vec<i64>)| {
-                    debug!(
-                        "Updated account balances. Account {} has: {}, account {} has: {}",
-                        from_account_id, balances[0], to_account_id, balances[1]
-                    );
-                    Ok(())
-                }),
+                .map(move |(_connection, _): (_, Value)| link),
         )
     }
-}
 
-impl ExchangeRateStore for RedisStore {
-    fn get_exchange_rates(&self, asset_codes: &[&str]) -> Result<Vec<f64>, ()> {
-        let rates: Vec<f64> = asset_codes
-            .iter()
-            .filter_map(|code| {
-                (*self.exchange_rates.read())
-                    .get(&code.to_string())
-                    .cloned()
-            })
-            .collect();
-        if rates.len() == asset_codes.len() {
-            Ok(rates)
-        } else {
-            Err(())
-        }
+    fn get_payment_link(
+        &self,
+        id: &str,
+    ) -> Box<Future<Item = Option<PaymentLink>, Error = StoreError> + Send> {
+        let id = id.to_string();
+        Box::new(
+            cmd("HGETALL")
+                .arg(payment_link_key(&id))
+                .query_async(self.connection.as_ref().clone())
+                .map_err(|err| {
+                    error!("Error getting payment link: {:?}", err);
+                    StoreError::StoreUnavailable
+                })
+                .and_then(move |(_connection, fields): (_, Vec<(String, String)>)| {
+                    Ok(parse_payment_link(id, fields))
+                }),
+        )
     }
-}
 
-impl BtpStore for RedisStore {
-    type Account = Account;
+    fn get_payment_links(
+        &self,
+        account_id: u64,
+    ) -> Box<Future<Item = Vec<PaymentLink>, Error = StoreError> + Send> {
+        let connection = self.connection.as_ref().clone();
+        Box::new(
+            cmd("ZRANGE")
+                .arg(account_payment_links_key(account_id))
+                .arg(0)
+                .arg(-1)
+                .query_async(self.connection.as_ref().clone())
+                .and_then(move |(_connection, ids): (_, Vec<String>)| {
+                    let mut pipe = redis::pipe();
+                    for id in ids.iter() {
+                        pipe.cmd("HGETALL").arg(payment_link_key(id));
+                    }
+                    pipe.query_async(connection).and_then(
+                        move |(_connection, links): (_, Vec<Vec<(String, String)>>)| {
+                            Ok(ids
+                                .into_iter()
+                                .zip(links.into_iter())
+                                .filter_map(|(id, fields)| parse_payment_link(id, fields))
+                                .collect())
+                        },
+                    )
+                })
+                .map_err(move |err| {
+                    error!(
+                        "Error getting payment links for account {}: {:?}",
+                        account_id, err
+                    );
+                    StoreError::StoreUnavailable
+                }),
+        )
+    }
 
-    fn get_account_from_btp_token(
+    fn pay_payment_link(
         &self,
-        token: &str,
-    ) -> Box<Future<Item = Self::Account, Error = ()> + Send> {
-        // TODO make sure it can't do script injection!
-        // TODO cache the result so we don't hit redis for every packet (is that necessary if redis is often used as a cache?)
-        let token = token.to_string();
+        id: &str,
+        account_id: &str,
+        amount: u64,
+        now: u64,
+    ) -> Box<Future<Item = PaymentLink, Error = String> + Send> {
+        let id = id.to_string();
         Box::new(
             cmd("EVAL")
-                .arg(ACCOUNT_FROM_INDEX)
+                .arg(PAY_PAYMENT_LINK)
                 .arg(1)
-                .arg("btp_auth")
-                .arg(&token)
+                .arg(payment_link_key(&id))
+                .arg(account_id)
+                .arg(amount)
+                .arg(now)
                 .query_async(self.connection.as_ref().clone())
-                .map_err(|err| error!("Error getting account from BTP token: {:?}", err))
-                .and_then(move |(_connection, account): (_, Option<Account>)| {
-                    if let Some(account) = account {
-                        Ok(account)
-                    } else {
-                        warn!("No account found with BTP token: {}", token);
-                        Err(())
-                    }
+                .map_err(|err| {
+                    error!("Error paying payment link: {:?}", err);
+                    "Unable to check the payment link".to_string()
+                })
+                .and_then(move |(_connection, value): (_, Value)| match value {
+                    Value::Data(reason) => Err(String::from_utf8_lossy(&reason).to_string()),
+                    value => Vec::<(String, String)>::from_redis_value(&value)
+                        .ok()
+                        .and_then(|fields| parse_payment_link(id, fields))
+                        .ok_or_else(|| "Unable to check the payment link".to_string()),
                 }),
         )
     }
-}
 
-impl HttpStore for RedisStore {
-    type Account = Account;
+    fn undo_payment_link_payment(
+        &self,
+        id: &str,
+        amount: u64,
+    ) -> Box<Future<Item = (), Error = ()> + Send> {
+        let id = id.to_string();
+        Box::new(
+            cmd("EVAL")
+                .arg(UNDO_PAYMENT_LINK_PAYMENT)
+                .arg(1)
+                .arg(payment_link_key(&id))
+                .arg(amount)
+                .query_async(self.connection.as_ref().clone())
+                .map_err(move |err| {
+                    error!(
+                        "Error taking back payment of {} to payment link {}: {:?}",
+                        amount, id, err
+                    )
+                })
+                .map(|(_connection, _): (_, Value)| ()),
+        )
+    }
 
-    fn get_account_from_http_auth(
+    fn take_paid_payment_link(
         &self,
-        auth_header: &str,
-    ) -> Box<Future<Item = Self::Account, Error = ()> + Send> {
-        // TODO make sure it can't do script injection!
-        let auth_header = auth_header.to_string();
+        id: &str,
+    ) -> Box<Future<Item = Option<PaymentLink>, Error = ()> + Send> {
+        let id = id.to_string();
         Box::new(
             cmd("EVAL")
-                .arg(ACCOUNT_FROM_INDEX)
+                .arg(TAKE_PAID_PAYMENT_LINK)
                 .arg(1)
-                .arg("http_auth")
-                .arg(&auth_header)
+                .arg(payment_link_key(&id))
                 .query_async(self.connection.as_ref().clone())
-                .map_err(|err| error!("Error getting account from HTTP auth: {:?}", err))
-                .and_then(move |(_connection, account): (_, Option<Account>)| {
-                    if let Some(account) = account {
-                        Ok(account)
-                    } else {
-                        warn!("No account found with HTTP auth: {}", auth_header);
-                        Err(())
-                    }
-                }),
+                .map_err(|err| error!("Error checking whether payment link was paid: {:?}", err))
+                .map(
+                    move |(_connection, fields): (_, Option<Vec<(String, String)>>)| {
+                        fields.and_then(|fields| parse_payment_link(id, fields))
+                    },
+                ),
         )
     }
 }
 
-impl RouterStore for RedisStore {
-    fn routing_table(&self) -> HashMap<Bytes, u64> {
-        self.routes.read().clone()
+fn parse_streaming_sessions(sessions: Vec<Option<String>>) -> Vec<StreamingSession> {
+    sessions
+        .into_iter()
+        .filter_map(|session| session)
+        .filter_map(|session| match serde_json::from_str(&session) {
+            Ok(session) => Some(session),
+            Err(err) => {
+                warn!("Ignoring invalid streaming session: {:?}", err);
+                None
+            }
+        })
+        .collect()
+}
+
+// Get the streaming sessions with the given IDs, skipping any that no longer exist
+fn get_streaming_sessions_by_id(
+    connection: SharedConnection,
+    ids: Vec<u64>,
+) -> impl Future<Item = Vec<StreamingSession>, Error = StoreError> {
+    if ids.is_empty() {
+        return Either::A(ok(Vec::new()));
     }
+    let keys: Vec<String> = ids.into_iter().map(streaming_session_key).collect();
+    Either::B(
+        cmd("MGET")
+            .arg(keys)
+            .query_async(connection)
+            .map_err(|err| {
+                error!("Error getting streaming sessions: {:?}", err);
+                StoreError::StoreUnavailable
+            })
+            .and_then(|(_connection, sessions): (_, Vec<Option<String>>)| {
+                Ok(parse_streaming_sessions(sessions))
+            }),
+    )
 }
 
-impl NodeStore for RedisStore {
-    type Account = Account;
+// Save the session and add it to (or remove it from) the active sessions
+fn save_streaming_session(pipe: &mut redis::Pipeline, session: &StreamingSession) {
+    pipe.cmd("SET")
+        .arg(streaming_session_key(session.id))
+        .arg(serde_json::to_string(session).unwrap())
+        .ignore();
+    pipe.cmd(if session.status == SessionStatus::Active {
+        "SADD"
+    } else {
+        "SREM"
+    })
+    .arg(ACTIVE_STREAMING_SESSIONS_KEY)
+    .arg(session.id)
+    .ignore();
+}
 
-    fn insert_account(
+impl StreamingSessionStore for RedisStore {
+    fn add_streaming_session(
         &self,
-        account: AccountDetails,
-    ) -> Box<Future<Item = Account, Error = ()> + Send> {
-        debug!("Inserting account: {:?}", account);
-        let connection = self.connection.clone();
-        let routing_table = self.routes.clone();
-
+        session: StreamingSession,
+    ) -> Box<Future<Item = StreamingSession, Error = StoreError> + Send> {
+        let account_id = match u64::from_str(&session.account_id) {
+            Ok(account_id) => account_id,
+            Err(_) => {
+                error!("Invalid account ID: {}", session.account_id);
+                return Box::new(err(StoreError::InvalidData));
+            }
+        };
+        let connection = self.connection.as_ref().clone();
         Box::new(
-            self.get_next_account_id()
-                .and_then(|id| {
-                    debug!("Next account id is: {}", id);
-                    Account::try_from(id, account)
-                })
-                .and_then(move |account| {
-                    // Check that there isn't already an account with values that must be unique
-                    let mut keys: Vec<String> = vec!["ID".to_string(), "ID".to_string()];
-
-                    let mut pipe = redis::pipe();
-                    pipe.cmd("EXISTS")
-                        .arg(account_details_key(account.id))
-                        .cmd("HEXISTS")
-                        .arg(balance_key(account.asset_code.as_str()))
-                        .arg(account.id);
-
-                    if let Some(ref auth) = account.btp_incoming_authorization {
-                        keys.push("BTP auth".to_string());
-                        pipe.cmd("HEXISTS")
-                            .arg("btp_auth")
-                            .arg(auth.clone().to_string());
-                    }
-                    if let Some(ref auth) = account.http_incoming_authorization {
-                        keys.push("HTTP auth".to_string());
-                        pipe.cmd("HEXISTS")
-                            .arg("http_auth")
-                            .arg(auth.clone().to_string());
-                    }
-                    if let Some(ref xrp_address) = account.xrp_address {
-                        keys.push("XRP address".to_string());
-                        pipe.cmd("HEXISTS").arg("xrp_addresses").arg(xrp_address);
-                    }
-
-                    pipe.query_async(connection.as_ref().clone())
-                        .map_err(|err| {
-                            error!(
-                                "Error checking whether account details already exist: {:?}",
-                                err
-                            )
-                        })
-                        .and_then(
-                            move |(connection, results): (SharedConnection, Vec<bool>)| {
-                                if let Some(index) = results.iter().position(|val| *val) {
-                                    warn!("An account already exists with the same {}. Cannot insert account: {:?}", keys[index], account);
-                                    Err(())
-                                } else {
-                                    Ok((connection, account))
-                                }
-                            },
-                        )
-                })
-                .and_then(|(connection, account)| {
+            cmd("INCR")
+                .arg(NEXT_STREAMING_SESSION_ID_KEY)
+                .query_async(self.connection.as_ref().clone())
+                .and_then(move |(_connection, id): (_, u64)| {
+                    let mut session = session;
+                    session.id = id;
                     let mut pipe = redis::pipe();
-
-                    // Set balance
-                    pipe.atomic()
-                        .cmd("HSET")
-                        .arg(balance_key(account.asset_code.as_str()))
-                        .arg(account.id)
-                        .arg(0u64)
-                        .ignore();
-
-                    // Set incoming auth details
-                    if let Some(ref auth) = account.btp_incoming_authorization {
-                        pipe.cmd("HSET")
-                            .arg("btp_auth")
-                            .arg(auth.clone().to_string())
-                            .arg(account.id)
-                            .ignore();
-                    }
-                    if let Some(ref auth) = account.http_incoming_authorization {
-                        pipe.cmd("HSET")
-                            .arg("http_auth")
-                            .arg(auth.clone().to_string())
-                            .arg(account.id)
-                            .ignore();
-                    }
-
-                    // Add settlement details
-                    if let Some(ref xrp_address) = account.xrp_address {
-                        pipe.cmd("HSET")
-                            .arg("xrp_addresses")
-                            .arg(xrp_address)
-                            .arg(account.id)
-                            .ignore();
-                    }
-
-                    if account.send_routes {
-                        pipe.cmd("SADD")
-                            .arg("send_routes_to")
-                            .arg(account.id)
-                            .ignore();
-                    }
-
-                    // Add route to routing table
-                    pipe.hset(ROUTES_KEY, account.ilp_address.to_vec(), account.id)
-                        .ignore();
-
-                    // Set account details
-                    pipe.cmd("HMSET")
-                        .arg(account_details_key(account.id))
-                        .arg(account.clone())
+                    pipe.atomic();
+                    save_streaming_session(&mut pipe, &session);
+                    pipe.cmd("SADD")
+                        .arg(account_streaming_sessions_key(account_id))
+                        .arg(id)
                         .ignore();
-
                     pipe.query_async(connection)
-                        .map_err(|err| error!("Error inserting account into DB: {:?}", err))
-                        .and_then(move |(connection, _ret): (SharedConnection, Value)| {
-                            update_routes(connection, routing_table)
-                        })
-                        .and_then(move |_| Ok(account))
+                        .map(move |(_connection, _): (_, Value)| session)
+                })
+                .map_err(|err| {
+                    error!("Error adding streaming session: {:?}", err);
+                    StoreError::StoreUnavailable
                 }),
         )
     }
 
-    // TODO limit the number of results and page through them
-    fn get_all_accounts(&self) -> Box<Future<Item = Vec<Self::Account>, Error = ()> + Send> {
+    fn update_streaming_session(
+        &self,
+        session: StreamingSession,
+    ) -> Box<Future<Item = StreamingSession, Error = StoreError> + Send> {
+        let loaded_version = session.version;
+        let mut session = session;
+        session.version += 1;
+        let index_command = if session.status == SessionStatus::Active {
+            "SADD"
+        } else {
+            "SREM"
+        };
         Box::new(
-            cmd("GET")
-                .arg(NEXT_ACCOUNT_ID_KEY)
-                .query_async(self.connection.as_ref().clone())
-                .and_then(|(connection, next_account_id): (SharedConnection, u64)| {
-                    let mut pipe = redis::pipe();
-                    for i in 0..next_account_id {
-                        pipe.cmd("HGETALL").arg(account_details_key(i));
-                    }
-                    pipe.query_async(connection)
-                        .and_then(|(_, accounts): (_, Vec<Self::Account>)| Ok(accounts))
-                })
-                .map_err(|err| error!("Error getting all accounts: {:?}", err)),
+            save_if_unchanged(
+                self.connection.as_ref().clone(),
+                streaming_session_key(session.id),
+                loaded_version,
+                serde_json::to_string(&session).unwrap(),
+                ACTIVE_STREAMING_SESSIONS_KEY,
+                index_command,
+                &[session.id],
+            )
+            .map(move |_| session),
         )
     }
 
-    fn set_rates<R>(&self, rates: R) -> Box<Future<Item = (), Error = ()> + Send>
-    where
-        R: IntoIterator<Item = (String, f64)>,
-    {
-        let rates: Vec<(String, f64)> = rates.into_iter().collect();
-        let exchange_rates = self.exchange_rates.clone();
-        let mut pipe = redis::pipe();
-        pipe.atomic()
-            .cmd("DEL")
-            .arg(RATES_KEY)
-            .ignore()
-            .cmd("HMSET")
-            .arg(RATES_KEY)
-            .arg(rates)
-            .ignore();
+    fn get_streaming_session(
+        &self,
+        id: u64,
+    ) -> Box<Future<Item = Option<StreamingSession>, Error = StoreError> + Send> {
         Box::new(
-            pipe.query_async(self.connection.as_ref().clone())
-                .map_err(|err| error!("Error setting rates: {:?}", err))
-                .and_then(move |(connection, _): (SharedConnection, Value)| {
-                    update_rates(connection, exchange_rates)
-                }),
+            get_streaming_sessions_by_id(self.connection.as_ref().clone(), vec![id])
+                .map(|mut sessions| sessions.pop()),
         )
     }
 
-    // TODO fix inconsistency betwen this method and set_routes which
-    // takes the prefixes as Bytes and the account as an Account object
-    fn set_static_routes<R>(&self, routes: R) -> Box<Future<Item = (), Error = ()> + Send>
-    where
-        R: IntoIterator<Item = (String, u64)>,
-    {
-        let routes: Vec<(String, u64)> = routes.into_iter().collect();
-        let accounts: HashSet<u64> =
-            HashSet::from_iter(routes.iter().map(|(_prefix, account_id)| *account_id));
-        let mut pipe = redis::pipe();
-        for account_id in accounts {
-            pipe.cmd("EXISTS").arg(account_details_key(account_id));
-        }
-
-        let routing_table = self.routes.clone();
-        Box::new(pipe.query_async(self.connection.as_ref().clone())
-            .map_err(|err| error!("Error checking if accounts exist while setting static routes: {:?}", err))
-            .and_then(|(connection, accounts_exist): (SharedConnection, Vec<bool>)| {
-                if accounts_exist.iter().all(|a| *a) {
-                    Ok(connection)
-                } else {
-                    error!("Error setting static routes because not all of the given accounts exist");
-                    Err(())
-                }
-            })
-            .and_then(move |connection| {
-        let mut pipe = redis::pipe();
-        pipe.atomic()
-            .cmd("DEL")
-            .arg(STATIC_ROUTES_KEY)
-            .ignore()
-            .cmd("HMSET")
-            .arg(STATIC_ROUTES_KEY)
-            .arg(routes)
-            .ignore();
-            pipe.query_async(connection)
-                .map_err(|err| error!("Error setting static routes: {:?}", err))
-                .and_then(move |(connection, _): (SharedConnection, Value)| {
-                    update_routes(connection, routing_table)
+    fn get_streaming_sessions(
+        &self,
+        account_id: u64,
+    ) -> Box<Future<Item = Vec<StreamingSession>, Error = StoreError> + Send> {
+        let connection = self.connection.as_ref().clone();
+        Box::new(
+            cmd("SMEMBERS")
+                .arg(account_streaming_sessions_key(account_id))
+                .query_async(self.connection.as_ref().clone())
+                .map_err(move |err| {
+                    error!(
+                        "Error getting streaming sessions for account {}: {:?}",
+                        account_id, err
+                    );
+                    StoreError::StoreUnavailable
                 })
-            }))
+                .and_then(move |(_connection, mut ids): (_, Vec<u64>)| {
+                    ids.sort();
+                    get_streaming_sessions_by_id(connection, ids)
+                }),
+        )
     }
 
-    fn set_static_route(
+    fn get_active_streaming_sessions(
         &self,
-        prefix: String,
-        account_id: u64,
-    ) -> Box<Future<Item = (), Error = ()> + Send> {
-        let routing_table = self.routes.clone();
-        let prefix_clone = prefix.clone();
+    ) -> Box<Future<Item = Vec<StreamingSession>, Error = StoreError> + Send> {
+        let connection = self.connection.as_ref().clone();
         Box::new(
-        cmd("EXISTS")
-            .arg(account_details_key(account_id))
-            .query_async(self.connection.as_ref().clone())
-            .map_err(|err| error!("Error checking if account exists before setting static route: {:?}", err))
-            .and_then(move |(connection, exists): (SharedConnection, bool)| {
-                if exists {
-                    Ok(connection)
-                } else {
-                    error!("Cannot set static route for prefix: {} because account {} does not exist", prefix_clone, account_id);
-                    Err(())
-                }
-            })
-            .and_then(move |connection| {
-                cmd("HSET")
-                    .arg(STATIC_ROUTES_KEY)
-                    .arg(prefix)
-                    .arg(account_id)
-                    .query_async(connection)
-                    .map_err(|err| error!("Error setting static route: {:?}", err))
-                    .and_then(move |(connection, _): (SharedConnection, Value)| {
-                        update_routes(connection, routing_table)
-                    })
-            })
+            cmd("SMEMBERS")
+                .arg(ACTIVE_STREAMING_SESSIONS_KEY)
+                .query_async(self.connection.as_ref().clone())
+                .map_err(|err| {
+                    error!("Error getting active streaming sessions: {:?}", err);
+                    StoreError::StoreUnavailable
+                })
+                .and_then(move |(_connection, ids): (_, Vec<u64>)| {
+                    get_streaming_sessions_by_id(connection, ids)
+                }),
         )
     }
 }
@@ -674,14 +4957,19 @@ impl RouteManagerStore for RedisStore {
         &self,
     ) -> Box<Future<Item = ((HashMap<Bytes, Account>), (HashMap<Bytes, Account>)), Error = ()> + Send>
     {
-        let get_static_routes = cmd("HGETALL")
+        // The static routes are read before the accounts so that a route is never dropped
+        // because it was added together with an account we did not see yet
+        let get_accounts_and_static_routes = cmd("HGETALL")
             .arg(STATIC_ROUTES_KEY)
             .query_async(self.connection.as_ref().clone())
-            .map_err(|err| error!("Error getting static routes: {:?}", err))
             .and_then(
-                |(_, static_routes): (SharedConnection, Vec<(String, u64)>)| Ok(static_routes),
-            );
-        Box::new(self.get_all_accounts().join(get_static_routes).and_then(
+                |(connection, static_routes): (SharedConnection, RouteVec)| {
+                    get_all_accounts(connection)
+                        .map(move |(_connection, accounts)| (accounts, static_routes))
+                },
+            )
+            .map_err(|err| error!("Error getting accounts and static routes: {:?}", err));
+        Box::new(get_accounts_and_static_routes.and_then(
             |(accounts, static_routes)| {
                 let local_table = HashMap::from_iter(
                     accounts
@@ -711,10 +4999,10 @@ impl RouteManagerStore for RedisStore {
     {
         let routes: Vec<(String, u64)> = routes
             .into_iter()
-            .filter_map(|(prefix, account)| {
-                if let Ok(prefix) = String::from_utf8(prefix.to_vec()) {
-                    Some((prefix, account.id))
-                } else {
+            .filter_map(|(prefix, account)| match Address::try_from(prefix) {
+                Ok(prefix) => Some((prefix.to_string(), account.id)),
+                Err(err) => {
+                    warn!("Not saving route with invalid prefix: {:?}", err);
                     None
                 }
             })
@@ -737,13 +5025,238 @@ impl RouteManagerStore for RedisStore {
                 .map_err(|err| error!("Error setting routes: {:?}", err))
                 .and_then(move |(connection, _): (SharedConnection, Value)| {
                     trace!("Saved {} routes to Redis", num_routes);
-                    update_routes(connection, routing_tale)
+                    routes_changed(connection, routing_tale, RouteSource::Ccp)
+                }),
+        )
+    }
+
+    fn set_route_hints(
+        &mut self,
+        hints: HashMap<Bytes, Vec<RouteHint<u64>>>,
+    ) -> Box<Future<Item = (), Error = ()> + Send> {
+        let hints: Vec<(String, String)> = hints
+            .iter()
+            .filter_map(|(prefix, hints)| {
+                Some((
+                    String::from_utf8(prefix.to_vec()).ok()?,
+                    format_route_hints(hints),
+                ))
+            })
+            .collect();
+        let num_prefixes = hints.len();
+
+        let route_hints = self.route_hints.clone();
+        let mut pipe = redis::pipe();
+        pipe.atomic().cmd("DEL").arg(ROUTE_HINTS_KEY).ignore();
+        if !hints.is_empty() {
+            pipe.cmd("HMSET").arg(ROUTE_HINTS_KEY).arg(hints).ignore();
+        }
+        Box::new(
+            pipe.query_async(self.connection.as_ref().clone())
+                .map_err(|err| error!("Error setting route hints: {:?}", err))
+                .and_then(move |(connection, _): (SharedConnection, Value)| {
+                    trace!("Saved route hints for {} prefixes to Redis", num_prefixes);
+                    publish_update(connection, ROUTES_CHANNEL)
+                        .and_then(move |connection| update_route_hints(connection, route_hints))
                 }),
         )
     }
 }
 
-// TODO replace this with pubsub when async pubsub is added upstream: https://github.com/mitsuhiko/redis-rs/issues/183
+// Route hints are stored as a comma-separated list of "<account id> <asset code> <rate> [max amount]"
+fn format_route_hints(hints: &[RouteHint<u64>]) -> String {
+    let hints: Vec<String> = hints
+        .iter()
+        .map(|hint| {
+            if let Some(max_amount) = hint.max_amount {
+                format!(
+                    "{} {} {} {}",
+                    hint.account_id, hint.asset_code, hint.rate, max_amount
+                )
+            } else {
+                format!("{} {} {}", hint.account_id, hint.asset_code, hint.rate)
+            }
+        })
+        .collect();
+    hints.join(",")
+}
+
+fn parse_route_hints(value: &str) -> Option<Vec<RouteHint<u64>>> {
+    value
+        .split(',')
+        .map(|hint| {
+            let mut parts = hint.split(' ');
+            let account_id = u64::from_str(parts.next()?).ok()?;
+            let asset_code = parts.next()?.to_string();
+            let rate = f64::from_str(parts.next()?).ok()?;
+            let max_amount = match parts.next() {
+                Some(max_amount) => Some(f64::from_str(max_amount).ok()?),
+                None => None,
+            };
+            Some(RouteHint {
+                account_id,
+                asset_code,
+                rate,
+                max_amount,
+            })
+        })
+        .collect()
+}
+
+fn update_route_hints(
+    connection: SharedConnection,
+    route_hints: Arc<RwLock<HashMap<Bytes, Vec<RouteHint<u64>>>>>,
+) -> impl Future<Item = (), Error = ()> {
+    cmd("HGETALL")
+        .arg(ROUTE_HINTS_KEY)
+        .query_async(connection)
+        .map_err(|err| error!("Error polling for route hints: {:?}", err))
+        .and_then(move |(_connection, entries): (_, Vec<(String, String)>)| {
+            let hints = HashMap::from_iter(entries.into_iter().filter_map(|(prefix, value)| {
+                if let Some(hints) = parse_route_hints(&value) {
+                    Some((Bytes::from(prefix), hints))
+                } else {
+                    warn!(
+                        "Ignoring invalid route hints for prefix {}: {}",
+                        prefix, value
+                    );
+                    None
+                }
+            }));
+            debug!("Updated route hints for {} prefixes", hints.len());
+            *route_hints.write() = hints;
+            Ok(())
+        })
+}
+
+// Tell the other instances that share the database to reload the routing table or the rates.
+// A failed notification is only logged, because the other instances also poll for updates
+fn publish_update(
+    connection: SharedConnection,
+    channel: &'static str,
+) -> impl Future<Item = SharedConnection, Error = ()> {
+    cmd("PUBLISH")
+        .arg(channel)
+        .arg(1)
+        .query_async(connection.clone())
+        .then(
+            move |result: RedisResult<(SharedConnection, u64)>| -> Result<SharedConnection, ()> {
+                if let Err(err) = result {
+                    warn!("Error publishing update on {}: {:?}", channel, err);
+                }
+                Ok(connection)
+            },
+        )
+}
+
+// Record the change in the route history, reload this instance's routing table and tell the
+// others to reload theirs
+fn routes_changed(
+    connection: SharedConnection,
+    routing_table: Arc<RwLock<HashMap<Bytes, u64>>>,
+    source: RouteSource,
+) -> impl Future<Item = (), Error = ()> {
+    record_routes(connection.clone(), source, None)
+        .then(move |result| {
+            if let Err(err) = result {
+                warn!(
+                    "Error recording route change, it will be recorded with the next version: {:?}",
+                    err
+                );
+            }
+            Ok(connection)
+        })
+        .and_then(move |connection| reload_routes(connection, routing_table))
+}
+
+// Reload this instance's routing table, and tell the others to reload theirs
+fn reload_routes(
+    connection: SharedConnection,
+    routing_table: Arc<RwLock<HashMap<Bytes, u64>>>,
+) -> impl Future<Item = (), Error = ()> {
+    publish_update(connection, ROUTES_CHANNEL)
+        .and_then(move |connection| update_routes(connection, routing_table))
+}
+
+// Reload this instance's rates after changing them, and tell the other instances to reload theirs
+fn rates_changed(
+    connection: SharedConnection,
+    exchange_rates: Arc<RwLock<HashMap<String, f64>>>,
+) -> impl Future<Item = (), Error = ()> {
+    publish_update(connection, RATES_CHANNEL)
+        .and_then(move |connection| update_rates(connection, exchange_rates))
+}
+
+// The Redis client cannot subscribe asynchronously, so the subscription uses a blocking connection
+// on its own thread, which passes the channel of each update it gets to the returned stream. If the
+// subscription drops, it is reestablished after RESUBSCRIBE_DELAY and both the routing table and
+// the rates are reloaded, in case updates were missed. The polling keeps them up to date meanwhile
+fn subscribe_to_updates(
+    client: Client,
+    store_connection: Weak<SharedConnection>,
+) -> UnboundedReceiver<&'static str> {
+    let (updates, receiver) = unbounded();
+    let spawned = thread::Builder::new()
+        .name("redis-store-updates".to_string())
+        .spawn(move || {
+            // Reload once the first subscription is up too, because anything published between
+            // the initial load and the SUBSCRIBE was missed
+            let mut missed_updates = true;
+            while store_connection.upgrade().is_some() {
+                if let Err(err) =
+                    receive_updates(&client, &updates, &store_connection, missed_updates)
+                {
+                    warn!(
+                        "Subscription to updates dropped, polling until it is back: {:?}",
+                        err
+                    );
+                    missed_updates = true;
+                    thread::sleep(RESUBSCRIBE_DELAY);
+                }
+            }
+            debug!("Not subscribed to updates anymore because connection was closed");
+        });
+    if let Err(err) = spawned {
+        error!("Error subscribing to route and rate updates: {:?}", err);
+    }
+    receiver
+}
+
+// Returns when the store is dropped, or with an error when the subscription drops
+fn receive_updates(
+    client: &Client,
+    updates: &UnboundedSender<&'static str>,
+    store_connection: &Weak<SharedConnection>,
+    missed_updates: bool,
+) -> RedisResult<()> {
+    let mut connection = client.get_connection()?;
+    let mut pubsub = connection.as_pubsub();
+    pubsub.subscribe(ROUTES_CHANNEL)?;
+    pubsub.subscribe(RATES_CHANNEL)?;
+    // Without a timeout, the thread could not tell that the store was dropped until the next update
+    pubsub.set_read_timeout(Some(SUBSCRIPTION_READ_TIMEOUT))?;
+    debug!("Subscribed to route and rate updates");
+    if missed_updates {
+        for channel in [ROUTES_CHANNEL, RATES_CHANNEL].iter() {
+            if updates.unbounded_send(*channel).is_err() {
+                return Ok(());
+            }
+        }
+    }
+    while store_connection.upgrade().is_some() {
+        let channel = match pubsub.get_message() {
+            Ok(ref message) if message.get_channel_name() == ROUTES_CHANNEL => ROUTES_CHANNEL,
+            Ok(_) => RATES_CHANNEL,
+            Err(ref err) if err.is_timeout() => continue,
+            Err(err) => return Err(err),
+        };
+        if updates.unbounded_send(channel).is_err() {
+            return Ok(());
+        }
+    }
+    Ok(())
+}
+
 fn update_rates(
     connection: SharedConnection,
     exchange_rates: Arc<RwLock<HashMap<String, f64>>>,
@@ -761,30 +5274,122 @@ fn update_rates(
         })
 }
 
-// TODO replace this with pubsub when async pubsub is added upstream: https://github.com/mitsuhiko/redis-rs/issues/183
+// Fee policies are stored in a hash where the fields are either "account:{id}" or "prefix:{prefix}"
+fn update_fees(
+    connection: SharedConnection,
+    fee_policies: Arc<RwLock<FeePolicies>>,
+) -> impl Future<Item = (), Error = ()> {
+    cmd("HGETALL")
+        .arg(FEES_KEY)
+        .query_async(connection)
+        .map_err(|err| error!("Error polling for fee policies: {:?}", err))
+        .and_then(move |(_connection, policies): (_, Vec<(String, String)>)| {
+            let mut accounts = HashMap::new();
+            let mut prefixes = HashMap::new();
+            for (key, policy) in policies {
+                let policy = if let Ok(policy) = FeePolicy::from_str(&policy) {
+                    policy
+                } else {
+                    warn!("Ignoring invalid fee policy for {}: {}", key, policy);
+                    continue;
+                };
+                if key.starts_with("account:") {
+                    if let Ok(id) = u64::from_str(&key[8..]) {
+                        accounts.insert(id, policy);
+                        continue;
+                    }
+                } else if key.starts_with("prefix:") {
+                    prefixes.insert(Bytes::from(&key[7..]), policy);
+                    continue;
+                }
+                warn!("Ignoring fee policy with invalid key: {}", key);
+            }
+            debug!(
+                "Updated fee policies for {} accounts and {} prefixes",
+                accounts.len(),
+                prefixes.len()
+            );
+            *fee_policies.write() = FeePolicies { accounts, prefixes };
+            Ok(())
+        })
+}
+
+// Maintenance windows are stored in a hash where the fields are account IDs and
+// the values are JSON arrays of that account's windows
+fn parse_maintenance_windows(entries: Vec<(u64, String)>) -> HashMap<u64, Vec<MaintenanceWindow>> {
+    let mut windows = HashMap::new();
+    for (account_id, json) in entries {
+        match serde_json::from_str(&json) {
+            Ok(account_windows) => {
+                windows.insert(account_id, account_windows);
+            }
+            Err(err) => warn!(
+                "Ignoring invalid maintenance windows for account {}: {:?}",
+                account_id, err
+            ),
+        }
+    }
+    windows
+}
+
+fn update_maintenance_windows(
+    connection: SharedConnection,
+    maintenance_windows: Arc<RwLock<HashMap<u64, Vec<MaintenanceWindow>>>>,
+) -> impl Future<Item = (), Error = ()> {
+    cmd("HGETALL")
+        .arg(MAINTENANCE_KEY)
+        .query_async(connection)
+        .map_err(|err| error!("Error polling for maintenance windows: {:?}", err))
+        .and_then(move |(_connection, entries): (_, Vec<(u64, String)>)| {
+            let windows = parse_maintenance_windows(entries);
+            debug!("Updated maintenance windows for {} accounts", windows.len());
+            *maintenance_windows.write() = windows;
+            Ok(())
+        })
+}
+
 type RouteVec = Vec<(String, u64)>;
 
 fn update_routes(
     connection: SharedConnection,
     routing_table: Arc<RwLock<HashMap<Bytes, u64>>>,
 ) -> impl Future<Item = (), Error = ()> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
     let mut pipe = redis::pipe();
-    pipe.cmd("HGETALL")
+    pipe.atomic()
+        .cmd("HGETALL")
         .arg(ROUTES_KEY)
         .cmd("HGETALL")
-        .arg(STATIC_ROUTES_KEY);
+        .arg(STATIC_ROUTES_KEY)
+        .cmd("HGETALL")
+        .arg(ADDRESS_ALIASES_KEY)
+        .cmd("ZRANGEBYSCORE")
+        .arg(ADDRESS_ALIAS_EXPIRY_KEY)
+        .arg(format!("({}", now))
+        .arg("+inf");
     pipe.query_async(connection)
         .map_err(|err| error!("Error polling for routing table updates: {:?}", err))
         .and_then(
-            move |(_connection, (routes, static_routes)): (_, (RouteVec, RouteVec))| {
+            move |(_connection, (routes, static_routes, aliases, current_aliases)): (
+                _,
+                (RouteVec, RouteVec, RouteVec, Vec<String>),
+            )| {
                 trace!(
                     "Loaded routes from redis. Static routes: {:?}, other routes: {:?}",
                     static_routes,
                     routes
                 );
+                // Aliases that expired are ignored even before they are garbage collected
+                let current_aliases: HashSet<String> = HashSet::from_iter(current_aliases);
                 let routes = HashMap::from_iter(
-                    routes
+                    aliases
                         .into_iter()
+                        .filter(|(address, _account_id)| current_aliases.contains(address))
+                        // The routes for accounts' current addresses take precedence over aliases
+                        .chain(routes.into_iter())
                         // Having the static_routes inserted after ensures that they will overwrite
                         // any routes with the same prefix from the first set
                         .chain(static_routes.into_iter())
@@ -5,13 +5,16 @@ extern crate lazy_static;
 use bytes::Bytes;
 use env_logger;
 use futures::{future, Future};
-use interledger_api::{AccountDetails, NodeStore};
-use interledger_store_redis::{connect, connect_with_poll_interval, Account, RedisStore};
+use interledger_api::{AccountDetails, BatchError, NodeStore};
+use interledger_service::StoreError;
+use interledger_store_redis::{
+    connect, connect_with_poll_interval, Account, RedisStore, RedisStoreBuilder,
+};
 use parking_lot::Mutex;
 use redis;
 use std::{
     collections::HashMap,
-    time::{Duration, Instant},
+    time::{Duration, Instant, SystemTime},
 };
 use tokio::{runtime::Runtime, timer::Delay};
 
@@ -24,6 +27,12 @@ lazy_static! {
         asset_scale: 6,
         asset_code: "XYZ".to_string(),
         max_packet_amount: 1000,
+        max_packet_data: None,
+        priority: None,
+        max_value_per_second: None,
+        inactivity_timeout: None,
+        owner: None,
+        pending: false,
         min_balance: -1000,
         http_endpoint: Some("http://example.com/ilp".to_string()),
         http_incoming_authorization: Some("Bearer incoming_auth_token".to_string()),
@@ -31,6 +40,7 @@ lazy_static! {
         btp_uri: Some("btp+ws://example.com/btp".to_string()),
         btp_incoming_authorization: Some("btp_token".to_string()),
         is_admin: true,
+        read_only: false,
         xrp_address: Some("rELhRfZ7YS31jbouULKYLB64KmrizFuC3T".to_string()),
         settle_threshold: Some(0),
         settle_to: Some(-1000),
@@ -43,6 +53,12 @@ lazy_static! {
         asset_scale: 9,
         asset_code: "ABC".to_string(),
         max_packet_amount: 1_000_000,
+        max_packet_data: None,
+        priority: None,
+        max_value_per_second: None,
+        inactivity_timeout: None,
+        owner: None,
+        pending: false,
         min_balance: 0,
         http_endpoint: Some("http://example.com/ilp".to_string()),
         http_incoming_authorization: Some("Basic QWxhZGRpbjpPcGVuU2VzYW1l".to_string()),
@@ -50,6 +66,7 @@ lazy_static! {
         btp_uri: Some("btp+ws://example.com/btp".to_string()),
         btp_incoming_authorization: Some("other_btp_token".to_string()),
         is_admin: true,
+        read_only: false,
         xrp_address: Some("rMLwdY4w8FT8zCEUL9q9173NrvpLGLEFDu".to_string()),
         settle_threshold: Some(0),
         settle_to: Some(-1000),
@@ -68,10 +85,18 @@ fn test_store() -> impl Future<Item = (RedisStore, TestContext), Error = ()> {
             .clone()
             .insert_account(ACCOUNT_DETAILS_0.clone())
             .and_then(move |_| store_clone.insert_account(ACCOUNT_DETAILS_1.clone()))
+            .map_err(|err| panic!(err))
             .and_then(|_| Ok((store, context)))
     })
 }
 
+fn unwrap_accounts(accounts: Vec<Option<Account>>) -> Vec<Account> {
+    accounts
+        .into_iter()
+        .map(|account| account.expect("Account not found"))
+        .collect()
+}
+
 fn block_on<F>(f: F) -> Result<F::Item, F::Error>
 where
     F: Future + Send + 'static,
@@ -89,6 +114,7 @@ where
 
 mod connect_store {
     use super::*;
+    use interledger_service::{Account as AccountTrait, AccountStore};
 
     #[test]
     fn fails_if_db_unavailable() {
@@ -104,10 +130,95 @@ mod connect_store {
             ))
             .unwrap();
     }
+
+    #[test]
+    fn fails_if_an_interval_is_zero() {
+        let context = TestContext::new();
+        block_on(
+            RedisStoreBuilder::new(context.get_client_connection_info())
+                .gc_interval(Some(Duration::from_millis(0)))
+                .connect()
+                .then(move |result| {
+                    assert!(result.is_err());
+                    let _ = context;
+                    Ok::<(), ()>(())
+                }),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn loads_all_scripts() {
+        block_on(test_store().and_then(|(store, context)| {
+            store
+                .check_scripts()
+                .map_err(|err| panic!(err))
+                .and_then(move |_| {
+                    let _ = context;
+                    Ok(())
+                })
+        }))
+        .unwrap();
+    }
+
+    #[test]
+    fn elects_one_leader_among_stores_sharing_a_db() {
+        let context = TestContext::new();
+        let connection_info = context.get_client_connection_info();
+        block_on(
+            RedisStoreBuilder::new(connection_info.clone())
+                .leader_election(Some(Duration::from_secs(1)))
+                .connect()
+                .join(
+                    RedisStoreBuilder::new(connection_info)
+                        .leader_election(Some(Duration::from_secs(1)))
+                        .connect(),
+                )
+                .and_then(|(store_a, store_b)| {
+                    Delay::new(Instant::now() + Duration::from_millis(100))
+                        .map_err(|err| panic!(err))
+                        .and_then(move |_| {
+                            assert!(store_a.is_leader() != store_b.is_leader());
+                            let _ = context;
+                            Ok(())
+                        })
+                }),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn reads_from_replica_within_staleness_bound() {
+        let context = TestContext::new();
+        let connection_info = context.get_client_connection_info();
+        // The database acts as its own replica, so the replica is never behind
+        block_on(
+            RedisStoreBuilder::new(connection_info.clone())
+                .read_replica(connection_info, Duration::from_secs(1))
+                .connect()
+                .and_then(|store| {
+                    assert!(store.is_reading_from_replica());
+                    store
+                        .clone()
+                        .insert_account(ACCOUNT_DETAILS_0.clone())
+                        .map_err(|err| panic!(err))
+                        .and_then(move |account| store.get_accounts(vec![account.id()]))
+                        .and_then(move |accounts| {
+                            assert!(accounts[0].is_some());
+                            let _ = context;
+                            Ok(())
+                        })
+                }),
+        )
+        .unwrap();
+    }
 }
 
 mod insert_accounts {
     use super::*;
+    use interledger_ildcp::IldcpAccount;
+    use interledger_router::RouterStore;
+    use interledger_service::Account as AccountTrait;
 
     #[test]
     fn insert_accounts() {
@@ -143,6 +254,12 @@ mod insert_accounts {
                     asset_scale: 6,
                     asset_code: "XYZ".to_string(),
                     max_packet_amount: 1000,
+                    max_packet_data: None,
+                    priority: None,
+                    max_value_per_second: None,
+                    inactivity_timeout: None,
+                    owner: None,
+                    pending: false,
                     min_balance: -1000,
                     http_endpoint: None,
                     http_incoming_authorization: None,
@@ -150,6 +267,7 @@ mod insert_accounts {
                     btp_uri: None,
                     btp_incoming_authorization: None,
                     is_admin: false,
+                    read_only: false,
                     xrp_address: Some("rELhRfZ7YS31jbouULKYLB64KmrizFuC3T".to_string()),
                     settle_threshold: Some(0),
                     settle_to: Some(-1000),
@@ -159,7 +277,7 @@ mod insert_accounts {
                 })
                 .then(move |result| {
                     let _ = context;
-                    result
+                    result.map_err(|err| assert_eq!(err, StoreError::Conflict))
                 })
         }));
         assert!(result.is_err());
@@ -174,6 +292,12 @@ mod insert_accounts {
                     asset_scale: 6,
                     asset_code: "XYZ".to_string(),
                     max_packet_amount: 1000,
+                    max_packet_data: None,
+                    priority: None,
+                    max_value_per_second: None,
+                    inactivity_timeout: None,
+                    owner: None,
+                    pending: false,
                     min_balance: -1000,
                     http_endpoint: None,
                     http_incoming_authorization: Some("Bearer incoming_auth_token".to_string()),
@@ -181,6 +305,7 @@ mod insert_accounts {
                     btp_uri: None,
                     btp_incoming_authorization: None,
                     is_admin: false,
+                    read_only: false,
                     xrp_address: None,
                     settle_threshold: None,
                     settle_to: None,
@@ -190,7 +315,7 @@ mod insert_accounts {
                 })
                 .then(move |result| {
                     let _ = context;
-                    result
+                    result.map_err(|err| assert_eq!(err, StoreError::Conflict))
                 })
         }));
         assert!(result.is_err());
@@ -205,6 +330,12 @@ mod insert_accounts {
                     asset_scale: 6,
                     asset_code: "XYZ".to_string(),
                     max_packet_amount: 1000,
+                    max_packet_data: None,
+                    priority: None,
+                    max_value_per_second: None,
+                    inactivity_timeout: None,
+                    owner: None,
+                    pending: false,
                     min_balance: -1000,
                     http_endpoint: None,
                     http_incoming_authorization: None,
@@ -212,6 +343,7 @@ mod insert_accounts {
                     btp_uri: None,
                     btp_incoming_authorization: Some("btp_token".to_string()),
                     is_admin: false,
+                    read_only: false,
                     xrp_address: None,
                     settle_threshold: None,
                     settle_to: None,
@@ -221,26 +353,405 @@ mod insert_accounts {
                 })
                 .then(move |result| {
                     let _ = context;
-                    result
+                    result.map_err(|err| assert_eq!(err, StoreError::Conflict))
                 })
         }));
         assert!(result.is_err());
     }
+
+    #[test]
+    fn only_one_concurrent_insert_with_the_same_token_succeeds() {
+        block_on(test_store().and_then(|(store, context)| {
+            let inserts: Vec<_> = (0..5)
+                .map(|i| {
+                    store
+                        .insert_account(AccountDetails {
+                            ilp_address: format!("example.racer{}", i).into_bytes(),
+                            http_incoming_authorization: Some("Bearer racing_token".to_string()),
+                            btp_incoming_authorization: None,
+                            xrp_address: None,
+                            ..ACCOUNT_DETAILS_1.clone()
+                        })
+                        .then(Ok)
+                })
+                .collect();
+            future::join_all(inserts).and_then(move |results: Vec<Result<_, StoreError>>| {
+                let inserted = results.iter().filter(|result| result.is_ok()).count();
+                assert_eq!(inserted, 1);
+                for result in results.into_iter().filter(|result| result.is_err()) {
+                    assert_eq!(result.unwrap_err(), StoreError::Conflict);
+                }
+                let _ = context;
+                Ok(())
+            })
+        }))
+        .unwrap();
+    }
+
+    #[test]
+    fn inserts_batch_of_accounts_atomically() {
+        block_on(test_store().and_then(|(store, context)| {
+            let store_clone = store.clone();
+            let charlie = AccountDetails {
+                ilp_address: b"example.charlie".to_vec(),
+                http_incoming_authorization: Some("Bearer charlie_token".to_string()),
+                btp_incoming_authorization: None,
+                xrp_address: None,
+                ..ACCOUNT_DETAILS_1.clone()
+            };
+            let dave = AccountDetails {
+                ilp_address: b"example.dave".to_vec(),
+                http_incoming_authorization: Some("Bearer dave_token".to_string()),
+                ..charlie.clone()
+            };
+            store
+                .insert_accounts(vec![
+                    charlie.clone(),
+                    dave.clone(),
+                    // Conflicts with an existing account
+                    AccountDetails {
+                        ilp_address: b"example.eve".to_vec(),
+                        xrp_address: None,
+                        ..ACCOUNT_DETAILS_0.clone()
+                    },
+                    // Conflicts with another account in the batch
+                    AccountDetails {
+                        ilp_address: b"example.frank".to_vec(),
+                        ..dave.clone()
+                    },
+                ])
+                .then(move |result| {
+                    assert_eq!(
+                        result.unwrap_err(),
+                        BatchError::Entries(vec![
+                            (2, StoreError::Conflict),
+                            (3, StoreError::Conflict)
+                        ])
+                    );
+                    store_clone.get_all_accounts().map_err(|err| panic!(err))
+                })
+                .and_then(move |accounts| {
+                    assert_eq!(accounts.len(), 2);
+                    store
+                        .insert_accounts(vec![charlie, dave])
+                        .map_err(|err| panic!(err))
+                        .and_then(move |inserted| {
+                            assert_eq!(inserted.len(), 2);
+                            assert_eq!(inserted[0].client_address(), &b"example.charlie"[..]);
+                            assert_eq!(inserted[1].client_address(), &b"example.dave"[..]);
+                            let routes = store.routing_table();
+                            assert_eq!(routes[&b"example.dave"[..]], inserted[1].id());
+                            store.get_all_accounts().map_err(|err| panic!(err))
+                        })
+                })
+                .and_then(move |accounts| {
+                    assert_eq!(accounts.len(), 4);
+                    let _ = context;
+                    Ok(())
+                })
+        }))
+        .unwrap();
+    }
 }
 
 mod node_store {
     use super::*;
     use interledger_api::NodeStore;
+    use interledger_ildcp::IldcpAccount;
+    use interledger_router::RouterStore;
+    use interledger_service::{Account as AccountTrait, AccountStore};
     use interledger_service_util::ExchangeRateStore;
 
+    #[test]
+    fn changes_ilp_address_of_account_and_its_children() {
+        block_on(test_store().and_then(|(store, context)| {
+            let store_clone = store.clone();
+            let child = AccountDetails {
+                ilp_address: b"example.alice.child".to_vec(),
+                http_incoming_authorization: None,
+                btp_incoming_authorization: None,
+                xrp_address: None,
+                routing_relation: Some("Child".to_string()),
+                ..ACCOUNT_DETAILS_1.clone()
+            };
+            // Only child accounts are readdressed
+            let peer = AccountDetails {
+                ilp_address: b"example.alice.peer".to_vec(),
+                routing_relation: Some("Peer".to_string()),
+                ..child.clone()
+            };
+            store
+                .insert_accounts(vec![child, peer])
+                .map_err(|err| panic!(err))
+                .and_then(move |mut inserted| {
+                    let child = inserted.remove(0);
+                    store_clone
+                        .change_ilp_address(0, Bytes::from("example.carol"))
+                        .map_err(|err| panic!(err))
+                        .map(move |accounts| (store_clone, child, accounts))
+                })
+                .and_then(move |(store, child, accounts)| {
+                    let mut addresses: Vec<(u64, Bytes)> = accounts
+                        .iter()
+                        .map(|account| (account.id(), Bytes::from(account.client_address())))
+                        .collect();
+                    addresses.sort();
+                    assert_eq!(
+                        addresses,
+                        vec![
+                            (0, Bytes::from("example.carol")),
+                            (child.id(), Bytes::from("example.carol.child"))
+                        ]
+                    );
+
+                    // The previous addresses are still routed to the accounts during the grace period
+                    let routing_table = store.routing_table();
+                    assert_eq!(routing_table[&Bytes::from("example.carol")], 0);
+                    assert_eq!(routing_table[&Bytes::from("example.alice")], 0);
+                    assert_eq!(
+                        routing_table[&Bytes::from("example.carol.child")],
+                        child.id()
+                    );
+                    assert_eq!(
+                        routing_table[&Bytes::from("example.alice.child")],
+                        child.id()
+                    );
+                    assert_eq!(routing_table[&Bytes::from("example.bob")], 1);
+                    assert!(routing_table.contains_key(&Bytes::from("example.alice.peer")));
+                    assert!(!routing_table.contains_key(&Bytes::from("example.carol.peer")));
+                    let _ = context;
+                    Ok(())
+                })
+        }))
+        .unwrap();
+    }
+
+    #[test]
+    fn does_not_take_another_accounts_address() {
+        block_on(test_store().and_then(|(store, context)| {
+            let store_clone = store.clone();
+            store
+                .change_ilp_address(0, Bytes::from("example.bob"))
+                .then(move |result| {
+                    assert_eq!(result.unwrap_err(), StoreError::Conflict);
+                    store_clone
+                        .get_accounts(vec![0])
+                        .map(unwrap_accounts)
+                        .map_err(|err| panic!(err))
+                        .and_then(move |accounts| {
+                            assert_eq!(accounts[0].client_address(), b"example.alice");
+                            assert_eq!(store_clone.routing_table()[&Bytes::from("example.bob")], 1);
+                            let _ = context;
+                            Ok(())
+                        })
+                })
+        }))
+        .unwrap();
+    }
+
+    #[test]
+    fn errors_changing_ilp_address_of_unknown_account() {
+        let result = block_on(test_store().and_then(|(store, context)| {
+            store
+                .change_ilp_address(10, Bytes::from("example.carol"))
+                .then(move |result| {
+                    let _ = context;
+                    Ok(result)
+                })
+        }))
+        .unwrap();
+        assert_eq!(result.unwrap_err(), StoreError::NotFound);
+    }
+
     #[test]
     fn get_all_accounts() {
         block_on(test_store().and_then(|(store, context)| {
-            store.get_all_accounts().and_then(move |accounts| {
-                assert_eq!(accounts.len(), 2);
-                let _ = context;
-                Ok(())
-            })
+            store
+                .get_all_accounts()
+                .map_err(|err| panic!(err))
+                .and_then(move |accounts| {
+                    assert_eq!(accounts.len(), 2);
+                    let _ = context;
+                    Ok(())
+                })
+        }))
+        .unwrap();
+    }
+
+    #[test]
+    fn gets_all_accounts_in_more_than_one_batch() {
+        block_on(test_store().and_then(|(store, context)| {
+            let store_clone = store.clone();
+            let accounts = (0..250)
+                .map(|i| AccountDetails {
+                    ilp_address: format!("example.account{}", i).into_bytes(),
+                    http_incoming_authorization: None,
+                    btp_incoming_authorization: None,
+                    xrp_address: None,
+                    ..ACCOUNT_DETAILS_1.clone()
+                })
+                .collect();
+            store
+                .insert_accounts(accounts)
+                .map_err(|err| panic!(err))
+                .and_then(move |_| store_clone.get_all_accounts().map_err(|err| panic!(err)))
+                .and_then(move |accounts| {
+                    let ids: Vec<u64> = accounts.iter().map(|account| account.id()).collect();
+                    assert_eq!(ids, (0..252).collect::<Vec<u64>>());
+                    let _ = context;
+                    Ok(())
+                })
+        }))
+        .unwrap();
+    }
+
+    #[test]
+    fn indexes_account_ids_of_older_databases() {
+        block_on(test_store().and_then(|(store, context)| {
+            let accounts = (0..150)
+                .map(|i| AccountDetails {
+                    ilp_address: format!("example.account{}", i).into_bytes(),
+                    http_incoming_authorization: None,
+                    btp_incoming_authorization: None,
+                    xrp_address: None,
+                    ..ACCOUNT_DETAILS_1.clone()
+                })
+                .collect();
+            store
+                .insert_accounts(accounts)
+                .map_err(|err| panic!(err))
+                .and_then(move |_| {
+                    // Databases written before the index was kept do not have it
+                    let connection_info = context.get_client_connection_info();
+                    context
+                        .async_connection()
+                        .map_err(|err| panic!(err))
+                        .and_then(|connection| {
+                            redis::cmd("DEL")
+                                .arg("accounts:ids")
+                                .arg("accounts:ids:indexed")
+                                .query_async(connection)
+                                .map_err(|err| panic!(err))
+                                .and_then(|(_, _): (_, redis::Value)| Ok(()))
+                        })
+                        .and_then(move |_| connect(connection_info))
+                        .and_then(|store| store.get_all_accounts().map_err(|err| panic!(err)))
+                        .and_then(move |accounts| {
+                            assert_eq!(accounts.len(), 152);
+                            let _ = context;
+                            Ok(())
+                        })
+                })
+        }))
+        .unwrap();
+    }
+
+    #[test]
+    fn gets_accounts_by_owner() {
+        block_on(test_store().and_then(|(store, context)| {
+            let with_owner = |owner: &str| AccountDetails {
+                owner: Some(owner.to_string()),
+                ..ACCOUNT_DETAILS_0.clone()
+            };
+            let store_clone = store.clone();
+            store
+                .update_account(0, with_owner("alice"))
+                .and_then(move |_| store_clone.get_accounts_by_owner("alice"))
+                .and_then({
+                    let store = store.clone();
+                    move |accounts| {
+                        assert_eq!(accounts.len(), 1);
+                        assert_eq!(accounts[0].id(), 0);
+                        store.update_account(0, with_owner("bob"))
+                    }
+                })
+                .and_then({
+                    let store = store.clone();
+                    move |_| {
+                        store
+                            .get_accounts_by_owner("alice")
+                            .join(store.get_accounts_by_owner("bob"))
+                    }
+                })
+                .and_then({
+                    let store = store.clone();
+                    move |(alice, bob)| {
+                        assert!(alice.is_empty());
+                        assert_eq!(bob.len(), 1);
+                        store.delete_account(0)
+                    }
+                })
+                .and_then(move |_| store.get_accounts_by_owner("bob"))
+                .map_err(|err| panic!(err))
+                .and_then(move |bob| {
+                    assert!(bob.is_empty());
+                    let _ = context;
+                    Ok(())
+                })
+        }))
+        .unwrap();
+    }
+
+    #[test]
+    fn get_claims() {
+        block_on(test_store().and_then(|(store, context)| {
+            context
+                .async_connection()
+                .map_err(|err| panic!(err))
+                .and_then(|connection| {
+                    redis::cmd("LPUSH")
+                        .arg("claims:0")
+                        .arg(r#"{"direction":"outgoing","ledger":"xrp","amount":"1000","channel":"ABCD","signature":"1234","transaction_id":null,"timestamp":1}"#)
+                        .arg("not a claim")
+                        .arg(r#"{"direction":"incoming","ledger":"xrp","amount":"500","channel":null,"signature":null,"transaction_id":"EF01","timestamp":2}"#)
+                        .query_async(connection)
+                        .map_err(|err| panic!(err))
+                        .and_then(|(_, _): (_, redis::Value)| Ok(()))
+                })
+                .and_then(move |_| store.get_claims(0).map_err(|err| panic!(err)))
+                .and_then(move |claims| {
+                    assert_eq!(claims.len(), 2);
+                    assert_eq!(claims[0].direction, "incoming");
+                    assert_eq!(claims[0].transaction_id, Some("EF01".to_string()));
+                    assert_eq!(claims[1].amount, "1000");
+                    assert_eq!(claims[1].channel, Some("ABCD".to_string()));
+                    let _ = context;
+                    Ok(())
+                })
+        }))
+        .unwrap();
+    }
+
+    #[test]
+    fn get_store_usage() {
+        block_on(test_store().and_then(|(store, context)| {
+            context
+                .async_connection()
+                .map_err(|err| panic!(err))
+                .and_then(|connection| {
+                    redis::cmd("LPUSH")
+                        .arg("claims:1")
+                        .arg("claim")
+                        .query_async(connection)
+                        .map_err(|err| panic!(err))
+                        .and_then(|(_, _): (_, redis::Value)| Ok(()))
+                })
+                .and_then(move |_| store.get_store_usage().map_err(|err| panic!(err)))
+                .and_then(move |usage| {
+                    assert_eq!(usage.accounts, 2);
+                    assert_eq!(usage.balances, 2);
+                    assert_eq!(usage.routes, 2);
+                    assert_eq!(usage.settlement_claims, 1);
+                    assert_eq!(usage.in_flight, 0);
+                    assert!(usage.keys > 2);
+                    assert!(usage.used_memory.unwrap() > 0);
+                    assert!(usage
+                        .key_groups
+                        .iter()
+                        .any(|group| group.name == "accounts"));
+                    let _ = context;
+                    Ok(())
+                })
         }))
         .unwrap();
     }
@@ -253,6 +764,7 @@ mod node_store {
             assert!(rates.is_err());
             store
                 .set_rates(vec![("ABC".to_string(), 500.0), ("XYZ".to_string(), 0.005)])
+                .map_err(|err| panic!(err))
                 .and_then(move |_| {
                     let rates = store_clone.get_exchange_rates(&["XYZ", "ABC"]).unwrap();
                     assert_eq!(rates[0].to_string(), "0.005");
@@ -274,7 +786,10 @@ mod get_accounts {
     fn gets_single_account() {
         block_on(test_store().and_then(|(store, context)| {
             store.get_accounts(vec![1]).and_then(move |accounts| {
-                assert_eq!(accounts[0].client_address(), b"example.bob");
+                assert_eq!(
+                    accounts[0].as_ref().unwrap().client_address(),
+                    b"example.bob"
+                );
                 let _ = context;
                 Ok(())
             })
@@ -287,8 +802,14 @@ mod get_accounts {
         block_on(test_store().and_then(|(store, context)| {
             store.get_accounts(vec![1, 0]).and_then(move |accounts| {
                 // note reverse order is intentional
-                assert_eq!(accounts[0].client_address(), b"example.bob");
-                assert_eq!(accounts[1].client_address(), b"example.alice");
+                assert_eq!(
+                    accounts[0].as_ref().unwrap().client_address(),
+                    b"example.bob"
+                );
+                assert_eq!(
+                    accounts[1].as_ref().unwrap().client_address(),
+                    b"example.alice"
+                );
                 let _ = context;
                 Ok(())
             })
@@ -297,14 +818,20 @@ mod get_accounts {
     }
 
     #[test]
-    fn errors_for_unknown_accounts() {
-        let result = block_on(test_store().and_then(|(store, context)| {
-            store.get_accounts(vec![0, 2]).then(move |result| {
+    fn returns_none_for_unknown_accounts() {
+        block_on(test_store().and_then(|(store, context)| {
+            store.get_accounts(vec![0, 2]).and_then(move |accounts| {
+                assert_eq!(accounts.len(), 2);
+                assert_eq!(
+                    accounts[0].as_ref().unwrap().client_address(),
+                    b"example.alice"
+                );
+                assert!(accounts[1].is_none());
                 let _ = context;
-                result
+                Ok(())
             })
-        }));
-        assert!(result.is_err());
+        }))
+        .unwrap();
     }
 }
 
@@ -313,11 +840,36 @@ mod routes_and_rates {
     use interledger_router::RouterStore;
     use interledger_service_util::ExchangeRateStore;
 
+    #[test]
+    fn loads_routes_when_polling_is_disabled() {
+        block_on(test_store().and_then(|(_store, context)| {
+            RedisStoreBuilder::new(context.get_client_connection_info())
+                .disable_polling()
+                .poll_jitter(Duration::from_millis(100))
+                .connect()
+                .and_then(move |store| {
+                    let routing_table = store.routing_table();
+                    assert_eq!(routing_table.len(), 2);
+                    assert_eq!(
+                        *routing_table.get(&Bytes::from("example.bob")).unwrap(),
+                        1
+                    );
+                    let _ = context;
+                    Ok(())
+                })
+        }))
+        .unwrap();
+    }
+
     #[test]
     fn polls_for_route_updates() {
         let context = TestContext::new();
         block_on(
-            connect_with_poll_interval(context.get_client_connection_info(), 1).and_then(|store| {
+            connect_with_poll_interval(
+                context.get_client_connection_info(),
+                Duration::from_millis(1),
+            )
+            .and_then(|store| {
                 let connection = context.async_connection();
                 assert_eq!(store.routing_table().len(), 0);
                 let store_clone_1 = store.clone();
@@ -325,6 +877,7 @@ mod routes_and_rates {
                 store
                     .clone()
                     .insert_account(ACCOUNT_DETAILS_0.clone())
+                    .map_err(|err| panic!(err))
                     .and_then(move |_| {
                         let routing_table = store_clone_1.routing_table();
                         assert_eq!(routing_table.len(), 1);
@@ -337,6 +890,12 @@ mod routes_and_rates {
                             asset_scale: 6,
                             asset_code: "XYZ".to_string(),
                             max_packet_amount: 1000,
+                            max_packet_data: None,
+                            priority: None,
+                            max_value_per_second: None,
+                            inactivity_timeout: None,
+                            owner: None,
+                            pending: false,
                             min_balance: -1000,
                             http_endpoint: None,
                             http_incoming_authorization: None,
@@ -344,6 +903,7 @@ mod routes_and_rates {
                             btp_uri: None,
                             btp_incoming_authorization: None,
                             is_admin: false,
+                            read_only: false,
                             xrp_address: None,
                             settle_threshold: None,
                             settle_to: None,
@@ -351,6 +911,7 @@ mod routes_and_rates {
                             receive_routes: false,
                             routing_relation: None,
                         })
+                        .map_err(|err| panic!(err))
                     })
                     .and_then(move |_| {
                         let routing_table = store_clone_2.routing_table();
@@ -398,11 +959,80 @@ mod routes_and_rates {
         .unwrap();
     }
 
+    /// Resolves once the given number of stores' subscriptions to updates have been acknowledged
+    fn subscribed_stores(
+        context: &TestContext,
+        count: usize,
+    ) -> impl Future<Item = (), Error = ()> {
+        context.async_connection().and_then(move |connection| {
+            future::loop_fn(connection, move |connection| {
+                // The stores subscribe to the rates after the routes
+                redis::cmd("PUBSUB")
+                    .arg("NUMSUB")
+                    .arg("rates:updates")
+                    .query_async(connection)
+                    .map_err(|err| panic!(err))
+                    .and_then(
+                        move |(connection, (_channel, subscribers)): (_, (String, usize))| {
+                            if subscribers >= count {
+                                future::Either::A(future::ok(future::Loop::Break(())))
+                            } else {
+                                future::Either::B(
+                                    Delay::new(Instant::now() + Duration::from_millis(10))
+                                        .map_err(|err| panic!(err))
+                                        .map(move |_| future::Loop::Continue(connection)),
+                                )
+                            }
+                        },
+                    )
+            })
+        })
+    }
+
+    #[test]
+    fn subscribes_to_route_and_rate_updates() {
+        block_on(test_store().and_then(|(store, context)| {
+            RedisStoreBuilder::new(context.get_client_connection_info())
+                .disable_polling()
+                .connect()
+                .join(subscribed_stores(&context, 2))
+                .and_then(move |(other_store, _)| {
+                    assert!(other_store.get_exchange_rates(&["ABC"]).is_err());
+                    let store_clone = store.clone();
+                    store
+                        .set_static_route("example.static".to_string(), 1)
+                        .and_then(move |_| store_clone.set_rates(vec![("ABC".to_string(), 0.5f64)]))
+                        .map_err(|err| panic!(err))
+                        .and_then(|_| {
+                            Delay::new(Instant::now() + Duration::from_millis(100)).then(|_| Ok(()))
+                        })
+                        .and_then(move |_| {
+                            let routing_table = other_store.routing_table();
+                            assert_eq!(
+                                *routing_table.get(&Bytes::from("example.static")).unwrap(),
+                                1
+                            );
+                            assert_eq!(
+                                other_store.get_exchange_rates(&["ABC"]).unwrap(),
+                                vec![0.5]
+                            );
+                            let _ = context;
+                            Ok(())
+                        })
+                })
+        }))
+        .unwrap();
+    }
+
     #[test]
     fn polls_for_rate_updates() {
         let context = TestContext::new();
         block_on(
-            connect_with_poll_interval(context.get_client_connection_info(), 1).and_then(|store| {
+            connect_with_poll_interval(
+                context.get_client_connection_info(),
+                Duration::from_millis(1),
+            )
+            .and_then(|store| {
                 assert!(store.get_exchange_rates(&["ABC", "XYZ"]).is_err());
                 store
                     .clone()
@@ -410,6 +1040,7 @@ mod routes_and_rates {
                         ("ABC".to_string(), 0.5f64),
                         ("DEF".to_string(), 9_999_999_999.0f64),
                     ])
+                    .map_err(|err| panic!(err))
                     .and_then(|_| {
                         Delay::new(Instant::now() + Duration::from_millis(10)).then(|_| Ok(()))
                     })
@@ -442,27 +1073,34 @@ mod balances {
             store
                 .clone()
                 .get_accounts(vec![0, 1])
+                .map(unwrap_accounts)
                 .map_err(|_err| panic!("Unable to get accounts"))
                 .and_then(move |accounts| {
                     let account0 = accounts[0].clone();
                     let account1 = accounts[1].clone();
                     store
-                        .update_balances(accounts[0].clone(), 100, accounts[1].clone(), 500)
-                        .and_then(move |_| {
+                        .update_balances(
+                            accounts[0].clone(),
+                            100,
+                            accounts[1].clone(),
+                            500,
+                            SystemTime::now() + Duration::from_secs(30),
+                        )
+                        .and_then(move |update_id| {
                             store_clone_1
                                 .clone()
                                 .get_balance(accounts[0].clone())
                                 .join(store_clone_1.clone().get_balance(accounts[1].clone()))
-                                .and_then(|(balance0, balance1)| {
+                                .and_then(move |(balance0, balance1)| {
                                     assert_eq!(balance0, -100);
                                     assert_eq!(balance1, 500);
-                                    Ok(())
+                                    Ok(update_id)
                                 })
                         })
-                        .and_then(move |_| {
+                        .and_then(move |update_id| {
                             store_clone_2
                                 .clone()
-                                .undo_balance_update(account0.clone(), 100, account1.clone(), 500)
+                                .undo_balance_update(update_id)
                                 .and_then(move |_| {
                                     store_clone_2
                                         .clone()
@@ -476,6 +1114,7 @@ mod balances {
                                         })
                                 })
                         })
+                        .map_err(|err| panic!(err))
                 })
         }))
         .unwrap();
@@ -487,12 +1126,19 @@ mod balances {
             store
                 .clone()
                 .get_accounts(vec![0, 1])
+                .map(unwrap_accounts)
                 .map_err(|_err| panic!("Unable to get accounts"))
                 .and_then(move |accounts| {
                     store
-                        .update_balances(accounts[0].clone(), 10000, accounts[1].clone(), 500)
+                        .update_balances(
+                            accounts[0].clone(),
+                            10000,
+                            accounts[1].clone(),
+                            500,
+                            SystemTime::now() + Duration::from_secs(30),
+                        )
                         .then(move |result| {
-                            assert!(result.is_err());
+                            assert_eq!(result.unwrap_err(), StoreError::InsufficientBalance);
                             let _ = context;
                             Ok(())
                         })
@@ -500,92 +1146,1544 @@ mod balances {
         }))
         .unwrap()
     }
-}
 
-mod from_btp {
+    #[test]
+    fn does_not_roll_back_committed_packets() {
+        block_on(test_store().and_then(|(store, context)| {
+            let store_clone = store.clone();
+            store
+                .clone()
+                .get_accounts(vec![0, 1])
+                .map(unwrap_accounts)
+                .map_err(|_err| panic!("Unable to get accounts"))
+                .and_then(move |accounts| {
+                    // The commit removes the update from the journal right away
+                    store
+                        .update_balances(
+                            accounts[0].clone(),
+                            100,
+                            accounts[1].clone(),
+                            500,
+                            SystemTime::now() - Duration::from_secs(120),
+                        )
+                        .and_then(move |update_id| store.commit_balance_update(update_id))
+                        .map_err(|err| panic!(err))
+                        .and_then(move |_| store_clone.recover_in_flight_packets())
+                        .and_then(move |recovered| {
+                            assert_eq!(recovered, 0);
+                            let _ = context;
+                            Ok(())
+                        })
+                })
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn rolls_back_expired_in_flight_packets() {
+        block_on(test_store().and_then(|(store, context)| {
+            let store_clone = store.clone();
+            store
+                .clone()
+                .get_accounts(vec![0, 1])
+                .map(unwrap_accounts)
+                .map_err(|_err| panic!("Unable to get accounts"))
+                .and_then(move |accounts| {
+                    let expired = store.update_balances(
+                        accounts[0].clone(),
+                        100,
+                        accounts[1].clone(),
+                        500,
+                        SystemTime::now() - Duration::from_secs(120),
+                    );
+                    let pending = store.update_balances(
+                        accounts[0].clone(),
+                        10,
+                        accounts[1].clone(),
+                        50,
+                        SystemTime::now() + Duration::from_secs(30),
+                    );
+                    expired
+                        .join(pending)
+                        .map_err(|err| panic!(err))
+                        .and_then(move |(expired, _pending)| {
+                            store_clone
+                                .recover_in_flight_packets()
+                                .and_then(move |recovered| {
+                                    assert_eq!(recovered, 1);
+                                    // Rejecting the packet afterwards must not roll it back again
+                                    store_clone
+                                        .undo_balance_update(expired)
+                                        .map_err(|err| panic!(err))
+                                        .and_then(move |_| {
+                                            store_clone
+                                                .get_balance(accounts[0].clone())
+                                                .join(store_clone.get_balance(accounts[1].clone()))
+                                                .map_err(|err| panic!(err))
+                                        })
+                                })
+                        })
+                        .and_then(move |(balance0, balance1)| {
+                            assert_eq!(balance0, -10);
+                            assert_eq!(balance1, 50);
+                            let _ = context;
+                            Ok(())
+                        })
+                })
+        }))
+        .unwrap()
+    }
+}
+
+mod expiry {
+    use super::*;
+    use interledger_router::RouterStore;
+    use interledger_service::AccountStore;
+    use interledger_service_util::BalanceStore;
+
+    #[test]
+    fn deletes_inactive_accounts_without_a_balance() {
+        block_on(test_store().and_then(|(store, context)| {
+            let store_clone = store.clone();
+            let details = |ilp_address: &[u8]| AccountDetails {
+                ilp_address: ilp_address.to_vec(),
+                http_incoming_authorization: None,
+                btp_incoming_authorization: None,
+                xrp_address: None,
+                inactivity_timeout: Some(1),
+                owner: None,
+                pending: false,
+                ..ACCOUNT_DETAILS_0.clone()
+            };
+            store
+                .insert_account(details(b"example.charlie"))
+                .join(store.insert_account(details(b"example.dave")))
+                .map_err(|err| panic!(err))
+                .and_then(move |(_charlie, dave)| {
+                    store
+                        .get_accounts(vec![0])
+                        .map(unwrap_accounts)
+                        .map_err(|_err| panic!("Unable to get accounts"))
+                        .and_then(move |accounts| {
+                            // Dave is owed money, so it must not be deleted
+                            store
+                                .update_balances(
+                                    accounts[0].clone(),
+                                    100,
+                                    dave,
+                                    100,
+                                    SystemTime::now() + Duration::from_secs(30),
+                                )
+                                .map_err(|err| panic!(err))
+                        })
+                })
+                .and_then(|_| {
+                    Delay::new(Instant::now() + Duration::from_secs(2)).map_err(|err| panic!(err))
+                })
+                .and_then(move |_| {
+                    store_clone
+                        .expire_accounts()
+                        .map(move |deleted| (store_clone, deleted))
+                })
+                .and_then(move |(store, deleted)| {
+                    assert_eq!(deleted, 1);
+                    let routing_table = store.routing_table();
+                    assert!(!routing_table.contains_key(&Bytes::from("example.charlie")));
+                    assert!(routing_table.contains_key(&Bytes::from("example.dave")));
+                    let _ = context;
+                    Ok(())
+                })
+        }))
+        .unwrap()
+    }
+}
+
+mod from_btp {
+    use super::*;
+    use interledger_btp::BtpStore;
+    use interledger_router::RouterStore;
+    use interledger_service::Account as AccountTrait;
+
+    #[test]
+    fn gets_account_from_btp_token() {
+        block_on(test_store().and_then(|(store, context)| {
+            store
+                .get_account_from_btp_token("other_btp_token")
+                .and_then(move |account| {
+                    assert_eq!(account.id(), 1);
+                    let _ = context;
+                    Ok(())
+                })
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn errors_on_unknown_btp_token() {
+        let result = block_on(test_store().and_then(|(store, context)| {
+            store
+                .get_account_from_btp_token("unknown_btp_token")
+                .then(move |result| {
+                    let _ = context;
+                    result
+                })
+        }));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn updated_account_is_found_by_its_new_btp_token() {
+        block_on(test_store().and_then(|(store, context)| {
+            let store_clone = store.clone();
+            store
+                .update_account(
+                    1,
+                    AccountDetails {
+                        btp_incoming_authorization: Some("new_btp_token".to_string()),
+                        ..ACCOUNT_DETAILS_1.clone()
+                    },
+                )
+                .map_err(|err| panic!(err))
+                .and_then(move |_| {
+                    store_clone
+                        .get_account_from_btp_token("other_btp_token")
+                        .then(move |result| {
+                            assert!(result.is_err());
+                            store_clone.get_account_from_btp_token("new_btp_token")
+                        })
+                })
+                .and_then(move |account| {
+                    assert_eq!(account.id(), 1);
+                    let _ = context;
+                    Ok(())
+                })
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn deleted_account_is_not_found_or_routed_to() {
+        block_on(test_store().and_then(|(store, context)| {
+            let store_clone = store.clone();
+            store
+                .delete_account(1)
+                .map_err(|err| panic!(err))
+                .and_then(move |account| {
+                    assert_eq!(account.id(), 1);
+                    assert!(!store_clone
+                        .routing_table()
+                        .contains_key(&Bytes::from("example.bob")));
+                    store_clone
+                        .get_account_from_btp_token("other_btp_token")
+                        .then(move |result| {
+                            assert!(result.is_err());
+                            let _ = context;
+                            Ok(())
+                        })
+                })
+        }))
+        .unwrap()
+    }
+}
+
+mod pending {
     use super::*;
     use interledger_btp::BtpStore;
+    use interledger_router::RouterStore;
     use interledger_service::Account as AccountTrait;
 
     #[test]
-    fn gets_account_from_btp_token() {
+    fn pending_account_is_not_found_or_routed_to_until_approved() {
+        block_on(test_store().and_then(|(store, context)| {
+            let store_clone = store.clone();
+            store
+                .update_account(
+                    1,
+                    AccountDetails {
+                        pending: true,
+                        ..ACCOUNT_DETAILS_1.clone()
+                    },
+                )
+                .map_err(|err| panic!(err))
+                .and_then(move |_| {
+                    assert!(!store_clone
+                        .routing_table()
+                        .contains_key(&Bytes::from("example.bob")));
+                    store_clone
+                        .get_account_from_btp_token("other_btp_token")
+                        .then(move |result| {
+                            assert!(result.is_err());
+                            store_clone
+                                .update_account(1, ACCOUNT_DETAILS_1.clone())
+                                .map_err(|err| panic!(err))
+                                .and_then(move |_| {
+                                    assert!(store_clone
+                                        .routing_table()
+                                        .contains_key(&Bytes::from("example.bob")));
+                                    store_clone.get_account_from_btp_token("other_btp_token")
+                                })
+                        })
+                })
+                .and_then(move |account| {
+                    assert_eq!(account.id(), 1);
+                    let _ = context;
+                    Ok(())
+                })
+        }))
+        .unwrap()
+    }
+}
+
+mod scheduled_payments {
+    use super::*;
+    use interledger_api::{ScheduleStatus, ScheduleStore, ScheduledPayment};
+
+    #[test]
+    fn only_returns_payments_that_are_due_and_scheduled() {
+        block_on(test_store().and_then(|(store, context)| {
+            let store_clone = store.clone();
+            let later =
+                ScheduledPayment::new("0".to_string(), "$later".to_string(), 10, 200, None, 0);
+            store
+                .add_scheduled_payment(ScheduledPayment::new(
+                    "0".to_string(),
+                    "$now".to_string(),
+                    10,
+                    100,
+                    Some(60),
+                    0,
+                ))
+                .join(store.add_scheduled_payment(later))
+                .map_err(|err| panic!(err))
+                .and_then(move |(now, later)| {
+                    assert_ne!(now.id, later.id);
+                    store_clone
+                        .get_due_scheduled_payments(150)
+                        .map_err(|err| panic!(err))
+                        .and_then(move |due| {
+                            assert_eq!(due, vec![now.clone()]);
+                            let mut cancelled = now;
+                            cancelled.status = ScheduleStatus::Cancelled;
+                            store_clone
+                                .update_scheduled_payment(cancelled)
+                                .and_then(move |cancelled| {
+                                    store_clone
+                                        .get_due_scheduled_payments(300)
+                                        .join(store_clone.get_scheduled_payments(0))
+                                        .map(move |(due, all)| (cancelled, due, all))
+                                })
+                                .map_err(|err| panic!(err))
+                                .and_then(move |(cancelled, due, all)| {
+                                    assert_eq!(due, vec![later.clone()]);
+                                    assert_eq!(all, vec![cancelled, later]);
+                                    let _ = context;
+                                    Ok(())
+                                })
+                        })
+                })
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn does_not_save_outdated_copies() {
+        block_on(test_store().and_then(|(store, context)| {
+            let store_clone = store.clone();
+            store
+                .add_scheduled_payment(ScheduledPayment::new(
+                    "0".to_string(),
+                    "$now".to_string(),
+                    10,
+                    100,
+                    None,
+                    0,
+                ))
+                .and_then(move |payment| {
+                    let mut cancelled = payment.clone();
+                    cancelled.status = ScheduleStatus::Cancelled;
+                    store_clone
+                        .update_scheduled_payment(cancelled)
+                        .and_then(move |_| store_clone.update_scheduled_payment(payment))
+                })
+                .then(move |result| {
+                    assert_eq!(result.unwrap_err(), StoreError::Conflict);
+                    let _ = context;
+                    Ok(())
+                })
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn rejects_invalid_account_ids() {
+        block_on(test_store().and_then(|(store, context)| {
+            store
+                .add_scheduled_payment(ScheduledPayment::new(
+                    "abc".to_string(),
+                    "$now".to_string(),
+                    10,
+                    100,
+                    None,
+                    0,
+                ))
+                .then(move |result| {
+                    assert_eq!(result.unwrap_err(), StoreError::InvalidData);
+                    let _ = context;
+                    Ok(())
+                })
+        }))
+        .unwrap()
+    }
+}
+
+mod payments {
+    use super::*;
+    use interledger_api::PaymentStore;
+    use interledger_service::AccountStore;
+    use interledger_stream::StreamConnectionStore;
+    use std::time::UNIX_EPOCH;
+
+    #[test]
+    fn aggregates_packets_into_payments() {
+        block_on(test_store().and_then(|(store, context)| {
+            let store_clone = store.clone();
+            store
+                .get_accounts(vec![0])
+                .map(unwrap_accounts)
+                .and_then(move |accounts| {
+                    let account = accounts[0].clone();
+                    let packets = vec![(&b"abc"[..], 100), (&b"abc"[..], 50), (&b"def"[..], 10)];
+                    future::join_all(packets.into_iter().map({
+                        let store = store_clone.clone();
+                        let account = account.clone();
+                        move |(connection_id, amount)| {
+                            store.add_amount_received(&account, connection_id, None, amount)
+                        }
+                    }))
+                    .and_then(move |totals| {
+                        assert_eq!(totals, vec![100, 150, 10]);
+                        store_clone
+                            .close_connection(&account, b"abc")
+                            .and_then(move |_| {
+                                store_clone
+                                    .get_incoming_payment("abc".to_string())
+                                    .join(store_clone.get_incoming_payments(0))
+                                    .map_err(|err| panic!(err))
+                            })
+                    })
+                })
+                .map(move |(payment, payments)| {
+                    let payment = payment.unwrap();
+                    assert_eq!(payment.account_id, "0");
+                    assert_eq!(payment.received, 150);
+                    assert_eq!(payment.asset_code, Some("XYZ".to_string()));
+                    assert_eq!(payment.asset_scale, Some(6));
+                    assert!(payment.completed);
+                    assert!(payment.started_at <= payment.updated_at);
+                    assert_eq!(payments.len(), 2);
+                    assert!(payments.iter().any(|payment| payment.connection_id == "def"
+                        && payment.received == 10
+                        && !payment.completed));
+                    let _ = context;
+                })
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn lists_payments_recorded_without_asset() {
+        block_on(test_store().and_then(|(store, context)| {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs();
+            context
+                .async_connection()
+                .map_err(|err| panic!(err))
+                .and_then(move |connection| {
+                    let mut pipe = redis::pipe();
+                    pipe.cmd("HMSET")
+                        .arg("stream_connections:abc")
+                        .arg("account_id")
+                        .arg(0)
+                        .arg("received")
+                        .arg(100)
+                        .arg("started_at")
+                        .arg(now)
+                        .arg("updated_at")
+                        .arg(now)
+                        .ignore()
+                        .cmd("ZADD")
+                        .arg("payments:0")
+                        .arg(now)
+                        .arg("abc")
+                        .ignore();
+                    pipe.query_async(connection)
+                        .map_err(|err| panic!(err))
+                        .and_then(move |(_connection, _): (_, redis::Value)| {
+                            store.get_incoming_payments(0).map_err(|err| panic!(err))
+                        })
+                })
+                .map(move |payments| {
+                    assert_eq!(payments.len(), 1);
+                    assert_eq!(payments[0].received, 100);
+                    assert_eq!(payments[0].asset_code, None);
+                    assert_eq!(payments[0].asset_scale, None);
+                    let _ = context;
+                })
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn gets_recent_payments_of_all_accounts() {
+        block_on(test_store().and_then(|(store, context)| {
+            let store_clone = store.clone();
+            store
+                .get_accounts(vec![0, 1])
+                .map(unwrap_accounts)
+                .and_then(move |accounts| {
+                    let packets = vec![
+                        (accounts[0].clone(), &b"abc"[..]),
+                        (accounts[1].clone(), &b"def"[..]),
+                        (accounts[0].clone(), &b"ghi"[..]),
+                    ];
+                    future::join_all(packets.into_iter().map({
+                        let store = store_clone.clone();
+                        move |(account, connection_id)| {
+                            store.add_amount_received(&account, connection_id, None, 100)
+                        }
+                    }))
+                    .and_then(move |_| {
+                        store_clone
+                            .get_recent_payments(2)
+                            .join(store_clone.get_recent_payments(10))
+                            .map_err(|err| panic!(err))
+                    })
+                })
+                .map(move |(recent, all)| {
+                    assert_eq!(recent.len(), 2);
+                    assert_eq!(all.len(), 3);
+                    assert!(all
+                        .windows(2)
+                        .all(|payments| payments[0].started_at >= payments[1].started_at));
+                    let _ = context;
+                })
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn records_metadata_sent_with_payment() {
+        block_on(test_store().and_then(|(store, context)| {
+            let store_clone = store.clone();
+            store
+                .get_accounts(vec![0])
+                .map(unwrap_accounts)
+                .and_then(move |accounts| {
+                    store_clone
+                        .add_amount_received(&accounts[0], b"abc", None, 100)
+                        .and_then(move |_| {
+                            store_clone
+                                .set_metadata(b"abc", "order-1")
+                                .and_then(move |_| {
+                                    store_clone
+                                        .get_incoming_payments(0)
+                                        .map_err(|err| panic!(err))
+                                })
+                        })
+                })
+                .map(move |payments| {
+                    assert_eq!(payments.len(), 1);
+                    assert_eq!(payments[0].metadata, Some("order-1".to_string()));
+                    assert_eq!(payments[0].received, 100);
+                    let _ = context;
+                })
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn rejects_amounts_that_overflow_the_total() {
+        block_on(test_store().and_then(|(store, context)| {
+            let store_clone = store.clone();
+            store
+                .get_accounts(vec![0])
+                .map(unwrap_accounts)
+                .and_then(move |accounts| {
+                    let account = accounts[0].clone();
+                    store_clone
+                        .add_amount_received(&account, b"abc", None, i64::max_value() as u64)
+                        .and_then(move |total| {
+                            assert_eq!(total, i64::max_value() as u64);
+                            store_clone
+                                .add_amount_received(&account, b"abc", None, 1)
+                                .then(move |result| {
+                                    assert!(result.is_err());
+                                    store_clone.add_amount_received(&account, b"abc", None, 0)
+                                })
+                        })
+                })
+                .map(move |total| {
+                    assert_eq!(total, i64::max_value() as u64);
+                    let _ = context;
+                })
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn records_connection_tag() {
+        block_on(test_store().and_then(|(store, context)| {
+            let store_clone = store.clone();
+            store
+                .get_accounts(vec![0])
+                .map(unwrap_accounts)
+                .and_then(move |accounts| {
+                    store_clone
+                        .add_amount_received(&accounts[0], b"abc", Some(b"invoice123"), 100)
+                        .and_then(move |_| {
+                            store_clone
+                                .get_incoming_payments(0)
+                                .map_err(|err| panic!(err))
+                        })
+                })
+                .map(move |payments| {
+                    assert_eq!(payments.len(), 1);
+                    assert_eq!(payments[0].tag, Some("invoice123".to_string()));
+                    let _ = context;
+                })
+        }))
+        .unwrap()
+    }
+}
+
+mod refunds {
+    use super::*;
+    use interledger_api::{PaymentStore, RefundStatus, RefundStore};
+    use interledger_service::AccountStore;
+    use interledger_stream::StreamConnectionStore;
+
+    #[test]
+    fn refunds_add_up_to_at_most_the_payment() {
+        block_on(test_store().and_then(|(store, context)| {
+            let store_clone = store.clone();
+            store
+                .get_accounts(vec![0])
+                .map(unwrap_accounts)
+                .and_then(move |accounts| {
+                    store_clone
+                        .add_amount_received(&accounts[0], b"abc", None, 100)
+                        .and_then(move |_| {
+                            store_clone
+                                .add_refund("abc", "0", Some(10), 0)
+                                .then(move |result| {
+                                    // The sender did not give a return pointer
+                                    assert_eq!(result.unwrap_err(), StoreError::InvalidData);
+                                    store_clone
+                                        .set_return_pointer(b"abc", "$sender.example")
+                                        .map(move |_| store_clone)
+                                })
+                        })
+                })
+                .and_then(|store| {
+                    let store_clone = store.clone();
+                    store
+                        .add_refund("abc", "1", None, 0)
+                        .then(move |result| {
+                            assert_eq!(result.unwrap_err(), StoreError::NotFound);
+                            store_clone
+                                .add_refund("abc", "0", Some(60), 0)
+                                .and_then(move |first| {
+                                    store_clone
+                                        .add_refund("abc", "0", Some(50), 0)
+                                        .then(move |result| Ok((first, result)))
+                                })
+                        })
+                        .map_err(|err| panic!(err))
+                        .and_then(move |(mut first, too_much)| {
+                            assert_eq!(too_much.unwrap_err(), StoreError::InsufficientBalance);
+                            assert_eq!(first.return_pointer, "$sender.example");
+                            assert_eq!(first.status, RefundStatus::Sending);
+                            // Only part of the refund was sent, so the rest can be refunded again
+                            first.source_amount_sent = 20;
+                            first.status = RefundStatus::Partial;
+                            let store_clone = store.clone();
+                            store
+                                .finish_refund(first.clone())
+                                .and_then(move |_| store_clone.add_refund("abc", "0", None, 0))
+                                .and_then(move |second| {
+                                    assert_eq!(second.amount, 80);
+                                    store
+                                        .get_incoming_payment("abc".to_string())
+                                        .join(store.get_refunds("abc"))
+                                        .map(move |(payment, refunds)| {
+                                            assert_eq!(payment.unwrap().refunded, 100);
+                                            assert_eq!(refunds, vec![first, second]);
+                                            let _ = context;
+                                        })
+                                })
+                                .map_err(|err| panic!(err))
+                        })
+                })
+        }))
+        .unwrap()
+    }
+}
+
+mod payment_links {
+    use super::*;
+    use interledger_api::{PaymentLink, PaymentLinkStatus, PaymentLinkStore};
+
+    #[test]
+    fn link_is_paid_once_in_full() {
+        block_on(test_store().and_then(|(store, context)| {
+            let store_clone = store.clone();
+            store
+                .add_payment_link(PaymentLink::new(
+                    "0".to_string(),
+                    100,
+                    Some("Order 1".to_string()),
+                    0,
+                    1000,
+                ))
+                .map_err(|err| panic!(err))
+                .and_then(move |link| {
+                    let id = link.id.clone();
+                    let store = store_clone.clone();
+                    store_clone
+                        .pay_payment_link(&id, "1", 10, 10)
+                        .then(move |wrong_account| {
+                            assert!(wrong_account.is_err());
+                            store.pay_payment_link(&id, "0", 60, 10)
+                        })
+                        .and_then(move |partly_paid| {
+                            assert_eq!(partly_paid.received, 60);
+                            assert_eq!(partly_paid.status, PaymentLinkStatus::Open);
+                            let id = link.id.clone();
+                            let store = store_clone.clone();
+                            store_clone
+                                .pay_payment_link(&link.id, "0", 50, 20)
+                                .then(move |too_much| {
+                                    assert!(too_much.is_err());
+                                    store.pay_payment_link(&id, "0", 40, 20)
+                                })
+                                .and_then(move |paid| {
+                                    assert_eq!(paid.status, PaymentLinkStatus::Paid);
+                                    assert_eq!(paid.paid_at, Some(20));
+                                    assert_eq!(paid.description, Some("Order 1".to_string()));
+                                    store_clone
+                                        .pay_payment_link(&link.id, "0", 1, 30)
+                                        .then(move |already_paid| {
+                                            assert!(already_paid.is_err());
+                                            store_clone
+                                                .get_payment_links(0)
+                                                .map_err(|err| panic!(err))
+                                        })
+                                        .map(move |links| {
+                                            assert_eq!(links, vec![paid]);
+                                            let _ = context;
+                                        })
+                                })
+                        })
+                        .map_err(|err| panic!(err))
+                })
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn payments_that_were_not_fulfilled_can_be_taken_back() {
+        block_on(test_store().and_then(|(store, context)| {
+            let store_clone = store.clone();
+            store
+                .add_payment_link(PaymentLink::new("0".to_string(), 100, None, 0, 1000))
+                .map_err(|err| panic!(err))
+                .and_then(move |link| {
+                    let id = link.id.clone();
+                    let store = store_clone.clone();
+                    store_clone
+                        .pay_payment_link(&id, "0", 100, 10)
+                        .map_err(|err| panic!(err))
+                        .and_then(move |paid| {
+                            assert_eq!(paid.status, PaymentLinkStatus::Paid);
+                            store
+                                .undo_payment_link_payment(&id, 100)
+                                .and_then(move |_| {
+                                    store.get_payment_link(&id).map_err(|err| panic!(err))
+                                })
+                        })
+                        .map(move |link| {
+                            let link = link.unwrap();
+                            assert_eq!(link.received, 0);
+                            assert_eq!(link.status, PaymentLinkStatus::Open);
+                            assert_eq!(link.paid_at, None);
+                            let _ = context;
+                        })
+                })
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn paid_link_is_only_taken_once() {
+        block_on(test_store().and_then(|(store, context)| {
+            let store_clone = store.clone();
+            store
+                .add_payment_link(PaymentLink::new("0".to_string(), 100, None, 0, 1000))
+                .map_err(|err| panic!(err))
+                .and_then(move |link| {
+                    let id = link.id.clone();
+                    let store = store_clone.clone();
+                    store_clone
+                        .take_paid_payment_link(&id)
+                        .and_then(move |open| {
+                            assert_eq!(open, None);
+                            store
+                                .pay_payment_link(&id, "0", 100, 10)
+                                .map_err(|err| panic!(err))
+                                .and_then(move |_| {
+                                    store
+                                        .take_paid_payment_link(&id)
+                                        .join(store.take_paid_payment_link(&id))
+                                })
+                        })
+                        .map(move |(first, second)| {
+                            assert_eq!(first.unwrap().status, PaymentLinkStatus::Paid);
+                            assert_eq!(second, None);
+                            let _ = context;
+                        })
+                })
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn expired_link_cannot_be_paid() {
+        block_on(test_store().and_then(|(store, context)| {
+            let store_clone = store.clone();
+            store
+                .add_payment_link(PaymentLink::new("0".to_string(), 100, None, 0, 1000))
+                .map_err(|err| panic!(err))
+                .and_then(move |link| {
+                    store_clone
+                        .pay_payment_link(&link.id, "0", 10, 1000)
+                        .then(move |result| {
+                            assert!(result.is_err());
+                            let _ = context;
+                            Ok(())
+                        })
+                })
+        }))
+        .unwrap()
+    }
+}
+
+mod streaming_sessions {
+    use super::*;
+    use interledger_api::{SessionStatus, StreamingSession, StreamingSessionStore};
+
+    #[test]
+    fn only_returns_active_sessions_as_active() {
+        block_on(test_store().and_then(|(store, context)| {
+            let store_clone = store.clone();
+            store
+                .add_streaming_session(StreamingSession::new(
+                    "0".to_string(),
+                    "$alice".to_string(),
+                    60,
+                    100,
+                ))
+                .join(store.add_streaming_session(StreamingSession::new(
+                    "0".to_string(),
+                    "$bob".to_string(),
+                    120,
+                    100,
+                )))
+                .map_err(|err| panic!(err))
+                .and_then(move |(alice, bob)| {
+                    let mut stopped = alice;
+                    stopped.stop(SessionStatus::Stopped, 160);
+                    store_clone
+                        .update_streaming_session(stopped)
+                        .and_then(move |stopped| {
+                            store_clone
+                                .get_active_streaming_sessions()
+                                .join(store_clone.get_streaming_sessions(0))
+                                .map(move |(active, all)| (stopped, active, all))
+                        })
+                        .map_err(|err| panic!(err))
+                        .and_then(move |(stopped, active, all)| {
+                            assert_eq!(active, vec![bob.clone()]);
+                            assert_eq!(all, vec![stopped, bob]);
+                            let _ = context;
+                            Ok(())
+                        })
+                })
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn does_not_save_outdated_copies() {
+        block_on(test_store().and_then(|(store, context)| {
+            let store_clone = store.clone();
+            store
+                .add_streaming_session(StreamingSession::new(
+                    "0".to_string(),
+                    "$alice".to_string(),
+                    60,
+                    100,
+                ))
+                .and_then(move |session| {
+                    let mut stopped = session.clone();
+                    stopped.stop(SessionStatus::Stopped, 160);
+                    store_clone
+                        .update_streaming_session(stopped)
+                        .and_then(move |_| store_clone.update_streaming_session(session))
+                })
+                .then(move |result| {
+                    assert_eq!(result.unwrap_err(), StoreError::Conflict);
+                    let _ = context;
+                    Ok(())
+                })
+        }))
+        .unwrap()
+    }
+}
+
+mod from_http {
+    use super::*;
+    use interledger_http::HttpStore;
+    use interledger_service::Account as AccountTrait;
+
+    #[test]
+    fn gets_account_from_http_bearer_token() {
+        block_on(test_store().and_then(|(store, context)| {
+            store
+                .get_account_from_http_auth("Bearer incoming_auth_token")
+                .and_then(move |account| {
+                    assert_eq!(account.id(), 0);
+                    let _ = context;
+                    Ok(())
+                })
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn gets_account_from_http_basic_auth() {
+        block_on(test_store().and_then(|(store, context)| {
+            store
+                .get_account_from_http_auth("Basic QWxhZGRpbjpPcGVuU2VzYW1l")
+                .and_then(move |account| {
+                    assert_eq!(account.id(), 1);
+                    let _ = context;
+                    Ok(())
+                })
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn errors_on_unknown_http_auth() {
+        let result = block_on(test_store().and_then(|(store, context)| {
+            store
+                .get_account_from_http_auth("Bearer unknown_token")
+                .then(move |result| {
+                    let _ = context;
+                    result
+                })
+        }));
+        assert!(result.is_err());
+    }
+}
+
+mod api_keys {
+    use super::*;
+    use interledger_api::{ApiKeyStore, ApiScope};
+    use interledger_http::HttpStore;
+    use interledger_service::Account as AccountTrait;
+
+    #[test]
+    fn api_key_acts_on_behalf_of_its_account() {
+        block_on(test_store().and_then(|(store, context)| {
+            let store_clone = store.clone();
+            store
+                .create_api_key(
+                    "deploy bot".to_string(),
+                    "1".to_string(),
+                    vec![ApiScope::AccountsRead],
+                )
+                .map_err(|err| panic!(err))
+                .and_then(move |(api_key, token)| {
+                    assert!(token.starts_with("ilpk_"));
+                    store_clone
+                        .get_account_from_http_auth(&format!("Bearer {}", token))
+                        .join(store_clone.get_api_key(&token))
+                        .and_then(move |(account, found)| {
+                            assert_eq!(account.id(), 1);
+                            assert_eq!(found, Some(api_key));
+                            let _ = context;
+                            Ok(())
+                        })
+                })
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn api_key_tokens_are_not_saved() {
+        block_on(test_store().and_then(|(store, context)| {
+            let connection = context.async_connection();
+            store
+                .create_api_key("bot".to_string(), "0".to_string(), Vec::new())
+                .map_err(|err| panic!(err))
+                .and_then(move |(api_key, token)| {
+                    connection
+                        .map_err(|err| panic!(err))
+                        .and_then(move |connection| {
+                            let mut pipe = redis::pipe();
+                            pipe.cmd("GET")
+                                .arg(format!("api_keys:{}", api_key.id))
+                                .cmd("HKEYS")
+                                .arg("api_key_tokens");
+                            pipe.query_async(connection)
+                                .map_err(|err| panic!(err))
+                        })
+                        .and_then(move |(_, (stored, hashes)): (_, (String, Vec<String>))| {
+                            assert!(!stored.contains(&token));
+                            assert_eq!(hashes.len(), 1);
+                            assert_ne!(hashes[0], token);
+                            let _ = context;
+                            Ok(())
+                        })
+                })
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn deleted_api_key_cannot_be_used() {
+        let result = block_on(test_store().and_then(|(store, context)| {
+            let store_clone = store.clone();
+            store
+                .create_api_key("bot".to_string(), "0".to_string(), Vec::new())
+                .and_then(move |(api_key, token)| {
+                    let store = store_clone.clone();
+                    store_clone
+                        .delete_api_key(api_key.id)
+                        .and_then(move |_| store.get_api_keys())
+                        .map(move |api_keys| {
+                            assert!(api_keys.is_empty());
+                            (store_clone, token)
+                        })
+                })
+                .map_err(|err| panic!(err))
+                .and_then(|(store, token)| {
+                    store
+                        .get_account_from_http_auth(&format!("Bearer {}", token))
+                        .then(move |result| {
+                            let _ = context;
+                            result
+                        })
+                })
+        }));
+        assert!(result.is_err());
+    }
+}
+
+mod locks {
+    use super::*;
+    use interledger_service_util::LockStore;
+
+    #[test]
+    fn only_one_holder_at_a_time() {
+        block_on(test_store().and_then(|(store, context)| {
+            let store_clone = store.clone();
+            store
+                .acquire_lock("sweeper", Duration::from_secs(10))
+                .join(store.acquire_lock("sweeper", Duration::from_secs(10)))
+                .and_then(move |(first, second)| {
+                    assert!(first.is_some() != second.is_some());
+                    let first = first.or(second).unwrap();
+                    let store = store_clone.clone();
+                    store_clone
+                        .release_lock(first.clone())
+                        .and_then(move |_| store.acquire_lock("sweeper", Duration::from_secs(10)))
+                        .map(move |third| {
+                            // Each holder gets a higher token than the last
+                            assert!(third.unwrap().token > first.token);
+                            let _ = context;
+                        })
+                })
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn expired_lock_cannot_be_extended_or_released() {
+        block_on(test_store().and_then(|(store, context)| {
+            let store_clone = store.clone();
+            store
+                .acquire_lock("exporter", Duration::from_millis(10))
+                .and_then(|lock| {
+                    Delay::new(Instant::now() + Duration::from_millis(50))
+                        .map_err(|err| panic!(err))
+                        .map(move |_| lock.unwrap())
+                })
+                .and_then(move |stale| {
+                    let store = store_clone.clone();
+                    store_clone
+                        .acquire_lock("exporter", Duration::from_secs(10))
+                        .and_then(move |current| {
+                            let current = current.unwrap();
+                            store
+                                .release_lock(stale.clone())
+                                .and_then(move |_| {
+                                    store
+                                        .extend_lock(&stale, Duration::from_secs(10))
+                                        .join(store.extend_lock(&current, Duration::from_secs(10)))
+                                })
+                                .map(move |(stale_extended, current_extended)| {
+                                    assert!(!stale_extended);
+                                    assert!(current_extended);
+                                    let _ = context;
+                                })
+                        })
+                })
+        }))
+        .unwrap()
+    }
+}
+
+mod fees {
+    use super::*;
+    use interledger_api::NodeStore;
+    use interledger_service::AccountStore;
+    use interledger_service_util::{FeePolicy, FeeStore};
+
+    #[test]
+    fn sets_and_gets_fee_policies() {
+        block_on(test_store().and_then(|(store, context)| {
+            let store_clone = store.clone();
+            store
+                .set_fee_policies(vec![
+                    (
+                        "account:1".to_string(),
+                        FeePolicy {
+                            fixed: 1,
+                            proportional: 0.0,
+                        },
+                    ),
+                    (
+                        "prefix:example".to_string(),
+                        FeePolicy {
+                            fixed: 0,
+                            proportional: 0.01,
+                        },
+                    ),
+                    (
+                        "prefix:example.bob".to_string(),
+                        FeePolicy {
+                            fixed: 2,
+                            proportional: 0.02,
+                        },
+                    ),
+                ])
+                .map_err(|err| panic!(err))
+                .and_then(move |_| store_clone.get_accounts(vec![0, 1]).map(unwrap_accounts))
+                .and_then(move |accounts| {
+                    assert_eq!(
+                        store.get_fee_policy(&accounts[1], b"example.alice").unwrap().fixed,
+                        1
+                    );
+                    assert_eq!(
+                        store.get_fee_policy(&accounts[0], b"example.alice").unwrap().proportional,
+                        0.01
+                    );
+                    let policy = store.get_fee_policy(&accounts[0], b"example.bob.1").unwrap();
+                    assert_eq!(policy.fee(100), 4);
+                    // Prefixes only match whole address segments
+                    let policy = store.get_fee_policy(&accounts[0], b"example.bob2").unwrap();
+                    assert_eq!(policy.fee(100), 1);
+                    assert!(store.get_fee_policy(&accounts[0], b"examples.bob").is_none());
+                    assert!(store.get_fee_policy(&accounts[0], b"test.bob").is_none());
+                    let _ = context;
+                    Ok(())
+                })
+        }))
+        .unwrap();
+    }
+}
+
+mod maintenance {
+    use super::*;
+    use interledger_api::NodeStore;
+    use interledger_service::AccountStore;
+    use interledger_service_util::{MaintenanceStore, MaintenanceWindow};
+
+    #[test]
+    fn sets_and_clears_maintenance_windows() {
+        block_on(test_store().and_then(|(store, context)| {
+            let store_clone = store.clone();
+            let window = MaintenanceWindow {
+                start: 1000,
+                end: 2000,
+                reason: Some("upgrade".to_string()),
+            };
+            store
+                .set_maintenance_windows(1, vec![window.clone()])
+                .map_err(|err| panic!(err))
+                .and_then(move |_| store_clone.get_accounts(vec![0, 1]).map(unwrap_accounts))
+                .and_then(move |accounts| {
+                    assert_eq!(
+                        store.get_maintenance_window(&accounts[1], 1500),
+                        Some(window.clone())
+                    );
+                    assert!(store.get_maintenance_window(&accounts[1], 2000).is_none());
+                    assert!(store.get_maintenance_window(&accounts[0], 1500).is_none());
+                    let store_clone = store.clone();
+                    store
+                        .get_maintenance_windows()
+                        .map_err(|err| panic!(err))
+                        .and_then(move |windows| {
+                            assert_eq!(windows, vec![(1, vec![window])]);
+                            store_clone
+                                .set_maintenance_windows(1, Vec::new())
+                                .map_err(|err| panic!(err))
+                        })
+                        .and_then(move |_| {
+                            assert!(store.get_maintenance_window(&accounts[1], 1500).is_none());
+                            let _ = context;
+                            Ok(())
+                        })
+                })
+        }))
+        .unwrap();
+    }
+}
+
+mod stats {
+    use super::*;
+    use interledger_service::AccountStore;
+    use interledger_service_util::StatsStore;
+
+    #[test]
+    fn records_forwarded_packets() {
+        block_on(test_store().and_then(|(store, context)| {
+            let store_clone = store.clone();
+            let store_clone_2 = store.clone();
+            store
+                .get_accounts(vec![0, 1])
+                .map(unwrap_accounts)
+                .and_then(move |accounts| {
+                    store_clone.record_forwarded_packet(&accounts[0], 100, &accounts[1], 500, 1);
+                    store_clone.record_forwarded_packet(&accounts[0], 50, &accounts[1], 250, 0);
+                    Ok(())
+                })
+                .and_then(move |_| store_clone_2.get_total_stats())
+                .and_then(move |total| {
+                    assert_eq!(total.len(), 2);
+                    assert_eq!(total[0].account_id, "0");
+                    assert_eq!(total[0].asset_code, "XYZ");
+                    assert_eq!(total[0].incoming, 150);
+                    assert_eq!(total[0].fees, 1);
+                    assert_eq!(total[1].outgoing, 750);
+                    store.get_daily_stats(3)
+                })
+                .and_then(move |daily| {
+                    assert_eq!(daily.len(), 3);
+                    assert_eq!(daily[0].accounts.len(), 2);
+                    assert_eq!(daily[0].accounts[0].incoming, 150);
+                    assert!(daily[1].accounts.is_empty());
+                    let _ = context;
+                    Ok(())
+                })
+        }))
+        .unwrap();
+    }
+
+    #[test]
+    fn caps_amounts_that_overflow() {
         block_on(test_store().and_then(|(store, context)| {
+            let store_clone = store.clone();
+            let store_clone_2 = store.clone();
             store
-                .get_account_from_btp_token("other_btp_token")
-                .and_then(move |account| {
-                    assert_eq!(account.id(), 1);
+                .get_accounts(vec![0, 1])
+                .map(unwrap_accounts)
+                .and_then(move |accounts| {
+                    let max = u64::max_value();
+                    store_clone.record_forwarded_packet(&accounts[0], max, &accounts[1], 1, 0);
+                    // The first batch is written before the second one is recorded
+                    store_clone
+                        .get_total_stats()
+                        .map(move |_| (store_clone, accounts))
+                })
+                .and_then(move |(store_clone, accounts)| {
+                    store_clone.record_forwarded_packet(&accounts[0], 10, &accounts[1], 1, 0);
+                    store_clone_2.get_total_stats()
+                })
+                .and_then(move |total| {
+                    assert_eq!(total[0].incoming, i64::max_value() as u64);
+                    assert_eq!(total[1].outgoing, 2);
                     let _ = context;
                     Ok(())
                 })
         }))
-        .unwrap()
+        .unwrap();
     }
 
     #[test]
-    fn errors_on_unknown_btp_token() {
-        let result = block_on(test_store().and_then(|(store, context)| {
+    fn aggregates_packets_by_destination_prefix() {
+        block_on(test_store().and_then(|(store, context)| {
+            let store_clone = store.clone();
             store
-                .get_account_from_btp_token("unknown_btp_token")
-                .then(move |result| {
+                .get_accounts(vec![0, 1])
+                .map(unwrap_accounts)
+                .and_then(move |accounts| {
+                    let packets = vec![
+                        (&b"g.exchange.alice.1"[..], 1, 100, true),
+                        (&b"g.exchange.alice.2"[..], 1, 200, true),
+                        (&b"g.exchange.alice.2"[..], 1, 300, false),
+                        (&b"g.exchange.alice.3"[..], 0, 400, true),
+                        (&b"g.other"[..], 1, 50, true),
+                    ];
+                    for (destination, to, amount, fulfilled) in packets {
+                        store_clone.record_destination_packet(
+                            destination,
+                            &accounts[to],
+                            amount,
+                            fulfilled,
+                        );
+                    }
+                    store_clone.get_prefix_stats(10)
+                })
+                .and_then(move |prefixes| {
+                    assert_eq!(prefixes.len(), 2);
+                    assert_eq!(prefixes[0].prefix, "g.exchange.alice");
+                    assert_eq!(prefixes[0].packets, 4);
+                    assert_eq!(prefixes[0].fulfilled, 3);
+                    // Amounts in different assets are not added together
+                    assert_eq!(prefixes[0].amounts.len(), 2);
+                    assert_eq!(prefixes[0].amounts["ABC"], 300);
+                    assert_eq!(prefixes[0].amounts["XYZ"], 400);
+                    assert_eq!(prefixes[0].unique_destinations, 3);
+                    assert_eq!(prefixes[1].prefix, "g.other");
+                    assert_eq!(prefixes[1].packets, 1);
+                    store.get_prefix_stats(1)
+                })
+                .and_then(move |prefixes| {
+                    assert_eq!(prefixes.len(), 1);
                     let _ = context;
-                    result
+                    Ok(())
                 })
-        }));
-        assert!(result.is_err());
+        }))
+        .unwrap();
     }
 }
 
-mod from_http {
+mod balance_history {
     use super::*;
-    use interledger_http::HttpStore;
-    use interledger_service::Account as AccountTrait;
+    use interledger_api::BalanceHistoryStore;
 
     #[test]
-    fn gets_account_from_http_bearer_token() {
+    fn records_and_gets_snapshots() {
         block_on(test_store().and_then(|(store, context)| {
+            let store_clone = store.clone();
             store
-                .get_account_from_http_auth("Bearer incoming_auth_token")
-                .and_then(move |account| {
-                    assert_eq!(account.id(), 0);
+                .snapshot_balances()
+                .and_then(move |_| store_clone.get_balance_history(1, 0, u64::max_value()))
+                .map_err(|err| panic!(err))
+                .and_then(move |history| {
+                    assert_eq!(history.len(), 1);
+                    assert_eq!(history[0].balance, 0);
+                    store.get_balance_history(1, 0, 1).map_err(|err| panic!(err))
+                })
+                .and_then(move |history| {
+                    assert!(history.is_empty());
                     let _ = context;
                     Ok(())
                 })
         }))
-        .unwrap()
+        .unwrap();
     }
+}
+
+mod idempotency {
+    use super::*;
+    use interledger_api::IdempotencyStore;
 
     #[test]
-    fn gets_account_from_http_basic_auth() {
+    fn only_claims_key_once() {
         block_on(test_store().and_then(|(store, context)| {
+            let store_clone = store.clone();
             store
-                .get_account_from_http_auth("Basic QWxhZGRpbjpPcGVuU2VzYW1l")
-                .and_then(move |account| {
-                    assert_eq!(account.id(), 1);
+                .claim_idempotency_key("abc".to_string(), 60)
+                .and_then(move |is_new| {
+                    assert!(is_new);
+                    store_clone.claim_idempotency_key("abc".to_string(), 60)
+                })
+                .and_then(move |is_new| {
+                    assert!(!is_new);
+                    store.claim_idempotency_key("def".to_string(), 60)
+                })
+                .and_then(move |is_new| {
+                    assert!(is_new);
                     let _ = context;
                     Ok(())
                 })
         }))
-        .unwrap()
+        .unwrap();
     }
 
     #[test]
-    fn errors_on_unknown_http_auth() {
-        let result = block_on(test_store().and_then(|(store, context)| {
+    fn released_keys_can_be_claimed_again() {
+        block_on(test_store().and_then(|(store, context)| {
+            let connection = context.async_connection();
+            let store_clone = store.clone();
             store
-                .get_account_from_http_auth("Bearer unknown_token")
-                .then(move |result| {
+                .claim_idempotency_key("api:Bearer secret:abc".to_string(), 60)
+                .and_then(move |is_new| {
+                    assert!(is_new);
+                    store_clone
+                        .release_idempotency_key("api:Bearer secret:abc".to_string())
+                        .map(move |_| store_clone)
+                })
+                .and_then(move |store| {
+                    store
+                        .claim_idempotency_key("api:Bearer secret:abc".to_string(), 60)
+                        .join(connection)
+                })
+                .and_then(move |(is_new, connection)| {
+                    assert!(is_new);
+                    // The credentials in the keys are not saved
+                    redis::cmd("KEYS")
+                        .arg("idempotency:*")
+                        .query_async(connection)
+                        .map_err(|err| panic!(err))
+                        .and_then(move |(_, keys): (_, Vec<String>)| {
+                            assert_eq!(keys.len(), 1);
+                            assert!(!keys[0].contains("secret"));
+                            let _ = context;
+                            Ok(())
+                        })
+                })
+        }))
+        .unwrap();
+    }
+}
+
+mod garbage_collection {
+    use super::*;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    #[test]
+    fn removes_expired_payments_and_snapshots() {
+        block_on(test_store().and_then(|(store, context)| {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs();
+            let connection = context.async_connection();
+            connection
+                .map_err(|err| panic!(err))
+                .and_then(move |connection| {
+                    let mut pipe = redis::pipe();
+                    pipe.cmd("HSET")
+                        .arg("stream_connections:present")
+                        .arg("received")
+                        .arg(1)
+                        .ignore()
+                        .cmd("ZADD")
+                        .arg("payments:0")
+                        .arg(now)
+                        .arg("present")
+                        .arg(now)
+                        .arg("expired")
+                        .arg(1)
+                        .arg("too_old")
+                        .ignore()
+                        .cmd("ZADD")
+                        .arg("balance_history:0")
+                        .arg(1)
+                        .arg("1:0")
+                        .ignore();
+                    pipe.query_async(connection)
+                        .map_err(|err| panic!(err))
+                        .and_then(move |(connection, _): (_, redis::Value)| {
+                            store.collect_garbage().and_then(move |removed| {
+                                assert_eq!(removed, 3);
+                                redis::cmd("ZRANGE")
+                                    .arg("payments:0")
+                                    .arg(0)
+                                    .arg(-1)
+                                    .query_async(connection)
+                                    .map_err(|err| panic!(err))
+                            })
+                        })
+                })
+                .and_then(move |(_connection, payments): (_, Vec<String>)| {
+                    assert_eq!(payments, vec!["present".to_string()]);
                     let _ = context;
-                    result
+                    Ok(())
                 })
-        }));
-        assert!(result.is_err());
+        }))
+        .unwrap();
+    }
+
+    #[test]
+    fn removes_data_of_accounts_deleted_long_ago() {
+        block_on(test_store().and_then(|(store, context)| {
+            let connection = context.async_connection();
+            store
+                .delete_account(1)
+                .map_err(|err| panic!(err))
+                .join(connection.map_err(|err| panic!(err)))
+                .and_then(|(_, connection)| {
+                    let mut pipe = redis::pipe();
+                    pipe.cmd("LPUSH")
+                        .arg("claims:1")
+                        .arg("claim")
+                        .ignore()
+                        .cmd("LPUSH")
+                        .arg("claims:0")
+                        .arg("claim")
+                        .ignore()
+                        // Deleted at the start of the epoch, well past the retention period
+                        .cmd("ZADD")
+                        .arg("accounts:deleted")
+                        .arg(1)
+                        .arg(1)
+                        .ignore();
+                    pipe.query_async(connection).map_err(|err| panic!(err))
+                })
+                .and_then(move |(connection, _): (_, redis::Value)| {
+                    store.collect_garbage().and_then(move |removed| {
+                        assert_eq!(removed, 1);
+                        redis::cmd("EXISTS")
+                            .arg("claims:0")
+                            .arg("claims:1")
+                            .arg("accounts:deleted")
+                            .query_async(connection)
+                            .map_err(|err| panic!(err))
+                    })
+                })
+                .and_then(move |(_connection, exists): (_, u64)| {
+                    // Only the claims of the account that still exists are left
+                    assert_eq!(exists, 1);
+                    let _ = context;
+                    Ok(())
+                })
+        }))
+        .unwrap();
     }
 }
 
 mod ccp_store {
     use super::*;
     use interledger_ccp::RouteManagerStore;
-    use interledger_router::RouterStore;
+    use interledger_router::{RouteHint, RouterStore};
     use interledger_service::Account as AccountTrait;
 
     #[test]
@@ -680,14 +2778,52 @@ mod ccp_store {
         }))
         .unwrap()
     }
+
+    #[test]
+    fn saves_route_hints() {
+        block_on(test_store().and_then(|(store, context)| {
+            let mut hints = hashbrown::HashMap::new();
+            hints.insert(
+                Bytes::from("example.remote"),
+                vec![
+                    RouteHint {
+                        account_id: 0,
+                        asset_code: "XYZ".to_string(),
+                        rate: 0.5,
+                        max_amount: None,
+                    },
+                    RouteHint {
+                        account_id: 1,
+                        asset_code: "ABC".to_string(),
+                        rate: 2.0,
+                        max_amount: Some(1000.0),
+                    },
+                ],
+            );
+            store
+                .clone()
+                .set_route_hints(hints.clone())
+                .and_then(move |_| {
+                    assert_eq!(
+                        store.route_hints(b"example.remote"),
+                        hints[&Bytes::from("example.remote")]
+                    );
+                    assert!(store.route_hints(b"example.other").is_empty());
+                    let _ = context;
+                    Ok(())
+                })
+        }))
+        .unwrap()
+    }
 }
 
 mod configured_routes {
     use super::*;
-    use interledger_api::NodeStore;
+    use interledger_api::{NodeStore, RouteChange, RouteSource};
     use interledger_ccp::RouteManagerStore;
     use interledger_router::RouterStore;
     use interledger_service::Account as AccountTrait;
+    use std::iter::FromIterator;
 
     #[test]
     fn adds_static_routes_to_redis() {
@@ -700,6 +2836,7 @@ mod configured_routes {
                     ("example.b".to_string(), 0),
                     ("example.c".to_string(), 1),
                 ])
+                .map_err(|err| panic!(err))
                 .and_then(move |_| {
                     get_connection.and_then(|connection| {
                         redis::cmd("HGETALL")
@@ -720,6 +2857,44 @@ mod configured_routes {
         .unwrap()
     }
 
+    #[test]
+    fn adds_batch_of_static_routes() {
+        block_on(test_store().and_then(|(store, context)| {
+            let store_clone = store.clone();
+            store
+                .clone()
+                .set_static_routes(vec![("example.a".to_string(), 0)])
+                .map_err(|err| panic!(err))
+                .and_then(move |_| {
+                    store_clone.add_static_routes(vec![
+                        ("example.b".to_string(), 1),
+                        ("example.c".to_string(), 99),
+                    ])
+                })
+                .then(move |result| {
+                    assert_eq!(
+                        result.unwrap_err(),
+                        BatchError::Entries(vec![(1, StoreError::NotFound)])
+                    );
+                    assert_eq!(store.routing_table().get(&b"example.b"[..]), None);
+                    store
+                        .add_static_routes(vec![
+                            ("example.a".to_string(), 1),
+                            ("example.b".to_string(), 1),
+                        ])
+                        .map_err(|err| panic!(err))
+                        .and_then(move |_| {
+                            let routes = store.routing_table();
+                            assert_eq!(routes[&b"example.a"[..]], 1);
+                            assert_eq!(routes[&b"example.b"[..]], 1);
+                            let _ = context;
+                            Ok(())
+                        })
+                })
+        }))
+        .unwrap()
+    }
+
     #[test]
     fn static_routes_override_others() {
         block_on(test_store().and_then(|(store, context)| {
@@ -730,6 +2905,7 @@ mod configured_routes {
                     ("example.a".to_string(), 0),
                     ("example.b".to_string(), 0),
                 ])
+                .map_err(|err| panic!(err))
                 .and_then(move |_| {
                     let account1 = Account::try_from(1, ACCOUNT_DETAILS_1.clone()).unwrap();
                     store_clone.set_routes(vec![
@@ -760,6 +2936,7 @@ mod configured_routes {
                     ("example.a".to_string(), 0),
                     ("example.b".to_string(), 1),
                 ])
+                .map_err(|err| panic!(err))
                 .and_then(move |_| store.get_local_and_configured_routes())
                 .and_then(move |(_local, configured)| {
                     assert_eq!(configured.len(), 2);
@@ -771,4 +2948,214 @@ mod configured_routes {
         }))
         .unwrap()
     }
+
+    #[test]
+    fn rolls_back_to_earlier_routes() {
+        block_on(test_store().and_then(|(store, context)| {
+            let store_clone = store.clone();
+            store
+                .clone()
+                .set_static_routes(vec![("example.a".to_string(), 0)])
+                .and_then(move |_| store_clone.set_static_route("example.b".to_string(), 1))
+                .and_then({
+                    let store = store.clone();
+                    move |_| store.get_route_history()
+                })
+                .and_then({
+                    let store = store.clone();
+                    move |history| {
+                        assert!(history[0].version > history[1].version);
+                        assert_eq!(history[0].source, RouteSource::Api);
+                        assert!(history[0].routes.is_empty());
+                        assert_eq!(
+                            history[0].static_routes,
+                            HashMap::from_iter(vec![(
+                                "example.b".to_string(),
+                                RouteChange {
+                                    old: None,
+                                    new: Some("1".to_string()),
+                                },
+                            )])
+                        );
+                        assert_eq!(history[1].static_routes.len(), 1);
+                        store.rollback_routes(history[1].version)
+                    }
+                })
+                .and_then({
+                    let store = store.clone();
+                    move |version| {
+                        assert_eq!(version.source, RouteSource::Rollback);
+                        assert_eq!(
+                            version.static_routes,
+                            HashMap::from_iter(vec![(
+                                "example.b".to_string(),
+                                RouteChange {
+                                    old: Some("1".to_string()),
+                                    new: None,
+                                },
+                            )])
+                        );
+                        let routes = store.routing_table();
+                        assert_eq!(routes[&b"example.a"[..]], 0);
+                        assert!(!routes.contains_key(&b"example.b"[..]));
+                        store.rollback_routes(1000).then(move |result| {
+                            assert_eq!(result.unwrap_err(), StoreError::NotFound);
+                            let _ = context;
+                            Ok(())
+                        })
+                    }
+                })
+                .map_err(|err| panic!(err))
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn records_account_route_changes() {
+        block_on(test_store().and_then(|(store, context)| {
+            let change = |old: Option<&str>, new: Option<&str>| RouteChange {
+                old: old.map(String::from),
+                new: new.map(String::from),
+            };
+            store
+                .get_route_history()
+                .and_then({
+                    let store = store.clone();
+                    move |history| {
+                        assert_eq!(history[0].source, RouteSource::Account);
+                        assert_eq!(
+                            history[0].routes,
+                            HashMap::from_iter(vec![(
+                                "example.bob".to_string(),
+                                change(None, Some("1"))
+                            )])
+                        );
+                        store.change_ilp_address(0, Bytes::from("example.carol"))
+                    }
+                })
+                .and_then({
+                    let store = store.clone();
+                    move |_| store.get_route_history()
+                })
+                .and_then({
+                    let store = store.clone();
+                    move |history| {
+                        assert_eq!(history[0].source, RouteSource::Account);
+                        assert_eq!(
+                            history[0].routes,
+                            HashMap::from_iter(vec![
+                                ("example.alice".to_string(), change(Some("0"), None)),
+                                ("example.carol".to_string(), change(None, Some("0"))),
+                            ])
+                        );
+                        store.delete_account(1)
+                    }
+                })
+                .and_then({
+                    let store = store.clone();
+                    move |_| store.get_route_history()
+                })
+                .and_then(move |history| {
+                    assert_eq!(history[0].source, RouteSource::Account);
+                    assert_eq!(
+                        history[0].routes,
+                        HashMap::from_iter(vec![(
+                            "example.bob".to_string(),
+                            change(Some("1"), None)
+                        )])
+                    );
+                    let _ = context;
+                    Ok(())
+                })
+                .map_err(|err| panic!(err))
+        }))
+        .unwrap()
+    }
+}
+
+mod store_contract {
+    use super::*;
+    use interledger_store_tests as store_tests;
+
+    #[test]
+    fn get_accounts() {
+        block_on(test_store().and_then(|(store, context)| {
+            store_tests::get_accounts(store).map(move |_| drop(context))
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn account_from_http_auth() {
+        block_on(test_store().and_then(|(store, context)| {
+            store_tests::account_from_http_auth(store).map(move |_| drop(context))
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn account_from_btp_token() {
+        block_on(test_store().and_then(|(store, context)| {
+            store_tests::account_from_btp_token(store).map(move |_| drop(context))
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn routing_table() {
+        block_on(test_store().and_then(|(store, context)| {
+            store_tests::routing_table(store);
+            let _ = context;
+            Ok(())
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn concurrent_balance_updates() {
+        block_on(test_store().and_then(|(store, context)| {
+            store_tests::concurrent_balance_updates(store).map(move |_| drop(context))
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn enforces_minimum_balance() {
+        block_on(test_store().and_then(|(store, context)| {
+            store_tests::enforces_minimum_balance(store).map(move |_| drop(context))
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn undo_balance_update_once() {
+        block_on(test_store().and_then(|(store, context)| {
+            store_tests::undo_balance_update_once(store).map(move |_| drop(context))
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn balance_invariants_under_load() {
+        block_on(test_store().and_then(|(store, context)| {
+            store_tests::balance_invariants_under_load(store, 500).map(move |_| drop(context))
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn node_store_uniqueness() {
+        block_on(test_store().and_then(|(store, context)| {
+            store_tests::node_store_uniqueness(store).map(move |_| drop(context))
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn node_store_secondary_indexes() {
+        block_on(test_store().and_then(|(store, context)| {
+            store_tests::node_store_secondary_indexes(store).map(move |_| drop(context))
+        }))
+        .unwrap()
+    }
 }
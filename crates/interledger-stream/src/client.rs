@@ -1,9 +1,11 @@
 use super::congestion::CongestionController;
 use super::crypto::*;
 use super::error::Error;
+use super::outcome::{PaymentEndState, PaymentOutcome};
 use super::packet::*;
-use bytes::Bytes;
+use bytes::{Bytes, BytesMut};
 use futures::{Async, Future, Poll};
+use hashbrown::HashMap;
 use interledger_ildcp::get_ildcp_info;
 use interledger_packet::{
     ErrorClass, ErrorCode as IlpErrorCode, Fulfill, PacketType as IlpPacketType, PrepareBuilder,
@@ -14,62 +16,289 @@ use std::{
     cell::Cell,
     cmp::min,
     str,
-    time::{Duration, SystemTime},
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
 };
+use tokio_timer::Delay;
+
+/// The stream money is sent on, along with any `SendMoneyOptions::request`
+const MONEY_STREAM_ID: u64 = 1;
+
+/// The stream senders send their return payment pointer on, for the receiver to send refunds to
+/// (see `SendMoneyOptions::return_pointer`). Money is sent on stream 1.
+pub const RETURN_POINTER_STREAM_ID: u64 = 3;
+
+/// The stream senders send application metadata on, such as the ID of the order a payment is for
+/// (see `SendMoneyOptions::metadata`).
+pub const METADATA_STREAM_ID: u64 = 5;
+
+/// Looks up market exchange rates, given in units of the destination asset
+/// per unit of the source asset (not accounting for the asset scales).
+pub trait ExchangeRateProvider: Send + Sync {
+    fn get_rate(&self, source_asset: &str, destination_asset: &str) -> Option<f64>;
+}
+
+impl<F> ExchangeRateProvider for F
+where
+    F: Fn(&str, &str) -> Option<f64> + Send + Sync,
+{
+    fn get_rate(&self, source_asset: &str, destination_asset: &str) -> Option<f64> {
+        (self)(source_asset, destination_asset)
+    }
+}
+
+/// Where the sender gets the current time from, to set the expiries of its packets.
+/// This is the system clock unless `SendMoneyOptions::clock` is set, which is needed on
+/// platforms that do not have one, such as wasm32 in the browser.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> SystemTime;
+}
+
+impl<F> Clock for F
+where
+    F: Fn() -> SystemTime + Send + Sync,
+{
+    fn now(&self) -> SystemTime {
+        (self)()
+    }
+}
+
+/// What the sender does when the exchange rate of the path is worse than allowed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SlippagePolicy {
+    /// Stop sending and return an error.
+    /// Packets also tell the receiver the minimum it should accept, so it rejects them otherwise
+    Abort,
+    /// Keep sending but log a warning
+    Warn,
+}
+
+impl Default for SlippagePolicy {
+    fn default() -> Self {
+        SlippagePolicy::Abort
+    }
+}
+
+/// Optional settings for `send_money_with_options`.
+#[derive(Clone, Default)]
+pub struct SendMoneyOptions {
+    rate_provider: Option<Arc<ExchangeRateProvider>>,
+    clock: Option<Arc<Clock>>,
+    max_slippage: f64,
+    slippage_policy: SlippagePolicy,
+    timeout: Option<Duration>,
+    max_idle: Option<Duration>,
+    randomize_address: bool,
+    return_pointer: Option<String>,
+    metadata: Option<String>,
+    request: Option<Bytes>,
+}
+
+impl SendMoneyOptions {
+    pub fn new() -> Self {
+        SendMoneyOptions::default()
+    }
+
+    /// Compare the exchange rate of the path to the market rate from the `rate_provider`
+    /// and apply the `SlippagePolicy` if it is more than `max_slippage` worse (0.01 is 1%).
+    ///
+    /// The rate can only be checked once the receiver has told us its asset, so the first
+    /// packets are checked after they are fulfilled rather than before.
+    pub fn max_slippage<R>(mut self, rate_provider: R, max_slippage: f64) -> Self
+    where
+        R: ExchangeRateProvider + 'static,
+    {
+        self.rate_provider = Some(Arc::new(rate_provider));
+        self.max_slippage = max_slippage;
+        self
+    }
+
+    /// Use this clock instead of the system clock.
+    pub fn clock<C>(mut self, clock: C) -> Self
+    where
+        C: Clock + 'static,
+    {
+        self.clock = Some(Arc::new(clock));
+        self
+    }
+
+    pub fn slippage_policy(mut self, slippage_policy: SlippagePolicy) -> Self {
+        self.slippage_policy = slippage_policy;
+        self
+    }
+
+    /// Give up if the whole payment takes longer than this.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Give up if no money is delivered for this long.
+    pub fn max_idle(mut self, max_idle: Duration) -> Self {
+        self.max_idle = Some(max_idle);
+        self
+    }
+
+    /// Add a random suffix to the destination address, so that connections which reuse the
+    /// same receiver details do not send packets to the same address and intermediaries cannot
+    /// tell that they go to the same recipient from the address alone.
+    ///
+    /// The receiver must support suffixes (Interledger.rs receivers derive a separate shared
+    /// secret for each one), otherwise it will reject the packets.
+    pub fn randomize_address(mut self, randomize_address: bool) -> Self {
+        self.randomize_address = randomize_address;
+        self
+    }
+
+    /// Tell the receiver which payment pointer to send refunds to. It is sent on the
+    /// `RETURN_POINTER_STREAM_ID` stream with the packets sent before the first one is fulfilled.
+    pub fn return_pointer(mut self, return_pointer: String) -> Self {
+        self.return_pointer = Some(return_pointer);
+        self
+    }
+
+    /// Attach application metadata to the payment, such as an invoice or order ID for the
+    /// receiver to reconcile the payment with. It is sent on the `METADATA_STREAM_ID` stream
+    /// with the packets sent before the first one is fulfilled, so it should be small.
+    pub fn metadata(mut self, metadata: String) -> Self {
+        self.metadata = Some(metadata);
+        self
+    }
+
+    /// Send a request for the receiver's `StreamDataHandler` to respond to, such as a call to
+    /// a paid API. It is sent on the money stream with the packets sent before the first one
+    /// is fulfilled, and the receiver's response is returned in `PaymentOutcome::response`.
+    ///
+    /// The request must fit in the Prepare packets along with the other data, otherwise
+    /// the payment fails with an error.
+    pub fn request(mut self, request: Bytes) -> Self {
+        self.request = Some(request);
+        self
+    }
+}
 
 /// Send a given amount of money using the STREAM transport protocol.
 ///
-/// This returns the amount delivered, as reported by the receiver and in the receiver's asset's units.
+/// This returns a `PaymentOutcome` with the amount delivered, as reported by the receiver
+/// and in the receiver's asset's units, even if the payment stopped part of the way through.
+/// Errors are only returned if the payment could not be attempted.
 pub fn send_money<S, A>(
     service: S,
     from_account: &A,
     destination_account: &[u8],
     shared_secret: &[u8],
     source_amount: u64,
-) -> impl Future<Item = (u64, S), Error = Error>
+) -> impl Future<Item = (PaymentOutcome, S), Error = Error>
+where
+    S: IncomingService<A> + Clone,
+    A: Account,
+{
+    send_money_with_options(
+        service,
+        from_account,
+        destination_account,
+        shared_secret,
+        source_amount,
+        SendMoneyOptions::default(),
+    )
+}
+
+/// Same as `send_money` but with additional `SendMoneyOptions`.
+pub fn send_money_with_options<S, A>(
+    service: S,
+    from_account: &A,
+    destination_account: &[u8],
+    shared_secret: &[u8],
+    source_amount: u64,
+    options: SendMoneyOptions,
+) -> impl Future<Item = (PaymentOutcome, S), Error = Error>
 where
     S: IncomingService<A> + Clone,
     A: Account,
 {
-    let destination_account = Bytes::from(destination_account);
-    let shared_secret = Bytes::from(shared_secret);
+    let (destination_account, shared_secret) = if options.randomize_address {
+        let suffix = base64::encode_config(&generate_token()[..], base64::URL_SAFE_NO_PAD);
+        let mut address = BytesMut::from(destination_account);
+        address.extend_from_slice(b"~");
+        address.extend_from_slice(suffix.as_bytes());
+        (
+            address.freeze(),
+            Bytes::from(&suffix_shared_secret(shared_secret, suffix.as_bytes())[..]),
+        )
+    } else {
+        (Bytes::from(destination_account), Bytes::from(shared_secret))
+    };
     let from_account = from_account.clone();
     // TODO can/should we avoid cloning the account?
     get_ildcp_info(&mut service.clone(), from_account.clone())
         .map_err(|_err| Error::ConnectionError("Unable to get ILDCP info: {:?}".to_string()))
         .and_then(move |account_details| SendMoneyFuture {
             state: SendMoneyFutureState::SendMoney,
+            deadline: options
+                .timeout
+                .map(|timeout| Delay::new(Instant::now() + timeout)),
+            idle_deadline: options
+                .max_idle
+                .map(|max_idle| Delay::new(Instant::now() + max_idle)),
             next: Some(service),
             from_account,
             source_account: Bytes::from(account_details.client_address()),
+            source_asset: (
+                str::from_utf8(account_details.asset_code())
+                    .unwrap_or_default()
+                    .to_string(),
+                account_details.asset_scale(),
+            ),
             destination_account,
             shared_secret,
             source_amount,
             congestion_controller: CongestionController::default(),
             pending_requests: Cell::new(Vec::new()),
             amount_delivered: 0,
+            source_amount_sent: 0,
+            receiver_asset: None,
+            response: None,
+            options,
+            min_rate: None,
+            warned_about_rate: false,
             should_send_source_account: true,
             sequence: 1,
-            rejected_packets: 0,
-            error: None,
+            fulfilled_packets: 0,
+            rejected_packets: HashMap::new(),
+            end_state: None,
         })
 }
 
 struct SendMoneyFuture<S: IncomingService<A>, A: Account> {
     state: SendMoneyFutureState,
+    deadline: Option<Delay>,
+    /// Pushed back every time money is delivered
+    idle_deadline: Option<Delay>,
     next: Option<S>,
     from_account: A,
     source_account: Bytes,
+    source_asset: (String, u8),
     destination_account: Bytes,
     shared_secret: Bytes,
     source_amount: u64,
     congestion_controller: CongestionController,
     pending_requests: Cell<Vec<PendingRequest>>,
     amount_delivered: u64,
+    source_amount_sent: u64,
+    /// The asset code and scale the receiver's amounts are denominated in, once it tells us
+    receiver_asset: Option<(String, u8)>,
+    /// The receiver's response to the `SendMoneyOptions::request`, once it sends one
+    response: Option<Bytes>,
+    options: SendMoneyOptions,
+    /// The lowest acceptable rate, in the receiver's base units per source base unit
+    min_rate: Option<f64>,
+    warned_about_rate: bool,
     should_send_source_account: bool,
     sequence: u64,
-    rejected_packets: u64,
-    error: Option<Error>,
+    fulfilled_packets: u64,
+    rejected_packets: HashMap<IlpErrorCode, u64>,
+    /// Set when the sender should stop early
+    end_state: Option<PaymentEndState>,
 }
 
 struct PendingRequest {
@@ -108,18 +337,43 @@ where
             // Load up the STREAM packet
             let sequence = self.next_sequence();
             let mut frames = vec![Frame::StreamMoney(StreamMoneyFrame {
-                stream_id: 1,
+                stream_id: MONEY_STREAM_ID,
                 shares: 1,
             })];
             if self.should_send_source_account {
                 frames.push(Frame::ConnectionNewAddress(ConnectionNewAddressFrame {
                     source_account: &self.source_account[..],
                 }));
+                if let Some(ref return_pointer) = self.options.return_pointer {
+                    frames.push(Frame::StreamData(StreamDataFrame {
+                        stream_id: RETURN_POINTER_STREAM_ID,
+                        offset: 0,
+                        data: return_pointer.as_bytes(),
+                    }));
+                }
+                if let Some(ref metadata) = self.options.metadata {
+                    frames.push(Frame::StreamData(StreamDataFrame {
+                        stream_id: METADATA_STREAM_ID,
+                        offset: 0,
+                        data: metadata.as_bytes(),
+                    }));
+                }
+                if let Some(ref request) = self.options.request {
+                    frames.push(Frame::StreamData(StreamDataFrame {
+                        stream_id: MONEY_STREAM_ID,
+                        offset: 0,
+                        data: &request[..],
+                    }));
+                }
             }
             let stream_packet = StreamPacketBuilder {
                 ilp_packet_type: IlpPacketType::Prepare,
-                // TODO enforce min exchange rate
-                prepare_amount: 0,
+                // Only ask the receiver to enforce the rate if we would stop anyway
+                prepare_amount: if self.options.slippage_policy == SlippagePolicy::Abort {
+                    self.min_destination_amount(amount)
+                } else {
+                    0
+                },
                 sequence,
                 frames: &frames,
             }
@@ -131,12 +385,18 @@ where
                 sequence, amount, stream_packet
             );
             let data = stream_packet.into_encrypted(&self.shared_secret);
+            if data.len() > MAX_DATA_LENGTH {
+                return Err(Error::SendMoneyError(format!(
+                    "Data sent with the payment does not fit in a packet ({} bytes)",
+                    data.len()
+                )));
+            }
             let execution_condition = generate_condition(&self.shared_secret, &data);
             let prepare = PrepareBuilder {
                 destination: &self.destination_account[..],
                 amount,
                 execution_condition: &execution_condition,
-                expires_at: SystemTime::now() + Duration::from_secs(30),
+                expires_at: self.now() + Duration::from_secs(30),
                 // TODO don't copy the data
                 data: &data[..],
             }
@@ -162,6 +422,13 @@ where
         Ok(sent_packets)
     }
 
+    fn now(&self) -> SystemTime {
+        match self.options.clock {
+            Some(ref clock) => clock.now(),
+            None => SystemTime::now(),
+        }
+    }
+
     fn try_send_connection_close(&mut self) -> Result<(), Error> {
         let sequence = self.next_sequence();
         let stream_packet = StreamPacketBuilder {
@@ -180,7 +447,7 @@ where
             destination: &self.destination_account[..],
             amount: 0,
             execution_condition: &random_condition(),
-            expires_at: SystemTime::now() + Duration::from_secs(30),
+            expires_at: self.now() + Duration::from_secs(30),
             data: &data[..],
         }
         .build();
@@ -221,10 +488,7 @@ where
             .collect();
         self.pending_requests.set(pending_requests);
 
-        if let Some(error) = self.error.take() {
-            error!("Send money stopped because of error: {:?}", error);
-            Err(error)
-        } else if self.pending_requests.get_mut().is_empty() {
+        if self.pending_requests.get_mut().is_empty() {
             Ok(Async::Ready(()))
         } else {
             Ok(Async::NotReady)
@@ -235,11 +499,25 @@ where
         // TODO should we check the fulfillment and expiry or can we assume the plugin does that?
         self.congestion_controller.fulfill(amount);
         self.should_send_source_account = false;
+        self.fulfilled_packets += 1;
+        self.source_amount_sent += amount;
 
         if let Ok(packet) = StreamPacket::from_encrypted(&self.shared_secret, fulfill.into_data()) {
+            self.record_asset_details(&packet);
+            self.record_response(&packet);
             if packet.ilp_packet_type() == IlpPacketType::Fulfill {
                 // TODO check that the sequence matches our outgoing packet
                 self.amount_delivered += packet.prepare_amount();
+                if packet.prepare_amount() > 0 {
+                    if let (Some(idle_deadline), Some(max_idle)) =
+                        (&mut self.idle_deadline, self.options.max_idle)
+                    {
+                        idle_deadline.reset(Instant::now() + max_idle);
+                    }
+                }
+                if packet.prepare_amount() < self.min_destination_amount(amount) {
+                    self.exchange_rate_too_low(amount, packet.prepare_amount());
+                }
             }
         } else {
             warn!(
@@ -257,7 +535,7 @@ where
     fn handle_reject(&mut self, sequence: u64, amount: u64, reject: Reject) {
         self.source_amount += amount;
         self.congestion_controller.reject(amount, &reject);
-        self.rejected_packets += 1;
+        *self.rejected_packets.entry(reject.code()).or_insert(0) += 1;
         debug!(
             "Prepare {} with amount {} was rejected with code: {} ({} left to send)",
             sequence,
@@ -266,24 +544,162 @@ where
             self.source_amount
         );
 
+        // Rejects from connectors along the path have no STREAM packet in them
+        let packet = if reject.data().len() >= ENCRYPTION_OVERHEAD {
+            StreamPacket::from_encrypted(&self.shared_secret, BytesMut::from(reject.data())).ok()
+        } else {
+            None
+        };
+        if let Some(ref packet) = packet {
+            self.record_asset_details(packet);
+        }
+
         match (reject.code().class(), reject.code()) {
             (ErrorClass::Temporary, _) => {}
             (_, IlpErrorCode::F08_AMOUNT_TOO_LARGE) => {
                 // Handled by the congestion controller
             }
             (_, IlpErrorCode::F99_APPLICATION_ERROR) => {
-                // TODO handle STREAM errors
+                // TODO handle other STREAM errors
+                if let Some(message) = packet.as_ref().and_then(close_message) {
+                    self.stop(PaymentEndState::Partial(format!(
+                        "Receiver closed the connection: {}",
+                        message
+                    )));
+                } else if let Some(ref packet) = packet {
+                    // The receiver rejects packets that would deliver less than we asked for
+                    if packet.prepare_amount() < self.min_destination_amount(amount) {
+                        self.exchange_rate_too_low(amount, packet.prepare_amount());
+                    }
+                }
             }
             _ => {
-                self.error = Some(Error::SendMoneyError(format!(
-                    "Packet was rejected with error: {} {}",
+                debug!(
+                    "Packet was rejected with final error: {} {}",
                     reject.code(),
                     str::from_utf8(reject.message()).unwrap_or_default(),
-                )));
+                );
+                self.stop(PaymentEndState::FailedWithCode(reject.code()));
+            }
+        }
+    }
+
+    fn record_asset_details(&mut self, packet: &StreamPacket) {
+        if self.receiver_asset.is_some() {
+            return;
+        }
+        for frame in packet.frames() {
+            if let Frame::ConnectionAssetDetails(frame) = frame {
+                debug!(
+                    "Receiver's asset is {} (scale: {})",
+                    frame.source_asset_code, frame.source_asset_scale
+                );
+                self.receiver_asset = Some((
+                    frame.source_asset_code.to_string(),
+                    frame.source_asset_scale,
+                ));
+                self.set_min_rate();
             }
         }
     }
 
+    /// Keep the receiver's response to our request, which it sends on the money stream
+    fn record_response(&mut self, packet: &StreamPacket) {
+        if self.response.is_some() {
+            return;
+        }
+        for frame in packet.frames() {
+            if let Frame::StreamData(frame) = frame {
+                if frame.stream_id == MONEY_STREAM_ID {
+                    self.response = Some(Bytes::from(frame.data));
+                }
+            }
+        }
+    }
+
+    /// Look up the market rate once we know the receiver's asset
+    fn set_min_rate(&mut self) {
+        let (rate_provider, (asset_code, asset_scale)) =
+            match (&self.options.rate_provider, &self.receiver_asset) {
+                (Some(rate_provider), Some(receiver_asset)) => (rate_provider, receiver_asset),
+                _ => return,
+            };
+        if let Some(rate) = rate_provider.get_rate(&self.source_asset.0, asset_code) {
+            let scale_change = i32::from(*asset_scale) - i32::from(self.source_asset.1);
+            let min_rate = rate * 10f64.powi(scale_change) * (1.0 - self.options.max_slippage);
+            debug!(
+                "Minimum acceptable exchange rate is {} (market rate from {} to {}: {})",
+                min_rate, self.source_asset.0, asset_code, rate
+            );
+            self.min_rate = Some(min_rate);
+        } else {
+            warn!(
+                "No market rate from {} to {}, unable to check the exchange rate of the path",
+                self.source_asset.0, asset_code
+            );
+        }
+    }
+
+    /// The least the receiver should get for the given source amount, or 0 if the rate isn't checked
+    fn min_destination_amount(&self, source_amount: u64) -> u64 {
+        self.min_rate
+            .map(|min_rate| (source_amount as f64 * min_rate) as u64)
+            .unwrap_or(0)
+    }
+
+    fn exchange_rate_too_low(&mut self, source_amount: u64, delivered: u64) {
+        let message = format!(
+            "Exchange rate of the path is too low: {} delivered for {} sent (minimum rate: {})",
+            delivered,
+            source_amount,
+            self.min_rate.unwrap_or_default()
+        );
+        match self.options.slippage_policy {
+            SlippagePolicy::Abort => self.stop(PaymentEndState::Partial(message)),
+            SlippagePolicy::Warn => {
+                if !self.warned_about_rate {
+                    warn!("{}", message);
+                    self.warned_about_rate = true;
+                }
+            }
+        }
+    }
+
+    /// Stop the payment if it has timed out.
+    ///
+    /// Packets that are still in flight are abandoned, so a few more may be delivered
+    /// without being included in the outcome.
+    fn check_timeouts(&mut self) -> Result<(), Error> {
+        if has_expired(&mut self.deadline)? {
+            self.stop(PaymentEndState::Partial("Payment timed out".to_string()));
+        } else if has_expired(&mut self.idle_deadline)? {
+            self.stop(PaymentEndState::Partial(
+                "Payment stopped making progress".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Keep the first reason for stopping
+    fn stop(&mut self, end_state: PaymentEndState) {
+        if self.end_state.is_none() {
+            warn!("Stopping payment: {:?}", end_state);
+            self.end_state = Some(end_state);
+        }
+    }
+
+    fn outcome(&mut self, end_state: PaymentEndState) -> PaymentOutcome {
+        PaymentOutcome {
+            amount_delivered: self.amount_delivered,
+            source_amount_sent: self.source_amount_sent,
+            receiver_asset: self.receiver_asset.clone(),
+            response: self.response.clone(),
+            end_state,
+            fulfilled_packets: self.fulfilled_packets,
+            rejected_packets: self.rejected_packets.clone(),
+        }
+    }
+
     fn next_sequence(&mut self) -> u64 {
         let seq = self.sequence;
         self.sequence += 1;
@@ -291,18 +707,47 @@ where
     }
 }
 
+fn has_expired(delay: &mut Option<Delay>) -> Result<bool, Error> {
+    if let Some(delay) = delay {
+        match delay.poll() {
+            Ok(Async::NotReady) => Ok(false),
+            Ok(Async::Ready(_)) => Ok(true),
+            Err(err) => Err(Error::PollError(format!("Timer error: {:?}", err))),
+        }
+    } else {
+        Ok(false)
+    }
+}
+
+/// The receiver's reason for closing the connection, if it did
+fn close_message(packet: &StreamPacket) -> Option<String> {
+    for frame in packet.frames() {
+        if let Frame::ConnectionClose(frame) = frame {
+            return Some(frame.message.to_string());
+        }
+    }
+    None
+}
+
 impl<S, A> Future for SendMoneyFuture<S, A>
 where
     S: IncomingService<A>,
     A: Account,
 {
-    type Item = (u64, S);
+    type Item = (PaymentOutcome, S);
     type Error = Error;
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
         // TODO maybe don't have loops here and in try_send_money
         loop {
             self.poll_pending_requests()?;
+            self.check_timeouts()?;
+
+            if let Some(end_state) = self.end_state.take() {
+                self.state = SendMoneyFutureState::Closed;
+                let outcome = self.outcome(end_state);
+                return Ok(Async::Ready((outcome, self.next.take().unwrap())));
+            }
 
             if self.source_amount == 0 && self.pending_requests.get_mut().is_empty() {
                 if self.state == SendMoneyFutureState::SendMoney {
@@ -310,13 +755,9 @@ where
                     self.try_send_connection_close()?;
                 } else {
                     self.state = SendMoneyFutureState::Closed;
-                    debug!(
-                        "Send money future finished. Delivered: {} ({} packets fulfilled, {} packets rejected)", self.amount_delivered, self.sequence - 1, self.rejected_packets,
-                    );
-                    return Ok(Async::Ready((
-                        self.amount_delivered,
-                        self.next.take().unwrap(),
-                    )));
+                    let outcome = self.outcome(PaymentEndState::Completed);
+                    debug!("Send money future finished: {:?}", outcome);
+                    return Ok(Async::Ready((outcome, self.next.take().unwrap())));
                 }
             } else if !self.try_send_money()? {
                 return Ok(Async::NotReady);
@@ -329,12 +770,14 @@ where
 mod send_money_tests {
     use super::*;
     use crate::test_helpers::TestAccount;
-    use bytes::Bytes;
+    use bytes::{Bytes, BytesMut};
+    use futures::future::empty;
     use interledger_ildcp::IldcpService;
     use interledger_packet::{ErrorCode as IlpErrorCode, RejectBuilder};
     use interledger_service::incoming_service_fn;
     use parking_lot::Mutex;
     use std::sync::Arc;
+    use tokio::runtime::Runtime;
 
     #[test]
     fn stops_at_final_errors() {
@@ -363,7 +806,152 @@ mod send_money_tests {
             100,
         )
         .wait();
-        assert!(result.is_err());
+        let (outcome, _service) = result.unwrap();
+        assert_eq!(
+            outcome.end_state,
+            PaymentEndState::FailedWithCode(IlpErrorCode::F00_BAD_REQUEST)
+        );
+        assert_eq!(outcome.rejected_packets[&IlpErrorCode::F00_BAD_REQUEST], 1);
         assert_eq!(requests.lock().len(), 1);
     }
+
+    #[test]
+    fn sends_return_pointer_and_metadata() {
+        let account = TestAccount {
+            id: 0,
+            asset_code: "XYZ".to_string(),
+            asset_scale: 9,
+            ilp_address: Bytes::from("example.destination"),
+        };
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let requests_clone = requests.clone();
+        send_money_with_options(
+            IldcpService::new(incoming_service_fn(move |request| {
+                requests_clone.lock().push(request);
+                Err(RejectBuilder {
+                    code: IlpErrorCode::F00_BAD_REQUEST,
+                    message: &[],
+                    triggered_by: b"example.connector",
+                    data: &[],
+                }
+                .build())
+            })),
+            &account,
+            b"example.destination",
+            &[0; 32][..],
+            100,
+            SendMoneyOptions::new()
+                .return_pointer("$sender.example".to_string())
+                .metadata("order-1".to_string()),
+        )
+        .wait()
+        .unwrap();
+        let request = requests.lock().pop().unwrap();
+        let packet =
+            StreamPacket::from_encrypted(&[0; 32], BytesMut::from(request.prepare.data())).unwrap();
+        let stream_data = |stream_id: u64| {
+            packet.frames().find_map(|frame| match frame {
+                Frame::StreamData(ref frame) if frame.stream_id == stream_id => {
+                    Some(frame.data.to_vec())
+                }
+                _ => None,
+            })
+        };
+        assert_eq!(
+            stream_data(RETURN_POINTER_STREAM_ID),
+            Some(b"$sender.example".to_vec())
+        );
+        assert_eq!(stream_data(METADATA_STREAM_ID), Some(b"order-1".to_vec()));
+    }
+
+    #[test]
+    fn uses_the_given_clock_for_expiries() {
+        let account = TestAccount {
+            id: 0,
+            asset_code: "XYZ".to_string(),
+            asset_scale: 9,
+            ilp_address: Bytes::from("example.destination"),
+        };
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_500_000_000);
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let requests_clone = requests.clone();
+        send_money_with_options(
+            IldcpService::new(incoming_service_fn(move |request| {
+                requests_clone.lock().push(request);
+                Err(RejectBuilder {
+                    code: IlpErrorCode::F00_BAD_REQUEST,
+                    message: &[],
+                    triggered_by: b"example.connector",
+                    data: &[],
+                }
+                .build())
+            })),
+            &account,
+            b"example.destination",
+            &[0; 32][..],
+            100,
+            SendMoneyOptions::new().clock(move || now),
+        )
+        .wait()
+        .unwrap();
+        let request = requests.lock().pop().unwrap();
+        assert_eq!(request.prepare.expires_at(), now + Duration::from_secs(30));
+    }
+
+    #[test]
+    fn fails_if_request_does_not_fit_in_a_packet() {
+        let account = TestAccount {
+            id: 0,
+            asset_code: "XYZ".to_string(),
+            asset_scale: 9,
+            ilp_address: Bytes::from("example.destination"),
+        };
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let requests_clone = requests.clone();
+        let result = send_money_with_options(
+            IldcpService::new(incoming_service_fn(move |request| {
+                requests_clone.lock().push(request);
+                Err(RejectBuilder {
+                    code: IlpErrorCode::F00_BAD_REQUEST,
+                    message: &[],
+                    triggered_by: b"example.connector",
+                    data: &[],
+                }
+                .build())
+            })),
+            &account,
+            b"example.destination",
+            &[0; 32][..],
+            100,
+            SendMoneyOptions::new().request(Bytes::from(vec![0; MAX_DATA_LENGTH])),
+        )
+        .wait();
+        assert!(result.is_err());
+        assert!(requests.lock().is_empty());
+    }
+
+    #[test]
+    fn stops_when_no_money_is_delivered() {
+        let account = TestAccount {
+            id: 0,
+            asset_code: "XYZ".to_string(),
+            asset_scale: 9,
+            ilp_address: Bytes::from("example.destination"),
+        };
+        // Packets are never fulfilled or rejected
+        let run = send_money_with_options(
+            IldcpService::new(incoming_service_fn(|_| empty())),
+            &account,
+            b"example.destination",
+            &[0; 32][..],
+            100,
+            SendMoneyOptions::new().max_idle(Duration::from_millis(10)),
+        );
+        let (outcome, _service) = Runtime::new().unwrap().block_on_all(run).unwrap();
+        assert_eq!(
+            outcome.end_state,
+            PaymentEndState::Partial("Payment stopped making progress".to_string())
+        );
+        assert_eq!(outcome.amount_delivered, 0);
+    }
 }
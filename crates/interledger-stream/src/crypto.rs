@@ -1,19 +1,18 @@
+use super::primitives::{RingCrypto, StreamCrypto, AUTH_TAG_LENGTH, NONCE_LENGTH};
 use bytes::BytesMut;
-use ring::rand::{SecureRandom, SystemRandom};
-use ring::{aead, digest, hmac};
 
-const NONCE_LENGTH: usize = 12;
-const AUTH_TAG_LENGTH: usize = 16;
+/// The implementation of the primitives the STREAM client and server use
+type Crypto = RingCrypto;
+
+/// Encrypted data is at least this long, even if the plaintext is empty
+pub(crate) const ENCRYPTION_OVERHEAD: usize = NONCE_LENGTH + AUTH_TAG_LENGTH;
 
 static ENCRYPTION_KEY_STRING: &[u8] = b"ilp_stream_encryption";
 static FULFILLMENT_GENERATION_STRING: &[u8] = b"ilp_stream_fulfillment";
+static ADDRESS_SUFFIX_STRING: &[u8] = b"ilp_stream_address_suffix";
 
 pub fn hmac_sha256(key: &[u8], message: &[u8]) -> [u8; 32] {
-    let key = hmac::SigningKey::new(&digest::SHA256, key);
-    let output = hmac::sign(&key, message);
-    let mut to_return: [u8; 32] = [0; 32];
-    to_return.copy_from_slice(output.as_ref());
-    to_return
+    Crypto::hmac_sign(&Crypto::hmac_key(key), message)
 }
 
 pub fn generate_fulfillment(shared_secret: &[u8], data: &[u8]) -> [u8; 32] {
@@ -21,11 +20,14 @@ pub fn generate_fulfillment(shared_secret: &[u8], data: &[u8]) -> [u8; 32] {
     hmac_sha256(&key[..], &data[..])
 }
 
+/// Derive the shared secret of a connection whose sender added the given suffix to the receiver's address
+pub fn suffix_shared_secret(shared_secret: &[u8], suffix: &[u8]) -> [u8; 32] {
+    let key = hmac_sha256(&shared_secret[..], &ADDRESS_SUFFIX_STRING);
+    hmac_sha256(&key[..], &suffix[..])
+}
+
 pub fn hash_sha256(preimage: &[u8]) -> [u8; 32] {
-    let output = digest::digest(&digest::SHA256, &preimage[..]);
-    let mut to_return: [u8; 32] = [0; 32];
-    to_return.copy_from_slice(output.as_ref());
-    to_return
+    Crypto::sha256(&preimage[..])
 }
 
 pub fn generate_condition(shared_secret: &[u8], data: &[u8]) -> [u8; 32] {
@@ -35,56 +37,75 @@ pub fn generate_condition(shared_secret: &[u8], data: &[u8]) -> [u8; 32] {
 
 pub fn random_condition() -> [u8; 32] {
     let mut condition_slice: [u8; 32] = [0; 32];
-    SystemRandom::new()
-        .fill(&mut condition_slice)
-        .expect("Failed to securely generate random condition!");
+    Crypto::fill_random(&mut condition_slice);
     condition_slice
 }
 
 pub fn generate_token() -> [u8; 18] {
     let mut token: [u8; 18] = [0; 18];
-    SystemRandom::new()
-        .fill(&mut token)
-        .expect("Failed to securely generate a random token!");
+    Crypto::fill_random(&mut token);
     token
 }
 
-pub fn encrypt(shared_secret: &[u8], plaintext: BytesMut) -> BytesMut {
-    // Generate a random nonce or IV
+/// The keys derived from a connection's shared secret, which can be reused for all of
+/// the connection's packets instead of being derived again for each one.
+pub struct ConnectionKeys {
+    pub shared_secret: [u8; 32],
+    fulfillment_key: <Crypto as StreamCrypto>::HmacKey,
+    encryption_key: [u8; 32],
+}
+
+impl ConnectionKeys {
+    pub fn new(shared_secret: [u8; 32]) -> Self {
+        let fulfillment_key = hmac_sha256(&shared_secret[..], &FULFILLMENT_GENERATION_STRING);
+        ConnectionKeys {
+            shared_secret,
+            fulfillment_key: Crypto::hmac_key(&fulfillment_key[..]),
+            encryption_key: hmac_sha256(&shared_secret[..], &ENCRYPTION_KEY_STRING),
+        }
+    }
+
+    /// Same as `generate_fulfillment` with the connection's shared secret
+    pub fn generate_fulfillment(&self, data: &[u8]) -> [u8; 32] {
+        Crypto::hmac_sign(&self.fulfillment_key, data)
+    }
+
+    /// Same as `encrypt` with the connection's shared secret
+    pub fn encrypt(&self, plaintext: BytesMut) -> BytesMut {
+        encrypt_with_key(&self.encryption_key, plaintext, random_nonce())
+    }
+
+    /// Same as `decrypt` with the connection's shared secret
+    pub fn decrypt(&self, ciphertext: BytesMut) -> Result<BytesMut, ()> {
+        decrypt_with_key(&self.encryption_key, ciphertext)
+    }
+}
+
+fn random_nonce() -> [u8; NONCE_LENGTH] {
     let mut nonce: [u8; NONCE_LENGTH] = [0; NONCE_LENGTH];
-    SystemRandom::new()
-        .fill(&mut nonce[..])
-        .expect("Failed to securely generate a random nonce!");
+    Crypto::fill_random(&mut nonce[..]);
+    nonce
+}
 
-    encrypt_with_nonce(shared_secret, plaintext, nonce)
+pub fn encrypt(shared_secret: &[u8], plaintext: BytesMut) -> BytesMut {
+    encrypt_with_nonce(shared_secret, plaintext, random_nonce())
 }
 
 fn encrypt_with_nonce(
     shared_secret: &[u8],
-    mut plaintext: BytesMut,
+    plaintext: BytesMut,
     nonce: [u8; NONCE_LENGTH],
 ) -> BytesMut {
     let key = hmac_sha256(&shared_secret[..], &ENCRYPTION_KEY_STRING);
-    let key = aead::SealingKey::new(&aead::AES_256_GCM, &key)
-        .expect("Failed to create a new sealing key for encrypting data!");
-
-    let additional_data = aead::Aad::from(&[]);
-
-    // seal_in_place expects the data to have enough room (in length, not just capacity) to append the auth tag
-    let auth_tag_place_holder: [u8; AUTH_TAG_LENGTH] = [0; AUTH_TAG_LENGTH];
-    plaintext.extend_from_slice(&auth_tag_place_holder[..]);
-
-    aead::seal_in_place(
-        &key,
-        aead::Nonce::assume_unique_for_key(nonce),
-        additional_data,
-        plaintext.as_mut(),
-        AUTH_TAG_LENGTH,
-    )
-    .unwrap_or_else(|err| {
-        error!("Error encrypting {:?}", err);
-        panic!(err);
-    });
+    encrypt_with_key(&key, plaintext, nonce)
+}
+
+fn encrypt_with_key(
+    key: &[u8; 32],
+    mut plaintext: BytesMut,
+    nonce: [u8; NONCE_LENGTH],
+) -> BytesMut {
+    Crypto::seal_in_place(key, nonce, &mut plaintext);
 
     // Rearrange the bytes so that the tag goes first (should have put it last in the JS implementation, but oh well)
     let auth_tag_position = plaintext.len() - AUTH_TAG_LENGTH;
@@ -98,31 +119,26 @@ fn encrypt_with_nonce(
     nonce_tag_data
 }
 
-pub fn decrypt(shared_secret: &[u8], mut ciphertext: BytesMut) -> Result<BytesMut, ()> {
+pub fn decrypt(shared_secret: &[u8], ciphertext: BytesMut) -> Result<BytesMut, ()> {
     let key = hmac_sha256(shared_secret, &ENCRYPTION_KEY_STRING);
-    let key = aead::OpeningKey::new(&aead::AES_256_GCM, &key)
-        .expect("Failed to create a new opening key for decrypting data!");
+    decrypt_with_key(&key, ciphertext)
+}
+
+fn decrypt_with_key(key: &[u8; 32], mut ciphertext: BytesMut) -> Result<BytesMut, ()> {
+    // Such as the empty data of rejects from connectors along the path
+    if ciphertext.len() < ENCRYPTION_OVERHEAD {
+        return Err(());
+    }
 
     let mut nonce: [u8; NONCE_LENGTH] = [0; NONCE_LENGTH];
     nonce.copy_from_slice(&ciphertext.split_to(NONCE_LENGTH));
 
     let auth_tag = ciphertext.split_to(AUTH_TAG_LENGTH);
-    let additional_data: &[u8] = &[];
 
-    // Ring expects the tag to come after the data
+    // The primitives expect the tag to come after the data
     ciphertext.unsplit(auth_tag);
 
-    let length = aead::open_in_place(
-        &key,
-        aead::Nonce::assume_unique_for_key(nonce),
-        aead::Aad::from(additional_data),
-        0,
-        ciphertext.as_mut(),
-    )
-    .map_err(|err| {
-        error!("Error decrypting {:?}", err);
-    })?
-    .len();
+    let length = Crypto::open_in_place(key, nonce, ciphertext.as_mut())?;
     ciphertext.truncate(length);
     Ok(ciphertext)
 }
@@ -183,6 +199,12 @@ mod encrypt_decrypt_test {
         assert_eq!(&decrypted.unwrap()[..], PLAINTEXT);
     }
 
+    #[test]
+    fn fails_to_decrypt_data_shorter_than_the_nonce_and_tag() {
+        assert!(decrypt(SHARED_SECRET, BytesMut::new()).is_err());
+        assert!(decrypt(SHARED_SECRET, BytesMut::from(&CIPHERTEXT[..20])).is_err());
+    }
+
     #[test]
     fn it_losslessly_encrypts_and_decrypts() {
         let ciphertext = encrypt(SHARED_SECRET, BytesMut::from(PLAINTEXT));
@@ -190,3 +212,30 @@ mod encrypt_decrypt_test {
         assert_eq!(&decrypted.unwrap()[..], PLAINTEXT);
     }
 }
+
+#[cfg(test)]
+mod connection_keys {
+    use super::*;
+
+    static SHARED_SECRET: [u8; 32] = [
+        126, 219, 117, 93, 118, 248, 249, 211, 20, 211, 65, 110, 237, 80, 253, 179, 81, 146, 229,
+        67, 231, 49, 92, 127, 254, 230, 144, 102, 103, 166, 150, 36,
+    ];
+    static PLAINTEXT: &[u8] = &[99, 0, 12, 255, 77, 31];
+
+    #[test]
+    fn derives_the_same_keys_as_the_shared_secret() {
+        let keys = ConnectionKeys::new(SHARED_SECRET);
+        assert_eq!(
+            keys.generate_fulfillment(PLAINTEXT),
+            generate_fulfillment(&SHARED_SECRET[..], PLAINTEXT)
+        );
+        let ciphertext = keys.encrypt(BytesMut::from(PLAINTEXT));
+        assert_eq!(
+            &decrypt(&SHARED_SECRET[..], ciphertext).unwrap()[..],
+            PLAINTEXT
+        );
+        let ciphertext = encrypt(&SHARED_SECRET[..], BytesMut::from(PLAINTEXT));
+        assert_eq!(&keys.decrypt(ciphertext).unwrap()[..], PLAINTEXT);
+    }
+}
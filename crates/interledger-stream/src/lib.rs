@@ -3,6 +3,10 @@
 //! Client and server implementations of the Interledger [STREAM](https://github.com/interledger/rfcs/blob/master/0029-stream/0029-stream.md) transport protocol.
 //!
 //! STREAM is responsible for splitting larger payments and messages into smaller chunks of money and data, and sending them over ILP.
+//!
+//! The client and server need the `ring` feature (enabled by default). Without it, only the
+//! STREAM packet format and the `StreamCrypto` trait are built, which also works on targets ring
+//! does not support, such as wasm32.
 #[macro_use]
 extern crate log;
 #[cfg(test)]
@@ -11,18 +15,37 @@ extern crate lazy_static;
 #[macro_use]
 extern crate failure;
 
+#[cfg(feature = "ring")]
 mod client;
+#[cfg(feature = "ring")]
 mod congestion;
+#[cfg(feature = "ring")]
 mod crypto;
 mod error;
-mod packet;
+mod outcome;
+pub mod packet;
+mod primitives;
+#[cfg(feature = "ring")]
 mod server;
 
-pub use client::send_money;
+#[cfg(feature = "ring")]
+pub use client::{
+    send_money, send_money_with_options, Clock, ExchangeRateProvider, SendMoneyOptions,
+    SlippagePolicy, METADATA_STREAM_ID, RETURN_POINTER_STREAM_ID,
+};
 pub use error::Error;
-pub use server::{ConnectionGenerator, StreamReceiverService};
+pub use outcome::{PaymentEndState, PaymentOutcome};
+#[cfg(feature = "ring")]
+pub use primitives::RingCrypto;
+pub use primitives::{StreamCrypto, AUTH_TAG_LENGTH, NONCE_LENGTH};
+#[cfg(feature = "ring")]
+pub use server::{
+    AcceptAllConnections, ConnectionGenerator, ConnectionPolicy, ConnectionPolicyRequest,
+    MinPacketAmountService, NoConnectionStore, NoDataHandler, StreamConnectionStore,
+    StreamDataHandler, StreamDataRequest, StreamReceiverService,
+};
 
-#[cfg(test)]
+#[cfg(all(test, feature = "ring"))]
 pub mod test_helpers {
     use bytes::Bytes;
     use futures::{future::ok, Future};
@@ -73,8 +96,8 @@ pub mod test_helpers {
         fn get_accounts(
             &self,
             _account_ids: Vec<<<Self as AccountStore>::Account as Account>::AccountId>,
-        ) -> Box<Future<Item = Vec<TestAccount>, Error = ()> + Send> {
-            Box::new(ok(vec![self.route.1.clone()]))
+        ) -> Box<Future<Item = Vec<Option<TestAccount>>, Error = ()> + Send> {
+            Box::new(ok(vec![Some(self.route.1.clone())]))
         }
     }
 
@@ -85,12 +108,12 @@ pub mod test_helpers {
     }
 }
 
-#[cfg(test)]
+#[cfg(all(test, feature = "ring"))]
 mod send_money_to_receiver {
     use super::test_helpers::*;
     use super::*;
     use bytes::Bytes;
-    use futures::Future;
+    use futures::{future::ok, Future};
     use interledger_ildcp::IldcpService;
     use interledger_packet::{ErrorCode, RejectBuilder};
     use interledger_router::Router;
@@ -141,12 +164,169 @@ mod send_money_to_receiver {
             &shared_secret[..],
             100,
         )
-        .and_then(|(amount_delivered, _service)| {
-            assert_eq!(amount_delivered, 100);
+        .and_then(|(outcome, _service)| {
+            assert_eq!(outcome.amount_delivered, 100);
+            assert!(outcome.is_complete());
             Ok(())
         })
         .map_err(|err| panic!(err));
         let runtime = Runtime::new().unwrap();
         runtime.block_on_all(run).unwrap();
     }
+
+    #[test]
+    fn stops_if_exchange_rate_is_too_low() {
+        let server_secret = Bytes::from(&[0; 32][..]);
+        let destination_address = Bytes::from("example.receiver");
+        let account = TestAccount {
+            id: 0,
+            ilp_address: destination_address.clone(),
+            asset_code: "XYZ".to_string(),
+            asset_scale: 9,
+        };
+        let store = TestStore {
+            route: (destination_address.clone(), account.clone()),
+        };
+        let connection_generator = ConnectionGenerator::new(server_secret.clone());
+        let server = StreamReceiverService::new(
+            server_secret,
+            outgoing_service_fn(|_| {
+                Err(RejectBuilder {
+                    code: ErrorCode::F02_UNREACHABLE,
+                    message: b"No other outgoing handler",
+                    triggered_by: b"example.receiver",
+                    data: &[],
+                }
+                .build())
+            }),
+        );
+        let server = IldcpService::new(Router::new(store, server));
+        let (destination_account, shared_secret) =
+            connection_generator.generate_address_and_secret(&destination_address[..]);
+
+        // The market rate says the receiver should get twice what is actually delivered
+        let options = SendMoneyOptions::new().max_slippage(|_: &str, _: &str| Some(2.0), 0.01);
+        let run = send_money_with_options(
+            server,
+            &account,
+            &destination_account[..],
+            &shared_secret[..],
+            100,
+            options,
+        );
+        let runtime = Runtime::new().unwrap();
+        let (outcome, _service) = runtime.block_on_all(run).unwrap();
+        match outcome.end_state {
+            PaymentEndState::Partial(_) => {}
+            end_state => panic!("Expected payment to stop early, got: {:?}", end_state),
+        }
+        // The first packet is only checked after it is fulfilled
+        assert_eq!(outcome.amount_delivered, 100);
+    }
+    #[test]
+    fn sends_money_to_randomized_address() {
+        let server_secret = Bytes::from(&[0; 32][..]);
+        let destination_address = Bytes::from("example.receiver");
+        let account = TestAccount {
+            id: 0,
+            ilp_address: destination_address.clone(),
+            asset_code: "XYZ".to_string(),
+            asset_scale: 9,
+        };
+        let store = TestStore {
+            route: (destination_address.clone(), account.clone()),
+        };
+        let connection_generator = ConnectionGenerator::new(server_secret.clone());
+        let server = StreamReceiverService::new(
+            server_secret,
+            outgoing_service_fn(|_| {
+                Err(RejectBuilder {
+                    code: ErrorCode::F02_UNREACHABLE,
+                    message: b"No other outgoing handler",
+                    triggered_by: b"example.receiver",
+                    data: &[],
+                }
+                .build())
+            }),
+        );
+        let server = IldcpService::new(Router::new(store, server));
+        let (destination_account, shared_secret) =
+            connection_generator.generate_address_and_secret(&destination_address[..]);
+
+        let run = send_money_with_options(
+            server,
+            &account,
+            &destination_account[..],
+            &shared_secret[..],
+            100,
+            SendMoneyOptions::new().randomize_address(true),
+        );
+        let runtime = Runtime::new().unwrap();
+        let (outcome, _service) = runtime.block_on_all(run).unwrap();
+        assert_eq!(outcome.amount_delivered, 100);
+        assert!(outcome.is_complete());
+    }
+
+    /// Responds to requests with the same data
+    #[derive(Clone)]
+    struct EchoHandler;
+
+    impl StreamDataHandler<TestAccount> for EchoHandler {
+        fn handle_data(
+            &self,
+            _account: &TestAccount,
+            request: StreamDataRequest,
+        ) -> Box<Future<Item = Option<Bytes>, Error = ()> + Send> {
+            if request.data.is_empty() {
+                Box::new(ok(None))
+            } else {
+                Box::new(ok(Some(request.data)))
+            }
+        }
+    }
+
+    #[test]
+    fn gets_response_to_request() {
+        let server_secret = Bytes::from(&[0; 32][..]);
+        let destination_address = Bytes::from("example.receiver");
+        let account = TestAccount {
+            id: 0,
+            ilp_address: destination_address.clone(),
+            asset_code: "XYZ".to_string(),
+            asset_scale: 9,
+        };
+        let store = TestStore {
+            route: (destination_address.clone(), account.clone()),
+        };
+        let connection_generator = ConnectionGenerator::new(server_secret.clone());
+        let server = StreamReceiverService::new(
+            server_secret,
+            outgoing_service_fn(|_| {
+                Err(RejectBuilder {
+                    code: ErrorCode::F02_UNREACHABLE,
+                    message: b"No other outgoing handler",
+                    triggered_by: b"example.receiver",
+                    data: &[],
+                }
+                .build())
+            }),
+        )
+        .data_handler(EchoHandler);
+        let server = IldcpService::new(Router::new(store, server));
+        let (destination_account, shared_secret) =
+            connection_generator.generate_address_and_secret(&destination_address[..]);
+
+        let run = send_money_with_options(
+            server,
+            &account,
+            &destination_account[..],
+            &shared_secret[..],
+            100,
+            SendMoneyOptions::new().request(Bytes::from("ping")),
+        );
+        let runtime = Runtime::new().unwrap();
+        let (outcome, _service) = runtime.block_on_all(run).unwrap();
+        assert!(outcome.is_complete());
+        assert_eq!(outcome.response, Some(Bytes::from("ping")));
+    }
 }
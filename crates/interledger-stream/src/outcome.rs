@@ -0,0 +1,41 @@
+use bytes::Bytes;
+use hashbrown::HashMap;
+use interledger_packet::ErrorCode;
+
+/// How a payment ended.
+#[derive(Clone, Debug, PartialEq)]
+pub enum PaymentEndState {
+    /// The full source amount was sent
+    Completed,
+    /// The sender stopped before sending the full amount, for example because
+    /// it timed out or the exchange rate was too low. Includes the reason
+    Partial(String),
+    /// A packet was rejected with a final error, so the sender stopped
+    FailedWithCode(ErrorCode),
+}
+
+/// The result of sending a payment with STREAM.
+///
+/// Payments can stop part of the way through, so this is returned whether or not
+/// the full amount was sent. Check the `end_state` to see which happened.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PaymentOutcome {
+    /// In the receiver's units, as reported by the receiver
+    pub amount_delivered: u64,
+    /// In the sender's units. This only includes packets that were fulfilled
+    pub source_amount_sent: u64,
+    /// The receiver's asset code and scale, if it told us
+    pub receiver_asset: Option<(String, u8)>,
+    /// The receiver's response to the `SendMoneyOptions::request`, if it sent one
+    pub response: Option<Bytes>,
+    pub end_state: PaymentEndState,
+    pub fulfilled_packets: u64,
+    /// The number of rejected packets for each ILP error code
+    pub rejected_packets: HashMap<ErrorCode, u64>,
+}
+
+impl PaymentOutcome {
+    pub fn is_complete(&self) -> bool {
+        self.end_state == PaymentEndState::Completed
+    }
+}
@@ -1,4 +1,5 @@
-use super::crypto::{decrypt, encrypt};
+#[cfg(feature = "ring")]
+use super::crypto::{decrypt, encrypt, ConnectionKeys};
 use byteorder::ReadBytesExt;
 use bytes::{BufMut, BytesMut};
 use interledger_packet::{
@@ -8,6 +9,8 @@ use interledger_packet::{
 use std::{fmt, str};
 
 const STREAM_VERSION: u8 = 1;
+/// The most data an ILP Prepare or Fulfill can carry, including the STREAM packet's encryption overhead
+pub const MAX_DATA_LENGTH: usize = 32767;
 
 pub struct StreamPacketBuilder<'a> {
     pub sequence: u64,
@@ -112,6 +115,7 @@ pub struct StreamPacket {
 }
 
 impl StreamPacket {
+    #[cfg(feature = "ring")]
     pub fn from_encrypted(shared_secret: &[u8], ciphertext: BytesMut) -> Result<Self, ParseError> {
         // TODO handle decryption failure
         let decrypted = decrypt(shared_secret, ciphertext)
@@ -119,7 +123,20 @@ impl StreamPacket {
         StreamPacket::from_bytes_unencrypted(decrypted)
     }
 
-    fn from_bytes_unencrypted(buffer_unencrypted: BytesMut) -> Result<Self, ParseError> {
+    /// Same as `from_encrypted`, using keys that were already derived from the shared secret
+    #[cfg(feature = "ring")]
+    pub(crate) fn from_encrypted_with_keys(
+        keys: &ConnectionKeys,
+        ciphertext: BytesMut,
+    ) -> Result<Self, ParseError> {
+        let decrypted = keys
+            .decrypt(ciphertext)
+            .map_err(|_err| ParseError::InvalidPacket(String::from("Unable to decrypt packet")))?;
+        StreamPacket::from_bytes_unencrypted(decrypted)
+    }
+
+    /// Parse a packet that was already decrypted, such as with another `StreamCrypto` implementation
+    pub fn from_bytes_unencrypted(buffer_unencrypted: BytesMut) -> Result<Self, ParseError> {
         // TODO don't copy the whole packet again
         let mut reader = &buffer_unencrypted[..];
         let version = reader.read_u8()?;
@@ -158,10 +175,22 @@ impl StreamPacket {
         }
     }
 
+    #[cfg(feature = "ring")]
     pub fn into_encrypted(self, shared_secret: &[u8]) -> BytesMut {
         encrypt(shared_secret, self.buffer_unencrypted)
     }
 
+    /// Same as `into_encrypted`, using keys that were already derived from the shared secret
+    #[cfg(feature = "ring")]
+    pub(crate) fn into_encrypted_with_keys(self, keys: &ConnectionKeys) -> BytesMut {
+        keys.encrypt(self.buffer_unencrypted)
+    }
+
+    /// The packet's bytes before they are encrypted
+    pub fn into_bytes_unencrypted(self) -> BytesMut {
+        self.buffer_unencrypted
+    }
+
     pub fn sequence(&self) -> u64 {
         self.sequence
     }
@@ -808,6 +837,18 @@ mod serialization {
         );
     }
 
+    #[test]
+    fn non_canonical_sequence_numbers() {
+        // Sequence 1 encoded with a leading zero byte, amount 99, no frames
+        let packet = BytesMut::from(vec![1, 12, 2, 0, 1, 1, 99, 1, 0]);
+        let result = StreamPacket::from_bytes_unencrypted(packet);
+        if cfg!(feature = "strict") {
+            assert!(result.is_err());
+        } else {
+            assert_eq!(result.unwrap().sequence(), 1);
+        }
+    }
+
     #[test]
     fn it_iterates_through_the_frames() {
         let mut iter = PACKET.frames();
@@ -0,0 +1,117 @@
+use bytes::BytesMut;
+#[cfg(feature = "ring")]
+use ring::{
+    aead, digest, hmac,
+    rand::{SecureRandom, SystemRandom},
+};
+
+/// The length of the nonces STREAM packets are encrypted with
+pub const NONCE_LENGTH: usize = 12;
+/// The length of the AES-GCM auth tag of encrypted STREAM packets
+pub const AUTH_TAG_LENGTH: usize = 16;
+
+/// The cryptographic primitives STREAM is built on: HMAC-SHA256, SHA-256, AES-256-GCM
+/// and secure randomness.
+///
+/// They are provided by ring with the (default) `ring` feature. ring does not build for every
+/// target, such as wasm32, so the STREAM client and server are only available with it.
+pub trait StreamCrypto {
+    /// An HMAC-SHA256 key, which may be prepared once and used for many messages
+    type HmacKey;
+
+    fn hmac_key(key: &[u8]) -> Self::HmacKey;
+
+    fn hmac_sign(key: &Self::HmacKey, message: &[u8]) -> [u8; 32];
+
+    fn sha256(preimage: &[u8]) -> [u8; 32];
+
+    /// Fill the buffer with cryptographically secure random bytes
+    fn fill_random(buffer: &mut [u8]);
+
+    /// Encrypt the data in place and append the `AUTH_TAG_LENGTH`-byte auth tag to it
+    fn seal_in_place(key: &[u8; 32], nonce: [u8; NONCE_LENGTH], data: &mut BytesMut);
+
+    /// Decrypt data that is followed by its auth tag in place, returning the plaintext's length
+    fn open_in_place(
+        key: &[u8; 32],
+        nonce: [u8; NONCE_LENGTH],
+        data: &mut [u8],
+    ) -> Result<usize, ()>;
+}
+
+/// `StreamCrypto` implemented with ring.
+#[cfg(feature = "ring")]
+#[derive(Debug, Clone, Copy)]
+pub struct RingCrypto;
+
+#[cfg(feature = "ring")]
+impl StreamCrypto for RingCrypto {
+    type HmacKey = hmac::SigningKey;
+
+    fn hmac_key(key: &[u8]) -> Self::HmacKey {
+        hmac::SigningKey::new(&digest::SHA256, key)
+    }
+
+    fn hmac_sign(key: &Self::HmacKey, message: &[u8]) -> [u8; 32] {
+        let output = hmac::sign(key, message);
+        let mut to_return: [u8; 32] = [0; 32];
+        to_return.copy_from_slice(output.as_ref());
+        to_return
+    }
+
+    fn sha256(preimage: &[u8]) -> [u8; 32] {
+        let output = digest::digest(&digest::SHA256, preimage);
+        let mut to_return: [u8; 32] = [0; 32];
+        to_return.copy_from_slice(output.as_ref());
+        to_return
+    }
+
+    fn fill_random(buffer: &mut [u8]) {
+        SystemRandom::new()
+            .fill(buffer)
+            .expect("Failed to securely generate random bytes!");
+    }
+
+    fn seal_in_place(key: &[u8; 32], nonce: [u8; NONCE_LENGTH], data: &mut BytesMut) {
+        let key = aead::SealingKey::new(&aead::AES_256_GCM, &key[..])
+            .expect("Failed to create a new sealing key for encrypting data!");
+
+        // seal_in_place expects the data to have enough room (in length, not just capacity) to append the auth tag
+        let auth_tag_place_holder: [u8; AUTH_TAG_LENGTH] = [0; AUTH_TAG_LENGTH];
+        data.extend_from_slice(&auth_tag_place_holder[..]);
+
+        aead::seal_in_place(
+            &key,
+            aead::Nonce::assume_unique_for_key(nonce),
+            aead::Aad::from(&[]),
+            data.as_mut(),
+            AUTH_TAG_LENGTH,
+        )
+        .unwrap_or_else(|err| {
+            error!("Error encrypting {:?}", err);
+            panic!(err);
+        });
+    }
+
+    fn open_in_place(
+        key: &[u8; 32],
+        nonce: [u8; NONCE_LENGTH],
+        data: &mut [u8],
+    ) -> Result<usize, ()> {
+        let key = aead::OpeningKey::new(&aead::AES_256_GCM, &key[..])
+            .expect("Failed to create a new opening key for decrypting data!");
+        let additional_data: &[u8] = &[];
+
+        aead::open_in_place(
+            &key,
+            aead::Nonce::assume_unique_for_key(nonce),
+            aead::Aad::from(additional_data),
+            0,
+            data,
+        )
+        .map(|plaintext| plaintext.len())
+        .map_err(|err| {
+            error!("Error decrypting {:?}", err);
+        })
+    }
+}
@@ -1,19 +1,47 @@
 use super::crypto::*;
-use super::packet::*;
+use super::packet::{ErrorCode as StreamErrorCode, *};
 use base64;
 use bytes::{BufMut, Bytes, BytesMut};
-use futures::future::result;
+use futures::{
+    future::{err, join_all, ok, result, Either, FutureResult},
+    Future,
+};
 use hex;
 use interledger_ildcp::IldcpAccount;
 use interledger_packet::{
     ErrorCode, Fulfill, FulfillBuilder, PacketType as IlpPacketType, Prepare, Reject, RejectBuilder,
 };
 use interledger_service::{Account, BoxedIlpFuture, OutgoingRequest, OutgoingService};
+use std::collections::{HashMap, VecDeque};
 use std::marker::PhantomData;
 use std::str;
+use std::sync::{Arc, Mutex};
 
 const STREAM_SERVER_SECRET_GENERATOR: &[u8] = b"ilp_stream_secret_generator";
 
+/// How many connections' keys the `ConnectionGenerator` keeps
+const KEY_CACHE_SIZE: usize = 10_000;
+
+/// The keys of the most recently seen connections, keyed by their full destination address.
+/// The oldest connections are dropped first when the cache is full.
+#[derive(Default)]
+struct KeyCache {
+    keys: HashMap<Bytes, Arc<ConnectionKeys>>,
+    order: VecDeque<Bytes>,
+}
+
+impl KeyCache {
+    fn insert(&mut self, destination_account: Bytes, keys: Arc<ConnectionKeys>) {
+        if self.order.len() >= KEY_CACHE_SIZE {
+            if let Some(oldest) = self.order.pop_front() {
+                self.keys.remove(&oldest);
+            }
+        }
+        self.order.push_back(destination_account.clone());
+        self.keys.insert(destination_account, keys);
+    }
+}
+
 /// A STREAM connection generator that creates `destination_account` and `shared_secret` values
 /// based on a single root secret.
 ///
@@ -22,6 +50,7 @@ const STREAM_SERVER_SECRET_GENERATOR: &[u8] = b"ilp_stream_secret_generator";
 #[derive(Clone)]
 pub struct ConnectionGenerator {
     secret_generator: Bytes,
+    key_cache: Arc<Mutex<KeyCache>>,
 }
 
 impl ConnectionGenerator {
@@ -31,6 +60,7 @@ impl ConnectionGenerator {
             secret_generator: Bytes::from(
                 &hmac_sha256(&server_secret[..], STREAM_SERVER_SECRET_GENERATOR)[..],
             ),
+            key_cache: Arc::new(Mutex::new(KeyCache::default())),
         }
     }
 
@@ -60,10 +90,84 @@ impl ConnectionGenerator {
         (destination_account.freeze(), shared_secret)
     }
 
+    /// Same as `generate_address_and_secret` but with a tag in the address, such as an invoice ID,
+    /// which the `ConnectionPolicy` is given for each of the connection's packets.
+    ///
+    /// The tag is covered by the address's auth tag, so senders cannot change it. It is visible
+    /// to the connectors on the path, so it should not contain anything private. It must be a
+    /// single ILP address segment that does not contain `~`.
+    pub fn generate_address_and_secret_with_tag(
+        &self,
+        base_address: &[u8],
+        connection_tag: &[u8],
+    ) -> Result<(Bytes, [u8; 32]), ()> {
+        if connection_tag.is_empty()
+            || !connection_tag
+                .iter()
+                .all(|c| c.is_ascii_alphanumeric() || c == &b'_' || c == &b'-')
+        {
+            return Err(());
+        }
+        let mut tagged_address =
+            BytesMut::with_capacity(base_address.len() + connection_tag.len() + 1);
+        tagged_address.put(base_address);
+        tagged_address.put(b'.');
+        tagged_address.put(connection_tag);
+        Ok(self.generate_address_and_secret(&tagged_address[..]))
+    }
+
     /// Rederive the `shared_secret` from a `destination_account`. This will return an
     /// error if the address has been modified in any way or if the packet was not generated
     /// with the same server secret.
+    ///
+    /// Senders may append a `~` and a random suffix to the generated address, so that each of
+    /// their connections uses a different address (see `SendMoneyOptions::randomize_address`).
+    /// Each suffix is a separate connection whose shared secret is derived from the suffix.
     pub fn rederive_secret(&self, destination_account: &[u8]) -> Result<[u8; 32], ()> {
+        let segment_start = destination_account
+            .iter()
+            .rposition(|c| c == &b'.')
+            .map(|index| index + 1)
+            .unwrap_or(0);
+        match destination_account[segment_start..]
+            .iter()
+            .position(|c| c == &b'~')
+        {
+            Some(index) => {
+                let (generated, suffix) = destination_account.split_at(segment_start + index);
+                let suffix = &suffix[1..];
+                if suffix.is_empty() {
+                    return Err(());
+                }
+                self.rederive_generated_secret(generated)
+                    .map(|shared_secret| suffix_shared_secret(&shared_secret[..], suffix))
+            }
+            None => self.rederive_generated_secret(destination_account),
+        }
+    }
+
+    /// Same as `rederive_secret`, but returns all of the keys derived from the connection's
+    /// shared secret. The keys of recently seen connections are cached, so that they are
+    /// not derived again for each of the connection's packets.
+    pub(crate) fn connection_keys(
+        &self,
+        destination_account: &[u8],
+    ) -> Result<Arc<ConnectionKeys>, ()> {
+        if let Some(keys) = self.key_cache.lock().unwrap().keys.get(destination_account) {
+            return Ok(keys.clone());
+        }
+        // Only addresses whose auth tag checks out are cached
+        let keys = Arc::new(ConnectionKeys::new(
+            self.rederive_secret(destination_account)?,
+        ));
+        self.key_cache
+            .lock()
+            .unwrap()
+            .insert(Bytes::from(destination_account), keys.clone());
+        Ok(keys)
+    }
+
+    fn rederive_generated_secret(&self, destination_account: &[u8]) -> Result<[u8; 32], ()> {
         if let Some(local_part) = destination_account.rsplit(|c| c == &b'.').next() {
             let local_part =
                 base64::decode_config(local_part, base64::URL_SAFE_NO_PAD).map_err(|_| ())?;
@@ -88,15 +192,186 @@ impl ConnectionGenerator {
     }
 }
 
+/// Keeps track of how much each STREAM connection has received.
+///
+/// Storing this outside of the `StreamReceiverService` means that multiple
+/// instances of a node (for example, behind a load balancer) can fulfill packets
+/// for the same connections, as long as they are configured with the same server secret.
+/// Each connection is one incoming payment to the account it was generated for.
+pub trait StreamConnectionStore<A: Account>: Clone + Send + Sync + 'static {
+    /// Add the amount to the total received on the connection and return the new total.
+    ///
+    /// The `connection_id` is the last segment of the connection's ILP address. The
+    /// `connection_tag` is the tag the receiver generated the address with, if any
+    /// (see `ConnectionGenerator::generate_address_and_secret_with_tag`), which is the
+    /// same for all of the connection's packets.
+    ///
+    /// Fails if the total would be more than the store can hold, in which case the packet
+    /// is rejected instead of the total wrapping around.
+    fn add_amount_received(
+        &self,
+        account: &A,
+        connection_id: &[u8],
+        connection_tag: Option<&[u8]>,
+        amount: u64,
+    ) -> Box<Future<Item = u64, Error = ()> + Send>;
+
+    /// Called when the sender closes the connection, which means the payment is complete.
+    fn close_connection(
+        &self,
+        _account: &A,
+        _connection_id: &[u8],
+    ) -> Box<Future<Item = (), Error = ()> + Send> {
+        Box::new(ok(()))
+    }
+}
+
+/// Used when the connection state is not stored.
+/// The receiver always reports that nothing has been received on the connection.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NoConnectionStore;
+
+impl<A: Account> StreamConnectionStore<A> for NoConnectionStore {
+    fn add_amount_received(
+        &self,
+        _account: &A,
+        _connection_id: &[u8],
+        _connection_tag: Option<&[u8]>,
+        _amount: u64,
+    ) -> Box<Future<Item = u64, Error = ()> + Send> {
+        Box::new(ok(0))
+    }
+}
+
+/// Data (or money) received on one of a connection's streams in a packet that is being fulfilled.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StreamDataRequest {
+    /// The last segment of the connection's ILP address, as for the `StreamConnectionStore`
+    pub connection_id: Bytes,
+    pub stream_id: u64,
+    /// Where `data` starts within everything the sender has sent on the stream
+    pub offset: u64,
+    /// Empty if the packet only carried money for this stream
+    pub data: Bytes,
+    /// The total received on the connection, including this packet
+    pub total_received: u64,
+}
+
+/// Responds to data sent over STREAM, for request/response protocols such as paid APIs.
+///
+/// The handler is called for each stream that a fulfilled packet carries data or money for.
+/// Whatever it returns is sent back in the Fulfill as data on the same stream, which is
+/// how senders match responses to their requests. Each request should use a new stream because
+/// the response is always sent at offset 0. Responses must fit in the Fulfill packet (32767 bytes
+/// including the STREAM packet's other frames and encryption), otherwise they are dropped.
+///
+/// To only respond once the request has been paid for, a handler can remember the request
+/// and return `None` until the `total_received` is enough, then return the response
+/// for the stream with the packet that carries the rest of the money.
+pub trait StreamDataHandler<A: Account>: Clone + Send + Sync + 'static {
+    fn handle_data(
+        &self,
+        account: &A,
+        request: StreamDataRequest,
+    ) -> Box<Future<Item = Option<Bytes>, Error = ()> + Send>;
+}
+
+/// Used when the receiver does not respond to data.
+/// Data sent to the receiver is accepted and dropped.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NoDataHandler;
+
+impl<A: Account> StreamDataHandler<A> for NoDataHandler {
+    fn handle_data(
+        &self,
+        _account: &A,
+        _request: StreamDataRequest,
+    ) -> Box<Future<Item = Option<Bytes>, Error = ()> + Send> {
+        Box::new(ok(None))
+    }
+}
+
+/// A packet that the receiver is about to fulfill, as seen by a `ConnectionPolicy`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConnectionPolicyRequest {
+    /// The last segment of the connection's ILP address, as for the `StreamConnectionStore`
+    pub connection_id: Bytes,
+    /// The tag the receiver put in the connection's address, if any
+    /// (see `ConnectionGenerator::generate_address_and_secret_with_tag`)
+    pub connection_tag: Option<Bytes>,
+    pub amount: u64,
+    /// The `(stream_id, offset, data)` the sender sent on each stream in this packet.
+    /// Streams that only received money are included with no data
+    pub data: Vec<(u64, u64, Bytes)>,
+}
+
+/// Decides whether the receiver accepts money on a connection, for example to only accept
+/// payments for invoices that are expected instead of any payment to the account.
+///
+/// The policy is asked about every packet that would be fulfilled. The connection tag is in
+/// every packet, but application data such as an order ID is usually only sent once, so
+/// policies that look at the data should remember which connections they accepted.
+pub trait ConnectionPolicy<A: Account>: Clone + Send + Sync + 'static {
+    /// Resolves to an error with a message for the sender to reject the packet and close the connection.
+    fn check_packet(
+        &self,
+        account: &A,
+        request: &ConnectionPolicyRequest,
+    ) -> Box<Future<Item = (), Error = String> + Send>;
+
+    /// Called once a packet the policy accepted is fulfilled.
+    fn packet_fulfilled(
+        &self,
+        _account: &A,
+        _request: &ConnectionPolicyRequest,
+    ) -> Box<Future<Item = (), Error = ()> + Send> {
+        Box::new(ok(()))
+    }
+
+    /// Called if a packet the policy accepted is rejected after all, because the receiver could
+    /// not record it, so that anything `check_packet` counted can be undone.
+    fn packet_rejected(
+        &self,
+        _account: &A,
+        _request: &ConnectionPolicyRequest,
+    ) -> Box<Future<Item = (), Error = ()> + Send> {
+        Box::new(ok(()))
+    }
+}
+
+/// Used when the receiver accepts payments on any connection.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct AcceptAllConnections;
+
+impl<A: Account> ConnectionPolicy<A> for AcceptAllConnections {
+    fn check_packet(
+        &self,
+        _account: &A,
+        _request: &ConnectionPolicyRequest,
+    ) -> Box<Future<Item = (), Error = String> + Send> {
+        Box::new(ok(()))
+    }
+}
+
 /// An OutgoingService that fulfills incoming STREAM packets.
 ///
 /// Note this does **not** maintain STREAM state, but instead fulfills
-/// all incoming packets to collect the money.
-///
-/// This does not currently support handling data sent via STREAM.
+/// all incoming packets to collect the money. Optionally, the amount received
+/// on each connection can be tracked in a `StreamConnectionStore`, data
+/// sent on the connection's streams can be answered by a `StreamDataHandler`
+/// and connections can be rejected by a `ConnectionPolicy`.
 #[derive(Clone)]
-pub struct StreamReceiverService<S: OutgoingService<A>, A: Account> {
+pub struct StreamReceiverService<
+    S: OutgoingService<A>,
+    A: Account,
+    T = NoConnectionStore,
+    D = NoDataHandler,
+    P = AcceptAllConnections,
+> {
     connection_generator: ConnectionGenerator,
+    store: T,
+    data_handler: D,
+    policy: P,
     next: S,
     account_type: PhantomData<A>,
 }
@@ -107,20 +382,77 @@ where
     A: Account,
 {
     pub fn new(server_secret: Bytes, next: S) -> Self {
+        StreamReceiverService::with_store(server_secret, NoConnectionStore, next)
+    }
+}
+
+impl<S, A, T> StreamReceiverService<S, A, T>
+where
+    S: OutgoingService<A>,
+    A: Account,
+    T: StreamConnectionStore<A>,
+{
+    /// Create a receiver that records the amount received on each connection in the store.
+    pub fn with_store(server_secret: Bytes, store: T, next: S) -> Self {
         let connection_generator = ConnectionGenerator::new(server_secret);
         StreamReceiverService {
             connection_generator,
+            store,
+            data_handler: NoDataHandler,
+            policy: AcceptAllConnections,
             next,
             account_type: PhantomData,
         }
     }
 }
 
+impl<S, A, T, D, P> StreamReceiverService<S, A, T, D, P>
+where
+    S: OutgoingService<A>,
+    A: Account,
+    T: StreamConnectionStore<A>,
+    D: StreamDataHandler<A>,
+    P: ConnectionPolicy<A>,
+{
+    /// Pass the data received on each stream to the handler and send its responses back to the sender.
+    pub fn data_handler<H>(self, data_handler: H) -> StreamReceiverService<S, A, T, H, P>
+    where
+        H: StreamDataHandler<A>,
+    {
+        StreamReceiverService {
+            connection_generator: self.connection_generator,
+            store: self.store,
+            data_handler,
+            policy: self.policy,
+            next: self.next,
+            account_type: PhantomData,
+        }
+    }
+
+    /// Only fulfill the packets that the policy accepts.
+    pub fn connection_policy<Q>(self, policy: Q) -> StreamReceiverService<S, A, T, D, Q>
+    where
+        Q: ConnectionPolicy<A>,
+    {
+        StreamReceiverService {
+            connection_generator: self.connection_generator,
+            store: self.store,
+            data_handler: self.data_handler,
+            policy,
+            next: self.next,
+            account_type: PhantomData,
+        }
+    }
+}
+
 // TODO should this be an OutgoingService instead so the balance logic is applied before this is called?
-impl<S, A> OutgoingService<A> for StreamReceiverService<S, A>
+impl<S, A, T, D, P> OutgoingService<A> for StreamReceiverService<S, A, T, D, P>
 where
     S: OutgoingService<A>,
-    A: Account + IldcpAccount,
+    A: Account + IldcpAccount + 'static,
+    T: StreamConnectionStore<A>,
+    D: StreamDataHandler<A>,
+    P: ConnectionPolicy<A>,
 {
     type Future = BoxedIlpFuture;
 
@@ -136,31 +468,324 @@ where
             .destination()
             .starts_with(request.to.client_address())
         {
-            if let Ok(shared_secret) = self
+            if let Ok(keys) = self
                 .connection_generator
-                .rederive_secret(request.prepare.destination())
+                .connection_keys(request.prepare.destination())
             {
-                {
-                    return Box::new(result(receive_money(
-                        &shared_secret,
-                        request.to.client_address(),
-                        request.prepare,
-                    )));
-                }
+                let client_address = Bytes::from(request.to.client_address());
+                let connection_id = request
+                    .prepare
+                    .destination()
+                    .rsplit(|c| c == &b'.')
+                    .next()
+                    .map(Bytes::from)
+                    .unwrap_or_default();
+                let connection_tag =
+                    connection_tag(request.prepare.destination(), &client_address[..]);
+                let packet = match decrypt_prepare(&keys, &client_address, request.prepare) {
+                    Ok(packet) => packet,
+                    Err(reject) => return Box::new(err(reject)),
+                };
+                // Rejected packets don't change the total but we still report it to the sender
+                let amount = if packet.should_fulfill() {
+                    packet.prepare_amount
+                } else {
+                    0
+                };
+                let closes_connection = packet.closes_connection();
+                // Data is only passed on from packets we fulfill, because the sender
+                // will retry the others and the handler would see the data twice
+                let stream_data = if packet.should_fulfill() {
+                    packet.stream_data()
+                } else {
+                    Vec::new()
+                };
+                let policy_request = if packet.should_fulfill() {
+                    Some(ConnectionPolicyRequest {
+                        connection_id: connection_id.clone(),
+                        connection_tag: connection_tag.clone(),
+                        amount,
+                        data: stream_data.clone(),
+                    })
+                } else {
+                    None
+                };
+                let check_policy = match policy_request {
+                    Some(ref policy_request) => {
+                        Either::A(self.policy.check_packet(&request.to, policy_request))
+                    }
+                    None => Either::B(ok(())),
+                };
+                let policy = self.policy.clone();
+                let store = self.store.clone();
+                let data_handler = self.data_handler.clone();
+                let account = request.to.clone();
+                let data_account = request.to.clone();
+                let policy_account = request.to.clone();
+                let data_connection_id = connection_id.clone();
+                let asset_code = request.to.asset_code().to_string();
+                let asset_scale = request.to.asset_scale();
+                return Box::new(check_policy.then(move |policy_result| {
+                    if let Err(message) = policy_result {
+                        debug!(
+                            "Connection policy rejected packet on connection {}: {}",
+                            str::from_utf8(&connection_id[..]).unwrap_or("<not utf8>"),
+                            message
+                        );
+                        return Either::B(err(reject_connection(
+                            &keys,
+                            &client_address,
+                            packet,
+                            &message,
+                        )));
+                    }
+                    Either::A(
+                        store
+                            .add_amount_received(
+                                &account,
+                                &connection_id[..],
+                                connection_tag.as_ref().map(|tag| &tag[..]),
+                                amount,
+                            )
+                            .and_then(move |total_received| {
+                                if closes_connection {
+                                    Either::A(
+                                        store
+                                            .close_connection(&account, &connection_id[..])
+                                            .map(move |_| total_received),
+                                    )
+                                } else {
+                                    Either::B(ok(total_received))
+                                }
+                            })
+                            .map_err(|_| {
+                                error!("Error updating the amount received on STREAM connection")
+                            })
+                            .and_then(move |total_received| {
+                                let handle_data = stream_data.into_iter().map(
+                                    move |(stream_id, offset, data)| {
+                                        let request = StreamDataRequest {
+                                            connection_id: data_connection_id.clone(),
+                                            stream_id,
+                                            offset,
+                                            data,
+                                            total_received,
+                                        };
+                                        data_handler.handle_data(&data_account, request).map(
+                                            move |response| {
+                                                response.map(|response| (stream_id, response))
+                                            },
+                                        )
+                                    },
+                                );
+                                join_all(handle_data)
+                                    .map_err(|_| error!("Error handling data sent over STREAM"))
+                                    .map(move |responses| {
+                                        let responses: Vec<(u64, Bytes)> =
+                                            responses.into_iter().flatten().collect();
+                                        (total_received, responses)
+                                    })
+                            })
+                            .then(move |result| {
+                                let response = if let Ok((total_received, data_responses)) = result
+                                {
+                                    respond(
+                                        &keys,
+                                        &client_address,
+                                        &ConnectionAssetDetailsFrame {
+                                            source_asset_code: &asset_code,
+                                            source_asset_scale: asset_scale,
+                                        },
+                                        packet,
+                                        total_received,
+                                        &data_responses,
+                                    )
+                                } else {
+                                    Err(RejectBuilder {
+                                        code: ErrorCode::T00_INTERNAL_ERROR,
+                                        message: &[],
+                                        triggered_by: &client_address[..],
+                                        data: &[],
+                                    }
+                                    .build())
+                                };
+                                // Let the policy know whether the packet it accepted was fulfilled
+                                let notify_policy = match (policy_request, &response) {
+                                    (Some(ref policy_request), Ok(_)) => Either::A(
+                                        policy.packet_fulfilled(&policy_account, policy_request),
+                                    ),
+                                    (Some(ref policy_request), Err(_)) => Either::A(
+                                        policy.packet_rejected(&policy_account, policy_request),
+                                    ),
+                                    (None, _) => Either::B(ok(())),
+                                };
+                                notify_policy.then(move |_| response)
+                            }),
+                    )
+                }));
             }
         }
         Box::new(self.next.send_request(request))
     }
 }
 
-// TODO send asset code and scale back to sender also
-fn receive_money(
-    shared_secret: &[u8; 32],
+/// An OutgoingService that rejects STREAM packets for a receiver that carry less than a
+/// minimum amount, which protects the node and its store from being flooded with tiny packets.
+///
+/// It should be put in front of the services that change balances, so that dust is rejected
+/// before anything is written for it. That means the amount is compared before any exchange
+/// rate is applied, in the units of the account the packet was received from.
+///
+/// The sender is told to close the connection, with a message that includes the minimum.
+/// Packets with no money in them, such as the one closing the connection, are passed on.
+#[derive(Clone)]
+pub struct MinPacketAmountService<S> {
+    connection_generator: ConnectionGenerator,
+    min_packet_amount: u64,
+    next: S,
+}
+
+impl<S> MinPacketAmountService<S> {
+    /// The server secret must be the same as the `StreamReceiverService`'s.
+    pub fn new(server_secret: Bytes, min_packet_amount: u64, next: S) -> Self {
+        MinPacketAmountService {
+            connection_generator: ConnectionGenerator::new(server_secret),
+            min_packet_amount,
+            next,
+        }
+    }
+}
+
+impl<S, A> OutgoingService<A> for MinPacketAmountService<S>
+where
+    S: OutgoingService<A>,
+    A: Account + IldcpAccount,
+{
+    type Future = Either<FutureResult<Fulfill, Reject>, S::Future>;
+
+    fn send_request(&mut self, request: OutgoingRequest<A>) -> Self::Future {
+        let amount = request.prepare.amount();
+        if amount == 0
+            || amount >= self.min_packet_amount
+            || !request
+                .prepare
+                .destination()
+                .starts_with(request.to.client_address())
+        {
+            return Either::B(self.next.send_request(request));
+        }
+        let keys = match self
+            .connection_generator
+            .connection_keys(request.prepare.destination())
+        {
+            Ok(keys) => keys,
+            // Not for the receiver
+            Err(_) => return Either::B(self.next.send_request(request)),
+        };
+        let client_address = request.to.client_address();
+        debug!(
+            "Rejecting packet of {} because the minimum packet amount is {}",
+            amount, self.min_packet_amount
+        );
+        Either::A(result(
+            decrypt_prepare(&keys, client_address, request.prepare).and_then(|packet| {
+                let message = format!("Packets must carry at least {}", self.min_packet_amount);
+                Err(reject_connection(&keys, client_address, packet, &message))
+            }),
+        ))
+    }
+}
+
+/// The segment the receiver put between the account's address and the last segment of the
+/// destination when it generated the address, if any
+fn connection_tag(destination: &[u8], client_address: &[u8]) -> Option<Bytes> {
+    let end = destination.iter().rposition(|c| c == &b'.')?;
+    if end > client_address.len() + 1 {
+        Some(Bytes::from(&destination[client_address.len() + 1..end]))
+    } else {
+        None
+    }
+}
+
+/// Reject a packet and tell the sender to close the connection, for example because
+/// the `ConnectionPolicy` did not accept it
+fn reject_connection(
+    keys: &ConnectionKeys,
+    client_address: &[u8],
+    packet: ReceivedPacket,
+    message: &str,
+) -> Reject {
+    let response_packet = StreamPacketBuilder {
+        sequence: packet.stream_packet.sequence(),
+        ilp_packet_type: IlpPacketType::Reject,
+        prepare_amount: packet.prepare_amount,
+        frames: &[Frame::ConnectionClose(ConnectionCloseFrame {
+            code: StreamErrorCode::ApplicationError,
+            message,
+        })],
+    }
+    .build();
+    let encrypted_response = response_packet.into_encrypted_with_keys(keys);
+    RejectBuilder {
+        code: ErrorCode::F99_APPLICATION_ERROR,
+        message: message.as_bytes(),
+        triggered_by: client_address,
+        data: &encrypted_response[..],
+    }
+    .build()
+}
+
+/// A Prepare packet addressed to this receiver with its STREAM data decrypted
+struct ReceivedPacket {
+    stream_packet: StreamPacket,
+    prepare_amount: u64,
+    fulfillment: [u8; 32],
+    is_fulfillable: bool,
+}
+
+impl ReceivedPacket {
+    fn should_fulfill(&self) -> bool {
+        self.is_fulfillable && self.prepare_amount >= self.stream_packet.prepare_amount()
+    }
+
+    fn closes_connection(&self) -> bool {
+        self.stream_packet.frames().any(|frame| match frame {
+            Frame::ConnectionClose(_) => true,
+            _ => false,
+        })
+    }
+
+    /// The `(stream_id, offset, data)` sent on each stream in this packet.
+    /// Streams that only received money are included with no data.
+    fn stream_data(&self) -> Vec<(u64, u64, Bytes)> {
+        let mut stream_data: Vec<(u64, u64, Bytes)> = self
+            .stream_packet
+            .frames()
+            .filter_map(|frame| match frame {
+                Frame::StreamData(frame) => {
+                    Some((frame.stream_id, frame.offset, Bytes::from(frame.data)))
+                }
+                _ => None,
+            })
+            .collect();
+        for frame in self.stream_packet.frames() {
+            if let Frame::StreamMoney(frame) = frame {
+                if !stream_data.iter().any(|(id, _, _)| *id == frame.stream_id) {
+                    stream_data.push((frame.stream_id, 0, Bytes::new()));
+                }
+            }
+        }
+        stream_data
+    }
+}
+
+fn decrypt_prepare(
+    keys: &ConnectionKeys,
     client_address: &[u8],
     prepare: Prepare,
-) -> Result<Fulfill, Reject> {
+) -> Result<ReceivedPacket, Reject> {
     // Generate fulfillment
-    let fulfillment = generate_fulfillment(&shared_secret[..], prepare.data());
+    let fulfillment = keys.generate_fulfillment(prepare.data());
     let condition = hash_sha256(&fulfillment);
     let is_fulfillable = condition == prepare.execution_condition();
 
@@ -168,7 +793,7 @@ fn receive_money(
     // TODO avoid copying data
     let prepare_amount = prepare.amount();
     let stream_packet =
-        StreamPacket::from_encrypted(shared_secret, prepare.into_data()).map_err(|_| {
+        StreamPacket::from_encrypted_with_keys(keys, prepare.into_data()).map_err(|_| {
             debug!("Unable to parse data, rejecting Prepare packet");
             RejectBuilder {
                 code: ErrorCode::F06_UNEXPECTED_PAYMENT,
@@ -179,37 +804,112 @@ fn receive_money(
             .build()
         })?;
 
+    Ok(ReceivedPacket {
+        stream_packet,
+        prepare_amount,
+        fulfillment,
+        is_fulfillable,
+    })
+}
+
+#[cfg(test)]
+fn receive_money(
+    shared_secret: &[u8; 32],
+    client_address: &[u8],
+    prepare: Prepare,
+) -> Result<Fulfill, Reject> {
+    let keys = ConnectionKeys::new(*shared_secret);
+    let packet = decrypt_prepare(&keys, client_address, prepare)?;
+    let asset_details = ConnectionAssetDetailsFrame {
+        source_asset_code: "XYZ",
+        source_asset_scale: 9,
+    };
+    respond(&keys, client_address, &asset_details, packet, 0, &[])
+}
+
+/// The asset the receiving account is denominated in, which is advertised to senders
+fn asset_details<A: IldcpAccount>(account: &A) -> ConnectionAssetDetailsFrame {
+    ConnectionAssetDetailsFrame {
+        source_asset_code: account.asset_code(),
+        source_asset_scale: account.asset_scale(),
+    }
+}
+
+/// Build the Fulfill or Reject for a packet, including the total received so far on the connection,
+/// any data to send back on the packet's streams and, in response to the sender's first packets,
+/// the receiver's asset details
+fn respond(
+    keys: &ConnectionKeys,
+    client_address: &[u8],
+    asset_details: &ConnectionAssetDetailsFrame,
+    packet: ReceivedPacket,
+    total_received: u64,
+    data_responses: &[(u64, Bytes)],
+) -> Result<Fulfill, Reject> {
+    let should_fulfill = packet.should_fulfill();
+    let ReceivedPacket {
+        stream_packet,
+        prepare_amount,
+        fulfillment,
+        is_fulfillable,
+    } = packet;
     let mut response_frames: Vec<Frame> = Vec::new();
 
     // Handle STREAM frames
-    // TODO reject if they send data?
     for frame in stream_packet.frames() {
-        // Tell the sender the stream can handle lots of money
-        if let Frame::StreamMoney(frame) = frame {
-            response_frames.push(Frame::StreamMaxMoney(StreamMaxMoneyFrame {
-                stream_id: frame.stream_id,
-                // TODO will returning zero here cause problems?
-                total_received: 0,
-                receive_max: u64::max_value(),
-            }));
+        match frame {
+            // Tell the sender the stream can handle lots of money
+            Frame::StreamMoney(frame) => {
+                response_frames.push(Frame::StreamMaxMoney(StreamMaxMoneyFrame {
+                    stream_id: frame.stream_id,
+                    total_received,
+                    receive_max: u64::max_value(),
+                }));
+            }
+            // Senders include their address until a packet gets through, so this tells
+            // them which asset the amounts they get back are denominated in
+            Frame::ConnectionNewAddress(_) => {
+                response_frames.push(Frame::ConnectionAssetDetails(asset_details.clone()));
+            }
+            _ => {}
         }
     }
+    let num_frames_without_data = response_frames.len();
+    for (stream_id, data) in data_responses {
+        response_frames.push(Frame::StreamData(StreamDataFrame {
+            stream_id: *stream_id,
+            offset: 0,
+            data: &data[..],
+        }));
+    }
 
     // Return Fulfill or Reject Packet
-    if is_fulfillable && prepare_amount >= stream_packet.prepare_amount() {
-        let response_packet = StreamPacketBuilder {
-            sequence: stream_packet.sequence(),
-            ilp_packet_type: IlpPacketType::Fulfill,
-            prepare_amount,
-            frames: &response_frames,
-        }
-        .build();
+    if should_fulfill {
+        let build_response = |frames: &[Frame]| {
+            StreamPacketBuilder {
+                sequence: stream_packet.sequence(),
+                ilp_packet_type: IlpPacketType::Fulfill,
+                prepare_amount,
+                frames,
+            }
+            .build()
+        };
+        let mut response_packet = build_response(&response_frames);
         debug!(
             "Fulfilling prepare with fulfillment: {} and encrypted stream packet: {:?}",
             hex::encode(&fulfillment[..]),
             response_packet
         );
-        let encrypted_response = response_packet.into_encrypted(shared_secret);
+        let mut encrypted_response = response_packet.into_encrypted_with_keys(keys);
+        // The money has already been counted, so the packet is still fulfilled without the data
+        if encrypted_response.len() > MAX_DATA_LENGTH {
+            warn!(
+                "Responses to STREAM data do not fit in the Fulfill ({} bytes), dropping them",
+                encrypted_response.len()
+            );
+            response_packet = build_response(&response_frames[..num_frames_without_data]);
+            encrypted_response = response_packet.into_encrypted_with_keys(keys);
+        }
         let fulfill = FulfillBuilder {
             fulfillment: &fulfillment,
             data: &encrypted_response[..],
@@ -237,7 +937,7 @@ fn receive_money(
             "Rejecting Prepare and including encrypted stream packet {:?}",
             response_packet
         );
-        let encrypted_response = response_packet.into_encrypted(shared_secret);
+        let encrypted_response = response_packet.into_encrypted_with_keys(keys);
         let reject = RejectBuilder {
             code: ErrorCode::F99_APPLICATION_ERROR,
             message: &[],
@@ -285,6 +985,53 @@ mod connection_generator {
             .rederive_secret(&destination_account[..])
             .is_err());
     }
+
+    #[test]
+    fn caches_connection_keys() {
+        let connection_generator = ConnectionGenerator::new(Bytes::from(&[9; 32][..]));
+        let (destination_account, shared_secret) =
+            connection_generator.generate_address_and_secret(b"example.receiver");
+
+        let keys = connection_generator
+            .connection_keys(&destination_account[..])
+            .unwrap();
+        assert_eq!(keys.shared_secret, shared_secret);
+        let cached = connection_generator
+            .clone()
+            .connection_keys(&destination_account[..])
+            .unwrap();
+        assert!(Arc::ptr_eq(&keys, &cached));
+
+        let mut modified = BytesMut::from(destination_account);
+        modified.extend_from_slice(b"extra");
+        assert!(connection_generator.connection_keys(&modified[..]).is_err());
+    }
+
+    #[test]
+    fn derives_a_different_secret_for_each_suffix() {
+        let server_secret = [9; 32];
+        let receiver_address = b"example.receiver";
+        let connection_generator = ConnectionGenerator::new(Bytes::from(&server_secret[..]));
+        let (destination_account, shared_secret) =
+            connection_generator.generate_address_and_secret(receiver_address);
+
+        let mut first = destination_account.clone();
+        first.extend_from_slice(b"~abc");
+        let mut second = destination_account.clone();
+        second.extend_from_slice(b"~def");
+        let first_secret = connection_generator.rederive_secret(&first[..]).unwrap();
+        let second_secret = connection_generator.rederive_secret(&second[..]).unwrap();
+
+        assert_eq!(first_secret, suffix_shared_secret(&shared_secret, b"abc"));
+        assert_ne!(first_secret, shared_secret);
+        assert_ne!(first_secret, second_secret);
+
+        let mut empty_suffix = destination_account.clone();
+        empty_suffix.extend_from_slice(b"~");
+        assert!(connection_generator
+            .rederive_secret(&empty_suffix[..])
+            .is_err());
+    }
 }
 
 #[cfg(test)]
@@ -426,6 +1173,49 @@ mod receiving_money {
         let result = receive_money(&shared_secret, &client_address[..], prepare);
         assert!(result.is_err());
     }
+
+    #[test]
+    fn sends_asset_details_in_response_to_sender_address() {
+        let client_address = Bytes::from("example.destination");
+        let server_secret = Bytes::from(&[1; 32][..]);
+        let connection_generator = ConnectionGenerator::new(server_secret.clone());
+        let (destination_account, shared_secret) =
+            connection_generator.generate_address_and_secret(&client_address[..]);
+
+        let stream_packet = StreamPacketBuilder {
+            ilp_packet_type: IlpPacketType::Prepare,
+            prepare_amount: 0,
+            sequence: 1,
+            frames: &[Frame::ConnectionNewAddress(ConnectionNewAddressFrame {
+                source_account: b"example.sender",
+            })],
+        }
+        .build();
+
+        let data = stream_packet.into_encrypted(&shared_secret[..]);
+        let execution_condition = generate_condition(&shared_secret[..], &data);
+
+        let prepare = PrepareBuilder {
+            destination: &destination_account[..],
+            amount: 100,
+            expires_at: UNIX_EPOCH,
+            data: &data[..],
+            execution_condition: &execution_condition,
+        }
+        .build();
+
+        let fulfill = receive_money(&shared_secret, &client_address[..], prepare).unwrap();
+        let response =
+            StreamPacket::from_encrypted(&shared_secret[..], BytesMut::from(fulfill.data()))
+                .unwrap();
+        assert_eq!(
+            response.frames().next().unwrap(),
+            Frame::ConnectionAssetDetails(ConnectionAssetDetailsFrame {
+                source_asset_code: "XYZ",
+                source_asset_scale: 9,
+            })
+        );
+    }
 }
 
 #[cfg(test)]
@@ -592,3 +1382,536 @@ mod stream_receiver_service {
         );
     }
 }
+
+#[cfg(test)]
+mod connection_store {
+    use super::*;
+    use crate::test_helpers::TestAccount;
+    use futures::Future;
+    use hashbrown::HashMap;
+    use interledger_packet::PrepareBuilder;
+    use interledger_service::outgoing_service_fn;
+    use parking_lot::Mutex;
+    use std::sync::Arc;
+    use std::time::{Duration, SystemTime};
+
+    #[derive(Clone, Default)]
+    struct TestConnectionStore {
+        received: Arc<Mutex<HashMap<Vec<u8>, u64>>>,
+    }
+
+    impl StreamConnectionStore<TestAccount> for TestConnectionStore {
+        fn add_amount_received(
+            &self,
+            _account: &TestAccount,
+            connection_id: &[u8],
+            _connection_tag: Option<&[u8]>,
+            amount: u64,
+        ) -> Box<Future<Item = u64, Error = ()> + Send> {
+            let mut received = self.received.lock();
+            let total = received.entry(connection_id.to_vec()).or_insert(0);
+            *total += amount;
+            Box::new(ok(*total))
+        }
+    }
+
+    fn test_receiver(
+        store: TestConnectionStore,
+    ) -> impl OutgoingService<TestAccount, Future = BoxedIlpFuture> {
+        StreamReceiverService::with_store(
+            Bytes::from(&[1; 32][..]),
+            store,
+            outgoing_service_fn(|_| {
+                Err(RejectBuilder {
+                    code: ErrorCode::F02_UNREACHABLE,
+                    message: &[],
+                    triggered_by: &[],
+                    data: &[],
+                }
+                .build())
+            }),
+        )
+    }
+
+    fn send_to(
+        receiver: &mut impl OutgoingService<TestAccount>,
+        destination_account: &[u8],
+        shared_secret: &[u8; 32],
+    ) -> u64 {
+        let data = test_stream_packet().into_encrypted(&shared_secret[..]);
+        let execution_condition = generate_condition(&shared_secret[..], &data);
+        let account = TestAccount {
+            id: 0,
+            ilp_address: Bytes::from("example.destination"),
+            asset_code: "XYZ".to_string(),
+            asset_scale: 9,
+        };
+        let fulfill = receiver
+            .send_request(OutgoingRequest {
+                from: account.clone(),
+                to: account,
+                prepare: PrepareBuilder {
+                    destination: destination_account,
+                    amount: 100,
+                    expires_at: SystemTime::now() + Duration::from_secs(30),
+                    data: &data[..],
+                    execution_condition: &execution_condition,
+                }
+                .build(),
+            })
+            .wait()
+            .unwrap();
+        let response =
+            StreamPacket::from_encrypted(&shared_secret[..], BytesMut::from(fulfill.data()))
+                .unwrap();
+        response
+            .frames()
+            .filter_map(|frame| match frame {
+                Frame::StreamMaxMoney(frame) => Some(frame.total_received),
+                _ => None,
+            })
+            .next()
+            .unwrap()
+    }
+
+    #[test]
+    fn instances_share_amount_received() {
+        let store = TestConnectionStore::default();
+        let mut receiver_a = test_receiver(store.clone());
+        let mut receiver_b = test_receiver(store.clone());
+        let (destination_account, shared_secret) =
+            ConnectionGenerator::new(Bytes::from(&[1; 32][..]))
+                .generate_address_and_secret(b"example.destination");
+
+        assert_eq!(
+            send_to(&mut receiver_a, &destination_account[..], &shared_secret),
+            100
+        );
+        assert_eq!(
+            send_to(&mut receiver_b, &destination_account[..], &shared_secret),
+            200
+        );
+    }
+
+    #[test]
+    fn rejects_packets_below_minimum() {
+        let store = TestConnectionStore::default();
+        let mut receiver = MinPacketAmountService::new(
+            Bytes::from(&[1; 32][..]),
+            100,
+            test_receiver(store.clone()),
+        );
+        let (destination_account, shared_secret) =
+            ConnectionGenerator::new(Bytes::from(&[1; 32][..]))
+                .generate_address_and_secret(b"example.destination");
+        let data = test_stream_packet().into_encrypted(&shared_secret[..]);
+        let execution_condition = generate_condition(&shared_secret[..], &data);
+        let account = TestAccount {
+            id: 0,
+            ilp_address: Bytes::from("example.destination"),
+            asset_code: "XYZ".to_string(),
+            asset_scale: 9,
+        };
+        let reject = receiver
+            .send_request(OutgoingRequest {
+                from: account.clone(),
+                to: account,
+                prepare: PrepareBuilder {
+                    destination: &destination_account[..],
+                    amount: 1,
+                    expires_at: SystemTime::now() + Duration::from_secs(30),
+                    data: &data[..],
+                    execution_condition: &execution_condition,
+                }
+                .build(),
+            })
+            .wait()
+            .unwrap_err();
+        assert_eq!(reject.code(), ErrorCode::F99_APPLICATION_ERROR);
+        let response =
+            StreamPacket::from_encrypted(&shared_secret[..], BytesMut::from(reject.data()))
+                .unwrap();
+        assert_eq!(
+            response.frames().next().unwrap(),
+            Frame::ConnectionClose(ConnectionCloseFrame {
+                code: StreamErrorCode::ApplicationError,
+                message: "Packets must carry at least 100",
+            })
+        );
+        assert!(store.received.lock().is_empty());
+
+        // Packets of the minimum amount get through to the receiver
+        assert_eq!(
+            send_to(&mut receiver, &destination_account[..], &shared_secret),
+            100
+        );
+    }
+
+    /// Holds on to each request until the connection has received 200
+    #[derive(Clone, Default)]
+    struct PaidEchoHandler {
+        requests: Arc<Mutex<HashMap<u64, Bytes>>>,
+    }
+
+    impl StreamDataHandler<TestAccount> for PaidEchoHandler {
+        fn handle_data(
+            &self,
+            _account: &TestAccount,
+            request: StreamDataRequest,
+        ) -> Box<Future<Item = Option<Bytes>, Error = ()> + Send> {
+            let mut requests = self.requests.lock();
+            if !request.data.is_empty() {
+                requests.insert(request.stream_id, request.data);
+            }
+            if request.total_received >= 200 {
+                Box::new(ok(requests.remove(&request.stream_id)))
+            } else {
+                Box::new(ok(None))
+            }
+        }
+    }
+
+    #[test]
+    fn responds_to_data_once_paid() {
+        let mut receiver = StreamReceiverService::with_store(
+            Bytes::from(&[1; 32][..]),
+            TestConnectionStore::default(),
+            outgoing_service_fn(|_: OutgoingRequest<TestAccount>| -> BoxedIlpFuture {
+                panic!("shouldn't get here")
+            }),
+        )
+        .data_handler(PaidEchoHandler::default());
+        let (destination_account, shared_secret) =
+            ConnectionGenerator::new(Bytes::from(&[1; 32][..]))
+                .generate_address_and_secret(b"example.destination");
+        let account = TestAccount {
+            id: 0,
+            ilp_address: Bytes::from("example.destination"),
+            asset_code: "XYZ".to_string(),
+            asset_scale: 9,
+        };
+        let mut send = |frames: &[Frame]| {
+            let data = StreamPacketBuilder {
+                ilp_packet_type: IlpPacketType::Prepare,
+                prepare_amount: 0,
+                sequence: 1,
+                frames,
+            }
+            .build()
+            .into_encrypted(&shared_secret[..]);
+            let execution_condition = generate_condition(&shared_secret[..], &data);
+            let fulfill = receiver
+                .send_request(OutgoingRequest {
+                    from: account.clone(),
+                    to: account.clone(),
+                    prepare: PrepareBuilder {
+                        destination: &destination_account[..],
+                        amount: 100,
+                        expires_at: SystemTime::now() + Duration::from_secs(30),
+                        data: &data[..],
+                        execution_condition: &execution_condition,
+                    }
+                    .build(),
+                })
+                .wait()
+                .unwrap();
+            let response =
+                StreamPacket::from_encrypted(&shared_secret[..], BytesMut::from(fulfill.data()))
+                    .unwrap();
+            response
+                .frames()
+                .filter_map(|frame| match frame {
+                    Frame::StreamData(frame) => Some((frame.stream_id, frame.data.to_vec())),
+                    _ => None,
+                })
+                .next()
+        };
+
+        let request = send(&[
+            Frame::StreamMoney(StreamMoneyFrame {
+                stream_id: 3,
+                shares: 1,
+            }),
+            Frame::StreamData(StreamDataFrame {
+                stream_id: 3,
+                offset: 0,
+                data: b"ping",
+            }),
+        ]);
+        assert_eq!(request, None);
+
+        let payment = send(&[Frame::StreamMoney(StreamMoneyFrame {
+            stream_id: 3,
+            shares: 1,
+        })]);
+        assert_eq!(payment, Some((3, b"ping".to_vec())));
+    }
+
+    /// Responds with more data than fits in a Fulfill
+    #[derive(Clone)]
+    struct OversizedHandler;
+
+    impl StreamDataHandler<TestAccount> for OversizedHandler {
+        fn handle_data(
+            &self,
+            _account: &TestAccount,
+            _request: StreamDataRequest,
+        ) -> Box<Future<Item = Option<Bytes>, Error = ()> + Send> {
+            Box::new(ok(Some(Bytes::from(vec![0; MAX_DATA_LENGTH]))))
+        }
+    }
+
+    #[test]
+    fn drops_responses_that_do_not_fit_in_the_fulfill() {
+        let mut receiver = StreamReceiverService::with_store(
+            Bytes::from(&[1; 32][..]),
+            TestConnectionStore::default(),
+            outgoing_service_fn(|_: OutgoingRequest<TestAccount>| -> BoxedIlpFuture {
+                panic!("shouldn't get here")
+            }),
+        )
+        .data_handler(OversizedHandler);
+        let (destination_account, shared_secret) =
+            ConnectionGenerator::new(Bytes::from(&[1; 32][..]))
+                .generate_address_and_secret(b"example.destination");
+        let account = TestAccount {
+            id: 0,
+            ilp_address: Bytes::from("example.destination"),
+            asset_code: "XYZ".to_string(),
+            asset_scale: 9,
+        };
+        let data = StreamPacketBuilder {
+            ilp_packet_type: IlpPacketType::Prepare,
+            prepare_amount: 0,
+            sequence: 1,
+            frames: &[Frame::StreamMoney(StreamMoneyFrame {
+                stream_id: 1,
+                shares: 1,
+            })],
+        }
+        .build()
+        .into_encrypted(&shared_secret[..]);
+        let execution_condition = generate_condition(&shared_secret[..], &data);
+        let fulfill = receiver
+            .send_request(OutgoingRequest {
+                from: account.clone(),
+                to: account.clone(),
+                prepare: PrepareBuilder {
+                    destination: &destination_account[..],
+                    amount: 100,
+                    expires_at: SystemTime::now() + Duration::from_secs(30),
+                    data: &data[..],
+                    execution_condition: &execution_condition,
+                }
+                .build(),
+            })
+            .wait()
+            .unwrap();
+        assert!(fulfill.data().len() <= MAX_DATA_LENGTH);
+        let response =
+            StreamPacket::from_encrypted(&shared_secret[..], BytesMut::from(fulfill.data()))
+                .unwrap();
+        assert_eq!(response.prepare_amount(), 100);
+        assert!(!response.frames().any(|frame| match frame {
+            Frame::StreamData(_) => true,
+            _ => false,
+        }));
+    }
+}
+
+#[cfg(test)]
+mod connection_policy {
+    use super::*;
+    use crate::test_helpers::TestAccount;
+    use futures::Future;
+    use interledger_packet::PrepareBuilder;
+    use interledger_service::outgoing_service_fn;
+    use parking_lot::Mutex;
+    use std::{
+        sync::Arc,
+        time::{Duration, SystemTime},
+    };
+
+    /// Only accepts payments for the invoice tagged "invoice1", and records whether the
+    /// packets it accepted were fulfilled
+    #[derive(Clone, Default)]
+    struct InvoicePolicy {
+        fulfilled: Arc<Mutex<Vec<bool>>>,
+    }
+
+    impl ConnectionPolicy<TestAccount> for InvoicePolicy {
+        fn check_packet(
+            &self,
+            _account: &TestAccount,
+            request: &ConnectionPolicyRequest,
+        ) -> Box<Future<Item = (), Error = String> + Send> {
+            if request.connection_tag == Some(Bytes::from("invoice1")) {
+                Box::new(ok(()))
+            } else {
+                Box::new(err("Unknown invoice".to_string()))
+            }
+        }
+
+        fn packet_fulfilled(
+            &self,
+            _account: &TestAccount,
+            _request: &ConnectionPolicyRequest,
+        ) -> Box<Future<Item = (), Error = ()> + Send> {
+            self.fulfilled.lock().push(true);
+            Box::new(ok(()))
+        }
+
+        fn packet_rejected(
+            &self,
+            _account: &TestAccount,
+            _request: &ConnectionPolicyRequest,
+        ) -> Box<Future<Item = (), Error = ()> + Send> {
+            self.fulfilled.lock().push(false);
+            Box::new(ok(()))
+        }
+    }
+
+    /// Fails to record any amount received
+    #[derive(Clone)]
+    struct FailingConnectionStore;
+
+    impl StreamConnectionStore<TestAccount> for FailingConnectionStore {
+        fn add_amount_received(
+            &self,
+            _account: &TestAccount,
+            _connection_id: &[u8],
+            _connection_tag: Option<&[u8]>,
+            _amount: u64,
+        ) -> Box<Future<Item = u64, Error = ()> + Send> {
+            Box::new(err(()))
+        }
+    }
+
+    fn send_to(destination_account: &[u8], shared_secret: &[u8; 32]) -> Result<Fulfill, Reject> {
+        send_with(
+            NoConnectionStore,
+            InvoicePolicy::default(),
+            destination_account,
+            shared_secret,
+        )
+    }
+
+    fn send_with<T: StreamConnectionStore<TestAccount>>(
+        store: T,
+        policy: InvoicePolicy,
+        destination_account: &[u8],
+        shared_secret: &[u8; 32],
+    ) -> Result<Fulfill, Reject> {
+        let mut receiver = StreamReceiverService::with_store(
+            Bytes::from(&[1; 32][..]),
+            store,
+            outgoing_service_fn(|_: OutgoingRequest<TestAccount>| -> BoxedIlpFuture {
+                panic!("shouldn't get here")
+            }),
+        )
+        .connection_policy(policy);
+        let data = test_stream_packet().into_encrypted(&shared_secret[..]);
+        let execution_condition = generate_condition(&shared_secret[..], &data);
+        let account = TestAccount {
+            id: 0,
+            ilp_address: Bytes::from("example.destination"),
+            asset_code: "XYZ".to_string(),
+            asset_scale: 9,
+        };
+        receiver
+            .send_request(OutgoingRequest {
+                from: account.clone(),
+                to: account,
+                prepare: PrepareBuilder {
+                    destination: destination_account,
+                    amount: 100,
+                    expires_at: SystemTime::now() + Duration::from_secs(30),
+                    data: &data[..],
+                    execution_condition: &execution_condition,
+                }
+                .build(),
+            })
+            .wait()
+    }
+
+    #[test]
+    fn puts_tag_in_address() {
+        let connection_generator = ConnectionGenerator::new(Bytes::from(&[1; 32][..]));
+        let (destination_account, shared_secret) = connection_generator
+            .generate_address_and_secret_with_tag(b"example.destination", b"invoice1")
+            .unwrap();
+        assert!(destination_account.starts_with(b"example.destination.invoice1."));
+        assert_eq!(
+            connection_generator
+                .rederive_secret(&destination_account[..])
+                .unwrap(),
+            shared_secret
+        );
+        assert_eq!(
+            connection_tag(&destination_account[..], b"example.destination"),
+            Some(Bytes::from("invoice1"))
+        );
+        assert!(connection_generator
+            .generate_address_and_secret_with_tag(b"example.destination", b"invoice.1")
+            .is_err());
+    }
+
+    #[test]
+    fn accepts_connections_the_policy_allows() {
+        let connection_generator = ConnectionGenerator::new(Bytes::from(&[1; 32][..]));
+        let (destination_account, shared_secret) = connection_generator
+            .generate_address_and_secret_with_tag(b"example.destination", b"invoice1")
+            .unwrap();
+        assert!(send_to(&destination_account[..], &shared_secret).is_ok());
+    }
+
+    #[test]
+    fn tells_the_policy_whether_accepted_packets_were_fulfilled() {
+        let connection_generator = ConnectionGenerator::new(Bytes::from(&[1; 32][..]));
+        let (destination_account, shared_secret) = connection_generator
+            .generate_address_and_secret_with_tag(b"example.destination", b"invoice1")
+            .unwrap();
+        let policy = InvoicePolicy::default();
+        assert!(send_with(
+            NoConnectionStore,
+            policy.clone(),
+            &destination_account[..],
+            &shared_secret
+        )
+        .is_ok());
+        assert!(send_with(
+            FailingConnectionStore,
+            policy.clone(),
+            &destination_account[..],
+            &shared_secret
+        )
+        .is_err());
+        assert_eq!(*policy.fulfilled.lock(), vec![true, false]);
+    }
+
+    #[test]
+    fn rejects_and_closes_other_connections() {
+        let connection_generator = ConnectionGenerator::new(Bytes::from(&[1; 32][..]));
+        let (tagged_account, tagged_secret) = connection_generator
+            .generate_address_and_secret_with_tag(b"example.destination", b"invoice2")
+            .unwrap();
+        let (untagged_account, untagged_secret) =
+            connection_generator.generate_address_and_secret(b"example.destination");
+
+        for (destination_account, shared_secret) in &[
+            (tagged_account, tagged_secret),
+            (untagged_account, untagged_secret),
+        ] {
+            let reject = send_to(&destination_account[..], shared_secret).unwrap_err();
+            assert_eq!(reject.code(), ErrorCode::F99_APPLICATION_ERROR);
+            assert_eq!(reject.message(), b"Unknown invoice");
+            let response =
+                StreamPacket::from_encrypted(&shared_secret[..], BytesMut::from(reject.data()))
+                    .unwrap();
+            assert!(response.frames().any(|frame| match frame {
+                Frame::ConnectionClose(frame) => frame.code == StreamErrorCode::ApplicationError,
+                _ => false,
+            }));
+        }
+    }
+}
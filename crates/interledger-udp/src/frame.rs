@@ -0,0 +1,108 @@
+use bytes::{BufMut, Bytes, BytesMut};
+use ring::{digest, hmac};
+
+const HEADER_LEN: usize = 5;
+const TAG_LEN: usize = 32;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+#[repr(u8)]
+pub enum FrameType {
+    /// Contains an ILP Prepare packet
+    Request = 1,
+    /// Contains an ILP Fulfill or Reject packet
+    Response = 2,
+}
+
+/// The header of a datagram, which can be read before the sender is authenticated.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct FrameHeader {
+    pub frame_type: FrameType,
+    pub request_id: u32,
+}
+
+/// Serialize a frame. Each datagram has the format:
+///
+/// `frame type (1 byte) | request ID (4 bytes, big endian) | ILP packet | HMAC-SHA256 tag (32 bytes)`
+///
+/// where the tag is computed over everything before it using the secret shared with the peer.
+pub fn encode(frame_type: FrameType, request_id: u32, packet: &[u8], secret: &[u8]) -> Bytes {
+    let mut frame = BytesMut::with_capacity(HEADER_LEN + packet.len() + TAG_LEN);
+    frame.put_u8(frame_type as u8);
+    frame.put_u32_be(request_id);
+    frame.put(packet);
+    let key = hmac::SigningKey::new(&digest::SHA256, secret);
+    let tag = hmac::sign(&key, &frame[..]);
+    frame.put(tag.as_ref());
+    frame.freeze()
+}
+
+pub fn decode_header(frame: &[u8]) -> Option<FrameHeader> {
+    if frame.len() < HEADER_LEN + TAG_LEN {
+        return None;
+    }
+    let frame_type = match frame[0] {
+        1 => FrameType::Request,
+        2 => FrameType::Response,
+        _ => return None,
+    };
+    let request_id = (u32::from(frame[1]) << 24)
+        | (u32::from(frame[2]) << 16)
+        | (u32::from(frame[3]) << 8)
+        | u32::from(frame[4]);
+    Some(FrameHeader {
+        frame_type,
+        request_id,
+    })
+}
+
+/// Check the frame's authentication tag and return the ILP packet it contains.
+pub fn decode_packet(frame: &[u8], secret: &[u8]) -> Result<BytesMut, ()> {
+    if frame.len() < HEADER_LEN + TAG_LEN {
+        return Err(());
+    }
+    let (data, tag) = frame.split_at(frame.len() - TAG_LEN);
+    let key = hmac::SigningKey::new(&digest::SHA256, secret);
+    hmac::verify_with_own_key(&key, data, tag).map_err(|_| ())?;
+    Ok(BytesMut::from(&data[HEADER_LEN..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_and_decodes() {
+        let frame = encode(FrameType::Request, 258, b"packet", b"secret");
+        assert_eq!(frame.len(), HEADER_LEN + 6 + TAG_LEN);
+        assert_eq!(
+            decode_header(&frame[..]),
+            Some(FrameHeader {
+                frame_type: FrameType::Request,
+                request_id: 258,
+            })
+        );
+        assert_eq!(
+            decode_packet(&frame[..], b"secret").unwrap(),
+            BytesMut::from(&b"packet"[..])
+        );
+    }
+
+    #[test]
+    fn rejects_wrong_secret() {
+        let frame = encode(FrameType::Response, 1, b"packet", b"secret");
+        assert!(decode_packet(&frame[..], b"other secret").is_err());
+    }
+
+    #[test]
+    fn rejects_modified_frame() {
+        let mut frame = BytesMut::from(encode(FrameType::Response, 1, b"packet", b"secret"));
+        frame[6] ^= 1;
+        assert!(decode_packet(&frame[..], b"secret").is_err());
+    }
+
+    #[test]
+    fn rejects_short_frames() {
+        assert!(decode_header(&[1, 0, 0, 0, 1]).is_none());
+        assert!(decode_packet(&[1, 0, 0, 0, 1], b"secret").is_err());
+    }
+}
@@ -0,0 +1,215 @@
+//! # interledger-udp
+//!
+//! **Experimental** transport that sends ILP packets directly in UDP datagrams.
+//!
+//! This is intended for connectors that are co-located (for example in the same datacenter)
+//! and want to avoid the per-packet overhead of WebSockets or HTTP. Each datagram contains a
+//! single ILP packet, a request ID used to match responses to requests, and an HMAC tag
+//! computed with a secret shared by the two peers. Requests are retransmitted until
+//! a response is received or the Prepare packet expires, and responses are cached so that
+//! retransmitted requests are not processed twice.
+//!
+//! ILP packets must fit in a single datagram, so this should only be used on networks
+//! where large datagrams are not fragmented or dropped.
+
+#[macro_use]
+extern crate log;
+
+use futures::Future;
+use interledger_service::Account;
+use std::net::SocketAddr;
+
+mod frame;
+mod service;
+
+pub use self::service::{create_udp_service, UdpOutgoingService, UdpService};
+
+pub trait UdpAccount: Account {
+    /// The address to send UDP datagrams to for this account.
+    fn get_udp_address(&self) -> Option<SocketAddr>;
+
+    /// The secret used to authenticate datagrams exchanged with this account.
+    fn get_udp_shared_secret(&self) -> Option<&[u8]>;
+}
+
+/// The interface for Stores that can be used with the UDP transport.
+pub trait UdpStore {
+    type Account: UdpAccount;
+
+    /// Load the details of the account that datagrams from the given address belong to.
+    fn get_account_from_udp_address(
+        &self,
+        address: SocketAddr,
+    ) -> Box<Future<Item = Self::Account, Error = ()> + Send>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future::{lazy, result};
+    use interledger_packet::{ErrorCode, FulfillBuilder, PrepareBuilder, RejectBuilder};
+    use interledger_service::*;
+    use std::{
+        sync::Arc,
+        time::{Duration, SystemTime},
+    };
+    use tokio::runtime::Runtime;
+
+    #[derive(Clone, Debug)]
+    pub struct TestAccount {
+        pub id: u64,
+        pub udp_address: SocketAddr,
+        pub udp_shared_secret: Vec<u8>,
+    }
+
+    impl Account for TestAccount {
+        type AccountId = u64;
+
+        fn id(&self) -> u64 {
+            self.id
+        }
+    }
+
+    impl UdpAccount for TestAccount {
+        fn get_udp_address(&self) -> Option<SocketAddr> {
+            Some(self.udp_address)
+        }
+
+        fn get_udp_shared_secret(&self) -> Option<&[u8]> {
+            Some(&self.udp_shared_secret[..])
+        }
+    }
+
+    #[derive(Clone)]
+    pub struct TestStore {
+        accounts: Arc<Vec<TestAccount>>,
+    }
+
+    impl UdpStore for TestStore {
+        type Account = TestAccount;
+
+        fn get_account_from_udp_address(
+            &self,
+            address: SocketAddr,
+        ) -> Box<Future<Item = Self::Account, Error = ()> + Send> {
+            Box::new(result(
+                self.accounts
+                    .iter()
+                    .find(|account| account.udp_address == address)
+                    .cloned()
+                    .ok_or(()),
+            ))
+        }
+    }
+
+    fn no_other_outgoing_handler() -> impl OutgoingService<TestAccount> + Clone {
+        outgoing_service_fn(|_| {
+            Err(RejectBuilder {
+                code: ErrorCode::F02_UNREACHABLE,
+                message: b"No other outgoing handler",
+                triggered_by: &[],
+                data: &[],
+            }
+            .build())
+        })
+    }
+
+    // Starts a peer on the server address that fulfills every request from the client address,
+    // and returns the client's side of the connection along with the account of the server
+    fn connect_peers(
+        server_address: SocketAddr,
+        client_address: SocketAddr,
+        client_secret: &[u8],
+    ) -> (
+        impl OutgoingService<TestAccount, Future = BoxedIlpFuture>,
+        TestAccount,
+    ) {
+        let server_store = TestStore {
+            accounts: Arc::new(vec![TestAccount {
+                id: 0,
+                udp_address: client_address,
+                udp_shared_secret: b"secret".to_vec(),
+            }]),
+        };
+        create_udp_service(server_address, server_store, no_other_outgoing_handler())
+            .unwrap()
+            .handle_incoming(incoming_service_fn(|_| {
+                Ok(FulfillBuilder {
+                    fulfillment: &[0; 32],
+                    data: b"test data",
+                }
+                .build())
+            }));
+
+        let server_account = TestAccount {
+            id: 1,
+            udp_address: server_address,
+            udp_shared_secret: client_secret.to_vec(),
+        };
+        let client_store = TestStore {
+            accounts: Arc::new(vec![server_account.clone()]),
+        };
+        let client = create_udp_service(client_address, client_store, no_other_outgoing_handler())
+            .unwrap()
+            .handle_incoming(incoming_service_fn(|_| {
+                Err(RejectBuilder {
+                    code: ErrorCode::F02_UNREACHABLE,
+                    message: &[],
+                    triggered_by: &[],
+                    data: &[],
+                }
+                .build())
+            }));
+        (client, server_account)
+    }
+
+    fn send_prepare<S>(mut service: S, to: TestAccount, expiry: Duration) -> BoxedIlpFuture
+    where
+        S: OutgoingService<TestAccount, Future = BoxedIlpFuture>,
+    {
+        service.send_request(OutgoingRequest {
+            from: to.clone(),
+            to,
+            prepare: PrepareBuilder {
+                destination: b"example.destination",
+                amount: 100,
+                execution_condition: &[0; 32],
+                expires_at: SystemTime::now() + expiry,
+                data: b"test data",
+            }
+            .build(),
+        })
+    }
+
+    #[test]
+    fn sends_requests_to_peer() {
+        let mut runtime = Runtime::new().unwrap();
+        let fulfill = runtime
+            .block_on(lazy(|| {
+                let (client, server_account) = connect_peers(
+                    "127.0.0.1:12410".parse().unwrap(),
+                    "127.0.0.1:12411".parse().unwrap(),
+                    b"secret",
+                );
+                send_prepare(client, server_account, Duration::from_secs(30))
+            }))
+            .unwrap();
+        assert_eq!(fulfill.data(), b"test data");
+    }
+
+    #[test]
+    fn times_out_if_the_peer_does_not_authenticate_the_request() {
+        let mut runtime = Runtime::new().unwrap();
+        let reject = runtime
+            .block_on(lazy(|| {
+                let (client, server_account) = connect_peers(
+                    "127.0.0.1:12412".parse().unwrap(),
+                    "127.0.0.1:12413".parse().unwrap(),
+                    b"wrong secret",
+                );
+                send_prepare(client, server_account, Duration::from_millis(500))
+            }))
+            .unwrap_err();
+        assert_eq!(reject.code(), ErrorCode::R00_TRANSFER_TIMED_OUT);
+    }
+}
@@ -0,0 +1,396 @@
+use super::frame::{decode_header, decode_packet, encode, FrameType};
+use super::{UdpAccount, UdpStore};
+use bytes::{Bytes, BytesMut};
+use futures::{
+    future::err,
+    sync::mpsc::{unbounded, UnboundedReceiver, UnboundedSender},
+    sync::oneshot,
+    Future, Sink, Stream,
+};
+use hashbrown::HashMap;
+use interledger_packet::{ErrorCode, Fulfill, Packet, Prepare, Reject, RejectBuilder};
+use interledger_service::*;
+use parking_lot::Mutex;
+use rand::random;
+use std::{
+    io::Error as IoError,
+    marker::PhantomData,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
+use tokio_codec::BytesCodec;
+use tokio_executor::spawn;
+use tokio_timer::{Delay, Interval};
+use tokio_udp::{UdpFramed, UdpSocket};
+
+/// How long to wait for a response before sending a request again
+const RETRANSMIT_INTERVAL: Duration = Duration::from_millis(100);
+/// How long to keep a response so it can be resent if the request is retransmitted.
+/// This should be longer than the expiry of any Prepare packet we accept
+const RESPONSE_CACHE_TTL: Duration = Duration::from_secs(30);
+
+type IncomingRequestBuffer<A> = UnboundedReceiver<(A, SocketAddr, u32, Prepare)>;
+
+struct PendingRequest {
+    address: SocketAddr,
+    secret: Bytes,
+    channel: oneshot::Sender<Result<Fulfill, Reject>>,
+}
+
+/// Bind a UDP socket on the given address and return a service that sends outgoing
+/// requests to accounts that have a UDP address configured.
+///
+/// Incoming requests are buffered until `handle_incoming` is called with the
+/// incoming handler.
+pub fn create_udp_service<U, T, A>(
+    address: SocketAddr,
+    store: U,
+    next_outgoing: T,
+) -> Result<UdpOutgoingService<T, A>, IoError>
+where
+    U: UdpStore<Account = A> + Send + Sync + 'static,
+    T: OutgoingService<A> + Clone + Send + 'static,
+    A: UdpAccount + 'static,
+{
+    let socket = UdpSocket::bind(&address)?;
+    info!("UDP transport listening on: {}", address);
+    let (sink, stream) = UdpFramed::new(socket, BytesCodec::new()).split();
+    let (datagrams, datagrams_receiver) = unbounded();
+    let (incoming_sender, incoming_receiver) = unbounded();
+
+    let service = UdpOutgoingService {
+        datagrams,
+        pending_outgoing: Arc::new(Mutex::new(HashMap::new())),
+        pending_incoming: Arc::new(Mutex::new(Some(incoming_receiver))),
+        incoming_sender,
+        responses: Arc::new(Mutex::new(HashMap::new())),
+        next_outgoing,
+    };
+
+    spawn(
+        sink.sink_map_err(|err| error!("Error sending UDP datagram: {:?}", err))
+            .send_all(datagrams_receiver)
+            .then(|_| {
+                debug!("Finished sending UDP datagrams");
+                Ok(())
+            }),
+    );
+
+    let service_clone = service.clone();
+    spawn(
+        stream
+            .map_err(|err| error!("Error reading from UDP socket: {:?}", err))
+            .for_each(move |(datagram, from)| {
+                service_clone.handle_datagram(&store, datagram, from);
+                Ok(())
+            })
+            .then(|_| {
+                debug!("UDP socket closed");
+                Ok(())
+            }),
+    );
+
+    Ok(service)
+}
+
+/// A UDP socket that implements OutgoingService for sending outgoing ILP Prepare
+/// packets to accounts with a UDP address.
+#[derive(Clone)]
+pub struct UdpOutgoingService<T, A: Account> {
+    datagrams: UnboundedSender<(Bytes, SocketAddr)>,
+    pending_outgoing: Arc<Mutex<HashMap<u32, PendingRequest>>>,
+    pending_incoming: Arc<Mutex<Option<IncomingRequestBuffer<A>>>>,
+    incoming_sender: UnboundedSender<(A, SocketAddr, u32, Prepare)>,
+    /// Responses to recent incoming requests. The value is None while the request is being handled
+    responses: Arc<Mutex<HashMap<(SocketAddr, u32), Option<Bytes>>>>,
+    next_outgoing: T,
+}
+
+impl<T, A> UdpOutgoingService<T, A>
+where
+    T: OutgoingService<A> + Clone,
+    A: UdpAccount + 'static,
+{
+    fn handle_datagram<U>(&self, store: &U, datagram: BytesMut, from: SocketAddr)
+    where
+        U: UdpStore<Account = A>,
+    {
+        let header = if let Some(header) = decode_header(&datagram[..]) {
+            header
+        } else {
+            debug!("Ignoring invalid datagram from {}", from);
+            return;
+        };
+        let request_id = header.request_id;
+
+        match header.frame_type {
+            FrameType::Response => {
+                let mut pending_outgoing = self.pending_outgoing.lock();
+                let is_from_peer = pending_outgoing
+                    .get(&request_id)
+                    .map(|pending| {
+                        pending.address == from
+                            && decode_packet(&datagram[..], &pending.secret[..]).is_ok()
+                    })
+                    .unwrap_or(false);
+                if !is_from_peer {
+                    // This may be a response to a retransmitted request we already got a response for
+                    trace!(
+                        "Ignoring response from {} for unknown request: {}",
+                        from,
+                        request_id
+                    );
+                    return;
+                }
+                let pending = pending_outgoing
+                    .remove(&request_id)
+                    .expect("Pending request was just checked");
+                drop(pending_outgoing);
+
+                let packet = decode_packet(&datagram[..], &pending.secret[..])
+                    .expect("Frame was just authenticated");
+                let result = match Packet::try_from(packet) {
+                    Ok(Packet::Fulfill(fulfill)) => Ok(fulfill),
+                    Ok(Packet::Reject(reject)) => Err(reject),
+                    _ => {
+                        warn!("Got invalid response packet from {}", from);
+                        Err(RejectBuilder {
+                            code: ErrorCode::T00_INTERNAL_ERROR,
+                            message: &[],
+                            triggered_by: &[],
+                            data: &[],
+                        }
+                        .build())
+                    }
+                };
+                let _ = pending
+                    .channel
+                    .send(result)
+                    .map_err(|_| debug!("Sender of request {} is no longer waiting", request_id));
+            }
+            FrameType::Request => {
+                {
+                    let mut responses = self.responses.lock();
+                    match responses.get(&(from, request_id)) {
+                        Some(Some(response)) => {
+                            trace!("Resending response for request {} to {}", request_id, from);
+                            let _ = self.datagrams.unbounded_send((response.clone(), from));
+                            return;
+                        }
+                        // The request is still being handled
+                        Some(None) => return,
+                        None => {
+                            responses.insert((from, request_id), None);
+                        }
+                    }
+                }
+
+                let responses = self.responses.clone();
+                let incoming_sender = self.incoming_sender.clone();
+                let handle = store
+                    .get_account_from_udp_address(from)
+                    .and_then(move |account| {
+                        let prepare = account
+                            .get_udp_shared_secret()
+                            .ok_or(())
+                            .and_then(|secret| decode_packet(&datagram[..], secret))
+                            .and_then(|packet| Prepare::try_from(packet).map_err(|_| ()));
+                        prepare.map(|prepare| (account, prepare))
+                    })
+                    .map_err(move |_| {
+                        warn!("Got unauthorized or invalid request from {}", from);
+                        responses.lock().remove(&(from, request_id));
+                    })
+                    .and_then(move |(account, prepare)| {
+                        incoming_sender
+                            .unbounded_send((account, from, request_id, prepare))
+                            .map_err(|err| error!("Unable to buffer incoming request: {:?}", err))
+                    });
+                spawn(handle);
+            }
+        }
+    }
+
+    /// Set the `IncomingService` that should handle the Prepare packets
+    /// received over the UDP socket.
+    pub fn handle_incoming<S>(self, incoming_handler: S) -> UdpService<S, T, A>
+    where
+        S: IncomingService<A> + Clone + Send + 'static,
+    {
+        let mut incoming_handler_clone = incoming_handler.clone();
+        let datagrams = self.datagrams.clone();
+        let responses = self.responses.clone();
+        let handle_pending_incoming = self
+            .pending_incoming
+            .lock()
+            .take()
+            .expect("handle_incoming can only be called once")
+            .for_each(move |(account, from, request_id, prepare)| {
+                let secret = Bytes::from(account.get_udp_shared_secret().unwrap_or(&[]));
+                let datagrams = datagrams.clone();
+                let responses = responses.clone();
+                let request = IncomingRequest {
+                    from: account,
+                    prepare,
+                };
+                debug!("Handling incoming request: {:?}", &request);
+                incoming_handler_clone
+                    .handle_request(request)
+                    .then(move |result| {
+                        let packet = match result {
+                            Ok(fulfill) => BytesMut::from(fulfill),
+                            Err(reject) => BytesMut::from(reject),
+                        };
+                        let response =
+                            encode(FrameType::Response, request_id, &packet[..], &secret[..]);
+                        responses
+                            .lock()
+                            .insert((from, request_id), Some(response.clone()));
+                        spawn(
+                            Delay::new(Instant::now() + RESPONSE_CACHE_TTL).then(move |_| {
+                                responses.lock().remove(&(from, request_id));
+                                Ok(())
+                            }),
+                        );
+                        let _ = datagrams
+                            .unbounded_send((response, from))
+                            .map_err(|err| error!("Error sending response to {}: {:?}", from, err));
+                        Ok(())
+                    })
+            })
+            .then(move |_| {
+                debug!("Finished reading from pending_incoming buffer");
+                Ok(())
+            });
+        spawn(handle_pending_incoming);
+
+        UdpService {
+            outgoing: self,
+            incoming_handler_type: PhantomData,
+        }
+    }
+}
+
+impl<T, A> OutgoingService<A> for UdpOutgoingService<T, A>
+where
+    T: OutgoingService<A> + Clone,
+    A: UdpAccount + 'static,
+{
+    type Future = BoxedIlpFuture;
+
+    /// Send an outgoing request to the account's UDP address.
+    ///
+    /// If the account in `request.to` does not have a UDP address and shared secret configured,
+    /// the request will be passed through to the `next_outgoing` handler.
+    fn send_request(&mut self, request: OutgoingRequest<A>) -> Self::Future {
+        let destination = request.to.get_udp_address().and_then(|address| {
+            request
+                .to
+                .get_udp_shared_secret()
+                .map(|secret| (address, Bytes::from(secret)))
+        });
+        if let Some((address, secret)) = destination {
+            let request_id = random::<u32>();
+            let expires_at = request.prepare.expires_at();
+            let frame = encode(
+                FrameType::Request,
+                request_id,
+                &BytesMut::from(request.prepare)[..],
+                &secret[..],
+            );
+            let (sender, receiver) = oneshot::channel();
+            self.pending_outgoing.lock().insert(
+                request_id,
+                PendingRequest {
+                    address,
+                    secret,
+                    channel: sender,
+                },
+            );
+            debug!("Sending request {} to {} over UDP", request_id, address);
+
+            if let Err(send_error) = self.datagrams.unbounded_send((frame.clone(), address)) {
+                error!("Error sending datagram to {}: {:?}", address, send_error);
+                self.pending_outgoing.lock().remove(&request_id);
+                return Box::new(err(RejectBuilder {
+                    code: ErrorCode::T00_INTERNAL_ERROR,
+                    message: &[],
+                    triggered_by: &[],
+                    data: &[],
+                }
+                .build()));
+            }
+
+            // Retransmit the request until we get a response or the Prepare expires
+            let deadline = Instant::now()
+                + expires_at
+                    .duration_since(SystemTime::now())
+                    .unwrap_or_else(|_| Duration::from_secs(0));
+            let pending_outgoing = self.pending_outgoing.clone();
+            let pending_outgoing_clone = self.pending_outgoing.clone();
+            let datagrams = self.datagrams.clone();
+            let retransmit =
+                Interval::new(Instant::now() + RETRANSMIT_INTERVAL, RETRANSMIT_INTERVAL)
+                    .map_err(|err| error!("Retransmit interval error: {:?}", err))
+                    .take_while(move |_| {
+                        Ok(Instant::now() < deadline
+                            && pending_outgoing_clone.lock().contains_key(&request_id))
+                    })
+                    .for_each(move |_| {
+                        trace!("Retransmitting request {} to {}", request_id, address);
+                        datagrams
+                            .unbounded_send((frame.clone(), address))
+                            .map_err(|_| ())
+                    })
+                    .then(move |_| {
+                        // Dropping the channel rejects the request if it is still pending
+                        pending_outgoing.lock().remove(&request_id);
+                        Ok(())
+                    });
+            spawn(retransmit);
+
+            Box::new(
+                receiver
+                    .map_err(move |_| {
+                        debug!(
+                            "No response received for request {} before it expired",
+                            request_id
+                        );
+                        RejectBuilder {
+                            code: ErrorCode::R00_TRANSFER_TIMED_OUT,
+                            message: &[],
+                            triggered_by: &[],
+                            data: &[],
+                        }
+                        .build()
+                    })
+                    .and_then(|result| result),
+            )
+        } else {
+            Box::new(self.next_outgoing.send_request(request))
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct UdpService<S, T, A: Account> {
+    outgoing: UdpOutgoingService<T, A>,
+    incoming_handler_type: PhantomData<S>,
+}
+
+impl<S, T, A> OutgoingService<A> for UdpService<S, T, A>
+where
+    T: OutgoingService<A> + Clone + Send + 'static,
+    A: UdpAccount + 'static,
+{
+    type Future = BoxedIlpFuture;
+
+    /// Send an outgoing request to the account's UDP address.
+    ///
+    /// If the account in `request.to` does not have a UDP address configured, the
+    /// request will be passed through to the `next_outgoing` handler.
+    fn send_request(&mut self, request: OutgoingRequest<A>) -> Self::Future {
+        self.outgoing.send_request(request)
+    }
+}
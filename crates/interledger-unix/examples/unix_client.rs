@@ -0,0 +1,87 @@
+//! Connects to a node's Unix socket and sends a single ILP Prepare packet.
+//!
+//! Usage: `cargo run --example unix_client -- <socket path> <token> <destination> <amount>`
+//!
+//! The node sends back a Reject if the destination is unreachable or if the
+//! fulfillment cannot be produced, which is expected for this example's made-up condition.
+
+use futures::Future;
+use interledger_packet::{ErrorCode, PrepareBuilder, RejectBuilder};
+use interledger_service::{
+    incoming_service_fn, outgoing_service_fn, Account, OutgoingRequest, OutgoingService,
+};
+use interledger_unix::connect_client;
+use std::{
+    env, process,
+    time::{Duration, SystemTime},
+};
+
+/// The account that represents the node on the other side of the socket
+#[derive(Debug, Clone)]
+struct NodeAccount;
+
+impl Account for NodeAccount {
+    type AccountId = u64;
+
+    fn id(&self) -> u64 {
+        0
+    }
+}
+
+fn main() {
+    env_logger::init();
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.len() != 4 {
+        eprintln!("Usage: unix_client <socket path> <token> <destination> <amount>");
+        return;
+    }
+    let destination = args[2].clone();
+    let amount: u64 = args[3].parse().expect("Amount must be an integer");
+
+    let reject_all = outgoing_service_fn(|_| {
+        Err(RejectBuilder {
+            code: ErrorCode::F02_UNREACHABLE,
+            message: b"No other outgoing handler",
+            triggered_by: &[],
+            data: &[],
+        }
+        .build())
+    });
+    let run = connect_client(args[0].clone(), NodeAccount, &args[1], reject_all).and_then(
+        move |service| {
+            // This client does not accept incoming payments
+            let mut service = service.handle_incoming(incoming_service_fn(|_| {
+                Err(RejectBuilder {
+                    code: ErrorCode::F02_UNREACHABLE,
+                    message: b"Not accepting incoming packets",
+                    triggered_by: &[],
+                    data: &[],
+                }
+                .build())
+            }));
+            let prepare = PrepareBuilder {
+                destination: destination.as_bytes(),
+                amount,
+                execution_condition: &[0; 32],
+                expires_at: SystemTime::now() + Duration::from_secs(30),
+                data: &[],
+            }
+            .build();
+            service
+                .send_request(OutgoingRequest {
+                    from: NodeAccount,
+                    to: NodeAccount,
+                    prepare,
+                })
+                .then(|result| -> Result<(), ()> {
+                    match result {
+                        Ok(fulfill) => println!("Got fulfill: {:?}", fulfill),
+                        Err(reject) => println!("Got reject: {:?}", reject),
+                    }
+                    // The connection would otherwise keep the runtime running
+                    process::exit(0)
+                })
+        },
+    );
+    tokio::run(run);
+}
@@ -0,0 +1,66 @@
+use super::{
+    codec::{Frame, FrameCodec, FrameType},
+    UnixOutgoingService,
+};
+use bytes::BytesMut;
+use futures::{Future, Sink, Stream};
+use interledger_service::*;
+use rand::random;
+use std::path::Path;
+use tokio_codec::Decoder;
+use tokio_uds::UnixStream;
+
+/// Connect to a node's Unix socket and authenticate with the given token.
+///
+/// `node_account` represents the node: Prepare packets sent to it will be sent over the socket
+/// and Prepare packets the node sends will be passed to the incoming handler set with
+/// `handle_incoming` as coming from it.
+pub fn connect_client<P, A, S>(
+    path: P,
+    node_account: A,
+    token: &str,
+    next_outgoing: S,
+) -> impl Future<Item = UnixOutgoingService<S, A>, Error = ()>
+where
+    P: AsRef<Path>,
+    S: OutgoingService<A> + Clone + 'static,
+    A: Account + 'static,
+{
+    let path = path.as_ref().to_path_buf();
+    let request_id = random::<u32>();
+    let auth_frame = Frame {
+        frame_type: FrameType::Auth,
+        request_id,
+        data: BytesMut::from(token.as_bytes()),
+    };
+    debug!("Connecting to Unix socket {:?}", path);
+    UnixStream::connect(&path)
+        .map_err(move |err| error!("Error connecting to Unix socket {:?}: {:?}", path, err))
+        .and_then(move |stream| {
+            FrameCodec
+                .framed(stream)
+                .send(auth_frame)
+                .map_err(|err| error!("Error sending auth frame: {:?}", err))
+        })
+        .and_then(|connection| {
+            connection
+                .into_future()
+                .map_err(|(err, _connection)| error!("Error reading auth response: {:?}", err))
+        })
+        .and_then(move |(frame, connection)| match frame {
+            Some(Frame {
+                frame_type: FrameType::Response,
+                request_id: response_id,
+                ..
+            }) if response_id == request_id => {
+                debug!("Authenticated with the node over the Unix socket");
+                let service = UnixOutgoingService::new(next_outgoing);
+                service.add_connection(node_account, connection);
+                Ok(service)
+            }
+            _ => {
+                error!("Authentication was rejected by the node");
+                Err(())
+            }
+        })
+}
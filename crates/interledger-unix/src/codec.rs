@@ -0,0 +1,142 @@
+use bytes::{BufMut, BytesMut};
+use std::io::{Error as IoError, ErrorKind};
+use tokio_codec::{Decoder, Encoder};
+
+/// Length of the frame type and request ID
+const HEADER_LEN: usize = 5;
+/// Large enough for any ILP packet plus the header
+const MAX_FRAME_LEN: usize = 40000;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+#[repr(u8)]
+pub enum FrameType {
+    /// Sent by the client when it connects. The data is the client's token
+    Auth = 0,
+    /// Contains an ILP Prepare packet
+    Request = 1,
+    /// Contains an ILP Fulfill or Reject packet, or is empty if it is the response to an Auth frame
+    Response = 2,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Frame {
+    pub frame_type: FrameType,
+    pub request_id: u32,
+    pub data: BytesMut,
+}
+
+/// Codec for frames with the format:
+///
+/// `length (4 bytes, big endian) | frame type (1 byte) | request ID (4 bytes, big endian) | data`
+///
+/// where the length covers everything after the length prefix.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct FrameCodec;
+
+impl Decoder for FrameCodec {
+    type Item = Frame;
+    type Error = IoError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Frame>, IoError> {
+        if src.len() < 4 {
+            return Ok(None);
+        }
+        let len = ((src[0] as usize) << 24)
+            | ((src[1] as usize) << 16)
+            | ((src[2] as usize) << 8)
+            | src[3] as usize;
+        if len < HEADER_LEN || len > MAX_FRAME_LEN {
+            return Err(IoError::new(
+                ErrorKind::InvalidData,
+                format!("Invalid frame length: {}", len),
+            ));
+        }
+        if src.len() < 4 + len {
+            src.reserve(4 + len - src.len());
+            return Ok(None);
+        }
+
+        src.advance(4);
+        let mut frame = src.split_to(len);
+        let frame_type = match frame[0] {
+            0 => FrameType::Auth,
+            1 => FrameType::Request,
+            2 => FrameType::Response,
+            other => {
+                return Err(IoError::new(
+                    ErrorKind::InvalidData,
+                    format!("Unknown frame type: {}", other),
+                ));
+            }
+        };
+        let request_id = (u32::from(frame[1]) << 24)
+            | (u32::from(frame[2]) << 16)
+            | (u32::from(frame[3]) << 8)
+            | u32::from(frame[4]);
+        frame.advance(HEADER_LEN);
+        Ok(Some(Frame {
+            frame_type,
+            request_id,
+            data: frame,
+        }))
+    }
+}
+
+impl Encoder for FrameCodec {
+    type Item = Frame;
+    type Error = IoError;
+
+    fn encode(&mut self, frame: Frame, dst: &mut BytesMut) -> Result<(), IoError> {
+        let len = HEADER_LEN + frame.data.len();
+        if len > MAX_FRAME_LEN {
+            return Err(IoError::new(
+                ErrorKind::InvalidInput,
+                format!("Frame is too long: {}", len),
+            ));
+        }
+        dst.reserve(4 + len);
+        dst.put_u32_be(len as u32);
+        dst.put_u8(frame.frame_type as u8);
+        dst.put_u32_be(frame.request_id);
+        dst.put(&frame.data[..]);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_and_decodes() {
+        let frame = Frame {
+            frame_type: FrameType::Request,
+            request_id: 258,
+            data: BytesMut::from(&b"packet"[..]),
+        };
+        let mut buffer = BytesMut::new();
+        FrameCodec.encode(frame.clone(), &mut buffer).unwrap();
+        assert_eq!(&buffer[..9], &[0, 0, 0, 11, 1, 0, 0, 1, 2]);
+        assert_eq!(FrameCodec.decode(&mut buffer).unwrap(), Some(frame));
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn waits_for_full_frame() {
+        let mut buffer = BytesMut::from(&[0, 0, 0, 6, 2, 0, 0, 0][..]);
+        assert_eq!(FrameCodec.decode(&mut buffer).unwrap(), None);
+        buffer.extend_from_slice(&[1, 9]);
+        let frame = FrameCodec.decode(&mut buffer).unwrap().unwrap();
+        assert_eq!(frame.frame_type, FrameType::Response);
+        assert_eq!(frame.request_id, 1);
+        assert_eq!(&frame.data[..], &[9]);
+    }
+
+    #[test]
+    fn rejects_invalid_frames() {
+        let mut buffer = BytesMut::from(&[0, 0, 0, 5, 7, 0, 0, 0, 1][..]);
+        assert!(FrameCodec.decode(&mut buffer).is_err());
+        let mut buffer = BytesMut::from(&[0, 1, 0, 0][..]);
+        assert!(FrameCodec.decode(&mut buffer).is_err());
+    }
+}
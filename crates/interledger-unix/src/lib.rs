@@ -0,0 +1,170 @@
+//! # interledger-unix
+//!
+//! Transport for exchanging ILP packets with applications running on the same machine as
+//! the node (for example settlement engines or local wallets) over a Unix domain socket.
+//! This avoids the overhead of HTTP or WebSockets and does not require the node to expose
+//! a TCP port to those applications.
+//!
+//! Each frame on the socket is length-prefixed and contains either an authentication token,
+//! an ILP Prepare packet, or the ILP Fulfill or Reject sent in response to a Prepare.
+//! The first frame a client sends must be its authentication token. Access to the socket
+//! can additionally be restricted using the permissions of the socket file.
+
+#[macro_use]
+extern crate log;
+
+use futures::Future;
+use interledger_service::Account;
+
+mod client;
+mod codec;
+mod server;
+mod service;
+
+pub use self::client::connect_client;
+pub use self::codec::{Frame, FrameCodec, FrameType};
+pub use self::server::create_server;
+pub use self::service::{UnixOutgoingService, UnixService};
+
+/// The interface for Stores that can be used with the Unix socket server.
+pub trait UnixStore {
+    type Account: Account;
+
+    /// Load the details of the account that uses the given token to authenticate over the socket.
+    fn get_account_from_unix_token(
+        &self,
+        token: &str,
+    ) -> Box<Future<Item = Self::Account, Error = ()> + Send>;
+}
+
+#[cfg(test)]
+mod client_server {
+    use super::*;
+    use futures::future::{lazy, ok, result};
+    use interledger_packet::{ErrorCode, FulfillBuilder, PrepareBuilder, RejectBuilder};
+    use interledger_service::*;
+    use std::{
+        env, fs,
+        path::PathBuf,
+        time::{Duration, SystemTime},
+    };
+    use tokio::runtime::Runtime;
+
+    #[derive(Clone, Debug)]
+    pub struct TestAccount {
+        pub id: u64,
+    }
+
+    impl Account for TestAccount {
+        type AccountId = u64;
+
+        fn id(&self) -> u64 {
+            self.id
+        }
+    }
+
+    #[derive(Clone)]
+    pub struct TestStore;
+
+    impl UnixStore for TestStore {
+        type Account = TestAccount;
+
+        fn get_account_from_unix_token(
+            &self,
+            token: &str,
+        ) -> Box<Future<Item = Self::Account, Error = ()> + Send> {
+            Box::new(result(if token == "test_auth_token" {
+                Ok(TestAccount { id: 1 })
+            } else {
+                Err(())
+            }))
+        }
+    }
+
+    fn socket_path(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("interledger-unix-{}.sock", name));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    fn reject_all() -> impl OutgoingService<TestAccount> + Clone + Send + Sync {
+        outgoing_service_fn(|_| {
+            Err(RejectBuilder {
+                code: ErrorCode::F02_UNREACHABLE,
+                message: b"No other outgoing handler",
+                triggered_by: &[],
+                data: &[],
+            }
+            .build())
+        })
+    }
+
+    // Start a server that fulfills every request from an authenticated client
+    fn start_server(path: PathBuf) -> impl Future<Item = (), Error = ()> {
+        create_server(path, TestStore, reject_all()).and_then(|server| {
+            server.handle_incoming(incoming_service_fn(
+                |request: IncomingRequest<TestAccount>| {
+                    assert_eq!(request.from.id, 1);
+                    Ok(FulfillBuilder {
+                        fulfillment: &[0; 32],
+                        data: b"test data",
+                    }
+                    .build())
+                },
+            ));
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn client_server_test() {
+        let path = socket_path("client-server");
+        let mut runtime = Runtime::new().unwrap();
+        let fulfill = runtime
+            .block_on(lazy(move || {
+                start_server(path.clone())
+                    .and_then(move |_| {
+                        connect_client(path, TestAccount { id: 0 }, "test_auth_token", reject_all())
+                    })
+                    .and_then(|client| {
+                        let mut client = client.handle_incoming(incoming_service_fn(|_| {
+                            Err(RejectBuilder {
+                                code: ErrorCode::F02_UNREACHABLE,
+                                message: &[],
+                                triggered_by: &[],
+                                data: &[],
+                            }
+                            .build())
+                        }));
+                        client
+                            .send_request(OutgoingRequest {
+                                from: TestAccount { id: 0 },
+                                to: TestAccount { id: 0 },
+                                prepare: PrepareBuilder {
+                                    destination: b"example.destination",
+                                    amount: 100,
+                                    execution_condition: &[0; 32],
+                                    expires_at: SystemTime::now() + Duration::from_secs(30),
+                                    data: b"test data",
+                                }
+                                .build(),
+                            })
+                            .then(ok)
+                    })
+            }))
+            .unwrap();
+        assert_eq!(fulfill.unwrap().data(), b"test data");
+    }
+
+    #[test]
+    fn rejects_unknown_token() {
+        let path = socket_path("unknown-token");
+        let mut runtime = Runtime::new().unwrap();
+        let result = runtime.block_on(lazy(move || {
+            start_server(path.clone()).and_then(move |_| {
+                connect_client(path, TestAccount { id: 0 }, "other_token", reject_all())
+            })
+        }));
+        assert!(result.is_err());
+    }
+}
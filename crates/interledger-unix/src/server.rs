@@ -0,0 +1,105 @@
+use super::{
+    codec::{Frame, FrameCodec, FrameType},
+    UnixOutgoingService, UnixStore,
+};
+use bytes::BytesMut;
+use futures::{future::result, Future, Sink, Stream};
+use interledger_service::*;
+use std::{path::Path, str};
+use tokio_codec::{Decoder, Framed};
+use tokio_executor::spawn;
+use tokio_uds::{UnixListener, UnixStream};
+
+/// Returns a UnixOutgoingService that wraps all connections made to a Unix socket
+/// bound to the given path. Calling `handle_incoming` with an `IncomingService` will
+/// turn the returned UnixOutgoingService into a bidirectional handler.
+///
+/// The socket file is created when the server starts and must not already exist.
+pub fn create_server<P, T, U, A>(
+    path: P,
+    store: U,
+    next_outgoing: T,
+) -> impl Future<Item = UnixOutgoingService<T, A>, Error = ()>
+where
+    P: AsRef<Path>,
+    T: OutgoingService<A> + Clone + Send + Sync + 'static,
+    U: UnixStore<Account = A> + Clone + Send + Sync + 'static,
+    A: Account + 'static,
+{
+    let path = path.as_ref().to_path_buf();
+    result(UnixListener::bind(&path).map_err(|err| {
+        error!("Error binding to Unix socket {:?} {:?}", path, err);
+    }))
+    .and_then(move |listener| {
+        debug!("Listening on Unix socket {:?}", path);
+        let service = UnixOutgoingService::new(next_outgoing);
+
+        let service_clone = service.clone();
+        let handle_incoming = listener
+            .incoming()
+            .map_err(|err| error!("Error handling incoming connection: {:?}", err))
+            .for_each(move |stream| {
+                let service_clone = service_clone.clone();
+                // Don't stop accepting connections if one of them fails to authenticate
+                validate_auth(store.clone(), FrameCodec.framed(stream))
+                    .and_then(move |(account, connection)| {
+                        debug!("Added connection for account: {:?}", account);
+                        service_clone.add_connection(account, connection);
+                        Ok(())
+                    })
+                    .then(|_| Ok(()))
+            })
+            .then(move |result| {
+                debug!("Finished reading connections from UnixListener");
+                result
+            });
+        spawn(handle_incoming);
+
+        Ok(service)
+    })
+}
+
+/// Read the auth frame the client sends when it connects, load the account it
+/// belongs to, and acknowledge it.
+fn validate_auth<U, A>(
+    store: U,
+    connection: Framed<UnixStream, FrameCodec>,
+) -> impl Future<Item = (A, Framed<UnixStream, FrameCodec>), Error = ()>
+where
+    U: UnixStore<Account = A> + 'static,
+    A: Account + 'static,
+{
+    connection
+        .into_future()
+        .map_err(|(err, _connection)| error!("Error reading auth frame: {:?}", err))
+        .and_then(|(frame, connection)| match frame {
+            Some(Frame {
+                frame_type: FrameType::Auth,
+                request_id,
+                data,
+            }) => Ok((request_id, data, connection)),
+            _ => {
+                warn!("First frame sent on Unix socket was not an auth frame");
+                Err(())
+            }
+        })
+        .and_then(move |(request_id, data, connection)| {
+            let token = str::from_utf8(&data[..]).map_err(|_| ())?.to_string();
+            Ok((request_id, token, connection))
+        })
+        .and_then(move |(request_id, token, connection)| {
+            store
+                .get_account_from_unix_token(&token)
+                .map(move |account| (request_id, account, connection))
+        })
+        .and_then(|(request_id, account, connection)| {
+            connection
+                .send(Frame {
+                    frame_type: FrameType::Response,
+                    request_id,
+                    data: BytesMut::new(),
+                })
+                .map_err(|err| error!("Error sending auth response: {:?}", err))
+                .map(move |connection| (account, connection))
+        })
+}
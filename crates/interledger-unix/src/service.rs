@@ -0,0 +1,264 @@
+use super::codec::{Frame, FrameCodec, FrameType};
+use bytes::BytesMut;
+use futures::{
+    future::err,
+    sync::mpsc::{unbounded, UnboundedReceiver, UnboundedSender},
+    sync::oneshot,
+    Future, Sink, Stream,
+};
+use hashbrown::HashMap;
+use interledger_packet::{ErrorCode, Fulfill, Packet, Prepare, Reject, RejectBuilder};
+use interledger_service::*;
+use parking_lot::{Mutex, RwLock};
+use rand::random;
+use std::{
+    io::{Error as IoError, ErrorKind},
+    marker::PhantomData,
+    sync::Arc,
+};
+use tokio_codec::Framed;
+use tokio_executor::spawn;
+use tokio_uds::UnixStream;
+
+type IlpResultChannel = oneshot::Sender<Result<Fulfill, Reject>>;
+type IncomingRequestBuffer<A> = UnboundedReceiver<(A, u32, Prepare)>;
+
+/// A container for Unix socket connections that implements OutgoingService
+/// for sending outgoing ILP Prepare packets over the connection for the account.
+///
+/// This is used by both the server (the node) and the client (the local application).
+#[derive(Clone)]
+pub struct UnixOutgoingService<T, A: Account> {
+    connections: Arc<RwLock<HashMap<A::AccountId, UnboundedSender<Frame>>>>,
+    pending_outgoing: Arc<Mutex<HashMap<u32, IlpResultChannel>>>,
+    pending_incoming: Arc<Mutex<Option<IncomingRequestBuffer<A>>>>,
+    incoming_sender: UnboundedSender<(A, u32, Prepare)>,
+    next_outgoing: T,
+}
+
+impl<T, A> UnixOutgoingService<T, A>
+where
+    T: OutgoingService<A> + Clone,
+    A: Account + 'static,
+{
+    pub fn new(next_outgoing: T) -> Self {
+        let (incoming_sender, incoming_receiver) = unbounded();
+        UnixOutgoingService {
+            connections: Arc::new(RwLock::new(HashMap::new())),
+            pending_outgoing: Arc::new(Mutex::new(HashMap::new())),
+            pending_incoming: Arc::new(Mutex::new(Some(incoming_receiver))),
+            incoming_sender,
+            next_outgoing,
+        }
+    }
+
+    /// Set up an authenticated connection so that outgoing Prepare packets can be sent to it,
+    /// incoming Prepare packets are buffered until an IncomingService is added, and ILP Fulfill
+    /// and Reject packets are sent back to the Future that sent the outgoing request.
+    pub(crate) fn add_connection(&self, account: A, connection: Framed<UnixStream, FrameCodec>) {
+        let account_id = account.id();
+
+        // Set up a channel to forward outgoing frames to the socket
+        let (tx, rx) = unbounded();
+        let (sink, stream) = connection.split();
+        let forward_to_connection = sink
+            .send_all(rx.map_err(|_err| IoError::from(ErrorKind::ConnectionAborted)))
+            .then(move |_| {
+                debug!("Finished forwarding to Unix socket");
+                Ok(())
+            });
+
+        let pending_requests = self.pending_outgoing.clone();
+        let incoming_sender = self.incoming_sender.clone();
+        let handle_incoming = stream
+            .map_err(move |err| {
+                error!(
+                    "Error reading from Unix socket for account {}: {:?}",
+                    account_id, err
+                )
+            })
+            .for_each(move |frame| {
+                match (frame.frame_type, Packet::try_from(frame.data)) {
+                    (FrameType::Request, Ok(Packet::Prepare(prepare))) => incoming_sender
+                        .clone()
+                        .unbounded_send((account.clone(), frame.request_id, prepare))
+                        .map_err(|err| error!("Unable to buffer incoming request: {:?}", err)),
+                    (FrameType::Response, Ok(Packet::Fulfill(fulfill))) => {
+                        if let Some(channel) = pending_requests.lock().remove(&frame.request_id) {
+                            let _ = channel.send(Ok(fulfill));
+                        } else {
+                            warn!("Got Fulfill packet that does not match an outgoing Prepare we sent: {:?}", fulfill);
+                        }
+                        Ok(())
+                    }
+                    (FrameType::Response, Ok(Packet::Reject(reject))) => {
+                        if let Some(channel) = pending_requests.lock().remove(&frame.request_id) {
+                            let _ = channel.send(Err(reject));
+                        } else {
+                            warn!("Got Reject packet that does not match an outgoing Prepare we sent: {:?}", reject);
+                        }
+                        Ok(())
+                    }
+                    _ => {
+                        // Close the connection if the peer isn't following the protocol
+                        warn!("Got unexpected frame from account {}", account_id);
+                        Err(())
+                    }
+                }
+            });
+
+        let connections = self.connections.clone();
+        let handle_connection = handle_incoming
+            .select(forward_to_connection)
+            .then(move |_| {
+                connections.write().remove(&account_id);
+                debug!("Unix socket connection closed for account {}", account_id);
+                Ok(())
+            });
+        spawn(handle_connection);
+
+        self.connections.write().insert(account_id, tx);
+    }
+
+    /// Convert this UnixOutgoingService into a bidirectional UnixService by adding a handler for incoming requests.
+    /// This will automatically pull all incoming Prepare packets from the channel buffer and call the IncomingService with them.
+    pub fn handle_incoming<S>(self, incoming_handler: S) -> UnixService<S, T, A>
+    where
+        S: IncomingService<A> + Clone + Send + 'static,
+    {
+        let mut incoming_handler_clone = incoming_handler.clone();
+        let connections_clone = self.connections.clone();
+        let handle_pending_incoming = self
+            .pending_incoming
+            .lock()
+            .take()
+            .expect("handle_incoming can only be called once")
+            .for_each(move |(account, request_id, prepare)| {
+                let account_id = account.id();
+                let connections_clone = connections_clone.clone();
+                let request = IncomingRequest {
+                    from: account,
+                    prepare,
+                };
+                debug!("Handling incoming request: {:?}", &request);
+                incoming_handler_clone
+                    .handle_request(request)
+                    .then(move |result| {
+                        let data = match result {
+                            Ok(fulfill) => BytesMut::from(fulfill),
+                            Err(reject) => BytesMut::from(reject),
+                        };
+                        let frame = Frame {
+                            frame_type: FrameType::Response,
+                            request_id,
+                            data,
+                        };
+                        if let Some(sender) = connections_clone.read().get(&account_id) {
+                            let _ = sender.unbounded_send(frame).map_err(|err| {
+                                error!(
+                                    "Error sending response to account: {} {:?}",
+                                    account_id, err
+                                )
+                            });
+                        } else {
+                            debug!(
+                                "Connection for account {} was closed before the response could be sent",
+                                account_id
+                            );
+                        }
+                        Ok(())
+                    })
+            })
+            .then(move |_| {
+                debug!("Finished reading from pending_incoming buffer");
+                Ok(())
+            });
+        spawn(handle_pending_incoming);
+
+        UnixService {
+            outgoing: self,
+            incoming_handler_type: PhantomData,
+        }
+    }
+}
+
+impl<T, A> OutgoingService<A> for UnixOutgoingService<T, A>
+where
+    T: OutgoingService<A> + Clone,
+    A: Account + 'static,
+{
+    type Future = BoxedIlpFuture;
+
+    /// Send an outgoing request over the account's Unix socket connection.
+    ///
+    /// If there is no open connection for the Account specified in `request.to`, the
+    /// request will be passed through to the `next_outgoing` handler.
+    fn send_request(&mut self, request: OutgoingRequest<A>) -> Self::Future {
+        if let Some(sender) = self.connections.read().get(&request.to.id()) {
+            let request_id = random::<u32>();
+            let (channel, receiver) = oneshot::channel();
+            self.pending_outgoing.lock().insert(request_id, channel);
+            debug!("Sending outgoing request: {:?}", request);
+
+            let frame = Frame {
+                frame_type: FrameType::Request,
+                request_id,
+                data: BytesMut::from(request.prepare),
+            };
+            if let Err(send_error) = sender.unbounded_send(frame) {
+                error!("Error sending frame to Unix socket: {:?}", send_error);
+                self.pending_outgoing.lock().remove(&request_id);
+                return Box::new(err(RejectBuilder {
+                    code: ErrorCode::T00_INTERNAL_ERROR,
+                    message: &[],
+                    triggered_by: &[],
+                    data: &[],
+                }
+                .build()));
+            }
+
+            Box::new(
+                receiver
+                    .map_err(|err| {
+                        debug!("Sending request failed: {:?}", err);
+                        RejectBuilder {
+                            code: ErrorCode::T00_INTERNAL_ERROR,
+                            message: &[],
+                            triggered_by: &[],
+                            data: &[],
+                        }
+                        .build()
+                    })
+                    .and_then(|result| result),
+            )
+        } else {
+            debug!(
+                "No open connection for account: {}, forwarding request to the next service",
+                request.to.id()
+            );
+            Box::new(self.next_outgoing.send_request(request))
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct UnixService<S, T, A: Account> {
+    outgoing: UnixOutgoingService<T, A>,
+    incoming_handler_type: PhantomData<S>,
+}
+
+impl<S, T, A> OutgoingService<A> for UnixService<S, T, A>
+where
+    T: OutgoingService<A> + Clone + Send + 'static,
+    A: Account + 'static,
+{
+    type Future = BoxedIlpFuture;
+
+    /// Send an outgoing request over the account's Unix socket connection.
+    ///
+    /// If there is no open connection for the Account specified in `request.to`, the
+    /// request will be passed through to the `next_outgoing` handler.
+    fn send_request(&mut self, request: OutgoingRequest<A>) -> Self::Future {
+        self.outgoing.send_request(request)
+    }
+}
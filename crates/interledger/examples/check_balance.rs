@@ -0,0 +1,77 @@
+//! Look up an account's balance using the node's HTTP API.
+//!
+//! The account is configured with environment variables:
+//!
+//! - `NODE_URL`: the node's HTTP API (default `http://localhost:7770`)
+//! - `ACCOUNT_ID`: the account to look up (default `1`)
+//! - `AUTH_TOKEN`: the account's (or an admin's) HTTP bearer token (default `token-two`)
+//! - `MIN_BALANCE`: if set, exit with an error unless the balance is at least this much
+//!
+//! ```sh
+//! cargo run --example check_balance
+//! ```
+
+use futures::{Future, Stream};
+use hyper::{Body, Client, Request};
+use serde_json::Value;
+use std::{env, process};
+
+fn env_or(name: &str, default: &str) -> String {
+    env::var(name).unwrap_or_else(|_| default.to_string())
+}
+
+fn main() {
+    let node_url = env_or("NODE_URL", "http://localhost:7770");
+    let account_id = env_or("ACCOUNT_ID", "1");
+    let auth_token = env_or("AUTH_TOKEN", "token-two");
+    let min_balance: Option<i64> = env::var("MIN_BALANCE")
+        .ok()
+        .map(|min| min.parse().expect("MIN_BALANCE must be a number"));
+
+    let request = Request::get(format!(
+        "{}/accounts/{}/balance",
+        node_url.trim_end_matches('/'),
+        account_id
+    ))
+    .header("Authorization", format!("Bearer {}", auth_token))
+    .body(Body::empty())
+    .expect("Invalid NODE_URL");
+    let get_balance = Client::new()
+        .request(request)
+        .map_err(|err| eprintln!("Error querying the node: {:?}", err))
+        .and_then(|response| {
+            let status = response.status();
+            response
+                .into_body()
+                .concat2()
+                .map_err(|err| eprintln!("Error reading the node's response: {:?}", err))
+                .and_then(move |body| {
+                    if !status.is_success() {
+                        eprintln!("Node returned an error: {}", status);
+                        return Err(());
+                    }
+                    // The balance is returned as a string so it can be larger than JSON numbers allow
+                    let body: Value = serde_json::from_slice(&body)
+                        .map_err(|err| eprintln!("Invalid JSON from the node: {:?}", err))?;
+                    body["balance"]
+                        .as_str()
+                        .and_then(|balance| balance.parse::<i64>().ok())
+                        .ok_or_else(|| eprintln!("Node did not return a balance: {}", body))
+                })
+        });
+
+    let balance = tokio::runtime::Runtime::new()
+        .unwrap()
+        .block_on(get_balance)
+        .unwrap_or_else(|_| process::exit(1));
+    println!("Balance of account {}: {}", account_id, balance);
+    if let Some(min_balance) = min_balance {
+        if balance < min_balance {
+            eprintln!(
+                "Expected a balance of at least {} but it is {}",
+                min_balance, balance
+            );
+            process::exit(1);
+        }
+    }
+}
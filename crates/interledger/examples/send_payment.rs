@@ -0,0 +1,101 @@
+//! Send an SPSP payment through a node, connecting to it as a BTP client.
+//!
+//! The account is configured with environment variables:
+//!
+//! - `ILP_BTP_URI`: the node's BTP server, with the account's token as the password
+//!   (default `btp+ws://:token-one@localhost:7768`)
+//! - `SPSP_RECEIVER`: the receiver's SPSP endpoint or payment pointer
+//!   (default `http://localhost:7770/spsp/1`)
+//! - `AMOUNT`: how much to send, in the sender's units (default `1000`)
+//! - `MIN_DELIVERED`: if set, exit with an error unless at least this much was
+//!   delivered, in the receiver's units
+//!
+//! ```sh
+//! cargo run --example send_payment
+//! ```
+
+use futures::Future;
+use interledger::{
+    btp::{connect_client, parse_btp_url},
+    packet::{ErrorCode, RejectBuilder},
+    router::Router,
+    service::{incoming_service_fn, outgoing_service_fn, OutgoingRequest},
+    service_util::ValidatorService,
+    spsp::pay,
+    store_memory::{Account, AccountBuilder, InMemoryStore},
+};
+use std::{env, process};
+
+fn env_or(name: &str, default: &str) -> String {
+    env::var(name).unwrap_or_else(|_| default.to_string())
+}
+
+fn main() {
+    let btp_uri = env_or("ILP_BTP_URI", "btp+ws://:token-one@localhost:7768");
+    let receiver = env_or("SPSP_RECEIVER", "http://localhost:7770/spsp/1");
+    let amount: u64 = env_or("AMOUNT", "1000")
+        .parse()
+        .expect("AMOUNT must be a number");
+    let min_delivered: Option<u64> = env::var("MIN_DELIVERED")
+        .ok()
+        .map(|min| min.parse().expect("MIN_DELIVERED must be a number"));
+
+    // Everything we send goes to the node
+    let account = AccountBuilder::new()
+        .additional_routes(&[&b""[..]])
+        .btp_uri(parse_btp_url(&btp_uri).expect("Invalid ILP_BTP_URI"))
+        .build();
+    let store = InMemoryStore::from_accounts(vec![account.clone()]);
+
+    let payment = connect_client(
+        vec![account.clone()],
+        outgoing_service_fn(|_request: OutgoingRequest<Account>| {
+            Err(RejectBuilder {
+                code: ErrorCode::F02_UNREACHABLE,
+                message: b"The sender only sends packets to the node",
+                triggered_by: &[],
+                data: &[],
+            }
+            .build())
+        }),
+    )
+    .map_err(move |_| eprintln!("Unable to connect to the node's BTP server at {}", btp_uri))
+    .and_then(move |btp_service| {
+        // The node should not send us any packets because we do not receive payments
+        let btp_service = btp_service.handle_incoming(incoming_service_fn(|_| {
+            Err(RejectBuilder {
+                code: ErrorCode::F02_UNREACHABLE,
+                message: b"Not expecting incoming prepare packets",
+                triggered_by: &[],
+                data: &[],
+            }
+            .build())
+        }));
+        let service = ValidatorService::outgoing(btp_service.clone());
+        let service = Router::new(store, service);
+        pay(service, account, &receiver, amount)
+            .map_err(|err| eprintln!("Error sending payment: {:?}", err))
+            .then(move |result| {
+                btp_service.close();
+                result
+            })
+    });
+
+    let outcome = tokio::runtime::Runtime::new()
+        .unwrap()
+        .block_on(payment)
+        .unwrap_or_else(|_| process::exit(1));
+    println!(
+        "Sent {}, delivered {} (in the receiver's units)",
+        outcome.source_amount_sent, outcome.amount_delivered
+    );
+    if let Some(min_delivered) = min_delivered {
+        if outcome.amount_delivered < min_delivered {
+            eprintln!(
+                "Expected at least {} to be delivered but only {} was",
+                min_delivered, outcome.amount_delivered
+            );
+            process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,155 @@
+//! Obtains certificates from an ACME provider such as Let's Encrypt and renews them before
+//! they expire, answering the provider's HTTP-01 challenges with a small HTTP server.
+//!
+//! The ACME client is blocking, so it runs on its own thread.
+
+use super::tls::{parse_certified_key, CertificateResolver};
+use acme_lib::{create_p384_key, persist::FilePersist, Certificate, Directory, DirectoryUrl};
+use futures::{future::result, Future};
+use hyper::{service::service_fn_ok, Body, Request, Response, Server};
+use interledger_config::AcmeConfig;
+use parking_lot::RwLock;
+use std::{collections::HashMap, fs, sync::Arc, thread, time::Duration};
+
+const CHALLENGE_PATH: &str = "/.well-known/acme-challenge/";
+/// How long to wait between polls while the provider validates challenges and issues certificates
+const POLL_INTERVAL_MS: u64 = 5000;
+/// How long to wait before trying again if a certificate could not be obtained
+const RETRY_DELAY: Duration = Duration::from_secs(60 * 60);
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+// The key authorization to answer each challenge token with
+type Challenges = Arc<RwLock<HashMap<String, String>>>;
+
+/// Answer the provider's challenges and keep a valid certificate for the configured
+/// domains in the resolver.
+pub fn run_acme(
+    config: AcmeConfig,
+    resolver: CertificateResolver,
+) -> impl Future<Item = (), Error = ()> {
+    let challenges: Challenges = Arc::new(RwLock::new(HashMap::new()));
+    let challenges_clone = challenges.clone();
+    let address = config.challenge_address;
+    result(Server::try_bind(&address))
+        .map_err(move |err| {
+            error!(
+                "Unable to answer ACME challenges on {}, so no certificates will be obtained: {:?}",
+                address, err
+            )
+        })
+        .and_then(move |server| {
+            thread::spawn(move || renew_certificates(config, resolver, challenges));
+            server
+                .serve(move || {
+                    let challenges = challenges_clone.clone();
+                    service_fn_ok(move |request: Request<Body>| {
+                        let path = request.uri().path();
+                        let proof = if path.starts_with(CHALLENGE_PATH) {
+                            challenges
+                                .read()
+                                .get(&path[CHALLENGE_PATH.len()..])
+                                .cloned()
+                        } else {
+                            None
+                        };
+                        match proof {
+                            Some(proof) => Response::new(Body::from(proof)),
+                            None => Response::builder().status(404).body(Body::empty()).unwrap(),
+                        }
+                    })
+                })
+                .map_err(|err| error!("ACME challenge server error: {:?}", err))
+        })
+}
+
+fn renew_certificates(config: AcmeConfig, resolver: CertificateResolver, challenges: Challenges) {
+    loop {
+        let wait = match obtain_certificate(&config, &challenges).and_then(|certificate| {
+            let key = parse_certified_key(
+                certificate.certificate().as_bytes(),
+                certificate.private_key().as_bytes(),
+            )?;
+            Ok((key, certificate.valid_days_left()))
+        }) {
+            Ok((key, days_left)) => {
+                resolver.set_certificate(&config.domains, key);
+                let valid_for = Duration::from_secs(days_left.max(0) as u64 * SECONDS_PER_DAY);
+                valid_for
+                    .checked_sub(config.renew_before)
+                    .unwrap_or_else(|| Duration::from_secs(0))
+                    .max(RETRY_DELAY)
+            }
+            Err(message) => {
+                error!(
+                    "Unable to obtain a certificate for {} from {}: {}",
+                    config.domains.join(", "),
+                    config.directory_url,
+                    message
+                );
+                RETRY_DELAY
+            }
+        };
+        thread::sleep(wait);
+    }
+}
+
+// Use the saved certificate unless it is due for renewal, in which case a new one is ordered
+fn obtain_certificate(config: &AcmeConfig, challenges: &Challenges) -> Result<Certificate, String> {
+    fs::create_dir_all(&config.cache_dir).map_err(|err| {
+        format!(
+            "Unable to create directory {}: {}",
+            config.cache_dir.display(),
+            err
+        )
+    })?;
+    let directory = Directory::from_url(
+        FilePersist::new(&config.cache_dir),
+        DirectoryUrl::Other(config.directory_url.as_str()),
+    )
+    .map_err(|err| err.to_string())?;
+    let account = directory
+        .account(&config.email)
+        .map_err(|err| err.to_string())?;
+    let primary = config.domains[0].as_str();
+    let alternates: Vec<&str> = config.domains[1..].iter().map(String::as_str).collect();
+
+    let renew_before_days = (config.renew_before.as_secs() / SECONDS_PER_DAY) as i64;
+    if let Some(certificate) = account
+        .certificate(primary)
+        .map_err(|err| err.to_string())?
+    {
+        if certificate.valid_days_left() > renew_before_days {
+            return Ok(certificate);
+        }
+    }
+
+    info!("Ordering a certificate for {}", config.domains.join(", "));
+    let mut order = account
+        .new_order(primary, &alternates)
+        .map_err(|err| err.to_string())?;
+    let csr = loop {
+        if let Some(csr) = order.confirm_validations() {
+            break csr;
+        }
+        for authorization in order.authorizations().map_err(|err| err.to_string())? {
+            if !authorization.need_challenge() {
+                continue;
+            }
+            let challenge = authorization.http_challenge();
+            challenges
+                .write()
+                .insert(challenge.http_token().to_string(), challenge.http_proof());
+            challenge
+                .validate(POLL_INTERVAL_MS)
+                .map_err(|err| err.to_string())?;
+        }
+        order.refresh().map_err(|err| err.to_string())?;
+    };
+    challenges.write().clear();
+    let certificate = csr
+        .finalize_pkey(create_p384_key(), POLL_INTERVAL_MS)
+        .and_then(|order| order.download_and_save_cert())
+        .map_err(|err| err.to_string())?;
+    info!("Obtained a certificate for {}", config.domains.join(", "));
+    Ok(certificate)
+}
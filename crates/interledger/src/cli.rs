@@ -1,33 +1,47 @@
+use super::node::NodeBuilder;
 use base64;
 use bytes::Bytes;
-use futures::{future::ok, Future};
+use futures::{
+    future::{err, ok, result, Either},
+    Future, Stream,
+};
 use hyper::{
     header::{HeaderValue, ACCEPT},
     service::{service_fn, Service},
-    Body, Error, Method, Request, Response, Server,
+    Body, Client, Error, Method, Request, Response, Server,
+};
+use interledger_api::{ConnectionInfo, ConnectionRegistry, NodeStore};
+use interledger_btp::{
+    connect_client, create_open_signup_server, parse_btp_url, BtpOutgoingService,
 };
-use interledger_api::{NodeApi, NodeStore};
-use interledger_btp::{connect_client, create_open_signup_server, create_server, parse_btp_url};
-use interledger_ccp::CcpRouteManager;
+use interledger_config::ConstraintsConfig;
 use interledger_http::{HttpClientService, HttpServerService};
-use interledger_ildcp::{get_ildcp_info, IldcpAccount, IldcpResponse, IldcpService};
+use interledger_ildcp::{
+    get_ildcp_info, IldcpAccount, IldcpResponse, IldcpResponseBuilder, IldcpService,
+};
 use interledger_packet::{ErrorCode, RejectBuilder};
 use interledger_router::Router;
 use interledger_service::{
-    incoming_service_fn, outgoing_service_fn, AccountStore, OutgoingRequest,
-};
-use interledger_service_util::{
-    ExchangeRateAndBalanceService, MaxPacketAmountService, ValidatorService,
+    incoming_service_fn, outgoing_service_fn, Account as AccountTrait, AccountStore,
+    BoxedIlpFuture, OutgoingRequest, OutgoingService,
 };
+use interledger_service_util::ValidatorService;
 use interledger_spsp::{pay, SpspResponder};
 use interledger_store_memory::{Account, AccountBuilder, InMemoryStore};
 use interledger_store_redis::{connect as connect_redis_store, IntoConnectionInfo};
-use interledger_stream::StreamReceiverService;
+use interledger_stream::{PaymentEndState, PaymentOutcome, StreamReceiverService};
 use parking_lot::RwLock;
 use ring::rand::{SecureRandom, SystemRandom};
-use std::{net::SocketAddr, str, sync::Arc, u64};
-use tokio::{self, net::TcpListener};
-use tower_web::ServiceBuilder;
+use std::{
+    net::SocketAddr,
+    str::{self, FromStr},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+    u64,
+};
 use url::Url;
 
 #[doc(hidden)]
@@ -44,80 +58,88 @@ pub fn random_secret() -> [u8; 32] {
     bytes
 }
 
+/// Returns the amount delivered, or a message describing why the payment failed.
 #[doc(hidden)]
 pub fn send_spsp_payment_btp(
     btp_server: &str,
     receiver: &str,
     amount: u64,
     quiet: bool,
-) -> impl Future<Item = (), Error = ()> {
+) -> impl Future<Item = u64, Error = String> {
     let receiver = receiver.to_string();
+    let btp_uri = match Url::parse(btp_server) {
+        Ok(btp_uri) => btp_uri,
+        Err(error) => return Either::A(err(format!("Invalid BTP server URL: {}", error))),
+    };
     let account = AccountBuilder::new()
         .additional_routes(&[&b""[..]])
-        .btp_uri(Url::parse(btp_server).unwrap())
+        .btp_uri(btp_uri)
         .build();
-    connect_client(
-        vec![account.clone()],
-        outgoing_service_fn(|request: OutgoingRequest<Account>| {
-            Err(RejectBuilder {
-                code: ErrorCode::F02_UNREACHABLE,
-                message: &format!(
-                    "No route found for address: {}",
-                    str::from_utf8(&request.from.client_address()[..]).unwrap_or("<not utf8>")
-                )
-                .as_bytes(),
-                triggered_by: &[],
-                data: &[],
-            }
-            .build())
+    Either::B(
+        connect_client(
+            vec![account.clone()],
+            outgoing_service_fn(|request: OutgoingRequest<Account>| {
+                Err(RejectBuilder {
+                    code: ErrorCode::F02_UNREACHABLE,
+                    message: &format!(
+                        "No route found for address: {}",
+                        str::from_utf8(&request.from.client_address()[..]).unwrap_or("<not utf8>")
+                    )
+                    .as_bytes(),
+                    triggered_by: &[],
+                    data: &[],
+                }
+                .build())
+            }),
+        )
+        .map_err(|err| {
+            format!(
+                "Error connecting to BTP server: {:?}\n(Hint: is moneyd running?)",
+                err
+            )
+        })
+        .and_then(move |btp_service| {
+            let service = btp_service.handle_incoming(incoming_service_fn(|_| {
+                Err(RejectBuilder {
+                    code: ErrorCode::F02_UNREACHABLE,
+                    message: b"Not expecting incoming prepare packets",
+                    triggered_by: &[],
+                    data: &[],
+                }
+                .build())
+            }));
+            // TODO seems kind of janky to clone the btp_service just to
+            // close it later. Is there some better way of making sure it closes?
+            let btp_service = service.clone();
+            let service = ValidatorService::outgoing(service);
+            let store = InMemoryStore::from_accounts(vec![account.clone()]);
+            let router = Router::new(store, service);
+            pay(router, account, &receiver, amount)
+                .map_err(|err| format!("Error sending SPSP payment: {}", err))
+                .and_then(move |outcome| {
+                    if !quiet {
+                        print_outcome(&outcome);
+                    }
+                    btp_service.close();
+                    Ok(outcome.amount_delivered)
+                })
         }),
     )
-    .map_err(|err| {
-        eprintln!("Error connecting to BTP server: {:?}", err);
-        eprintln!("(Hint: is moneyd running?)");
-    })
-    .and_then(move |btp_service| {
-        let service = btp_service.handle_incoming(incoming_service_fn(|_| {
-            Err(RejectBuilder {
-                code: ErrorCode::F02_UNREACHABLE,
-                message: b"Not expecting incoming prepare packets",
-                triggered_by: &[],
-                data: &[],
-            }
-            .build())
-        }));
-        // TODO seems kind of janky to clone the btp_service just to
-        // close it later. Is there some better way of making sure it closes?
-        let btp_service = service.clone();
-        let service = ValidatorService::outgoing(service);
-        let store = InMemoryStore::from_accounts(vec![account.clone()]);
-        let router = Router::new(store, service);
-        pay(router, account, &receiver, amount)
-            .map_err(|err| {
-                eprintln!("Error sending SPSP payment: {:?}", err);
-            })
-            .and_then(move |delivered| {
-                if !quiet {
-                    println!(
-                        "Sent: {}, delivered: {} (in the receiver's units)",
-                        amount, delivered
-                    );
-                }
-                btp_service.close();
-                Ok(())
-            })
-    })
 }
 
+/// Returns the amount delivered, or a message describing why the payment failed.
 #[doc(hidden)]
 pub fn send_spsp_payment_http(
     http_server: &str,
     receiver: &str,
     amount: u64,
     quiet: bool,
-) -> impl Future<Item = (), Error = ()> {
+) -> impl Future<Item = u64, Error = String> {
     let receiver = receiver.to_string();
-    let url = Url::parse(http_server).expect("Cannot parse HTTP URL");
+    let url = match Url::parse(http_server) {
+        Ok(url) => url,
+        Err(error) => return Either::A(err(format!("Invalid HTTP server URL: {}", error))),
+    };
     let auth_header = if !url.username().is_empty() {
         Some(format!(
             "Basic {}",
@@ -135,32 +157,43 @@ pub fn send_spsp_payment_http(
     let account = if let Some(auth_header) = auth_header {
         AccountBuilder::new()
             .additional_routes(&[&b""[..]])
-            .http_endpoint(Url::parse(http_server).unwrap())
+            .http_endpoint(url)
             .http_outgoing_authorization(auth_header)
             .build()
     } else {
         AccountBuilder::new()
             .additional_routes(&[&b""[..]])
-            .http_endpoint(Url::parse(http_server).unwrap())
+            .http_endpoint(url)
             .build()
     };
     let store = InMemoryStore::from_accounts(vec![account.clone()]);
     let service = HttpClientService::new(store.clone());
     let service = ValidatorService::outgoing(service);
     let service = Router::new(store, service);
-    pay(service, account, &receiver, amount)
-        .map_err(|err| {
-            eprintln!("Error sending SPSP payment: {:?}", err);
-        })
-        .and_then(move |delivered| {
-            if !quiet {
-                println!(
-                    "Sent: {}, delivered: {} (in the receiver's units)",
-                    amount, delivered
-                );
-            }
-            Ok(())
-        })
+    Either::B(
+        pay(service, account, &receiver, amount)
+            .map_err(|err| format!("Error sending SPSP payment: {}", err))
+            .and_then(move |outcome| {
+                if !quiet {
+                    print_outcome(&outcome);
+                }
+                Ok(outcome.amount_delivered)
+            }),
+    )
+}
+
+fn print_outcome(outcome: &PaymentOutcome) {
+    println!(
+        "Sent: {}, delivered: {} (in the receiver's units)",
+        outcome.source_amount_sent, outcome.amount_delivered
+    );
+    match outcome.end_state {
+        PaymentEndState::Completed => {}
+        PaymentEndState::Partial(ref reason) => println!("Payment stopped early: {}", reason),
+        PaymentEndState::FailedWithCode(code) => {
+            println!("Payment failed with ILP error: {}", code)
+        }
+    }
 }
 
 // TODO allow server secret to be specified
@@ -354,75 +387,61 @@ pub fn run_node_redis<R>(
     btp_address: SocketAddr,
     http_address: SocketAddr,
     server_secret: &[u8; 32],
+    constraints: ConstraintsConfig,
+    balance_snapshot_interval: Duration,
 ) -> impl Future<Item = (), Error = ()>
 where
     R: IntoConnectionInfo,
 {
-    debug!("Starting Interledger node with Redis store");
-    let server_secret = Bytes::from(&server_secret[..]);
-    connect_redis_store(redis_uri)
-        .map_err(|err| eprintln!("Error connecting to Redis: {:?}", err))
-        .and_then(move |store| {
-            store
-                .clone()
-                .get_accounts(vec![0])
-                .map_err(|_| {
-                    eprintln!("Must add account 0 (the default account) before running the node")
-                })
-                .and_then(move |accounts| {
-                    let default_account = accounts[0].clone();
-                    let outgoing_service = HttpClientService::new(store.clone());
-                    create_server(btp_address, store.clone(), outgoing_service).and_then(
-                        move |btp_service| {
-                            // The BTP service is both an Incoming and Outgoing one so we pass it first as the Outgoing
-                            // service to others like the router and then call handle_incoming on it to set up the incoming handler
-                            let outgoing_service = btp_service.clone();
-                            let outgoing_service = ValidatorService::outgoing(outgoing_service);
-                            let outgoing_service =
-                                StreamReceiverService::new(server_secret.clone(), outgoing_service);
-                            let outgoing_service =
-                                ExchangeRateAndBalanceService::new(store.clone(), outgoing_service);
-
-                            // Set up the Router and Routing Manager
-                            let incoming_service =
-                                Router::new(store.clone(), outgoing_service.clone());
-                            let incoming_service = CcpRouteManager::new(
-                                default_account,
-                                store.clone(),
-                                outgoing_service,
-                                incoming_service,
-                            );
+    NodeBuilder::new(redis_uri)
+        .server_secret(*server_secret)
+        .btp_address(btp_address)
+        .http_address(http_address)
+        .constraints(constraints)
+        .balance_snapshot_interval(balance_snapshot_interval)
+        .run()
+        .map(|_node| ())
+}
 
-                            let incoming_service = IldcpService::new(incoming_service);
-                            let incoming_service = MaxPacketAmountService::new(incoming_service);
-                            let incoming_service = ValidatorService::incoming(incoming_service);
+// Exposes the BTP server's connections to the API
+pub(crate) struct BtpConnections<T, A: AccountTrait>(pub(crate) BtpOutgoingService<T, A>);
 
-                            // Handle incoming packets sent via BTP
-                            btp_service.handle_incoming(incoming_service.clone());
+impl<T, A> ConnectionRegistry for BtpConnections<T, A>
+where
+    T: OutgoingService<A> + Clone,
+    A: AccountTrait + 'static,
+{
+    fn connections(&self) -> Vec<ConnectionInfo> {
+        self.0
+            .connections()
+            .into_iter()
+            .map(|connection| ConnectionInfo {
+                account_id: connection.account_id.to_string(),
+                remote_address: connection.remote_address.map(|address| address.to_string()),
+                connected_at: connection.connected_at,
+                packets: connection.packets as u64,
+                protocol_version: connection.capabilities.version,
+                features: connection
+                    .capabilities
+                    .features()
+                    .into_iter()
+                    .map(String::from)
+                    .collect(),
+            })
+            .collect()
+    }
 
-                            // TODO should this run the node api on a different port so it's easier to separate public/private?
-                            // Note the API also includes receiving ILP packets sent via HTTP
-                            let api = NodeApi::new(
-                                server_secret,
-                                store.clone(),
-                                incoming_service.clone(),
-                            );
-                            let listener = TcpListener::bind(&http_address)
-                                .expect("Unable to bind to HTTP address");
-                            println!("Interledger node listening on: {}", http_address);
-                            let server = ServiceBuilder::new()
-                                .resource(api)
-                                .serve(listener.incoming());
-                            tokio::spawn(server);
-                            Ok(())
-                        },
-                    )
-                })
-        })
+    fn disconnect(&self, account_id: &str) -> bool {
+        if let Ok(account_id) = A::AccountId::from_str(account_id) {
+            self.0.disconnect(account_id)
+        } else {
+            false
+        }
+    }
 }
 
 #[doc(hidden)]
-pub use interledger_api::AccountDetails;
+pub use interledger_api::{AccountDetails, NodeConstraints};
 #[doc(hidden)]
 pub fn insert_account_redis<R>(
     redis_uri: R,
@@ -436,7 +455,7 @@ where
         .and_then(move |store| {
             store
                 .insert_account(account)
-                .map_err(|_| eprintln!("Unable to create account"))
+                .map_err(|err| eprintln!("Unable to create account: {}", err))
                 .and_then(|account| {
                     // TODO add quiet option
                     println!("Created account: {:?}", account);
@@ -444,3 +463,281 @@ where
                 })
         })
 }
+
+/// Connect to a testnet parent connector, look up the node's ILP address and asset with ILDCP,
+/// and add the parent as the node's default account (account 0). `interledger node` then
+/// connects to the parent and forwards everything it has no other route for to it.
+///
+/// If the parent's BTP URI does not include credentials, random ones are generated and saved
+/// on the account so that the node always reconnects as the same child of the parent.
+#[doc(hidden)]
+pub fn join_testnet<R>(
+    redis_uri: R,
+    parent_btp_uri: &str,
+    xrp_address: Option<String>,
+) -> impl Future<Item = (), Error = ()>
+where
+    R: IntoConnectionInfo,
+{
+    let mut parent_url = parse_btp_url(parent_btp_uri).expect("Invalid parent BTP URI");
+    if parent_url.password().is_none() {
+        parent_url.set_username(&random_token()).unwrap();
+        parent_url.set_password(Some(&random_token())).unwrap();
+    }
+    let parent_btp_uri = format!("btp+{}", parent_url);
+    let parent = AccountBuilder::new()
+        .additional_routes(&[b"peer."])
+        .btp_uri(parent_url)
+        .build();
+    let store = InMemoryStore::from_accounts(vec![parent.clone()]);
+
+    connect_client(
+        vec![parent.clone()],
+        outgoing_service_fn(|_request: OutgoingRequest<Account>| {
+            Err(RejectBuilder {
+                code: ErrorCode::F02_UNREACHABLE,
+                message: b"Not accepting packets while joining the testnet",
+                triggered_by: &[],
+                data: &[],
+            }
+            .build())
+        }),
+    )
+    .map_err(|err| eprintln!("Error connecting to the parent connector: {:?}", err))
+    .and_then(move |btp_service| {
+        let mut service = Router::new(store, ValidatorService::outgoing(btp_service.clone()));
+        get_ildcp_info(&mut service, parent).then(move |result| {
+            btp_service.close();
+            result.map_err(|_| eprintln!("Error getting the node's address from the parent"))
+        })
+    })
+    .and_then(move |info| {
+        let client_address = info.client_address().to_vec();
+        // The parent's address is the node's address without the last segment
+        let parent_address = match client_address.iter().rposition(|byte| *byte == b'.') {
+            Some(index) => client_address[..index].to_vec(),
+            None => client_address.clone(),
+        };
+        let asset_code = String::from_utf8(info.asset_code().to_vec()).unwrap_or_default();
+        let asset_scale = info.asset_scale();
+        let details = AccountDetails {
+            ilp_address: parent_address,
+            asset_code: asset_code.clone(),
+            asset_scale,
+            max_packet_amount: u64::max_value(),
+            max_packet_data: None,
+            priority: None,
+            max_value_per_second: None,
+            inactivity_timeout: None,
+            owner: None,
+            pending: false,
+            min_balance: i64::min_value(),
+            http_endpoint: None,
+            http_incoming_authorization: None,
+            http_outgoing_authorization: None,
+            btp_uri: Some(parent_btp_uri),
+            btp_incoming_authorization: None,
+            is_admin: false,
+            read_only: false,
+            xrp_address,
+            settle_threshold: None,
+            settle_to: None,
+            send_routes: false,
+            receive_routes: false,
+            routing_relation: Some("Parent".to_string()),
+        };
+        connect_redis_store(redis_uri)
+            .map_err(|err| eprintln!("Error connecting to Redis: {:?}", err))
+            .and_then(|store| {
+                store
+                    .get_accounts(vec![0])
+                    .map_err(|_| eprintln!("Error loading the default account"))
+                    .and_then(move |accounts| {
+                        if accounts[0].is_some() {
+                            eprintln!("This node already has a default account (account 0)");
+                            Either::A(err(()))
+                        } else {
+                            Either::B(store.insert_account(details).map_err(|err| {
+                                eprintln!("Unable to create the parent account: {}", err)
+                            }))
+                        }
+                    })
+            })
+            .and_then(move |_| {
+                let client_address = String::from_utf8_lossy(&client_address);
+                println!(
+                    "Joined the testnet as {} (asset: {}, scale: {})",
+                    client_address, asset_code, asset_scale
+                );
+                println!(
+                    "Start the node with: interledger node --child_address_prefix {}",
+                    client_address
+                );
+                Ok(())
+            })
+    })
+}
+
+#[doc(hidden)]
+pub use interledger_api::{records_from_js_config, AccountRecord, JsConfigError};
+/// Create the accounts through the node's account import endpoint,
+/// which either creates all of them or none if any are invalid.
+#[doc(hidden)]
+pub fn import_accounts_http(
+    node_url: &str,
+    admin_token: &str,
+    records: Vec<AccountRecord>,
+) -> impl Future<Item = (), Error = ()> {
+    let num_accounts = records.len();
+    let body: String = records
+        .iter()
+        .map(|record| serde_json::to_string(record).unwrap() + "\n")
+        .collect();
+    let url = format!("{}/accounts/import", node_url.trim_end_matches('/'));
+    let request = Request::builder()
+        .method(Method::POST)
+        .uri(url.as_str())
+        .header("Authorization", format!("Bearer {}", admin_token))
+        .header("Content-Type", "application/x-ndjson")
+        .body(Body::from(body))
+        .map_err(|err| eprintln!("Invalid node URL: {} {:?}", url, err));
+    result(request)
+        .and_then(|request| {
+            Client::new()
+                .request(request)
+                .map_err(|err| eprintln!("Error sending accounts to the node: {:?}", err))
+        })
+        .and_then(move |response| {
+            let status = response.status();
+            response
+                .into_body()
+                .concat2()
+                .map_err(|err| eprintln!("Error reading the node's response: {:?}", err))
+                .and_then(move |body| {
+                    if status.is_success() {
+                        println!("Imported {} accounts", num_accounts);
+                        Ok(())
+                    } else {
+                        eprintln!(
+                            "Node rejected the accounts ({}): {}",
+                            status,
+                            String::from_utf8_lossy(&body)
+                        );
+                        Err(())
+                    }
+                })
+        })
+}
+
+/// Run two nodes in this process and send a payment from a sender on the first
+/// node to an SPSP receiver on the second, printing each packet on the way.
+///
+/// Node A holds the sender's account and is peered with node B over ILP-over-HTTP.
+/// Node B only runs a STREAM receiver. Both use in-memory stores, so nothing
+/// (not even Redis or moneyd) needs to be running beforehand.
+#[doc(hidden)]
+pub fn run_demo(
+    amount: u64,
+    node_a_port: u16,
+    node_b_port: u16,
+) -> impl Future<Item = (), Error = ()> {
+    let node_a_address: SocketAddr = ([127, 0, 0, 1], node_a_port).into();
+    let node_b_address: SocketAddr = ([127, 0, 0, 1], node_b_port).into();
+    let peering_token = random_token();
+    let sender_token = random_token();
+
+    let node_b_info = IldcpResponseBuilder {
+        client_address: b"example.node_b",
+        asset_code: "XYZ",
+        asset_scale: 9,
+    }
+    .build();
+    println!("Starting node B (example.node_b) on {}", node_b_address);
+    tokio::spawn(run_spsp_server_http(
+        node_b_info,
+        node_b_address,
+        peering_token.clone(),
+        true,
+    ));
+
+    let sender = AccountBuilder::new()
+        .id(0)
+        .ilp_address(b"example.node_a.sender")
+        .asset_code("XYZ".to_string())
+        .asset_scale(9)
+        .http_incoming_authorization(format!("Bearer {}", sender_token))
+        .build();
+    // Node A forwards everything that is not for its own accounts to node B
+    let node_b = AccountBuilder::new()
+        .id(1)
+        .ilp_address(b"example.node_b")
+        .asset_code("XYZ".to_string())
+        .asset_scale(9)
+        .additional_routes(&[&b""[..]])
+        .http_endpoint(Url::parse(&format!("http://{}/ilp", node_b_address)).unwrap())
+        .http_outgoing_authorization(format!("Bearer {}", peering_token))
+        .build();
+    let store = InMemoryStore::from_accounts(vec![sender, node_b]);
+    let outgoing_service = ProgressService {
+        packets: Arc::new(AtomicUsize::new(0)),
+        next: ValidatorService::outgoing(HttpClientService::new(store.clone())),
+    };
+    let incoming_service = Router::new(store.clone(), outgoing_service);
+    let incoming_service = IldcpService::new(incoming_service);
+    let incoming_service = ValidatorService::incoming(incoming_service);
+    let http_service = HttpServerService::new(incoming_service, store);
+    println!("Starting node A (example.node_a) on {}", node_a_address);
+    tokio::spawn(
+        Server::bind(&node_a_address)
+            .serve(move || {
+                let mut http_service = http_service.clone();
+                service_fn(move |req: Request<Body>| http_service.call(req))
+            })
+            .map_err(|err| eprintln!("Node A server error: {:?}", err)),
+    );
+
+    println!(
+        "Sending {} from example.node_a.sender to the SPSP receiver on node B",
+        amount
+    );
+    send_spsp_payment_http(
+        &format!("http://:{}@{}/ilp", sender_token, node_a_address),
+        &format!("http://{}/spsp", node_b_address),
+        amount,
+        false,
+    )
+    .map(|_amount_delivered| ())
+    .map_err(|err| eprintln!("Error sending the demo payment: {}", err))
+}
+
+/// Prints every packet node A forwards to node B in the demo, so the
+/// payment can be seen being split up and streamed.
+#[derive(Clone)]
+struct ProgressService<S> {
+    packets: Arc<AtomicUsize>,
+    next: S,
+}
+
+impl<S> OutgoingService<Account> for ProgressService<S>
+where
+    S: OutgoingService<Account>,
+{
+    type Future = BoxedIlpFuture;
+
+    fn send_request(&mut self, request: OutgoingRequest<Account>) -> Self::Future {
+        let packet = self.packets.fetch_add(1, Ordering::SeqCst) + 1;
+        let amount = request.prepare.amount();
+        Box::new(self.next.send_request(request).then(move |result| {
+            match result {
+                Ok(_) => println!("Packet {}: {} fulfilled by node B", packet, amount),
+                Err(ref reject) => println!(
+                    "Packet {}: {} rejected with {}",
+                    packet,
+                    amount,
+                    reject.code()
+                ),
+            }
+            result
+        }))
+    }
+}
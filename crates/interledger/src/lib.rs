@@ -1,10 +1,22 @@
 //! # Interledger.rs
 //!
 //! A CLI and library bundle for the Rust implementation of the Interledger Protocol stack.
+//!
+//! All of the components are behind feature flags. The default `cli` feature builds the
+//! full node and command-line tool; library consumers that only need some of the protocols
+//! can disable the default features, for example:
+//!
+//! ```toml
+//! [dependencies]
+//! interledger = { version = "0.4.0", default-features = false, features = ["stream"] }
+//! ```
+//!
+//! The `packet` and `service` modules are always available and only depend on `bytes`
+//! and `futures`.
 
+#[cfg(feature = "cli")]
 #[macro_use]
 extern crate log;
-#[cfg(feature = "cli")]
 
 /// ILP Packet (De)Serialization
 pub mod packet {
@@ -20,6 +32,35 @@ pub mod service {
 #[cfg(feature = "cli")]
 pub mod cli;
 
+/// Typed configuration for the node, which can be loaded from a file and the environment
+#[cfg(feature = "cli")]
+pub mod config {
+    pub use interledger_config::*;
+}
+
+/// Text and JSON log output for the CLI and node
+#[cfg(feature = "cli")]
+pub mod logging;
+
+/// Run the full node inside another application
+#[cfg(feature = "cli")]
+pub mod node;
+
+/// Reverse proxy that requires requests to an HTTP service to be paid for with STREAM
+#[cfg(feature = "cli")]
+pub mod paid_proxy;
+
+/// Checks that the node's store and accounts are usable before it starts
+#[cfg(feature = "cli")]
+pub mod preflight;
+
+/// Serves the node API over TLS, reloading certificates when they change
+#[cfg(feature = "cli")]
+pub mod tls;
+
+#[cfg(feature = "acme")]
+mod acme;
+
 /// Bilateral Transport Protocol (BTP) client and server
 #[cfg(feature = "btp")]
 pub mod btp {
@@ -3,17 +3,27 @@ extern crate interledger;
 extern crate clap;
 
 use base64;
-use clap::{App, Arg, ArgGroup, SubCommand};
+use clap::{App, Arg, ArgGroup, ArgMatches, SubCommand};
+use futures::Future;
 use hex;
-use interledger::cli::*;
+use interledger::{
+    cli::*,
+    config::{
+        AcmeConfig, CertificateConfig, ClockConfig, ConfigError, IpNetwork, NodeConfig,
+        ReplicaConfig, SettlementConfig, TlsConfig,
+    },
+    logging::{init_logger, LogFormat},
+    node::NodeBuilder,
+    packet::redact::set_developer_mode,
+    paid_proxy::PaidProxyBuilder,
+};
 use interledger_ildcp::IldcpResponseBuilder;
-use tokio;
+use std::{env, fs, process, str::FromStr, time::Duration};
+use tokio::{self, runtime::Runtime};
 use url::Url;
 
 #[allow(clippy::cyclomatic_complexity)]
 pub fn main() {
-    env_logger::init();
-
     let moneyd_uri = format!(
         "btp+ws://{}:{}@localhost:7768",
         random_token(),
@@ -21,6 +31,18 @@ pub fn main() {
     );
     let mut app = App::new("interledger")
         .about("Blazing fast Interledger CLI written in Rust")
+        .arg(
+            Arg::with_name("developer_mode")
+                .long("developer_mode")
+                .help("Include secrets such as auth tokens and fulfillments in the logs (only use this locally)"),
+        )
+        .arg(
+            Arg::with_name("log_format")
+                .long("log_format")
+                .takes_value(true)
+                .possible_values(&["text", "json"])
+                .help("Write logs as text or as JSON lines (defaults to the ILP_LOG_FORMAT environment variable, or text)"),
+        )
         .subcommands(vec![
             SubCommand::with_name("spsp")
                 .about("Client and Server for the Simple Payment Setup Protocol (SPSP)")
@@ -103,12 +125,99 @@ pub fn main() {
                                 .default_value("9"),
                         ])
                     ),
+                SubCommand::with_name("demo")
+                    .about("Run two nodes in this process and stream a payment between them, printing each packet")
+                    .args(&[
+                        Arg::with_name("amount")
+                            .long("amount")
+                            .help("Amount to send, in units of the nodes' asset (XYZ with scale 9)")
+                            .default_value("1000000"),
+                        Arg::with_name("node_a_port")
+                            .long("node_a_port")
+                            .help("Port of the sender's node")
+                            .default_value("7780"),
+                        Arg::with_name("node_b_port")
+                            .long("node_b_port")
+                            .help("Port of the receiver's node")
+                            .default_value("7781"),
+                    ]),
+                SubCommand::with_name("paid-proxy")
+                    .about("Proxy requests to an HTTP service once they have been paid for with STREAM (receives ILP packets over HTTP on /ilp)")
+                    .args(&[
+                        Arg::with_name("upstream")
+                            .long("upstream")
+                            .help("URL of the HTTP service to proxy requests to")
+                            .takes_value(true)
+                            .required(true),
+                        Arg::with_name("port")
+                            .long("port")
+                            .short("p")
+                            .default_value("8080"),
+                        Arg::with_name("ilp_address")
+                            .long("ilp_address")
+                            .help("The proxy's ILP address, which the node sending it packets routes to it")
+                            .takes_value(true)
+                            .required(true),
+                        Arg::with_name("asset_code")
+                            .long("asset_code")
+                            .takes_value(true)
+                            .required(true),
+                        Arg::with_name("asset_scale")
+                            .long("asset_scale")
+                            .default_value("9"),
+                        Arg::with_name("incoming_auth_token")
+                            .long("incoming_auth_token")
+                            .help("Bearer token the node must use to send ILP packets to the proxy")
+                            .takes_value(true)
+                            .required(true),
+                        Arg::with_name("price")
+                            .long("price")
+                            .help("Price of each request, in units of the proxy's asset")
+                            .default_value("0"),
+                        Arg::with_name("path_price")
+                            .long("path_price")
+                            .help("Price of requests to paths starting with a prefix, as PREFIX=PRICE (can be repeated)")
+                            .takes_value(true)
+                            .multiple(true)
+                            .number_of_values(1),
+                    ]),
+                SubCommand::with_name("testnet")
+                    .about("Connect a node to the Interledger testnet")
+                    .subcommand(SubCommand::with_name("join")
+                        .about("Add a testnet connector as the node's parent (default) account, using ILDCP to get the node's address and asset")
+                        .args(&[
+                            Arg::with_name("redis_uri")
+                                .long("redis_uri")
+                                .help("Redis database of the node that should join the testnet")
+                                .default_value("redis://127.0.0.1:6379"),
+                            Arg::with_name("parent")
+                                .long("parent")
+                                .help("BTP URI of the testnet connector to use as the parent (random credentials are generated if it does not include any)")
+                                .takes_value(true)
+                                .required(true),
+                            Arg::with_name("xrp_address")
+                                .long("xrp_address")
+                                .help("XRP testnet address to associate with the parent account for settlement")
+                                .takes_value(true),
+                        ])),
                 SubCommand::with_name("node")
                     .about("Run an Interledger node (sender, connector, receiver bundle)")
                     .args(&[
+                        Arg::with_name("config")
+                            .long("config")
+                            .help("JSON file to read the node's settings from. Settings can be overridden with ILP_-prefixed environment variables, such as ILP_BTP_ADDRESS, and with the other arguments")
+                            .takes_value(true),
                         Arg::with_name("redis_uri")
                             .long("redis_uri")
                             .default_value("redis://127.0.0.1:6379"),
+                        Arg::with_name("redis_replica_uri")
+                            .long("redis_replica_uri")
+                            .help("Replica of the Redis database to look up accounts and poll for routes and rates from, while writes go to redis_uri")
+                            .takes_value(true),
+                        Arg::with_name("replica_max_staleness")
+                            .long("replica_max_staleness")
+                            .help("Only read from the replica while it is at most this many milliseconds behind the primary")
+                            .default_value("1000"),
                         Arg::with_name("btp_port")
                             .long("btp_port")
                             .default_value("7768"),
@@ -119,8 +228,123 @@ pub fn main() {
                             .long("server_secret")
                             .help("Cryptographic seed used to derive keys for STREAM, specified in hex")
                             .takes_value(true),
+                        Arg::with_name("allowed_asset_codes")
+                            .long("allowed_asset_codes")
+                            .help("Comma-separated list of the only asset codes accounts may be created with")
+                            .takes_value(true),
+                        Arg::with_name("max_asset_scale")
+                            .long("max_asset_scale")
+                            .help("Largest asset scale accounts may be created with")
+                            .takes_value(true),
+                        Arg::with_name("child_address_prefix")
+                            .long("child_address_prefix")
+                            .help("ILP address prefix that the addresses of all child accounts must start with")
+                            .takes_value(true),
+                        Arg::with_name("allowed_endpoint_networks")
+                            .long("allowed_endpoint_networks")
+                            .help("Comma-separated CIDR ranges that accounts' HTTP and BTP URLs must resolve to (loopback addresses are denied unless they are listed)")
+                            .takes_value(true),
+                        Arg::with_name("denied_endpoint_networks")
+                            .long("denied_endpoint_networks")
+                            .help("Comma-separated CIDR ranges that accounts' HTTP and BTP URLs must not resolve to (link-local addresses are always denied)")
+                            .takes_value(true),
+                        Arg::with_name("settlement_engine_url")
+                            .long("settlement_engine_url")
+                            .help("URL of this node's settlement engine, which is sent to peers in peer.settle messages")
+                            .takes_value(true),
+                        Arg::with_name("settlement_ledger")
+                            .long("settlement_ledger")
+                            .help("Ledger this node settles with its peers on, such as XRP")
+                            .takes_value(true),
+                        Arg::with_name("settlement_ledger_address")
+                            .long("settlement_ledger_address")
+                            .help("This node's address on the settlement ledger")
+                            .takes_value(true),
+                        Arg::with_name("balance_snapshot_interval")
+                            .long("balance_snapshot_interval")
+                            .help("How often to record account balances for the balance history, in seconds")
+                            .default_value("300"),
+                        Arg::with_name("public_url")
+                            .long("public_url")
+                            .help("URL the node API is publicly reachable at, which payment pointers hosted on other domains redirect to")
+                            .takes_value(true),
+                        Arg::with_name("payment_webhook")
+                            .long("payment_webhook")
+                            .help("URL to POST incoming payments to when they are completed")
+                            .takes_value(true),
+                        Arg::with_name("scheduled_payment_webhook")
+                            .long("scheduled_payment_webhook")
+                            .help("URL to POST scheduled outgoing payments to after each attempt to send them")
+                            .takes_value(true),
+                        Arg::with_name("payment_link_webhook")
+                            .long("payment_link_webhook")
+                            .help("URL to POST payment links to when they are paid in full, with the metadata the sender attached to the payment")
+                            .takes_value(true),
+                        Arg::with_name("min_incoming_packet_amount")
+                            .long("min_incoming_packet_amount")
+                            .help("Reject STREAM packets for local accounts that carry less than this amount, in the units of the account the packet arrived from")
+                            .default_value("0"),
+                        Arg::with_name("leader_lease")
+                            .long("leader_lease")
+                            .help("Elect one of the nodes sharing the Redis database to broadcast routes and run background tasks, holding the lease for this many milliseconds")
+                            .takes_value(true),
+                        Arg::with_name("max_in_flight_packets")
+                            .long("max_in_flight_packets")
+                            .help("Process at most this many incoming packets at a time, queueing the rest by their account's priority")
+                            .default_value("10000"),
+                        Arg::with_name("packet_shards")
+                            .long("packet_shards")
+                            .help("Process incoming packets on this many worker threads, each handling a fixed subset of the accounts")
+                            .default_value("0"),
+                        Arg::with_name("self_test_amount")
+                            .long("self_test_amount")
+                            .help("When starting, send a STREAM payment of this amount from the default account to itself and exit if it is not delivered")
+                            .takes_value(true),
+                        Arg::with_name("accept_peering_requests")
+                            .long("accept_peering_requests")
+                            .help("Let anyone request to peer with the node through the API. Their accounts cannot send or receive packets until an admin approves them"),
+                        Arg::with_name("read_only")
+                            .long("read_only")
+                            .help("Reject every API request that would change anything, for example on a standby instance. This includes ILP packets sent over HTTP"),
+                        Arg::with_name("ntp_server")
+                            .long("ntp_server")
+                            .help("Check the clock against this NTP server (host:port) when starting and every hour, warning if it is skewed")
+                            .takes_value(true),
+                        Arg::with_name("max_clock_skew")
+                            .long("max_clock_skew")
+                            .help("Warn if the clock is off from the NTP server by more than this many milliseconds (checks the clock against pool.ntp.org:123 unless --ntp_server is set)")
+                            .takes_value(true),
+                        Arg::with_name("compensate_clock_skew")
+                            .long("compensate_clock_skew")
+                            .help("Correct for the measured clock skew, by up to a minute, when checking packets' expiries (checks the clock against pool.ntp.org:123 unless --ntp_server is set)"),
+                        Arg::with_name("route_aggregation_threshold")
+                            .long("route_aggregation_threshold")
+                            .help("Advertise sibling routes to peers as their parent prefix when there are at least this many of them going through the same account")
+                            .takes_value(true),
+                        Arg::with_name("tls_cert")
+                            .long("tls_cert")
+                            .help("PEM certificate chain to serve the node API over TLS with. It is reloaded when the file changes")
+                            .takes_value(true)
+                            .requires("tls_key"),
+                        Arg::with_name("tls_key")
+                            .long("tls_key")
+                            .help("PEM private key of the TLS certificate")
+                            .takes_value(true)
+                            .requires("tls_cert"),
+                        Arg::with_name("acme_domains")
+                            .long("acme_domains")
+                            .help("Comma-separated list of domains to obtain a TLS certificate for from Let's Encrypt, answering its challenges on port 80 (requires the acme feature)")
+                            .takes_value(true)
+                            .requires("acme_email"),
+                        Arg::with_name("acme_email")
+                            .long("acme_email")
+                            .help("Contact email address for the Let's Encrypt account")
+                            .takes_value(true)
+                            .requires("acme_domains"),
                     ])
                     .group(ArgGroup::with_name("redis_connector").requires_all(&["redis_uri", "btp_port", "http_port"]))
+                    .subcommand(SubCommand::with_name("check-config")
+                        .about("Check the node's configuration, the Redis database and the accounts' endpoints without starting the node"))
                     .subcommand(SubCommand::with_name("accounts")
                         .subcommand(SubCommand::with_name("add")
                         .args(&[
@@ -162,6 +386,9 @@ pub fn main() {
                             Arg::with_name("admin")
                                 .long("admin")
                                 .help("Flag to indicate the account is an administrator (and can add, modify, delete other accounts and change configuration)"),
+                            Arg::with_name("read_only")
+                                .long("read_only")
+                                .help("Flag to indicate the account can only view data through the API and cannot send packets over HTTP, for example for dashboards and auditors (combine with --admin to let it view every account)"),
                             Arg::with_name("xrp_address")
                                 .long("xrp_address")
                                 .help("XRP address to associate with this account for settlement")
@@ -188,11 +415,68 @@ pub fn main() {
                                 .long("min_balance")
                                 .help("Minimum balance this account is allowed to have (can be negative)")
                                 .default_value("0"),
+                            Arg::with_name("max_packet_data")
+                                .long("max_packet_data")
+                                .takes_value(true)
+                                .help("Largest data field (in bytes) of the ILP packets this account may send or be sent"),
+                            Arg::with_name("priority")
+                                .long("priority")
+                                .takes_value(true)
+                                .possible_values(&["high", "normal", "low"])
+                                .help("Priority class of the packets from this account when the node is busy"),
+                            Arg::with_name("max_value_per_second")
+                                .long("max_value_per_second")
+                                .takes_value(true)
+                                .help("Delay the packets sent to and received from this account to keep them within this average value per second"),
+                            Arg::with_name("inactivity_timeout")
+                                .long("inactivity_timeout")
+                                .takes_value(true)
+                                .help("Delete this account after this many seconds without any packets, if its balance is zero"),
+                            Arg::with_name("owner")
+                                .long("owner")
+                                .takes_value(true)
+                                .help("The customer or other owner this account belongs to, whose accounts' balances can be viewed together"),
                         ])
-                        .group(ArgGroup::with_name("account_admin").arg("admin").requires("http_incoming_token")))),
+                        .group(ArgGroup::with_name("account_admin").arg("admin").requires("http_incoming_token")))
+                        .subcommand(SubCommand::with_name("import")
+                        .about("Create the accounts from a moneyd or ilp-connector JSON config through the node's API")
+                        .args(&[
+                            Arg::with_name("config")
+                                .long("config")
+                                .help("Path to the moneyd config (~/.moneyd.json) or the ilp-connector config with an \"accounts\" field")
+                                .takes_value(true)
+                                .required(true),
+                            Arg::with_name("node_url")
+                                .long("node_url")
+                                .help("URL of the node's HTTP API")
+                                .default_value("http://localhost:7770"),
+                            Arg::with_name("admin_token")
+                                .long("admin_token")
+                                .help("HTTP token of an admin account on the node")
+                                .takes_value(true)
+                                .required(true),
+                            Arg::with_name("ilp_address")
+                                .long("ilp_address")
+                                .help("Prefix for the addresses of accounts that do not configure their own (defaults to the connector's ilpAddress)")
+                                .takes_value(true),
+                        ]))),
         ]);
 
-    match app.clone().get_matches().subcommand() {
+    let matches = app.clone().get_matches();
+    let log_format = matches
+        .value_of("log_format")
+        .map(str::to_string)
+        .or_else(|| env::var("ILP_LOG_FORMAT").ok())
+        .map(|format| {
+            LogFormat::from_str(&format).unwrap_or_else(|err| {
+                eprintln!("{}", err);
+                process::exit(1)
+            })
+        })
+        .unwrap_or_default();
+    init_logger(log_format);
+    set_developer_mode(matches.is_present("developer_mode"));
+    match matches.subcommand() {
         ("spsp", Some(matches)) => match matches.subcommand() {
             ("server", Some(matches)) => {
                 let port = value_t!(matches, "port", u16).expect("Invalid port");
@@ -229,18 +513,23 @@ pub fn main() {
                 let amount = value_t!(matches, "amount", u64).expect("Invalid amount");
                 let quiet = matches.is_present("quiet");
 
+                let mut runtime = Runtime::new().unwrap();
                 // Check for http_server first because btp_server has the default value of connecting to moneyd
-                if let Ok(http_server) = value_t!(matches, "http_server", String) {
-                    tokio::run(send_spsp_payment_http(
+                let paid = if let Ok(http_server) = value_t!(matches, "http_server", String) {
+                    runtime.block_on(send_spsp_payment_http(
                         &http_server,
                         &receiver,
                         amount,
                         quiet,
-                    ));
+                    ))
                 } else if let Ok(btp_server) = value_t!(matches, "btp_server", String) {
-                    tokio::run(send_spsp_payment_btp(&btp_server, &receiver, amount, quiet));
+                    runtime.block_on(send_spsp_payment_btp(&btp_server, &receiver, amount, quiet))
                 } else {
                     panic!("Must specify either btp_server or http_server");
+                };
+                if let Err(err) = paid {
+                    eprintln!("{}", err);
+                    process::exit(1);
                 }
             }
             _ => app.print_help().unwrap(),
@@ -267,6 +556,73 @@ pub fn main() {
             }
             _ => app.print_help().unwrap(),
         },
+        ("demo", Some(matches)) => {
+            let amount = value_t!(matches, "amount", u64).expect("amount must be a number");
+            let node_a_port = value_t!(matches, "node_a_port", u16).expect("Invalid port");
+            let node_b_port = value_t!(matches, "node_b_port", u16).expect("Invalid port");
+            // The nodes keep running in the background, so use a runtime that is
+            // dropped once the payment is done instead of tokio::run
+            let mut runtime = Runtime::new().unwrap();
+            let sent = runtime.block_on(run_demo(amount, node_a_port, node_b_port));
+            if sent.is_err() {
+                process::exit(1);
+            }
+        }
+        ("paid-proxy", Some(matches)) => {
+            let upstream = value_t!(matches, "upstream", String).expect("upstream is required");
+            let upstream = Url::parse(&upstream).expect("upstream is not a valid URL");
+            let port = value_t!(matches, "port", u16).expect("Invalid port");
+            let ilp_address =
+                value_t!(matches, "ilp_address", String).expect("ilp_address is required");
+            let asset_code =
+                value_t!(matches, "asset_code", String).expect("asset_code is required");
+            let asset_scale = value_t!(matches, "asset_scale", u8).expect("Invalid asset_scale");
+            let auth_token = value_t!(matches, "incoming_auth_token", String)
+                .expect("incoming_auth_token is required");
+            let price = value_t!(matches, "price", u64).expect("Invalid price");
+            let ildcp_info = IldcpResponseBuilder {
+                client_address: ilp_address.as_bytes(),
+                asset_code: &asset_code,
+                asset_scale,
+            }
+            .build();
+            println!("Proxying paid requests to {} on port {}", upstream, port);
+            let mut proxy = PaidProxyBuilder::new(upstream, ildcp_info, auth_token)
+                .address(([127, 0, 0, 1], port).into())
+                .price(price);
+            for path_price in matches.values_of("path_price").into_iter().flatten() {
+                let mut parts = path_price.rsplitn(2, '=');
+                let price = parts
+                    .next()
+                    .and_then(|price| price.parse::<u64>().ok())
+                    .expect("path_price must be in the form PREFIX=PRICE");
+                let prefix = parts
+                    .next()
+                    .expect("path_price must be in the form PREFIX=PRICE");
+                proxy = proxy.path_price(prefix.to_string(), price);
+            }
+            tokio::run(proxy.run());
+        }
+        ("testnet", Some(matches)) => match matches.subcommand() {
+            ("join", Some(matches)) => {
+                let redis_uri =
+                    value_t!(matches, "redis_uri", String).expect("redis_uri is required");
+                let redis_uri = Url::parse(&redis_uri).expect("redis_uri is not a valid URI");
+                let parent = value_t!(matches, "parent", String).expect("parent is required");
+                // Use a runtime that is dropped when we are done, because the BTP connection
+                // to the parent would otherwise keep tokio::run from returning
+                let mut runtime = Runtime::new().unwrap();
+                let joined = runtime.block_on(join_testnet(
+                    redis_uri,
+                    &parent,
+                    matches.value_of("xrp_address").map(|s| s.to_string()),
+                ));
+                if joined.is_err() {
+                    process::exit(1);
+                }
+            }
+            _ => app.print_help().unwrap(),
+        },
         ("node", Some(matches)) => match matches.subcommand() {
             ("accounts", Some(matches)) => match matches.subcommand() {
                 ("add", Some(matches)) => {
@@ -311,8 +667,15 @@ pub fn main() {
                         http_outgoing_authorization,
                         http_endpoint,
                         max_packet_amount: u64::max_value(),
+                        max_packet_data: value_t!(matches, "max_packet_data", u16).ok(),
+                        priority: matches.value_of("priority").map(|s| s.to_string()),
+                        max_value_per_second: value_t!(matches, "max_value_per_second", u64).ok(),
+                        inactivity_timeout: value_t!(matches, "inactivity_timeout", u64).ok(),
+                        owner: matches.value_of("owner").map(|s| s.to_string()),
+                        pending: false,
                         min_balance: value_t!(matches, "min_balance", i64).unwrap(),
                         is_admin: matches.is_present("admin"),
+                        read_only: matches.is_present("read_only"),
                         xrp_address: value_t!(matches, "xrp_address", String).ok(),
                         settle_threshold: value_t!(matches, "settle_threshold", i64).ok(),
                         settle_to: value_t!(matches, "settle_to", i64).ok(),
@@ -322,33 +685,272 @@ pub fn main() {
                     };
                     tokio::run(insert_account_redis(redis_uri, account));
                 }
+                ("import", Some(matches)) => {
+                    let path = matches.value_of("config").unwrap();
+                    let config = fs::read_to_string(path).unwrap_or_else(|err| {
+                        eprintln!("Unable to read config file {}: {}", path, err);
+                        process::exit(1);
+                    });
+                    match records_from_js_config(&config, matches.value_of("ilp_address")) {
+                        Ok(records) => {
+                            let mut runtime = Runtime::new().unwrap();
+                            let imported = runtime.block_on(import_accounts_http(
+                                matches.value_of("node_url").unwrap(),
+                                matches.value_of("admin_token").unwrap(),
+                                records,
+                            ));
+                            if imported.is_err() {
+                                process::exit(1);
+                            }
+                        }
+                        Err(errors) => {
+                            for error in errors {
+                                eprintln!(
+                                    "Unable to import account {}: {}",
+                                    error.account, error.message
+                                );
+                            }
+                            process::exit(1);
+                        }
+                    }
+                }
                 _ => app.print_help().unwrap(),
             },
-            _ => {
-                let redis_uri =
-                    value_t!(matches, "redis_uri", String).expect("redis_uri is required");
-                let redis_uri = Url::parse(&redis_uri).expect("redis_uri is not a valid URI");
-                let btp_port = value_t!(matches, "btp_port", u16).expect("btp_port is required");
-                let http_port = value_t!(matches, "http_port", u16).expect("http_port is required");
-                let server_secret: [u8; 32] = if let Some(secret) =
-                    matches.value_of("server_secret")
-                {
-                    let mut server_secret = [0; 32];
-                    let decoded = hex::decode(secret).expect("server_secret must be hex-encoded");
-                    assert_eq!(decoded.len(), 32, "server_secret must be 32 bytes");
-                    server_secret.clone_from_slice(&decoded);
-                    server_secret
-                } else {
-                    random_secret()
-                };
-                tokio::run(run_node_redis(
-                    redis_uri,
-                    ([0, 0, 0, 0], btp_port).into(),
-                    ([0, 0, 0, 0], http_port).into(),
-                    &server_secret,
-                ));
+            ("check-config", Some(_)) => {
+                let mut runtime = Runtime::new().unwrap();
+                if let Err(errors) = runtime.block_on(node_builder(matches).check()) {
+                    for error in errors {
+                        eprintln!("{}", error);
+                    }
+                    process::exit(1);
+                }
+                println!("The configuration is valid and the node is ready to start");
             }
+            _ => tokio::run(node_builder(matches).run().map(|_node| ())),
         },
         _ => app.print_help().unwrap(),
     }
 }
+
+// Settings are read from the config file (or the arguments' defaults if there is none),
+// then the environment, then the arguments that were passed explicitly
+fn node_builder(matches: &ArgMatches) -> NodeBuilder<Url> {
+    let config = match matches.value_of("config") {
+        Some(path) => {
+            NodeConfig::from_file(path).unwrap_or_else(|error| exit_with_errors(&[error]))
+        }
+        None => apply_node_args(NodeConfig::default(), matches, false),
+    };
+    let config = config
+        .with_env_overrides()
+        .unwrap_or_else(|errors| exit_with_errors(&errors));
+    NodeBuilder::from_config(apply_node_args(config, matches, true))
+}
+
+fn apply_node_args(
+    mut config: NodeConfig,
+    matches: &ArgMatches,
+    explicit_only: bool,
+) -> NodeConfig {
+    let arg = |name: &str| {
+        if explicit_only && matches.occurrences_of(name) == 0 {
+            None
+        } else {
+            matches.value_of(name)
+        }
+    };
+    if let Some(redis_uri) = arg("redis_uri") {
+        config.redis_uri = Url::parse(redis_uri).expect("redis_uri is not a valid URI");
+    }
+    if let Some(replica_uri) = arg("redis_replica_uri") {
+        config.read_replica = Some(ReplicaConfig::new(
+            Url::parse(replica_uri).expect("redis_replica_uri is not a valid URI"),
+        ));
+    }
+    if let (Some(max_staleness), Some(replica)) =
+        (arg("replica_max_staleness"), config.read_replica.as_mut())
+    {
+        replica.max_staleness = Duration::from_millis(
+            max_staleness
+                .parse()
+                .expect("replica_max_staleness must be a number of milliseconds"),
+        );
+    }
+    if let Some(btp_port) = arg("btp_port") {
+        let btp_port: u16 = btp_port.parse().expect("btp_port must be a port number");
+        config.btp_address = ([0, 0, 0, 0], btp_port).into();
+    }
+    if let Some(http_port) = arg("http_port") {
+        let http_port: u16 = http_port.parse().expect("http_port must be a port number");
+        config.http_address = Some(([0, 0, 0, 0], http_port).into());
+    }
+    if let Some(secret) = arg("server_secret") {
+        let mut server_secret = [0; 32];
+        let decoded = hex::decode(secret).expect("server_secret must be hex-encoded");
+        assert_eq!(decoded.len(), 32, "server_secret must be 32 bytes");
+        server_secret.clone_from_slice(&decoded);
+        config.server_secret = Some(server_secret);
+    }
+    if let Some(codes) = arg("allowed_asset_codes") {
+        config.constraints.allowed_asset_codes = Some(
+            codes
+                .split(',')
+                .map(|code| code.trim().to_string())
+                .collect(),
+        );
+    }
+    if let Some(scale) = arg("max_asset_scale") {
+        config.constraints.max_asset_scale = Some(
+            scale
+                .parse()
+                .expect("max_asset_scale must be a number from 0-255"),
+        );
+    }
+    if let Some(prefix) = arg("child_address_prefix") {
+        config.constraints.child_address_prefix = Some(prefix.to_string());
+    }
+    if let Some(networks) = arg("allowed_endpoint_networks") {
+        config.constraints.allowed_endpoint_networks = Some(parse_networks(networks));
+    }
+    if let Some(networks) = arg("denied_endpoint_networks") {
+        config.constraints.denied_endpoint_networks = parse_networks(networks);
+    }
+    if let Some(url) = arg("settlement_engine_url") {
+        config
+            .settlement
+            .get_or_insert_with(SettlementConfig::default)
+            .engine_url = Some(Url::parse(url).expect("Invalid settlement_engine_url"));
+    }
+    if let Some(ledger) = arg("settlement_ledger") {
+        config
+            .settlement
+            .get_or_insert_with(SettlementConfig::default)
+            .ledger = Some(ledger.to_string());
+    }
+    if let Some(address) = arg("settlement_ledger_address") {
+        config
+            .settlement
+            .get_or_insert_with(SettlementConfig::default)
+            .ledger_address = Some(address.to_string());
+    }
+    if let Some(interval) = arg("balance_snapshot_interval") {
+        config.balance_snapshot_interval = Duration::from_secs(
+            interval
+                .parse()
+                .expect("balance_snapshot_interval must be a number of seconds"),
+        );
+    }
+    if let Some(public_url) = arg("public_url") {
+        config.public_url = Some(Url::parse(public_url).expect("Invalid public_url"));
+    }
+    if let Some(webhook) = arg("payment_webhook") {
+        config.payment_webhook = Some(Url::parse(webhook).expect("Invalid payment_webhook URL"));
+    }
+    if let Some(webhook) = arg("scheduled_payment_webhook") {
+        config.scheduled_payment_webhook =
+            Some(Url::parse(webhook).expect("Invalid scheduled_payment_webhook URL"));
+    }
+    if let Some(webhook) = arg("payment_link_webhook") {
+        config.payment_link_webhook =
+            Some(Url::parse(webhook).expect("Invalid payment_link_webhook URL"));
+    }
+    if let Some(amount) = arg("min_incoming_packet_amount") {
+        config.min_incoming_packet_amount = amount
+            .parse()
+            .expect("min_incoming_packet_amount must be a number");
+    }
+    if let Some(lease) = arg("leader_lease") {
+        config.store.leader_lease = Some(Duration::from_millis(
+            lease
+                .parse()
+                .expect("leader_lease must be a number of milliseconds"),
+        ));
+    }
+    if let Some(max_in_flight_packets) = arg("max_in_flight_packets") {
+        config.max_in_flight_packets = max_in_flight_packets
+            .parse()
+            .expect("max_in_flight_packets must be a number");
+    }
+    if let Some(packet_shards) = arg("packet_shards") {
+        config.packet_shards = packet_shards
+            .parse()
+            .expect("packet_shards must be a number");
+    }
+    if let Some(amount) = arg("self_test_amount") {
+        config.self_test_amount = Some(amount.parse().expect("self_test_amount must be a number"));
+    }
+    if matches.is_present("accept_peering_requests") {
+        config.accept_peering_requests = true;
+    }
+    if matches.is_present("read_only") {
+        config.read_only = true;
+    }
+    // Any of the clock settings turns the clock checks on, like the environment variables
+    if let Some(server) = arg("ntp_server") {
+        config
+            .clock
+            .get_or_insert_with(ClockConfig::default)
+            .ntp_server = server.to_string();
+    }
+    if let Some(max_skew) = arg("max_clock_skew") {
+        let max_skew = max_skew.parse().unwrap_or_else(|_| {
+            eprintln!("max_clock_skew must be a number of milliseconds");
+            process::exit(1)
+        });
+        config
+            .clock
+            .get_or_insert_with(ClockConfig::default)
+            .max_skew = Duration::from_millis(max_skew);
+    }
+    if matches.is_present("compensate_clock_skew") {
+        config
+            .clock
+            .get_or_insert_with(ClockConfig::default)
+            .compensate = true;
+    }
+    if let Some(threshold) = arg("route_aggregation_threshold") {
+        config.route_aggregation_threshold = Some(
+            threshold
+                .parse()
+                .expect("route_aggregation_threshold must be a number"),
+        );
+    }
+    if let (Some(cert_path), Some(key_path)) = (arg("tls_cert"), arg("tls_key")) {
+        config
+            .tls
+            .get_or_insert_with(TlsConfig::default)
+            .certificates = vec![CertificateConfig {
+            domains: Vec::new(),
+            cert_path: cert_path.into(),
+            key_path: key_path.into(),
+        }];
+    }
+    if let (Some(domains), Some(email)) = (arg("acme_domains"), arg("acme_email")) {
+        let domains = domains
+            .split(',')
+            .map(|domain| domain.trim().to_string())
+            .collect();
+        config.tls.get_or_insert_with(TlsConfig::default).acme =
+            Some(AcmeConfig::new(domains, email.to_string()));
+    }
+    config
+}
+
+fn parse_networks(networks: &str) -> Vec<IpNetwork> {
+    networks
+        .split(',')
+        .map(|network| {
+            network
+                .trim()
+                .parse()
+                .expect("Networks must be comma-separated CIDR ranges, such as 10.0.0.0/8")
+        })
+        .collect()
+}
+
+fn exit_with_errors(errors: &[ConfigError]) -> ! {
+    for error in errors {
+        eprintln!("{}", error);
+    }
+    process::exit(1)
+}
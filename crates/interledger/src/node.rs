@@ -0,0 +1,1210 @@
+//! # Embedded node
+//!
+//! Run a complete Interledger node (Redis store, BTP server, node API and the full
+//! service stack) inside an application's own tokio runtime.
+//!
+//! The application sends packets through the node using the `NodeService`, which
+//! is an `IncomingService` that passes requests to the node over a channel.
+//! Packets routed to any of the builder's `local_accounts` are delivered to the
+//! application on the `Node`'s `local_requests` channel instead of being sent over HTTP.
+//!
+//! ```rust,no_run
+//! # use futures::{Future, Stream};
+//! # use interledger::node::NodeBuilder;
+//! let run = NodeBuilder::new("redis://127.0.0.1:6379")
+//!     .local_accounts(vec![1])
+//!     .run()
+//!     .and_then(|node| {
+//!         node.local_requests.for_each(|(request, respond)| {
+//!             println!("Got packet for local account: {:?}", request.prepare);
+//!             let _ = respond.send(Err(interledger::packet::RejectBuilder {
+//!                 code: interledger::packet::ErrorCode::F99_APPLICATION_ERROR,
+//!                 message: &[],
+//!                 triggered_by: &[],
+//!                 data: &[],
+//!             }
+//!             .build()));
+//!             Ok(())
+//!         })
+//!     });
+//! tokio::run(run);
+//! ```
+
+use super::{
+    cli::BtpConnections,
+    preflight::check_store,
+    tls::{keep_certificates_updated, load_certificates, tls_incoming},
+};
+use bytes::Bytes;
+use futures::{
+    future::{err, join_all, ok, result, Either},
+    sync::{
+        mpsc::{unbounded, UnboundedReceiver, UnboundedSender},
+        oneshot,
+    },
+    Future, Stream,
+};
+use interledger_api::{
+    AccessMiddleware, AddressListener, BalanceHistoryStore, NodeAccount, NodeApi, NodeConstraints,
+    NodeStore, PaymentDataRecorder, PaymentLinkPolicy, PaymentNotifier, PaymentScheduler,
+    StreamingSessionRunner,
+};
+#[cfg(feature = "chaos")]
+use interledger_api::{FailureInjector, FailureSettings};
+use interledger_btp::{
+    connect_to_service_accounts, create_server, BtpAccount, BtpStore, ConnectCheck,
+};
+use interledger_ccp::{
+    CcpRouteManager, CcpRoutingAccount, RouteManagerStore, RoutingRelation,
+    DEFAULT_BROADCAST_INTERVAL,
+};
+use interledger_config::{
+    ClockConfig, ConstraintsConfig, NodeConfig, ReplicaConfig, SettlementConfig, StoreConfig,
+};
+use interledger_http::{HttpAccount, HttpClientService};
+use interledger_ildcp::{IldcpAccount, IldcpClient, IldcpService};
+use interledger_packet::{ErrorCode, Fulfill, Reject, RejectBuilder};
+use interledger_router::Router;
+use interledger_service::{
+    layer_fn, Account as AccountTrait, AccountStore, BoxedIlpFuture, IncomingRequest,
+    IncomingService, OutgoingRequest, OutgoingService, ServiceBuilder as LayerBuilder,
+};
+use interledger_service_util::{
+    check_clock_skew, exchange_settlement_info, with_lock, AddressSpaceService, ClockSkew,
+    ExchangeRateAndBalanceService, LatencyService, LatencyStage, LatencyStats, MaintenanceService,
+    MaxPacketAmountService, MaxPacketDataService, PacketDataStats, PriorityService, PriorityStats,
+    SettlementInfo, SettlementInfoService, ShapingService, ShardedService, ValidatorService,
+};
+#[cfg(feature = "chaos")]
+use interledger_service_util::{FailureInjection, FailureInjectionService};
+use interledger_store_redis::{Account, IntoConnectionInfo, RedisStore, RedisStoreBuilder};
+use interledger_stream::{
+    send_money_with_options, ConnectionGenerator, MinPacketAmountService, SendMoneyOptions,
+    StreamReceiverService,
+};
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    sync::{Arc, RwLock},
+    time::{Duration, Instant},
+};
+use tokio::{self, net::TcpListener, timer::Interval};
+use tower_web::ServiceBuilder;
+use url::Url;
+
+type ResultSender = oneshot::Sender<Result<Fulfill, Reject>>;
+
+/// A packet sent to one of the node's local accounts, along with the channel to send the response on.
+pub type LocalRequest = (OutgoingRequest<Account>, ResultSender);
+
+/// Configuration for an embedded node.
+pub struct NodeBuilder<R> {
+    redis_uri: R,
+    // The config's redis_uri is only used by `NodeBuilder::from_config`
+    config: NodeConfig,
+    local_accounts: Vec<u64>,
+}
+
+impl NodeBuilder<Url> {
+    /// Create a node with all of the given settings, for example ones loaded from
+    /// a config file with `NodeConfig::from_file`.
+    pub fn from_config(config: NodeConfig) -> Self {
+        NodeBuilder {
+            redis_uri: config.redis_uri.clone(),
+            config,
+            local_accounts: Vec::new(),
+        }
+    }
+}
+
+impl<R> NodeBuilder<R>
+where
+    R: IntoConnectionInfo,
+{
+    /// Create a node that uses the Redis store at the given URI, with the defaults of `NodeConfig`.
+    /// By default the BTP server listens on 127.0.0.1:7768 and the node API is not served.
+    pub fn new(redis_uri: R) -> Self {
+        NodeBuilder {
+            redis_uri,
+            config: NodeConfig::default(),
+            local_accounts: Vec::new(),
+        }
+    }
+
+    /// Secret used to generate STREAM receiver credentials and API tokens.
+    /// A random one is generated if this is not set.
+    ///
+    /// Nodes that share a Redis store and the same secret can receive payments for
+    /// each other's SPSP receivers, so they can be run behind a load balancer.
+    pub fn server_secret(mut self, server_secret: [u8; 32]) -> Self {
+        self.config.server_secret = Some(server_secret);
+        self
+    }
+
+    pub fn btp_address(mut self, btp_address: SocketAddr) -> Self {
+        self.config.btp_address = btp_address;
+        self
+    }
+
+    /// Serve the node API (including ILP-over-HTTP) on the given address.
+    pub fn http_address(mut self, http_address: SocketAddr) -> Self {
+        self.config.http_address = Some(http_address);
+        self
+    }
+
+    pub fn constraints(mut self, constraints: ConstraintsConfig) -> Self {
+        self.config.constraints = constraints;
+        self
+    }
+
+    /// How often to record account balances.
+    pub fn balance_snapshot_interval(mut self, balance_snapshot_interval: Duration) -> Self {
+        self.config.balance_snapshot_interval = balance_snapshot_interval;
+        self
+    }
+
+    /// Deliver packets routed to these accounts to the `Node`'s `local_requests` channel.
+    pub fn local_accounts(mut self, account_ids: Vec<u64>) -> Self {
+        self.local_accounts = account_ids;
+        self
+    }
+
+    /// The URL the node API is publicly reachable at, which payment pointers hosted on other
+    /// domains should redirect to. By default, it is guessed from the Host of API requests.
+    pub fn public_url(mut self, url: Url) -> Self {
+        self.config.public_url = Some(url);
+        self
+    }
+
+    /// POST each incoming payment to this URL as JSON once the sender closes the connection.
+    pub fn payment_webhook(mut self, url: Url) -> Self {
+        self.config.payment_webhook = Some(url);
+        self
+    }
+
+    /// POST each scheduled payment to this URL as JSON after every attempt to send it.
+    pub fn scheduled_payment_webhook(mut self, url: Url) -> Self {
+        self.config.scheduled_payment_webhook = Some(url);
+        self
+    }
+
+    /// POST each payment link to this URL as JSON once it is paid in full.
+    pub fn payment_link_webhook(mut self, url: Url) -> Self {
+        self.config.payment_link_webhook = Some(url);
+        self
+    }
+
+    /// Reject STREAM packets for the node's accounts that carry less than this amount,
+    /// in the units of the account the packet arrived from. By default, packets of any size are accepted.
+    pub fn min_incoming_packet_amount(mut self, amount: u64) -> Self {
+        self.config.min_incoming_packet_amount = amount;
+        self
+    }
+
+    /// Run the node as one of several instances that share the Redis store. Only the elected
+    /// leader broadcasts routes and records balance snapshots, and another instance takes over
+    /// if the leader does not renew its lease in time.
+    pub fn leader_election(mut self, lease: Duration) -> Self {
+        self.config.store.leader_lease = Some(lease);
+        self
+    }
+
+    /// Look up accounts and poll for routes and rates using a replica of the Redis database,
+    /// while it is at most `max_staleness` behind the primary (see
+    /// `RedisStoreBuilder::read_replica`).
+    pub fn read_replica(mut self, replica_uri: Url, max_staleness: Duration) -> Self {
+        self.config.read_replica = Some(ReplicaConfig {
+            uri: replica_uri,
+            max_staleness,
+        });
+        self
+    }
+
+    /// How the store polls for updates and runs its background tasks.
+    pub fn store_config(mut self, store: StoreConfig) -> Self {
+        self.config.store = store;
+        self
+    }
+
+    /// Process at most this many incoming packets at a time (10,000 by default). When the node
+    /// is busy, the other packets wait and are processed in order of their account's priority.
+    pub fn max_in_flight_packets(mut self, max_in_flight_packets: usize) -> Self {
+        self.config.max_in_flight_packets = max_in_flight_packets;
+        self
+    }
+
+    /// Process incoming packets on this many worker threads. Each account's packets are always
+    /// processed by the same thread, picked by consistent hashing on the account ID.
+    pub fn packet_shards(mut self, packet_shards: usize) -> Self {
+        self.config.packet_shards = packet_shards;
+        self
+    }
+
+    /// When starting, send a STREAM payment of this amount from the default account to itself
+    /// through the whole service stack, checking that the store, router and STREAM receiver
+    /// are wired up. The node does not start if the payment is not delivered.
+    ///
+    /// The payment is recorded like any other incoming payment, so it is also sent to the
+    /// payment webhook.
+    pub fn self_test(mut self, amount: u64) -> Self {
+        self.config.self_test_amount = Some(amount);
+        self
+    }
+
+    /// Let anyone request to peer with the node through the API. The accounts created for
+    /// the requests cannot send or receive packets until an admin approves them.
+    pub fn accept_peering_requests(mut self, accept: bool) -> Self {
+        self.config.accept_peering_requests = accept;
+        self
+    }
+
+    /// Reject every API request that would change anything, for example because the node is a
+    /// standby instance. Requests from read-only accounts are rejected the same way regardless.
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.config.read_only = read_only;
+        self
+    }
+
+    /// Advertise sibling routes to peers as their parent prefix when there are at least
+    /// `threshold` of them and they all go through the same account.
+    pub fn aggregate_routes(mut self, threshold: usize) -> Self {
+        self.config.route_aggregation_threshold = Some(threshold);
+        self
+    }
+
+    /// Send the node's settlement details to its peers and parents in `peer.settle` messages
+    /// and save the ones they respond with. Peers' messages are answered either way.
+    pub fn settlement(mut self, settlement: SettlementConfig) -> Self {
+        self.config.settlement = Some(settlement);
+        self
+    }
+
+    /// Check the node's clock against an NTP server when it starts and periodically after that,
+    /// warning if it is skewed and optionally correcting for the skew in packets' expiry checks.
+    pub fn clock_checks(mut self, clock: ClockConfig) -> Self {
+        self.config.clock = Some(clock);
+        self
+    }
+
+    /// Check the node's configuration, then connect to the store and run the preflight checks
+    /// (see `preflight::check_store`) without starting any of the node's servers.
+    ///
+    /// Returns a description of each problem that was found.
+    pub fn check(self) -> impl Future<Item = (), Error = Vec<String>> {
+        let mut errors = config_errors(&self.config);
+        if let Some(ref tls) = self.config.tls {
+            if let Err(certificate_errors) = load_certificates(tls) {
+                errors.extend(certificate_errors);
+            }
+        }
+        if !errors.is_empty() {
+            return Either::A(err(errors));
+        }
+        let store_builder = store_builder(self.redis_uri, &self.config)
+            .disable_polling()
+            .gc_interval(None);
+        Either::B(
+            store_builder
+                .connect()
+                .map_err(|_| vec!["Unable to connect to Redis".to_string()])
+                .and_then(check_store),
+        )
+    }
+
+    /// Connect to the store and start the node's servers and background tasks.
+    /// This must be called from within a tokio runtime.
+    ///
+    /// The node does not start if its configuration is invalid or any of the preflight
+    /// checks fail (see `NodeBuilder::check`).
+    pub fn run(self) -> impl Future<Item = Node, Error = ()> {
+        debug!("Starting Interledger node with Redis store");
+        let mut config_errors = config_errors(&self.config);
+        // The certificates are loaded up front so the node does not start with unusable ones
+        let tls = match self.config.tls {
+            Some(ref tls) => match load_certificates(tls) {
+                Ok(resolver) => Some((tls.clone(), resolver)),
+                Err(errors) => {
+                    config_errors.extend(errors);
+                    None
+                }
+            },
+            None => None,
+        };
+        let config_checked = if config_errors.is_empty() {
+            Ok(())
+        } else {
+            log_preflight_errors(&config_errors);
+            Err(())
+        };
+        let store_builder = store_builder(self.redis_uri, &self.config);
+        let config = self.config;
+        let server_secret = config
+            .server_secret
+            .unwrap_or_else(super::cli::random_secret);
+        let server_secret = Bytes::from(&server_secret[..]);
+        let btp_address = config.btp_address;
+        let http_address = config.http_address;
+        let constraints = node_constraints(config.constraints);
+        let endpoint_constraints = constraints.clone();
+        let balance_snapshot_interval = config.balance_snapshot_interval;
+        let local_accounts: HashSet<u64> = self.local_accounts.into_iter().collect();
+        let payment_webhook = config.payment_webhook;
+        let scheduled_payment_webhook = config.scheduled_payment_webhook;
+        let payment_link_webhook = config.payment_link_webhook;
+        let public_url = config.public_url;
+        let min_incoming_packet_amount = config.min_incoming_packet_amount;
+        let max_in_flight_packets = config.max_in_flight_packets;
+        let packet_shards = config.packet_shards;
+        let self_test_amount = config.self_test_amount;
+        let accept_peering_requests = config.accept_peering_requests;
+        let read_only = config.read_only;
+        let route_aggregation_threshold = config.route_aggregation_threshold;
+        let clock_config = config.clock;
+        let clock = Arc::new(ClockSkew::new(
+            clock_config
+                .as_ref()
+                .map(|clock| clock.compensate)
+                .unwrap_or(false),
+        ));
+        let settlement_info = config
+            .settlement
+            .as_ref()
+            .map(node_settlement_info)
+            .unwrap_or_default();
+        let settlement_refresh_interval = config
+            .settlement
+            .as_ref()
+            .map(|settlement| settlement.refresh_interval);
+        let (local_sender, local_receiver) = unbounded();
+
+        result(config_checked)
+            .and_then(move |_| {
+                store_builder
+                    .connect()
+                    .map_err(|err| error!("Error connecting to Redis: {:?}", err))
+            })
+            .and_then(|store| {
+                check_store(store.clone())
+                    .map_err(|errors| log_preflight_errors(&errors))
+                    .map(move |_| store)
+            })
+            .and_then(move |store| {
+                store
+                    .clone()
+                    .get_accounts(vec![0])
+                    .and_then(|mut accounts| accounts.remove(0).ok_or(()))
+                    .map_err(|_| {
+                        error!("Must add account 0 (the default account) before running the node")
+                    })
+                    .and_then(move |default_account| {
+                        let outgoing_service = LocalAccountService {
+                            local_accounts,
+                            sender: local_sender,
+                            next: EndpointCheckService::new(
+                                endpoint_constraints.clone(),
+                                HttpClientService::new(store.clone()),
+                            ),
+                        };
+                        let latency_stats = Arc::new(LatencyStats::default());
+                        let btp_store = TimedBtpStore {
+                            store: store.clone(),
+                            stats: latency_stats.clone(),
+                        };
+                        let store_clone = store.clone();
+                        create_server(btp_address, btp_store, outgoing_service)
+                            .and_then(move |btp_service| {
+                                // Connect to the accounts we are a BTP client of, such as a parent connector
+                                store_clone
+                                    .get_all_accounts()
+                                    .map_err(|err| {
+                                        error!("Error loading accounts to connect to: {:?}", err)
+                                    })
+                                    .and_then(move |accounts| {
+                                        let btp_client_accounts = accounts
+                                            .into_iter()
+                                            .filter(|account| {
+                                                account.get_btp_uri().is_some()
+                                                    && !account.is_pending()
+                                            })
+                                            .collect();
+                                        // Peers' hosts are checked each time the node connects,
+                                        // in case they resolve to other addresses than before
+                                        let check: ConnectCheck = Arc::new(move |url: &Url| {
+                                            Box::new(endpoint_constraints.check_host(url))
+                                                as Box<Future<Item = (), Error = String> + Send>
+                                        });
+                                        connect_to_service_accounts(
+                                            btp_service,
+                                            btp_client_accounts,
+                                            check,
+                                        )
+                                    })
+                            })
+                            .and_then(move |btp_service| {
+                                // The BTP service is both an Incoming and Outgoing one so we pass it first as the Outgoing
+                                // service to others like the router and then call handle_incoming on it to set up the incoming handler
+                                let packet_data_stats = Arc::new(PacketDataStats::default());
+                                // Peers' Rejects cannot claim to be triggered by address space they do not have
+                                let node_address = Arc::new(RwLock::new(Bytes::from(
+                                    default_account.client_address(),
+                                )));
+                                let outgoing_service = AddressSpaceService::new(
+                                    node_address.clone(),
+                                    btp_service.clone(),
+                                );
+                                let outgoing_service = LatencyService::new(
+                                    latency_stats.clone(),
+                                    LatencyStage::Transport,
+                                    outgoing_service,
+                                );
+                                let outgoing_service = MaxPacketDataService::with_stats(
+                                    packet_data_stats.clone(),
+                                    outgoing_service,
+                                );
+                                let outgoing_service = ShapingService::new(outgoing_service);
+                                let outgoing_service = ValidatorService::outgoing(outgoing_service)
+                                    .with_clock(clock.clone());
+                                // Storing the connection state in Redis means that multiple
+                                // instances with the same server secret can receive payments
+                                let outgoing_service = StreamReceiverService::with_store(
+                                    server_secret.clone(),
+                                    PaymentNotifier::new(store.clone(), payment_webhook),
+                                    outgoing_service,
+                                )
+                                .data_handler(PaymentDataRecorder::new(store.clone()))
+                                .connection_policy(PaymentLinkPolicy::new(
+                                    store.clone(),
+                                    payment_link_webhook,
+                                ));
+                                let outgoing_service = LatencyService::new(
+                                    latency_stats.clone(),
+                                    LatencyStage::Outgoing,
+                                    outgoing_service,
+                                );
+                                let outgoing_service = ExchangeRateAndBalanceService::new(
+                                    store.clone(),
+                                    outgoing_service,
+                                );
+                                // Packets to peers in maintenance are rejected before their
+                                // balances are changed, instead of changing them and rolling
+                                // them back
+                                let outgoing_service =
+                                    MaintenanceService::new(store.clone(), outgoing_service);
+                                let outgoing_service = LatencyService::new(
+                                    latency_stats.clone(),
+                                    LatencyStage::Balance,
+                                    outgoing_service,
+                                );
+                                // Dust for the node's accounts is rejected before any
+                                // balances are changed
+                                let outgoing_service = MinPacketAmountService::new(
+                                    server_secret.clone(),
+                                    min_incoming_packet_amount,
+                                    outgoing_service,
+                                );
+                                // Delays are injected before the balance updates, which are the
+                                // first calls to the store for each packet
+                                #[cfg(feature = "chaos")]
+                                let failures = Arc::new(FailureInjection::default());
+                                #[cfg(feature = "chaos")]
+                                let outgoing_service = FailureInjectionService::new(
+                                    failures.clone(),
+                                    outgoing_service,
+                                );
+
+                                // Set up the Router and Routing Manager
+                                let incoming_service = LatencyService::new(
+                                    latency_stats.clone(),
+                                    LatencyStage::Route,
+                                    Router::new(store.clone(), outgoing_service.clone()),
+                                );
+                                let default_account_id = default_account.id();
+                                let self_test_account = default_account.clone();
+                                if let Some(interval) = settlement_refresh_interval {
+                                    tokio::spawn(exchange_settlement_info_with_peers(
+                                        store.clone(),
+                                        outgoing_service.clone(),
+                                        default_account.clone(),
+                                        settlement_info.clone(),
+                                        interval,
+                                    ));
+                                }
+                                let mut route_manager =
+                                    CcpRouteManager::new_without_spawn_broadcast(
+                                        default_account,
+                                        store.clone(),
+                                        outgoing_service,
+                                        incoming_service,
+                                    );
+                                if let Some(threshold) = route_aggregation_threshold {
+                                    route_manager = route_manager.aggregate_routes(threshold);
+                                }
+                                let store_clone = store.clone();
+                                tokio::spawn(route_manager.broadcast_routes_when(
+                                    DEFAULT_BROADCAST_INTERVAL,
+                                    move || store_clone.is_leader(),
+                                ));
+                                let priority_stats = Arc::new(PriorityStats::default());
+                                // The first layer handles each incoming packet first
+                                let incoming_layers = LayerBuilder::new()
+                                    // Children that connect at the same time share one ILDCP
+                                    // request instead of each taking a slot in the stack
+                                    .layer(layer_fn(IldcpClient::new))
+                                    // Each shard's thread only handles its own accounts' packets
+                                    .layer(layer_fn(move |next| {
+                                        ShardedService::new(packet_shards, next)
+                                    }))
+                                    .layer(layer_fn({
+                                        let clock = clock.clone();
+                                        move |next| {
+                                            ValidatorService::incoming(next)
+                                                .with_clock(clock.clone())
+                                        }
+                                    }))
+                                    // Packets are delayed before they wait for a slot, so that
+                                    // shaped accounts' packets do not hold up the others
+                                    .layer(layer_fn(ShapingService::new))
+                                    .layer(layer_fn({
+                                        let priority_stats = priority_stats.clone();
+                                        move |next| {
+                                            PriorityService::with_stats(
+                                                priority_stats.clone(),
+                                                max_in_flight_packets,
+                                                next,
+                                            )
+                                        }
+                                    }))
+                                    .layer(layer_fn({
+                                        let store = store.clone();
+                                        move |next| MaintenanceService::new(store.clone(), next)
+                                    }))
+                                    .layer(layer_fn({
+                                        let packet_data_stats = packet_data_stats.clone();
+                                        move |next| {
+                                            MaxPacketDataService::with_stats(
+                                                packet_data_stats.clone(),
+                                                next,
+                                            )
+                                        }
+                                    }))
+                                    .layer(layer_fn(MaxPacketAmountService::new))
+                                    .layer(layer_fn({
+                                        let store = store.clone();
+                                        move |next| {
+                                            SettlementInfoService::new(
+                                                settlement_info.clone(),
+                                                store.clone(),
+                                                next,
+                                            )
+                                        }
+                                    }))
+                                    .layer(layer_fn(IldcpService::new));
+                                let incoming_service =
+                                    incoming_layers.service(route_manager.clone());
+
+                                // Handle incoming packets sent via BTP
+                                let btp_connections = BtpConnections(btp_service.clone());
+                                btp_service.handle_incoming(incoming_service.clone());
+
+                                // The node only starts listening and running its background
+                                // tasks once the self-test payment went through
+                                let tested = match self_test_amount {
+                                    Some(amount) => Either::A(self_test(
+                                        incoming_service.clone(),
+                                        server_secret.clone(),
+                                        self_test_account,
+                                        amount,
+                                    )),
+                                    None => Either::B(ok(())),
+                                };
+                                tested.and_then(move |_| {
+                                    // Note the API also includes receiving ILP packets sent via HTTP
+                                    if let Some(http_address) = http_address {
+                                        let api = NodeApi::new(
+                                            server_secret.clone(),
+                                            store.clone(),
+                                            incoming_service.clone(),
+                                        )
+                                        .constraints(constraints)
+                                        .accept_peering_requests(accept_peering_requests)
+                                        .connection_registry(btp_connections)
+                                        .packet_data_stats(packet_data_stats)
+                                        .priority_stats(priority_stats)
+                                        .latency_stats(latency_stats)
+                                        .address_listener(NodeAddress {
+                                            default_account_id,
+                                            node_address,
+                                            route_manager,
+                                        });
+                                        let api = if let Some(public_url) = public_url {
+                                            api.public_url(public_url)
+                                        } else {
+                                            api
+                                        };
+                                        #[cfg(feature = "chaos")]
+                                        let api = api.failure_injector(NodeFailures(failures));
+                                        let listener =
+                                            TcpListener::bind(&http_address).map_err(|err| {
+                                                error!(
+                                                    "Unable to bind to HTTP address {}: {:?}",
+                                                    http_address, err
+                                                )
+                                            })?;
+                                        let server =
+                                            ServiceBuilder::new().resource(api).middleware(
+                                                AccessMiddleware::new(store.clone(), read_only),
+                                            );
+                                        if let Some((tls_config, resolver)) = tls {
+                                            info!(
+                                                "Interledger node listening on: {} (TLS)",
+                                                http_address
+                                            );
+                                            keep_certificates_updated(tls_config, resolver.clone());
+                                            tokio::spawn(
+                                                server.serve(tls_incoming(listener, resolver)),
+                                            );
+                                        } else {
+                                            info!(
+                                                "Interledger node listening on: {}",
+                                                http_address
+                                            );
+                                            tokio::spawn(server.serve(listener.incoming()));
+                                        }
+                                    }
+
+                                    // Record the balances periodically so they can be graphed over time
+                                    let store_clone = store.clone();
+                                    let snapshot_balances =
+                                        Interval::new(Instant::now(), balance_snapshot_interval)
+                                            .map_err(|err| error!("Interval error: {:?}", err))
+                                            .for_each(move |_| {
+                                                if !store_clone.is_leader() {
+                                                    return Either::B(ok(()));
+                                                }
+                                                let store = store_clone.clone();
+                                                // Keep taking snapshots even if one of them fails
+                                                Either::A(
+                                                    with_lock(
+                                                        store_clone.clone(),
+                                                        "balance_snapshots",
+                                                        TASK_LOCK_TTL,
+                                                        // The store logs the errors
+                                                        move |_| {
+                                                            store
+                                                                .snapshot_balances()
+                                                                .map_err(|_| ())
+                                                        },
+                                                    )
+                                                    .then(|_| Ok(())),
+                                                )
+                                            });
+                                    tokio::spawn(snapshot_balances);
+
+                                    // Send the payments users have scheduled through the API. The next
+                                    // check waits until the payments being sent are finished
+                                    let scheduler = PaymentScheduler::new(
+                                        store.clone(),
+                                        incoming_service.clone(),
+                                        scheduled_payment_webhook,
+                                    );
+                                    let store_clone = store.clone();
+                                    let send_scheduled_payments =
+                                        Interval::new(Instant::now(), SCHEDULED_PAYMENT_INTERVAL)
+                                            .map_err(|err| error!("Interval error: {:?}", err))
+                                            .for_each(move |_| {
+                                                if !store_clone.is_leader() {
+                                                    return Either::B(ok(()));
+                                                }
+                                                let scheduler = scheduler.clone();
+                                                Either::A(
+                                                    with_lock(
+                                                        store_clone.clone(),
+                                                        "scheduled_payments",
+                                                        TASK_LOCK_TTL,
+                                                        move |_| scheduler.send_due_payments(),
+                                                    )
+                                                    .then(|_| Ok(())),
+                                                )
+                                            });
+                                    tokio::spawn(send_scheduled_payments);
+
+                                    // Send what the streaming payment sessions owe
+                                    let runner = StreamingSessionRunner::new(
+                                        store.clone(),
+                                        incoming_service.clone(),
+                                    );
+                                    let store_clone = store.clone();
+                                    let stream_session_payments =
+                                        Interval::new(Instant::now(), STREAMING_SESSION_INTERVAL)
+                                            .map_err(|err| error!("Interval error: {:?}", err))
+                                            .for_each(move |_| {
+                                                if !store_clone.is_leader() {
+                                                    return Either::B(ok(()));
+                                                }
+                                                let runner = runner.clone();
+                                                Either::A(
+                                                    with_lock(
+                                                        store_clone.clone(),
+                                                        "streaming_sessions",
+                                                        TASK_LOCK_TTL,
+                                                        move |_| runner.send_owed_payments(),
+                                                    )
+                                                    .then(|_| Ok(())),
+                                                )
+                                            });
+                                    tokio::spawn(stream_session_payments);
+
+                                    // Every instance checks its own clock, starting right away
+                                    if let Some(clock_config) = clock_config {
+                                        let check_clock = Interval::new(
+                                            Instant::now(),
+                                            clock_config.check_interval,
+                                        )
+                                        .map_err(|err| error!("Interval error: {:?}", err))
+                                        .for_each(move |_| {
+                                            // A failed check is logged and the last skew is kept
+                                            check_clock_skew(
+                                                clock.clone(),
+                                                &clock_config.ntp_server,
+                                                clock_config.max_skew,
+                                            )
+                                            .then(|_| Ok(()))
+                                        });
+                                        tokio::spawn(check_clock);
+                                    }
+
+                                    // Pass requests from the application to the service stack
+                                    let (incoming_sender, incoming_receiver) = unbounded();
+                                    let mut incoming_service = incoming_service;
+                                    tokio::spawn(incoming_receiver.for_each(
+                                        move |(request, respond): (
+                                            IncomingRequest<Account>,
+                                            ResultSender,
+                                        )| {
+                                            tokio::spawn(
+                                                incoming_service.handle_request(request).then(
+                                                    move |result| {
+                                                        let _ = respond.send(result);
+                                                        Ok(())
+                                                    },
+                                                ),
+                                            );
+                                            Ok(())
+                                        },
+                                    ));
+
+                                    let node = Node {
+                                        service: NodeService {
+                                            sender: incoming_sender,
+                                        },
+                                        store,
+                                        local_requests: local_receiver,
+                                    };
+                                    Ok(node)
+                                })
+                            })
+                    })
+            })
+    }
+}
+
+// The config's own checks, plus the ones that depend on how the node was built
+fn config_errors(config: &NodeConfig) -> Vec<String> {
+    #[allow(unused_mut)]
+    let mut errors: Vec<String> = match config.validate() {
+        Ok(()) => Vec::new(),
+        Err(errors) => errors.iter().map(ToString::to_string).collect(),
+    };
+    #[cfg(not(feature = "acme"))]
+    {
+        if config.tls.as_ref().map_or(false, |tls| tls.acme.is_some()) {
+            errors.push("ACME requires the node to be built with the acme feature".to_string());
+        }
+    }
+    errors
+}
+
+fn store_builder<R>(redis_uri: R, config: &NodeConfig) -> RedisStoreBuilder<R>
+where
+    R: IntoConnectionInfo,
+{
+    let store_builder = RedisStoreBuilder::new(redis_uri).config(config.store.clone());
+    match config.read_replica {
+        Some(ref replica) => store_builder.read_replica(replica.uri.clone(), replica.max_staleness),
+        None => store_builder,
+    }
+}
+
+fn node_constraints(config: ConstraintsConfig) -> NodeConstraints {
+    NodeConstraints {
+        allowed_asset_codes: config.allowed_asset_codes,
+        max_asset_scale: config.max_asset_scale,
+        child_address_prefix: config
+            .child_address_prefix
+            .map(|prefix| prefix.into_bytes()),
+        allowed_endpoint_networks: config.allowed_endpoint_networks,
+        denied_endpoint_networks: config.denied_endpoint_networks,
+    }
+}
+
+fn node_settlement_info(config: &SettlementConfig) -> SettlementInfo {
+    SettlementInfo {
+        engine_url: config.engine_url.as_ref().map(Url::to_string),
+        ledger: config.ledger.clone(),
+        ledger_address: config.ledger_address.clone(),
+        ..SettlementInfo::default()
+    }
+}
+
+// How often to check for scheduled payments that are due
+const SCHEDULED_PAYMENT_INTERVAL: Duration = Duration::from_secs(10);
+
+// How often the amounts owed by streaming payment sessions are sent
+const STREAMING_SESSION_INTERVAL: Duration = Duration::from_secs(5);
+
+// The background tasks hold a lock while they run, so instances that share the database do not
+// run them at the same time even while the leadership is changing hands. The locks expire after
+// this long if the instance holding them stops
+const TASK_LOCK_TTL: Duration = Duration::from_secs(60);
+
+// Peers' connections may not be up yet when the node starts
+const SETTLEMENT_INFO_DELAY: Duration = Duration::from_secs(10);
+
+/// Periodically send our settlement details to the peers and parents and save theirs
+fn exchange_settlement_info_with_peers<S>(
+    store: RedisStore,
+    service: S,
+    from: Account,
+    info: SettlementInfo,
+    interval: Duration,
+) -> impl Future<Item = (), Error = ()>
+where
+    S: OutgoingService<Account> + Clone + Send + 'static,
+{
+    Interval::new(Instant::now() + SETTLEMENT_INFO_DELAY, interval)
+        .map_err(|err| error!("Interval error: {:?}", err))
+        .for_each(move |_| {
+            if !store.is_leader() {
+                return Either::B(ok(()));
+            }
+            let store_clone = store.clone();
+            let service = service.clone();
+            let from = from.clone();
+            let info = info.clone();
+            let exchange = move |_| {
+                let store = store_clone;
+                store
+                    .get_all_accounts()
+                    .map_err(|err| {
+                        error!(
+                            "Error loading accounts to send settlement info to: {:?}",
+                            err
+                        )
+                    })
+                    .and_then(move |accounts| {
+                        let exchanges = accounts
+                            .into_iter()
+                            .filter(|account| {
+                                account.id() != from.id()
+                                    && account.routing_relation() != RoutingRelation::Child
+                            })
+                            .map(|account| {
+                                exchange_settlement_info(
+                                    service.clone(),
+                                    store.clone(),
+                                    &info,
+                                    from.clone(),
+                                    account,
+                                )
+                                // One peer failing should not stop the others
+                                .then(|_| Ok::<(), ()>(()))
+                            })
+                            .collect::<Vec<_>>();
+                        join_all(exchanges).map(|_| ())
+                    })
+            };
+            // Keep trying at the next interval
+            Either::A(
+                with_lock(store.clone(), "settlement_info", TASK_LOCK_TTL, exchange)
+                    .then(|_| Ok::<(), ()>(())),
+            )
+        })
+}
+
+const SELF_TEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Send a STREAM payment from the account to itself through the service, which must be the
+/// node's full incoming service stack
+fn self_test<S>(
+    service: S,
+    server_secret: Bytes,
+    account: Account,
+    amount: u64,
+) -> impl Future<Item = (), Error = ()>
+where
+    S: IncomingService<Account> + Clone + Send + 'static,
+{
+    let (destination, shared_secret) = ConnectionGenerator::new(server_secret)
+        .generate_address_and_secret(&account.client_address()[..]);
+    send_money_with_options(
+        service,
+        &account,
+        &destination[..],
+        &shared_secret[..],
+        amount,
+        SendMoneyOptions::default().timeout(SELF_TEST_TIMEOUT),
+    )
+    .map_err(|err| error!("Self-test payment could not be sent: {:?}", err))
+    .and_then(move |(outcome, _service)| {
+        if outcome.is_complete() && outcome.amount_delivered > 0 {
+            info!(
+                "Self-test payment of {} from account {} to itself was delivered",
+                amount,
+                account.id()
+            );
+            Ok(())
+        } else {
+            error!("Self-test payment was not delivered: {:?}", outcome);
+            Err(())
+        }
+    })
+}
+
+fn log_preflight_errors(errors: &[String]) {
+    error!("The node cannot start:");
+    for error in errors {
+        error!("  - {}", error);
+    }
+}
+
+/// A running node.
+pub struct Node {
+    /// Sends packets through the node as if they came from the `from` account
+    pub service: NodeService,
+    /// The node's store, which can be used to manage accounts
+    pub store: RedisStore,
+    /// Packets routed to the local accounts. The node stops delivering them if this is dropped
+    pub local_requests: UnboundedReceiver<LocalRequest>,
+}
+
+/// An `IncomingService` that passes requests to the embedded node's service stack.
+#[derive(Clone)]
+pub struct NodeService {
+    sender: UnboundedSender<(IncomingRequest<Account>, ResultSender)>,
+}
+
+impl IncomingService<Account> for NodeService {
+    type Future = BoxedIlpFuture;
+
+    fn handle_request(&mut self, request: IncomingRequest<Account>) -> Self::Future {
+        let (respond, response) = oneshot::channel();
+        if self.sender.unbounded_send((request, respond)).is_err() {
+            error!("Node is no longer running");
+            return Box::new(err(internal_error()));
+        }
+        Box::new(
+            response
+                .map_err(|_| internal_error())
+                .and_then(|result| result),
+        )
+    }
+}
+
+fn internal_error() -> Reject {
+    RejectBuilder {
+        code: ErrorCode::T00_INTERNAL_ERROR,
+        message: &[],
+        triggered_by: &[],
+        data: &[],
+    }
+    .build()
+}
+
+// The default account's address is the node's own, which the route manager adds to the routes it advertises
+struct NodeAddress<S, T, U> {
+    default_account_id: u64,
+    /// The address the AddressSpaceService checks Rejects against
+    node_address: Arc<RwLock<Bytes>>,
+    route_manager: CcpRouteManager<S, T, U, Account>,
+}
+
+impl<S, T, U> AddressListener for NodeAddress<S, T, U>
+where
+    S: IncomingService<Account> + Clone + Send + Sync + 'static,
+    T: OutgoingService<Account> + Clone + Send + Sync + 'static,
+    U: RouteManagerStore<Account = Account> + Clone + Send + Sync + 'static,
+{
+    fn address_changed(&self, account_id: &str, ilp_address: &[u8]) {
+        if account_id == self.default_account_id.to_string() {
+            *self.node_address.write().unwrap() = Bytes::from(ilp_address);
+            tokio::spawn(self.route_manager.set_ilp_address(Bytes::from(ilp_address)));
+        }
+    }
+}
+
+// Lets admins change the failures injected by the node's FailureInjectionService through the API
+#[cfg(feature = "chaos")]
+struct NodeFailures(Arc<FailureInjection>);
+
+#[cfg(feature = "chaos")]
+impl FailureInjector for NodeFailures {
+    fn failures(&self) -> FailureSettings {
+        FailureSettings {
+            drop_percent: self.0.drop_percent(),
+            delay: self.0.delay(),
+        }
+    }
+
+    fn inject_failures(&self, settings: FailureSettings) {
+        self.0.set_drop_percent(settings.drop_percent);
+        self.0.set_delay(settings.delay);
+    }
+}
+
+// How long a peer's HTTP host is trusted to resolve to addresses the node may connect to
+const ENDPOINT_CHECK_TTL: Duration = Duration::from_secs(60);
+
+// How many hosts' checks are remembered before they are all checked again
+const MAX_CHECKED_ENDPOINTS: usize = 1000;
+
+/// Checks that the node may connect to the addresses the hosts of accounts' HTTP URLs resolve to
+/// before sending packets to them, so that a hostname cannot be pointed at an internal service
+/// after the account is saved. The results are remembered for a short time so that hosts are not
+/// resolved for every packet.
+#[derive(Clone)]
+struct EndpointCheckService<S> {
+    constraints: NodeConstraints,
+    checked: Arc<RwLock<HashMap<String, (Instant, Result<(), String>)>>>,
+    next: S,
+}
+
+impl<S> EndpointCheckService<S> {
+    fn new(constraints: NodeConstraints, next: S) -> Self {
+        EndpointCheckService {
+            constraints,
+            checked: Arc::new(RwLock::new(HashMap::new())),
+            next,
+        }
+    }
+}
+
+fn endpoint_not_allowed(account_id: u64, message: &str) -> Reject {
+    warn!(
+        "Not sending packet to the HTTP endpoint of account {}: {}",
+        account_id, message
+    );
+    RejectBuilder {
+        code: ErrorCode::F02_UNREACHABLE,
+        message: b"Peer's endpoint is not allowed",
+        triggered_by: &[],
+        data: &[],
+    }
+    .build()
+}
+
+impl<S> OutgoingService<Account> for EndpointCheckService<S>
+where
+    S: OutgoingService<Account> + Clone + Send + 'static,
+{
+    type Future = BoxedIlpFuture;
+
+    fn send_request(&mut self, request: OutgoingRequest<Account>) -> Self::Future {
+        let url = match request.to.get_http_url() {
+            Some(url) => url.clone(),
+            None => return Box::new(self.next.send_request(request)),
+        };
+        let host = url.host_str().unwrap_or_default().to_string();
+        let account_id = request.to.id();
+        if let Some((checked_at, result)) = self.checked.read().unwrap().get(&host) {
+            if checked_at.elapsed() < ENDPOINT_CHECK_TTL {
+                return match result {
+                    Ok(()) => Box::new(self.next.send_request(request)),
+                    Err(message) => Box::new(err(endpoint_not_allowed(account_id, message))),
+                };
+            }
+        }
+        let checked = self.checked.clone();
+        let mut next = self.next.clone();
+        Box::new(self.constraints.check_host(&url).then(move |result| {
+            {
+                let mut checked = checked.write().unwrap();
+                if checked.len() >= MAX_CHECKED_ENDPOINTS {
+                    checked.clear();
+                }
+                checked.insert(host, (Instant::now(), result.clone()));
+            }
+            match result {
+                Ok(()) => Either::A(next.send_request(request)),
+                Err(message) => Either::B(err(endpoint_not_allowed(account_id, &message))),
+            }
+        }))
+    }
+}
+
+/// A BtpStore that records how long the account lookups for incoming BTP connections take.
+#[derive(Clone)]
+struct TimedBtpStore<S> {
+    store: S,
+    stats: Arc<LatencyStats>,
+}
+
+impl<S> BtpStore for TimedBtpStore<S>
+where
+    S: BtpStore,
+    S::Account: 'static,
+{
+    type Account = S::Account;
+
+    fn get_account_from_btp_token(
+        &self,
+        token: &str,
+    ) -> Box<Future<Item = Self::Account, Error = ()> + Send> {
+        let stats = self.stats.clone();
+        let start = Instant::now();
+        Box::new(
+            self.store
+                .get_account_from_btp_token(token)
+                .then(move |result| {
+                    stats.record(LatencyStage::Auth, start.elapsed());
+                    result
+                }),
+        )
+    }
+}
+
+/// Sends requests for the local accounts to the application and passes the rest to the next service.
+#[derive(Clone)]
+struct LocalAccountService<S> {
+    local_accounts: HashSet<u64>,
+    sender: UnboundedSender<LocalRequest>,
+    next: S,
+}
+
+impl<S> OutgoingService<Account> for LocalAccountService<S>
+where
+    S: OutgoingService<Account>,
+{
+    type Future = BoxedIlpFuture;
+
+    fn send_request(&mut self, request: OutgoingRequest<Account>) -> Self::Future {
+        if !self.local_accounts.contains(&request.to.id()) {
+            return Box::new(self.next.send_request(request));
+        }
+        let (respond, response) = oneshot::channel();
+        if self.sender.unbounded_send((request, respond)).is_err() {
+            debug!("Application is no longer receiving packets for local accounts");
+            return Box::new(err(RejectBuilder {
+                code: ErrorCode::F02_UNREACHABLE,
+                message: b"Local account is not accepting packets",
+                triggered_by: &[],
+                data: &[],
+            }
+            .build()));
+        }
+        Box::new(
+            response
+                .map_err(|_| internal_error())
+                .and_then(|result| result),
+        )
+    }
+}
@@ -0,0 +1,536 @@
+//! # Paid HTTP proxy
+//!
+//! A reverse proxy that only forwards requests to an HTTP service once they
+//! have been paid for with STREAM.
+//!
+//! A request without a valid `Pay-Token` header gets a `402 Payment Required` response
+//! with a header like `Pay: interledger-stream <amount> <destination_account> <shared_secret>`,
+//! where the shared secret is encoded as base64url. After sending at least that amount
+//! over STREAM to the destination, the client repeats the request with the shared secret
+//! as its `Pay-Token`. The request's price is deducted from what was received on that
+//! connection, so one payment can cover several requests, and the response includes
+//! the amount left in a `Pay-Balance` header.
+//!
+//! Connections that have not been paid on are forgotten after a few minutes and paid ones
+//! after an hour without requests, and at most 100,000 are tracked at a time.
+//!
+//! The proxy receives ILP packets over ILP-over-HTTP on `/ilp`, so it should be added to
+//! a node as an account with the proxy's URL as its HTTP endpoint.
+//!
+//! ```rust,no_run
+//! # use interledger::{ildcp::IldcpResponseBuilder, paid_proxy::PaidProxyBuilder};
+//! # use url::Url;
+//! let ildcp_info = IldcpResponseBuilder {
+//!     client_address: b"example.node.proxy",
+//!     asset_code: "XYZ",
+//!     asset_scale: 9,
+//! }
+//! .build();
+//! let proxy = PaidProxyBuilder::new(
+//!     Url::parse("http://localhost:3000").unwrap(),
+//!     ildcp_info,
+//!     "incoming_auth_token".to_string(),
+//! )
+//! .price(1000)
+//! .path_price("/expensive".to_string(), 100_000)
+//! .run();
+//! tokio::run(proxy);
+//! ```
+
+use base64;
+use bytes::Bytes;
+use futures::{future::ok, Future};
+use hyper::{
+    client::HttpConnector,
+    header::{HeaderValue, HOST},
+    service::{service_fn, Service},
+    Body, Client, Error, Method, Request, Response, Server, StatusCode, Uri,
+};
+use interledger_http::HttpServerService;
+use interledger_ildcp::{IldcpResponse, IldcpService};
+use interledger_packet::{ErrorCode, RejectBuilder};
+use interledger_router::Router;
+use interledger_service::{outgoing_service_fn, OutgoingRequest};
+use interledger_service_util::ValidatorService;
+use interledger_store_memory::{Account, AccountBuilder, InMemoryStore};
+use interledger_stream::{ConnectionGenerator, StreamConnectionStore, StreamReceiverService};
+use parking_lot::RwLock;
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    str,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use url::Url;
+
+static PAY: &str = "pay";
+static PAY_TOKEN: &str = "pay-token";
+static PAY_BALANCE: &str = "pay-balance";
+/// How long a connection that has not been paid on is kept
+const UNPAID_CONNECTION_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+/// How long a paid connection is kept after it was last used
+const IDLE_CONNECTION_TIMEOUT: Duration = Duration::from_secs(60 * 60);
+const MAX_CONNECTIONS: usize = 100_000;
+/// How often expired connections are removed
+const PRUNE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Configuration for a paid HTTP proxy.
+pub struct PaidProxyBuilder {
+    upstream: Url,
+    ildcp_info: IldcpResponse,
+    incoming_auth_token: String,
+    address: SocketAddr,
+    server_secret: Option<[u8; 32]>,
+    price: u64,
+    path_prices: Vec<(String, u64)>,
+}
+
+impl PaidProxyBuilder {
+    /// Create a proxy for the `upstream` HTTP service that receives payments with the
+    /// given ILP address and asset. The node sending it packets must use the
+    /// `incoming_auth_token` as its bearer token.
+    pub fn new(upstream: Url, ildcp_info: IldcpResponse, incoming_auth_token: String) -> Self {
+        PaidProxyBuilder {
+            upstream,
+            ildcp_info,
+            incoming_auth_token,
+            address: ([127, 0, 0, 1], 8080).into(),
+            server_secret: None,
+            price: 0,
+            path_prices: Vec::new(),
+        }
+    }
+
+    /// Address to listen on (default `127.0.0.1:8080`)
+    pub fn address(mut self, address: SocketAddr) -> Self {
+        self.address = address;
+        self
+    }
+
+    /// Seed used to generate the STREAM connections. A random one is used if none is set
+    pub fn server_secret(mut self, server_secret: [u8; 32]) -> Self {
+        self.server_secret = Some(server_secret);
+        self
+    }
+
+    /// Price of requests whose path does not match any of the `path_price` prefixes
+    pub fn price(mut self, price: u64) -> Self {
+        self.price = price;
+        self
+    }
+
+    /// Price of requests whose path starts with the prefix. The longest matching prefix is used
+    pub fn path_price(mut self, path_prefix: String, price: u64) -> Self {
+        self.path_prices.push((path_prefix, price));
+        self
+    }
+
+    pub fn run(self) -> impl Future<Item = (), Error = ()> {
+        let server_secret =
+            Bytes::from(&self.server_secret.unwrap_or_else(super::cli::random_secret)[..]);
+        let ilp_address = Bytes::from(self.ildcp_info.client_address());
+        let prices = Prices {
+            default: self.price,
+            paths: self.path_prices,
+        };
+        let upstream = self.upstream;
+        let payments = Payments::default();
+        let connection_generator = ConnectionGenerator::new(server_secret.clone());
+
+        let account = AccountBuilder::new()
+            .ilp_address(&ilp_address[..])
+            .asset_code(String::from_utf8_lossy(self.ildcp_info.asset_code()).to_string())
+            .asset_scale(self.ildcp_info.asset_scale())
+            .http_incoming_authorization(format!("Bearer {}", self.incoming_auth_token))
+            .build();
+        let store = InMemoryStore::from_accounts(vec![account]);
+        let ilp_address_clone = ilp_address.clone();
+        let outgoing_handler = StreamReceiverService::with_store(
+            server_secret,
+            payments.clone(),
+            outgoing_service_fn(move |request: OutgoingRequest<Account>| {
+                Err(RejectBuilder {
+                    code: ErrorCode::F02_UNREACHABLE,
+                    message: &format!(
+                        "No handler configured for destination: {}",
+                        str::from_utf8(&request.prepare.destination()).unwrap_or("<not utf8>")
+                    )
+                    .as_bytes(),
+                    triggered_by: &ilp_address_clone[..],
+                    data: &[],
+                }
+                .build())
+            }),
+        );
+        let incoming_handler = Router::new(store.clone(), outgoing_handler);
+        let incoming_handler = IldcpService::new(incoming_handler);
+        let incoming_handler = ValidatorService::incoming(incoming_handler);
+        let http_service = HttpServerService::new(incoming_handler, store);
+        let client = Client::new();
+
+        debug!(
+            "Paid proxy listening on {} for {} with ILP address {}",
+            self.address,
+            upstream,
+            str::from_utf8(&ilp_address[..]).unwrap_or("<not utf8>")
+        );
+        Server::bind(&self.address)
+            .serve(move || {
+                let mut http_service = http_service.clone();
+                let payments = payments.clone();
+                let connection_generator = connection_generator.clone();
+                let ilp_address = ilp_address.clone();
+                let prices = prices.clone();
+                let upstream = upstream.clone();
+                let client = client.clone();
+                service_fn(
+                    move |req: Request<Body>| -> Box<Future<Item = Response<Body>, Error = Error> + Send> {
+                        if req.method() == &Method::POST && req.uri().path() == "/ilp" {
+                            return Box::new(http_service.call(req));
+                        }
+
+                        let price = prices.price(req.uri().path());
+                        let token = req
+                            .headers()
+                            .get(PAY_TOKEN)
+                            .and_then(|token| token.to_str().ok())
+                            .map(|token| token.to_string());
+                        let spent = token
+                            .as_ref()
+                            .and_then(|token| payments.spend(token, price));
+                        match (token, spent) {
+                            (Some(token), Some(Ok(remaining))) => Box::new(forward(
+                                &client,
+                                &upstream,
+                                req,
+                                payments.clone(),
+                                token,
+                                price,
+                                remaining,
+                            )),
+                            // Ask for the rest of the price on the same connection
+                            (Some(token), Some(Err((destination_account, missing)))) => Box::new(
+                                ok(payment_required(missing, &destination_account, &token)),
+                            ),
+                            _ => match payments.new_connection(&connection_generator, &ilp_address) {
+                                Some((destination_account, token)) => Box::new(ok(
+                                    payment_required(price, &destination_account, &token),
+                                )),
+                                None => {
+                                    warn!("Too many open connections, rejecting request");
+                                    Box::new(ok(Response::builder()
+                                        .status(StatusCode::SERVICE_UNAVAILABLE)
+                                        .body(Body::empty())
+                                        .unwrap()))
+                                }
+                            },
+                        }
+                    },
+                )
+            })
+            .map_err(|err| error!("Server error: {:?}", err))
+    }
+}
+
+#[derive(Clone)]
+struct Prices {
+    default: u64,
+    paths: Vec<(String, u64)>,
+}
+
+impl Prices {
+    fn price(&self, path: &str) -> u64 {
+        self.paths
+            .iter()
+            .filter(|(prefix, _)| path.starts_with(prefix.as_str()))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(_, price)| *price)
+            .unwrap_or(self.default)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Balance {
+    received: u64,
+    spent: u64,
+}
+
+struct Connections {
+    /// The balance of each connection and when it was last used, by connection ID
+    balances: HashMap<Bytes, (Balance, Instant)>,
+    /// The destination account of the connection each Pay-Token is for and when it was created
+    tokens: HashMap<String, (Bytes, Instant)>,
+    last_pruned: Instant,
+}
+
+impl Default for Connections {
+    fn default() -> Self {
+        Connections {
+            balances: HashMap::new(),
+            tokens: HashMap::new(),
+            last_pruned: Instant::now(),
+        }
+    }
+}
+
+impl Connections {
+    /// Remove the connections that were never paid on or have not been used in a while
+    fn prune(&mut self, now: Instant) {
+        self.balances
+            .retain(|_, (_, last_used)| now.duration_since(*last_used) < IDLE_CONNECTION_TIMEOUT);
+        let balances = &self.balances;
+        self.tokens.retain(|_, (destination_account, created)| {
+            balances.contains_key(connection_id(&destination_account[..]))
+                || now.duration_since(*created) < UNPAID_CONNECTION_TIMEOUT
+        });
+        self.last_pruned = now;
+    }
+}
+
+/// Keeps track of the payments for requests in memory.
+#[derive(Clone, Default)]
+struct Payments {
+    connections: Arc<RwLock<Connections>>,
+}
+
+impl Payments {
+    /// Generate a STREAM connection for a client to pay on.
+    /// Returns the destination account and the Pay-Token (the base64url-encoded shared secret),
+    /// or `None` if too many connections are open
+    fn new_connection(
+        &self,
+        connection_generator: &ConnectionGenerator,
+        ilp_address: &[u8],
+    ) -> Option<(Bytes, String)> {
+        let now = Instant::now();
+        let mut connections = self.connections.write();
+        if now.duration_since(connections.last_pruned) >= PRUNE_INTERVAL {
+            connections.prune(now);
+        }
+        if connections.tokens.len() >= MAX_CONNECTIONS {
+            return None;
+        }
+        let (destination_account, shared_secret) =
+            connection_generator.generate_address_and_secret(ilp_address);
+        let token = base64::encode_config(&shared_secret[..], base64::URL_SAFE_NO_PAD);
+        connections
+            .tokens
+            .insert(token.clone(), (destination_account.clone(), now));
+        Some((destination_account, token))
+    }
+
+    /// Deduct the price from what was received on the token's connection and return what is left.
+    /// If not enough was received, returns the connection's destination account and how
+    /// much more is needed. Returns `None` if the token is unknown.
+    fn spend(&self, token: &str, price: u64) -> Option<Result<u64, (Bytes, u64)>> {
+        let mut connections = self.connections.write();
+        let destination_account = connections.tokens.get(token)?.0.clone();
+        let available = match connections
+            .balances
+            .get_mut(connection_id(&destination_account))
+        {
+            Some((balance, last_used)) => {
+                *last_used = Instant::now();
+                let available = balance.received - balance.spent;
+                if available >= price {
+                    balance.spent += price;
+                    return Some(Ok(available - price));
+                }
+                available
+            }
+            None if price == 0 => return Some(Ok(0)),
+            None => 0,
+        };
+        Some(Err((destination_account, price - available)))
+    }
+
+    /// Give back the price of a request that could not be forwarded
+    fn refund(&self, token: &str, price: u64) {
+        let mut connections = self.connections.write();
+        if let Some((destination_account, _)) = connections.tokens.get(token).cloned() {
+            if let Some((balance, _)) = connections
+                .balances
+                .get_mut(connection_id(&destination_account))
+            {
+                balance.spent = balance.spent.saturating_sub(price);
+            }
+        }
+    }
+}
+
+impl StreamConnectionStore<Account> for Payments {
+    fn add_amount_received(
+        &self,
+        _account: &Account,
+        connection_id: &[u8],
+        _connection_tag: Option<&[u8]>,
+        amount: u64,
+    ) -> Box<Future<Item = u64, Error = ()> + Send> {
+        let mut connections = self.connections.write();
+        let (balance, last_used) = connections
+            .balances
+            .entry(Bytes::from(connection_id))
+            .or_insert((
+                Balance {
+                    received: 0,
+                    spent: 0,
+                },
+                Instant::now(),
+            ));
+        balance.received = balance.received.saturating_add(amount);
+        *last_used = Instant::now();
+        Box::new(ok(balance.received))
+    }
+}
+
+/// The last segment of the destination account, which the STREAM receiver uses as the connection ID
+fn connection_id(destination_account: &[u8]) -> &[u8] {
+    destination_account
+        .rsplit(|c| c == &b'.')
+        .next()
+        .unwrap_or(destination_account)
+}
+
+fn payment_required(amount: u64, destination_account: &[u8], token: &str) -> Response<Body> {
+    let destination_account = str::from_utf8(destination_account).unwrap_or_default();
+    Response::builder()
+        .status(StatusCode::PAYMENT_REQUIRED)
+        .header(
+            PAY,
+            format!(
+                "interledger-stream {} {} {}",
+                amount, destination_account, token
+            ),
+        )
+        .body(Body::from(format!(
+            "Send {} to {} with STREAM and repeat the request with the Pay-Token header",
+            amount, destination_account
+        )))
+        .unwrap()
+}
+
+/// Send the request to the upstream service, refunding the price if it cannot be reached
+fn forward(
+    client: &Client<HttpConnector>,
+    upstream: &Url,
+    mut req: Request<Body>,
+    payments: Payments,
+    token: String,
+    price: u64,
+    remaining: u64,
+) -> impl Future<Item = Response<Body>, Error = Error> {
+    let mut url = upstream.clone();
+    url.set_path(&format!(
+        "{}{}",
+        upstream.path().trim_end_matches('/'),
+        req.uri().path()
+    ));
+    url.set_query(req.uri().query());
+    *req.uri_mut() = url
+        .as_str()
+        .parse::<Uri>()
+        .expect("Upstream URL is not a valid URI");
+    // The upstream service does not need to know about the payment
+    req.headers_mut().remove(PAY_TOKEN);
+    req.headers_mut().remove(HOST);
+
+    client
+        .request(req)
+        .then(move |result| -> Result<Response<Body>, Error> {
+            match result {
+                Ok(mut response) => {
+                    response
+                        .headers_mut()
+                        .insert(PAY_BALANCE, HeaderValue::from(remaining));
+                    Ok(response)
+                }
+                Err(err) => {
+                    error!("Error forwarding request to upstream service: {:?}", err);
+                    payments.refund(&token, price);
+                    Ok(Response::builder()
+                        .status(StatusCode::BAD_GATEWAY)
+                        .body(Body::empty())
+                        .unwrap())
+                }
+            }
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn uses_longest_matching_path_price() {
+        let prices = Prices {
+            default: 1,
+            paths: vec![
+                ("/api".to_string(), 10),
+                ("/api/expensive".to_string(), 100),
+            ],
+        };
+        assert_eq!(prices.price("/"), 1);
+        assert_eq!(prices.price("/api/cheap"), 10);
+        assert_eq!(prices.price("/api/expensive/thing"), 100);
+    }
+
+    #[test]
+    fn spends_what_was_received_on_the_connection() {
+        let payments = Payments::default();
+        let generator = ConnectionGenerator::new(Bytes::from(&[0; 32][..]));
+        let (destination_account, token) = payments
+            .new_connection(&generator, b"example.proxy")
+            .unwrap();
+        let account = AccountBuilder::new().build();
+
+        assert_eq!(payments.spend("unknown", 10), None);
+        assert_eq!(
+            payments.spend(&token, 10),
+            Some(Err((destination_account.clone(), 10)))
+        );
+
+        payments
+            .add_amount_received(&account, connection_id(&destination_account), None, 15)
+            .wait()
+            .unwrap();
+        assert_eq!(payments.spend(&token, 10), Some(Ok(5)));
+        assert_eq!(
+            payments.spend(&token, 10),
+            Some(Err((destination_account, 5)))
+        );
+        payments.refund(&token, 10);
+        assert_eq!(payments.spend(&token, 10), Some(Ok(5)));
+    }
+
+    #[test]
+    fn forgets_unpaid_and_idle_connections() {
+        let payments = Payments::default();
+        let generator = ConnectionGenerator::new(Bytes::from(&[0; 32][..]));
+        let account = AccountBuilder::new().build();
+        let (_, unpaid) = payments
+            .new_connection(&generator, b"example.proxy")
+            .unwrap();
+        let (destination_account, paid) = payments
+            .new_connection(&generator, b"example.proxy")
+            .unwrap();
+        payments
+            .add_amount_received(&account, connection_id(&destination_account), None, 15)
+            .wait()
+            .unwrap();
+
+        let now = Instant::now();
+        payments
+            .connections
+            .write()
+            .prune(now + UNPAID_CONNECTION_TIMEOUT);
+        assert_eq!(payments.spend(&unpaid, 10), None);
+        assert_eq!(payments.spend(&paid, 10), Some(Ok(5)));
+
+        payments
+            .connections
+            .write()
+            .prune(now + IDLE_CONNECTION_TIMEOUT * 2);
+        assert_eq!(payments.spend(&paid, 1), None);
+    }
+}
@@ -0,0 +1,95 @@
+//! # Preflight checks
+//!
+//! Checks that the node runs before it starts (and that the `node check-config` command runs
+//! on its own) so that problems with the store or the accounts' endpoints are reported up
+//! front, rather than when the first packets are sent.
+
+use futures::Future;
+use interledger_api::NodeStore;
+use interledger_btp::BtpAccount;
+use interledger_http::HttpAccount;
+use interledger_ildcp::IldcpAccount;
+use interledger_service::Account as AccountTrait;
+use interledger_store_redis::{Account, RedisStore};
+use std::net::ToSocketAddrs;
+use url::{Host, Url};
+
+/// Check that Redis accepts all of the store's scripts, that the default account (account 0)
+/// exists, and that the HTTP endpoints and BTP URIs of all of the accounts resolve.
+///
+/// Returns a description of each problem that was found.
+pub fn check_store(store: RedisStore) -> impl Future<Item = (), Error = Vec<String>> {
+    let store_clone = store.clone();
+    store
+        .check_scripts()
+        .map_err(|err| vec![err])
+        .and_then(move |_| {
+            store_clone.get_all_accounts().map_err(|err| {
+                vec![format!(
+                    "Unable to load the accounts from Redis: {:?}",
+                    err
+                )]
+            })
+        })
+        .and_then(|accounts| {
+            let mut errors = Vec::new();
+            if !accounts.iter().any(|account| account.id() == 0) {
+                errors.push(
+                    "Account 0 (the default account) does not exist, add it with `node accounts add`"
+                        .to_string(),
+                );
+            }
+            // Resolving blocks the thread, but this only runs once before the node starts
+            for account in accounts.iter() {
+                if let Some(url) = account.get_http_url() {
+                    check_resolves(&mut errors, account, "HTTP endpoint", url);
+                }
+                if let Some(url) = account.get_btp_uri() {
+                    check_resolves(&mut errors, account, "BTP URI", url);
+                }
+            }
+            if errors.is_empty() {
+                Ok(())
+            } else {
+                Err(errors)
+            }
+        })
+}
+
+fn check_resolves(errors: &mut Vec<String>, account: &Account, endpoint: &str, url: &Url) {
+    let address = String::from_utf8_lossy(&account.client_address()[..]);
+    match url.host() {
+        Some(Host::Domain(domain)) => {
+            let resolved = (domain, 0)
+                .to_socket_addrs()
+                .map(|mut addrs| addrs.next().is_some());
+            match resolved {
+                Ok(true) => {}
+                Ok(false) => errors.push(format!(
+                    "The {} of account {} ({}) cannot be reached because {} has no DNS records",
+                    endpoint,
+                    account.id(),
+                    address,
+                    domain
+                )),
+                Err(err) => errors.push(format!(
+                    "The {} of account {} ({}) cannot be reached because {} does not resolve: {}",
+                    endpoint,
+                    account.id(),
+                    address,
+                    domain,
+                    err
+                )),
+            }
+        }
+        // IP addresses do not need to be resolved
+        Some(_) => {}
+        // The URL is not printed because it may contain the account's credentials
+        None => errors.push(format!(
+            "The {} of account {} ({}) has no host",
+            endpoint,
+            account.id(),
+            address
+        )),
+    }
+}
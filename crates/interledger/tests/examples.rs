@@ -0,0 +1,117 @@
+//! Runs the examples against a node as smoke tests, using the
+//! environment variables they are configured with.
+
+use env_logger;
+use futures::Future;
+use interledger::cli;
+use std::{process::Command, thread::sleep, time::Duration};
+use tokio::runtime::Runtime;
+
+mod redis_helpers;
+use redis_helpers::*;
+
+fn get_open_port() -> u16 {
+    let listener = net2::TcpBuilder::new_v4().unwrap();
+    listener.reuse_address(true).unwrap();
+    let listener = listener.bind("127.0.0.1:0").unwrap();
+    listener.listen(1).unwrap().local_addr().unwrap().port()
+}
+
+fn account(ilp_address: &str) -> cli::AccountDetails {
+    cli::AccountDetails {
+        ilp_address: Vec::from(ilp_address),
+        asset_code: "XYZ".to_string(),
+        asset_scale: 9,
+        btp_incoming_authorization: None,
+        btp_uri: None,
+        http_endpoint: None,
+        http_incoming_authorization: None,
+        http_outgoing_authorization: None,
+        max_packet_amount: u64::max_value(),
+        max_packet_data: None,
+        priority: None,
+        max_value_per_second: None,
+        inactivity_timeout: None,
+        owner: None,
+        pending: false,
+        min_balance: -1_000_000,
+        is_admin: false,
+        read_only: false,
+        xrp_address: None,
+        settle_threshold: None,
+        settle_to: None,
+        send_routes: false,
+        receive_routes: false,
+        routing_relation: Some("Child".to_string()),
+    }
+}
+
+/// Run one of the examples with `cargo run` and return whether it exited successfully
+fn run_example(name: &str, env: &[(&str, String)]) -> bool {
+    Command::new(env!("CARGO"))
+        .args(&["run", "--quiet", "--example", name, "--manifest-path"])
+        .arg(concat!(env!("CARGO_MANIFEST_DIR"), "/Cargo.toml"))
+        .envs(env.iter().cloned())
+        .status()
+        .expect("Unable to run cargo")
+        .success()
+}
+
+#[test]
+fn send_payment_and_check_balance() {
+    let _ = env_logger::try_init();
+    let context = TestContext::new();
+    let btp_port = get_open_port();
+    let http_port = get_open_port();
+
+    let sender = cli::AccountDetails {
+        btp_incoming_authorization: Some("token-one".to_string()),
+        ..account("example.one")
+    };
+    let receiver = cli::AccountDetails {
+        http_incoming_authorization: Some("Bearer token-two".to_string()),
+        ..account("example.two")
+    };
+    let connection_info = context.get_client_connection_info();
+    let create_accounts = cli::insert_account_redis(connection_info.clone(), sender)
+        .and_then(move |_| cli::insert_account_redis(connection_info, receiver));
+    let run_node = cli::run_node_redis(
+        context.get_client_connection_info(),
+        ([127, 0, 0, 1], btp_port).into(),
+        ([127, 0, 0, 1], http_port).into(),
+        &cli::random_secret(),
+        Default::default(),
+        Duration::from_secs(300),
+    );
+    // The node keeps running on the runtime's threads while the examples run
+    let mut runtime = Runtime::new().unwrap();
+    runtime
+        .block_on(create_accounts.and_then(|_| run_node))
+        .unwrap();
+    sleep(Duration::from_millis(200));
+
+    assert!(run_example(
+        "send_payment",
+        &[
+            (
+                "ILP_BTP_URI",
+                format!("btp+ws://:token-one@localhost:{}", btp_port)
+            ),
+            (
+                "SPSP_RECEIVER",
+                format!("http://localhost:{}/spsp/1", http_port)
+            ),
+            ("AMOUNT", "1000".to_string()),
+            ("MIN_DELIVERED", "1000".to_string()),
+        ],
+    ));
+    assert!(run_example(
+        "check_balance",
+        &[
+            ("NODE_URL", format!("http://localhost:{}", http_port)),
+            ("ACCOUNT_ID", "1".to_string()),
+            ("AUTH_TOKEN", "token-two".to_string()),
+            ("MIN_BALANCE", "1000".to_string()),
+        ],
+    ));
+}
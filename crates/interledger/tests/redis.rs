@@ -3,8 +3,10 @@ extern crate interledger;
 extern crate log;
 
 use env_logger;
-use futures::{future::ok, Future};
-use interledger::cli;
+use futures::{future::ok, stream, Future, Stream};
+use hyper::{Body, Client, Request};
+use interledger::{cli, node::NodeBuilder};
+use serde_json::{json, Value};
 use std::time::{Duration, Instant};
 use tokio::{runtime::Runtime, timer::Delay};
 
@@ -57,8 +59,15 @@ fn btp_end_to_end() {
                 http_incoming_authorization: None,
                 http_outgoing_authorization: None,
                 max_packet_amount: u64::max_value(),
+                max_packet_data: None,
+                priority: None,
+                max_value_per_second: None,
+                inactivity_timeout: None,
+                owner: None,
+                pending: false,
                 min_balance: -1000000,
                 is_admin: false,
+                read_only: false,
                 xrp_address: None,
                 settle_threshold: None,
                 settle_to: None,
@@ -80,8 +89,15 @@ fn btp_end_to_end() {
                     http_incoming_authorization: None,
                     http_outgoing_authorization: None,
                     max_packet_amount: u64::max_value(),
+                    max_packet_data: None,
+                    priority: None,
+                    max_value_per_second: None,
+                    inactivity_timeout: None,
+                    owner: None,
+                    pending: false,
                     min_balance: -1000000,
                     is_admin: false,
+                    read_only: false,
                     xrp_address: None,
                     settle_threshold: None,
                     settle_to: None,
@@ -102,6 +118,8 @@ fn btp_end_to_end() {
                 ([127, 0, 0, 1], btp_port).into(),
                 ([127, 0, 0, 1], http_port).into(),
                 &cli::random_secret(),
+                Default::default(),
+                Duration::from_secs(300),
             );
             tokio::spawn(connector);
             Ok(())
@@ -131,6 +149,7 @@ fn btp_end_to_end() {
                     10000,
                     true,
                 )
+                .map_err(|err| eprintln!("{}", err))
                 .then(move |result| {
                     let _ = context;
                     result
@@ -140,3 +159,274 @@ fn btp_end_to_end() {
     let mut runtime = Runtime::new().unwrap();
     runtime.block_on(run).unwrap();
 }
+
+#[test]
+fn check_reports_unresolvable_endpoints() {
+    let _ = env_logger::try_init();
+    let context = TestContext::new();
+    let connection_info1 = context.get_client_connection_info();
+    let connection_info2 = context.get_client_connection_info();
+    let run = cli::insert_account_redis(
+        connection_info1,
+        cli::AccountDetails {
+            ilp_address: Vec::from("example.one"),
+            asset_code: "XYZ".to_string(),
+            asset_scale: 9,
+            btp_incoming_authorization: None,
+            btp_uri: None,
+            http_endpoint: Some("http://peer.invalid/ilp".to_string()),
+            http_incoming_authorization: None,
+            http_outgoing_authorization: None,
+            max_packet_amount: u64::max_value(),
+            max_packet_data: None,
+            priority: None,
+            max_value_per_second: None,
+            inactivity_timeout: None,
+            owner: None,
+            pending: false,
+            min_balance: 0,
+            is_admin: false,
+            read_only: false,
+            xrp_address: None,
+            settle_threshold: None,
+            settle_to: None,
+            send_routes: false,
+            receive_routes: false,
+            routing_relation: Some("Peer".to_string()),
+        },
+    )
+    .and_then(move |_| NodeBuilder::new(connection_info2).check().then(Ok))
+    .and_then(move |result: Result<(), Vec<String>>| {
+        let errors = result.unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].starts_with("The HTTP endpoint of account 0 (example.one)"));
+        let _ = context;
+        Ok(())
+    });
+    let mut runtime = Runtime::new().unwrap();
+    runtime.block_on(run).unwrap();
+}
+
+#[test]
+fn peering_requests_are_pending_and_rate_limited() {
+    let _ = env_logger::try_init();
+    let context = TestContext::new();
+    let connection_info = context.get_client_connection_info();
+    let http_port = get_open_port(None);
+    let btp_port = get_open_port(None);
+    let url = format!("http://localhost:{}/peering_requests", http_port);
+    let post = move |index: usize| {
+        let body = json!({
+            "ilp_address": format!("example.peer{}", index).into_bytes(),
+            "asset_code": "XYZ",
+            "asset_scale": 9,
+            "max_packet_amount": 100,
+            "http_endpoint": null,
+            "http_incoming_authorization": null,
+            "http_outgoing_authorization": null,
+            "btp_uri": "btp+ws://:token@internal.invalid",
+            "btp_incoming_authorization": format!("token-{}", index),
+            "is_admin": true,
+            "xrp_address": null,
+            "settle_threshold": null,
+            "settle_to": null,
+            "routing_relation": "Peer"
+        });
+        Client::new()
+            .request(
+                Request::post(url.as_str())
+                    .header("Content-Type", "application/json")
+                    .body(Body::from(body.to_string()))
+                    .unwrap(),
+            )
+            .and_then(|response| {
+                let status = response.status().as_u16();
+                response
+                    .into_body()
+                    .concat2()
+                    .map(move |body| (status, serde_json::from_slice(&body).ok()))
+            })
+            .map_err(|err| panic!(err))
+    };
+    let run = NodeBuilder::new(connection_info)
+        .btp_address(([127, 0, 0, 1], btp_port).into())
+        .http_address(([127, 0, 0, 1], http_port).into())
+        .accept_peering_requests(true)
+        .run()
+        .and_then(|_node| delay(200))
+        .and_then(move |_| {
+            stream::iter_ok(0..11).and_then(post).collect().map(
+                move |responses: Vec<(u16, Option<Value>)>| {
+                    let account = responses[0].1.clone().unwrap();
+                    assert_eq!(responses[0].0, 200);
+                    assert_eq!(account["pending"], json!(true));
+                    assert_eq!(account["is_admin"], json!(false));
+                    assert!(account["btp_uri"].is_null());
+                    assert!(responses[..10].iter().all(|(status, _)| *status == 200));
+                    assert_eq!(responses[10].0, 429);
+                    let _ = context;
+                },
+            )
+        });
+    let mut runtime = Runtime::new().unwrap();
+    runtime.block_on(run).unwrap();
+}
+
+#[test]
+fn node_starts_after_its_self_test_payment() {
+    let _ = env_logger::try_init();
+    let context = TestContext::new();
+    let connection_info1 = context.get_client_connection_info();
+    let connection_info2 = context.get_client_connection_info();
+    let http_port = get_open_port(None);
+    let btp_port = get_open_port(None);
+    let run = cli::insert_account_redis(
+        connection_info1,
+        cli::AccountDetails {
+            ilp_address: Vec::from("example.node"),
+            asset_code: "XYZ".to_string(),
+            asset_scale: 9,
+            btp_incoming_authorization: None,
+            btp_uri: None,
+            http_endpoint: None,
+            http_incoming_authorization: None,
+            http_outgoing_authorization: None,
+            max_packet_amount: u64::max_value(),
+            max_packet_data: None,
+            priority: None,
+            max_value_per_second: None,
+            inactivity_timeout: None,
+            owner: None,
+            pending: false,
+            min_balance: -1000000,
+            is_admin: false,
+            read_only: false,
+            xrp_address: None,
+            settle_threshold: None,
+            settle_to: None,
+            send_routes: false,
+            receive_routes: false,
+            routing_relation: Some("Peer".to_string()),
+        },
+    )
+    .and_then(move |_| {
+        NodeBuilder::new(connection_info2)
+            .btp_address(([127, 0, 0, 1], btp_port).into())
+            .http_address(([127, 0, 0, 1], http_port).into())
+            .self_test(100)
+            .run()
+    })
+    .and_then(move |_node| {
+        // The API is up as soon as the node has started
+        Client::new()
+            .get(format!("http://localhost:{}/", http_port).parse().unwrap())
+            .map(move |response| {
+                assert_eq!(response.status().as_u16(), 200);
+                let _ = context;
+            })
+            .map_err(|err| panic!(err))
+    });
+    let mut runtime = Runtime::new().unwrap();
+    runtime.block_on(run).unwrap();
+}
+
+#[test]
+fn gets_balances_of_an_owners_accounts() {
+    let _ = env_logger::try_init();
+    let context = TestContext::new();
+    let connection_info = context.get_client_connection_info();
+    let http_port = get_open_port(None);
+    let btp_port = get_open_port(None);
+    let account = |ilp_address: &str, asset_code: &str, owner: Option<&str>| cli::AccountDetails {
+        ilp_address: Vec::from(ilp_address),
+        asset_code: asset_code.to_string(),
+        asset_scale: 9,
+        btp_incoming_authorization: None,
+        btp_uri: None,
+        http_endpoint: None,
+        http_incoming_authorization: None,
+        http_outgoing_authorization: None,
+        max_packet_amount: u64::max_value(),
+        max_packet_data: None,
+        priority: None,
+        max_value_per_second: None,
+        inactivity_timeout: None,
+        owner: owner.map(String::from),
+        pending: false,
+        min_balance: 0,
+        is_admin: false,
+        read_only: false,
+        xrp_address: None,
+        settle_threshold: None,
+        settle_to: None,
+        send_routes: false,
+        receive_routes: false,
+        routing_relation: None,
+    };
+    let accounts = vec![
+        cli::AccountDetails {
+            http_incoming_authorization: Some("Bearer admin".to_string()),
+            is_admin: true,
+            ..account("example.admin", "XYZ", None)
+        },
+        account("example.alice.1", "XYZ", Some("alice")),
+        account("example.alice.2", "ABC", Some("alice")),
+        account("example.bob", "XYZ", Some("bob")),
+    ];
+    let get = move |path: &str, authorization: &str| {
+        Client::new()
+            .request(
+                Request::get(format!("http://localhost:{}{}", http_port, path).as_str())
+                    .header("Authorization", authorization)
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .and_then(|response| {
+                let status = response.status().as_u16();
+                response
+                    .into_body()
+                    .concat2()
+                    .map(move |body| (status, serde_json::from_slice(&body).ok()))
+            })
+            .map_err(|err| panic!(err))
+    };
+    let run = stream::iter_ok(accounts)
+        .for_each(move |account| cli::insert_account_redis(connection_info.clone(), account))
+        .and_then(move |_| {
+            NodeBuilder::new(context.get_client_connection_info())
+                .btp_address(([127, 0, 0, 1], btp_port).into())
+                .http_address(([127, 0, 0, 1], http_port).into())
+                .run()
+                .map(move |_node| context)
+        })
+        .and_then(move |context| {
+            get("/owners/alice/balance", "Bearer admin")
+                .join4(
+                    get("/owners/alice/balance/history", "Bearer admin"),
+                    get("/owners/carol/balance", "Bearer admin"),
+                    get("/owners/alice/balance", "Bearer other"),
+                )
+                .map(
+                    move |(balance, history, unknown, unauthorized): (
+                        (u16, Option<Value>),
+                        (u16, Option<Value>),
+                        (u16, Option<Value>),
+                        (u16, Option<Value>),
+                    )| {
+                        assert_eq!(balance.0, 200);
+                        let balance = balance.1.unwrap();
+                        assert_eq!(balance["accounts"].as_array().unwrap().len(), 2);
+                        assert_eq!(balance["totals"][0]["asset_code"], json!("ABC"));
+                        assert_eq!(balance["totals"][1]["asset_code"], json!("XYZ"));
+                        assert_eq!(balance["totals"][1]["balance"], json!("0"));
+                        assert_eq!(history.0, 200);
+                        assert_eq!(history.1.unwrap()["assets"].as_array().unwrap().len(), 2);
+                        assert_eq!(unknown.0, 404);
+                        assert_eq!(unauthorized.0, 401);
+                        let _ = context;
+                    },
+                )
+        });
+    let mut runtime = Runtime::new().unwrap();
+    runtime.block_on(run).unwrap();
+}
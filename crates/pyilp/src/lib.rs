@@ -0,0 +1,92 @@
+//! # pyilp
+//!
+//! Python bindings for resolving Payment Pointers and sending SPSP payments with Interledger.rs.
+//!
+//! pyo3 requires a nightly compiler. Build the package with `pyo3-pack build` (or
+//! `pyo3-pack develop` to install it into the current virtualenv), then:
+//!
+//! ```python
+//! import pyilp
+//! receiver = pyilp.resolve("$example.com/alice")
+//! delivered = pyilp.pay_btp("btp+ws://:token@localhost:7768", "$example.com/alice", 1000)
+//! ```
+//!
+//! The node itself can be managed from Python through its HTTP API.
+
+#![feature(specialization)]
+
+use futures::Future;
+use interledger::{
+    cli::{send_spsp_payment_btp, send_spsp_payment_http},
+    spsp::query,
+};
+use pyo3::{
+    exceptions::RuntimeError,
+    prelude::*,
+    types::{PyBytes, PyDict},
+    wrap_pyfunction,
+};
+use tokio::runtime::Runtime;
+
+fn block_on<F, T>(future: F) -> PyResult<T>
+where
+    F: Future<Item = T> + Send + 'static,
+    F::Error: std::fmt::Display + Send,
+    T: Send + 'static,
+{
+    let mut runtime = Runtime::new().map_err(|err| RuntimeError::py_err(err.to_string()))?;
+    runtime
+        .block_on(future)
+        .map_err(|err| RuntimeError::py_err(err.to_string()))
+}
+
+/// resolve(payment_pointer)
+/// --
+///
+/// Query the SPSP receiver and return a dict with its `destination_account` and `shared_secret`.
+#[pyfunction]
+fn resolve(py: Python, payment_pointer: &str) -> PyResult<PyObject> {
+    let payment_pointer = payment_pointer.to_string();
+    let response = py.allow_threads(move || block_on(query(&payment_pointer)))?;
+    let dict = PyDict::new(py);
+    dict.set_item("destination_account", response.destination_account())?;
+    dict.set_item("shared_secret", PyBytes::new(py, response.shared_secret()))?;
+    Ok(dict.into())
+}
+
+/// pay_btp(btp_server, receiver, amount)
+/// --
+///
+/// Send a payment through a BTP server such as moneyd. Returns the amount delivered,
+/// in the receiver's units.
+#[pyfunction]
+fn pay_btp(py: Python, btp_server: &str, receiver: &str, amount: u64) -> PyResult<u64> {
+    let (btp_server, receiver) = (btp_server.to_string(), receiver.to_string());
+    py.allow_threads(move || block_on(send_spsp_payment_btp(&btp_server, &receiver, amount, true)))
+}
+
+/// pay_http(http_server, receiver, amount)
+/// --
+///
+/// Send a payment through a node using ILP-over-HTTP. The credentials are included in the URL.
+/// Returns the amount delivered, in the receiver's units.
+#[pyfunction]
+fn pay_http(py: Python, http_server: &str, receiver: &str, amount: u64) -> PyResult<u64> {
+    let (http_server, receiver) = (http_server.to_string(), receiver.to_string());
+    py.allow_threads(move || {
+        block_on(send_spsp_payment_http(
+            &http_server,
+            &receiver,
+            amount,
+            true,
+        ))
+    })
+}
+
+#[pymodule]
+fn pyilp(_py: Python, m: &PyModule) -> PyResult<()> {
+    m.add_wrapped(wrap_pyfunction!(resolve))?;
+    m.add_wrapped(wrap_pyfunction!(pay_btp))?;
+    m.add_wrapped(wrap_pyfunction!(pay_http))?;
+    Ok(())
+}
@@ -0,0 +1,29 @@
+-- Same as the XRP engine's script, except that amounts are left in the account's own units
+-- and no ledger address is required.
+-- The cursor is used to page through the results.
+-- This script should be called first with '0'.
+-- If the cursor returned is '0', that means it has finished going through all of the accounts.
+local balances_key = 'balances:' .. ARGV[1]
+local cursor = ARGV[2]
+local stop_after_num_accounts = tonumber(ARGV[3]) or 20
+
+local accounts = {}
+local balances = nil
+
+repeat
+    cursor, balances = unpack(redis.call('HSCAN', balances_key, cursor))
+    for i = 1, table.getn(balances), 2 do
+        local account = balances[i]
+        local balance = tonumber(balances[i + 1])
+
+        local settle_threshold, settle_to = unpack(redis.call('HMGET', 'accounts:' .. account, 'settle_threshold', 'settle_to'))
+        if settle_threshold and settle_to and balance >= tonumber(settle_threshold) then
+            local amount_to_settle = balance - tonumber(settle_to)
+            if amount_to_settle > 0 then
+                table.insert(accounts, {account, amount_to_settle})
+            end
+        end
+    end
+until table.getn(accounts) >= stop_after_num_accounts or cursor == '0'
+
+return {cursor, accounts}
@@ -0,0 +1,11 @@
+-- Adjusts an account's balance by the given amount.
+-- Outgoing settlements lower the balance (we owe them less), incoming settlements raise it.
+local balances_key = 'balances:' .. ARGV[1]
+local account = ARGV[2]
+local amount = tonumber(ARGV[3])
+
+if not (account and amount) then
+    error('account and amount are required')
+end
+
+return redis.call('HINCRBY', balances_key, account, amount)
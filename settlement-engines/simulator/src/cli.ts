@@ -0,0 +1,37 @@
+#!/usr/bin/env node
+
+import * as yargs from 'yargs'
+import { SimulatedSettlementEngine } from './index'
+
+const argv = yargs.option('asset_code', {
+    description: 'Asset code of the accounts to settle',
+    type: 'string'
+}).option('redis', {
+    description: 'Redis URI to connect to',
+    default: 'redis://localhost:6379',
+    type: 'string'
+}).option('poll_interval', {
+    default: 1000,
+    type: 'number',
+    description: 'Interval, denominated in milliseconds, to poll the Redis database for changes in account balances'
+}).option('latency', {
+    default: 0,
+    type: 'number',
+    description: 'Simulated time, denominated in milliseconds, each settlement takes to complete'
+}).option('failure_rate', {
+    default: 0,
+    type: 'number',
+    description: 'Probability, from 0 to 1, that a settlement fails'
+}).require(['asset_code'])
+    .argv
+
+const engine = new SimulatedSettlementEngine({
+  assetCode: argv.asset_code,
+  redisUri: argv.redis,
+  pollInterval: argv.poll_interval,
+  latency: argv.latency,
+  failureRate: argv.failure_rate
+})
+engine.connect().then(() => {
+  console.log('Polling Redis for accounts that need to be settled')
+}).catch((err) => console.error(err))
@@ -0,0 +1,145 @@
+import { RedisClient, createClient } from 'redis'
+import { readFile } from 'fs'
+import { promisify } from 'util'
+import Debug from 'debug'
+import * as path from 'path'
+import { createHash } from 'crypto'
+const debug = Debug('simulated-settlement-engine')
+const readFileAsync = promisify(readFile)
+
+const DEFAULT_POLL_INTERVAL = 1000
+const KEY_ARGS = 0
+
+export interface SimulatedSettlementEngineConfig {
+  // Lowercase asset code of the accounts this engine settles (the same as the balances:{asset_code} key)
+  assetCode: string,
+  redisUri?: string,
+  pollInterval?: number,
+  // Simulated time, in milliseconds, it takes the "ledger" to process a settlement
+  latency?: number,
+  // Probability, from 0 to 1, that a settlement fails
+  failureRate?: number
+}
+
+export interface SettlementRecord {
+  account: string,
+  amount: number,
+  success: boolean
+}
+
+/**
+ * A settlement engine that does not talk to any ledger.
+ *
+ * It polls Redis in the same way as the real settlement engines but simply
+ * adjusts the account balances after the configured latency, optionally
+ * failing some fraction of settlements. This makes it possible to test the
+ * settlement flow end-to-end without connecting to a real ledger.
+ */
+export class SimulatedSettlementEngine {
+  private redisClient: RedisClient
+  private assetCode: string
+  private pollInterval: number
+  private latency: number
+  private failureRate: number
+  private interval: NodeJS.Timeout
+  private inFlight: Set<string>
+  // Every settlement that was attempted, so tests can make assertions about them
+  readonly settlements: SettlementRecord[]
+  private getAccountsThatNeedSettlement: any
+  private updateBalance: any
+
+  constructor (config: SimulatedSettlementEngineConfig) {
+    this.assetCode = config.assetCode.toLowerCase()
+    this.redisClient = createClient(config.redisUri || 'redis://localhost:6379')
+    this.pollInterval = config.pollInterval || DEFAULT_POLL_INTERVAL
+    this.latency = config.latency || 0
+    this.failureRate = config.failureRate || 0
+    this.inFlight = new Set()
+    this.settlements = []
+  }
+
+  async connect (): Promise<void> {
+    await new Promise((resolve, reject) => {
+      this.redisClient.once('ready', resolve)
+      this.redisClient.once('error', reject)
+    })
+    debug('Connected to Redis')
+    await this.loadScripts()
+
+    debug(`Setting up to poll for balance changes every ${this.pollInterval}ms`)
+    await this.checkAccounts()
+    this.interval = setInterval(() => this.checkAccounts(), this.pollInterval)
+  }
+
+  async disconnect (): Promise<void> {
+    debug('Disconnecting')
+    if (this.interval) {
+      clearInterval(this.interval)
+    }
+    await promisify(this.redisClient.quit.bind(this.redisClient))()
+    debug('Disconnected')
+  }
+
+  /**
+   * Simulate receiving a settlement from the given account.
+   */
+  async receiveSettlement (account: string, amount: number): Promise<number> {
+    await delay(this.latency)
+    const newBalance = await this.updateBalance(this.assetCode, account, amount)
+    debug(`Credited account: ${account} for incoming settlement, balance is now: ${newBalance}`)
+    return newBalance
+  }
+
+  private async loadScripts () {
+    debug('Loading scripts into Redis')
+    const loadScript = promisify(this.redisClient.script.bind(this.redisClient, 'load'))
+    const load = async (name: string) => {
+      const script = await readFileAsync(path.join(__dirname, `../scripts/${name}.lua`), 'utf8')
+      await loadScript(script)
+      const hash = createHash('sha1').update(script).digest('hex')
+      return promisify(this.redisClient.evalsha.bind(this.redisClient, hash, KEY_ARGS))
+    }
+    this.getAccountsThatNeedSettlement = await load('get_accounts_that_need_settlement')
+    this.updateBalance = await load('update_balance')
+    debug('Loaded scripts')
+  }
+
+  private async checkAccounts () {
+    let cursor = '0'
+    do {
+      const [newCursor, accountsToSettle] = await this.getAccountsThatNeedSettlement(this.assetCode, cursor, 20)
+      cursor = newCursor
+      for (let [account, amount] of accountsToSettle) {
+        this.settle(account, amount)
+      }
+    }
+    while (cursor !== '0')
+  }
+
+  private async settle (account: string, amount: number) {
+    // Don't settle the same account twice while the simulated ledger is "processing" the first settlement
+    if (this.inFlight.has(account)) {
+      return
+    }
+    this.inFlight.add(account)
+    try {
+      await delay(this.latency)
+      if (Math.random() < this.failureRate) {
+        debug(`Simulating failed settlement of ${amount} to account: ${account}`)
+        this.settlements.push({ account, amount, success: false })
+        return
+      }
+      const newBalance = await this.updateBalance(this.assetCode, account, 0 - amount)
+      this.settlements.push({ account, amount, success: true })
+      debug(`Settled ${amount} to account: ${account}, balance is now: ${newBalance}`)
+    } catch (err) {
+      console.error(`Error updating balance after settling ${amount} to account: ${account}:`, err)
+    } finally {
+      this.inFlight.delete(account)
+    }
+  }
+}
+
+function delay (ms: number): Promise<void> {
+  return new Promise((resolve) => setTimeout(resolve, ms))
+}
@@ -0,0 +1,27 @@
+-- Credits the account for the part of a payment channel claim it has not been credited for yet.
+-- The amount credited for each channel is kept in the xrp_incoming_claims_credited hash
+-- (channel -> drops) so a claim is never credited twice, even by multiple engine instances.
+local account = ARGV[1]
+local channel = ARGV[2]
+local claim_drops = tonumber(ARGV[3])
+
+if not (account and channel and claim_drops) then
+    error('account, channel, and drops are required')
+end
+
+local asset_scale = redis.call('HGET', 'accounts:' .. account, 'asset_scale')
+if not asset_scale then
+    error('account ' .. account .. ' is missing asset_scale')
+end
+
+local credited = tonumber(redis.call('HGET', 'xrp_incoming_claims_credited', channel) or '0')
+if claim_drops <= credited then
+    return {0, redis.call('HGET', 'balances:xrp', account) or 0}
+end
+redis.call('HSET', 'xrp_incoming_claims_credited', channel, ARGV[3])
+
+-- XRP drops have a scale of 6
+local amount = math.floor((claim_drops - credited) * 10 ^ (asset_scale - 6))
+-- The balance represents how much we owe them so raise it to reflect that they settled with us
+local new_balance = redis.call('HINCRBY', 'balances:xrp', account, amount)
+return {claim_drops - credited, new_balance}
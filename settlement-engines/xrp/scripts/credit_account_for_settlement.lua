@@ -1,5 +1,6 @@
 local xrp_address = ARGV[1]
 local drops = ARGV[2]
+local tx_hash = ARGV[3]
 
 if not (xrp_address and drops) then
     error('xrp_address and drops are required')
@@ -7,6 +8,12 @@ end
 
 local account = redis.call('HGET', 'xrp_addresses', ARGV[1])
 if account then
+    -- Use the shared idempotency registry so each transaction is only credited once,
+    -- even if multiple settlement engine instances see it
+    if tx_hash and not redis.call('SET', 'idempotency:xrp_tx:' .. tx_hash, 1, 'NX', 'EX', 604800) then
+        return {}
+    end
+
     local asset_scale = tonumber(redis.call('HGET', 'accounts:' .. account, 'asset_scale'))
     local scaled_amount = math.floor(drops * 10 ^ (asset_scale - 6))
     local new_balance = redis.call('HINCRBY', 'balances:xrp', '' .. account, scaled_amount)
@@ -24,8 +24,10 @@ repeat
         local balance = balances[i + 1]
 
         local settle_threshold = redis.call('HGET', 'accounts:' .. account, 'settle_threshold')
+        -- Skip accounts that already have a settlement waiting to be retried so we don't settle twice
+        local is_pending = redis.call('HEXISTS', 'xrp_pending_settlements', account) == 1
         -- Note: this ignores accounts that are missing any of the required details
-        if settle_threshold then
+        if settle_threshold and not is_pending then
             -- Check whether this account needs to settle
             if balance >= settle_threshold then
                 local xrp_address, asset_scale, settle_to = unpack(redis.call('HMGET', 'accounts:' .. account, 'xrp_address', 'asset_scale', 'settle_to'))
@@ -0,0 +1,26 @@
+-- Returns the pending settlements whose next retry time has passed.
+-- Each entry is {account, xrp_address, drops, attempts}.
+local now = tonumber(ARGV[1])
+local max_settlements = tonumber(ARGV[2]) or 20
+
+if not now then
+    error('now is required')
+end
+
+local settlements = {}
+local due = redis.call('ZRANGEBYSCORE', 'xrp_settlement_retries', 0, now, 'LIMIT', 0, max_settlements)
+for i = 1, table.getn(due) do
+    local account = due[i]
+    local pending = redis.call('HGET', 'xrp_pending_settlements', account)
+    if pending then
+        local xrp_address, drops, attempts = string.match(pending, '([^:]*):([^:]*):([^:]*)')
+        table.insert(settlements, {account, xrp_address, drops, attempts})
+        -- Remove it from the schedule so it is not picked up again while the retry is in flight.
+        -- If the retry fails, it will be rescheduled by queue_pending_settlement.lua
+        redis.call('ZREM', 'xrp_settlement_retries', account)
+    else
+        redis.call('ZREM', 'xrp_settlement_retries', account)
+    end
+end
+
+return settlements
@@ -0,0 +1,28 @@
+-- Records a settlement that could not be completed so that it is retried later.
+-- Pending settlements are stored in the xrp_pending_settlements hash (account -> xrp_address, drops, attempts)
+-- and scheduled in the xrp_settlement_retries sorted set (account -> timestamp of the next retry).
+local account = ARGV[1]
+local xrp_address = ARGV[2]
+local drops = ARGV[3]
+local now = tonumber(ARGV[4])
+local retry_interval = tonumber(ARGV[5])
+local max_retry_delay = tonumber(ARGV[6])
+
+if not (account and xrp_address and drops and now and retry_interval and max_retry_delay) then
+    error('account, xrp_address, drops, now, retry_interval, and max_retry_delay are required')
+end
+
+local attempts = 1
+local pending = redis.call('HGET', 'xrp_pending_settlements', account)
+if pending then
+    local _, _, previous_attempts = string.match(pending, '([^:]*):([^:]*):([^:]*)')
+    attempts = (tonumber(previous_attempts) or 0) + 1
+end
+
+redis.call('HSET', 'xrp_pending_settlements', account, xrp_address .. ':' .. drops .. ':' .. attempts)
+
+-- Back off exponentially, up to the max retry delay
+local delay = math.min(retry_interval * 2 ^ (attempts - 1), max_retry_delay)
+redis.call('ZADD', 'xrp_settlement_retries', now + delay, account)
+
+return attempts
@@ -0,0 +1,17 @@
+-- Saves a payment channel claim a peer sent us if it is for more than the best claim we have
+-- for the same channel. Claims are stored in the xrp_incoming_claims hash (channel -> JSON claim).
+local channel = ARGV[1]
+local drops = tonumber(ARGV[2])
+local claim = ARGV[3]
+
+if not (channel and drops and claim) then
+    error('channel, drops, and claim are required')
+end
+
+local existing = redis.call('HGET', 'xrp_incoming_claims', channel)
+if existing and tonumber(cjson.decode(existing).amount) >= drops then
+    return 0
+end
+
+redis.call('HSET', 'xrp_incoming_claims', channel, claim)
+return 1
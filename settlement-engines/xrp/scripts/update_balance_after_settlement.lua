@@ -15,4 +15,8 @@ local amount = math.floor(drops * 10 ^ (asset_scale - 6))
 -- The balance represents how much we owe them so lower it to reflect that we settled
 local new_balance = redis.call('HINCRBY', 'balances:xrp', account, 0 - amount)
 
+-- The settlement went through so it no longer needs to be retried
+redis.call('HDEL', 'xrp_pending_settlements', account)
+redis.call('ZREM', 'xrp_settlement_retries', account)
+
 return new_balance
\ No newline at end of file
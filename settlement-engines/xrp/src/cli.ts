@@ -32,6 +32,38 @@ const argv = yargs.option('address', {
     default: 60000,
     type: 'number',
     description: 'Interval, denominated in milliseconds, to poll the Redis database for changes in account balances'
+}).option('retry_interval', {
+    default: 5000,
+    type: 'number',
+    description: 'Initial delay, denominated in milliseconds, before retrying a settlement that failed. The delay doubles after each failed attempt'
+}).option('max_retry_delay', {
+    default: 600000,
+    type: 'number',
+    description: 'Maximum delay, denominated in milliseconds, between retries of a failed settlement'
+}).option('paychan', {
+    default: false,
+    type: 'boolean',
+    description: 'Settle using XRP payment channel claims instead of on-ledger payments'
+}).option('channel_amount', {
+    default: 10000000000,
+    type: 'number',
+    description: 'Amount, denominated in XRP drops, to fund outgoing payment channels with'
+}).option('settle_delay', {
+    default: 86400,
+    type: 'number',
+    description: 'Number of seconds the peer has to submit their latest claim after we close a payment channel'
+}).option('claim_interval', {
+    default: 60000,
+    type: 'number',
+    description: 'Interval, denominated in milliseconds, to check payment channels and submit incoming claims to the ledger'
+}).option('min_incoming_settle_delay', {
+    default: 3600,
+    type: 'number',
+    description: 'Minimum number of seconds peers\' payment channels must give us to submit our latest claim after they close the channel'
+}).option('port', {
+    default: 3001,
+    type: 'number',
+    description: 'Port to listen on for payment channel claims sent by peers\' settlement engines. The node\'s settlement_engine_url should point here'
 }).require(['address', 'secret'])
     .argv
 
@@ -41,7 +73,16 @@ const config = {
   rippledUri: (argv.testnet ? testnetServer : argv.rippled),
   redisUri: argv.redis,
   minSettlementAmount: argv.min_settlement_amount,
-  pollInterval: argv.poll_interval
+  pollInterval: argv.poll_interval,
+  retryInterval: argv.retry_interval,
+  maxRetryDelay: argv.max_retry_delay,
+  paychan: (argv.paychan ? {
+    channelAmountDrops: argv.channel_amount,
+    settleDelay: argv.settle_delay,
+    minIncomingSettleDelay: argv.min_incoming_settle_delay,
+    claimInterval: argv.claim_interval,
+    port: argv.port
+  } : undefined)
 }
 
 const engine = new XrpSettlementEngine(config)
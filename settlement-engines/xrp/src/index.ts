@@ -5,11 +5,15 @@ import { promisify } from 'util'
 import Debug from 'debug'
 import * as path from 'path'
 import { createHash } from 'crypto'
+import { PaymentChannelManager, PaymentChannelConfig, SettlementInProgressError } from './paychan'
+import { recordClaim } from './receipts'
 const debug = Debug('xrp-settlement-engine')
 const readFileAsync = promisify(readFile)
 
 // TODO should the settlement engine go through and make sure all of the xrp_addresses are stored in this hash map?
 const DEFAULT_POLL_INTERVAL = 60000
+const DEFAULT_RETRY_INTERVAL = 5000
+const DEFAULT_MAX_RETRY_DELAY = 600000
 const KEY_ARGS = 0
 
 export interface XrpSettlementEngineConfig {
@@ -18,7 +22,11 @@ export interface XrpSettlementEngineConfig {
   rippledUri?: string,
   redisUri?: string,
   minSettlementDrops?: number,
-  pollInterval?: number
+  pollInterval?: number,
+  retryInterval?: number,
+  maxRetryDelay?: number,
+  // Settle using payment channel claims instead of on-ledger payments
+  paychan?: PaymentChannelConfig
 }
 
 export class XrpSettlementEngine {
@@ -28,11 +36,19 @@ export class XrpSettlementEngine {
   private secret: string
   private minDropsToSettle: number
   private pollInterval: number
+  private retryInterval: number
+  private maxRetryDelay: number
   private interval: NodeJS.Timeout
+  private retryTimer: NodeJS.Timeout
+  private paychan?: PaymentChannelManager
     // TODO add type annotations to these arrow functions
   private getAccountsThatNeedSettlement: any
   private creditAccountForSettlement: any
   private updateBalanceAfterSettlement: any
+  private queuePendingSettlement: any
+  private getPendingSettlementsDue: any
+  private creditAccountForClaim: any
+  private saveIncomingClaim: any
 
   constructor (config: XrpSettlementEngineConfig) {
     this.address = config.address
@@ -43,6 +59,11 @@ export class XrpSettlementEngine {
       server: config.rippledUri || 'wss://s.altnet.rippletest.net:51233'// 'wss://s1.ripple.com'
     })
     this.pollInterval = config.pollInterval || DEFAULT_POLL_INTERVAL
+    this.retryInterval = config.retryInterval || DEFAULT_RETRY_INTERVAL
+    this.maxRetryDelay = config.maxRetryDelay || DEFAULT_MAX_RETRY_DELAY
+    if (config.paychan) {
+      this.paychan = new PaymentChannelManager(this.rippleClient, this.redisClient, this.address, this.secret, config.paychan)
+    }
   }
 
   async connect (): Promise<void> {
@@ -63,6 +84,15 @@ export class XrpSettlementEngine {
     await this.checkAccounts()
     this.interval = setInterval(() => this.checkAccounts(), this.pollInterval)
 
+        // Settlements that failed are kept in Redis and retried until the ledger accepts them
+    debug(`Setting up to retry pending settlements every ${this.retryInterval}ms`)
+    await this.retryPendingSettlements()
+    this.retryTimer = setInterval(() => this.retryPendingSettlements(), this.retryInterval)
+
+    if (this.paychan) {
+      await this.paychan.connect(this.creditAccountForClaim, this.saveIncomingClaim)
+    }
+
         // Subscribe to rippled events to be notified of incoming payments
     this.rippleClient.connection.on('transaction', this.handleTransaction.bind(this))
     await this.rippleClient.request('subscribe', {
@@ -90,6 +120,26 @@ export class XrpSettlementEngine {
     const updateBalanceAfterSettlementScriptHash = createHash('sha1').update(updateBalanceAfterSettlementScript).digest('hex')
     this.updateBalanceAfterSettlement = promisify(this.redisClient.evalsha.bind(this.redisClient, updateBalanceAfterSettlementScriptHash, KEY_ARGS))
 
+    const queuePendingSettlementScript = await readFileAsync(path.join(__dirname, '../scripts/queue_pending_settlement.lua'), 'utf8')
+    await loadScript(queuePendingSettlementScript)
+    const queuePendingSettlementScriptHash = createHash('sha1').update(queuePendingSettlementScript).digest('hex')
+    this.queuePendingSettlement = promisify(this.redisClient.evalsha.bind(this.redisClient, queuePendingSettlementScriptHash, KEY_ARGS))
+
+    const getPendingSettlementsDueScript = await readFileAsync(path.join(__dirname, '../scripts/get_pending_settlements_due.lua'), 'utf8')
+    await loadScript(getPendingSettlementsDueScript)
+    const getPendingSettlementsDueScriptHash = createHash('sha1').update(getPendingSettlementsDueScript).digest('hex')
+    this.getPendingSettlementsDue = promisify(this.redisClient.evalsha.bind(this.redisClient, getPendingSettlementsDueScriptHash, KEY_ARGS))
+
+    const creditAccountForClaimScript = await readFileAsync(path.join(__dirname, '../scripts/credit_account_for_claim.lua'), 'utf8')
+    await loadScript(creditAccountForClaimScript)
+    const creditAccountForClaimScriptHash = createHash('sha1').update(creditAccountForClaimScript).digest('hex')
+    this.creditAccountForClaim = promisify(this.redisClient.evalsha.bind(this.redisClient, creditAccountForClaimScriptHash, KEY_ARGS))
+
+    const saveIncomingClaimScript = await readFileAsync(path.join(__dirname, '../scripts/save_incoming_claim.lua'), 'utf8')
+    await loadScript(saveIncomingClaimScript)
+    const saveIncomingClaimScriptHash = createHash('sha1').update(saveIncomingClaimScript).digest('hex')
+    this.saveIncomingClaim = promisify(this.redisClient.evalsha.bind(this.redisClient, saveIncomingClaimScriptHash, KEY_ARGS))
+
     debug('Loaded scripts')
   }
 
@@ -98,6 +148,12 @@ export class XrpSettlementEngine {
     if (this.interval) {
       clearInterval(this.interval)
     }
+    if (this.retryTimer) {
+      clearInterval(this.retryTimer)
+    }
+    if (this.paychan) {
+      this.paychan.disconnect()
+    }
     await Promise.all([
       this.rippleClient.disconnect().then(() => debug('Disconnected from rippled')),
       promisify(this.redisClient.quit.bind(this.redisClient))().then(() => debug('Disconnected from Redis'))
@@ -125,37 +181,87 @@ export class XrpSettlementEngine {
     return newCursor
   }
 
+  private async retryPendingSettlements () {
+    let pending
+    try {
+      pending = await this.getPendingSettlementsDue(Date.now(), 20)
+    } catch (err) {
+      console.error('Error loading pending settlements from Redis:', err)
+      return
+    }
+
+    for (let pendingRecord of pending) {
+      const [account, xrpAddress, drops, attempts] = pendingRecord
+      debug(`Retrying settlement of ${drops} drops to account: ${account} (attempt ${parseInt(attempts, 10) + 1})`)
+      this.settle(account, xrpAddress, drops)
+    }
+  }
+
   private async settle (account: string, xrpAddress: string, drops: string) {
     debug(`Attempting to send ${drops} XRP drops to account: ${account} (XRP address: ${xrpAddress})`)
     try {
-      const payment = await this.rippleClient.preparePayment(this.address, {
-        source: {
-          address: this.address,
-          amount: {
-            value: '' + drops,
-            currency: 'drops'
-          }
-        },
-        destination: {
-          address: xrpAddress,
-          minAmount: {
-            value: '' + drops,
-            currency: 'drops'
-          }
-        }
-      }, {
-                    // TODO add max fee
-        maxLedgerVersionOffset: 5
-      })
-      const { signedTransaction } = this.rippleClient.sign(payment.txJSON, this.secret)
-      const result = await this.rippleClient.submit(signedTransaction)
-      if (result.resultCode === 'tesSUCCESS') {
-        debug(`Sent ${drops} drop payment to account: ${account} (xrpAddress: ${xrpAddress})`)
-        const newBalance = await this.updateBalanceAfterSettlement(account, drops)
-        debug(`Account ${account} now has balance: ${newBalance}`)
+      if (this.paychan) {
+        await this.paychan.sendClaim(account, xrpAddress, drops)
+      } else {
+        const transactionId = await this.sendPayment(xrpAddress, drops)
+        await recordClaim(this.redisClient, account, {
+          direction: 'outgoing',
+          ledger: 'xrp',
+          amount: '' + drops,
+          channel: null,
+          signature: null,
+          transaction_id: transactionId,
+          timestamp: Date.now()
+        })
       }
+      debug(`Sent ${drops} drop payment to account: ${account} (xrpAddress: ${xrpAddress})`)
+      // This also removes the account from the pending settlements, if it was there
+      const newBalance = await this.updateBalanceAfterSettlement(account, drops)
+      debug(`Account ${account} now has balance: ${newBalance}`)
     } catch (err) {
+      if (err instanceof SettlementInProgressError) {
+        debug(err.message)
+        return
+      }
       console.error(`Error preparing and submitting payment to rippled. Settlement to account: ${account} (xrpAddress: ${xrpAddress}) for ${drops} drops failed:`, err)
+      await this.queueRetry(account, xrpAddress, drops)
+    }
+  }
+
+  private async sendPayment (xrpAddress: string, drops: string): Promise<string> {
+    const payment = await this.rippleClient.preparePayment(this.address, {
+      source: {
+        address: this.address,
+        amount: {
+          value: '' + drops,
+          currency: 'drops'
+        }
+      },
+      destination: {
+        address: xrpAddress,
+        minAmount: {
+          value: '' + drops,
+          currency: 'drops'
+        }
+      }
+    }, {
+                // TODO add max fee
+      maxLedgerVersionOffset: 5
+    })
+    const { signedTransaction, id } = this.rippleClient.sign(payment.txJSON, this.secret)
+    const result = await this.rippleClient.submit(signedTransaction)
+    if (result.resultCode !== 'tesSUCCESS') {
+      throw new Error(`rippled responded with result code: ${result.resultCode}`)
+    }
+    return id
+  }
+
+  private async queueRetry (account: string, xrpAddress: string, drops: string) {
+    try {
+      const attempts = await this.queuePendingSettlement(account, xrpAddress, drops, Date.now(), this.retryInterval, this.maxRetryDelay)
+      debug(`Queued settlement of ${drops} drops to account: ${account} for retry (failed ${attempts} time(s))`)
+    } catch (err) {
+      console.error(`Error saving pending settlement for account: ${account}. The settlement will be retried when the account balance is next checked:`, err)
     }
   }
 
@@ -181,8 +287,21 @@ export class XrpSettlementEngine {
     debug(`Got incoming XRP payment for ${drops} drops from XRP address: ${fromAddress}`)
 
     try {
-      const [account, newBalance] = await this.creditAccountForSettlement(fromAddress, drops)
+      const [account, newBalance] = await this.creditAccountForSettlement(fromAddress, drops, tx.transaction.hash)
+      if (!account) {
+        debug(`Already credited transaction: ${tx.transaction.hash}`)
+        return
+      }
       debug(`Credited account: ${account} for incoming settlement, balance is now: ${newBalance}`)
+      await recordClaim(this.redisClient, account, {
+        direction: 'incoming',
+        ledger: 'xrp',
+        amount: '' + drops,
+        channel: null,
+        signature: null,
+        transaction_id: tx.transaction.hash,
+        timestamp: Date.now()
+      })
     } catch (err) {
       debug('Error crediting account: ', err)
       console.warn('Got incoming payment from an unknown account: ', JSON.stringify(tx))
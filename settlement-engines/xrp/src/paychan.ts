@@ -0,0 +1,529 @@
+import { RippleAPI } from 'ripple-lib'
+import { RedisClient } from 'redis'
+import { promisify } from 'util'
+import * as http from 'http'
+import * as https from 'https'
+import { URL } from 'url'
+import { randomBytes } from 'crypto'
+import { recordClaim } from './receipts'
+import Debug from 'debug'
+const debug = Debug('xrp-settlement-engine:paychan')
+
+// Outgoing channels we have opened to each account (account -> channel id)
+const OUTGOING_CHANNELS_KEY = 'xrp_outgoing_channels'
+// Latest claim we have signed for each account (account -> JSON claim)
+const OUTGOING_CLAIMS_KEY = 'xrp_outgoing_claims'
+// Latest claim each account's settlement engine has accepted (account -> JSON claim)
+const OUTGOING_CLAIMS_DELIVERED_KEY = 'xrp_outgoing_claims_delivered'
+// Outgoing channels we have started closing (channel id -> account)
+const CLOSING_CHANNELS_KEY = 'xrp_closing_channels'
+// Best claim each peer has sent us for each of its channels (channel id -> JSON claim)
+const INCOMING_CLAIMS_KEY = 'xrp_incoming_claims'
+// Amount, in drops, of each incoming claim that has already been submitted to the ledger
+const INCOMING_CLAIMS_SUBMITTED_KEY = 'xrp_incoming_claims_submitted'
+// Accounts associated with each XRP address (xrp address -> account), maintained by the node
+const XRP_ADDRESSES_KEY = 'xrp_addresses'
+// Held while a claim is signed and sent to the account so only one channel is opened per account
+const SETTLEMENT_LOCK_PREFIX = 'xrp_settlement_lock:'
+const SETTLEMENT_LOCK_TTL = 120000
+const RELEASE_LOCK_SCRIPT = "if redis.call('GET', KEYS[1]) == ARGV[1] then return redis.call('DEL', KEYS[1]) end return 0"
+// Claims are small, so anything larger than this is not a claim
+const MAX_CLAIM_REQUEST_SIZE = 4096
+const CLAIM_DELIVERY_TIMEOUT = 30000
+
+export interface Claim {
+  channel: string,
+  // Cumulative amount of the claim, denominated in drops
+  amount: string,
+  signature: string
+}
+
+interface IncomingClaim extends Claim {
+  account: string
+}
+
+export interface PaymentChannelConfig {
+  // Amount, in drops, to fund new channels with (and to top them up by when they run low)
+  channelAmountDrops?: number,
+  // Number of seconds the peer has to submit their latest claim when we close the channel
+  settleDelay?: number,
+  // Minimum number of seconds peers' channels must give us to submit our latest claim when they close them
+  minIncomingSettleDelay?: number,
+  // Interval, in milliseconds, to check incoming claims and outgoing channels
+  claimInterval?: number,
+  // Submit incoming claims once they are this many drops greater than the amount already claimed on the ledger
+  minClaimSubmitDrops?: number,
+  // Port to listen on for claims sent by peers' settlement engines
+  port?: number
+}
+
+/**
+ * Thrown when another settlement to the same account is already in progress.
+ * The settlement in progress covers the balance, so the caller should not retry.
+ */
+export class SettlementInProgressError extends Error {
+  constructor (account: string) {
+    super(`A settlement to account: ${account} is already in progress`)
+  }
+}
+
+class InvalidClaimError extends Error {}
+
+export class PaymentChannelManager {
+  private rippleClient: RippleAPI
+  private redisClient: RedisClient
+  private address: string
+  private secret: string
+  private keypair: { publicKey: string, privateKey: string }
+  private channelAmountDrops: number
+  private settleDelay: number
+  private minIncomingSettleDelay: number
+  private claimInterval: number
+  private minClaimSubmitDrops: number
+  private port: number
+  private interval: NodeJS.Timeout
+  private server: http.Server
+  private creditAccountForClaim: any
+  private saveIncomingClaim: any
+
+  constructor (rippleClient: RippleAPI, redisClient: RedisClient, address: string, secret: string, config: PaymentChannelConfig) {
+    this.rippleClient = rippleClient
+    this.redisClient = redisClient
+    this.address = address
+    this.secret = secret
+    this.keypair = this.rippleClient.deriveKeypair(secret)
+    this.channelAmountDrops = config.channelAmountDrops || 10000000000
+    this.settleDelay = config.settleDelay || 86400
+    this.minIncomingSettleDelay = config.minIncomingSettleDelay || 3600
+    this.claimInterval = config.claimInterval || 60000
+    this.minClaimSubmitDrops = config.minClaimSubmitDrops || 10000000
+    this.port = config.port || 3001
+  }
+
+  async connect (creditAccountForClaim: any, saveIncomingClaim: any) {
+    this.creditAccountForClaim = creditAccountForClaim
+    this.saveIncomingClaim = saveIncomingClaim
+
+    this.server = http.createServer((req, res) => this.handleClaimRequest(req, res))
+    await new Promise((resolve, reject) => {
+      this.server.once('error', reject)
+      this.server.listen(this.port, resolve)
+    })
+    debug(`Listening for payment channel claims on port ${this.port}`)
+
+    debug(`Setting up to check payment channels every ${this.claimInterval}ms`)
+    await this.checkChannels()
+    this.interval = setInterval(() => this.checkChannels(), this.claimInterval)
+  }
+
+  disconnect () {
+    if (this.interval) {
+      clearInterval(this.interval)
+    }
+    if (this.server) {
+      this.server.close()
+    }
+  }
+
+  /**
+   * Sign a claim for the given number of drops more than the last claim the account accepted,
+   * opening or topping up the channel first if needed, and send it to the account's settlement engine.
+   * Resolves once the account's engine has accepted the claim.
+   */
+  async sendClaim (account: string, xrpAddress: string, drops: string) {
+    const lockKey = SETTLEMENT_LOCK_PREFIX + account
+    const lockToken = randomBytes(16).toString('hex')
+    const locked = await promisify(this.redisClient.set.bind(this.redisClient))(lockKey, lockToken, 'PX', SETTLEMENT_LOCK_TTL, 'NX')
+    if (!locked) {
+      throw new SettlementInProgressError(account)
+    }
+    try {
+      await this.signAndDeliverClaim(account, xrpAddress, drops)
+    } finally {
+      await promisify(this.redisClient.eval.bind(this.redisClient))(RELEASE_LOCK_SCRIPT, 1, lockKey, lockToken)
+    }
+  }
+
+  private async signAndDeliverClaim (account: string, xrpAddress: string, drops: string) {
+    const hget = promisify(this.redisClient.hget.bind(this.redisClient))
+    const hset = promisify(this.redisClient.hset.bind(this.redisClient))
+
+    const engineUrl = await this.getPeerEngineUrl(account)
+
+    let channelId = await hget(OUTGOING_CHANNELS_KEY, account)
+    let channel = (channelId ? await this.getChannel(channelId) : null)
+    if (channelId && (!channel || channel.expiration || channel.destination !== xrpAddress)) {
+      // The channel was closed, is closing, or goes to the account's old address
+      await this.closeChannel(account, channelId)
+      channelId = null
+    }
+    if (!channelId) {
+      channelId = await this.openChannel(account, xrpAddress)
+      channel = await this.rippleClient.getPaymentChannel(channelId)
+    }
+
+    // Claims are cumulative, so build on the last one the peer accepted. If an earlier claim was
+    // delivered but we did not hear back, the peer is not credited twice for the same amount
+    const lastClaim: Claim | null = JSON.parse(await hget(OUTGOING_CLAIMS_DELIVERED_KEY, account) || 'null')
+    const previousAmount = (lastClaim && lastClaim.channel === channelId ? parseInt(lastClaim.amount, 10) : 0)
+    const newAmount = previousAmount + parseInt(drops, 10)
+
+    const channelDrops = parseInt(this.rippleClient.xrpToDrops(channel.amount), 10)
+    if (channelDrops < newAmount) {
+      await this.fundChannel(channelId, Math.max(this.channelAmountDrops, newAmount - channelDrops))
+    }
+
+    const signature = this.rippleClient.signPaymentChannelClaim(channelId, this.rippleClient.dropsToXrp('' + newAmount), this.keypair.privateKey)
+    const claim: Claim = { channel: channelId, amount: '' + newAmount, signature }
+    await hset(OUTGOING_CLAIMS_KEY, account, JSON.stringify(claim))
+    debug(`Signed claim for ${newAmount} drops on channel ${channelId} for account: ${account}`)
+
+    await postJson(engineUrl, claim)
+    await hset(OUTGOING_CLAIMS_DELIVERED_KEY, account, JSON.stringify(claim))
+    await recordClaim(this.redisClient, account, {
+      direction: 'outgoing',
+      ledger: 'xrp',
+      amount: claim.amount,
+      channel: claim.channel,
+      signature: claim.signature,
+      transaction_id: null,
+      timestamp: Date.now()
+    })
+    debug(`Account: ${account} accepted claim for ${newAmount} drops on channel ${channelId}`)
+  }
+
+  /**
+   * The claims endpoint of the account's settlement engine, from the settlement details
+   * the account sent the node in its last `peer.settle` message.
+   */
+  private async getPeerEngineUrl (account: string): Promise<string> {
+    const info = JSON.parse(await promisify(this.redisClient.get.bind(this.redisClient))(`settlement_info:${account}`) || '{}')
+    if (!info.engine_url) {
+      throw new Error(`Account: ${account} has not sent us the URL of its settlement engine`)
+    }
+    const url = new URL('claims', info.engine_url.endsWith('/') ? info.engine_url : info.engine_url + '/')
+    if (url.protocol !== 'https:' && url.protocol !== 'http:') {
+      throw new Error(`Account: ${account} sent an invalid settlement engine URL: ${info.engine_url}`)
+    }
+    return url.toString()
+  }
+
+  private async openChannel (account: string, xrpAddress: string): Promise<string> {
+    debug(`Opening payment channel with ${this.channelAmountDrops} drops to account: ${account} (XRP address: ${xrpAddress})`)
+    const prepared = await this.rippleClient.preparePaymentChannelCreate(this.address, {
+      amount: this.rippleClient.dropsToXrp('' + this.channelAmountDrops),
+      destination: xrpAddress,
+      settleDelay: this.settleDelay,
+      publicKey: this.keypair.publicKey
+    })
+    const txId = await this.submit(prepared.txJSON)
+    const tx: any = await this.waitForValidation(txId)
+    const channelId = tx.outcome.channelChanges && tx.outcome.channelChanges.channelId
+    if (!channelId) {
+      throw new Error(`Unable to determine channel id from transaction: ${txId}`)
+    }
+    await promisify(this.redisClient.hset.bind(this.redisClient))(OUTGOING_CHANNELS_KEY, account, channelId)
+    debug(`Opened payment channel ${channelId} to account: ${account}`)
+    return channelId
+  }
+
+  private async fundChannel (channelId: string, drops: number) {
+    debug(`Funding payment channel ${channelId} with ${drops} drops`)
+    const prepared = await this.rippleClient.preparePaymentChannelFund(this.address, {
+      channel: channelId,
+      amount: this.rippleClient.dropsToXrp('' + drops)
+    })
+    const txId = await this.submit(prepared.txJSON)
+    await this.waitForValidation(txId)
+  }
+
+  /**
+   * Start closing an outgoing channel. The peer has the channel's settle delay to submit the
+   * last claim we sent it, after which `checkChannels` finishes closing the channel and the
+   * rest of the XRP in it is returned to us.
+   */
+  private async closeChannel (account: string, channelId: string) {
+    const hset = promisify(this.redisClient.hset.bind(this.redisClient))
+    const hdel = promisify(this.redisClient.hdel.bind(this.redisClient))
+    await hset(CLOSING_CHANNELS_KEY, channelId, account)
+    await hdel(OUTGOING_CHANNELS_KEY, account)
+    const channel = await this.getChannel(channelId)
+    if (channel && !channel.expiration) {
+      debug(`Closing payment channel ${channelId} to account: ${account}`)
+      await this.submitClose(channelId)
+    }
+  }
+
+  private async submitClose (channelId: string) {
+    const prepared = await this.rippleClient.preparePaymentChannelClaim(this.address, {
+      channel: channelId,
+      close: true
+    })
+    await this.submit(prepared.txJSON)
+  }
+
+  private async checkChannels () {
+    await this.checkOutgoingChannels()
+    await this.checkIncomingClaims()
+  }
+
+  /**
+   * Close channels to accounts that were removed or changed their XRP address, and finish
+   * closing channels once the peer has had the channel's settle delay to submit its claim.
+   */
+  private async checkOutgoingChannels () {
+    const hgetall = promisify(this.redisClient.hgetall.bind(this.redisClient))
+    const hget = promisify(this.redisClient.hget.bind(this.redisClient))
+    const hdel = promisify(this.redisClient.hdel.bind(this.redisClient))
+
+    let channels: { [account: string]: string }
+    let closing: { [channel: string]: string }
+    try {
+      channels = await hgetall(OUTGOING_CHANNELS_KEY) || {}
+      closing = await hgetall(CLOSING_CHANNELS_KEY) || {}
+    } catch (err) {
+      debug('Error loading outgoing payment channels:', err)
+      return
+    }
+
+    for (let account of Object.keys(channels)) {
+      try {
+        const xrpAddress = await hget(`accounts:${account}`, 'xrp_address')
+        const channel = await this.getChannel(channels[account])
+        if (!xrpAddress || !channel || channel.destination !== xrpAddress) {
+          await this.closeChannel(account, channels[account])
+        }
+      } catch (err) {
+        debug(`Error checking payment channel to account: ${account}:`, err)
+      }
+    }
+
+    for (let channelId of Object.keys(closing)) {
+      try {
+        const channel = await this.getChannel(channelId)
+        if (!channel) {
+          debug(`Payment channel ${channelId} to account: ${closing[channelId]} is closed`)
+          await hdel(CLOSING_CHANNELS_KEY, channelId)
+        } else if (!channel.expiration) {
+          await this.submitClose(channelId)
+        } else if (Date.parse(channel.expiration) <= Date.now()) {
+          // Once a channel has expired, the next close removes it from the ledger
+          await this.submitClose(channelId)
+        }
+      } catch (err) {
+        debug(`Error closing payment channel ${channelId}:`, err)
+      }
+    }
+  }
+
+  /**
+   * Handle `POST /claims` requests from peers' settlement engines. Claims are checked against
+   * the channel on the ledger, so they do not need any other authentication.
+   */
+  private async handleClaimRequest (req: http.IncomingMessage, res: http.ServerResponse) {
+    if (req.method !== 'POST' || req.url !== '/claims') {
+      res.writeHead(404)
+      res.end()
+      return
+    }
+    try {
+      const body = await readBody(req, MAX_CLAIM_REQUEST_SIZE)
+      const claim = JSON.parse(body)
+      if (typeof claim.channel !== 'string' || typeof claim.amount !== 'string' || typeof claim.signature !== 'string' || !/^\d+$/.test(claim.amount)) {
+        throw new InvalidClaimError('Claims must have a channel, amount, and signature')
+      }
+      await this.receiveClaim({ channel: claim.channel, amount: claim.amount, signature: claim.signature })
+      res.writeHead(200, { 'Content-Type': 'application/json' })
+      res.end('{}')
+    } catch (err) {
+      const invalid = err instanceof InvalidClaimError || err instanceof SyntaxError
+      debug('Error handling payment channel claim:', err)
+      res.writeHead(invalid ? 400 : 500, { 'Content-Type': 'text/plain' })
+      res.end(invalid ? err.message : 'Unable to process claim')
+    }
+  }
+
+  private async receiveClaim (claim: Claim) {
+    const channel = await this.getChannel(claim.channel)
+    if (!channel) {
+      throw new InvalidClaimError(`Unknown payment channel: ${claim.channel}`)
+    }
+    const account = await promisify(this.redisClient.hget.bind(this.redisClient))(XRP_ADDRESSES_KEY, channel.account)
+    if (!account) {
+      throw new InvalidClaimError(`Payment channel ${claim.channel} is not from one of our accounts`)
+    }
+    const incoming: IncomingClaim = { account, ...claim }
+    this.verifyClaim(incoming, channel)
+    await this.saveIncomingClaim(claim.channel, claim.amount, JSON.stringify(incoming))
+    await this.processClaim(incoming, channel)
+  }
+
+  private verifyClaim (claim: IncomingClaim, channel: any) {
+    if (channel.destination !== this.address) {
+      throw new InvalidClaimError(`Payment channel ${claim.channel} is not to our address`)
+    }
+    if (channel.settleDelay < this.minIncomingSettleDelay) {
+      throw new InvalidClaimError(`Payment channel ${claim.channel} has a settle delay shorter than ${this.minIncomingSettleDelay} seconds`)
+    }
+    const channelDrops = parseInt(this.rippleClient.xrpToDrops(channel.amount), 10)
+    if (parseInt(claim.amount, 10) > channelDrops) {
+      throw new InvalidClaimError(`Claim for ${claim.amount} drops is more than payment channel ${claim.channel} holds`)
+    }
+    if (!this.rippleClient.verifyPaymentChannelClaim(claim.channel, this.rippleClient.dropsToXrp(claim.amount), claim.signature, channel.publicKey)) {
+      throw new InvalidClaimError(`Invalid signature on claim for payment channel ${claim.channel}`)
+    }
+  }
+
+  /**
+   * Credit the account for the part of the claim it has not been credited for and submit
+   * the claim to the ledger if it is large enough or the peer has started closing the channel.
+   */
+  private async processClaim (claim: IncomingClaim, channel: any) {
+    const hget = promisify(this.redisClient.hget.bind(this.redisClient))
+    const hset = promisify(this.redisClient.hset.bind(this.redisClient))
+    const claimAmount = parseInt(claim.amount, 10)
+
+    const [credited, newBalance] = await this.creditAccountForClaim(claim.account, claim.channel, claim.amount)
+    if (parseInt(credited, 10) > 0) {
+      await recordClaim(this.redisClient, claim.account, {
+        direction: 'incoming',
+        ledger: 'xrp',
+        amount: claim.amount,
+        channel: claim.channel,
+        signature: claim.signature,
+        transaction_id: null,
+        timestamp: Date.now()
+      })
+      debug(`Credited account: ${claim.account} for ${credited} drops from payment channel claim, balance is now: ${newBalance}`)
+    }
+
+    const submitted = parseInt(await hget(INCOMING_CLAIMS_SUBMITTED_KEY, claim.channel) || '0', 10)
+    const isClosing = !!(channel.expiration || channel.cancelAfter)
+    if (claimAmount > submitted && (isClosing || claimAmount - submitted >= this.minClaimSubmitDrops)) {
+      await this.submitClaim(claim, channel.publicKey)
+      await hset(INCOMING_CLAIMS_SUBMITTED_KEY, claim.channel, '' + claimAmount)
+    }
+  }
+
+  /**
+   * Re-check the best claim for each incoming channel, mainly to submit claims before
+   * channels the peer is closing expire.
+   */
+  private async checkIncomingClaims () {
+    const hgetall = promisify(this.redisClient.hgetall.bind(this.redisClient))
+    const hdel = promisify(this.redisClient.hdel.bind(this.redisClient))
+
+    let claims: { [channel: string]: string }
+    try {
+      claims = await hgetall(INCOMING_CLAIMS_KEY) || {}
+    } catch (err) {
+      debug('Error loading incoming payment channel claims:', err)
+      return
+    }
+
+    for (let channelId of Object.keys(claims)) {
+      try {
+        const claim: IncomingClaim = JSON.parse(claims[channelId])
+        const channel = await this.getChannel(channelId)
+        if (!channel) {
+          debug(`Payment channel ${channelId} from account: ${claim.account} is closed`)
+          await hdel(INCOMING_CLAIMS_KEY, channelId)
+          continue
+        }
+        this.verifyClaim(claim, channel)
+        await this.processClaim(claim, channel)
+      } catch (err) {
+        debug(`Error processing payment channel claim for channel ${channelId}:`, err)
+      }
+    }
+  }
+
+  private async submitClaim (claim: Claim, publicKey: string) {
+    debug(`Submitting claim for ${claim.amount} drops on channel ${claim.channel}`)
+    const amount = this.rippleClient.dropsToXrp(claim.amount)
+    const prepared = await this.rippleClient.preparePaymentChannelClaim(this.address, {
+      channel: claim.channel,
+      balance: amount,
+      amount,
+      signature: claim.signature.toUpperCase(),
+      publicKey
+    })
+    await this.submit(prepared.txJSON)
+  }
+
+  /**
+   * Get the channel from the ledger, or null if it does not exist (anymore).
+   */
+  private async getChannel (channelId: string): Promise<any> {
+    try {
+      return await this.rippleClient.getPaymentChannel(channelId)
+    } catch (err) {
+      if (err.name === 'NotFoundError' || err.message === 'entryNotFound') {
+        return null
+      }
+      throw err
+    }
+  }
+
+  private async submit (txJSON: string): Promise<string> {
+    const { signedTransaction, id } = this.rippleClient.sign(txJSON, this.secret)
+    const result = await this.rippleClient.submit(signedTransaction)
+    if (result.resultCode !== 'tesSUCCESS') {
+      throw new Error(`rippled responded with result code: ${result.resultCode}`)
+    }
+    return id
+  }
+
+  private async waitForValidation (txId: string, attempts: number = 10): Promise<any> {
+    for (let i = 0; i < attempts; i++) {
+      await new Promise((resolve) => setTimeout(resolve, 1000))
+      try {
+        return await this.rippleClient.getTransaction(txId)
+      } catch (err) {
+        // Not validated yet
+      }
+    }
+    throw new Error(`Transaction ${txId} was not validated`)
+  }
+}
+
+function readBody (req: http.IncomingMessage, maxSize: number): Promise<string> {
+  return new Promise((resolve, reject) => {
+    let body = ''
+    req.setEncoding('utf8')
+    req.on('data', (chunk: string) => {
+      body += chunk
+      if (body.length > maxSize) {
+        reject(new InvalidClaimError('Request is too large'))
+        req.destroy()
+      }
+    })
+    req.on('end', () => resolve(body))
+    req.on('error', reject)
+  })
+}
+
+function postJson (url: string, body: any): Promise<void> {
+  const data = JSON.stringify(body)
+  const request = (url.startsWith('https:') ? https.request : http.request)
+  return new Promise((resolve, reject) => {
+    const req = request(url, {
+      method: 'POST',
+      headers: {
+        'Content-Type': 'application/json',
+        'Content-Length': Buffer.byteLength(data)
+      },
+      timeout: CLAIM_DELIVERY_TIMEOUT
+    }, (res) => {
+      res.resume()
+      if (res.statusCode === 200) {
+        resolve()
+      } else {
+        reject(new Error(`Settlement engine at ${url} responded with status: ${res.statusCode}`))
+      }
+    })
+    req.on('timeout', () => req.abort())
+    req.on('error', reject)
+    req.end(data)
+  })
+}
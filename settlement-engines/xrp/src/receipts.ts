@@ -0,0 +1,28 @@
+import { RedisClient } from 'redis'
+import { promisify } from 'util'
+
+export interface SettlementClaim {
+  direction: 'incoming' | 'outgoing',
+  ledger: string,
+  // Amount in drops. For payment channel claims this is the cumulative amount of the channel
+  amount: string,
+  channel: string | null,
+  signature: string | null,
+  transaction_id: string | null,
+  timestamp: number
+}
+
+/**
+ * Record a signed claim or payment receipt for the given account so the node can
+ * export it as proof of what was paid (see `GET /accounts/:id/claims`).
+ *
+ * Errors are logged rather than thrown so that failing to record a receipt never
+ * causes a settlement that already went through to be retried.
+ */
+export async function recordClaim (redisClient: RedisClient, account: string, claim: SettlementClaim): Promise<void> {
+  try {
+    await promisify(redisClient.lpush.bind(redisClient))(`claims:${account}`, JSON.stringify(claim))
+  } catch (err) {
+    console.error(`Error recording settlement claim for account: ${account}:`, claim, err)
+  }
+}